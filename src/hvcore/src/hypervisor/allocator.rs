@@ -2,59 +2,317 @@
 //! heap and provides allocator for fixed-sized blocks. This allocator eliminates
 //! dependencies onto platform API for memory management at runtime. This is
 //! important as calling platform API from the hypervisor is unsound.
+//!
+//! Freed blocks are reused by later allocations. Allocations smaller than 4096
+//! bytes fall back to the 4096-byte blocks when the 128-byte blocks run out.
+//!
+//! The heap can be extended with more memory with `extend`, which is managed in
+//! 4096-byte blocks. `SharedHostData::heap_size` extends it during
+//! `virtualize_system`, and `PlatformOps::donate_heap` when it runs out after
+//! that, unless the host address space is minimal. See
+//! `PagingStructures::build_minimal`.
 
 use core::{
     alloc::{GlobalAlloc, Layout},
+    ops::Range,
     ptr::{addr_of, addr_of_mut, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
+use alloc::vec::Vec;
 use bitvec::{array::BitArray, prelude::*};
 use spin::{Mutex, Once};
 
+use crate::hypervisor::{platform_ops, HvError};
+
 pub const ALLOCATION_BYTES: usize = 0x80_0000;
 pub const ALLOCATION_PAGES: usize = ALLOCATION_BYTES / 0x1000;
 
+/// The maximum number of memory ranges `extend` accepts.
+pub const MAX_EXTENSIONS: usize = 16;
+
 /// Initializes the global allocator. `ptr` must be as large as `ALLOCATION_BYTES`
 /// and must be 4096 byte-aligned.
 pub fn init(ptr: *mut u8) {
     let _ = METADATA.call_once(|| Mutex::new(Metadata::new(ptr)));
 }
 
+/// Extends the heap with `size` bytes of memory at `ptr`. `ptr` must be 4096
+/// byte-aligned, and the memory must stay valid and unused by anything else
+/// while the hypervisor uses the heap. The first pages of the memory are used
+/// to manage the rest. Returns `false` without using the memory if it is too
+/// small, or the heap is already extended `MAX_EXTENSIONS` times.
+pub fn extend(ptr: *mut u8, size: usize) -> bool {
+    let mut meta = METADATA.get().expect("init() is not called").lock();
+    let Some(index) = meta.extensions.iter().position(Option::is_none) else {
+        return false;
+    };
+    let Some(extension) = Extension::new(ptr, size) else {
+        return false;
+    };
+    meta.stats.pool_bytes += extension.size as u64;
+    meta.extensions[index] = Some(extension);
+    true
+}
+
+/// Extends the heap with `PlatformOps::allocate_heap` until it is at least
+/// `size` bytes in total. Called during `virtualize_system`.
+pub(crate) fn grow(size: usize) -> Result<(), HvError> {
+    let capacity = stats().map_or(0, |stats| stats.pool_bytes as usize);
+    let Some(missing) = size.checked_sub(capacity).filter(|&missing| missing != 0) else {
+        return Ok(());
+    };
+
+    let missing = round_up_by(missing, BLOCK_SIZE_4096) * BLOCK_SIZE_4096;
+    let ptr = platform_ops::get().allocate_heap(missing);
+    if ptr.is_null() || !extend(ptr, missing) {
+        log::error!("Failed to extend the heap by {missing:#x} bytes");
+        return Err(HvError::OutOfMemory);
+    }
+    log::info!("Extended the heap by {missing:#x} bytes");
+    Ok(())
+}
+
+/// Returns the address ranges of the heap given to `init` and `extend`, if
+/// initialized.
+pub(crate) fn heap_ranges() -> Vec<Range<usize>> {
+    let Some(meta) = METADATA.get() else {
+        return Vec::new();
+    };
+    let meta = meta.lock();
+    let base = meta.blocks.as_ptr() as usize;
+    core::iter::once(base..base + ALLOCATION_BYTES)
+        .chain(meta.extensions.iter().flatten().map(Extension::range))
+        .collect()
+}
+
+/// The usage of the heap, as returned by `Hypercall::ReadAllocatorStats`.
+/// Sizes are in bytes of the blocks, that is, requested sizes rounded up to
+/// 128 or 4096 bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct AllocatorStats {
+    /// The size of the heap, `ALLOCATION_BYTES` plus the memory given to
+    /// `extend`.
+    pub pool_bytes: u64,
+
+    /// The size of the blocks currently allocated.
+    pub bytes_in_use: u64,
+
+    /// The largest `bytes_in_use` so far.
+    pub peak_bytes_in_use: u64,
+
+    /// The number of successful allocations.
+    pub allocation_count: u64,
+
+    /// The number of deallocations.
+    pub deallocation_count: u64,
+
+    /// The number of allocations failed due to lack of free blocks.
+    pub failure_count: u64,
+}
+
+/// Returns the current usage of the heap, or `None` if `init` is not called.
+pub fn stats() -> Option<AllocatorStats> {
+    METADATA.get().map(|meta| meta.lock().stats)
+}
+
+/// Page-aligned, physically contiguous memory returned by `alloc_pages`.
+#[derive(Clone, Copy, Debug)]
+pub struct PageAllocation {
+    /// The virtual address of the first page.
+    pub ptr: NonNull<u8>,
+
+    /// The physical address of the first page.
+    pub pa: u64,
+}
+
+/// Allocates `count` zeroed pages that are physically contiguous, as required
+/// for the structures referenced by physical address and larger than a page,
+/// such as the MSR and I/O permissions maps (AMD). Returns `None` if no such
+/// pages are free. `count` must not be zero. Must be called after
+/// `platform_ops::init`.
+///
+/// The pages must be freed with `free_pages` with the same `count`.
+pub fn alloc_pages(count: usize) -> Option<PageAllocation> {
+    assert!(count != 0);
+    let layout = pages_layout(count);
+    let ptr = alloc_or_donate(layout, |meta| meta.alloc_pages(count))?.as_ptr();
+    unsafe { ptr.write_bytes(0, layout.size()) };
+    Some(PageAllocation {
+        ptr: NonNull::new(ptr).unwrap(),
+        pa: platform_ops::get().pa(ptr.cast()),
+    })
+}
+
+/// Frees `count` pages at `ptr` allocated with `alloc_pages`.
+///
+/// # Safety
+///
+/// `ptr` and `count` must be those given to and returned by `alloc_pages`, and
+/// the pages must not be used after this.
+pub unsafe fn free_pages(ptr: NonNull<u8>, count: usize) {
+    unsafe { ALLOCATOR.dealloc(ptr.as_ptr(), pages_layout(count)) };
+}
+
+fn pages_layout(count: usize) -> Layout {
+    Layout::from_size_align(count * BLOCK_SIZE_4096, BLOCK_SIZE_4096).unwrap()
+}
+
+/// Allocates memory for `layout` with `alloc`. If it fails, extends the heap
+/// with `PlatformOps::donate_heap` and tries again, then, reports the failure
+/// if it still fails.
+fn alloc_or_donate(
+    layout: Layout,
+    alloc: impl Fn(&mut Metadata) -> Option<NonNull<u8>>,
+) -> Option<NonNull<u8>> {
+    let meta = METADATA.get().expect("init() is not called");
+    if let Some(ptr) = alloc(&mut meta.lock()) {
+        return Some(ptr);
+    }
+    if donate() {
+        if let Some(ptr) = alloc(&mut meta.lock()) {
+            return Some(ptr);
+        }
+    }
+
+    let mut meta = meta.lock();
+    meta.stats.failure_count += 1;
+    let exhaustion = meta.exhaustion(layout);
+
+    // Logging allocates memory, so release the lock first.
+    drop(meta);
+    exhaustion.report();
+    None
+}
+
+/// Stops extending the heap with `PlatformOps::donate_heap`, as the memory
+/// would not be mapped in the minimal host address space.
+pub(crate) fn disable_donation() {
+    DONATION_DISABLED.store(true, Ordering::Relaxed);
+}
+
+/// Extends the heap with `PlatformOps::donate_heap`. Returns `true` if
+/// extended.
+fn donate() -> bool {
+    if DONATION_DISABLED.load(Ordering::Relaxed) {
+        return false;
+    }
+    // Donation may allocate memory and fail, for example, by logging.
+    if DONATING.swap(true, Ordering::Acquire) {
+        return false;
+    }
+    let extended = platform_ops::try_get()
+        .and_then(platform_ops::PlatformOps::donate_heap)
+        .is_some_and(|(ptr, size)| extend(ptr, size));
+    DONATING.store(false, Ordering::Release);
+    extended
+}
+
+/// Whether `donate` is running.
+static DONATING: AtomicBool = AtomicBool::new(false);
+
+/// Whether `disable_donation` is called.
+static DONATION_DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the index of the first `count` free blocks in `bitmap` that are
+/// physically contiguous, where the blocks are 4096 bytes each from `base`.
+fn find_contiguous_pages(
+    base: *const u8,
+    bitmap: &BitSlice<u8, Msb0>,
+    count: usize,
+) -> Option<usize> {
+    let ops = platform_ops::get();
+    let pa = |index: usize| ops.pa(base.wrapping_add(index * BLOCK_SIZE_4096).cast());
+
+    let mut from = 0;
+    loop {
+        let start = from + find_empty_blocks(&bitmap[from..], count)?;
+        let base = pa(start);
+        match (1..count).find(|&i| pa(start + i) != base + (i * BLOCK_SIZE_4096) as u64) {
+            // Search again from the page that is not contiguous.
+            Some(i) => from = start + i,
+            None => return Some(start),
+        }
+    }
+}
+
 #[global_allocator]
 static ALLOCATOR: Allocator = Allocator;
 
 struct Allocator;
 
 unsafe impl GlobalAlloc for Allocator {
-    /// Allocates memory. If the requested size is smaller than 4096 bytes, it
-    /// returns 128-byte aligned block(s). If greater than 4096 bytes, it returns
-    /// 4096-byte aligned block(s).
+    /// Allocates memory. If the requested size is smaller than 4096 bytes and
+    /// alignment is 128 bytes or less, it returns 128-byte aligned block(s), or
+    /// a 4096-byte block if no 128-byte blocks are free. Otherwise, it returns
+    /// 4096-byte aligned block(s). Alignment greater than 4096 bytes is not
+    /// supported.
     ///
-    /// Allocation of memory that is physically continuous for 2 or more pages are
-    /// not supported.
+    /// Allocated memory is not guaranteed to be physically contiguous for 2 or
+    /// more pages. Use `alloc_pages` for such memory.
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut meta = METADATA.get().expect("init() is not called").lock();
-        let blocks = unsafe { meta.blocks.as_mut() };
-
-        if layout.size() >= BLOCK_SIZE_4096 {
-            alloc_internal(layout, &mut blocks.block4096, &mut meta.bitmap4096)
-        } else {
-            alloc_internal(layout, &mut blocks.block128, &mut meta.bitmap128)
+        if layout.align() > BLOCK_SIZE_4096 {
+            return core::ptr::null_mut();
         }
+        alloc_or_donate(layout, |meta| meta.alloc(layout))
+            .map_or(core::ptr::null_mut(), NonNull::as_ptr)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut meta = METADATA.get().expect("init() is not called").lock();
-        let blocks = unsafe { meta.blocks.as_mut() };
+        meta.dealloc(ptr, layout);
+    }
+}
 
-        if layout.size() >= BLOCK_SIZE_4096 {
-            dealloc_internal(ptr, layout, &blocks.block4096, &mut meta.bitmap4096);
-        } else {
-            dealloc_internal(ptr, layout, &blocks.block128, &mut meta.bitmap128);
+/// Returns whether `layout` is allocated from the 4096-byte blocks.
+fn uses_block4096(layout: Layout) -> bool {
+    layout.size() >= BLOCK_SIZE_4096 || layout.align() > BLOCK_SIZE_128
+}
+
+/// Returns the size of the blocks of `block_size` allocated for `layout`.
+fn block_bytes(layout: Layout, block_size: usize) -> usize {
+    round_up_by(layout.size(), block_size) * block_size
+}
+
+/// The details of a failed allocation.
+struct Exhaustion {
+    layout: Layout,
+
+    /// The size of the free 128-byte blocks.
+    free_bytes128: usize,
+
+    /// The size of the free 4096-byte blocks.
+    free_bytes4096: usize,
+
+    /// The size of the largest contiguous free 4096-byte blocks.
+    largest_free_bytes4096: usize,
+}
+
+impl Exhaustion {
+    /// Logs the failure, unless this is called while logging another failure,
+    /// as logging may fail to allocate too.
+    fn report(&self) {
+        if REPORTING.swap(true, Ordering::Acquire) {
+            return;
         }
+        log::error!(
+            "Failed to allocate {:#x} bytes aligned to {:#x}: {:#x} bytes free in \
+             128-byte blocks, {:#x} bytes free in 4096-byte blocks with up to {:#x} \
+             bytes contiguous",
+            self.layout.size(),
+            self.layout.align(),
+            self.free_bytes128,
+            self.free_bytes4096,
+            self.largest_free_bytes4096,
+        );
+        REPORTING.store(false, Ordering::Release);
     }
 }
 
+/// Whether `Exhaustion::report` is logging.
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
 fn alloc_internal<const BLOCK_COUNT: usize, const BLOCK_SIZE: usize, const BIT_COUNT: usize>(
     layout: Layout,
     blocks: &mut [Block<BLOCK_SIZE>; BLOCK_COUNT],
@@ -97,17 +355,26 @@ fn dealloc_internal<const BLOCK_COUNT: usize, const BLOCK_SIZE: usize, const BIT
 
 fn round_up_by(number: usize, size: usize) -> usize {
     let round_down = number / size;
-    if number % size != 0 {
+    if !number.is_multiple_of(size) {
         round_down + 1
     } else {
         round_down
     }
 }
 
-fn find_empty_blocks<const BIT_COUNT: usize>(
-    bitmap: &BitArray<[u8; BIT_COUNT], Msb0>,
-    count: usize,
-) -> Option<usize> {
+/// Returns the number of free blocks and the largest number of contiguous free
+/// blocks.
+fn count_empty_blocks(bitmap: &BitSlice<u8, Msb0>) -> (usize, usize) {
+    let mut largest = 0;
+    let mut current = 0;
+    for bit in bitmap.iter() {
+        current = if *bit { 0 } else { current + 1 };
+        largest = largest.max(current);
+    }
+    (bitmap.count_zeros(), largest)
+}
+
+fn find_empty_blocks(bitmap: &BitSlice<u8, Msb0>, count: usize) -> Option<usize> {
     let mut empty_block_count = 0;
     let mut start = 0;
     for (index, bit) in bitmap.iter().enumerate() {
@@ -130,12 +397,20 @@ fn find_empty_blocks<const BIT_COUNT: usize>(
 const NUMBER_OF_BLOCK_4096: usize = 0x700;
 const NUMBER_OF_BLOCK_128: usize = 0x2000;
 
+/// Returns whether the heap is locked by any processor. See
+/// `host::fail_open`.
+pub(crate) fn is_locked() -> bool {
+    METADATA.get().is_some_and(|metadata| metadata.is_locked())
+}
+
 static METADATA: Once<Mutex<Metadata>> = Once::new();
 
 struct Metadata {
     blocks: NonNull<Blocks>,
     bitmap4096: BitArray<[u8; NUMBER_OF_BLOCK_4096 / 8], Msb0>,
     bitmap128: BitArray<[u8; NUMBER_OF_BLOCK_128 / 8], Msb0>,
+    extensions: [Option<Extension>; MAX_EXTENSIONS],
+    stats: AllocatorStats,
 }
 
 unsafe impl Send for Metadata {}
@@ -144,14 +419,181 @@ unsafe impl Sync for Metadata {}
 impl Metadata {
     fn new(ptr: *mut u8) -> Self {
         assert!(!ptr.is_null());
-        assert!((ptr as usize) % core::mem::align_of::<Self>() == 0);
+        assert!((ptr as usize).is_multiple_of(core::mem::align_of::<Self>()));
         #[allow(clippy::cast_ptr_alignment)]
         let blocks = ptr.cast::<Blocks>();
         Self {
             blocks: unsafe { NonNull::new_unchecked(blocks) },
             bitmap4096: bitarr!(u8, Msb0; 0; NUMBER_OF_BLOCK_4096),
             bitmap128: bitarr!(u8, Msb0; 0; NUMBER_OF_BLOCK_128),
+            extensions: [const { None }; MAX_EXTENSIONS],
+            stats: AllocatorStats {
+                pool_bytes: ALLOCATION_BYTES as u64,
+                ..AllocatorStats::default()
+            },
+        }
+    }
+
+    /// Allocates blocks for `layout`, from the 128-byte blocks if small enough,
+    /// then, from the 4096-byte blocks and the extensions.
+    fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let blocks = unsafe { self.blocks.as_mut() };
+
+        let mut ptr = core::ptr::null_mut();
+        let mut bytes = 0;
+        if !uses_block4096(layout) {
+            ptr = alloc_internal(layout, &mut blocks.block128, &mut self.bitmap128);
+            bytes = block_bytes(layout, BLOCK_SIZE_128);
+        }
+        if ptr.is_null() {
+            ptr = alloc_internal(layout, &mut blocks.block4096, &mut self.bitmap4096);
+            bytes = block_bytes(layout, BLOCK_SIZE_4096);
+        }
+        let ptr = if let Some(ptr) = NonNull::new(ptr) {
+            ptr
+        } else {
+            let count = round_up_by(layout.size(), BLOCK_SIZE_4096);
+            self.extensions.iter_mut().flatten().find_map(|extension| {
+                extension.alloc(count, |bitmap| find_empty_blocks(bitmap, count))
+            })?
+        };
+        self.record_alloc(bytes);
+        Some(ptr)
+    }
+
+    /// Allocates `count` physically contiguous 4096-byte blocks, from the
+    /// extensions if the heap given to `init` has no such blocks.
+    fn alloc_pages(&mut self, count: usize) -> Option<NonNull<u8>> {
+        let blocks = unsafe { self.blocks.as_mut() };
+        let base = addr_of!(blocks.block4096).cast::<u8>();
+        let ptr = if let Some(start) = find_contiguous_pages(base, &self.bitmap4096, count) {
+            for index in start..start + count {
+                self.bitmap4096.set(index, true);
+            }
+            NonNull::new(addr_of_mut!(blocks.block4096[start].block).cast::<u8>()).unwrap()
+        } else {
+            self.extensions.iter_mut().flatten().find_map(|extension| {
+                let base = extension.blocks.as_ptr();
+                extension.alloc(count, |bitmap| find_contiguous_pages(base, bitmap, count))
+            })?
+        };
+        self.record_alloc(count * BLOCK_SIZE_4096);
+        Some(ptr)
+    }
+
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let blocks = unsafe { self.blocks.as_mut() };
+
+        // Find the blocks from the address, as small allocations may be in the
+        // 4096-byte blocks or the extensions.
+        let address = ptr.cast_const().cast();
+        let bytes = if blocks.block128.as_ptr_range().contains(&address) {
+            dealloc_internal(ptr, layout, &blocks.block128, &mut self.bitmap128);
+            block_bytes(layout, BLOCK_SIZE_128)
+        } else if blocks.block4096.as_ptr_range().contains(&address.cast()) {
+            dealloc_internal(ptr, layout, &blocks.block4096, &mut self.bitmap4096);
+            block_bytes(layout, BLOCK_SIZE_4096)
+        } else {
+            let count = round_up_by(layout.size(), BLOCK_SIZE_4096);
+            self.extensions
+                .iter_mut()
+                .flatten()
+                .find(|extension| extension.range().contains(&(ptr as usize)))
+                .expect("The address is not in the heap")
+                .dealloc(ptr, count);
+            count * BLOCK_SIZE_4096
+        };
+        self.stats.deallocation_count += 1;
+        self.stats.bytes_in_use -= bytes as u64;
+    }
+
+    fn record_alloc(&mut self, bytes: usize) {
+        let stats = &mut self.stats;
+        stats.allocation_count += 1;
+        stats.bytes_in_use += bytes as u64;
+        stats.peak_bytes_in_use = stats.peak_bytes_in_use.max(stats.bytes_in_use);
+    }
+
+    /// Returns the details of the failed allocation for `layout`.
+    fn exhaustion(&mut self, layout: Layout) -> Exhaustion {
+        let (free128, _) = count_empty_blocks(&self.bitmap128);
+        let (mut free4096, mut largest4096) = count_empty_blocks(&self.bitmap4096);
+        for extension in self.extensions.iter_mut().flatten() {
+            let (free, largest) = count_empty_blocks(extension.bitmap());
+            free4096 += free;
+            largest4096 = largest4096.max(largest);
         }
+        Exhaustion {
+            layout,
+            free_bytes128: free128 * BLOCK_SIZE_128,
+            free_bytes4096: free4096 * BLOCK_SIZE_4096,
+            largest_free_bytes4096: largest4096 * BLOCK_SIZE_4096,
+        }
+    }
+}
+
+/// Memory given to `extend`, allocated in 4096-byte blocks. The bitmap of the
+/// blocks is placed in the first pages of the memory.
+struct Extension {
+    /// The start of the memory, where the bitmap is.
+    base: NonNull<u8>,
+
+    /// The size of the memory in bytes.
+    size: usize,
+
+    /// The first block, following the bitmap.
+    blocks: NonNull<u8>,
+    block_count: usize,
+}
+
+impl Extension {
+    fn new(ptr: *mut u8, size: usize) -> Option<Self> {
+        let base = NonNull::new(ptr)?;
+        assert!((ptr as usize).is_multiple_of(BLOCK_SIZE_4096));
+
+        let page_count = size / BLOCK_SIZE_4096;
+        let bitmap_pages = round_up_by(round_up_by(page_count, 8), BLOCK_SIZE_4096);
+        if page_count <= bitmap_pages {
+            return None;
+        }
+        unsafe { ptr.write_bytes(0, bitmap_pages * BLOCK_SIZE_4096) };
+        Some(Self {
+            base,
+            size: page_count * BLOCK_SIZE_4096,
+            blocks: NonNull::new(ptr.wrapping_add(bitmap_pages * BLOCK_SIZE_4096)).unwrap(),
+            block_count: page_count - bitmap_pages,
+        })
+    }
+
+    fn range(&self) -> Range<usize> {
+        let base = self.base.as_ptr() as usize;
+        base..base + self.size
+    }
+
+    fn bitmap(&mut self) -> &mut BitSlice<u8, Msb0> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(self.base.as_ptr(), round_up_by(self.block_count, 8))
+        };
+        &mut bytes.view_bits_mut::<Msb0>()[..self.block_count]
+    }
+
+    /// Marks `count` blocks from the index `find` returns as in-use.
+    fn alloc(
+        &mut self,
+        count: usize,
+        find: impl FnOnce(&BitSlice<u8, Msb0>) -> Option<usize>,
+    ) -> Option<NonNull<u8>> {
+        let bitmap = self.bitmap();
+        let start = find(bitmap)?;
+        bitmap[start..start + count].fill(true);
+        NonNull::new(self.blocks.as_ptr().wrapping_add(start * BLOCK_SIZE_4096))
+    }
+
+    fn dealloc(&mut self, ptr: *mut u8, count: usize) {
+        let start = (ptr as usize - self.blocks.as_ptr() as usize) / BLOCK_SIZE_4096;
+        let bitmap = &mut self.bitmap()[start..start + count];
+        assert!(bitmap.all());
+        bitmap.fill(false);
     }
 }
 
@@ -0,0 +1,442 @@
+//! This module implements `preemption_timer` on AMD processors with the timer
+//! of the local APIC, as SVM has no equivalent of the VMX-preemption timer.
+//!
+//! The host takes over the timer of the local APIC, and arms it in the one-shot
+//! mode on every VMRUN for the earlier of the deadline of `preemption_timer`
+//! and that of the timer the guest programs. The timer of the guest is
+//! emulated: accesses to the LVT timer register, and the initial count,
+//! current count and divide configuration registers are intercepted and served
+//! with the deadline kept in TSC ticks. When it expires, the host sends the
+//! vector of the guest to itself with SELF IPI, so that the guest receives and
+//! completes it like any other interrupt.
+//!
+//! The timer of the local APIC interrupts with the vector set with
+//! `PreemptionTimer::with_apic_timer_vector`. External interrupts cause
+//! #VMEXIT(INTR) and are taken by the host IDT, which completes and discards
+//! the interrupt with the vector. With AVIC, the others are requested in the
+//! APIC backing page as usual. Otherwise, they are left in service on the local
+//! APIC and injected into the guest, which completes them. RFLAGS.IF of the
+//! guest masks physical interrupts then, so the timer is late while the guest
+//! runs with interrupts disabled.
+//!
+//! The emulation requires the guest in the x2APIC mode, the host IDT, and no
+//! nested virtualization, and stops with the guest leaving the x2APIC mode. The
+//! TSC-deadline mode, which AMD processors do not implement, is not emulated.
+//! The frequency of the timer relative to TSC is measured on each processor as
+//! it is virtualized, for about a million TSC ticks.
+//! See: 16.4.1 APIC Timer Interrupt
+
+use bit_field::BitField;
+
+use crate::hypervisor::{
+    interrupt_virtualization::{local_apic_vectors, self_ipi, write_eois, X2APIC_ISR0},
+    msr_intercepts::MsrIntercepts,
+    percpu,
+    preemption_timer::TimerDeadline,
+    x86_instructions::{rdmsr, rdtsc, wrmsr},
+    SHARED_HOST_DATA,
+};
+
+use super::vmcb::Vmcb;
+
+/// The x2APIC MSRs of the timer.
+/// See: Table 16-2. APIC Registers
+const X2APIC_LVT_TIMER: u32 = 0x832;
+const X2APIC_INITIAL_COUNT: u32 = 0x838;
+const X2APIC_CURRENT_COUNT: u32 = 0x839;
+const X2APIC_DIVIDE_CONFIG: u32 = 0x83e;
+
+/// The bits of the LVT timer register and the divide configuration register.
+/// See: Figure 16-12. APIC Timer Local Vector Table Register
+const LVT_MASK: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+const LVT_WRITABLE: u32 = 0xff | LVT_MASK | LVT_PERIODIC;
+const DIVIDE_WRITABLE: u32 = 0b1011;
+
+/// Returns `intercepts` with the x2APIC MSRs of the timer intercepted, and
+/// writes to IA32_APIC_BASE to see the guest leaving the x2APIC mode, for the
+/// guest to emulate them. The handlers already set for them, if any, are
+/// called first. Called from the guest before any processor is virtualized.
+pub(crate) fn install_timer_emulation(mut intercepts: MsrIntercepts) -> MsrIntercepts {
+    for msr in [
+        X2APIC_LVT_TIMER,
+        X2APIC_INITIAL_COUNT,
+        X2APIC_CURRENT_COUNT,
+        X2APIC_DIVIDE_CONFIG,
+    ] {
+        let next = intercepts.take_read_handler(msr);
+        intercepts = intercepts.on_read(msr, move |vcpu, msr| {
+            next.as_ref().and_then(|handler| handler(vcpu, msr))
+        });
+    }
+    for msr in [
+        X2APIC_LVT_TIMER,
+        X2APIC_INITIAL_COUNT,
+        X2APIC_DIVIDE_CONFIG,
+        x86::msr::IA32_APIC_BASE,
+    ] {
+        let next = intercepts.take_write_handler(msr);
+        intercepts = intercepts.on_write(msr, move |vcpu, msr, value| match &next {
+            Some(handler) => handler(vcpu, msr, value),
+            None => Some(value),
+        });
+    }
+    intercepts
+}
+
+/// The emulation of the timer of the local APIC for a vCPU.
+#[derive(Debug)]
+pub(crate) struct ApicTimer {
+    /// The vector the timer of the local APIC interrupts the host with.
+    vector: u8,
+
+    /// The deadline of `preemption_timer`.
+    host: TimerDeadline,
+
+    /// The timer the guest programs.
+    guest: GuestTimer,
+
+    /// The frequency of the timer of the local APIC.
+    clock: TimerClock,
+
+    /// Whether the host injects external interrupts into the guest, without
+    /// AVIC.
+    reinjecting: bool,
+}
+
+impl ApicTimer {
+    /// Returns the emulation for the current processor if `preemption_timer`
+    /// is enabled and it is usable. See the module documentation.
+    pub(crate) fn new() -> Option<Self> {
+        let shared_host = SHARED_HOST_DATA.get().unwrap();
+        let timer = &shared_host.preemption_timer;
+        if !timer.is_enabled() {
+            return None;
+        }
+        let Some(vector) = timer.apic_timer_vector() else {
+            log::warn!("The APIC timer vector is not set. Ignoring the preemption timer");
+            return None;
+        };
+        // See: 16.11 x2APIC (Extended Local APIC)
+        if shared_host.idt.is_none()
+            || super::nested::is_enabled()
+            || !rdmsr(x86::msr::IA32_APIC_BASE).get_bit(10)
+        {
+            log::warn!(
+                "The APIC timer is emulated only with the host IDT, without nested \
+                 virtualization, and in the x2APIC mode. Ignoring the preemption timer"
+            );
+            return None;
+        }
+        Some(Self {
+            vector,
+            host: TimerDeadline::new(0, timer.period()),
+            guest: GuestTimer::RESET,
+            clock: TimerClock::new(1, 1),
+            reinjecting: false,
+        })
+    }
+
+    /// Takes over the timer of the local APIC, moving the state the guest
+    /// programmed into the emulation, and measures its frequency. Intercepts
+    /// physical interrupts without AVIC, which does otherwise. Must be called
+    /// while setting up the host.
+    pub(crate) fn initialize(&mut self, vmcb: &mut Vmcb, avic: bool) {
+        const SVM_INTERCEPT_MISC1_INTR: u32 = 1 << 0;
+        const DIVIDE_BY_1: u64 = 0b1011;
+
+        let captured = rdtsc();
+        let current_count = rdmsr(X2APIC_CURRENT_COUNT);
+        self.guest = GuestTimer {
+            lvt: rdmsr(X2APIC_LVT_TIMER) as u32 & LVT_WRITABLE,
+            initial_count: rdmsr(X2APIC_INITIAL_COUNT) as u32,
+            divide_config: rdmsr(X2APIC_DIVIDE_CONFIG) as u32 & DIVIDE_WRITABLE,
+            deadline: None,
+        };
+        self.clock = TimerClock::measure();
+        if current_count != 0 {
+            let left = self.clock.to_tsc(current_count * self.guest.divisor());
+            self.guest.deadline = Some(captured.saturating_add(left));
+        }
+        let period = SHARED_HOST_DATA.get().unwrap().preemption_timer.period();
+        self.host = TimerDeadline::new(rdtsc(), period);
+
+        // Interrupt with the vector in the one-shot mode. The timer is armed on
+        // VMRUN.
+        wrmsr(X2APIC_DIVIDE_CONFIG, DIVIDE_BY_1);
+        wrmsr(X2APIC_LVT_TIMER, u64::from(self.vector));
+
+        let percpu = percpu::current();
+        percpu.host_interrupts.set_timer_vector(Some(self.vector));
+        if !avic {
+            self.reinjecting = true;
+            percpu.host_interrupts.set_reinjecting(true);
+            percpu.host_interrupts.set_accepting(true);
+            vmcb.set_intercept_misc1(vmcb.intercept_misc1() | SVM_INTERCEPT_MISC1_INTR);
+        }
+    }
+
+    /// Delivers the interrupt of the timer of the guest if it expired, and arms
+    /// the timer of the local APIC for the earlier of the deadlines. Called
+    /// right before VMRUN.
+    pub(crate) fn arm(&mut self) {
+        let now = rdtsc();
+        if let Some(vector) = self.guest.expire(now, self.clock) {
+            self_ipi(vector);
+        }
+        let deadline = self
+            .guest
+            .deadline
+            .map_or(self.host.deadline(), |deadline| {
+                deadline.min(self.host.deadline())
+            });
+        let count = self
+            .clock
+            .to_ticks(deadline.saturating_sub(now))
+            .clamp(1, u64::from(u32::MAX));
+        wrmsr(X2APIC_INITIAL_COUNT, count);
+    }
+
+    /// Returns whether the deadline of `preemption_timer` passed, and starts
+    /// the next period if so. Called on #VMEXIT(INTR), which the timer of the
+    /// local APIC causes.
+    pub(crate) fn take_expiry(&mut self) -> bool {
+        let now = rdtsc();
+        if now < self.host.deadline() {
+            return false;
+        }
+        self.host.restart(now);
+        true
+    }
+
+    /// Returns the value of `msr` the guest reads, or `None` if `msr` is not
+    /// emulated.
+    pub(crate) fn read(&self, msr: u32) -> Option<u64> {
+        let value = match msr {
+            X2APIC_LVT_TIMER => self.guest.lvt,
+            X2APIC_INITIAL_COUNT => self.guest.initial_count,
+            X2APIC_CURRENT_COUNT => self.guest.current_count(rdtsc(), self.clock),
+            X2APIC_DIVIDE_CONFIG => self.guest.divide_config,
+            _ => return None,
+        };
+        Some(u64::from(value))
+    }
+
+    /// Emulates the write of `value` to `msr` by the guest. Returns `false` if
+    /// `msr` is not emulated.
+    pub(crate) fn write(&mut self, msr: u32, value: u64) -> bool {
+        let value = value as u32;
+        match msr {
+            X2APIC_LVT_TIMER => self.guest.lvt = value & LVT_WRITABLE,
+            X2APIC_INITIAL_COUNT => self.guest.start(value, rdtsc(), self.clock),
+            X2APIC_DIVIDE_CONFIG => self.guest.divide_config = value & DIVIDE_WRITABLE,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Resets the timer of the guest on INIT. The interrupts injected but not
+    /// completed by the guest stay in service on the local APIC, as INIT is
+    /// converted to #SX. Complete them.
+    pub(crate) fn reset(&mut self) {
+        self.guest = GuestTimer::RESET;
+        if self.reinjecting {
+            write_eois(local_apic_vectors(X2APIC_ISR0).iter().count());
+        }
+    }
+
+    /// Stops the emulation, returning the timer of the local APIC to the guest
+    /// as it programmed the emulated one. For the guest leaving the x2APIC mode
+    /// and devirtualization.
+    pub(crate) fn deactivate(&self, vmcb: &mut Vmcb) {
+        const SVM_INTERCEPT_MISC1_INTR: u32 = 1 << 0;
+
+        wrmsr(X2APIC_INITIAL_COUNT, 0);
+        wrmsr(X2APIC_LVT_TIMER, u64::from(self.guest.lvt));
+        wrmsr(X2APIC_DIVIDE_CONFIG, u64::from(self.guest.divide_config));
+        let count = if self.guest.lvt & LVT_PERIODIC != 0 {
+            self.guest.initial_count
+        } else {
+            self.guest.current_count(rdtsc(), self.clock)
+        };
+        wrmsr(X2APIC_INITIAL_COUNT, u64::from(count));
+
+        let percpu = percpu::current();
+        percpu.host_interrupts.set_timer_vector(None);
+        if self.reinjecting {
+            percpu.host_interrupts.set_reinjecting(false);
+            percpu.host_interrupts.set_accepting(false);
+            vmcb.set_intercept_misc1(vmcb.intercept_misc1() & !SVM_INTERCEPT_MISC1_INTR);
+        }
+    }
+}
+
+/// The frequency of the timer of the local APIC without division, as TSC ticks
+/// per tick of the timer in the 32.32 fixed-point format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TimerClock(u64);
+
+impl TimerClock {
+    /// Returns the frequency at which the timer counts `ticks` in `tsc` TSC
+    /// ticks.
+    fn new(tsc: u64, ticks: u64) -> Self {
+        let value = (u128::from(tsc) << 32) / u128::from(ticks.max(1));
+        Self(u64::try_from(value).unwrap_or(u64::MAX).max(1))
+    }
+
+    /// Measures the frequency, counting down with the timer masked. Leaves the
+    /// timer stopped.
+    fn measure() -> Self {
+        const MEASURED_TSC_TICKS: u64 = 1 << 20;
+        const DIVIDE_BY_1: u64 = 0b1011;
+
+        wrmsr(X2APIC_LVT_TIMER, u64::from(LVT_MASK));
+        wrmsr(X2APIC_DIVIDE_CONFIG, DIVIDE_BY_1);
+        wrmsr(X2APIC_INITIAL_COUNT, u64::from(u32::MAX));
+        let start = rdtsc();
+        while rdtsc() - start < MEASURED_TSC_TICKS {
+            core::hint::spin_loop();
+        }
+        let ticks = u64::from(u32::MAX) - rdmsr(X2APIC_CURRENT_COUNT);
+        let elapsed = rdtsc() - start;
+        wrmsr(X2APIC_INITIAL_COUNT, 0);
+        Self::new(elapsed, ticks)
+    }
+
+    /// Converts `ticks` of the timer into TSC ticks.
+    fn to_tsc(self, ticks: u64) -> u64 {
+        let value = (u128::from(ticks) * u128::from(self.0)) >> 32;
+        u64::try_from(value).unwrap_or(u64::MAX)
+    }
+
+    /// Converts `tsc` TSC ticks into ticks of the timer, rounded up so that
+    /// the timer never expires early.
+    fn to_ticks(self, tsc: u64) -> u64 {
+        let value = (u128::from(tsc) << 32).div_ceil(u128::from(self.0));
+        u64::try_from(value).unwrap_or(u64::MAX)
+    }
+}
+
+/// The timer of the local APIC as the guest programs it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct GuestTimer {
+    lvt: u32,
+    initial_count: u32,
+    divide_config: u32,
+
+    /// The TSC at which the current count reaches zero, if counting.
+    deadline: Option<u64>,
+}
+
+impl GuestTimer {
+    /// The state after reset, which is masked and not counting.
+    const RESET: Self = Self {
+        lvt: LVT_MASK,
+        initial_count: 0,
+        divide_config: 0,
+        deadline: None,
+    };
+
+    /// Returns the divisor of the divide configuration, of the bits 3 and 1:0.
+    /// See: Figure 16-13. APIC Timer Divide Configuration Register
+    fn divisor(self) -> u64 {
+        let value =
+            self.divide_config.get_bits(0..=1) | u32::from(self.divide_config.get_bit(3)) << 2;
+        if value == 0b111 {
+            1
+        } else {
+            2 << value
+        }
+    }
+
+    /// Returns the period in TSC ticks, that is, the time to count down from
+    /// the initial count.
+    fn period(self, clock: TimerClock) -> u64 {
+        clock.to_tsc(u64::from(self.initial_count) * self.divisor())
+    }
+
+    /// Starts counting down from `initial_count` at `now`. Zero stops the
+    /// timer.
+    fn start(&mut self, initial_count: u32, now: u64, clock: TimerClock) {
+        self.initial_count = initial_count;
+        self.deadline = (initial_count != 0).then(|| now.saturating_add(self.period(clock)));
+    }
+
+    /// Returns the current count at `now`. In the periodic mode, the count
+    /// reloads from the initial count on reaching zero, even if the expiry is
+    /// not yet processed.
+    fn current_count(self, now: u64, clock: TimerClock) -> u32 {
+        let Some(deadline) = self.deadline else {
+            return 0;
+        };
+        let period = self.period(clock);
+        let left = if now < deadline {
+            deadline - now
+        } else if self.lvt & LVT_PERIODIC != 0 && period != 0 {
+            period - (now - deadline) % period
+        } else {
+            0
+        };
+        let count = clock.to_ticks(left) / self.divisor();
+        u32::try_from(count).unwrap_or(u32::MAX)
+    }
+
+    /// Processes the expiry of the timer at `now`, if any, reloading it in the
+    /// periodic mode. Returns the vector to interrupt the guest with, unless
+    /// masked or not valid.
+    fn expire(&mut self, now: u64, clock: TimerClock) -> Option<u8> {
+        let deadline = self.deadline?;
+        if now < deadline {
+            return None;
+        }
+        self.deadline = if self.lvt & LVT_PERIODIC != 0 {
+            Some(now.saturating_add(self.period(clock)))
+        } else {
+            None
+        };
+        let vector = self.lvt.get_bits(0..=7) as u8;
+        (self.lvt & LVT_MASK == 0 && vector >= 16).then_some(vector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_converts_both_ways() {
+        let clock = TimerClock::new(3_000, 100);
+        assert_eq!(clock.to_tsc(100), 3_000);
+        assert_eq!(clock.to_ticks(3_000), 100);
+        assert_eq!(clock.to_ticks(3_001), 101);
+    }
+
+    #[test]
+    fn guest_timer_counts_down_and_reloads() {
+        let clock = TimerClock::new(2, 1);
+        let mut timer = GuestTimer {
+            lvt: 0x40,
+            // Divide by 4.
+            divide_config: 0b0001,
+            ..GuestTimer::RESET
+        };
+        timer.start(10, 1000, clock);
+        assert_eq!(timer.current_count(1000, clock), 10);
+        assert_eq!(timer.current_count(1040, clock), 5);
+        assert_eq!(timer.expire(1079, clock), None);
+        assert_eq!(timer.expire(1080, clock), Some(0x40));
+        assert_eq!(timer.current_count(1080, clock), 0);
+        assert_eq!(timer.expire(2000, clock), None);
+
+        timer.lvt |= LVT_PERIODIC;
+        timer.start(10, 1000, clock);
+        assert_eq!(timer.current_count(1100, clock), 7);
+        assert_eq!(timer.expire(1100, clock), Some(0x40));
+        assert_eq!(timer.deadline, Some(1180));
+
+        timer.lvt |= LVT_MASK;
+        assert_eq!(timer.expire(1180, clock), None);
+        timer.start(0, 1200, clock);
+        assert_eq!(timer.deadline, None);
+    }
+}
@@ -0,0 +1,144 @@
+//! This module implements allocation of address space identifiers (ASIDs).
+//!
+//! The TLB entries of the guest are tagged with its ASID, while the host runs
+//! with the ASID 0. Each processor gets its own ASID, within the number of
+//! ASIDs the processor supports, so that entries of one guest are never used
+//! for another, for example, on the other thread of the same core. The entries
+//! are flushed only for the ASID if the processor supports FlushByAsid, instead
+//! of the entire TLB. With nested virtualization, nested guests get ASIDs from
+//! a separate range, so that their entries are never used for the processors.
+//! See: 15.16 TLB Control
+
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+
+use crate::hypervisor::amd::vmcb::TlbControl;
+
+/// The ASID of a guest.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Asid {
+    value: u32,
+    flush_by_asid: bool,
+
+    /// Whether the ASID is also that of the processor, which is only the case
+    /// for the nested guest when a single ASID is supported for guests. See
+    /// `allocate_nested`.
+    shared: bool,
+}
+
+impl Asid {
+    /// Allocates the ASID of the processor `id`.
+    pub(crate) fn allocate(id: usize) -> Self {
+        // See: Appendix E.4.10 Function 8000_000Ah—SVM Features
+        let svm_features = cpuid!(0x8000_000a);
+        let reserved = if super::nested::is_enabled() {
+            nested_asids(svm_features.ebx)
+        } else {
+            0
+        };
+        let value = asid_of(id, svm_features.ebx, reserved);
+        if value as usize != id + 1 {
+            log::debug!("The processor {id} shares the ASID {value}");
+        }
+        Self {
+            value,
+            flush_by_asid: svm_features.edx.get_bit(6),
+            shared: false,
+        }
+    }
+
+    /// Allocates the ASID of the nested guest of the processor `id`, from the
+    /// ASIDs reserved for nested guests, so that it is never an ASID of the
+    /// processors. If no ASID can be reserved, the ASID of the processor is
+    /// shared, and the TLB entries of the ASID need to be flushed whenever the
+    /// processor switches between the guest and the nested guest. See `nested`.
+    pub(crate) fn allocate_nested(id: usize) -> Self {
+        let svm_features = cpuid!(0x8000_000a);
+        let nested = nested_asid_of(id, svm_features.ebx);
+        if nested.is_none() {
+            log::debug!("The nested guest of the processor {id} shares the ASID");
+        }
+        Self {
+            value: nested.unwrap_or_else(|| asid_of(id, svm_features.ebx, 0)),
+            flush_by_asid: svm_features.edx.get_bit(6),
+            shared: nested.is_none(),
+        }
+    }
+
+    /// Returns the value to run the guest with.
+    pub(crate) fn value(self) -> u32 {
+        self.value
+    }
+
+    /// Returns whether the ASID is also that of the processor. See `shared`.
+    pub(crate) fn is_shared(self) -> bool {
+        self.shared
+    }
+
+    /// Returns the TLB control flushing the TLB entries of the guest: only
+    /// those for the ASID if supported, or the entire TLB otherwise.
+    pub(crate) fn flush_control(self) -> TlbControl {
+        if self.flush_by_asid {
+            TlbControl::FlushGuests
+        } else {
+            TlbControl::FlushAll
+        }
+    }
+}
+
+/// Returns the ASID of the processor `id` when ASIDs from 0 to `count` - 1 are
+/// supported, and the highest `reserved` of them are for nested guests. The
+/// ASID 0 is for the host, and the others are reused once all are allocated.
+fn asid_of(id: usize, count: u32, reserved: u32) -> u32 {
+    let guest_asids = count.saturating_sub(1).saturating_sub(reserved).max(1);
+    (id % guest_asids as usize) as u32 + 1
+}
+
+/// Returns how many of the ASIDs from 0 to `count` - 1 are reserved for nested
+/// guests: half of those for guests, rounded down, so that none is left when
+/// only one is supported for guests.
+fn nested_asids(count: u32) -> u32 {
+    count.saturating_sub(1) / 2
+}
+
+/// Returns the ASID of the nested guest of the processor `id` when ASIDs from
+/// 0 to `count` - 1 are supported, or `None` if none is reserved for nested
+/// guests. The reserved ASIDs are reused once all are allocated.
+fn nested_asid_of(id: usize, count: u32) -> Option<u32> {
+    let reserved = nested_asids(count);
+    (reserved != 0).then(|| count - reserved + (id % reserved as usize) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asids_skip_host_and_wrap() {
+        assert_eq!(asid_of(0, 8, 0), 1);
+        assert_eq!(asid_of(6, 8, 0), 7);
+        assert_eq!(asid_of(7, 8, 0), 1);
+        assert_eq!(asid_of(3, 0, 0), 1);
+    }
+
+    #[test]
+    fn nested_asids_are_never_those_of_processors() {
+        // 3 of the 7 ASIDs for guests are reserved.
+        let reserved = nested_asids(8);
+        assert_eq!(reserved, 3);
+        for id in 0..16 {
+            let asid = asid_of(id, 8, reserved);
+            let nested = nested_asid_of(id, 8).unwrap();
+            assert!((1..=4).contains(&asid));
+            assert!((5..=7).contains(&nested));
+        }
+        assert_eq!(nested_asid_of(3, 8), Some(5));
+
+        // The only ASID for guests is shared.
+        assert_eq!(nested_asids(2), 0);
+        assert_eq!(nested_asid_of(0, 2), None);
+        assert_eq!(asid_of(1, 2, 0), 1);
+        assert_eq!(nested_asid_of(0, 3), Some(2));
+        assert_eq!(asid_of(0, 3, nested_asids(3)), 1);
+    }
+}
@@ -0,0 +1,356 @@
+//! This module implements the Advanced Virtual Interrupt Controller (AVIC) of
+//! SVM, the AMD counterpart of APICv, for `interrupt_virtualization`.
+//!
+//! With AVIC in the x2APIC mode (x2AVIC), the processor delivers the interrupts
+//! requested in the APIC backing page to the guest, in the order of their
+//! priority against the virtual TPR, and virtualizes accesses to TPR, PPR, ISR
+//! and IRR, and EOI. EOIs of the interrupts marked level-triggered in the
+//! backing page cause #VMEXIT(AVIC_NOACCEL) after they take effect. The other
+//! processors request the interrupts posted to the processor in the backing
+//! page, and notify it with the AVIC doorbell while it runs the guest.
+//!
+//! Unlike with APICv, the processor does not acknowledge external interrupts on
+//! #VMEXIT. They are intercepted with the INTR intercept and taken by the host
+//! IDT with RFLAGS.IF and GIF set momentarily. To make them cause #VMEXIT
+//! regardless of the guest RFLAGS.IF, V_INTR_MASKING is set and the host runs
+//! the guest with RFLAGS.IF set, which GIF cleared keeps from taking effect in
+//! the host. The IPIs the guest sends are not accelerated: the ICR writes are
+//! intercepted, and the physical and logical APIC ID tables are left empty.
+//! See: 15.29 Advanced Virtual Interrupt Controller
+//! See: 15.21.1 Virtual Interrupt Masking
+
+use core::{
+    arch::asm,
+    sync::atomic::{fence, Ordering},
+};
+
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+
+use crate::hypervisor::{
+    interrupt_virtualization::{
+        is_level_triggered, local_apic_vectors, self_ipi, write_eois, DeferredEois,
+        VirtualApicPage, X2APIC_EOI, X2APIC_IRR0, X2APIC_ISR0, X2APIC_PPR, X2APIC_TPR,
+    },
+    percpu,
+    support::{Page, PageBox},
+    x86_instructions::{rdmsr, wrmsr},
+    HvError, SHARED_HOST_DATA,
+};
+
+use super::vmcb::Vmcb;
+
+/// The AVIC features the processor supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct AvicSupport {
+    pub(crate) avic: bool,
+    pub(crate) x2avic: bool,
+}
+
+impl AvicSupport {
+    /// Returns the features the current processor supports.
+    /// See: E.4.10 Function 8000_000Ah—SVM Revision and Feature Identification
+    pub(crate) fn detect() -> Self {
+        let svm_features = cpuid!(0x8000_000a).edx;
+        Self {
+            avic: svm_features.get_bit(13),
+            x2avic: svm_features.get_bit(18),
+        }
+    }
+}
+
+/// Logs the AVIC features of the current processor.
+pub(crate) fn report_support() {
+    let support = AvicSupport::detect();
+    let enabled = SHARED_HOST_DATA.get().is_some_and(|shared_host| {
+        shared_host
+            .interrupt_virtualization
+            .notification_vector()
+            .is_some()
+    });
+    if !support.avic {
+        log::debug!("AVIC is not supported");
+    } else if !support.x2avic {
+        log::debug!("AVIC is supported without x2AVIC, which is required");
+    } else if enabled {
+        log::debug!("AVIC is supported and used for the guest in the x2APIC mode");
+    } else {
+        log::debug!("AVIC is supported but not enabled");
+    }
+}
+
+/// The AVIC state of a vCPU.
+pub(crate) struct Avic {
+    /// The APIC backing page.
+    backing_page: PageBox<VirtualApicPage>,
+
+    /// The physical APIC ID table, whose only entry is not valid.
+    physical_table: PageBox<Page>,
+
+    /// The logical APIC ID table, whose entries are not valid.
+    logical_table: PageBox<Page>,
+
+    /// The MSR permissions map of the vCPU, which is the shared one with the
+    /// x2APIC MSRs virtualized.
+    msrpm: PageBox<[Page; 2]>,
+
+    /// The level-triggered interrupts whose EOIs cause #VMEXIT.
+    eois: DeferredEois,
+}
+
+impl Avic {
+    /// Returns the state for the current processor if enabled with
+    /// `SharedHostData::interrupt_virtualization` and usable, with the MSR
+    /// permissions map built from `shared_msrpm`. See `interrupt_virtualization`
+    /// for when it is not usable.
+    pub(crate) fn new(shared_msrpm: &[Page; 2]) -> Result<Option<Self>, HvError> {
+        let shared_host = SHARED_HOST_DATA.get().unwrap();
+        if shared_host
+            .interrupt_virtualization
+            .notification_vector()
+            .is_none()
+        {
+            return Ok(None);
+        }
+        // The AVIC backing page of L1 would have to be kept out of VMCB02 and
+        // synchronized around each VMRUN of L2. See `nested`.
+        if super::nested::is_enabled() {
+            log::warn!("AVIC is not used with nested virtualization");
+            return Ok(None);
+        }
+
+        // Handlers of the x2APIC MSRs are called only if the accesses are
+        // intercepted, such as the one for the ICR emulating SIPIs.
+        let x2apic_handled = (0x800..=0x8ff).any(|msr| {
+            (is_virtualized_read(msr) && shared_host.msr_intercepts.read_handler(msr).is_some())
+                || (is_virtualized_write(msr)
+                    && shared_host.msr_intercepts.write_handler(msr).is_some())
+        });
+        // See: 16.11 x2APIC (Extended Local APIC)
+        let x2apic_mode = rdmsr(x86::msr::IA32_APIC_BASE).get_bit(10);
+        let support = AvicSupport::detect();
+        if !support.avic
+            || !support.x2avic
+            || shared_host.idt.is_none()
+            || shared_host.apic_virt.is_enabled()
+            || x2apic_handled
+            || !x2apic_mode
+        {
+            return Ok(None);
+        }
+
+        // Accesses to the x2APIC MSRs are virtualized unless intercepted.
+        // Intercept those the local APIC serves, so that the guest accesses
+        // them on the local APIC with #VMEXIT, as well as writes to
+        // IA32_APIC_BASE to see the guest leaving the x2APIC mode. Each MSR
+        // takes two bits: the even bit for read and the odd bit for write.
+        // See: 15.11 MSR Intercepts
+        let mut msrpm = PageBox::<[Page; 2]>::try_new()?;
+        msrpm[0].0.copy_from_slice(&shared_msrpm[0].0);
+        msrpm[1].0.copy_from_slice(&shared_msrpm[1].0);
+        let msrpm_bytes = unsafe { &mut *msrpm.as_mut_ptr().cast::<[u8; 0x2000]>() };
+        let mut set_bit = |bit: usize, intercept: bool| {
+            let _ = msrpm_bytes[bit / 8].set_bit(bit % 8, intercept);
+        };
+        for msr in 0x800..=0x8ff {
+            set_bit(msr as usize * 2, !is_virtualized_read(msr));
+            set_bit(msr as usize * 2 + 1, !is_virtualized_write(msr));
+        }
+        set_bit(x86::msr::IA32_APIC_BASE as usize * 2 + 1, true);
+
+        Ok(Some(Self {
+            backing_page: PageBox::try_new()?,
+            physical_table: PageBox::try_new()?,
+            logical_table: PageBox::try_new()?,
+            msrpm,
+            eois: DeferredEois::default(),
+        }))
+    }
+
+    /// Initializes the VMCB fields for AVIC, and moves the TPR and the
+    /// interrupts in service of the local APIC into the backing page. Those in
+    /// service are completed on the local APIC when the guest completes them,
+    /// as when the processor is virtualized from an interrupt handler. Must be
+    /// called while setting up the host.
+    pub(crate) fn initialize(&mut self, vmcb: &mut Vmcb) {
+        const SVM_INTERCEPT_MISC1_INTR: u32 = 1 << 0;
+        const SVM_INTERCEPT_MISC1_MSR_PROT: u32 = 1 << 28;
+        const V_TPR: u64 = 0xff;
+        const V_INTR_MASKING: u64 = 1 << 24;
+        const X2AVIC_ENABLE: u64 = 1 << 30;
+        const AVIC_ENABLE: u64 = 1 << 31;
+
+        let tpr = rdmsr(X2APIC_TPR) as u32;
+        self.backing_page.set_tpr(tpr);
+        wrmsr(X2APIC_TPR, 0);
+        let in_service = local_apic_vectors(X2APIC_ISR0);
+        self.backing_page.set_in_service(in_service);
+        for vector in in_service.iter() {
+            self.eois.defer(vector);
+            self.backing_page.set_level_triggered(vector, true);
+        }
+
+        // The physical APIC ID table holds entries up to the index in the bits
+        // 7:0 of the pointer, which is 0.
+        // See: Appendix B Layout of VMCB
+        vmcb.set_avic_apic_bar(rdmsr(x86::msr::IA32_APIC_BASE) & 0x000f_ffff_ffff_f000);
+        vmcb.set_avic_apic_backing_page_pointer(self.backing_page.pa());
+        vmcb.set_avic_logical_table_pointer(self.logical_table.pa());
+        vmcb.set_avic_physical_table_pointer(self.physical_table.pa());
+        vmcb.set_vintr(
+            vmcb.vintr() & !V_TPR
+                | u64::from(tpr >> 4) & V_TPR
+                | V_INTR_MASKING
+                | X2AVIC_ENABLE
+                | AVIC_ENABLE,
+        );
+        vmcb.set_msrpm_base_pa(self.msrpm.pa());
+        vmcb.set_intercept_misc1(
+            vmcb.intercept_misc1() | SVM_INTERCEPT_MISC1_INTR | SVM_INTERCEPT_MISC1_MSR_PROT,
+        );
+
+        let percpu = percpu::current();
+        percpu.host_interrupts.set_accepting(true);
+        percpu
+            .posted_interrupts
+            .enable_backing_page(&self.backing_page);
+    }
+
+    /// Runs the guest with `run`, with RFLAGS.IF set so that physical
+    /// interrupts cause #VMEXIT(INTR) while the guest runs. GIF, which is
+    /// cleared in the host, holds them pending until VMRUN.
+    pub(crate) fn run_guest(run: impl FnOnce()) {
+        // Order IRR loaded on VMRUN after the heartbeat marking the processor
+        // out of the host. See `PostedInterrupts::post`.
+        fence(Ordering::SeqCst);
+        unsafe { asm!("sti", options(nomem, nostack)) };
+        run();
+        unsafe { asm!("cli", options(nomem, nostack)) };
+    }
+
+    /// Requests `vector` in the backing page, to be delivered to the guest once
+    /// it can receive it. The processor evaluates IRR on VMRUN.
+    pub(crate) fn request(&mut self, vector: u8) {
+        self.backing_page.request(vector);
+    }
+
+    /// Handles #VMEXIT(INTR). Takes the external interrupts pending by the host
+    /// IDT and requests them in the backing page. They are completed on the
+    /// local APIC unless level-triggered, in which case they are marked so in
+    /// the backing page. See `interrupt_handlers::handle_host_exception`.
+    pub(crate) fn handle_interrupt(&mut self) {
+        // NMIs pending are taken too. The caller delivers them to the guest.
+        unsafe { asm!("stgi", "sti", "nop", "cli", "clgi", options(nostack)) };
+        for vector in percpu::current().host_interrupts.take().iter() {
+            let level = is_level_triggered(vector);
+            if level {
+                self.eois.defer(vector);
+            }
+            self.backing_page.set_level_triggered(vector, level);
+            self.backing_page.request(vector);
+        }
+    }
+
+    /// Handles #VMEXIT(AVIC_NOACCEL), which is only for EOI of a level-triggered
+    /// interrupt as the other accesses are intercepted or accelerated,
+    /// completing it on the local APIC.
+    pub(crate) fn handle_unaccelerated_access(&mut self, vmcb: &Vmcb) {
+        const APIC_EOI: u64 = 0xb0;
+
+        // EXITINFO1 has the offset of the register in the bits 11:4, and
+        // whether the access is a write in the bit 32.
+        let exit_info1 = vmcb.exit_info1();
+        let offset = exit_info1 & 0xff0;
+        assert!(
+            offset == APIC_EOI && exit_info1.get_bit(32),
+            "Unaccelerated access to the APIC register {offset:#x} is not supported"
+        );
+
+        // EOI is a trap, taking effect on the backing page before #VMEXIT. The
+        // interrupts the guest completed are the ones neither in service nor
+        // requested anymore. Clear the one in service if EOI did not.
+        let in_service = self.backing_page.in_service();
+        let requested = self.backing_page.requested();
+        let outstanding = self.eois.outstanding();
+        let mut completed = outstanding
+            .iter()
+            .filter(|&vector| !in_service.contains(vector) && !requested.contains(vector))
+            .peekable();
+        if completed.peek().is_none() {
+            if let Some(vector) = in_service
+                .highest()
+                .filter(|&vector| outstanding.contains(vector))
+            {
+                self.backing_page.clear_in_service(vector);
+                self.complete(vector);
+            }
+            return;
+        }
+        for vector in completed {
+            self.complete(vector);
+        }
+    }
+
+    /// Resets the backing page on INIT, completing the interrupts the EOIs of
+    /// which are deferred on the local APIC.
+    pub(crate) fn reset(&mut self, vmcb: &mut Vmcb) {
+        const V_TPR: u64 = 0xff;
+
+        write_eois(self.eois.take_all());
+        self.backing_page.reset();
+        vmcb.set_vintr(vmcb.vintr() & !V_TPR);
+    }
+
+    /// Stops posting to the processor, and moves the state of the backing page
+    /// back into the local APIC, for the guest to use the local APIC again.
+    ///
+    /// The interrupts requested are requested on the local APIC with self-IPIs,
+    /// and the level-triggered ones are completed on the local APIC, which the
+    /// I/O APIC delivers again if still asserted. The interrupts in service in
+    /// the guest are not in service on the local APIC, where EOI is ignored
+    /// without one in service.
+    pub(crate) fn deactivate(&mut self) {
+        let percpu = percpu::current();
+        let _ = percpu.posted_interrupts.disable();
+        percpu.host_interrupts.set_accepting(false);
+        let deferred = self.eois.deferred();
+        write_eois(self.eois.take_all());
+        for vector in self
+            .backing_page
+            .requested()
+            .iter()
+            .filter(|&vector| !deferred.contains(vector))
+        {
+            self_ipi(vector);
+        }
+        wrmsr(X2APIC_TPR, u64::from(self.backing_page.tpr()));
+    }
+
+    /// Completes `vector` the guest completed, on the local APIC once it
+    /// completed the higher ones.
+    fn complete(&mut self, vector: u8) {
+        self.backing_page.set_level_triggered(vector, false);
+        write_eois(self.eois.complete(vector));
+    }
+}
+
+impl Drop for Avic {
+    fn drop(&mut self) {
+        // The backing page is no longer posted to.
+        let _ = percpu::current().posted_interrupts.disable();
+    }
+}
+
+/// Returns whether reads of the x2APIC MSR `msr` are virtualized from the
+/// backing page.
+fn is_virtualized_read(msr: u32) -> bool {
+    matches!(msr, X2APIC_TPR | X2APIC_PPR)
+        || (X2APIC_ISR0..X2APIC_ISR0 + 8).contains(&msr)
+        || (X2APIC_IRR0..X2APIC_IRR0 + 8).contains(&msr)
+}
+
+/// Returns whether writes to the x2APIC MSR `msr` are virtualized. SELF IPI
+/// is not, to leave the IPIs of the guest to the local APIC.
+fn is_virtualized_write(msr: u32) -> bool {
+    matches!(msr, X2APIC_TPR | X2APIC_EOI)
+}
@@ -2,31 +2,60 @@
 
 use core::{
     arch::{asm, global_asm},
-    ptr::addr_of,
     sync::atomic::{AtomicU8, Ordering},
 };
 
 use alloc::boxed::Box;
 use bit_field::BitField;
-use spin::{Lazy, RwLock};
+use spin::{Once, RwLock};
 use x86::{
     bits64::{paging::BASE_PAGE_SHIFT, rflags::RFlags},
-    controlregs::cr3_write,
+    controlregs::{cr3_write, Cr4},
     cpuid::cpuid,
+    dtables::DescriptorTablePointer,
     segmentation::{cs, ds, es, ss},
 };
 
 use crate::hypervisor::{
-    apic_id,
-    host::{Guest, InstructionInfo, VmExitReason},
-    platform_ops,
-    registers::Registers,
-    support::zeroed_box,
-    x86_instructions::{cr0, cr3, cr4, lidt, rdmsr, sgdt, sidt, wrmsr},
-    SHARED_HOST_DATA,
+    apic_id, breakpoint_marker, cet,
+    dirty_tracking::{DirtyBitmap, DirtyTrackingError},
+    ept_hook,
+    event::{self, Event, InterruptQueue},
+    exit_trace::RawExitInfo,
+    hidden_memory,
+    host::{
+        CrWriteInfo, DrAccessInfo, GpaMapping, Guest, GuestSegment, GuestSystemState,
+        InstructionInfo, IoInstructionInfo, NestedPageFaultInfo, SegmentRegister, Vcpu,
+        VmExitReason,
+    },
+    host_window,
+    hw_breakpoint::{self, DebugState},
+    instruction_decoder,
+    interrupt_handlers::take_host_nmi,
+    long_mode,
+    memory_protection::{self, Permissions, ViolationAction},
+    msr_intercepts::MsrIntercepts,
+    percpu, platform_ops,
+    registers::{is_xsave_supported, ExtendedRegisters, Registers},
+    single_step::{SingleStep, SingleStepCallback, SingleStepError},
+    smm,
+    support::{Page, PageBox},
+    tlb::{self, FlushScope},
+    tsc::TscCompensation,
+    virtualization_exception::VeError,
+    x86_instructions::{cr0, cr3, cr4, cr4_write, dr, dr_write, lidt, rdmsr, sgdt, sidt, wrmsr},
+    HvError, SHARED_HOST_DATA,
 };
 
-use super::npts::NestedPageTables;
+use super::{
+    apic_timer::ApicTimer,
+    asid::Asid,
+    avic::Avic,
+    nested::{self, Emulation, NestedSvm, Route},
+    npts::{NestedPageTables, StepTables},
+    vmcb::{TlbControl, Vmcb},
+    vmcb_checks,
+};
 
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
@@ -40,30 +69,306 @@ pub(crate) struct SvmGuest {
     #[derivative(Debug = "ignore")]
     host_state: HostStateArea,
     activity_state: &'static AtomicU8,
+
+    /// The generation of the hooks last applied onto the NPTs by this processor.
+    hook_generation: u64,
+
+    /// Whether the guest currently runs with the hook view NPT.
+    hook_view_active: bool,
+
+    /// The generation of the protections last applied onto the NPTs by this
+    /// processor.
+    protection_generation: u64,
+
+    /// The NPT lifting the protections for the instruction being
+    /// single-stepped on this processor. See `allow_access_once`.
+    #[derivative(Debug = "ignore")]
+    step_tables: StepTables,
+
+    /// Whether this processor hid the memory of the hypervisor in the NPTs.
+    host_memory_hidden: bool,
+
+    /// The TSC compensation for the time spent in the host.
+    tsc: TscCompensation,
+
+    /// The extended registers switched with the host's, if requested.
+    extended: Option<ExtendedRegisters>,
+
+    /// The external interrupts to be injected into the guest.
+    interrupts: InterruptQueue,
+
+    /// The single-step requested on this vCPU, performed with RFLAGS.TF.
+    single_step: SingleStep,
+
+    /// The guest RFLAGS.TF and DR6.BS before single-stepping.
+    saved_tf_and_bs: (bool, bool),
+
+    /// The debug registers virtualized for `hw_breakpoint`.
+    debug: DebugState,
+
+    /// The generation of the breakpoint markers last applied onto the
+    /// exception intercepts by this processor.
+    marker_generation: u64,
+
+    /// The ASID tagging the TLB entries of the guest.
+    asid: Asid,
+
+    /// The AVIC state with `SharedHostData::interrupt_virtualization`. See
+    /// `avic`.
+    #[derivative(Debug = "ignore")]
+    avic: Option<Avic>,
+
+    /// The emulation of the timer of the local APIC for
+    /// `SharedHostData::preemption_timer`. See `apic_timer`.
+    apic_timer: Option<ApicTimer>,
+
+    /// The value of VM_HSAVE_PA the guest set with nested virtualization.
+    hsave_pa: u64,
+
+    /// The SVM state of the guest while it sets EFER.SVME with nested
+    /// virtualization. See `nested`.
+    #[derivative(Debug = "ignore")]
+    nested: Option<Box<NestedSvm>>,
+}
+
+impl Vcpu for SvmGuest {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn regs(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    fn cpl(&self) -> u8 {
+        self.vmcb.cpl()
+    }
+
+    fn cr0(&self) -> u64 {
+        self.vmcb.cr0()
+    }
+
+    fn cr3(&self) -> u64 {
+        self.vmcb.cr3()
+    }
+
+    fn cr4(&self) -> u64 {
+        self.vmcb.cr4()
+    }
+
+    fn efer(&self) -> u64 {
+        // EFER.SVME is set only for SVM operation. Do not expose it unless the
+        // guest set it with nested virtualization.
+        const EFER_SVME: u64 = 1 << 12;
+        if self.nested.as_ref().is_some_and(|nested| !nested.in_l2()) {
+            self.vmcb.efer()
+        } else {
+            self.vmcb.efer() & !EFER_SVME
+        }
+    }
+
+    fn segment(&self, register: SegmentRegister) -> GuestSegment {
+        self.vmcb.segment(register)
+    }
+
+    fn read_msr(&self, msr: u32) -> u64 {
+        const R_INIT: u64 = 1 << 1;
+
+        if let Some(value) = self.apic_timer.as_ref().and_then(|timer| timer.read(msr)) {
+            return value;
+        }
+
+        // Some MSRs are held in the VMCB while the guest runs. Read the guest
+        // values from there. The registers VMSAVE saves are up to date since
+        // `run_svm_guest` executes it right after #VMEXIT.
+        let vmcb = &self.vmcb;
+        match msr {
+            x86::msr::IA32_EFER => self.efer(),
+            x86::msr::IA32_STAR => vmcb.star(),
+            x86::msr::IA32_LSTAR => vmcb.lstar(),
+            x86::msr::IA32_CSTAR => vmcb.cstar(),
+            x86::msr::IA32_FMASK => vmcb.sf_mask(),
+            x86::msr::IA32_FS_BASE => vmcb.fs_base(),
+            x86::msr::IA32_GS_BASE => vmcb.gs_base(),
+            x86::msr::IA32_KERNEL_GSBASE => vmcb.kernel_gs_base(),
+            x86::msr::IA32_SYSENTER_CS => vmcb.sysenter_cs(),
+            x86::msr::IA32_SYSENTER_ESP => vmcb.sysenter_esp(),
+            x86::msr::IA32_SYSENTER_EIP => vmcb.sysenter_eip(),
+            x86::msr::IA32_PAT => vmcb.gpat(),
+            x86::msr::IA32_DEBUGCTL => vmcb.dbg_ctl(),
+            cet::IA32_S_CET if cet::is_supported() => vmcb.s_cet(),
+            cet::IA32_INTERRUPT_SSP_TABLE_ADDR if cet::is_supported() => vmcb.isst_addr(),
+            // The SVM MSRs are virtualized with nested virtualization. The
+            // guest does not see INIT converted to #SX for the host.
+            // See: 15.30 SVM Related MSRs
+            nested::SVM_MSR_VM_HSAVE_PA if nested::is_enabled() => self.hsave_pa,
+            nested::SVM_MSR_VM_CR if nested::is_enabled() => rdmsr(msr) & !R_INIT,
+            _ => rdmsr(msr),
+        }
+    }
+
+    fn write_msr(&mut self, msr: u32, value: u64) {
+        const EFER_LMA: u64 = 1 << 10;
+        const EFER_SVME: u64 = 1 << 12;
+
+        if self
+            .apic_timer
+            .as_mut()
+            .is_some_and(|timer| timer.write(msr, value))
+        {
+            return;
+        }
+
+        let vmcb = &mut self.vmcb;
+        match msr {
+            // LMA is read-only. Keep it as is, consistent with CR0.PG and LME.
+            // See: 14.6 Enabling and Activating Long Mode
+            x86::msr::IA32_EFER => {
+                vmcb.set_efer((value & !EFER_LMA) | (vmcb.efer() & EFER_LMA) | EFER_SVME);
+                if nested::is_enabled() && !self.in_nested_guest() {
+                    self.set_svme(value & EFER_SVME != 0);
+                }
+            }
+            x86::msr::IA32_STAR => vmcb.set_star(value),
+            x86::msr::IA32_LSTAR => vmcb.set_lstar(value),
+            x86::msr::IA32_CSTAR => vmcb.set_cstar(value),
+            x86::msr::IA32_FMASK => vmcb.set_sf_mask(value),
+            x86::msr::IA32_FS_BASE => vmcb.set_fs_base(value),
+            x86::msr::IA32_GS_BASE => vmcb.set_gs_base(value),
+            x86::msr::IA32_KERNEL_GSBASE => vmcb.set_kernel_gs_base(value),
+            x86::msr::IA32_SYSENTER_CS => vmcb.set_sysenter_cs(value),
+            x86::msr::IA32_SYSENTER_ESP => vmcb.set_sysenter_esp(value),
+            x86::msr::IA32_SYSENTER_EIP => vmcb.set_sysenter_eip(value),
+            x86::msr::IA32_PAT => vmcb.set_gpat(value),
+            x86::msr::IA32_DEBUGCTL => vmcb.set_dbg_ctl(value),
+            cet::IA32_S_CET if cet::is_supported() => vmcb.set_s_cet(value),
+            cet::IA32_INTERRUPT_SSP_TABLE_ADDR if cet::is_supported() => {
+                vmcb.set_isst_addr(value);
+            }
+            // The guest can neither disable nor lock SVM, or redirect INIT.
+            nested::SVM_MSR_VM_HSAVE_PA if nested::is_enabled() => self.hsave_pa = value,
+            nested::SVM_MSR_VM_CR if nested::is_enabled() => {}
+            // Only the x2APIC mode is virtualized and emulates the timer. Move
+            // the state of the backing page and the timer back into the local
+            // APIC while it is still in the mode.
+            // See: 16.11 x2APIC (Extended Local APIC)
+            x86::msr::IA32_APIC_BASE if self.avic.is_some() || self.apic_timer.is_some() => {
+                if !value.get_bit(10) {
+                    if let Some(timer) = self.apic_timer.take() {
+                        timer.deactivate(&mut self.vmcb);
+                    }
+                    self.disable_avic();
+                }
+                wrmsr(msr, value);
+            }
+            _ => wrmsr(msr, value),
+        }
+    }
+
+    fn set_cr2(&mut self, value: u64) {
+        self.vmcb.set_cr2(value);
+    }
+
+    fn pending_event(&self) -> Option<Event> {
+        let event_inj = self.vmcb.event_inj();
+        Event::from_raw(event_inj as u32, (event_inj >> 32) as u32)
+    }
+
+    fn set_pending_event(&mut self, event: Option<Event>) {
+        // See: 15.20 Event Injection
+        let (info, error_code) = event.map_or((0, 0), Event::to_raw);
+        self.vmcb
+            .set_event_inj(u64::from(info) | (u64::from(error_code) << 32));
+    }
+
+    fn queue_interrupt(&mut self, vector: u8) {
+        match &mut self.avic {
+            Some(avic) => avic.request(vector),
+            None => self.interrupts.push(vector),
+        }
+    }
+
+    fn single_step(&mut self, callback: Box<SingleStepCallback>) -> Result<(), SingleStepError> {
+        const DB_VECTOR: u32 = 1;
+        const DR6_BS: u64 = 1 << 14;
+
+        // SVM has no equivalent of the monitor trap flag. Set TF and intercept
+        // the resulting #DB instead. DR6.BS is sticky. Clear it to tell the
+        // single-step from other causes of #DB.
+        // See: 18.3.1.4 Single-Step Exception Condition
+        self.single_step.request(callback)?;
+        let rflags = RFlags::from_raw(self.registers.rflags);
+        self.saved_tf_and_bs = (
+            rflags.contains(RFlags::FLAGS_TF),
+            self.vmcb.dr6() & DR6_BS != 0,
+        );
+        self.registers.rflags = (rflags | RFlags::FLAGS_TF).bits();
+        self.vmcb.set_dr6(self.vmcb.dr6() & !DR6_BS);
+        self.vmcb
+            .set_intercept_exception(self.vmcb.intercept_exception() | 1 << DB_VECTOR);
+        Ok(())
+    }
+
+    fn harvest_dirty_pages(&mut self) -> Result<DirtyBitmap, DirtyTrackingError> {
+        // Dirty tracking is implemented only with the EPT.
+        Err(DirtyTrackingError::Unsupported)
+    }
+
+    fn resolve_gpa(&self, gpa: u64) -> Option<GpaMapping> {
+        match self.nested.as_ref().filter(|nested| nested.in_l2()) {
+            Some(nested) => nested.resolve(self.id, gpa),
+            None => shared_guest_data().npt.read().resolve(gpa),
+        }
+    }
 }
 
 impl Guest for SvmGuest {
-    fn new(id: usize) -> Self {
+    fn new(id: usize) -> Result<Self, HvError> {
+        let shared_guest = SHARED_GUEST_DATA.try_call_once(SharedGuestData::new)?;
         let mut vm = Self {
             id,
             registers: Registers::default(),
-            vmcb: Vmcb::default(),
+            vmcb: Vmcb::new()?,
             vmcb_pa: 0,
-            host_vmcb: Vmcb::default(),
+            host_vmcb: Vmcb::new()?,
             host_vmcb_pa: 0,
-            host_state: HostStateArea::default(),
-            activity_state: &SHARED_GUEST_DATA.activity_states[id],
+            host_state: HostStateArea::new()?,
+            activity_state: &shared_guest.activity_states[id],
+            hook_generation: 0,
+            hook_view_active: false,
+            protection_generation: 0,
+            step_tables: StepTables::new()?,
+            host_memory_hidden: false,
+            tsc: TscCompensation::new(id, &SHARED_HOST_DATA.get().unwrap().tsc, Self::tsc_scale()),
+            extended: None,
+            interrupts: InterruptQueue::default(),
+            single_step: SingleStep::default(),
+            debug: DebugState::new(),
+            marker_generation: 0,
+            asid: Asid::allocate(id),
+            saved_tf_and_bs: (false, false),
+            avic: Avic::new(&shared_guest.msrpm)?,
+            apic_timer: ApicTimer::new(),
+            hsave_pa: 0,
+            nested: None,
         };
 
-        vm.vmcb_pa = platform_ops::get().pa(addr_of!(*vm.vmcb.as_ref()) as _);
-        vm.host_vmcb_pa = platform_ops::get().pa(addr_of!(*vm.host_vmcb.as_ref()) as _);
+        vm.vmcb_pa = vm.vmcb.pa();
+        vm.host_vmcb_pa = vm.host_vmcb.pa();
         if cfg!(feature = "uefi") && vm.id == 0 {
             vm.intercept_apic_write(true);
         }
-        vm
+        Ok(vm)
+    }
+    fn is_shared_data_locked() -> bool {
+        SHARED_GUEST_DATA
+            .get()
+            .is_some_and(|data| data.npt.reader_count() != 0 || data.npt.writer_count() != 0)
     }
-    fn activate(&mut self) {
+
+    fn activate(&mut self) -> Result<(), HvError> {
         const SVM_MSR_VM_HSAVE_PA: u32 = 0xc001_0117;
+        const SVM_MSR_TSC_RATIO: u32 = 0xc000_0104;
 
         // Need to specify the address of the host state-save area before executing
         // the VMRUN instruction. The host state-save area is where the processor
@@ -73,47 +378,407 @@ impl Guest for SvmGuest {
         //  the host state-save area in main memory at the physical address
         //  specified in the VM_HSAVE_PA MSR".
         // See: 15.5.1 Basic Operation
-        let pa = platform_ops::get().pa(addr_of!(*self.host_state.as_ref()) as _);
-        wrmsr(SVM_MSR_VM_HSAVE_PA, pa);
+        wrmsr(SVM_MSR_VM_HSAVE_PA, self.host_state.pa());
+
+        // The TSC ratio is an 8.32 fixed-point number, which is the format of
+        // `TscConfig::scale`. It applies only while the processor runs the guest.
+        // See: 15.30.5 TSC Ratio MSR (C000_0104h)
+        if let Some(scale) = self.tsc.scale() {
+            wrmsr(SVM_MSR_TSC_RATIO, scale);
+        }
+        Ok(())
     }
 
-    fn initialize(&mut self, registers: &Registers) {
+    fn initialize(
+        &mut self,
+        registers: &Registers,
+        extended: Option<ExtendedRegisters>,
+    ) -> Result<(), HvError> {
         self.registers = *registers;
+        self.extended = extended;
         self.initialize_control();
         self.initialize_guest();
-        self.initialize_host();
+        self.initialize_host()
     }
 
     fn run(&mut self) -> VmExitReason {
-        const VMEXIT_EXCEPTION_SX: u64 = 0x5e;
-        const VMEXIT_CPUID: u64 = 0x72;
+        if self.in_nested_guest() {
+            return self.run_l2();
+        }
+
+        self.sync_hooks();
+        self.sync_protections();
+        self.sync_hidden_memory();
+        hw_breakpoint::sync(self);
+        self.sync_breakpoint_markers();
+        self.flush_tlb();
+        self.inject_pending_interrupt();
+        if self.tsc.enabled() {
+            self.vmcb.set_tsc_offset(self.tsc.on_entry());
+        }
+        if let Some(timer) = &mut self.apic_timer {
+            timer.arm();
+        }
+        self.run_current();
+        self.handle_vm_exit()
+    }
+
+    fn exit_info(&self) -> RawExitInfo {
         const VMEXIT_NPF: u64 = 0x400;
 
-        self.vmcb.state_save_area.rax = self.registers.rax;
-        self.vmcb.state_save_area.rip = self.registers.rip;
-        self.vmcb.state_save_area.rsp = self.registers.rsp;
-        self.vmcb.state_save_area.rflags = self.registers.rflags;
+        let code = self.vmcb.exit_code();
+        RawExitInfo {
+            code,
+            qualification: self.vmcb.exit_info1(),
+            gpa: if code == VMEXIT_NPF {
+                self.vmcb.exit_info2()
+            } else {
+                0
+            },
+        }
+    }
+
+    fn write_cr(&mut self, cr: u8, value: u64) {
+        const CR0_PG: u64 = 1 << 31;
+        const CR3_NO_FLUSH: u64 = 1 << 63;
+
+        if cr == 3 {
+            // Bit 63 is not part of CR3 but asks not to flush TLB entries of the
+            // PCID. The guest TLB is flushed below regardless.
+            // See: 5.5.1 Process Context Identifier
+            self.vmcb.set_cr3(value & !CR3_NO_FLUSH);
+        } else if cr == 4 {
+            self.vmcb.set_cr4(value);
+        } else {
+            // CR0.PG is not guarded but may be changed together with the guarded
+            // bits. Update EFER.LMA as the processor would.
+            // See: 14.6 Enabling and Activating Long Mode
+            if (self.vmcb.cr0() ^ value) & CR0_PG != 0 {
+                self.vmcb
+                    .set_efer(long_mode::update_lma(self.vmcb.efer(), value));
+            }
+            self.vmcb.set_cr0(value);
+        }
+
+        // MOV to control registers may invalidate TLB entries, for example,
+        // when CR4.PGE changes. Flush those of the guest for simplicity.
+        tlb::flush_guest(self, FlushScope::GuestLinear);
+    }
+
+    fn set_cr3_targets(&mut self, _targets: &[u64]) {
+        // SVM has no equivalent of the CR3-target values. Every load of CR3
+        // causes #VMEXIT.
+    }
+
+    fn debug_state(&mut self) -> &mut DebugState {
+        &mut self.debug
+    }
+
+    fn read_dr(&self, index: u8) -> u64 {
+        // DR6 and DR7 are switched through the VMCB. The others are switched
+        // with the extended registers if requested, and are left the guest
+        // values otherwise.
+        match (index, &self.extended) {
+            (6, _) => self.vmcb.dr6(),
+            (7, _) => self.vmcb.dr7(),
+            (_, Some(extended)) => extended.dr(index),
+            (_, None) => dr(index),
+        }
+    }
+
+    fn write_dr(&mut self, index: u8, value: u64) {
+        match (index, &mut self.extended) {
+            (6, _) => self.vmcb.set_dr6(value),
+            (7, _) => self.vmcb.set_dr7(value),
+            (_, Some(extended)) => extended.set_dr(index, value),
+            (_, None) => dr_write(index, value),
+        }
+    }
+
+    fn intercept_debug(&mut self, enable: bool) {
+        const DB_VECTOR: u32 = 1;
+
+        // Keep intercepting #DB while single-stepping.
+        // See: 15.12 Exception Intercepts
+        let dr_intercepts = if enable { 0xff } else { 0 };
+        self.vmcb.set_intercept_dr_read(dr_intercepts);
+        self.vmcb.set_intercept_dr_write(dr_intercepts);
+        if enable || !self.single_step.is_pending() {
+            let exceptions = self.vmcb.intercept_exception();
+            self.vmcb.set_intercept_exception(if enable {
+                exceptions | 1 << DB_VECTOR
+            } else {
+                exceptions & !(1 << DB_VECTOR)
+            });
+        }
+    }
+
+    fn handle_nested_page_fault(&mut self, info: &NestedPageFaultInfo) {
+        // Writes to and execution of the pages hidden from the guest.
+        if hidden_memory::handle_violation(self, info) {
+            return;
+        }
+
+        // L2 running with NPT02 does not use the hook view. Map the hooked
+        // page in NPT02 for the access instead. See `nested`.
+        if let Some(nested) = self.nested.as_mut().filter(|nested| nested.uses_npt02()) {
+            if let Some(shadow_pa) = shared_guest_data().npt.read().shadow_pa(info.gpa) {
+                nested.map_hooked(info, shadow_pa);
+                return;
+            }
+        } else {
+            // With the hook view, any #VMEXIT(NPF) is either execution outside
+            // the shadow pages or a write to them. Switch back to the primary
+            // NPT and let the guest retry the access.
+            if self.hook_view_active {
+                self.switch_npt(false);
+                return;
+            }
+
+            // With the primary NPT, execution of the hooked page is the other
+            // case we restrict through NPT. Switch to the hook view where the
+            // shadow page is executable.
+            //
+            // Note that while the guest runs with the hook view, reads from the
+            // hooked page observe the shadow page, unlike with EPT.
+            if info.execute && shared_guest_data().npt.read().is_hooked(info.gpa) {
+                self.switch_npt(true);
+                return;
+            }
+        }
+
+        // Access to protected pages is another case.
+        match memory_protection::handle_violation(self, info) {
+            Some(ViolationAction::Allow) => {
+                self.allow_access_once(info.gpa);
+                return;
+            }
+            Some(ViolationAction::Resume) => return,
+            Some(ViolationAction::Skip) => {
+                let result = instruction_decoder::skip(self);
+                memory_protection::complete(self, result);
+                return;
+            }
+            Some(ViolationAction::Emulate) => {
+                let result = instruction_decoder::emulate(self);
+                memory_protection::complete(self, result);
+                return;
+            }
+            None => {}
+        }
+
+        // The page may have been unprotected but not yet applied on this
+        // processor. Let the guest retry after applying it.
+        if self.protection_generation != memory_protection::generation() {
+            return;
+        }
+
+        // The last case we restrict access through NPT is the APIC page to
+        // intercept Startup IPI.
+        self.handle_apic_write();
+    }
+
+    fn set_virtualization_exception_info(&mut self, _info_pa: Option<u64>) -> Result<(), VeError> {
+        // SVM has no equivalent of virtualization exceptions.
+        Err(VeError::Unsupported)
+    }
+
+    fn in_nested_guest(&self) -> bool {
+        self.nested.as_ref().is_some_and(|nested| nested.in_l2())
+    }
+
+    fn can_devirtualize(&self) -> bool {
+        // The SVM state of the guest could not be handed over to the processor,
+        // which would run the SVM instructions of the guest natively.
+        self.nested.is_none()
+    }
+
+    fn deactivate(&mut self) -> GuestSystemState {
+        const SVM_MSR_VM_CR: u32 = 0xc001_0114;
+        const R_INIT: u64 = 1 << 1;
+
+        self.leave_nested();
+        hw_breakpoint::restore(self);
+        if let Some(timer) = &self.apic_timer {
+            timer.deactivate(&mut self.vmcb);
+        }
+        if let Some(avic) = &mut self.avic {
+            avic.deactivate();
+        }
+
+        // Stop converting #INIT to #SX, as set in `initialize_control`.
+        wrmsr(SVM_MSR_VM_CR, rdmsr(SVM_MSR_VM_CR) & !R_INIT);
+
+        // Load the guest registers that VMSAVE saved, that is, the hidden parts
+        // of FS, GS, TR and LDTR, as well as KernelGsBase, STAR, LSTAR, CSTAR,
+        // SFMASK and SYSENTER MSRs.
+        // See: VMLOAD - Load State from VMCB
+        vmload(self.vmcb_pa);
+
+        // #VMEXIT loads the CET MSRs of the host, which runs with supervisor CET
+        // disabled. Only IA32_S_CET is left to be restored at the end, since it
+        // enables CET for the current code too.
+        if cet::is_supported() {
+            wrmsr(cet::IA32_INTERRUPT_SSP_TABLE_ADDR, self.vmcb.isst_addr());
+            self.registers.ssp = self.vmcb.ssp();
+        }
+
+        // DR6 is switched with the extended registers from here.
+        if let Some(extended) = &mut self.extended {
+            extended.set_dr(6, self.vmcb.dr6());
+        }
+
+        let vmcb = &self.vmcb;
+        GuestSystemState {
+            registers: self.registers,
+            extended: self.extended.take(),
+            cr0: vmcb.cr0(),
+            cr3: vmcb.cr3(),
+            cr4: vmcb.cr4(),
+            dr7: vmcb.dr7(),
+            gdtr: DescriptorTablePointer {
+                base: vmcb.gdtr_base() as _,
+                limit: vmcb.gdtr_limit() as _,
+            },
+            idtr: DescriptorTablePointer {
+                base: vmcb.idtr_base() as _,
+                limit: vmcb.idtr_limit() as _,
+            },
+            es: vmcb.es_selector(),
+            cs: vmcb.cs_selector(),
+            ss: vmcb.ss_selector(),
+            ds: vmcb.ds_selector(),
+            fs: vmcb.fs_selector(),
+            gs: vmcb.gs_selector(),
+            tr: vmcb.tr_selector(),
+            ldtr: vmcb.ldtr_selector(),
+            fs_base: vmcb.fs_base(),
+            gs_base: vmcb.gs_base(),
+            s_cet: vmcb.s_cet(),
+        }
+    }
+}
+
+impl SvmGuest {
+    /// Returns `TscConfig::scale` if the processor supports TSC scaling.
+    fn tsc_scale() -> Option<u64> {
+        let scale = SHARED_HOST_DATA.get().unwrap().tsc.scale?;
+
+        // See: Appendix E.4.10 Function 8000_000Ah—SVM Features
+        if !cpuid!(0x8000_000a).edx.get_bit(4) {
+            log::warn!("TSC scaling is not supported. Ignoring the TSC scale");
+            return None;
+        }
+        Some(scale)
+    }
+
+    /// Runs the guest with the current VMCB until #VMEXIT occurs.
+    fn run_current(&mut self) {
+        const V_INTR_MASKING: u64 = 1 << 24;
+
+        self.vmcb.set_rax(self.registers.rax);
+        self.vmcb.set_rip(self.registers.rip);
+        self.vmcb.set_rsp(self.registers.rsp);
+        self.vmcb.set_rflags(self.registers.rflags);
+
+        // Tell which consistency check VMRUN would fail in debug builds,
+        // before the guest state is lost in VMEXIT_INVALID. VMCB02 failing one
+        // is reported to L1 instead.
+        if cfg!(debug_assertions) && !self.in_nested_guest() {
+            if let Some(violation) = vmcb_checks::check(&self.vmcb) {
+                self.vmcb.dump();
+                panic!("VMRUN would fail the consistency check of {violation}");
+            }
+        }
 
         log::trace!("Entering the guest");
 
         // Run the guest until the #VMEXIT occurs.
-        unsafe { run_svm_guest(&mut self.registers, self.vmcb_pa, self.host_vmcb_pa) };
+        //
+        // NMIs need no handling. The host runs with GIF cleared, which holds
+        // NMIs pending until VMRUN sets GIF, and they are delivered to the guest
+        // as they are not intercepted. Physical interrupts cause #VMEXIT with
+        // AVIC (see `avic`), and with V_INTR_MASKING L1 sets for L2, under
+        // which RFLAGS.IF of the host masks them. They also do with the
+        // emulation of the APIC timer, under which RFLAGS.IF of the guest
+        // masks them without AVIC (see `apic_timer`).
+        // See: 15.21.1 Physical Interrupt Masking
+        // See: 15.17 Global Interrupt Flag, STGI and CLGI Instructions
+        let extended = self
+            .extended
+            .as_mut()
+            .map_or(core::ptr::null_mut(), ExtendedRegisters::prepare);
+        let interruptible = self.avic.is_some()
+            || (self.in_nested_guest() && self.vmcb.vintr() & V_INTR_MASKING != 0);
+        let mut run = || unsafe {
+            run_svm_guest(
+                &mut self.registers,
+                self.vmcb_pa,
+                self.host_vmcb_pa,
+                extended,
+            );
+        };
+        if interruptible {
+            Avic::run_guest(run);
+        } else {
+            run();
+        }
+        self.tsc.on_exit();
 
         log::trace!("Exited the guest");
 
         // #VMEXIT occurred. Copy the guest register values from VMCB so that
         // `self.registers` is complete and up to date.
-        self.registers.rax = self.vmcb.state_save_area.rax;
-        self.registers.rip = self.vmcb.state_save_area.rip;
-        self.registers.rsp = self.vmcb.state_save_area.rsp;
-        self.registers.rflags = self.vmcb.state_save_area.rflags;
+        self.registers.rax = self.vmcb.rax();
+        self.registers.rip = self.vmcb.rip();
+        self.registers.rsp = self.vmcb.rsp();
+        self.registers.rflags = self.vmcb.rflags();
+
+        // If the #VMEXIT occurred during delivery of an event, inject it again
+        // so that it is not lost. This also clears the event we injected with
+        // this VMRUN, if any.
+        // See: 15.7.2 Intercepts During IDT Interrupt Delivery
+        let exit_int_info = self.vmcb.exit_int_info();
+        self.set_pending_event(Event::from_raw(
+            exit_int_info as u32,
+            (exit_int_info >> 32) as u32,
+        ));
 
         // We might have requested flushing TLB. Clear the request.
-        self.vmcb.control_area.tlb_control = TlbControl::DoNotFlush as _;
-        self.vmcb.control_area.vmcb_clean = u32::MAX;
+        self.vmcb.set_tlb_control(TlbControl::DoNotFlush);
+        self.vmcb.mark_all_clean();
+    }
+
+    /// Handles #VMEXIT by translating it to the `VmExitReason` type.
+    fn handle_vm_exit(&mut self) -> VmExitReason {
+        const VMEXIT_CR3_WRITE: u64 = 0x13;
+        const VMEXIT_CR4_WRITE: u64 = 0x14;
+        const VMEXIT_DR0_READ: u64 = 0x20;
+        const VMEXIT_DR0_WRITE: u64 = 0x30;
+        const VMEXIT_DR15_WRITE: u64 = 0x3f;
+        const VMEXIT_EXCEPTION_DB: u64 = 0x41;
+        const VMEXIT_EXCEPTION_BP: u64 = 0x43;
+        const VMEXIT_EXCEPTION_MC: u64 = 0x52;
+        const VMEXIT_EXCEPTION_SX: u64 = 0x5e;
+        const VMEXIT_INTR: u64 = 0x60;
+        const VMEXIT_SMI: u64 = 0x62;
+        const VMEXIT_VINTR: u64 = 0x64;
+        const VMEXIT_CR0_SEL_WRITE: u64 = 0x65;
+        const VMEXIT_CPUID: u64 = 0x72;
+        const VMEXIT_INVD: u64 = 0x76;
+        const VMEXIT_INVLPGA: u64 = 0x7a;
+        const VMEXIT_IOIO: u64 = 0x7b;
+        const VMEXIT_MSR: u64 = 0x7c;
+        const VMEXIT_VMRUN: u64 = 0x80;
+        const VMEXIT_VMMCALL: u64 = 0x81;
+        const VMEXIT_VMLOAD: u64 = 0x82;
+        const VMEXIT_SKINIT: u64 = 0x86;
+        const VMEXIT_WBINVD: u64 = 0x89;
+        const VMEXIT_XSETBV: u64 = 0x8d;
+        const VMEXIT_NPF: u64 = 0x400;
+        const VMEXIT_AVIC_NOACCEL: u64 = 0x402;
+        const VMEXIT_INVALID: u64 = u64::MAX;
 
-        // Handle #VMEXIT by translating it to the `VmExitReason` type.
-        //
         // "On #VMEXIT, the processor:
         //  (...)
         //  - Saves the reason for exiting the guest in the VMCB's EXITCODE field."
@@ -121,40 +786,619 @@ impl Guest for SvmGuest {
         //
         // For the list of possible exit codes,
         // See: Appendix C SVM Intercept Exit Codes
-        match self.vmcb.control_area.exit_code {
+        match self.vmcb.exit_code() {
             VMEXIT_EXCEPTION_SX => {
+                self.leave_nested();
                 self.handle_security_exception();
                 VmExitReason::InitSignal
             }
+            VMEXIT_EXCEPTION_DB => self.handle_debug_exception(),
+            VMEXIT_EXCEPTION_BP => VmExitReason::Breakpoint(InstructionInfo {
+                next_rip: self.vmcb.nrip(),
+            }),
+            VMEXIT_EXCEPTION_MC => VmExitReason::MachineCheck,
+            VMEXIT_VINTR => VmExitReason::InterruptWindow,
+            VMEXIT_INTR => {
+                self.handle_interrupt();
+                if self.apic_timer.as_mut().is_some_and(ApicTimer::take_expiry) {
+                    VmExitReason::PreemptionTimer
+                } else {
+                    VmExitReason::ExternalInterrupt
+                }
+            }
+            VMEXIT_AVIC_NOACCEL => {
+                self.avic
+                    .as_mut()
+                    .unwrap()
+                    .handle_unaccelerated_access(&self.vmcb);
+                VmExitReason::VirtualEoi
+            }
+            VMEXIT_SMI => {
+                self.handle_smi();
+                VmExitReason::Smi
+            }
+            code @ (VMEXIT_CR0_SEL_WRITE | VMEXIT_CR3_WRITE | VMEXIT_CR4_WRITE) => {
+                // With decode assists, EXITINFO1 indicates the GPR for MOV to
+                // CRx. LMSW, which does not, cannot change the guarded bits.
+                // See: 15.33.1 MOV CRx/DRx Intercepts
+                let exit_info1 = self.vmcb.exit_info1();
+                assert!(exit_info1.get_bit(63), "LMSW is not supported");
+                VmExitReason::CrWrite(CrWriteInfo {
+                    next_rip: self.vmcb.nrip(),
+                    cr: match code {
+                        VMEXIT_CR3_WRITE => 3,
+                        VMEXIT_CR4_WRITE => 4,
+                        _ => 0,
+                    },
+                    value: self.registers.gpr(exit_info1.get_bits(0..=3) as u8),
+                })
+            }
+            code @ VMEXIT_DR0_READ..=VMEXIT_DR15_WRITE => {
+                // With decode assists, EXITINFO1 indicates the GPR. Only DR0-DR7
+                // are intercepted.
+                // See: 15.33.1 MOV CRx/DRx Intercepts
+                VmExitReason::DrAccess(DrAccessInfo {
+                    next_rip: self.vmcb.nrip(),
+                    dr: (code & 0xf) as u8,
+                    gpr: self.vmcb.exit_info1().get_bits(0..=3) as u8,
+                    write: code >= VMEXIT_DR0_WRITE,
+                })
+            }
             VMEXIT_CPUID => VmExitReason::Cpuid(InstructionInfo {
-                next_rip: self.vmcb.control_area.nrip,
+                next_rip: self.vmcb.nrip(),
+            }),
+            VMEXIT_IOIO => {
+                // See: Figure 15-2. EXITINFO1 for IOIO Intercept
+                //
+                // The segment is reported only with decode assists. Otherwise,
+                // assume DS without the segment override prefix.
+                // See: 15.33 Decode Assists
+                let exit_info1 = self.vmcb.exit_info1();
+                let segment = if cpuid!(0x8000_000a).edx.get_bit(7) {
+                    SegmentRegister::from_number(exit_info1.get_bits(10..=12))
+                } else {
+                    SegmentRegister::Ds
+                };
+                VmExitReason::Io(IoInstructionInfo {
+                    next_rip: self.vmcb.exit_info2(),
+                    port: exit_info1.get_bits(16..=31) as u16,
+                    size: exit_info1.get_bits(4..=6) as u8,
+                    is_in: exit_info1.get_bit(0),
+                    string: exit_info1.get_bit(2),
+                    rep: exit_info1.get_bit(3),
+                    // A16, A32 and A64 at bits 7, 8 and 9.
+                    address_size: match exit_info1.get_bits(7..=9) {
+                        0b001 => 2,
+                        0b010 => 4,
+                        _ => 8,
+                    },
+                    segment,
+                })
+            }
+            VMEXIT_MSR => {
+                // "EXITINFO1 = 0 for RDMSR, 1 for WRMSR"
+                // See: 15.11 MSR Intercepts
+                let info = InstructionInfo {
+                    next_rip: self.vmcb.nrip(),
+                };
+                if self.vmcb.exit_info1() == 0 {
+                    VmExitReason::Rdmsr(info)
+                } else {
+                    VmExitReason::Wrmsr(info)
+                }
+            }
+            VMEXIT_INVD => VmExitReason::Invd(InstructionInfo {
+                next_rip: self.vmcb.nrip(),
             }),
+            VMEXIT_WBINVD => VmExitReason::Wbinvd(InstructionInfo {
+                next_rip: self.vmcb.nrip(),
+            }),
+            VMEXIT_VMMCALL if !self.in_nested_guest() => VmExitReason::Hypercall(InstructionInfo {
+                next_rip: self.vmcb.nrip(),
+            }),
+            VMEXIT_XSETBV => VmExitReason::XSetBv(InstructionInfo {
+                next_rip: self.vmcb.nrip(),
+            }),
+            VMEXIT_VMRUN | VMEXIT_VMLOAD..=VMEXIT_SKINIT | VMEXIT_INVLPGA
+                if nested::is_enabled() && !self.in_nested_guest() =>
+            {
+                self.emulate_svm_instruction()
+            }
+            VMEXIT_VMRUN | VMEXIT_VMMCALL | VMEXIT_VMLOAD..=VMEXIT_SKINIT | VMEXIT_INVLPGA => {
+                // The guest sees EFER.SVME cleared without nested
+                // virtualization, and L2 does not see SVM reported. Deliver #UD
+                // as the processor with EFER.SVME cleared does. VMMCALL of L2
+                // is not a hypercall to L0 either.
+                // See: 15.4 Enabling SVM
+                let ud = Event::Exception {
+                    vector: 6,
+                    error_code: None,
+                };
+                if let Err(err) = event::inject_event(self, ud) {
+                    log::error!("Could not inject #UD: {err}");
+                }
+                VmExitReason::VirtualizationInstruction
+            }
             VMEXIT_NPF => {
-                self.handle_nested_page_fault();
-                VmExitReason::NestedPageFault
+                // See: 15.25.6 Nested versus Guest Page Faults, Fault Ordering
+                let exit_info1 = self.vmcb.exit_info1();
+                VmExitReason::NestedPageFault(NestedPageFaultInfo {
+                    gpa: self.vmcb.exit_info2(),
+                    write: exit_info1.get_bit(1),
+                    execute: exit_info1.get_bit(4),
+                })
+            }
+            VMEXIT_INVALID => {
+                self.vmcb.dump();
+                match vmcb_checks::check(&self.vmcb) {
+                    Some(violation) => {
+                        panic!("VMRUN failed the consistency check of {violation}")
+                    }
+                    None => panic!("VMRUN failed a consistency check not implemented"),
+                }
             }
             _ => {
-                log::error!("{:#x?}", self.vmcb);
-                panic!(
-                    "Unhandled #VMEXIT reason: {:?}",
-                    self.vmcb.control_area.exit_code
-                )
+                self.vmcb.dump();
+                panic!("Unhandled #VMEXIT reason: {:?}", self.vmcb.exit_code())
             }
         }
     }
 
-    fn regs(&mut self) -> &mut Registers {
-        &mut self.registers
+    /// Runs L2 with VMCB02 until #VMEXIT occurs, after applying the pending
+    /// changes for L1 onto the NPT. Returns the reason of the #VMEXIT L0
+    /// handles for L2, or `NestedGuest` if nothing else is to be done,
+    /// including when the #VMEXIT is reflected into L1. See `nested`.
+    fn run_l2(&mut self) -> VmExitReason {
+        const DB_VECTOR: u32 = 1;
+        const MC_VECTOR: u32 = 18;
+        const SX_VECTOR: u32 = 30;
+
+        self.sync_hooks();
+        self.sync_protections();
+        self.sync_hidden_memory();
+        self.flush_tlb();
+
+        // External interrupts for L1 cause #VMEXIT(INTR) from L2 if L1
+        // intercepts them. Otherwise, they are injected into L2.
+        // See: 15.21.2 Virtual Interrupt Masking
+        let nested = self.nested.as_ref().unwrap();
+        if !self.interrupts.is_empty() && nested.l1_intercepts_interrupts() {
+            return self.reflect_into_l1(Some(nested::VMEXIT_INTR));
+        }
+        self.inject_pending_interrupt();
+
+        // #DB is intercepted for L2 only while L0 single-steps it.
+        let nested = self.nested.as_ref().unwrap();
+        let mut exceptions = nested.l1_exceptions() | 1 << MC_VECTOR | 1 << SX_VECTOR;
+        if self.single_step.is_pending() {
+            exceptions |= 1 << DB_VECTOR;
+        }
+        self.vmcb.set_intercept_exception(exceptions);
+        if self.tsc.enabled() {
+            self.vmcb
+                .set_tsc_offset(self.tsc.on_entry().wrapping_add(nested.tsc_offset12()));
+        }
+
+        self.run_current();
+
+        let l0_step = self.single_step.is_pending();
+        let mut state = self.nested.take().unwrap();
+        let route = state.route(self, l0_step);
+        self.nested = Some(state);
+        match route {
+            Route::Reflect => self.reflect_into_l1(None),
+            Route::Host => self.handle_vm_exit(),
+            Route::NestedPageFault(info) => VmExitReason::NestedPageFault(info),
+            Route::Resolved => VmExitReason::NestedGuest,
+        }
+    }
+
+    /// Reflects the #VMEXIT from L2, or the one with the exit code `synthetic`,
+    /// into L1, which resumes with VMCB01. See `NestedSvm::reflect`.
+    fn reflect_into_l1(&mut self, synthetic: Option<u64>) -> VmExitReason {
+        const DB_VECTOR: u32 = 1;
+
+        let keep_dr7 = self.debug.is_active();
+        let mut state = self.nested.take().unwrap();
+        state.reflect(self, synthetic, keep_dr7);
+        self.nested = Some(state);
+
+        // The step L0 requested for L2 completes in L1 instead.
+        if self.single_step.is_pending() {
+            let rflags = RFlags::from_raw(self.registers.rflags);
+            self.saved_tf_and_bs.0 = rflags.contains(RFlags::FLAGS_TF);
+            self.registers.rflags = (rflags | RFlags::FLAGS_TF).bits();
+            self.vmcb
+                .set_intercept_exception(self.vmcb.intercept_exception() | 1 << DB_VECTOR);
+        }
+        VmExitReason::NestedGuest
+    }
+
+    /// Emulates the SVM instruction the guest executed with nested
+    /// virtualization. See `nested`.
+    fn emulate_svm_instruction(&mut self) -> VmExitReason {
+        let code = self.vmcb.exit_code();
+        let mut state = self.nested.take();
+        let emulation = nested::emulate(&mut state, self, code);
+        self.nested = state;
+        match emulation {
+            Emulation::Completed | Emulation::Entered => VmExitReason::VirtualizationInstruction,
+        }
+    }
+
+    /// Creates or drops the SVM state of the guest as it sets or clears
+    /// EFER.SVME with nested virtualization. See `nested`.
+    fn set_svme(&mut self, svme: bool) {
+        if svme == self.nested.is_some() {
+            return;
+        }
+        if !svme {
+            self.nested = None;
+            return;
+        }
+        match NestedSvm::try_new(self.id) {
+            Ok(nested) => self.nested = Some(Box::new(nested)),
+            Err(err) => log::error!("Could not enable SVM of the guest: {err}"),
+        }
+    }
+
+    /// Abandons L2 if it runs, making VMCB01 current, and drops the SVM state
+    /// of the guest, for INIT and devirtualization.
+    fn leave_nested(&mut self) {
+        if let Some(mut nested) = self.nested.take() {
+            nested.release(self);
+        }
+    }
+
+    /// Returns the current VMCB, which is VMCB02 while L2 runs. See `nested`.
+    pub(super) fn vmcb(&self) -> &Vmcb {
+        &self.vmcb
+    }
+
+    /// Returns the current VMCB for writing.
+    pub(super) fn vmcb_mut(&mut self) -> &mut Vmcb {
+        &mut self.vmcb
+    }
+
+    /// Swaps the current VMCB with `other`, which is the non-current one of
+    /// VMCB01 and VMCB02. See `nested`.
+    pub(super) fn switch_vmcb(&mut self, other: &mut Vmcb) {
+        core::mem::swap(&mut self.vmcb, other);
+        self.vmcb_pa = self.vmcb.pa();
+        self.vmcb.mark_all_dirty();
+        other.mark_all_dirty();
+    }
+
+    /// Performs the flushes requested with `tlb` since the last VMRUN. The TLB
+    /// entries of L2 are tagged with the ASID of its own, and are flushed for
+    /// NPT02 replacing entries. See `nested`.
+    fn flush_tlb(&mut self) {
+        let flushes = tlb::take();
+        let (in_l2, stale) = match &mut self.nested {
+            Some(nested) => {
+                if flushes.physical {
+                    nested.reset_npt02();
+                }
+                (nested.in_l2(), nested.in_l2() && nested.take_stale())
+            }
+            None => (false, false),
+        };
+        if in_l2 && flushes.any() {
+            self.vmcb.set_tlb_control(TlbControl::FlushAll);
+        } else if flushes.any() || stale {
+            self.flush_guest_tlb();
+        }
+    }
+
+    /// Applies changes of the hooks onto the NPTs if any. Each processor does
+    /// this and flushes its own TLB, instead of sending IPIs to other processors
+    /// from the host.
+    fn sync_hooks(&mut self) {
+        const EFER_NXE: u64 = 1 << 11;
+
+        let generation = ept_hook::generation();
+        if self.hook_generation == generation {
+            return;
+        }
+
+        // Try again on the next #VMEXIT if the guest on this processor owns the
+        // lock of the hook manager.
+        let Some(hook_manager) = ept_hook::try_hook_manager() else {
+            return;
+        };
+        self.hook_generation = generation;
+
+        // The NX bit in the NPT is reserved unless the host enables it.
+        // See: 15.25.5 Nested Table Walk
+        if rdmsr(x86::msr::IA32_EFER) & EFER_NXE == 0 {
+            log::error!("Hooks are unsupported as EFER.NXE is cleared");
+            return;
+        }
+
+        shared_guest_data().npt.write().apply_hooks(&hook_manager);
+        tlb::flush_guest(self, FlushScope::GuestPhysical);
+        percpu::current()
+            .hook_generation
+            .store(generation, Ordering::Release);
+    }
+
+    /// Applies changes of the protections onto the NPTs if any, the same way as
+    /// `sync_hooks`.
+    fn sync_protections(&mut self) {
+        let generation = memory_protection::generation();
+        if self.protection_generation == generation {
+            return;
+        }
+
+        let Some(protections) = memory_protection::try_protections() else {
+            return;
+        };
+        self.protection_generation = generation;
+
+        shared_guest_data()
+            .npt
+            .write()
+            .apply_protections(&protections);
+        tlb::flush_guest(self, FlushScope::GuestPhysical);
+    }
+
+    /// Hides the memory of the hypervisor in the NPTs once requested.
+    fn sync_hidden_memory(&mut self) {
+        if self.host_memory_hidden {
+            return;
+        }
+        let Some(pages) = hidden_memory::pages() else {
+            return;
+        };
+        self.host_memory_hidden = true;
+
+        shared_guest_data().npt.write().hide(pages);
+        tlb::flush_guest(self, FlushScope::GuestPhysical);
+    }
+
+    /// Intercepts #BP while any breakpoint marker is registered.
+    fn sync_breakpoint_markers(&mut self) {
+        const BP_VECTOR: u32 = 3;
+
+        if let Some(intercept) = breakpoint_marker::sync(&mut self.marker_generation) {
+            let exceptions = self.vmcb.intercept_exception();
+            self.vmcb.set_intercept_exception(if intercept {
+                exceptions | 1 << BP_VECTOR
+            } else {
+                exceptions & !(1 << BP_VECTOR)
+            });
+        }
+    }
+
+    /// Lets the guest complete the access to the protected page containing
+    /// `gpa` by lifting the protection in the step tables while single-stepping
+    /// the instruction. The guest faulted with the primary NPT, which the step
+    /// tables copy, and the protection stays in effect on the other processors.
+    fn allow_access_once(&mut self, gpa: u64) {
+        // The step may be already requested for another page of the same
+        // instruction, or for other purposes. Either way, RFLAGS.TF is set.
+        match self.single_step(Box::new(|_| {})) {
+            Ok(()) | Err(SingleStepError::Busy) => {}
+            Err(err) => {
+                log::error!("Could not allow access to {gpa:#x?}: {err}");
+                return;
+            }
+        }
+
+        // L2 running with NPT02 is mapped the page in NPT02 instead.
+        if let Some(nested) = self.nested.as_mut().filter(|nested| nested.uses_npt02()) {
+            nested.map_for_step();
+            return;
+        }
+
+        let npt = shared_guest_data().npt.read();
+        let ncr3 = npt.map_in_step_tables(&mut self.step_tables, gpa, Permissions::ALL);
+        drop(npt);
+        self.vmcb.set_ncr3(ncr3);
+        tlb::flush_guest(self, FlushScope::GuestPhysical);
+    }
+
+    /// Switches back from the step tables to the NPT in use before
+    /// `allow_access_once`, if the step tables are in use.
+    fn end_step_tables(&mut self) {
+        if let Some(nested) = &mut self.nested {
+            nested.end_steps();
+        }
+        if self.step_tables.is_active() {
+            self.step_tables.clear();
+            self.switch_npt(self.hook_view_active);
+        }
+    }
+
+    /// Switches the NPT to the hook view if `hook_view` is true, or to the
+    /// primary NPT otherwise.
+    fn switch_npt(&mut self, hook_view: bool) {
+        let npt = shared_guest_data().npt.read();
+        self.vmcb.set_ncr3(if hook_view {
+            npt.hook_view_ncr3().unwrap()
+        } else {
+            platform_ops::get().pa(npt.as_ref() as *const _ as _)
+        });
+        self.hook_view_active = hook_view;
+        tlb::flush_guest(self, FlushScope::GuestPhysical);
+    }
+
+    /// Requests flushing TLB entries of the guest on the next VMRUN.
+    fn flush_guest_tlb(&mut self) {
+        self.vmcb.set_tlb_control(self.asid.flush_control());
+    }
+
+    /// Injects the highest external interrupt queued for the guest if the guest
+    /// can receive it now. Otherwise, requests a virtual interrupt with the
+    /// VINTR intercept, which causes #VMEXIT as soon as the guest can receive
+    /// one.
+    fn inject_pending_interrupt(&mut self) {
+        const SVM_INTERCEPT_MISC1_VINTR: u32 = 1 << 4;
+        const V_IRQ: u64 = 1 << 8;
+        const V_IGN_TPR: u64 = 1 << 20;
+        const INTERRUPT_SHADOW: u64 = 1 << 0;
+
+        // External interrupts are recognized only when RFLAGS.IF is set and the
+        // guest is not in the interrupt shadow. Also, only one event can be
+        // injected at a time. Hold them while single-stepping, as delivery
+        // would save our RFLAGS.TF in the guest stack.
+        // See: 15.21.4 Injecting Virtual (INTR) Interrupts
+        //
+        // With nested virtualization, GIF of L1 holds them too. While L2 runs,
+        // they are only injected, as V_IRQ and the VINTR intercept are of L1.
+        // See: 15.17 Global Interrupt Flag, STGI and CLGI Instructions
+        let stepping = self.single_step.is_pending();
+        let gif = self.nested.as_ref().is_none_or(|nested| nested.gif());
+        if !self.interrupts.is_empty()
+            && !stepping
+            && gif
+            && RFlags::from_raw(self.registers.rflags).contains(RFlags::FLAGS_IF)
+            && self.vmcb.interrupt_shadow() & INTERRUPT_SHADOW == 0
+            && self.pending_event().is_none()
+        {
+            let vector = self.interrupts.pop().unwrap();
+            self.set_pending_event(Some(Event::ExternalInterrupt { vector }));
+        }
+
+        // The vector of the virtual interrupt does not matter as it is
+        // intercepted before being taken. Ignore the virtual TPR so that it is
+        // taken regardless of the priority.
+        if self.in_nested_guest() {
+            return;
+        }
+        let pending = !self.interrupts.is_empty() && !stepping && gif;
+        let vmcb = &mut self.vmcb;
+        if (vmcb.vintr() & V_IRQ != 0) != pending {
+            if pending {
+                vmcb.set_vintr(vmcb.vintr() | V_IRQ | V_IGN_TPR);
+                vmcb.set_intercept_misc1(vmcb.intercept_misc1() | SVM_INTERCEPT_MISC1_VINTR);
+            } else {
+                vmcb.set_vintr(vmcb.vintr() & !(V_IRQ | V_IGN_TPR));
+                vmcb.set_intercept_misc1(vmcb.intercept_misc1() & !SVM_INTERCEPT_MISC1_VINTR);
+            }
+        }
+    }
+
+    /// Handles #DB intercepted for single-stepping or `hw_breakpoint`. Calls
+    /// the handlers of the breakpoints hit, completes the single-step if #DB is
+    /// due to it, and injects #DB into the guest if the guest would have
+    /// received it otherwise.
+    fn handle_debug_exception(&mut self) -> VmExitReason {
+        const DB_VECTOR: u8 = 1;
+        // See: 18.2.3 Debug Status Register (DR6)
+        const DR6_B0_B3: u64 = 0b1111;
+        const DR6_BS: u64 = 1 << 14;
+
+        // Intercepted #DB is not delivered to the guest. DR6 is updated
+        // already though.
+        let debug_exception = Event::Exception {
+            vector: DB_VECTOR,
+            error_code: None,
+        };
+        let dr6 = self.vmcb.dr6();
+        if !self.single_step.is_pending() || dr6 & DR6_BS == 0 {
+            let causes = hw_breakpoint::handle_debug_exception(self, dr6);
+            if causes != 0 || dr6 & DR6_B0_B3 == 0 {
+                let _ = event::inject_event(self, debug_exception);
+            }
+            return VmExitReason::DebugException;
+        }
+
+        // The single-step completed. Restore TF and BS unless the guest single-
+        // steps by itself, in which case the guest should receive #DB too.
+        let (tf, bs) = self.saved_tf_and_bs;
+        let causes =
+            hw_breakpoint::handle_debug_exception(self, if tf { dr6 } else { dr6 & !DR6_BS });
+        if causes != 0 {
+            let _ = event::inject_event(self, debug_exception);
+        }
+        if !tf {
+            self.registers.rflags &= !RFlags::FLAGS_TF.bits();
+            if !bs {
+                self.vmcb.set_dr6(self.vmcb.dr6() & !DR6_BS);
+            }
+        }
+        if !self.debug.is_active() {
+            self.vmcb
+                .set_intercept_exception(self.vmcb.intercept_exception() & !(1 << DB_VECTOR));
+        }
+
+        if let Some(callback) = self.single_step.complete() {
+            callback(self);
+        }
+        self.end_step_tables();
+        VmExitReason::SingleStep
     }
-}
 
-impl SvmGuest {
     fn handle_security_exception(&mut self) {
         assert!(self.id != 0);
         self.handle_init_signal();
         self.handle_sipi(self.wait_for_sipi());
     }
 
+    /// Handles #VMEXIT(INTR) with AVIC or the emulation of the APIC timer.
+    /// Takes the external interrupts, which are requested in the backing page
+    /// with AVIC, and queued to be injected otherwise. See `apic_timer`. Then,
+    /// delivers the NMIs taken with them to the guest, which would have
+    /// received them otherwise.
+    fn handle_interrupt(&mut self) {
+        match &mut self.avic {
+            Some(avic) => avic.handle_interrupt(),
+            None => {
+                // NMIs pending are taken too. They are delivered below.
+                unsafe { asm!("stgi", "sti", "nop", "cli", "clgi", options(nostack)) };
+                for vector in percpu::current().host_interrupts.take().iter() {
+                    self.interrupts.push(vector);
+                }
+            }
+        }
+        if take_host_nmi() {
+            if let Err(err) = event::inject_event(self, Event::Nmi) {
+                log::error!("Could not inject NMI: {err}");
+            }
+        }
+    }
+
+    /// Stops using AVIC, moving its state back into the local APIC, for the
+    /// guest leaving the x2APIC mode. See `avic`.
+    fn disable_avic(&mut self) {
+        const SVM_INTERCEPT_MISC1_INTR: u32 = 1 << 0;
+        const SVM_INTERCEPT_MISC1_MSR_PROT: u32 = 1 << 28;
+        const V_INTR_MASKING: u64 = 1 << 24;
+        const X2AVIC_ENABLE: u64 = 1 << 30;
+        const AVIC_ENABLE: u64 = 1 << 31;
+
+        let Some(mut avic) = self.avic.take() else {
+            return;
+        };
+        avic.deactivate();
+
+        let vmcb = &mut self.vmcb;
+        vmcb.set_vintr(vmcb.vintr() & !(V_INTR_MASKING | X2AVIC_ENABLE | AVIC_ENABLE));
+        let mut intercept_misc1 = vmcb.intercept_misc1() & !SVM_INTERCEPT_MISC1_INTR;
+        if SHARED_HOST_DATA.get().unwrap().msr_intercepts.is_empty() {
+            intercept_misc1 &= !SVM_INTERCEPT_MISC1_MSR_PROT;
+        }
+        vmcb.set_intercept_misc1(intercept_misc1);
+        vmcb.set_msrpm_base_pa(shared_guest_data().msrpm.pa());
+    }
+
+    /// Lets SMM handle the SMI that caused #VMEXIT, which stays pending until
+    /// GIF is set. Without this, VMRUN would set GIF and cause #VMEXIT again.
+    /// See: 15.13.3 SMI Intercept
+    fn handle_smi(&mut self) {
+        smm::record_intercepted();
+
+        // NMIs pending are taken too, by the host IDT. Deliver them to the
+        // guest, which would have received them otherwise.
+        unsafe { asm!("stgi", "clgi", options(nomem, nostack)) };
+        if take_host_nmi() {
+            if let Err(err) = event::inject_event(self, Event::Nmi) {
+                log::error!("Could not inject NMI: {err}");
+            }
+        }
+    }
+
     fn handle_init_signal(&mut self) {
         const EFER_SVME: u64 = 1 << 12;
 
@@ -170,6 +1414,21 @@ impl SvmGuest {
 
         log::debug!("INIT");
 
+        // INIT abandons L2, and resets EFER.SVME of L1. See `nested`.
+        self.leave_nested();
+        self.hsave_pa = 0;
+
+        // INIT discards the event being delivered and the queued interrupts, if
+        // any, as it resets the local APIC.
+        self.set_pending_event(None);
+        self.interrupts = InterruptQueue::default();
+        if let Some(avic) = &mut self.avic {
+            avic.reset(&mut self.vmcb);
+        }
+        if let Some(timer) = &mut self.apic_timer {
+            timer.reset();
+        }
+
         // Extension Type
         // Not Write-through
         // Cache Disabled
@@ -177,55 +1436,58 @@ impl SvmGuest {
         let new_cr0 = 1u64 << 4
             | (previous_cr0.get_bit(29) as u64) << 29
             | (previous_cr0.get_bit(30) as u64) << 30;
-        self.vmcb.state_save_area.cr0 = new_cr0;
-        self.vmcb.state_save_area.cr2 = 0;
-        self.vmcb.state_save_area.cr3 = 0;
-        self.vmcb.state_save_area.cr4 = 0;
-        self.vmcb.state_save_area.rflags = RFlags::FLAGS_A1.bits();
-        self.vmcb.state_save_area.efer = EFER_SVME;
-        self.vmcb.state_save_area.rip = 0xfff0;
-        self.vmcb.state_save_area.cs_selector = 0xf000;
-        self.vmcb.state_save_area.cs_base = 0xffff0000;
-        self.vmcb.state_save_area.cs_limit = 0xffff;
-        self.vmcb.state_save_area.cs_attrib = 0x9b;
-        self.vmcb.state_save_area.ds_selector = 0;
-        self.vmcb.state_save_area.ds_base = 0;
-        self.vmcb.state_save_area.ds_limit = 0xffff;
-        self.vmcb.state_save_area.ds_attrib = 0x93;
-        self.vmcb.state_save_area.es_selector = 0;
-        self.vmcb.state_save_area.es_base = 0;
-        self.vmcb.state_save_area.es_limit = 0xffff;
-        self.vmcb.state_save_area.es_attrib = 0x93;
-        self.vmcb.state_save_area.fs_selector = 0;
-        self.vmcb.state_save_area.fs_base = 0;
-        self.vmcb.state_save_area.fs_limit = 0xffff;
-        self.vmcb.state_save_area.fs_attrib = 0x93;
-        self.vmcb.state_save_area.gs_selector = 0;
-        self.vmcb.state_save_area.gs_base = 0;
-        self.vmcb.state_save_area.gs_limit = 0xffff;
-        self.vmcb.state_save_area.gs_attrib = 0x93;
-        self.vmcb.state_save_area.ds_selector = 0;
-        self.vmcb.state_save_area.ds_base = 0;
-        self.vmcb.state_save_area.ds_limit = 0xffff;
-        self.vmcb.state_save_area.ds_attrib = 0x93;
-        self.vmcb.state_save_area.gdtr_base = 0;
-        self.vmcb.state_save_area.gdtr_limit = 0xffff;
-        self.vmcb.state_save_area.idtr_base = 0;
-        self.vmcb.state_save_area.idtr_limit = 0xffff;
-        self.vmcb.state_save_area.ldtr_selector = 0;
-        self.vmcb.state_save_area.ldtr_base = 0;
-        self.vmcb.state_save_area.ldtr_limit = 0xffff;
-        self.vmcb.state_save_area.ldtr_attrib = 0x82;
-        self.vmcb.state_save_area.tr_selector = 0;
-        self.vmcb.state_save_area.tr_base = 0;
-        self.vmcb.state_save_area.tr_limit = 0xffff;
-        self.vmcb.state_save_area.tr_attrib = 0x8b;
+        self.vmcb.set_cr0(new_cr0);
+        self.vmcb.set_cr2(0);
+        self.vmcb.set_cr3(0);
+        self.vmcb.set_cr4(0);
+        self.vmcb.set_rflags(RFlags::FLAGS_A1.bits());
+        self.vmcb.set_efer(EFER_SVME);
+        self.vmcb.set_s_cet(0);
+        self.vmcb.set_ssp(0);
+        self.vmcb.set_isst_addr(0);
+        self.vmcb.set_rip(0xfff0);
+        self.vmcb.set_cs_selector(0xf000);
+        self.vmcb.set_cs_base(0xffff0000);
+        self.vmcb.set_cs_limit(0xffff);
+        self.vmcb.set_cs_attrib(0x9b);
+        self.vmcb.set_ds_selector(0);
+        self.vmcb.set_ds_base(0);
+        self.vmcb.set_ds_limit(0xffff);
+        self.vmcb.set_ds_attrib(0x93);
+        self.vmcb.set_es_selector(0);
+        self.vmcb.set_es_base(0);
+        self.vmcb.set_es_limit(0xffff);
+        self.vmcb.set_es_attrib(0x93);
+        self.vmcb.set_fs_selector(0);
+        self.vmcb.set_fs_base(0);
+        self.vmcb.set_fs_limit(0xffff);
+        self.vmcb.set_fs_attrib(0x93);
+        self.vmcb.set_gs_selector(0);
+        self.vmcb.set_gs_base(0);
+        self.vmcb.set_gs_limit(0xffff);
+        self.vmcb.set_gs_attrib(0x93);
+        self.vmcb.set_ds_selector(0);
+        self.vmcb.set_ds_base(0);
+        self.vmcb.set_ds_limit(0xffff);
+        self.vmcb.set_ds_attrib(0x93);
+        self.vmcb.set_gdtr_base(0);
+        self.vmcb.set_gdtr_limit(0xffff);
+        self.vmcb.set_idtr_base(0);
+        self.vmcb.set_idtr_limit(0xffff);
+        self.vmcb.set_ldtr_selector(0);
+        self.vmcb.set_ldtr_base(0);
+        self.vmcb.set_ldtr_limit(0xffff);
+        self.vmcb.set_ldtr_attrib(0x82);
+        self.vmcb.set_tr_selector(0);
+        self.vmcb.set_tr_base(0);
+        self.vmcb.set_tr_limit(0xffff);
+        self.vmcb.set_tr_attrib(0x8b);
         self.registers.rax = 0;
         self.registers.rdx = cpuid!(0x1).eax as _;
         self.registers.rbx = 0;
         self.registers.rcx = 0;
         self.registers.rbp = 0;
-        self.vmcb.state_save_area.rsp = 0;
+        self.vmcb.set_rsp(0);
         self.registers.rdi = 0;
         self.registers.rsi = 0;
         self.registers.r8 = 0;
@@ -242,11 +1504,12 @@ impl SvmGuest {
             x86::debugregs::dr2_write(0);
             x86::debugregs::dr3_write(0);
         };
-        self.vmcb.state_save_area.dr6 = 0xffff0ff0;
-        self.vmcb.state_save_area.dr7 = 0x400;
+        self.vmcb.set_dr6(0xffff0ff0);
+        self.vmcb.set_dr7(0x400);
+        hw_breakpoint::reset(self);
 
-        self.vmcb.control_area.tlb_control = TlbControl::FlushAll as _;
-        self.vmcb.control_area.vmcb_clean = 0;
+        self.vmcb.set_tlb_control(TlbControl::FlushAll);
+        self.vmcb.mark_all_dirty();
     }
 
     fn wait_for_sipi(&self) -> u8 {
@@ -267,9 +1530,9 @@ impl SvmGuest {
         assert!(self.activity_state.load(Ordering::Relaxed) == GuestActivityState::Active as u8);
         log::debug!("SIPI vector {vector:#x?}");
 
-        self.vmcb.state_save_area.cs_selector = (vector as u16) << 8;
-        self.vmcb.state_save_area.cs_base = (vector as u64) << 12;
-        self.vmcb.state_save_area.rip = 0;
+        self.vmcb.set_cs_selector((vector as u16) << 8);
+        self.vmcb.set_cs_base((vector as u64) << 12);
+        self.vmcb.set_rip(0);
         self.registers.rip = 0;
     }
 
@@ -278,7 +1541,7 @@ impl SvmGuest {
         let apic_base = apic_base_raw & !0xfff;
         let pt_index = apic_base.get_bits(12..=20) as usize; // [20:12]
 
-        let mut npt = SHARED_GUEST_DATA.npt.write();
+        let mut npt = shared_guest_data().npt.write();
         let pt = npt.apic_pt();
         pt.0.entries[pt_index].set_writable(!enable);
 
@@ -286,21 +1549,16 @@ impl SvmGuest {
         // shootdown. It is fine because APIC writes we want to see are done by
         // this processors. We need to handle #VMEXIT(NFP) on other processors
         // if it happens.
-        self.vmcb.control_area.tlb_control = TlbControl::FlushAll as _;
+        self.vmcb.set_tlb_control(TlbControl::FlushAll);
     }
 
-    fn handle_nested_page_fault(&mut self) {
+    fn handle_apic_write(&mut self) {
         if self.id == apic_id::PROCESSOR_COUNT.load(Ordering::Relaxed) - 1 {
             log::debug!("Stopping APIC write interception");
             self.intercept_apic_write(false);
         }
 
-        let instructions = unsafe {
-            core::slice::from_raw_parts(
-                self.vmcb.control_area.guest_instruction_bytes.as_ptr(),
-                self.vmcb.control_area.num_of_bytes_fetched as _,
-            )
-        };
+        let instructions = self.vmcb.guest_instruction_bytes();
 
         // This one is by far the most frequent one. Micro-optimize this path by
         // checking this pattern first.
@@ -341,7 +1599,7 @@ impl SvmGuest {
                 }
                 _ => {
                     log::error!("{:#x?}", self.registers);
-                    log::error!("{:#x?}", self.vmcb);
+                    self.vmcb.dump();
                     panic!("Unhandled APIC access instructions: {:02x?}", instructions);
                 }
             }
@@ -350,7 +1608,7 @@ impl SvmGuest {
         self.registers.rip += instr_len;
 
         let message_type = value.get_bits(8..=10);
-        let faulting_gpa = self.vmcb.control_area.exit_info2;
+        let faulting_gpa = self.vmcb.exit_info2();
         let apic_register = faulting_gpa & 0xfff;
         if apic_register != 0xb0 && self.id == 0 {
             log::trace!("APIC reg:{apic_register:#x} <= {value:#x}");
@@ -387,49 +1645,139 @@ impl SvmGuest {
         let icr_high_value = unsafe { *(icr_high_addr as *mut u32) };
 
         // Collect necessary bits to emulate, that is, vector and destination.
+        // Send the actual SIPI if it cannot be emulated.
         let vector = value.get_bits(0..=7) as u8;
-        let apic_id = icr_high_value.get_bits(24..=31) as u8;
-        let processor_id = apic_id::processor_id_from(apic_id).unwrap();
-        log::debug!("SIPI to {apic_id} with vector {vector:#x?}");
-        assert!(vector != GuestActivityState::WaitForSipi as u8);
-
-        // Update the activity state of the target processor with the obtained
-        // vector value. The target processor should get out from the busy loop
-        // after this. Note that it is possible that the target processor is not
-        // yet in the WaitForSipi state when #VMEXIT(#SX) has not been processed.
-        // It is fine, as SIPI will be sent twice, and almost certain that 2nd
-        // SIPI is late enough.
-        let activity_state = &SHARED_GUEST_DATA.activity_states[processor_id];
-        let _ = activity_state.compare_exchange(
-            GuestActivityState::WaitForSipi as u8,
-            vector,
-            Ordering::Relaxed,
-            Ordering::Relaxed,
-        );
+        let apic_id = icr_high_value.get_bits(24..=31);
+        if !emulate_sipi(vector, apic_id) {
+            let apic_reg = faulting_gpa as *mut u32;
+            unsafe { apic_reg.write_volatile(value) };
+        }
     }
 
     fn initialize_control(&mut self) {
+        const SVM_INTERCEPT_CR_WRITE_CR3: u16 = 1 << 3;
+        const SVM_INTERCEPT_CR_WRITE_CR4: u16 = 1 << 4;
+        const SVM_INTERCEPT_MISC1_SMI: u32 = 1 << 2;
+        const SVM_INTERCEPT_MISC1_CR0_SEL_WRITE: u32 = 1 << 5;
         const SVM_INTERCEPT_MISC1_CPUID: u32 = 1 << 18;
+        const SVM_INTERCEPT_MISC1_INVD: u32 = 1 << 22;
+        const SVM_INTERCEPT_MISC1_INVLPGA: u32 = 1 << 26;
+        const SVM_INTERCEPT_MISC1_IOIO_PROT: u32 = 1 << 27;
+        const SVM_INTERCEPT_MISC1_MSR_PROT: u32 = 1 << 28;
         const SVM_INTERCEPT_MISC2_VMRUN: u32 = 1 << 0;
+        const SVM_INTERCEPT_MISC2_VMMCALL: u32 = 1 << 1;
+        const SVM_INTERCEPT_MISC2_VMLOAD: u32 = 1 << 2;
+        const SVM_INTERCEPT_MISC2_VMSAVE: u32 = 1 << 3;
+        const SVM_INTERCEPT_MISC2_STGI: u32 = 1 << 4;
+        const SVM_INTERCEPT_MISC2_CLGI: u32 = 1 << 5;
+        const SVM_INTERCEPT_MISC2_SKINIT: u32 = 1 << 6;
+        const SVM_INTERCEPT_MISC2_WBINVD: u32 = 1 << 9;
+        const SVM_INTERCEPT_MISC2_XSETBV: u32 = 1 << 13;
         const SVM_NP_ENABLE_NP_ENABLE: u64 = 1 << 0;
 
-        self.vmcb.control_area.intercept_misc1 = SVM_INTERCEPT_MISC1_CPUID;
-        self.vmcb.control_area.intercept_misc2 = SVM_INTERCEPT_MISC2_VMRUN;
-        self.vmcb.control_area.pause_filter_count = u16::MAX;
+        // Intercept XSETBV to validate XCR0 against CPUID the guest sees, as on
+        // Intel processors, where it always causes VM-exit. Intercept the SVM
+        // instructions too, which would otherwise run against the state of the
+        // host, as EFER.SVME is set regardless of what the guest sees.
+        // See: 15.5.1 Basic Operation
+        self.vmcb
+            .set_intercept_misc1(SVM_INTERCEPT_MISC1_CPUID | SVM_INTERCEPT_MISC1_INVLPGA);
+        self.vmcb.set_intercept_misc2(
+            SVM_INTERCEPT_MISC2_VMRUN
+                | SVM_INTERCEPT_MISC2_VMMCALL
+                | SVM_INTERCEPT_MISC2_VMLOAD
+                | SVM_INTERCEPT_MISC2_VMSAVE
+                | SVM_INTERCEPT_MISC2_STGI
+                | SVM_INTERCEPT_MISC2_CLGI
+                | SVM_INTERCEPT_MISC2_SKINIT
+                | SVM_INTERCEPT_MISC2_XSETBV,
+        );
+        self.vmcb.set_pause_filter_count(u16::MAX);
+
+        // Intercept SMIs to count them with `SharedHostData::smi_intercept`.
+        // See: 15.13.3 SMI Intercept
+        if smm::is_intercepted() {
+            self.vmcb
+                .set_intercept_misc1(self.vmcb.intercept_misc1() | SVM_INTERCEPT_MISC1_SMI);
+        }
+
+        // Intercept INVD and WBINVD as `SharedHostData::cache_invalidation`
+        // requires. WBINVD also intercepts WBNOINVD.
+        // See: 15.9 Instruction Intercepts
+        let cache_invalidation = SHARED_HOST_DATA.get().unwrap().cache_invalidation;
+        if cache_invalidation.intercepts_invd() {
+            self.vmcb
+                .set_intercept_misc1(self.vmcb.intercept_misc1() | SVM_INTERCEPT_MISC1_INVD);
+        }
+        if cache_invalidation.intercepts_wbinvd() {
+            self.vmcb
+                .set_intercept_misc2(self.vmcb.intercept_misc2() | SVM_INTERCEPT_MISC2_WBINVD);
+        }
+
+        // Intercept MSR accesses per the MSR permissions map only if any MSR is
+        // to be intercepted. Otherwise, MSRs outside the map would cause
+        // #VMEXIT needlessly.
+        // See: 15.11 MSR Intercepts
+        if !SHARED_HOST_DATA.get().unwrap().msr_intercepts.is_empty() {
+            self.vmcb.set_msrpm_base_pa(shared_guest_data().msrpm.pa());
+            self.vmcb
+                .set_intercept_misc1(self.vmcb.intercept_misc1() | SVM_INTERCEPT_MISC1_MSR_PROT);
+        }
+
+        // Likewise, intercept I/O instructions per the I/O permissions map only
+        // if any port is to be intercepted.
+        // See: 15.10 I/O Intercepts
+        if !SHARED_HOST_DATA.get().unwrap().io_intercepts.is_empty() {
+            self.vmcb.set_iopm_base_pa(shared_guest_data().iopm.pa());
+            self.vmcb
+                .set_intercept_misc1(self.vmcb.intercept_misc1() | SVM_INTERCEPT_MISC1_IOIO_PROT);
+        }
 
-        // Address Space Identifier (ASID) is useful when the given logical processor
-        // runs more than one guests. We do not but still need to set non-zero value.
+        // Intercept writes to CR0 changing bits other than TS and MP, and writes
+        // to CR4, if any bit of either is guarded. All of them cause #VMEXIT,
+        // unlike with the Intel guest/host masks, and writes not changing the
+        // guarded bits are applied by the host. Likewise, intercept writes to
+        // CR3 for CR3 tracking. Decode assists are required to find the value
+        // written.
+        // See: 15.9 Instruction Intercepts
+        // See: 15.33 Decode Assists
+        let shared_host = SHARED_HOST_DATA.get().unwrap();
+        let cr_intercepts = &shared_host.cr_intercepts;
+        let mut cr_write = 0;
+        if cr_intercepts.cr4_bits() != 0 {
+            cr_write |= SVM_INTERCEPT_CR_WRITE_CR4;
+        }
+        if shared_host.cr3_tracking.is_enabled() {
+            cr_write |= SVM_INTERCEPT_CR_WRITE_CR3;
+        }
+        if cr_write != 0 || cr_intercepts.cr0_bits() != 0 {
+            if cpuid!(0x8000_000a).edx.get_bit(7) {
+                if cr_intercepts.cr0_bits() != 0 {
+                    self.vmcb.set_intercept_misc1(
+                        self.vmcb.intercept_misc1() | SVM_INTERCEPT_MISC1_CR0_SEL_WRITE,
+                    );
+                }
+                self.vmcb.set_intercept_cr_write(cr_write);
+            } else {
+                log::warn!("Decode assists are not supported. Ignoring the CR intercepts");
+            }
+        }
+
+        // Tag the TLB entries of the guest with the ASID of this processor. Flush
+        // those left from earlier virtualization with the same ASID, if any.
         // See: 15.16 TLB Control
-        self.vmcb.control_area.guest_asid = 1;
+        self.vmcb.set_guest_asid(self.asid.value());
+        self.flush_guest_tlb();
 
         // Enable nested paging. This is done by:
         // - Setting the NP_ENABLE bit in VMCB, and
         // - Setting the base address of the nested PML4
         //
         // See: 15.25.3 Enabling Nested Paging
-        let nested_pml4_addr = SHARED_GUEST_DATA.npt.read().as_ref() as *const _;
-        self.vmcb.control_area.np_enable = SVM_NP_ENABLE_NP_ENABLE;
-        self.vmcb.control_area.ncr3 = platform_ops::get().pa(nested_pml4_addr as _);
+        let nested_pml4_addr = shared_guest_data().npt.read().as_ref() as *const _;
+        self.vmcb.set_np_enable(SVM_NP_ENABLE_NP_ENABLE);
+        self.vmcb
+            .set_ncr3(platform_ops::get().pa(nested_pml4_addr as _));
 
         // Convert #INIT to #SX. One cannot simply intercept #INIT because even
         // if we do, #INIT is still pending and will be delivered anyway.
@@ -437,8 +1785,27 @@ impl SvmGuest {
         const R_INIT: u64 = 1 << 1;
         wrmsr(SVM_MSR_VM_CR, rdmsr(SVM_MSR_VM_CR) | R_INIT);
 
+        // Intercept #MC too, so that the errors are logged before #MC is
+        // injected into the guest. See `machine_check`.
+        const MACHINE_CHECK: u32 = 1 << 18;
         const SECURITY_EXCEPTION: u32 = 1 << 30;
-        self.vmcb.control_area.intercept_exception = SECURITY_EXCEPTION;
+        self.vmcb
+            .set_intercept_exception(MACHINE_CHECK | SECURITY_EXCEPTION);
+
+        // Deliver external interrupts through the backing page with AVIC. This
+        // intercepts physical interrupts, and MSR accesses per the MSR
+        // permissions map of the vCPU. See `avic`.
+        if let Some(avic) = &mut self.avic {
+            avic.initialize(&mut self.vmcb);
+        }
+
+        // Take over the timer of the local APIC to emulate the preemption
+        // timer. This intercepts physical interrupts without AVIC too. See
+        // `apic_timer`.
+        let avic = self.avic.is_some();
+        if let Some(timer) = &mut self.apic_timer {
+            timer.initialize(&mut self.vmcb, avic);
+        }
     }
 
     fn initialize_guest(&mut self) {
@@ -448,253 +1815,103 @@ impl SvmGuest {
         let gdtr = sgdt();
         let guest_gdt = gdtr.base as u64;
 
-        self.vmcb.state_save_area.es_selector = es().bits();
-        self.vmcb.state_save_area.cs_selector = cs().bits();
-        self.vmcb.state_save_area.ss_selector = ss().bits();
-        self.vmcb.state_save_area.ds_selector = ds().bits();
-        self.vmcb.state_save_area.es_attrib = get_segment_access_right(guest_gdt, es().bits());
-        self.vmcb.state_save_area.cs_attrib = get_segment_access_right(guest_gdt, cs().bits());
-        self.vmcb.state_save_area.ss_attrib = get_segment_access_right(guest_gdt, ss().bits());
-        self.vmcb.state_save_area.ds_attrib = get_segment_access_right(guest_gdt, ds().bits());
-        self.vmcb.state_save_area.es_limit = get_segment_limit(guest_gdt, es().bits());
-        self.vmcb.state_save_area.cs_limit = get_segment_limit(guest_gdt, cs().bits());
-        self.vmcb.state_save_area.ss_limit = get_segment_limit(guest_gdt, ss().bits());
-        self.vmcb.state_save_area.ds_limit = get_segment_limit(guest_gdt, ds().bits());
-        self.vmcb.state_save_area.gdtr_base = gdtr.base as _;
-        self.vmcb.state_save_area.gdtr_limit = u32::from(gdtr.limit);
-        self.vmcb.state_save_area.idtr_base = idtr.base as _;
-        self.vmcb.state_save_area.idtr_limit = u32::from(idtr.limit);
-        self.vmcb.state_save_area.efer = rdmsr(x86::msr::IA32_EFER) | EFER_SVME;
-        self.vmcb.state_save_area.cr0 = cr0().bits() as _;
-        self.vmcb.state_save_area.cr3 = cr3();
-        self.vmcb.state_save_area.cr4 = cr4().bits() as _;
-        self.vmcb.state_save_area.rip = self.registers.rip;
-        self.vmcb.state_save_area.rsp = self.registers.rsp;
-        self.vmcb.state_save_area.rflags = self.registers.rflags;
-        self.vmcb.state_save_area.rax = self.registers.rax;
-        self.vmcb.state_save_area.gpat = rdmsr(x86::msr::IA32_PAT);
+        self.vmcb.set_es_selector(es().bits());
+        self.vmcb.set_cs_selector(cs().bits());
+        self.vmcb.set_ss_selector(ss().bits());
+        self.vmcb.set_ds_selector(ds().bits());
+        self.vmcb
+            .set_es_attrib(get_segment_access_right(guest_gdt, es().bits()));
+        self.vmcb
+            .set_cs_attrib(get_segment_access_right(guest_gdt, cs().bits()));
+        self.vmcb
+            .set_ss_attrib(get_segment_access_right(guest_gdt, ss().bits()));
+        self.vmcb
+            .set_ds_attrib(get_segment_access_right(guest_gdt, ds().bits()));
+        self.vmcb
+            .set_es_limit(get_segment_limit(guest_gdt, es().bits()));
+        self.vmcb
+            .set_cs_limit(get_segment_limit(guest_gdt, cs().bits()));
+        self.vmcb
+            .set_ss_limit(get_segment_limit(guest_gdt, ss().bits()));
+        self.vmcb
+            .set_ds_limit(get_segment_limit(guest_gdt, ds().bits()));
+        self.vmcb.set_gdtr_base(gdtr.base as _);
+        self.vmcb.set_gdtr_limit(u32::from(gdtr.limit));
+        self.vmcb.set_idtr_base(idtr.base as _);
+        self.vmcb.set_idtr_limit(u32::from(idtr.limit));
+        self.vmcb.set_efer(rdmsr(x86::msr::IA32_EFER) | EFER_SVME);
+        self.vmcb.set_cr0(cr0().bits() as _);
+        self.vmcb.set_cr3(cr3());
+        self.vmcb.set_cr4(cr4().bits() as _);
+        self.vmcb.set_rip(self.registers.rip);
+        self.vmcb.set_rsp(self.registers.rsp);
+        self.vmcb.set_rflags(self.registers.rflags);
+        self.vmcb.set_rax(self.registers.rax);
+        self.vmcb.set_gpat(rdmsr(x86::msr::IA32_PAT));
+        if cet::is_supported() {
+            self.vmcb.set_s_cet(rdmsr(cet::IA32_S_CET));
+            self.vmcb.set_ssp(self.registers.ssp);
+            self.vmcb
+                .set_isst_addr(rdmsr(cet::IA32_INTERRUPT_SSP_TABLE_ADDR));
+        }
 
         // VMSAVE copies some of the current register values into VMCB. Take
         // advantage of it.
         vmsave(self.vmcb_pa);
     }
 
-    fn initialize_host(&mut self) {
+    fn initialize_host(&mut self) -> Result<(), HvError> {
         let shared_host = SHARED_HOST_DATA.get().unwrap();
 
-        if let Some(host_pt) = &shared_host.pt {
-            let pml4 = addr_of!(*host_pt.as_ref());
-            unsafe { cr3_write(platform_ops::get().pa(pml4 as _)) };
-        }
-
+        // Apply the custom GDT first, as it is the only step that may fail, and
+        // it fails before changing anything.
         if let Some(host_gdt_and_tss) = &shared_host.gdts {
-            host_gdt_and_tss[self.id].apply().unwrap();
+            host_gdt_and_tss
+                .get(self.id)
+                .ok_or(HvError::InvalidHostGdt(self.id))?
+                .apply()
+                .map_err(|_| HvError::InvalidHostGdt(self.id))?;
         }
 
+        // Use the copy of the custom CR3 if specified, or the current, with the
+        // host window.
+        unsafe { cr3_write(host_window::host_cr3()) };
+
         if let Some(host_idt) = &shared_host.idt {
             lidt(&host_idt.idtr());
         }
 
-        // Save some of the current register values as host state. They are
-        // restored shortly after #VMEXIT.
-        vmsave(self.host_vmcb_pa);
-    }
-}
-
-/// Table 15-9. TLB Control Byte Encodings
-#[allow(dead_code)]
-#[repr(u32)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum TlbControl {
-    DoNotFlush = 0x0,
-    FlushAll = 0x1,
-    FlushGuests = 0x3,
-    FlushGuestsNonGlobal = 0x7,
-}
-
-#[derive(Debug, derive_deref::Deref, derive_deref::DerefMut)]
-struct Vmcb {
-    ptr: Box<VmcbRaw>,
-}
-
-impl Default for Vmcb {
-    fn default() -> Self {
-        Self {
-            ptr: zeroed_box::<VmcbRaw>(),
+        // Let the host switch the extended registers with `XSAVE`.
+        if self.extended.is_some() && is_xsave_supported() {
+            cr4_write(cr4() | Cr4::CR4_ENABLE_OS_XSAVE);
         }
-    }
-}
 
-/// The virtual machine control block (VMCB), which describes a virtual machine
-/// (guest) to be executed.
-///
-/// See: Appendix B Layout of VMCB
-#[derive(Debug, Default)]
-#[repr(C, align(4096))]
-struct VmcbRaw {
-    control_area: ControlArea,
-    state_save_area: StateSaveArea,
-}
-const _: () = assert!(core::mem::size_of::<VmcbRaw>() == 0x1000);
-
-/// The "metadata" area where we can specify what operations to intercept and
-/// can read details of #VMEXIT.
-///
-/// See: Table B-1. VMCB Layout, Control Area
-#[derive(derivative::Derivative)]
-#[derivative(Debug, Default)]
-#[repr(C)]
-struct ControlArea {
-    intercept_cr_read: u16,   // +0x000
-    intercept_cr_write: u16,  // +0x002
-    intercept_dr_read: u16,   // +0x004
-    intercept_dr_write: u16,  // +0x006
-    intercept_exception: u32, // +0x008
-    intercept_misc1: u32,     // +0x00c
-    intercept_misc2: u32,     // +0x010
-    intercept_misc3: u32,     // +0x014
-    #[derivative(Debug = "ignore", Default(value = "[0; 36]"))]
-    _padding1: [u8; 0x03c - 0x018], // +0x018
-    pause_filter_threshold: u16, // +0x03c
-    pause_filter_count: u16,  // +0x03e
-    iopm_base_pa: u64,        // +0x040
-    msrpm_base_pa: u64,       // +0x048
-    tsc_offset: u64,          // +0x050
-    guest_asid: u32,          // +0x058
-    tlb_control: u32,         // +0x05c
-    vintr: u64,               // +0x060
-    interrupt_shadow: u64,    // +0x068
-    exit_code: u64,           // +0x070
-    exit_info1: u64,          // +0x078
-    exit_info2: u64,          // +0x080
-    exit_int_info: u64,       // +0x088
-    np_enable: u64,           // +0x090
-    avic_apic_bar: u64,       // +0x098
-    guest_pa_pf_ghcb: u64,    // +0x0a0
-    event_inj: u64,           // +0x0a8
-    ncr3: u64,                // +0x0b0
-    lbr_virtualization_enable: u64, // +0x0b8
-    vmcb_clean: u32,          // +0x0c0
-    _reserved: u32,           // +0x0c4
-    nrip: u64,                // +0x0c8
-    num_of_bytes_fetched: u8, // +0x0d0
-    guest_instruction_bytes: [u8; 15], // +0x0d1
-    avic_apic_backing_page_pointer: u64, // +0x0e0
-    #[derivative(Debug = "ignore")]
-    _padding2: u64, // +0x0e8
-    avic_logical_table_pointer: u64, // +0x0f0
-    avic_physical_table_pointer: u64, // +0x0f8
-    #[derivative(Debug = "ignore")]
-    _padding3: u64, // +0x100
-    vmcb_save_state_pointer: u64, // +0x108
-    #[derivative(Debug = "ignore", Default(value = "[0; 720]"))]
-    _padding4: [u8; 0x3e0 - 0x110], // +0x110
-    reserved_for_host: [u8; 0x20], // +0x3e0
-}
-const _: () = assert!(core::mem::size_of::<ControlArea>() == 0x400);
+        // Run the host with supervisor CET disabled, which does not use the
+        // shadow stack of the guest then. VMRUN saves it as the host state.
+        if cet::is_supported() {
+            wrmsr(cet::IA32_S_CET, 0);
+        }
 
-/// The ares to specify and read guest register values.
-///
-/// See: Table B-2. VMCB Layout, State Save Area
-#[derive(derivative::Derivative)]
-#[derivative(Debug, Default)]
-#[repr(C)]
-struct StateSaveArea {
-    es_selector: u16,   // +0x000
-    es_attrib: u16,     // +0x002
-    es_limit: u32,      // +0x004
-    es_base: u64,       // +0x008
-    cs_selector: u16,   // +0x010
-    cs_attrib: u16,     // +0x012
-    cs_limit: u32,      // +0x014
-    cs_base: u64,       // +0x018
-    ss_selector: u16,   // +0x020
-    ss_attrib: u16,     // +0x022
-    ss_limit: u32,      // +0x024
-    ss_base: u64,       // +0x028
-    ds_selector: u16,   // +0x030
-    ds_attrib: u16,     // +0x032
-    ds_limit: u32,      // +0x034
-    ds_base: u64,       // +0x038
-    fs_selector: u16,   // +0x040
-    fs_attrib: u16,     // +0x042
-    fs_limit: u32,      // +0x044
-    fs_base: u64,       // +0x048
-    gs_selector: u16,   // +0x050
-    gs_attrib: u16,     // +0x052
-    gs_limit: u32,      // +0x054
-    gs_base: u64,       // +0x058
-    gdtr_selector: u16, // +0x060 (Reserved)
-    gdtr_attrib: u16,   // +0x062 (Reserved)
-    gdtr_limit: u32,    // +0x064
-    gdtr_base: u64,     // +0x068
-    ldtr_selector: u16, // +0x070 (Reserved)
-    ldtr_attrib: u16,   // +0x072 (Reserved)
-    ldtr_limit: u32,    // +0x074
-    ldtr_base: u64,     // +0x078
-    idtr_selector: u16, // +0x080
-    idtr_attrib: u16,   // +0x082
-    idtr_limit: u32,    // +0x084
-    idtr_base: u64,     // +0x088
-    tr_selector: u16,   // +0x090
-    tr_attrib: u16,     // +0x092
-    tr_limit: u32,      // +0x094
-    tr_base: u64,       // +0x098
-    #[derivative(Debug = "ignore", Default(value = "[0; 43]"))]
-    _padding1: [u8; 0x0cb - 0x0a0], // +0x0a0
-    cpl: u8,            // +0x0cb
-    #[derivative(Debug = "ignore")]
-    _padding2: u32, // +0x0cc
-    efer: u64,          // +0x0d0
-    #[derivative(Debug = "ignore", Default(value = "[0; 112]"))]
-    _padding3: [u8; 0x148 - 0x0d8], // +0x0d8
-    cr4: u64,           // +0x148
-    cr3: u64,           // +0x150
-    cr0: u64,           // +0x158
-    dr7: u64,           // +0x160
-    dr6: u64,           // +0x168
-    rflags: u64,        // +0x170
-    rip: u64,           // +0x178
-    #[derivative(Debug = "ignore", Default(value = "[0; 88]"))]
-    _padding4: [u8; 0x1d8 - 0x180], // +0x180
-    rsp: u64,           // +0x1d8
-    s_cet: u64,         // +0x1e0
-    ssp: u64,           // +0x1e8
-    isst_addr: u64,     // +0x1f0
-    rax: u64,           // +0x1f8
-    star: u64,          // +0x200
-    lstar: u64,         // +0x208
-    cstar: u64,         // +0x210
-    sf_mask: u64,       // +0x218
-    kernel_gs_base: u64, // +0x220
-    sysenter_cs: u64,   // +0x228
-    sysenter_esp: u64,  // +0x230
-    sysenter_eip: u64,  // +0x238
-    cr2: u64,           // +0x240
-    #[derivative(Debug = "ignore", Default(value = "[0; 32]"))]
-    _padding5: [u8; 0x268 - 0x248], // +0x248
-    gpat: u64,          // +0x268
-    dbg_ctl: u64,       // +0x270
-    br_from: u64,       // +0x278
-    br_to: u64,         // +0x280
-    last_excep_from: u64, // +0x288
-    last_excep_to: u64, // +0x290
-    #[derivative(Debug = "ignore", Default(value = "[0; 71]"))]
-    _padding6: [u8; 0x2df - 0x298], // +0x298
-    spec_ctl: u64,      // +0x2e0
+        // Save some of the current register values as host state. They are
+        // restored shortly after #VMEXIT. The GS base is replaced with that for
+        // the host, which the current code keeps running without until then.
+        vmsave(self.host_vmcb_pa);
+        let gs_base = percpu::host_gs_base(self.id, self.host_vmcb.gs_base(), shared_host);
+        self.host_vmcb.set_gs_base(gs_base);
+        Ok(())
+    }
 }
-const _: () = assert!(core::mem::size_of::<StateSaveArea>() == 0x2e8);
 
 #[derive(derive_deref::Deref, derive_deref::DerefMut)]
 struct HostStateArea {
-    ptr: Box<HostStateAreaRaw>,
+    ptr: PageBox<HostStateAreaRaw>,
 }
 
-impl Default for HostStateArea {
-    fn default() -> Self {
-        Self {
-            ptr: zeroed_box::<HostStateAreaRaw>(),
-        }
+impl HostStateArea {
+    fn new() -> Result<Self, HvError> {
+        Ok(Self {
+            ptr: PageBox::try_new()?,
+        })
     }
 }
 
@@ -712,13 +1929,28 @@ impl Default for HostStateAreaRaw {
     }
 }
 
-extern "C" {
+extern "win64" {
     /// Runs the guest until #VMEXIT occurs.
-    fn run_svm_guest(registers: &mut Registers, vmcb_pa: u64, host_vmcb_pa: u64);
+    fn run_svm_guest(
+        registers: &mut Registers,
+        vmcb_pa: u64,
+        host_vmcb_pa: u64,
+        extended: *mut ExtendedRegisters,
+    );
 }
 global_asm!(include_str!("../capture_registers.inc"));
 global_asm!(include_str!("run_guest.S"));
 
+/// Loads registers from VMCB
+fn vmload(vmcb_pa: u64) {
+    unsafe {
+        asm!(
+            "vmload rax",
+            in("rax") vmcb_pa, options(nostack, preserves_flags),
+        )
+    };
+}
+
 /// Saves registers to VMCS
 fn vmsave(vmcb_pa: u64) {
     unsafe {
@@ -769,27 +2001,109 @@ fn get_segment_limit(table_base: u64, selector: u16) -> u32 {
     limit as u32
 }
 
-struct SharedGuestData {
-    npt: RwLock<NestedPageTables>,
-    activity_states: [AtomicU8; 0xff],
+pub(super) struct SharedGuestData {
+    pub(super) npt: RwLock<NestedPageTables>,
+    /// The activity state of each processor, indexed by the processor ID.
+    activity_states: Box<[AtomicU8]>,
+
+    /// The MSR permissions map. Must be physically contiguous.
+    pub(super) msrpm: PageBox<[Page; 2]>,
+
+    /// The I/O permissions map. Must be physically contiguous.
+    pub(super) iopm: PageBox<[Page; 3]>,
 }
 
 impl SharedGuestData {
-    fn new() -> Self {
-        let mut npt = NestedPageTables::new();
-        npt.build_identity();
+    fn new() -> Result<Self, HvError> {
+        let mut npt = NestedPageTables::new()?;
+        npt.build_identity()?;
         npt.split_apic_page();
 
-        Self {
+        let shared_host = SHARED_HOST_DATA.get().unwrap();
+        let mut msrpm = PageBox::<[Page; 2]>::try_new()?;
+        let msrpm_bytes = unsafe { &mut *msrpm.as_mut_ptr().cast::<[u8; 0x2000]>() };
+        shared_host.msr_intercepts.build_svm_msrpm(msrpm_bytes);
+
+        // The last 4KB of the IOPM is for accesses wrapping around 0xffff and
+        // left cleared.
+        let mut iopm = PageBox::<[Page; 3]>::try_new()?;
+        let iopm_bytes = unsafe { &mut *iopm.as_mut_ptr().cast::<[u8; 0x2000]>() };
+        shared_host.io_intercepts.build_bitmap(iopm_bytes);
+
+        Ok(Self {
             npt: RwLock::new(npt),
-            activity_states: core::array::from_fn(|_| {
-                AtomicU8::new(GuestActivityState::Active as u8)
-            }),
-        }
+            activity_states: (0..apic_id::capacity())
+                .map(|_| AtomicU8::new(GuestActivityState::Active as u8))
+                .collect(),
+            msrpm,
+            iopm,
+        })
     }
 }
 
-static SHARED_GUEST_DATA: Lazy<SharedGuestData> = Lazy::new(SharedGuestData::new);
+/// Returns `intercepts` with the handler emulating Startup IPIs sent through
+/// the x2APIC ICR, which the NPT-based interception of the xAPIC page does not
+/// see. The handler already set for the ICR, if any, is called first. Called
+/// from the guest before any processor is virtualized.
+pub(crate) fn install_sipi_emulation(mut intercepts: MsrIntercepts) -> MsrIntercepts {
+    const IA32_X2APIC_ICR: u32 = 0x830;
+
+    let next = intercepts.take_write_handler(IA32_X2APIC_ICR);
+    intercepts.on_write(IA32_X2APIC_ICR, move |vcpu, msr, value| {
+        let value = match &next {
+            Some(handler) => handler(vcpu, msr, value)?,
+            None => value,
+        };
+
+        // Figure 16-18. Interrupt Command Register (APIC Offset 300h–310h)
+        // See: 16.11 x2APIC (Extended Local APIC)
+        let is_sipi = value.get_bits(8..=10) == 0b110
+            && !value.get_bit(11)
+            && value.get_bits(18..=19) == 0b00;
+        if is_sipi && emulate_sipi(value.get_bits(0..=7) as u8, value.get_bits(32..=63) as u32) {
+            return None;
+        }
+        Some(value)
+    })
+}
+
+/// Emulates the Startup IPI with `vector` to the processor with `apic_id`.
+/// Returns `false` if the processor is not waiting for an emulated SIPI, that
+/// is, is not enumerated, is devirtualized, or has not processed INIT yet, in
+/// which case the actual SIPI should be sent.
+fn emulate_sipi(vector: u8, apic_id: u32) -> bool {
+    let Some(processor_id) = apic_id::processor_id_from(apic_id) else {
+        log::warn!("SIPI to {apic_id}, which is not enumerated and starts unvirtualized");
+        return false;
+    };
+    log::debug!("SIPI to {apic_id} with vector {vector:#x?}");
+    assert!(vector != GuestActivityState::WaitForSipi as u8);
+
+    // Update the activity state of the target processor with the obtained
+    // vector value. The target processor should get out from the busy loop
+    // after this. Note that it is possible that the target processor is not
+    // yet in the WaitForSipi state when #VMEXIT(#SX) has not been processed.
+    // It is fine, as SIPI will be sent twice, and almost certain that 2nd
+    // SIPI is late enough. The actual SIPI sent instead is ignored by the
+    // processor, as INIT was converted to #SX.
+    let activity_state = &shared_guest_data().activity_states[processor_id];
+    activity_state
+        .compare_exchange(
+            GuestActivityState::WaitForSipi as u8,
+            vector,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        )
+        .is_ok()
+}
+
+/// Returns the data shared across processors, initialized by the first
+/// `SvmGuest::new`.
+pub(super) fn shared_guest_data() -> &'static SharedGuestData {
+    SHARED_GUEST_DATA.get().unwrap()
+}
+
+static SHARED_GUEST_DATA: Once<SharedGuestData> = Once::new();
 
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
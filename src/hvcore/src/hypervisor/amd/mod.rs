@@ -5,9 +5,21 @@
 
 use super::host::Architecture;
 
+mod apic_timer;
+mod asid;
+mod avic;
 mod guest;
+mod nested;
 mod npts;
+mod shadow_npt;
 mod svm;
+mod vmcb;
+mod vmcb_checks;
+
+pub(crate) use apic_timer::install_timer_emulation;
+pub(crate) use avic::AvicSupport;
+pub(crate) use guest::install_sipi_emulation;
+pub(crate) use nested::install_nested_virtualization;
 
 /// The AMD processor implements SVM as a virtualization extension.
 pub(crate) struct Amd;
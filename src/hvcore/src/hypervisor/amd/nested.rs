@@ -0,0 +1,888 @@
+//! This module implements nested virtualization, which lets the guest run its
+//! own hypervisor, such as Hyper-V for virtualization-based security, WSL2 or
+//! KVM, when enabled with `SharedHostData::nested_virtualization`. L0 is this
+//! hypervisor, L1 is the guest, and L2 is the guest of L1. L0 runs L1 with
+//! VMCB01 and L2 with VMCB02, which is merged from VMCB01 and VMCB12, the VMCB
+//! L1 runs L2 with in its memory.
+//!
+//! SVM is reported to L1 with the features in `install_nested_virtualization`,
+//! the subset of those of the processor this module can emulate. The SVM
+//! instructions cause #VMEXIT regardless of EFER.SVME L1 sees, and are emulated
+//! while L1 sets it.
+//!
+//! VMRUN merges VMCB12 and VMCB01 into VMCB02 and runs L2 with it. Each #VMEXIT
+//! from L2 is either reflected into L1 as #VMEXIT from VMCB12, when L1
+//! intercepts it, or handled by L0, resuming L2 afterwards. If L1 enables
+//! nested paging, L2 runs with NPT02 (see `shadow_npt`). Otherwise, L2 shares
+//! the guest-physical address space of L1 and runs with the NPT of L1.
+//!
+//! The limitations are:
+//! - L1 must execute the SVM instructions in 64-bit mode.
+//! - GIF of L1 is kept in software. It holds the external interrupts L0
+//!   injects, but not NMIs and SMIs.
+//! - AVIC, vGIF, LBR virtualization, VMSAVE and VMLOAD virtualization, TSC
+//!   scaling and SEV are not reported. L1 itself does not use AVIC either.
+//! - NPT12 is walked with 4 levels, and its accessed and dirty flags are not
+//!   updated.
+//! - L0 handles the #VMEXITs from L2 due to its own intercepts against the
+//!   state of L2: I/O to the ports in `io_intercepts`, the MSRs in
+//!   `msr_intercepts`, and nested page faults due to the pages of L1 that L0
+//!   hides, hooks or protects. The custom #VMEXIT handlers see the state of L2
+//!   for them too. The SVM instructions and VMMCALL L2 executes without L1
+//!   intercepting them raise #UD.
+//! - Hardware breakpoints, breakpoint markers and the CR intercepts apply to L1
+//!   only. Reads from the hooked pages observe the shadow pages after L2
+//!   executes them, as they do for L1.
+//! - INIT, converted to #SX, abandons L2 and resets the processor, instead of
+//!   being held pending while GIF is cleared.
+//! - The processor cannot be devirtualized while L1 sets EFER.SVME.
+// See: 15.5 VMRUN Instruction
+// See: 15.6 #VMEXIT
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use bit_field::BitField;
+use x86::{bits64::paging::BASE_PAGE_SIZE, cpuid::cpuid};
+
+use crate::hypervisor::{
+    cpuid_policy::CpuidRegister,
+    event::{self, Event},
+    guest_memory::{self, TranslationError},
+    host::{GpaMapping, NestedPageFaultInfo, SegmentRegister, Vcpu},
+    memory_protection::Permissions,
+    support::{Page, PageBox},
+    tlb::{self, FlushScope},
+    HvError, SharedHostData,
+};
+
+use super::{
+    asid::Asid,
+    guest::{shared_guest_data, SvmGuest},
+    npts::NestedPageTables,
+    shadow_npt::{self, Npt02, Npt12Features, Npt12Leaf, Npt12Walk},
+    vmcb::{TlbControl, Vmcb},
+    vmcb_checks,
+};
+
+pub(super) const SVM_MSR_VM_CR: u32 = 0xc001_0114;
+pub(super) const SVM_MSR_VM_HSAVE_PA: u32 = 0xc001_0117;
+
+// See: Appendix C SVM Intercept Exit Codes
+const VMEXIT_EXCEPTION_DB: u64 = 0x41;
+const VMEXIT_EXCEPTION_SX: u64 = 0x5e;
+pub(super) const VMEXIT_INTR: u64 = 0x60;
+const VMEXIT_SMI: u64 = 0x62;
+const VMEXIT_INVLPGA: u64 = 0x7a;
+const VMEXIT_IOIO: u64 = 0x7b;
+const VMEXIT_MSR: u64 = 0x7c;
+const VMEXIT_VMRUN: u64 = 0x80;
+const VMEXIT_VMLOAD: u64 = 0x82;
+const VMEXIT_VMSAVE: u64 = 0x83;
+const VMEXIT_STGI: u64 = 0x84;
+const VMEXIT_CLGI: u64 = 0x85;
+const VMEXIT_SKINIT: u64 = 0x86;
+const VMEXIT_NPF: u64 = 0x400;
+const VMEXIT_INVALID: u64 = u64::MAX;
+
+const SVM_INTERCEPT_MISC1_INTR: u32 = 1 << 0;
+const SVM_INTERCEPT_MISC1_SMI: u32 = 1 << 2;
+const SVM_INTERCEPT_MISC1_CPUID: u32 = 1 << 18;
+const SVM_INTERCEPT_MISC1_INVD: u32 = 1 << 22;
+const SVM_INTERCEPT_MISC1_INVLPGA: u32 = 1 << 26;
+const SVM_INTERCEPT_MISC1_IOIO_PROT: u32 = 1 << 27;
+const SVM_INTERCEPT_MISC1_MSR_PROT: u32 = 1 << 28;
+const SVM_NP_ENABLE_NP_ENABLE: u64 = 1 << 0;
+
+const MC_VECTOR: u32 = 18;
+const SX_VECTOR: u32 = 30;
+
+/// The offset of EXITCODE in the VMCB.
+// See: Table B-1. VMCB Layout, Control Area
+const EXIT_CODE_OFFSET: u64 = 0x70;
+
+/// Whether `install_nested_virtualization` reported SVM to the guest.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Modifies `shared_host.cpuid_policy` to report SVM, and installs the handlers
+/// of EFER and the SVM MSRs into `shared_host.msr_intercepts`, which are
+/// emulated by `SvmGuest`. Called from the guest before any processor is
+/// virtualized.
+pub(crate) fn install_nested_virtualization(shared_host: &mut SharedHostData) {
+    const SVM: u32 = 1 << 2;
+    // NP, NRIPS, VmcbClean, FlushByAsid, DecodeAssists, PauseFilter and
+    // PauseFilterThreshold.
+    // See: Appendix E.4.10 Function 8000_000Ah—SVM Features
+    const FEATURES: u32 = 1 << 0 | 1 << 3 | 1 << 5 | 1 << 6 | 1 << 7 | 1 << 10 | 1 << 12;
+
+    shared_host.cpuid_policy = core::mem::take(&mut shared_host.cpuid_policy)
+        .set_bits(0x8000_0001, None, CpuidRegister::Ecx, SVM)
+        .clear_bits(0x8000_000a, None, CpuidRegister::Edx, !FEATURES);
+    let mut intercepts = core::mem::take(&mut shared_host.msr_intercepts);
+    if intercepts.read_handler(x86::msr::IA32_EFER).is_none() {
+        intercepts = intercepts.on_read(x86::msr::IA32_EFER, |_, _| None);
+    }
+    for msr in [SVM_MSR_VM_CR, SVM_MSR_VM_HSAVE_PA] {
+        intercepts = intercepts
+            .on_read(msr, |_, _| None)
+            .on_write(msr, |_, _, value| Some(value));
+    }
+    shared_host.msr_intercepts = intercepts;
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns whether the SVM instructions are emulated for the guest.
+pub(super) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The result of `emulate`.
+pub(super) enum Emulation {
+    /// The instruction completed, failed, or raised an exception in L1.
+    Completed,
+    /// VMRUN entered L2 with VMCB02, which is current now.
+    Entered,
+}
+
+/// How a #VMEXIT from L2 is handled. See `NestedSvm::route`.
+pub(super) enum Route {
+    /// The #VMEXIT is to be reflected into L1 with `NestedSvm::reflect`.
+    Reflect,
+    /// L0 handles the #VMEXIT as it would for L1, and resumes L2.
+    Host,
+    /// L0 handles the nested page fault the NPT of L1 caused at the L1 GPA,
+    /// and resumes L2. See `NestedSvm::map_hooked` and
+    /// `NestedSvm::map_for_step`.
+    NestedPageFault(NestedPageFaultInfo),
+    /// L0 handled the #VMEXIT, and L2 resumes.
+    Resolved,
+}
+
+/// The SVM state of L1 on a processor, while L1 sets EFER.SVME.
+pub(super) struct NestedSvm {
+    /// The global interrupt flag of L1, cleared by CLGI and #VMEXIT, and set by
+    /// STGI and VMRUN.
+    gif: bool,
+
+    /// The VMCB not current, that is, VMCB02 while L1 runs, and VMCB01 while L2
+    /// runs. See `SvmGuest::switch_vmcb`.
+    vmcb: Vmcb,
+
+    /// The L1 GPA of VMCB12 of the last VMRUN.
+    vmcb12_pa: u64,
+
+    /// VMCB12 of the last VMRUN, or the VMCB of the last VMLOAD or VMSAVE.
+    vmcb12: Vmcb,
+
+    /// The MSR and I/O permissions maps of VMCB02, merged from those of L0 and
+    /// L1.
+    msrpm: PageBox<[Page; 2]>,
+    iopm: PageBox<[Page; 3]>,
+
+    /// NPT02, used if L1 enables nested paging.
+    npt02: Npt02,
+
+    /// The nCR3 of NPT12 NPT02 caches the translations of, if any.
+    ncr3_12: Option<u64>,
+
+    /// The ASID L2 runs with, shared by all ASIDs of L1, and with L1 itself
+    /// if too few ASIDs are supported. See `Asid::allocate_nested`.
+    asid: Asid,
+
+    /// The ASID and the nCR3 of VMCB12 of the last VMRUN, which the TLB entries
+    /// of the ASID of L2 are derived from.
+    last_entry: Option<(u32, Option<u64>)>,
+
+    /// The TSC offset of L2 relative to L1.
+    tsc_offset12: u64,
+
+    /// Whether L2 is running, that is, VMCB02 is current.
+    in_l2: bool,
+
+    /// EXITINFO1 to report instead of that of #VMEXIT from L2 on the next
+    /// `reflect`.
+    exit_override: Option<u64>,
+
+    /// The L2 GPA and the NPT12 translation of the last nested page fault L0
+    /// handles. See `Route::NestedPageFault`.
+    fault: Option<(u64, Npt12Leaf)>,
+
+    /// The L2 GPAs mapped in NPT02 only for the instruction being
+    /// single-stepped. See `map_for_step`.
+    steps: Vec<u64>,
+}
+
+impl NestedSvm {
+    /// Creates the SVM state of L1 on the processor `id`, for L1 setting
+    /// EFER.SVME.
+    pub(super) fn try_new(id: usize) -> Result<Self, HvError> {
+        Ok(Self {
+            gif: true,
+            vmcb: Vmcb::new()?,
+            vmcb12_pa: 0,
+            vmcb12: Vmcb::new()?,
+            msrpm: PageBox::try_new()?,
+            iopm: PageBox::try_new()?,
+            npt02: Npt02::try_new()?,
+            ncr3_12: None,
+            asid: Asid::allocate_nested(id),
+            last_entry: None,
+            tsc_offset12: 0,
+            in_l2: false,
+            exit_override: None,
+            fault: None,
+            steps: Vec::new(),
+        })
+    }
+
+    /// Returns whether L2 is running.
+    pub(super) fn in_l2(&self) -> bool {
+        self.in_l2
+    }
+
+    /// Returns whether L2 runs with NPT02.
+    pub(super) fn uses_npt02(&self) -> bool {
+        self.in_l2 && self.ncr3_12.is_some()
+    }
+
+    /// Returns the global interrupt flag of L1.
+    pub(super) fn gif(&self) -> bool {
+        self.gif
+    }
+
+    /// Returns whether L1 intercepts physical interrupts while L2 runs.
+    pub(super) fn l1_intercepts_interrupts(&self) -> bool {
+        self.vmcb12.intercept_misc1() & SVM_INTERCEPT_MISC1_INTR != 0
+    }
+
+    /// Returns the exceptions L1 intercepts while L2 runs.
+    pub(super) fn l1_exceptions(&self) -> u32 {
+        self.vmcb12.intercept_exception()
+    }
+
+    /// Returns the TSC offset of L2 relative to L1.
+    pub(super) fn tsc_offset12(&self) -> u64 {
+        self.tsc_offset12
+    }
+
+    /// Returns whether NPT02 replaced or removed any entry since the last call,
+    /// requiring the TLB entries of L2 to be flushed.
+    pub(super) fn take_stale(&mut self) -> bool {
+        self.npt02.take_stale()
+    }
+
+    /// Unmaps everything from NPT02, for the change of the NPT of L1.
+    pub(super) fn reset_npt02(&mut self) {
+        self.npt02.reset();
+    }
+
+    /// Returns the mapping of the L2 GPA `gpa` if it is mapped to the same L1
+    /// GPA, which is then mapped to the same PA for L1. See
+    /// `Vcpu::resolve_gpa`.
+    pub(super) fn resolve(&self, id: usize, gpa: u64) -> Option<GpaMapping> {
+        let npt = shared_guest_data().npt.read();
+        let Some(ncr3_12) = self.ncr3_12 else {
+            return npt.resolve(gpa);
+        };
+        let Npt12Walk::Mapped(leaf) = self.walk_npt12(&npt, id, ncr3_12, gpa) else {
+            return None;
+        };
+        if leaf.translate(gpa) != gpa {
+            return None;
+        }
+        npt.resolve(gpa).map(|mapping| GpaMapping {
+            permissions: intersect(mapping.permissions, leaf.permissions),
+            ram: mapping.ram,
+        })
+    }
+
+    /// Maps the page of the last nested page fault L0 handles in NPT02 to the
+    /// original page for data accesses, or to the shadow page at `shadow_pa`
+    /// for execution, as the hook view does for the hooked page at the L1 GPA
+    /// `info.gpa`.
+    pub(super) fn map_hooked(&mut self, info: &NestedPageFaultInfo, shadow_pa: u64) {
+        let Some((gpa, leaf)) = self.fault.take() else {
+            return;
+        };
+        let (pa, permissions) = if info.execute {
+            (shadow_pa, Permissions::READ_EXECUTE)
+        } else {
+            (info.gpa, Permissions::READ_WRITE)
+        };
+        let permissions = intersect(permissions, leaf.permissions);
+        self.npt02.map(gpa, pa, permissions, leaf.cache_flags);
+    }
+
+    /// Maps the page of the last nested page fault L0 handles in NPT02 to the
+    /// page of the L1 GPA with the permissions of NPT12 only, until
+    /// `end_steps`. The caller single-steps the instruction. See
+    /// `SvmGuest::allow_access_once`.
+    pub(super) fn map_for_step(&mut self) {
+        let Some((gpa, leaf)) = self.fault.take() else {
+            return;
+        };
+        self.npt02
+            .map(gpa, leaf.translate(gpa), leaf.permissions, leaf.cache_flags);
+        self.steps.push(gpa);
+    }
+
+    /// Unmaps the pages mapped with `map_for_step`, so that the next accesses
+    /// are mapped with the permissions of the NPT of L1 again.
+    pub(super) fn end_steps(&mut self) {
+        for gpa in core::mem::take(&mut self.steps) {
+            self.npt02.unmap(gpa);
+        }
+    }
+
+    /// Makes VMCB01 current again with the state of L1 at VMRUN if L2 runs,
+    /// for abandoning L2 on INIT or devirtualization.
+    pub(super) fn release(&mut self, vcpu: &mut SvmGuest) {
+        if !self.in_l2 {
+            return;
+        }
+        vcpu.switch_vmcb(&mut self.vmcb);
+        self.flush_shared_asid(vcpu);
+        let vmcb01 = vcpu.vmcb();
+        let (rax, rip, rsp, rflags) = (vmcb01.rax(), vmcb01.rip(), vmcb01.rsp(), vmcb01.rflags());
+        let regs = vcpu.regs();
+        regs.rax = rax;
+        regs.rip = rip;
+        regs.rsp = rsp;
+        regs.rflags = rflags;
+        self.in_l2 = false;
+    }
+
+    /// Requests flushing the TLB entries L2 left on the next VMRUN of L1, with
+    /// VMCB01 current, if L1 shares the ASID of L2.
+    fn flush_shared_asid(&self, vcpu: &mut SvmGuest) {
+        if self.asid.is_shared() {
+            vcpu.vmcb_mut().set_tlb_control(self.asid.flush_control());
+        }
+    }
+
+    /// Returns the paging features NPT12 is checked against. EFER of L1 is that
+    /// in VMCB01, which is not current while L2 runs.
+    fn npt12_features(&self) -> Npt12Features {
+        const EFER_NXE: u64 = 1 << 11;
+
+        Npt12Features {
+            nx: self.vmcb.efer() & EFER_NXE != 0,
+            page_1gb: cpuid!(0x8000_0001).edx.get_bit(26),
+            pa_bits: pa_bits(),
+        }
+    }
+
+    /// Translates the L2 GPA `gpa` with NPT12 at the L1 GPA `ncr3_12`.
+    fn walk_npt12(&self, npt: &NestedPageTables, id: usize, ncr3_12: u64, gpa: u64) -> Npt12Walk {
+        shadow_npt::walk(ncr3_12, gpa, &self.npt12_features(), |entry_gpa| {
+            let mut entry = [0u8; 8];
+            guest_memory::read_physical(id, entry_gpa, &mut entry, |gpa| {
+                npt.resolve(gpa).filter(|mapping| mapping.ram)
+            })
+            .ok()?;
+            Some(u64::from_le_bytes(entry))
+        })
+    }
+
+    /// Copies the state VMLOAD and VMSAVE transfer between VMCB01 and the VMCB
+    /// at the L1 GPA `pa`, into VMCB01 unless `save`.
+    // See: VMLOAD - Load State from VMCB
+    // See: VMSAVE - Save State to VMCB
+    fn transfer(
+        &mut self,
+        vcpu: &mut SvmGuest,
+        pa: u64,
+        save: bool,
+    ) -> Result<(), TranslationError> {
+        let id = vcpu.id();
+        read_l1(id, pa, self.vmcb12.as_bytes_mut())?;
+        if save {
+            self.vmcb12.copy_vmload_state(vcpu.vmcb());
+            write_l1(id, pa, self.vmcb12.as_bytes())
+        } else {
+            vcpu.vmcb_mut().copy_vmload_state(&self.vmcb12);
+            Ok(())
+        }
+    }
+
+    /// Invalidates the TLB entry for the linear address in RAX and the ASID in
+    /// ECX, which is of L2 unless 0, of L1. The whole TLB of L1 is flushed in
+    /// the latter case.
+    // See: INVLPGA - Invalidate TLB Entry in a Specified ASID
+    fn invlpga(&self, vcpu: &mut SvmGuest) {
+        let regs = *vcpu.regs();
+        if regs.rcx as u32 == 0 {
+            tlb::flush_guest(vcpu, FlushScope::GuestLinear);
+            return;
+        }
+        unsafe {
+            asm!(
+                "invlpga rax, ecx",
+                in("rax") regs.rax, in("ecx") self.asid.value(), options(nostack, preserves_flags),
+            );
+        };
+    }
+
+    /// Emulates VMRUN with VMCB12 at the L1 GPA in RAX, entering L2 with VMCB02
+    /// merged from VMCB01 and VMCB12. VMCB12 failing the consistency checks
+    /// causes #VMEXIT(INVALID) into L1 instead.
+    // See: 15.5.1 Basic Operation
+    fn enter(&mut self, vcpu: &mut SvmGuest) -> Emulation {
+        // The intercepts of L0 that apply to L2 too. The others are for L1 only,
+        // or are set when L2 is about to run.
+        const L0_MISC1: u32 = SVM_INTERCEPT_MISC1_SMI
+            | SVM_INTERCEPT_MISC1_CPUID
+            | SVM_INTERCEPT_MISC1_INVD
+            | SVM_INTERCEPT_MISC1_INVLPGA
+            | SVM_INTERCEPT_MISC1_IOIO_PROT
+            | SVM_INTERCEPT_MISC1_MSR_PROT;
+        // V_TPR, V_IRQ, V_INTR_PRIO, V_IGN_TPR, V_INTR_MASKING and
+        // V_INTR_VECTOR.
+        // See: Table B-1. VMCB Layout, Control Area
+        const VINTR: u64 = 0xff | 1 << 8 | 0xf << 16 | 1 << 20 | 1 << 24 | 0xff << 32;
+
+        let id = vcpu.id();
+        let regs = *vcpu.regs();
+        let nrip = vcpu.vmcb().nrip();
+        if read_l1(id, regs.rax, self.vmcb12.as_bytes_mut()).is_err() {
+            inject_exception(vcpu, 13, Some(0));
+            return Emulation::Completed;
+        }
+        if let Some(violation) = vmcb_checks::check(&self.vmcb12) {
+            log::warn!("VMRUN of the nested guest failed the consistency check of {violation}");
+            let _ = write_l1(
+                id,
+                regs.rax + EXIT_CODE_OFFSET,
+                &VMEXIT_INVALID.to_le_bytes(),
+            );
+            self.gif = false;
+            vcpu.regs().rip = nrip;
+            return Emulation::Completed;
+        }
+        self.vmcb12_pa = regs.rax;
+
+        // Keep the state of L1 in VMCB01 to return to on #VMEXIT, in place of
+        // the host state-save area.
+        let vmcb01 = vcpu.vmcb_mut();
+        vmcb01.set_rax(regs.rax);
+        vmcb01.set_rip(nrip);
+        vmcb01.set_rsp(regs.rsp);
+        vmcb01.set_rflags(regs.rflags);
+
+        // VMCB02 starts as VMCB01, including the state VMLOAD loads, which VMRUN
+        // does not.
+        let vmcb01 = vcpu.vmcb();
+        let (vmcb02, vmcb12) = (&mut self.vmcb, &self.vmcb12);
+        vmcb02.copy_from(vmcb01);
+        vmcb02.copy_guest_state(vmcb12);
+        vmcb02.set_intercept_cr_read(vmcb12.intercept_cr_read());
+        vmcb02.set_intercept_cr_write(vmcb12.intercept_cr_write());
+        vmcb02.set_intercept_dr_read(vmcb12.intercept_dr_read());
+        vmcb02.set_intercept_dr_write(vmcb12.intercept_dr_write());
+        vmcb02.set_intercept_exception(
+            vmcb12.intercept_exception() | 1 << MC_VECTOR | 1 << SX_VECTOR,
+        );
+        vmcb02.set_intercept_misc1(vmcb12.intercept_misc1() | vmcb01.intercept_misc1() & L0_MISC1);
+        vmcb02.set_intercept_misc2(vmcb12.intercept_misc2() | vmcb01.intercept_misc2());
+        vmcb02.set_intercept_misc3(vmcb12.intercept_misc3());
+        vmcb02.set_pause_filter_count(vmcb12.pause_filter_count());
+        vmcb02.set_pause_filter_threshold(vmcb12.pause_filter_threshold());
+        self.merge_permission_maps(id, vmcb01.intercept_misc1());
+
+        let (vmcb02, vmcb12) = (&mut self.vmcb, &self.vmcb12);
+        self.tsc_offset12 = vmcb12.tsc_offset();
+        vmcb02.set_tsc_offset(vmcb01.tsc_offset().wrapping_add(self.tsc_offset12));
+        vmcb02.set_vintr(vmcb12.vintr() & VINTR);
+        vmcb02.set_event_inj(vmcb12.event_inj());
+        vmcb02.set_interrupt_shadow(vmcb12.interrupt_shadow());
+
+        // All ASIDs of L1 share the ASID of L2, whose TLB entries are flushed
+        // whenever they may come from another ASID or NPT12 of L1, or from L1
+        // itself if it shares the ASID.
+        // See: 15.16 TLB Control
+        let ncr3_12 = (vmcb12.np_enable() & SVM_NP_ENABLE_NP_ENABLE != 0).then(|| vmcb12.ncr3());
+        if ncr3_12 != self.ncr3_12 || vmcb12.tlb_control() != TlbControl::DoNotFlush as u32 {
+            self.npt02.reset();
+        }
+        self.ncr3_12 = ncr3_12;
+        let stale = self.npt02.take_stale();
+        let entry = (vmcb12.guest_asid(), ncr3_12);
+        let flush = self.asid.is_shared()
+            || stale
+            || self.last_entry != Some(entry)
+            || vmcb12.tlb_control() != TlbControl::DoNotFlush as u32;
+        self.last_entry = Some(entry);
+        vmcb02.set_guest_asid(self.asid.value());
+        vmcb02.set_tlb_control(if flush {
+            self.asid.flush_control()
+        } else {
+            TlbControl::DoNotFlush
+        });
+
+        // Without nested paging of L1, L2 runs with the NPT and gPAT of L1.
+        if ncr3_12.is_some() {
+            vmcb02.set_ncr3(self.npt02.ncr3());
+            vmcb02.set_gpat(vmcb12.gpat());
+        }
+
+        let (rax, rip, rsp, rflags) = (vmcb12.rax(), vmcb12.rip(), vmcb12.rsp(), vmcb12.rflags());
+        let regs = vcpu.regs();
+        regs.rax = rax;
+        regs.rip = rip;
+        regs.rsp = rsp;
+        regs.rflags = rflags;
+        self.gif = true;
+        self.in_l2 = true;
+        vcpu.switch_vmcb(&mut self.vmcb);
+        Emulation::Entered
+    }
+
+    /// Merges the MSR and I/O permissions maps of L0 and L1 into those of
+    /// VMCB02, intercepting what either intercepts. `misc1` is the intercepts
+    /// of VMCB01 telling whether L0 uses its maps.
+    // See: 15.10.1 I/O Permissions Map
+    // See: 15.11 MSR Intercepts
+    fn merge_permission_maps(&mut self, id: usize, misc1: u32) {
+        let shared = shared_guest_data();
+        let vmcb12 = &self.vmcb12;
+        let maps = [
+            (
+                pages_mut(&mut self.msrpm),
+                pages(&shared.msrpm),
+                SVM_INTERCEPT_MISC1_MSR_PROT,
+                vmcb12.msrpm_base_pa(),
+            ),
+            (
+                pages_mut(&mut self.iopm),
+                pages(&shared.iopm),
+                SVM_INTERCEPT_MISC1_IOIO_PROT,
+                vmcb12.iopm_base_pa(),
+            ),
+        ];
+        for (merged, l0, intercept, pa12) in maps {
+            if vmcb12.intercept_misc1() & intercept == 0 {
+                merged.fill(0);
+            } else if read_l1(id, pa12 & !(BASE_PAGE_SIZE as u64 - 1), merged).is_err() {
+                merged.fill(u8::MAX);
+            }
+            if misc1 & intercept != 0 {
+                for (merged, l0) in merged.iter_mut().zip(l0) {
+                    *merged |= l0;
+                }
+            }
+        }
+        self.vmcb.set_msrpm_base_pa(self.msrpm.pa());
+        self.vmcb.set_iopm_base_pa(self.iopm.pa());
+    }
+
+    /// Decides how the #VMEXIT from L2 is handled. `l0_step` tells whether L0
+    /// single-steps L2.
+    pub(super) fn route(&mut self, vcpu: &mut SvmGuest, l0_step: bool) -> Route {
+        let id = vcpu.id();
+        let vmcb02 = vcpu.vmcb();
+        let code = vmcb02.exit_code();
+        let reflect_if = |reflect: bool| if reflect { Route::Reflect } else { Route::Host };
+        match code {
+            VMEXIT_INVALID => Route::Reflect,
+            VMEXIT_EXCEPTION_SX | VMEXIT_SMI => Route::Host,
+            VMEXIT_EXCEPTION_DB if l0_step => Route::Host,
+            VMEXIT_IOIO => reflect_if(self.l1_intercepts_io(id, vmcb02.exit_info1())),
+            VMEXIT_MSR => {
+                // "EXITINFO1 = 0 for RDMSR, 1 for WRMSR"
+                // See: 15.11 MSR Intercepts
+                let msr = vcpu.regs().rcx as u32;
+                let write = vcpu.vmcb().exit_info1() == 1;
+                reflect_if(self.l1_intercepts_msr(id, msr, write))
+            }
+            VMEXIT_NPF if self.ncr3_12.is_some() => self.fill_npt02(vcpu),
+            VMEXIT_NPF => Route::Host,
+            _ => reflect_if(is_intercepted(&self.vmcb12, code)),
+        }
+    }
+
+    /// Returns whether L1 intercepts the I/O instruction L2 executed, described
+    /// by `exit_info1`.
+    // See: Figure 15-2. EXITINFO1 for IOIO Intercept
+    // See: 15.10.1 I/O Permissions Map
+    fn l1_intercepts_io(&self, id: usize, exit_info1: u64) -> bool {
+        if self.vmcb12.intercept_misc1() & SVM_INTERCEPT_MISC1_IOIO_PROT == 0 {
+            return false;
+        }
+        let port = exit_info1.get_bits(16..=31);
+        let size = match exit_info1.get_bits(4..=6) {
+            0b001 => 1,
+            0b010 => 2,
+            _ => 4,
+        };
+        let iopm = self.vmcb12.iopm_base_pa() & !(BASE_PAGE_SIZE as u64 - 1);
+        let mut bytes = [0u8; 2];
+        read_l1(id, iopm + port / 8, &mut bytes).is_err()
+            || u16::from_le_bytes(bytes).get_bits(port as usize % 8..port as usize % 8 + size) != 0
+    }
+
+    /// Returns whether L1 intercepts RDMSR or WRMSR of `msr` L2 executed.
+    // See: 15.11 MSR Intercepts
+    fn l1_intercepts_msr(&self, id: usize, msr: u32, write: bool) -> bool {
+        if self.vmcb12.intercept_misc1() & SVM_INTERCEPT_MISC1_MSR_PROT == 0 {
+            return false;
+        }
+        let offset = match msr {
+            0..=0x1fff => 0,
+            0xc000_0000..=0xc000_1fff => 0x800,
+            0xc001_0000..=0xc001_1fff => 0x1000,
+            _ => return true,
+        };
+        let index = u64::from(msr & 0x1fff) * 2 + u64::from(write);
+        let msrpm = self.vmcb12.msrpm_base_pa() & !(BASE_PAGE_SIZE as u64 - 1);
+        let mut byte = [0u8];
+        read_l1(id, msrpm + offset + index / 8, &mut byte).is_err()
+            || byte[0].get_bit(index as usize % 8)
+    }
+
+    /// Handles the nested page fault on NPT02 by translating the L2 GPA with
+    /// NPT12 and the NPT of L1, and mapping the page in NPT02 with the
+    /// permissions of both. The fault of NPT12 is reflected into L1, and that of
+    /// the NPT of L1 is handled by L0.
+    // See: 15.25.6 Nested versus Guest Page Faults, Fault Ordering
+    fn fill_npt02(&mut self, vcpu: &SvmGuest) -> Route {
+        const PWT_PCD: u64 = 1 << 3 | 1 << 4;
+
+        let exit_info1 = vcpu.vmcb().exit_info1();
+        let gpa = vcpu.vmcb().exit_info2();
+        let info = NestedPageFaultInfo {
+            gpa,
+            write: exit_info1.get_bit(1),
+            execute: exit_info1.get_bit(4),
+        };
+        let npt = shared_guest_data().npt.read();
+        let leaf12 = match self.walk_npt12(&npt, vcpu.id(), self.ncr3_12.unwrap(), gpa) {
+            Npt12Walk::Mapped(leaf) if leaf.permissions.permits(&info) => leaf,
+            // Report the fault as the processor would for NPT12, whose walk is
+            // a user access: P for a present entry denying the access, and RSV
+            // for a reserved bit set. The others are those L2 caused.
+            walk => {
+                let mut exit_info1 = exit_info1;
+                let _ = exit_info1
+                    .set_bit(0, !matches!(walk, Npt12Walk::NotPresent))
+                    .set_bit(2, true)
+                    .set_bit(3, matches!(walk, Npt12Walk::Reserved));
+                self.exit_override = Some(exit_info1);
+                return Route::Reflect;
+            }
+        };
+
+        let gpa1 = leaf12.translate(gpa);
+        let mapping = npt.resolve(gpa1);
+        drop(npt);
+        let permissions = mapping.map_or(Permissions::NONE, |mapping| {
+            intersect(mapping.permissions, leaf12.permissions)
+        });
+        let Some(mapping) = mapping.filter(|_| permissions.permits(&info)) else {
+            self.fault = Some((gpa, leaf12));
+            return Route::NestedPageFault(NestedPageFaultInfo { gpa: gpa1, ..info });
+        };
+
+        // Keep MMIO uncacheable regardless of the PAT entry NPT12 selects.
+        let cache_flags = if mapping.ram {
+            leaf12.cache_flags
+        } else {
+            leaf12.cache_flags | PWT_PCD
+        };
+        self.npt02.map(gpa, gpa1, permissions, cache_flags);
+        Route::Resolved
+    }
+
+    /// Reflects the #VMEXIT from L2, or the one with the exit code `synthetic`
+    /// L0 causes instead, into L1 as #VMEXIT from VMCB12, and makes VMCB01
+    /// current with the state of L1 at VMRUN. `keep_dr7` tells L0 manages DR7
+    /// of L1. See `hw_breakpoint`.
+    // See: 15.6 #VMEXIT
+    pub(super) fn reflect(&mut self, vcpu: &mut SvmGuest, synthetic: Option<u64>, keep_dr7: bool) {
+        const V_TPR_AND_IRQ: u64 = 0xff | 1 << 8;
+        const EVENT_INJ_VALID: u64 = 1 << 31;
+
+        let regs = *vcpu.regs();
+        let exit_override = self.exit_override.take();
+        let vmcb02 = vcpu.vmcb_mut();
+        vmcb02.set_rax(regs.rax);
+        vmcb02.set_rip(regs.rip);
+        vmcb02.set_rsp(regs.rsp);
+        vmcb02.set_rflags(regs.rflags);
+
+        let vmcb02 = vcpu.vmcb();
+        let vmcb12 = &mut self.vmcb12;
+        vmcb12.copy_guest_state(vmcb02);
+        if self.ncr3_12.is_some() {
+            vmcb12.set_gpat(vmcb02.gpat());
+        }
+        vmcb12.copy_exit_info(vmcb02);
+        if let Some(code) = synthetic {
+            // The event being injected into L2 was not delivered.
+            // See: 15.7.2 Intercepts During IDT Interrupt Delivery
+            vmcb12.set_exit_code(code);
+            vmcb12.set_exit_info1(0);
+            vmcb12.set_exit_info2(0);
+            vmcb12.set_exit_int_info(vmcb02.event_inj());
+        } else if let Some(exit_info1) = exit_override {
+            vmcb12.set_exit_info1(exit_info1);
+        }
+        vmcb12.set_vintr((vmcb12.vintr() & !V_TPR_AND_IRQ) | (vmcb02.vintr() & V_TPR_AND_IRQ));
+        vmcb12.set_event_inj(vmcb12.event_inj() & !EVENT_INJ_VALID);
+        let _ = write_l1(vcpu.id(), self.vmcb12_pa, self.vmcb12.as_bytes());
+
+        // #VMEXIT does not save the state VMSAVE saves, which stays as L2 left
+        // it until L1 saves it.
+        vcpu.switch_vmcb(&mut self.vmcb);
+        let (vmcb01, vmcb02) = (vcpu.vmcb_mut(), &self.vmcb);
+        vmcb01.copy_vmload_state(vmcb02);
+        if self.ncr3_12.is_none() {
+            vmcb01.set_ncr3(vmcb02.ncr3());
+            vmcb01.set_gpat(vmcb02.gpat());
+        }
+        if !keep_dr7 {
+            vmcb01.set_dr7(0x400);
+        }
+        vmcb01.set_event_inj(0);
+        vmcb01.set_interrupt_shadow(0);
+        self.flush_shared_asid(vcpu);
+        let vmcb01 = vcpu.vmcb();
+        let (rax, rip, rsp, rflags) = (vmcb01.rax(), vmcb01.rip(), vmcb01.rsp(), vmcb01.rflags());
+        let regs = vcpu.regs();
+        regs.rax = rax;
+        regs.rip = rip;
+        regs.rsp = rsp;
+        regs.rflags = rflags;
+        self.gif = false;
+        self.in_l2 = false;
+    }
+}
+
+/// Emulates the SVM instruction with the exit code `code` L1 executed, with
+/// VMCB01 current. `nested` is the SVM state of L1, which exists while L1 sets
+/// EFER.SVME.
+// See: 15.5 VMRUN Instruction
+// See: 15.17 Global Interrupt Flag, STGI and CLGI Instructions
+pub(super) fn emulate(
+    nested: &mut Option<Box<NestedSvm>>,
+    vcpu: &mut SvmGuest,
+    code: u64,
+) -> Emulation {
+    const EFER_LMA: u64 = 1 << 10;
+
+    // The SVM instructions are emulated only in 64-bit mode. Deliver #UD as the
+    // processor does without EFER.SVME, and for SKINIT, which is not reported,
+    // unlike the processor in the other modes.
+    let long_mode =
+        vcpu.efer() & EFER_LMA != 0 && vcpu.segment(SegmentRegister::Cs).attributes.get_bit(9);
+    let Some(state) = nested
+        .as_mut()
+        .filter(|_| long_mode && code != VMEXIT_SKINIT)
+    else {
+        inject_exception(vcpu, 6, None);
+        return Emulation::Completed;
+    };
+    if vcpu.cpl() != 0 {
+        inject_exception(vcpu, 13, Some(0));
+        return Emulation::Completed;
+    }
+    let pa = vcpu.regs().rax;
+    if matches!(code, VMEXIT_VMRUN | VMEXIT_VMLOAD | VMEXIT_VMSAVE) && !is_valid_pa(pa) {
+        inject_exception(vcpu, 13, Some(0));
+        return Emulation::Completed;
+    }
+
+    match code {
+        VMEXIT_VMRUN => return state.enter(vcpu),
+        VMEXIT_VMLOAD | VMEXIT_VMSAVE => {
+            if state.transfer(vcpu, pa, code == VMEXIT_VMSAVE).is_err() {
+                inject_exception(vcpu, 13, Some(0));
+                return Emulation::Completed;
+            }
+        }
+        VMEXIT_STGI => state.gif = true,
+        VMEXIT_CLGI => state.gif = false,
+        VMEXIT_INVLPGA => state.invlpga(vcpu),
+        _ => unreachable!(),
+    }
+    vcpu.regs().rip = vcpu.vmcb().nrip();
+    Emulation::Completed
+}
+
+/// Returns whether `vmcb12` intercepts the #VMEXIT with `code` from L2. Codes
+/// without an intercept bit, such as VMEXIT_INVALID, are always reflected.
+// See: Appendix C SVM Intercept Exit Codes
+fn is_intercepted(vmcb12: &Vmcb, code: u64) -> bool {
+    let index = code as usize;
+    match code {
+        0x00..=0x0f => vmcb12.intercept_cr_read().get_bit(index),
+        0x10..=0x1f => vmcb12.intercept_cr_write().get_bit(index - 0x10),
+        0x20..=0x2f => vmcb12.intercept_dr_read().get_bit(index - 0x20),
+        0x30..=0x3f => vmcb12.intercept_dr_write().get_bit(index - 0x30),
+        0x40..=0x5f => vmcb12.intercept_exception().get_bit(index - 0x40),
+        0x60..=0x7f => vmcb12.intercept_misc1().get_bit(index - 0x60),
+        0x80..=0x9f => vmcb12.intercept_misc2().get_bit(index - 0x80),
+        0xa0..=0xbf => vmcb12.intercept_misc3().get_bit(index - 0xa0),
+        _ => true,
+    }
+}
+
+/// Returns the accesses both `a` and `b` permit.
+fn intersect(a: Permissions, b: Permissions) -> Permissions {
+    Permissions {
+        read: a.read && b.read,
+        write: a.write && b.write,
+        execute: a.execute && b.execute,
+    }
+}
+
+/// Returns the mapping of the L1 GPA `gpa` if it is RAM L1 can read and write,
+/// for the structures L1 refers to by GPAs.
+fn resolve_l1(npt: &NestedPageTables, gpa: u64) -> Option<GpaMapping> {
+    npt.resolve(gpa)
+        .filter(|mapping| mapping.ram && mapping.permissions.write)
+}
+
+/// Reads `buffer.len()` bytes from the L1 GPA `gpa`.
+fn read_l1(id: usize, gpa: u64, buffer: &mut [u8]) -> Result<(), TranslationError> {
+    let npt = shared_guest_data().npt.read();
+    guest_memory::read_physical(id, gpa, buffer, |gpa| resolve_l1(&npt, gpa))
+}
+
+/// Writes `data` to the L1 GPA `gpa`.
+fn write_l1(id: usize, gpa: u64, data: &[u8]) -> Result<(), TranslationError> {
+    let npt = shared_guest_data().npt.read();
+    guest_memory::write_physical(id, gpa, data, |gpa| resolve_l1(&npt, gpa))
+}
+
+/// Returns the bytes of the permissions map in `pages`.
+fn pages<const N: usize>(pages: &[Page; N]) -> &[u8] {
+    // Safety: `Page` is an array of bytes, and so is an array of them.
+    unsafe { core::slice::from_raw_parts(pages.as_ptr().cast(), N * BASE_PAGE_SIZE) }
+}
+
+/// Returns the bytes of the permissions map in `pages`, for writing.
+fn pages_mut<const N: usize>(pages: &mut [Page; N]) -> &mut [u8] {
+    // Safety: `Page` is an array of bytes, and so is an array of them.
+    unsafe { core::slice::from_raw_parts_mut(pages.as_mut_ptr().cast(), N * BASE_PAGE_SIZE) }
+}
+
+/// Returns the physical-address width of the processor (MAXPHYADDR).
+fn pa_bits() -> u8 {
+    cpuid!(0x8000_0008).eax.get_bits(0..=7) as u8
+}
+
+/// Returns whether `pa` is 4KB-aligned and within the physical-address width.
+fn is_valid_pa(pa: u64) -> bool {
+    pa & (BASE_PAGE_SIZE as u64 - 1) == 0 && pa >> pa_bits() == 0
+}
+
+/// Injects the exception `vector` into the guest.
+fn inject_exception(vcpu: &mut dyn Vcpu, vector: u8, error_code: Option<u32>) {
+    let exception = Event::Exception { vector, error_code };
+    if let Err(err) = event::inject_event(vcpu, exception) {
+        log::error!("Could not inject exception {vector}: {err}");
+    }
+}
@@ -1,32 +1,89 @@
-use alloc::boxed::Box;
+use alloc::{
+    boxed::Box,
+    collections::{btree_map, BTreeMap},
+    vec::Vec,
+};
 use bit_field::BitField;
-use x86::bits64::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE};
+use x86::bits64::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE};
 
 use crate::hypervisor::{
-    paging_structures::{build_identity_internal, Entry, PagingStructuresRaw, Pt},
+    ept_hook::HookManager,
+    hidden_memory,
+    host::GpaMapping,
+    memory_protection::{Permissions, Protections},
+    mtrr::{MemoryType, Mtrr},
+    paging_structures::{Entry, PagingStructures, PagingStructuresRaw, Pd, Pdpt, Pml4, Pt},
     platform_ops,
-    support::zeroed_box,
+    support::{try_zeroed_box, zeroed_box, Page},
     x86_instructions::rdmsr,
+    HvError,
 };
 
-#[derive(Debug, derive_deref::Deref, derive_deref::DerefMut)]
+#[derive(Debug)]
 pub(crate) struct NestedPageTables {
-    ptr: Box<PagingStructuresRaw>,
+    ps: PagingStructures,
+
+    /// The NPT PT for the 2MB page containing the APIC base page.
+    apic_pt: Box<Pt>,
+
+    /// The NPT PTs for 2MB pages split for hooks, keyed by the GPA of the 2MB
+    /// pages.
+    pts: BTreeMap<u64, Box<Pt>>,
+
+    /// The hooks applied onto the NPT, as a map of the GPA of an original page
+    /// to the PA of its shadow page.
+    hooks: BTreeMap<u64, u64>,
+
+    /// The NPT used while the guest executes hooked pages. Built on the first
+    /// hook.
+    hook_view: Option<HookView>,
+
+    /// The protections applied onto the NPT, as a map of the GPA of a page to
+    /// its permissions.
+    protections: BTreeMap<u64, Permissions>,
+
+    /// The zeroed page the hidden pages are mapped to.
+    dummy_page: Box<Page>,
+
+    /// The MTRRs when the NPT was built, which tell RAM from memory-mapped
+    /// I/O for `resolve`. The NPT does not specify memory types.
+    mtrr: Mtrr,
+}
+
+impl core::ops::Deref for NestedPageTables {
+    type Target = Box<PagingStructuresRaw>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ps
+    }
+}
+
+impl core::ops::DerefMut for NestedPageTables {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.ps
+    }
 }
 
 impl NestedPageTables {
-    pub(crate) fn new() -> Self {
-        Self {
-            ptr: zeroed_box::<PagingStructuresRaw>(),
-        }
+    pub(crate) fn new() -> Result<Self, HvError> {
+        Ok(Self {
+            ps: PagingStructures::try_new()?,
+            apic_pt: try_zeroed_box::<Pt>()?,
+            pts: BTreeMap::new(),
+            hooks: BTreeMap::new(),
+            hook_view: None,
+            protections: BTreeMap::new(),
+            dummy_page: try_zeroed_box::<Page>()?,
+            mtrr: Mtrr::new(),
+        })
     }
 
-    pub(crate) fn build_identity(&mut self) {
-        build_identity_internal(self.as_mut(), true);
+    pub(crate) fn build_identity(&mut self) -> Result<(), HvError> {
+        self.ps.build_identity_internal(true)
     }
 
     pub(crate) fn apic_pt(&mut self) -> &mut Pt {
-        &mut self.pt_apic
+        &mut self.apic_pt
     }
 
     /// Splits the 2MB NTP entry for the APIC base page into 4KB entries.
@@ -38,8 +95,316 @@ impl NestedPageTables {
 
         let pdpt_index = apic_base.get_bits(30..=38) as usize; // [38:30]
         let pd_index = apic_base.get_bits(21..=29) as usize; // [29:21]
-        let pde = &mut self.ptr.pd[pdpt_index].0.entries[pd_index];
-        Self::split_2mb(pde, &mut self.ptr.pt_apic);
+        let pde = &mut self.ps.pd(pdpt_index).0.entries[pd_index];
+        Self::split_2mb(pde, &mut self.apic_pt);
+    }
+
+    /// Returns the PA of the nested PML4 of the hook view, if built.
+    pub(crate) fn hook_view_ncr3(&self) -> Option<u64> {
+        self.hook_view.as_ref().map(|view| view.pml4_pa)
+    }
+
+    /// Returns the mapping of the page containing `gpa` in the primary NPT if
+    /// it maps the page to the same PA. Hooked pages are mapped to the original
+    /// pages in the primary NPT. See `Vcpu::resolve_gpa`.
+    pub(crate) fn resolve(&self, gpa: u64) -> Option<GpaMapping> {
+        if gpa.get_bits(39..=63) != 0 {
+            return None;
+        }
+
+        let page = gpa & !(BASE_PAGE_SIZE as u64 - 1);
+        let large_page_gpa = gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+        let pdpte = self.ps.pdpt.0.entries[pdpt_index(gpa)];
+        let (entry, size) = match self.ps.split_pd(pdpt_index(gpa)) {
+            None => (pdpte, HUGE_PAGE_SIZE),
+            Some(pd) => {
+                let pde = pd.0.entries[pd_index(gpa)];
+                if pde.large() {
+                    (pde, LARGE_PAGE_SIZE)
+                } else {
+                    let pt = self.pts.get(&large_page_gpa).unwrap_or(&self.apic_pt);
+                    (pt.0.entries[pt_index(gpa)], BASE_PAGE_SIZE)
+                }
+            }
+        };
+
+        let pa = (entry.pfn() << BASE_PAGE_SHIFT) + (gpa & (size as u64 - 1));
+        if !entry.present() || pa & !(BASE_PAGE_SIZE as u64 - 1) != page {
+            return None;
+        }
+        Some(GpaMapping {
+            permissions: Permissions {
+                read: true,
+                write: entry.writable(),
+                execute: !entry.no_execute(),
+            },
+            ram: self.mtrr.find(page..page + BASE_PAGE_SIZE as u64) == Some(MemoryType::WriteBack),
+        })
+    }
+
+    /// Returns whether `gpa` is in a hooked page.
+    pub(crate) fn is_hooked(&self, gpa: u64) -> bool {
+        self.hooks
+            .contains_key(&(gpa & !(BASE_PAGE_SIZE as u64 - 1)))
+    }
+
+    /// Returns the physical address of the shadow page if `gpa` is in a hooked
+    /// page.
+    pub(crate) fn shadow_pa(&self, gpa: u64) -> Option<u64> {
+        self.hooks
+            .get(&(gpa & !(BASE_PAGE_SIZE as u64 - 1)))
+            .copied()
+    }
+
+    /// Updates the NPTs to reflect hooks in `hook_manager`. The caller must
+    /// flush the TLB entries of the guest.
+    //
+    // AMD NPT cannot express execute-only pages. Instead, we maintain two NPTs:
+    // the primary one, where hooked pages are mapped to the original pages
+    // without execute permission, and the hook view, where only the shadow pages
+    // are executable. Each processor switches between the two on #VMEXIT(NPF).
+    pub(crate) fn apply_hooks(&mut self, hook_manager: &HookManager) {
+        if self.hook_view.is_none() {
+            self.hook_view = Some(HookView::new(&self.ps));
+
+            // Protect the pages in the hook view too.
+            let protected: Vec<u64> = self.protections.keys().copied().collect();
+            for gpa in protected {
+                if !self.is_hooked(gpa) {
+                    self.restore_protection(gpa);
+                }
+            }
+        }
+
+        // Restore the mappings of the pages no longer hooked.
+        let removed: Vec<u64> = self
+            .hooks
+            .keys()
+            .copied()
+            .filter(|&gpa| !hook_manager.hooks().any(|(pa, _)| pa == gpa))
+            .collect();
+        for gpa in removed {
+            let _ = self.hooks.remove(&gpa);
+            let pte = self.hook_view.as_mut().unwrap().pte(gpa);
+            let mut new_pte = *pte;
+            new_pte.set_pfn(gpa >> BASE_PAGE_SHIFT);
+            new_pte.set_no_execute(true);
+            *pte = new_pte;
+            self.restore_protection(gpa);
+        }
+
+        // Make the newly hooked pages non-executable in the primary NPT, and map
+        // the shadow pages as executable in the hook view.
+        for (gpa, shadow_pa) in hook_manager.hooks() {
+            if self.hooks.insert(gpa, shadow_pa) == Some(shadow_pa) {
+                continue;
+            }
+
+            // Protection of the page is suspended while it is hooked.
+            let pt = self.pt(gpa);
+            let pte = &mut pt.0.entries[pt_index(gpa)];
+            let mut new_pte = *pte;
+            set_permissions(&mut new_pte, Permissions::READ_WRITE);
+            *pte = new_pte;
+            let pt = *pt;
+
+            let pd = *self.ps.pd(pdpt_index(gpa));
+            let view = self.hook_view.as_mut().unwrap();
+            view.split(gpa, &pd, &pt);
+            let pte = view.pte(gpa);
+            let mut new_pte = *pte;
+            new_pte.set_pfn(shadow_pa >> BASE_PAGE_SHIFT);
+            new_pte.set_present(true);
+            new_pte.set_writable(true);
+            new_pte.set_no_execute(false);
+            *pte = new_pte;
+        }
+    }
+
+    /// Updates the NPTs to reflect `protections`. The caller must flush the
+    /// TLB entries of the guest.
+    pub(crate) fn apply_protections(&mut self, protections: &Protections) {
+        let pages = protections.pages();
+        let removed: Vec<u64> = self
+            .protections
+            .keys()
+            .copied()
+            .filter(|gpa| !pages.contains_key(gpa))
+            .collect();
+        let changed: Vec<(u64, Permissions)> = pages
+            .iter()
+            .map(|(&gpa, &permissions)| (gpa, permissions))
+            .filter(|(gpa, permissions)| self.protections.get(gpa) != Some(permissions))
+            .collect();
+        self.protections = pages;
+
+        // Hooked pages are left as they are. Their protection is applied once
+        // they are unhooked.
+        for gpa in removed
+            .into_iter()
+            .chain(changed.into_iter().map(|(gpa, _)| gpa))
+        {
+            if !self.is_hooked(gpa) {
+                self.restore_protection(gpa);
+            }
+        }
+    }
+
+    /// Maps the page containing `gpa` with `permissions` in `tables`, which
+    /// map the other pages as the primary NPT does, and returns the nCR3 of
+    /// `tables`. The pages mapped earlier are kept until `tables` are cleared
+    /// with `StepTables::clear`.
+    pub(crate) fn map_in_step_tables(
+        &self,
+        tables: &mut StepTables,
+        gpa: u64,
+        permissions: Permissions,
+    ) -> u64 {
+        let ops = platform_ops::get();
+        if !tables.active {
+            tables.pdpt.0.entries = self.ps.pdpt.0.entries;
+            tables.pml4.0.entries[0] = self.ps.pml4.0.entries[0];
+            tables.pml4.0.entries[0]
+                .set_pfn(ops.pa(tables.pdpt.as_ref() as *const _ as _) >> BASE_PAGE_SHIFT);
+            tables.active = true;
+        }
+
+        // Copy the NPT PD and PT for the page from the primary NPT, or split
+        // the copies of the 1GB and 2MB pages. Make them fully initialized
+        // before linking, as the processor may walk them speculatively.
+        let pdpt_index = pdpt_index(gpa);
+        let large_page_gpa = gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+        if let btree_map::Entry::Vacant(entry) = tables.pds.entry(pdpt_index) {
+            let pdpte = &mut tables.pdpt.0.entries[pdpt_index];
+            let mut pd = zeroed_box::<Pd>();
+            match self.ps.split_pd(pdpt_index) {
+                Some(primary_pd) => pd.0.entries = primary_pd.0.entries,
+                None => {
+                    let pfns = (pdpte.pfn()..).step_by(pd.0.entries.len());
+                    for (pfn, pde) in pfns.zip(pd.0.entries.iter_mut()) {
+                        *pde = *pdpte;
+                        pde.set_pfn(pfn);
+                    }
+                }
+            }
+            let pd_pa = ops.pa(pd.as_ref() as *const _ as _);
+            let _ = entry.insert(pd);
+
+            let mut new_pdpte = *pdpte;
+            new_pdpte.set_large(false);
+            new_pdpte.set_pfn(pd_pa >> BASE_PAGE_SHIFT);
+            *pdpte = new_pdpte;
+        }
+
+        if let btree_map::Entry::Vacant(entry) = tables.pts.entry(large_page_gpa) {
+            let pde = &mut tables.pds.get_mut(&pdpt_index).unwrap().0.entries[pd_index(gpa)];
+            let mut pt = zeroed_box::<Pt>();
+            if pde.large() {
+                for (pfn, pte) in (pde.pfn()..).zip(pt.0.entries.iter_mut()) {
+                    *pte = *pde;
+                    pte.set_large(false);
+                    pte.set_pfn(pfn);
+                }
+            } else {
+                // The only 2MB page split without `pts` is the one for the APIC
+                // page. See `pt`.
+                let primary_pt = self.pts.get(&large_page_gpa).unwrap_or(&self.apic_pt);
+                pt.0.entries = primary_pt.0.entries;
+            }
+            let pt_pa = ops.pa(pt.as_ref() as *const _ as _);
+            let _ = entry.insert(pt);
+
+            let mut new_pde = *pde;
+            new_pde.set_large(false);
+            new_pde.set_pfn(pt_pa >> BASE_PAGE_SHIFT);
+            *pde = new_pde;
+        }
+
+        let pte = &mut tables.pts.get_mut(&large_page_gpa).unwrap().0.entries[pt_index(gpa)];
+        let mut new_pte = *pte;
+        set_permissions(&mut new_pte, permissions);
+        *pte = new_pte;
+        ops.pa(tables.pml4.as_ref() as *const _ as _)
+    }
+
+    /// Maps the page containing `gpa` with the permissions of its protection in
+    /// both the primary NPT and the hook view. In the hook view, the page stays
+    /// non-executable.
+    pub(crate) fn restore_protection(&mut self, gpa: u64) {
+        if hidden_memory::is_hidden(gpa) {
+            return;
+        }
+        let gpa = gpa & !(BASE_PAGE_SIZE as u64 - 1);
+        let permissions = self
+            .protections
+            .get(&gpa)
+            .copied()
+            .unwrap_or(Permissions::ALL);
+
+        let pt = self.pt(gpa);
+        let pte = &mut pt.0.entries[pt_index(gpa)];
+        let mut new_pte = *pte;
+        set_permissions(&mut new_pte, permissions);
+        *pte = new_pte;
+        let pt = *pt;
+
+        // The hook view may have copied the mapping of the page. Make it map the
+        // page with its own PT to update it.
+        if let Some(view) = &mut self.hook_view {
+            let pd = *self.ps.pd(pdpt_index(gpa));
+            view.split(gpa, &pd, &pt);
+            let pte = view.pte(gpa);
+            let mut new_pte = *pte;
+            new_pte.set_present(permissions.read);
+            new_pte.set_writable(permissions.write);
+            *pte = new_pte;
+        }
+    }
+
+    /// Maps the pages at `gpas` to the zeroed dummy page as read-only in both
+    /// the primary NPT and the hook view, so that the guest can neither observe
+    /// their contents nor write to the dummy page. The protections of the pages
+    /// are no longer applied. The caller must flush the TLB entries of the
+    /// guest.
+    pub(crate) fn hide(&mut self, gpas: &[u64]) {
+        let dummy_pfn =
+            platform_ops::get().pa(self.dummy_page.as_ref() as *const _ as _) >> BASE_PAGE_SHIFT;
+        for &gpa in gpas {
+            let pt = self.pt(gpa);
+            let pte = &mut pt.0.entries[pt_index(gpa)];
+            let mut new_pte = *pte;
+            set_permissions(&mut new_pte, Permissions::READ_ONLY);
+            new_pte.set_pfn(dummy_pfn);
+            *pte = new_pte;
+            let pt = *pt;
+
+            if let Some(view) = &mut self.hook_view {
+                let pd = *self.ps.pd(pdpt_index(gpa));
+                view.split(gpa, &pd, &pt);
+                let pte = view.pte(gpa);
+                let mut new_pte = *pte;
+                set_permissions(&mut new_pte, Permissions::READ_ONLY);
+                new_pte.set_pfn(dummy_pfn);
+                *pte = new_pte;
+            }
+        }
+    }
+
+    /// Returns the NPT PT for `gpa`, splitting the NPT PDPTE and PDE for it if
+    /// they map a 1GB or 2MB page.
+    fn pt(&mut self, gpa: u64) -> &mut Pt {
+        let large_page_gpa = gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+        let pde = &mut self.ps.pd(pdpt_index(gpa)).0.entries[pd_index(gpa)];
+
+        // The only 2MB page split without `pts` is the one for the APIC page.
+        if !pde.large() && !self.pts.contains_key(&large_page_gpa) {
+            return &mut self.apic_pt;
+        }
+        self.pts.entry(large_page_gpa).or_insert_with(|| {
+            let mut pt = zeroed_box::<Pt>();
+            Self::split_2mb(pde, &mut pt);
+            pt
+        })
     }
 
     /// Update the `pde` to point to `pt` to split the page from 2MB to 4KBs.
@@ -49,19 +414,183 @@ impl NestedPageTables {
 
         let writable = pde.writable();
         let user = pde.user();
-        let mut pfn = pde.pfn();
-        for pte in &mut pt.0.entries {
+        for (pfn, pte) in (pde.pfn()..).zip(pt.0.entries.iter_mut()) {
             assert!(!pte.present());
             pte.set_present(true);
             pte.set_writable(writable);
             pte.set_user(user);
             pte.set_large(false);
             pte.set_pfn(pfn);
-            pfn += BASE_PAGE_SIZE as u64;
         }
 
+        // Update the PDE at once, as other processors may be walking the NPT.
         let pt_pa = platform_ops::get().pa(pt as *mut _ as _);
-        pde.set_pfn(pt_pa >> BASE_PAGE_SHIFT);
-        pde.set_large(false);
+        let mut new_pde = *pde;
+        new_pde.set_pfn(pt_pa >> BASE_PAGE_SHIFT);
+        new_pde.set_large(false);
+        *pde = new_pde;
+    }
+}
+
+/// The NPT where everything is non-executable except the shadow pages. The
+/// paging structures are shared with the primary NPT except for 1GB and 2MB
+/// regions with hooks.
+#[derive(Debug)]
+struct HookView {
+    #[allow(dead_code)]
+    pml4: Box<Pml4>,
+    pml4_pa: u64,
+    pdpt: Box<Pdpt>,
+
+    /// The NPT PDs for 1GB regions with hooks, keyed by the PDPT index.
+    pds: BTreeMap<usize, Box<Pd>>,
+
+    /// The NPT PTs for 2MB regions with hooks, keyed by the GPA of the regions.
+    pts: BTreeMap<u64, Box<Pt>>,
+}
+
+impl HookView {
+    fn new(primary: &PagingStructuresRaw) -> Self {
+        let ops = platform_ops::get();
+        let mut pml4 = zeroed_box::<Pml4>();
+        let mut pdpt = zeroed_box::<Pdpt>();
+
+        // Reuse the primary PDs, but make all of them non-executable.
+        pdpt.0.entries = primary.pdpt.0.entries;
+        for pdpte in &mut pdpt.0.entries {
+            pdpte.set_no_execute(true);
+        }
+
+        pml4.0.entries[0] = primary.pml4.0.entries[0];
+        pml4.0.entries[0].set_pfn(ops.pa(pdpt.as_ref() as *const _ as _) >> BASE_PAGE_SHIFT);
+
+        let pml4_pa = ops.pa(pml4.as_ref() as *const _ as _);
+        Self {
+            pml4,
+            pml4_pa,
+            pdpt,
+            pds: BTreeMap::new(),
+            pts: BTreeMap::new(),
+        }
     }
+
+    /// Makes the 2MB region containing `gpa` managed by its own NPT PD and PT
+    /// that are non-executable copies of `primary_pd` and `primary_pt`.
+    fn split(&mut self, gpa: u64, primary_pd: &Pd, primary_pt: &Pt) {
+        let ops = platform_ops::get();
+        let pdpt_index = pdpt_index(gpa);
+        let large_page_gpa = gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+
+        // Make the PD, then the PT, fully initialized before linking them, as
+        // other processors may be walking this NPT.
+        if let btree_map::Entry::Vacant(entry) = self.pds.entry(pdpt_index) {
+            let mut pd = zeroed_box::<Pd>();
+            pd.0.entries = primary_pd.0.entries;
+            for pde in &mut pd.0.entries {
+                pde.set_no_execute(true);
+            }
+            let pd_pa = ops.pa(pd.as_ref() as *const _ as _);
+            let _ = entry.insert(pd);
+
+            // The PDPTE may map a 1GB page if it was copied before the primary
+            // NPT split it.
+            let pdpte = &mut self.pdpt.0.entries[pdpt_index];
+            let mut new_pdpte = *pdpte;
+            new_pdpte.set_large(false);
+            new_pdpte.set_pfn(pd_pa >> BASE_PAGE_SHIFT);
+            new_pdpte.set_no_execute(false);
+            *pdpte = new_pdpte;
+        }
+
+        if let btree_map::Entry::Vacant(entry) = self.pts.entry(large_page_gpa) {
+            let mut pt = zeroed_box::<Pt>();
+            pt.0.entries = primary_pt.0.entries;
+            for pte in &mut pt.0.entries {
+                pte.set_no_execute(true);
+            }
+            let pt_pa = ops.pa(pt.as_ref() as *const _ as _);
+            let _ = entry.insert(pt);
+
+            let pde = &mut self.pds.get_mut(&pdpt_index).unwrap().0.entries[pd_index(gpa)];
+            let mut new_pde = *pde;
+            new_pde.set_large(false);
+            new_pde.set_pfn(pt_pa >> BASE_PAGE_SHIFT);
+            new_pde.set_no_execute(false);
+            *pde = new_pde;
+        }
+    }
+
+    /// Returns the NPT PTE for `gpa`. The region must be split with `split`.
+    fn pte(&mut self, gpa: u64) -> &mut Entry {
+        let large_page_gpa = gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+        &mut self.pts.get_mut(&large_page_gpa).unwrap().0.entries[pt_index(gpa)]
+    }
+}
+
+/// The NPT private to a processor, where pages are mapped differently from the
+/// primary NPT only while the processor single-steps an instruction, so that
+/// the other processors are not permitted the access meanwhile. Unlike
+/// `HookView`, the tables are not kept in sync with the primary NPT, but copy
+/// it on the first page mapped with `NestedPageTables::map_in_step_tables`
+/// after `clear`.
+pub(crate) struct StepTables {
+    pml4: Box<Pml4>,
+    pdpt: Box<Pdpt>,
+
+    /// The copies of the NPT PDs of the primary NPT for the 1GB regions
+    /// containing the mapped pages, keyed by the PDPT index.
+    pds: BTreeMap<usize, Box<Pd>>,
+
+    /// The copies of the NPT PTs of the primary NPT for the 2MB regions
+    /// containing the mapped pages, keyed by the GPA of the regions.
+    pts: BTreeMap<u64, Box<Pt>>,
+
+    /// Whether any page is mapped since the last `clear`.
+    active: bool,
+}
+
+impl StepTables {
+    pub(crate) fn new() -> Result<Self, HvError> {
+        Ok(Self {
+            pml4: try_zeroed_box::<Pml4>()?,
+            pdpt: try_zeroed_box::<Pdpt>()?,
+            pds: BTreeMap::new(),
+            pts: BTreeMap::new(),
+            active: false,
+        })
+    }
+
+    /// Returns whether any page is mapped with
+    /// `NestedPageTables::map_in_step_tables` since the last `clear`.
+    pub(crate) fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Unmaps the pages mapped with `NestedPageTables::map_in_step_tables`.
+    /// The processor must not use the tables until a page is mapped again.
+    pub(crate) fn clear(&mut self) {
+        self.pds.clear();
+        self.pts.clear();
+        self.active = false;
+    }
+}
+
+/// Updates the permissions of `entry` to `permissions`. Execute-only is not
+/// expressible, as any present page is readable.
+fn set_permissions(entry: &mut Entry, permissions: Permissions) {
+    entry.set_present(permissions.read);
+    entry.set_writable(permissions.write);
+    entry.set_no_execute(!permissions.execute);
+}
+
+fn pdpt_index(gpa: u64) -> usize {
+    gpa.get_bits(30..=38) as usize // [38:30]
+}
+
+fn pd_index(gpa: u64) -> usize {
+    gpa.get_bits(21..=29) as usize // [29:21]
+}
+
+fn pt_index(gpa: u64) -> usize {
+    gpa.get_bits(12..=20) as usize // [20:12]
 }
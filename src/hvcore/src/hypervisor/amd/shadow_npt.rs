@@ -0,0 +1,361 @@
+//! This module implements the NPT the guest builds for its own guest with
+//! nested virtualization, NPT12, and the shadow of it the processor actually
+//! uses for the nested guest, NPT02. See `nested` for the terms.
+//!
+//! NPT02 maps each L2 GPA to the PA NPT12 and then NPT01 translate it to, with
+//! the permissions both permit. It is filled on demand from nested page faults
+//! in L2, and is reset whenever either of them may have changed, that is, when
+//! L1 runs L2 with another NPT12 or requests flushing its TLB, or when L0
+//! updates NPT01.
+
+use alloc::{boxed::Box, collections::BTreeMap};
+use bit_field::BitField;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use crate::hypervisor::{
+    memory_protection::Permissions, platform_ops, support::try_zeroed_box, HvError,
+};
+
+/// The paging features of L1, which its NPT is checked against.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Npt12Features {
+    /// Whether EFER.NXE of L1 is set, without which the NX bit is reserved.
+    pub(crate) nx: bool,
+    /// Whether PDPTEs may map 1GB pages.
+    pub(crate) page_1gb: bool,
+    /// The physical-address width of the processor.
+    pub(crate) pa_bits: u8,
+}
+
+/// The translation of a GPA of L2 by NPT12.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Npt12Walk {
+    /// The GPA is mapped by `Npt12Leaf`.
+    Mapped(Npt12Leaf),
+    /// An entry to walk is not present.
+    NotPresent,
+    /// An entry to walk sets a reserved bit, or cannot be read.
+    Reserved,
+}
+
+/// The NPT12 entry mapping a page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Npt12Leaf {
+    /// The L1 GPA of the page.
+    pub(crate) base: u64,
+    /// The size of the page.
+    pub(crate) size: u64,
+    /// The accesses all entries walked permit. Nested page table walks are
+    /// user accesses, so entries without the U/S bit permit none.
+    pub(crate) permissions: Permissions,
+    /// The PWT, PCD and PAT bits, at their positions in a PTE.
+    pub(crate) cache_flags: u64,
+}
+
+impl Npt12Leaf {
+    /// Returns the L1 GPA `gpa` of L2 translates to.
+    pub(crate) fn translate(&self, gpa: u64) -> u64 {
+        self.base + (gpa & (self.size - 1))
+    }
+}
+
+/// Walks NPT12 whose PML4 is at the L1 GPA `ncr3` to translate `gpa`, with the
+/// value of each entry read with `read_entry` from its L1 GPA. An entry that
+/// cannot be read is treated as setting a reserved bit.
+// See: 15.25.5 Nested Table Walk
+// See: 5.3 Long-Mode Page Translation
+pub(crate) fn walk(
+    ncr3: u64,
+    gpa: u64,
+    features: &Npt12Features,
+    mut read_entry: impl FnMut(u64) -> Option<u64>,
+) -> Npt12Walk {
+    let pfn_mask = ((1u64 << features.pa_bits) - 1) & !(BASE_PAGE_SIZE as u64 - 1);
+    let mut table = ncr3 & pfn_mask;
+    let mut permissions = Permissions::ALL;
+    for level in (1..=4u8).rev() {
+        let shift = 12 + 9 * (u32::from(level) - 1);
+        let index = (gpa >> shift) & 0x1ff;
+        let Some(entry) = read_entry(table + index * 8) else {
+            return Npt12Walk::Reserved;
+        };
+
+        if entry & PRESENT == 0 {
+            return Npt12Walk::NotPresent;
+        }
+        if entry.get_bits(usize::from(features.pa_bits)..52) != 0
+            || (entry & NO_EXECUTE != 0 && !features.nx)
+        {
+            return Npt12Walk::Reserved;
+        }
+        let user = entry & USER != 0;
+        permissions = Permissions {
+            read: permissions.read && user,
+            write: permissions.write && user && entry & WRITABLE != 0,
+            execute: permissions.execute && user && entry & NO_EXECUTE == 0,
+        };
+
+        // Bit 7 of PTEs is the PAT bit instead.
+        let large = level != 1 && entry & LARGE != 0;
+        if large && (level == 4 || (level == 3 && !features.page_1gb)) {
+            return Npt12Walk::Reserved;
+        }
+        if level != 1 && !large {
+            table = entry & pfn_mask;
+            continue;
+        }
+
+        // The address bits below the page size of large pages are reserved,
+        // except bit 12, which is the PAT bit.
+        let size = 1u64 << shift;
+        let pat = if large {
+            if entry & pfn_mask & (size - 1) & !LARGE_PAT != 0 {
+                return Npt12Walk::Reserved;
+            }
+            entry & LARGE_PAT != 0
+        } else {
+            entry & PAT != 0
+        };
+        return Npt12Walk::Mapped(Npt12Leaf {
+            base: entry & pfn_mask & !(size - 1),
+            size,
+            permissions,
+            cache_flags: (entry & (WRITE_THROUGH | CACHE_DISABLE)) | if pat { PAT } else { 0 },
+        });
+    }
+    unreachable!()
+}
+
+/// NPT02, the NPT the processor uses for L2, owned by each processor. Unlike
+/// EPT on Intel, the processor has no instruction to invalidate translations
+/// derived from a specific NPT. The caller flushes the TLB entries of L2 when
+/// `take_stale` returns true.
+pub(crate) struct Npt02 {
+    pml4: Box<Table>,
+
+    /// The PDPTs, PDs and PTs, keyed by their PAs.
+    tables: BTreeMap<u64, Box<Table>>,
+
+    /// Whether entries were replaced or removed since the last `take_stale`.
+    stale: bool,
+}
+
+impl Npt02 {
+    /// Creates an empty NPT02, which maps nothing.
+    pub(crate) fn try_new() -> Result<Self, HvError> {
+        Ok(Self {
+            pml4: try_zeroed_box::<Table>()?,
+            tables: BTreeMap::new(),
+            stale: false,
+        })
+    }
+
+    /// Returns the nested page table CR3 of this NPT02.
+    pub(crate) fn ncr3(&self) -> u64 {
+        platform_ops::get().pa(self.pml4.as_ref() as *const _ as _)
+    }
+
+    /// Maps the 4KB page containing the L2 GPA `gpa` to the page containing
+    /// `pa` with `permissions` and `cache_flags` of `Npt12Leaf`. Pages that
+    /// are not readable are unmapped instead, as NPT cannot express them.
+    pub(crate) fn map(&mut self, gpa: u64, pa: u64, permissions: Permissions, cache_flags: u64) {
+        // Bound the memory each processor spends on this cache.
+        const MAX_TABLES: usize = 512;
+
+        if !permissions.read {
+            self.unmap(gpa);
+            return;
+        }
+        if self.tables.len() + 3 > MAX_TABLES {
+            self.reset();
+        }
+
+        let mut table_pa = None;
+        for level in (2..=4).rev() {
+            let index = index_of(gpa, level);
+            let entry = self.table_mut(table_pa).0[index];
+            let next_pa = if entry & PRESENT != 0 {
+                entry & PFN_MASK
+            } else {
+                // Start over if out of memory. The access faults again and
+                // maps the page with the tables freed.
+                let Ok(table) = try_zeroed_box::<Table>() else {
+                    self.reset();
+                    return;
+                };
+                let new_pa = platform_ops::get().pa(table.as_ref() as *const _ as _);
+                let _ = self.tables.insert(new_pa, table);
+                new_pa
+            };
+            self.table_mut(table_pa).0[index] = next_pa | USER | WRITABLE | PRESENT;
+            table_pa = Some(next_pa);
+        }
+
+        let table = self.table_mut(table_pa);
+        let replaced = table.0[index_of(gpa, 1)] & PRESENT != 0;
+        table.0[index_of(gpa, 1)] = (pa & PFN_MASK)
+            | (cache_flags & (WRITE_THROUGH | CACHE_DISABLE | PAT))
+            | if permissions.write { WRITABLE } else { 0 }
+            | if permissions.execute { 0 } else { NO_EXECUTE }
+            | USER
+            | PRESENT;
+        self.stale |= replaced;
+    }
+
+    /// Unmaps the 4KB page containing the L2 GPA `gpa` if it is mapped.
+    pub(crate) fn unmap(&mut self, gpa: u64) {
+        let mut table_pa = None;
+        for level in (2..=4).rev() {
+            let entry = self.table_mut(table_pa).0[index_of(gpa, level)];
+            if entry & PRESENT == 0 {
+                return;
+            }
+            table_pa = Some(entry & PFN_MASK);
+        }
+        let table = self.table_mut(table_pa);
+        let removed = table.0[index_of(gpa, 1)] & PRESENT != 0;
+        table.0[index_of(gpa, 1)] = 0;
+        self.stale |= removed;
+    }
+
+    /// Unmaps everything.
+    pub(crate) fn reset(&mut self) {
+        self.pml4.0.fill(0);
+        self.tables.clear();
+        self.stale = true;
+    }
+
+    /// Returns whether the TLB entries of L2 may be derived from entries
+    /// replaced or removed since the last call.
+    pub(crate) fn take_stale(&mut self) -> bool {
+        core::mem::take(&mut self.stale)
+    }
+
+    /// Returns the table at `pa`, or the PML4 for `None`.
+    fn table_mut(&mut self, pa: Option<u64>) -> &mut Table {
+        match pa {
+            Some(pa) => self.tables.get_mut(&pa).unwrap(),
+            None => &mut self.pml4,
+        }
+    }
+}
+
+/// Returns the index of the entry of the paging structure at `level`, 4 for
+/// PML4, translating `gpa`.
+fn index_of(gpa: u64, level: u32) -> usize {
+    ((gpa >> (12 + 9 * (level - 1))) & 0x1ff) as usize
+}
+
+// See: Figure 5-21. 4-Kbyte PTE—Long Mode
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const USER: u64 = 1 << 2;
+const WRITE_THROUGH: u64 = 1 << 3;
+const CACHE_DISABLE: u64 = 1 << 4;
+const LARGE: u64 = 1 << 7;
+const PAT: u64 = 1 << 7;
+const LARGE_PAT: u64 = 1 << 12;
+const NO_EXECUTE: u64 = 1 << 63;
+const PFN_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+#[repr(C, align(4096))]
+struct Table([u64; 512]);
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+
+    use super::*;
+
+    const FEATURES: Npt12Features = Npt12Features {
+        nx: true,
+        page_1gb: true,
+        pa_bits: 39,
+    };
+
+    /// Returns the NPT12 entries mapping 0x20_3000 to 0x1234_5000 with the
+    /// structures at 0x1000 (PML4), 0x2000 (PDPT), 0x3000 (PD) and 0x4000 (PT).
+    fn npt12(pte: u64) -> BTreeMap<u64, u64> {
+        BTreeMap::from([
+            (0x1000, 0x2000 | 0b111),
+            (0x2000, 0x3000 | 0b111),
+            (0x3000 + 8, 0x4000 | NO_EXECUTE | 0b111),
+            (0x4000 + 3 * 8, pte),
+        ])
+    }
+
+    fn walk_in(entries: &BTreeMap<u64, u64>, gpa: u64) -> Npt12Walk {
+        walk(0x1000, gpa, &FEATURES, |pa| {
+            Some(entries.get(&pa).copied().unwrap_or(0))
+        })
+    }
+
+    #[test]
+    fn walk_4kb_page() {
+        let entries = npt12(0x1234_5000 | PAT | CACHE_DISABLE | 0b111);
+        let Npt12Walk::Mapped(leaf) = walk_in(&entries, 0x20_3abc) else {
+            panic!("not mapped");
+        };
+        assert_eq!(leaf.translate(0x20_3abc), 0x1234_5abc);
+        assert_eq!(leaf.size, BASE_PAGE_SIZE as u64);
+        assert_eq!(leaf.cache_flags, PAT | CACHE_DISABLE);
+        // The PDE does not permit execution.
+        assert_eq!(leaf.permissions, Permissions::READ_WRITE);
+        assert_eq!(walk_in(&entries, 0x20_4000), Npt12Walk::NotPresent);
+
+        // Supervisor pages permit no access.
+        let entries = npt12(0x1234_5000 | 0b011);
+        let Npt12Walk::Mapped(leaf) = walk_in(&entries, 0x20_3abc) else {
+            panic!("not mapped");
+        };
+        assert_eq!(leaf.permissions, Permissions::NONE);
+    }
+
+    #[test]
+    fn walk_large_pages() {
+        let mut entries = npt12(0);
+        let _ = entries.insert(0x2000 + 8, 0x8000_0000 | LARGE_PAT | LARGE | 0b101);
+        let Npt12Walk::Mapped(leaf) = walk_in(&entries, 0x4123_4567) else {
+            panic!("not mapped");
+        };
+        assert_eq!(leaf.translate(0x4123_4567), 0x8123_4567);
+        assert_eq!(leaf.permissions, Permissions::READ_EXECUTE);
+        assert_eq!(leaf.cache_flags, PAT);
+
+        // 1GB pages are not supported, or the address is not aligned.
+        let no_1gb = Npt12Features {
+            page_1gb: false,
+            ..FEATURES
+        };
+        let walk_no_1gb = walk(0x1000, 0x4123_4567, &no_1gb, |pa| {
+            Some(entries.get(&pa).copied().unwrap_or(0))
+        });
+        assert_eq!(walk_no_1gb, Npt12Walk::Reserved);
+        let _ = entries.insert(0x2000 + 8, 0x8020_0000 | LARGE | 0b101);
+        assert_eq!(walk_in(&entries, 0x4123_4567), Npt12Walk::Reserved);
+    }
+
+    #[test]
+    fn walk_reserved_bits() {
+        // An address beyond the physical-address width, and a large PML4E.
+        let mut entries = npt12(1 << 40 | 0b111);
+        assert_eq!(walk_in(&entries, 0x20_3000), Npt12Walk::Reserved);
+        let _ = entries.insert(0x1000, 0x2000 | LARGE | 0b111);
+        assert_eq!(walk_in(&entries, 0x20_3000), Npt12Walk::Reserved);
+
+        // NX without EFER.NXE.
+        let no_nx = Npt12Features {
+            nx: false,
+            ..FEATURES
+        };
+        let entries = npt12(0x1234_5000 | 0b111);
+        let result = walk(0x1000, 0x20_3000, &no_nx, |pa| {
+            Some(entries.get(&pa).copied().unwrap_or(0))
+        });
+        assert_eq!(result, Npt12Walk::Reserved);
+
+        // Unreadable entries.
+        let result = walk(0x1000, 0x20_3000, &FEATURES, |_| None);
+        assert_eq!(result, Npt12Walk::Reserved);
+    }
+}
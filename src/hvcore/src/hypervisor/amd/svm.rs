@@ -1,19 +1,60 @@
 //! This module implements enablement of AMD SVM.
 
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+
 use crate::hypervisor::{
+    amd::avic,
     host::Extension,
     x86_instructions::{rdmsr, wrmsr},
+    HvError, VirtError,
 };
 
-#[derive(Default)]
 pub(crate) struct Svm;
 
 impl Extension for Svm {
-    fn enable(&mut self) {
+    fn new() -> Result<Self, HvError> {
+        Ok(Self)
+    }
+
+    fn check_support() -> Result<(), VirtError> {
+        const VM_CR: u32 = 0xc001_0114;
+        const VM_CR_SVMDIS_BIT: usize = 4;
+
+        // See: 15.4 Enabling SVM
+        if !cpuid!(0x8000_0001).ecx.get_bit(2) {
+            return Err(VirtError::SvmUnsupported);
+        }
+
+        // EFER.SVME cannot be set while VM_CR.SVMDIS is set. The BIOS may lock
+        // it, or have it unlockable only with a key, neither of which we do.
+        if rdmsr(VM_CR).get_bit(VM_CR_SVMDIS_BIT) {
+            return Err(VirtError::SvmDisabledByBios);
+        }
+
+        // The guest runs with nested paging, and VM-exit handlers depend on
+        // the next sequential instruction pointer saved on #VMEXIT.
+        // See: E.4.10 Function 8000_000Ah—SVM Revision and Feature Identification
+        let svm_features = cpuid!(0x8000_000a).edx;
+        if !svm_features.get_bit(0) || !svm_features.get_bit(3) {
+            return Err(VirtError::MissingFeatures);
+        }
+        avic::report_support();
+        Ok(())
+    }
+
+    fn enable(&mut self) -> Result<(), HvError> {
         const EFER_SVME: u64 = 1 << 12;
 
-        // Enable SVM. We assume the processor is compatible with this.
+        // Enable SVM. Support is checked with `check_support`.
         // See: 15.4 Enabling SVM
         wrmsr(x86::msr::IA32_EFER, rdmsr(x86::msr::IA32_EFER) | EFER_SVME);
+        Ok(())
+    }
+
+    fn disable(&mut self) {
+        const EFER_SVME: u64 = 1 << 12;
+
+        wrmsr(x86::msr::IA32_EFER, rdmsr(x86::msr::IA32_EFER) & !EFER_SVME);
     }
 }
@@ -0,0 +1,1261 @@
+//! This module implements typed access to the virtual machine control block
+//! (VMCB) with management of the VMCB clean field.
+//!
+//! The processor may cache groups of VMCB fields across VMRUN, and reloads a
+//! group from the VMCB only when its bit in the VMCB clean field is cleared.
+//! Modifying a field without clearing the bit makes the processor run the
+//! guest with the stale value. To prevent this, the fields are only modified
+//! through the setters of `Vmcb`, which clear the bit of the group the field
+//! belongs to. `Vmcb::mark_all_clean` is called after each #VMEXIT to let the
+//! processor use the cache again.
+//!
+//! ```ignore
+//! let dr6 = vmcb.dr6();
+//! vmcb.set_dr6(dr6 & !DR6_BS); // Clears the DRx clean bit.
+//! ```
+// See: 15.15 VMCB State Caching
+
+use crate::hypervisor::{
+    host::{GuestSegment, SegmentRegister},
+    support::PageBox,
+    HvError,
+};
+
+// The bits of the VMCB clean field, each of which tells the processor that the
+// group of fields is unmodified since the last VMRUN.
+// See: Table 15-10. VMCB Clean Field
+
+/// All the intercept vectors, TSC offset, Pause Filter Count
+const CLEAN_INTERCEPTS: u32 = 1 << 0;
+/// IOPM_BASE, MSRPM_BASE
+const CLEAN_IOPM: u32 = 1 << 1;
+/// ASID
+const CLEAN_ASID: u32 = 1 << 2;
+/// V_TPR, V_IRQ, V_INTR_PRIO, V_IGN_TPR, V_INTR_MASKING, V_INTR_VECTOR
+const CLEAN_TPR: u32 = 1 << 3;
+/// Nested paging: NCR3, G_PAT
+const CLEAN_NP: u32 = 1 << 4;
+/// CR0, CR3, CR4, EFER
+const CLEAN_CRX: u32 = 1 << 5;
+/// DR6, DR7
+const CLEAN_DRX: u32 = 1 << 6;
+/// GDT/IDT limit and base
+const CLEAN_DT: u32 = 1 << 7;
+/// CS/DS/SS/ES sel/base/limit/attr, CPL
+const CLEAN_SEG: u32 = 1 << 8;
+/// CR2
+const CLEAN_CR2: u32 = 1 << 9;
+/// DbgCtlMsr, br_from/to, lastint_from/to
+const CLEAN_LBR: u32 = 1 << 10;
+/// AVIC APIC_BAR, AVIC APIC_BACKING_PAGE, AVIC PHYSICAL_TABLE and AVIC
+/// LOGICAL_TABLE pointers
+const CLEAN_AVIC: u32 = 1 << 11;
+
+/// Table 15-9. TLB Control Byte Encodings
+#[allow(dead_code)]
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum TlbControl {
+    DoNotFlush = 0x0,
+    FlushAll = 0x1,
+    FlushGuests = 0x3,
+    FlushGuestsNonGlobal = 0x7,
+}
+
+/// The VMCB, accessed through the getters and setters managing the VMCB clean
+/// field.
+#[derive(Debug)]
+pub(crate) struct Vmcb {
+    ptr: PageBox<VmcbRaw>,
+}
+
+impl Vmcb {
+    /// Allocates a zeroed VMCB.
+    pub(crate) fn new() -> Result<Self, HvError> {
+        Ok(Self {
+            ptr: PageBox::try_new()?,
+        })
+    }
+
+    /// Returns the physical address of the VMCB.
+    pub(crate) fn pa(&self) -> u64 {
+        self.ptr.pa()
+    }
+
+    /// Tells the processor that no field is modified since the last VMRUN.
+    /// Called after #VMEXIT.
+    pub(crate) fn mark_all_clean(&mut self) {
+        self.ptr.control_area.vmcb_clean = u32::MAX;
+    }
+
+    /// Tells the processor that all fields may be modified since the last
+    /// VMRUN, for example, after the guest state is reset by INIT.
+    pub(crate) fn mark_all_dirty(&mut self) {
+        self.ptr.control_area.vmcb_clean = 0;
+    }
+
+    /// Logs the contents of the VMCB for diagnostics.
+    pub(crate) fn dump(&self) {
+        log::error!("{:#x?}", self.ptr);
+    }
+
+    /// Requests flushing TLB entries on the next VMRUN with `control`. This
+    /// field is not cached.
+    pub(crate) fn set_tlb_control(&mut self, control: TlbControl) {
+        self.ptr.control_area.tlb_control = control as _;
+    }
+
+    /// Returns the TLB control field requested for the next VMRUN.
+    pub(crate) fn tlb_control(&self) -> u32 {
+        self.ptr.control_area.tlb_control
+    }
+
+    /// Copies all fields from `other` and marks them dirty.
+    pub(crate) fn copy_from(&mut self, other: &Self) {
+        self.as_bytes_mut().copy_from_slice(other.as_bytes());
+        self.mark_all_dirty();
+    }
+
+    /// Returns the control area and the state save area as bytes, which are
+    /// the part of the VMCB page defined by the architecture.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        let ptr: *const VmcbRaw = &*self.ptr;
+        // Safety: The areas are `repr(C)` without implicit padding.
+        unsafe { core::slice::from_raw_parts(ptr.cast(), VMCB_AREAS_SIZE) }
+    }
+
+    /// Returns the control area and the state save area as mutable bytes. The
+    /// caller must mark modified fields dirty.
+    pub(crate) fn as_bytes_mut(&mut self) -> &mut [u8] {
+        let ptr: *mut VmcbRaw = &mut *self.ptr;
+        // Safety: The areas are `repr(C)` without implicit padding, and any
+        // byte pattern is a valid value of their fields.
+        unsafe { core::slice::from_raw_parts_mut(ptr.cast(), VMCB_AREAS_SIZE) }
+    }
+
+    /// Copies the guest state loaded by VMRUN and saved by #VMEXIT from `from`,
+    /// except G_PAT, which is loaded only with nested paging.
+    // See: 15.5.1 Basic Operation
+    pub(crate) fn copy_guest_state(&mut self, from: &Self) {
+        let (to, from) = (&mut self.ptr.state_save_area, &from.ptr.state_save_area);
+        macro_rules! copy {
+            ($($field:ident),* $(,)?) => { $(to.$field = from.$field;)* };
+        }
+        copy!(
+            es_selector,
+            es_attrib,
+            es_limit,
+            es_base,
+            cs_selector,
+            cs_attrib,
+            cs_limit,
+            cs_base,
+            ss_selector,
+            ss_attrib,
+            ss_limit,
+            ss_base,
+            ds_selector,
+            ds_attrib,
+            ds_limit,
+            ds_base,
+            gdtr_limit,
+            gdtr_base,
+            idtr_limit,
+            idtr_base,
+            cpl,
+            efer,
+            cr4,
+            cr3,
+            cr0,
+            dr7,
+            dr6,
+            rflags,
+            rip,
+            rsp,
+            s_cet,
+            ssp,
+            isst_addr,
+            rax,
+            cr2,
+        );
+        self.mark_dirty(CLEAN_CRX | CLEAN_DRX | CLEAN_DT | CLEAN_SEG | CLEAN_CR2);
+    }
+
+    /// Copies the guest state loaded by VMLOAD and saved by VMSAVE from `from`.
+    /// This state is not cached.
+    // See: 15.5.2 VMSAVE and VMLOAD Instructions
+    pub(crate) fn copy_vmload_state(&mut self, from: &Self) {
+        let (to, from) = (&mut self.ptr.state_save_area, &from.ptr.state_save_area);
+        macro_rules! copy {
+            ($($field:ident),* $(,)?) => { $(to.$field = from.$field;)* };
+        }
+        copy!(
+            fs_selector,
+            fs_attrib,
+            fs_limit,
+            fs_base,
+            gs_selector,
+            gs_attrib,
+            gs_limit,
+            gs_base,
+            tr_selector,
+            tr_attrib,
+            tr_limit,
+            tr_base,
+            ldtr_selector,
+            ldtr_attrib,
+            ldtr_limit,
+            ldtr_base,
+            kernel_gs_base,
+            star,
+            lstar,
+            cstar,
+            sf_mask,
+            sysenter_cs,
+            sysenter_esp,
+            sysenter_eip,
+        );
+    }
+
+    /// Copies the fields describing the last #VMEXIT from `from`.
+    // See: 15.6 #VMEXIT
+    pub(crate) fn copy_exit_info(&mut self, from: &Self) {
+        let (to, from) = (&mut self.ptr.control_area, &from.ptr.control_area);
+        to.interrupt_shadow = from.interrupt_shadow;
+        to.exit_code = from.exit_code;
+        to.exit_info1 = from.exit_info1;
+        to.exit_info2 = from.exit_info2;
+        to.exit_int_info = from.exit_int_info;
+        to.nrip = from.nrip;
+        to.num_of_bytes_fetched = from.num_of_bytes_fetched;
+        to.guest_instruction_bytes = from.guest_instruction_bytes;
+    }
+
+    /// Returns the bytes of the instruction that caused the last #VMEXIT due to
+    /// a nested page fault, as fetched by the processor.
+    // See: 15.25.9 Instruction Bytes
+    pub(crate) fn guest_instruction_bytes(&self) -> &[u8] {
+        let control = &self.ptr.control_area;
+        &control.guest_instruction_bytes[..usize::from(control.num_of_bytes_fetched)]
+    }
+
+    /// Returns the selector, base and limit of the guest `register`.
+    pub(crate) fn segment(&self, register: SegmentRegister) -> GuestSegment {
+        let state = &self.ptr.state_save_area;
+        let (selector, base, limit, attributes) = match register {
+            SegmentRegister::Es => (
+                state.es_selector,
+                state.es_base,
+                state.es_limit,
+                state.es_attrib,
+            ),
+            SegmentRegister::Cs => (
+                state.cs_selector,
+                state.cs_base,
+                state.cs_limit,
+                state.cs_attrib,
+            ),
+            SegmentRegister::Ss => (
+                state.ss_selector,
+                state.ss_base,
+                state.ss_limit,
+                state.ss_attrib,
+            ),
+            SegmentRegister::Ds => (
+                state.ds_selector,
+                state.ds_base,
+                state.ds_limit,
+                state.ds_attrib,
+            ),
+            SegmentRegister::Fs => (
+                state.fs_selector,
+                state.fs_base,
+                state.fs_limit,
+                state.fs_attrib,
+            ),
+            SegmentRegister::Gs => (
+                state.gs_selector,
+                state.gs_base,
+                state.gs_limit,
+                state.gs_attrib,
+            ),
+            SegmentRegister::Ldtr => (
+                state.ldtr_selector,
+                state.ldtr_base,
+                state.ldtr_limit,
+                state.ldtr_attrib,
+            ),
+            SegmentRegister::Tr => (
+                state.tr_selector,
+                state.tr_base,
+                state.tr_limit,
+                state.tr_attrib,
+            ),
+            SegmentRegister::Gdtr => (0, state.gdtr_base, state.gdtr_limit, 0),
+            SegmentRegister::Idtr => (0, state.idtr_base, state.idtr_limit, 0),
+        };
+        GuestSegment {
+            selector,
+            base,
+            limit,
+            attributes,
+        }
+    }
+
+    fn mark_dirty(&mut self, clean_bits: u32) {
+        self.ptr.control_area.vmcb_clean &= !clean_bits;
+    }
+
+    /// Returns the CR read intercept vector, one bit per control register.
+    pub(crate) fn intercept_cr_read(&self) -> u16 {
+        self.ptr.control_area.intercept_cr_read
+    }
+
+    /// Sets the CR read intercept vector, one bit per control register.
+    pub(crate) fn set_intercept_cr_read(&mut self, value: u16) {
+        self.ptr.control_area.intercept_cr_read = value;
+        self.mark_dirty(CLEAN_INTERCEPTS);
+    }
+
+    /// Returns the CR write intercept vector, one bit per control register.
+    pub(crate) fn intercept_cr_write(&self) -> u16 {
+        self.ptr.control_area.intercept_cr_write
+    }
+
+    /// Returns the DR read intercept vector, one bit per debug register.
+    pub(crate) fn intercept_dr_read(&self) -> u16 {
+        self.ptr.control_area.intercept_dr_read
+    }
+
+    /// Returns the DR write intercept vector, one bit per debug register.
+    pub(crate) fn intercept_dr_write(&self) -> u16 {
+        self.ptr.control_area.intercept_dr_write
+    }
+
+    /// Sets the DR read intercept vector, one bit per debug register.
+    pub(crate) fn set_intercept_dr_read(&mut self, value: u16) {
+        self.ptr.control_area.intercept_dr_read = value;
+        self.mark_dirty(CLEAN_INTERCEPTS);
+    }
+
+    /// Sets the DR write intercept vector, one bit per debug register.
+    pub(crate) fn set_intercept_dr_write(&mut self, value: u16) {
+        self.ptr.control_area.intercept_dr_write = value;
+        self.mark_dirty(CLEAN_INTERCEPTS);
+    }
+
+    /// Sets the CR write intercept vector, one bit per control register.
+    pub(crate) fn set_intercept_cr_write(&mut self, value: u16) {
+        self.ptr.control_area.intercept_cr_write = value;
+        self.mark_dirty(CLEAN_INTERCEPTS);
+    }
+
+    /// Returns the exception intercept vector.
+    pub(crate) fn intercept_exception(&self) -> u32 {
+        self.ptr.control_area.intercept_exception
+    }
+
+    /// Sets the exception intercept vector.
+    pub(crate) fn set_intercept_exception(&mut self, value: u32) {
+        self.ptr.control_area.intercept_exception = value;
+        self.mark_dirty(CLEAN_INTERCEPTS);
+    }
+
+    /// Returns the first vector of the miscellaneous intercepts.
+    pub(crate) fn intercept_misc1(&self) -> u32 {
+        self.ptr.control_area.intercept_misc1
+    }
+
+    /// Sets the first vector of the miscellaneous intercepts.
+    pub(crate) fn set_intercept_misc1(&mut self, value: u32) {
+        self.ptr.control_area.intercept_misc1 = value;
+        self.mark_dirty(CLEAN_INTERCEPTS);
+    }
+
+    /// Returns the second vector of the miscellaneous intercepts.
+    pub(crate) fn intercept_misc2(&self) -> u32 {
+        self.ptr.control_area.intercept_misc2
+    }
+
+    /// Sets the second vector of the miscellaneous intercepts.
+    pub(crate) fn set_intercept_misc2(&mut self, value: u32) {
+        self.ptr.control_area.intercept_misc2 = value;
+        self.mark_dirty(CLEAN_INTERCEPTS);
+    }
+
+    /// Returns the third vector of the miscellaneous intercepts.
+    pub(crate) fn intercept_misc3(&self) -> u32 {
+        self.ptr.control_area.intercept_misc3
+    }
+
+    /// Sets the third vector of the miscellaneous intercepts.
+    pub(crate) fn set_intercept_misc3(&mut self, value: u32) {
+        self.ptr.control_area.intercept_misc3 = value;
+        self.mark_dirty(CLEAN_INTERCEPTS);
+    }
+
+    /// Returns the PAUSE filter count.
+    pub(crate) fn pause_filter_count(&self) -> u16 {
+        self.ptr.control_area.pause_filter_count
+    }
+
+    /// Sets the PAUSE filter count.
+    pub(crate) fn set_pause_filter_count(&mut self, value: u16) {
+        self.ptr.control_area.pause_filter_count = value;
+        self.mark_dirty(CLEAN_INTERCEPTS);
+    }
+
+    /// Returns the PAUSE filter threshold.
+    pub(crate) fn pause_filter_threshold(&self) -> u16 {
+        self.ptr.control_area.pause_filter_threshold
+    }
+
+    /// Sets the PAUSE filter threshold.
+    pub(crate) fn set_pause_filter_threshold(&mut self, value: u16) {
+        self.ptr.control_area.pause_filter_threshold = value;
+        self.mark_dirty(CLEAN_INTERCEPTS);
+    }
+
+    /// Returns the physical address of the I/O permissions map.
+    pub(crate) fn iopm_base_pa(&self) -> u64 {
+        self.ptr.control_area.iopm_base_pa
+    }
+
+    /// Sets the physical address of the I/O permissions map.
+    pub(crate) fn set_iopm_base_pa(&mut self, value: u64) {
+        self.ptr.control_area.iopm_base_pa = value;
+        self.mark_dirty(CLEAN_IOPM);
+    }
+
+    /// Returns the physical address of the MSR permissions map.
+    pub(crate) fn msrpm_base_pa(&self) -> u64 {
+        self.ptr.control_area.msrpm_base_pa
+    }
+
+    /// Sets the physical address of the MSR permissions map.
+    pub(crate) fn set_msrpm_base_pa(&mut self, value: u64) {
+        self.ptr.control_area.msrpm_base_pa = value;
+        self.mark_dirty(CLEAN_IOPM);
+    }
+
+    /// Returns the TSC offset.
+    pub(crate) fn tsc_offset(&self) -> u64 {
+        self.ptr.control_area.tsc_offset
+    }
+
+    /// Sets the TSC offset.
+    pub(crate) fn set_tsc_offset(&mut self, value: u64) {
+        self.ptr.control_area.tsc_offset = value;
+        self.mark_dirty(CLEAN_INTERCEPTS);
+    }
+
+    /// Returns the ASID of the guest.
+    pub(crate) fn guest_asid(&self) -> u32 {
+        self.ptr.control_area.guest_asid
+    }
+
+    /// Sets the ASID of the guest.
+    pub(crate) fn set_guest_asid(&mut self, value: u32) {
+        self.ptr.control_area.guest_asid = value;
+        self.mark_dirty(CLEAN_ASID);
+    }
+
+    /// Returns the virtual interrupt control.
+    pub(crate) fn vintr(&self) -> u64 {
+        self.ptr.control_area.vintr
+    }
+
+    /// Sets the virtual interrupt control.
+    pub(crate) fn set_vintr(&mut self, value: u64) {
+        self.ptr.control_area.vintr = value;
+        self.mark_dirty(CLEAN_TPR);
+    }
+
+    /// Sets the guest physical address of the local APIC for AVIC.
+    pub(crate) fn set_avic_apic_bar(&mut self, value: u64) {
+        self.ptr.control_area.avic_apic_bar = value;
+        self.mark_dirty(CLEAN_AVIC);
+    }
+
+    /// Sets the physical address of the APIC backing page.
+    pub(crate) fn set_avic_apic_backing_page_pointer(&mut self, value: u64) {
+        self.ptr.control_area.avic_apic_backing_page_pointer = value;
+        self.mark_dirty(CLEAN_AVIC);
+    }
+
+    /// Sets the physical address of the logical APIC ID table.
+    pub(crate) fn set_avic_logical_table_pointer(&mut self, value: u64) {
+        self.ptr.control_area.avic_logical_table_pointer = value;
+        self.mark_dirty(CLEAN_AVIC);
+    }
+
+    /// Sets the physical address of the physical APIC ID table with the
+    /// highest index in the table.
+    pub(crate) fn set_avic_physical_table_pointer(&mut self, value: u64) {
+        self.ptr.control_area.avic_physical_table_pointer = value;
+        self.mark_dirty(CLEAN_AVIC);
+    }
+
+    /// Returns the interrupt shadow state.
+    pub(crate) fn interrupt_shadow(&self) -> u64 {
+        self.ptr.control_area.interrupt_shadow
+    }
+
+    /// Sets the interrupt shadow state.
+    pub(crate) fn set_interrupt_shadow(&mut self, value: u64) {
+        self.ptr.control_area.interrupt_shadow = value;
+    }
+
+    /// Returns the exit code of the last #VMEXIT.
+    pub(crate) fn exit_code(&self) -> u64 {
+        self.ptr.control_area.exit_code
+    }
+
+    /// Sets the exit code, for example, to report a #VMEXIT to the guest.
+    pub(crate) fn set_exit_code(&mut self, value: u64) {
+        self.ptr.control_area.exit_code = value;
+    }
+
+    /// Returns EXITINFO1 of the last #VMEXIT.
+    pub(crate) fn exit_info1(&self) -> u64 {
+        self.ptr.control_area.exit_info1
+    }
+
+    /// Sets EXITINFO1.
+    pub(crate) fn set_exit_info1(&mut self, value: u64) {
+        self.ptr.control_area.exit_info1 = value;
+    }
+
+    /// Sets EXITINFO2.
+    pub(crate) fn set_exit_info2(&mut self, value: u64) {
+        self.ptr.control_area.exit_info2 = value;
+    }
+
+    /// Returns EXITINFO2 of the last #VMEXIT.
+    pub(crate) fn exit_info2(&self) -> u64 {
+        self.ptr.control_area.exit_info2
+    }
+
+    /// Returns the event being delivered at the last #VMEXIT.
+    pub(crate) fn exit_int_info(&self) -> u64 {
+        self.ptr.control_area.exit_int_info
+    }
+
+    /// Sets the event being delivered at the #VMEXIT.
+    pub(crate) fn set_exit_int_info(&mut self, value: u64) {
+        self.ptr.control_area.exit_int_info = value;
+    }
+
+    /// Returns the nested paging enable bits.
+    pub(crate) fn np_enable(&self) -> u64 {
+        self.ptr.control_area.np_enable
+    }
+
+    /// Sets the nested paging enable bits.
+    pub(crate) fn set_np_enable(&mut self, value: u64) {
+        self.ptr.control_area.np_enable = value;
+        self.mark_dirty(CLEAN_NP);
+    }
+
+    /// Returns the event to inject on the next VMRUN.
+    pub(crate) fn event_inj(&self) -> u64 {
+        self.ptr.control_area.event_inj
+    }
+
+    /// Sets the event to inject on the next VMRUN.
+    pub(crate) fn set_event_inj(&mut self, value: u64) {
+        self.ptr.control_area.event_inj = value;
+    }
+
+    /// Returns the nested page table CR3.
+    pub(crate) fn ncr3(&self) -> u64 {
+        self.ptr.control_area.ncr3
+    }
+
+    /// Sets the nested page table CR3.
+    pub(crate) fn set_ncr3(&mut self, value: u64) {
+        self.ptr.control_area.ncr3 = value;
+        self.mark_dirty(CLEAN_NP);
+    }
+
+    /// Returns the next sequential instruction pointer of the last #VMEXIT.
+    pub(crate) fn nrip(&self) -> u64 {
+        self.ptr.control_area.nrip
+    }
+
+    /// Returns the guest ES selector.
+    pub(crate) fn es_selector(&self) -> u16 {
+        self.ptr.state_save_area.es_selector
+    }
+
+    /// Sets the guest ES selector.
+    pub(crate) fn set_es_selector(&mut self, value: u16) {
+        self.ptr.state_save_area.es_selector = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Sets the guest ES attributes.
+    pub(crate) fn set_es_attrib(&mut self, value: u16) {
+        self.ptr.state_save_area.es_attrib = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Sets the guest ES limit.
+    pub(crate) fn set_es_limit(&mut self, value: u32) {
+        self.ptr.state_save_area.es_limit = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Sets the guest ES base.
+    pub(crate) fn set_es_base(&mut self, value: u64) {
+        self.ptr.state_save_area.es_base = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Returns the guest CS selector.
+    pub(crate) fn cs_selector(&self) -> u16 {
+        self.ptr.state_save_area.cs_selector
+    }
+
+    /// Sets the guest CS selector.
+    pub(crate) fn set_cs_selector(&mut self, value: u16) {
+        self.ptr.state_save_area.cs_selector = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Sets the guest CS attributes.
+    pub(crate) fn set_cs_attrib(&mut self, value: u16) {
+        self.ptr.state_save_area.cs_attrib = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Sets the guest CS limit.
+    pub(crate) fn set_cs_limit(&mut self, value: u32) {
+        self.ptr.state_save_area.cs_limit = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Sets the guest CS base.
+    pub(crate) fn set_cs_base(&mut self, value: u64) {
+        self.ptr.state_save_area.cs_base = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Returns the guest SS selector.
+    pub(crate) fn ss_selector(&self) -> u16 {
+        self.ptr.state_save_area.ss_selector
+    }
+
+    /// Sets the guest SS selector.
+    pub(crate) fn set_ss_selector(&mut self, value: u16) {
+        self.ptr.state_save_area.ss_selector = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Sets the guest SS attributes.
+    pub(crate) fn set_ss_attrib(&mut self, value: u16) {
+        self.ptr.state_save_area.ss_attrib = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Sets the guest SS limit.
+    pub(crate) fn set_ss_limit(&mut self, value: u32) {
+        self.ptr.state_save_area.ss_limit = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Returns the guest DS selector.
+    pub(crate) fn ds_selector(&self) -> u16 {
+        self.ptr.state_save_area.ds_selector
+    }
+
+    /// Sets the guest DS selector.
+    pub(crate) fn set_ds_selector(&mut self, value: u16) {
+        self.ptr.state_save_area.ds_selector = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Sets the guest DS attributes.
+    pub(crate) fn set_ds_attrib(&mut self, value: u16) {
+        self.ptr.state_save_area.ds_attrib = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Sets the guest DS limit.
+    pub(crate) fn set_ds_limit(&mut self, value: u32) {
+        self.ptr.state_save_area.ds_limit = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Sets the guest DS base.
+    pub(crate) fn set_ds_base(&mut self, value: u64) {
+        self.ptr.state_save_area.ds_base = value;
+        self.mark_dirty(CLEAN_SEG);
+    }
+
+    /// Returns the guest FS selector.
+    pub(crate) fn fs_selector(&self) -> u16 {
+        self.ptr.state_save_area.fs_selector
+    }
+
+    /// Sets the guest FS selector.
+    pub(crate) fn set_fs_selector(&mut self, value: u16) {
+        self.ptr.state_save_area.fs_selector = value;
+    }
+
+    /// Sets the guest FS attributes.
+    pub(crate) fn set_fs_attrib(&mut self, value: u16) {
+        self.ptr.state_save_area.fs_attrib = value;
+    }
+
+    /// Sets the guest FS limit.
+    pub(crate) fn set_fs_limit(&mut self, value: u32) {
+        self.ptr.state_save_area.fs_limit = value;
+    }
+
+    /// Returns the guest FS base.
+    pub(crate) fn fs_base(&self) -> u64 {
+        self.ptr.state_save_area.fs_base
+    }
+
+    /// Sets the guest FS base.
+    pub(crate) fn set_fs_base(&mut self, value: u64) {
+        self.ptr.state_save_area.fs_base = value;
+    }
+
+    /// Returns the guest GS selector.
+    pub(crate) fn gs_selector(&self) -> u16 {
+        self.ptr.state_save_area.gs_selector
+    }
+
+    /// Sets the guest GS selector.
+    pub(crate) fn set_gs_selector(&mut self, value: u16) {
+        self.ptr.state_save_area.gs_selector = value;
+    }
+
+    /// Sets the guest GS attributes.
+    pub(crate) fn set_gs_attrib(&mut self, value: u16) {
+        self.ptr.state_save_area.gs_attrib = value;
+    }
+
+    /// Sets the guest GS limit.
+    pub(crate) fn set_gs_limit(&mut self, value: u32) {
+        self.ptr.state_save_area.gs_limit = value;
+    }
+
+    /// Returns the guest GS base.
+    pub(crate) fn gs_base(&self) -> u64 {
+        self.ptr.state_save_area.gs_base
+    }
+
+    /// Sets the guest GS base.
+    pub(crate) fn set_gs_base(&mut self, value: u64) {
+        self.ptr.state_save_area.gs_base = value;
+    }
+
+    /// Returns the guest LDTR selector.
+    pub(crate) fn ldtr_selector(&self) -> u16 {
+        self.ptr.state_save_area.ldtr_selector
+    }
+
+    /// Sets the guest LDTR selector.
+    pub(crate) fn set_ldtr_selector(&mut self, value: u16) {
+        self.ptr.state_save_area.ldtr_selector = value;
+    }
+
+    /// Sets the guest LDTR attributes.
+    pub(crate) fn set_ldtr_attrib(&mut self, value: u16) {
+        self.ptr.state_save_area.ldtr_attrib = value;
+    }
+
+    /// Sets the guest LDTR limit.
+    pub(crate) fn set_ldtr_limit(&mut self, value: u32) {
+        self.ptr.state_save_area.ldtr_limit = value;
+    }
+
+    /// Sets the guest LDTR base.
+    pub(crate) fn set_ldtr_base(&mut self, value: u64) {
+        self.ptr.state_save_area.ldtr_base = value;
+    }
+
+    /// Returns the guest TR selector.
+    pub(crate) fn tr_selector(&self) -> u16 {
+        self.ptr.state_save_area.tr_selector
+    }
+
+    /// Sets the guest TR selector.
+    pub(crate) fn set_tr_selector(&mut self, value: u16) {
+        self.ptr.state_save_area.tr_selector = value;
+    }
+
+    /// Sets the guest TR attributes.
+    pub(crate) fn set_tr_attrib(&mut self, value: u16) {
+        self.ptr.state_save_area.tr_attrib = value;
+    }
+
+    /// Sets the guest TR limit.
+    pub(crate) fn set_tr_limit(&mut self, value: u32) {
+        self.ptr.state_save_area.tr_limit = value;
+    }
+
+    /// Sets the guest TR base.
+    pub(crate) fn set_tr_base(&mut self, value: u64) {
+        self.ptr.state_save_area.tr_base = value;
+    }
+
+    /// Returns the guest GDTR limit.
+    pub(crate) fn gdtr_limit(&self) -> u32 {
+        self.ptr.state_save_area.gdtr_limit
+    }
+
+    /// Sets the guest GDTR limit.
+    pub(crate) fn set_gdtr_limit(&mut self, value: u32) {
+        self.ptr.state_save_area.gdtr_limit = value;
+        self.mark_dirty(CLEAN_DT);
+    }
+
+    /// Returns the guest GDTR base.
+    pub(crate) fn gdtr_base(&self) -> u64 {
+        self.ptr.state_save_area.gdtr_base
+    }
+
+    /// Sets the guest GDTR base.
+    pub(crate) fn set_gdtr_base(&mut self, value: u64) {
+        self.ptr.state_save_area.gdtr_base = value;
+        self.mark_dirty(CLEAN_DT);
+    }
+
+    /// Returns the guest IDTR limit.
+    pub(crate) fn idtr_limit(&self) -> u32 {
+        self.ptr.state_save_area.idtr_limit
+    }
+
+    /// Sets the guest IDTR limit.
+    pub(crate) fn set_idtr_limit(&mut self, value: u32) {
+        self.ptr.state_save_area.idtr_limit = value;
+        self.mark_dirty(CLEAN_DT);
+    }
+
+    /// Returns the guest IDTR base.
+    pub(crate) fn idtr_base(&self) -> u64 {
+        self.ptr.state_save_area.idtr_base
+    }
+
+    /// Sets the guest IDTR base.
+    pub(crate) fn set_idtr_base(&mut self, value: u64) {
+        self.ptr.state_save_area.idtr_base = value;
+        self.mark_dirty(CLEAN_DT);
+    }
+
+    /// Returns the current privilege level of the guest.
+    pub(crate) fn cpl(&self) -> u8 {
+        self.ptr.state_save_area.cpl
+    }
+
+    /// Returns the guest IA32_EFER.
+    pub(crate) fn efer(&self) -> u64 {
+        self.ptr.state_save_area.efer
+    }
+
+    /// Sets the guest IA32_EFER.
+    pub(crate) fn set_efer(&mut self, value: u64) {
+        self.ptr.state_save_area.efer = value;
+        self.mark_dirty(CLEAN_CRX);
+    }
+
+    /// Returns the guest CR4.
+    pub(crate) fn cr4(&self) -> u64 {
+        self.ptr.state_save_area.cr4
+    }
+
+    /// Sets the guest CR4.
+    pub(crate) fn set_cr4(&mut self, value: u64) {
+        self.ptr.state_save_area.cr4 = value;
+        self.mark_dirty(CLEAN_CRX);
+    }
+
+    /// Returns the guest CR3.
+    pub(crate) fn cr3(&self) -> u64 {
+        self.ptr.state_save_area.cr3
+    }
+
+    /// Sets the guest CR3.
+    pub(crate) fn set_cr3(&mut self, value: u64) {
+        self.ptr.state_save_area.cr3 = value;
+        self.mark_dirty(CLEAN_CRX);
+    }
+
+    /// Returns the guest CR0.
+    pub(crate) fn cr0(&self) -> u64 {
+        self.ptr.state_save_area.cr0
+    }
+
+    /// Sets the guest CR0.
+    pub(crate) fn set_cr0(&mut self, value: u64) {
+        self.ptr.state_save_area.cr0 = value;
+        self.mark_dirty(CLEAN_CRX);
+    }
+
+    /// Returns the guest DR7.
+    pub(crate) fn dr7(&self) -> u64 {
+        self.ptr.state_save_area.dr7
+    }
+
+    /// Sets the guest DR7.
+    pub(crate) fn set_dr7(&mut self, value: u64) {
+        self.ptr.state_save_area.dr7 = value;
+        self.mark_dirty(CLEAN_DRX);
+    }
+
+    /// Returns the guest DR6.
+    pub(crate) fn dr6(&self) -> u64 {
+        self.ptr.state_save_area.dr6
+    }
+
+    /// Sets the guest DR6.
+    pub(crate) fn set_dr6(&mut self, value: u64) {
+        self.ptr.state_save_area.dr6 = value;
+        self.mark_dirty(CLEAN_DRX);
+    }
+
+    /// Returns the guest RFLAGS.
+    pub(crate) fn rflags(&self) -> u64 {
+        self.ptr.state_save_area.rflags
+    }
+
+    /// Sets the guest RFLAGS.
+    pub(crate) fn set_rflags(&mut self, value: u64) {
+        self.ptr.state_save_area.rflags = value;
+    }
+
+    /// Returns the guest RIP.
+    pub(crate) fn rip(&self) -> u64 {
+        self.ptr.state_save_area.rip
+    }
+
+    /// Sets the guest RIP.
+    pub(crate) fn set_rip(&mut self, value: u64) {
+        self.ptr.state_save_area.rip = value;
+    }
+
+    /// Returns the guest RSP.
+    pub(crate) fn rsp(&self) -> u64 {
+        self.ptr.state_save_area.rsp
+    }
+
+    /// Sets the guest RSP.
+    pub(crate) fn set_rsp(&mut self, value: u64) {
+        self.ptr.state_save_area.rsp = value;
+    }
+
+    /// Returns the guest IA32_S_CET.
+    pub(crate) fn s_cet(&self) -> u64 {
+        self.ptr.state_save_area.s_cet
+    }
+
+    /// Sets the guest IA32_S_CET.
+    pub(crate) fn set_s_cet(&mut self, value: u64) {
+        self.ptr.state_save_area.s_cet = value;
+    }
+
+    /// Returns the guest SSP.
+    pub(crate) fn ssp(&self) -> u64 {
+        self.ptr.state_save_area.ssp
+    }
+
+    /// Sets the guest SSP.
+    pub(crate) fn set_ssp(&mut self, value: u64) {
+        self.ptr.state_save_area.ssp = value;
+    }
+
+    /// Returns the guest IA32_INTERRUPT_SSP_TABLE_ADDR.
+    pub(crate) fn isst_addr(&self) -> u64 {
+        self.ptr.state_save_area.isst_addr
+    }
+
+    /// Sets the guest IA32_INTERRUPT_SSP_TABLE_ADDR.
+    pub(crate) fn set_isst_addr(&mut self, value: u64) {
+        self.ptr.state_save_area.isst_addr = value;
+    }
+
+    /// Returns the guest RAX.
+    pub(crate) fn rax(&self) -> u64 {
+        self.ptr.state_save_area.rax
+    }
+
+    /// Sets the guest RAX.
+    pub(crate) fn set_rax(&mut self, value: u64) {
+        self.ptr.state_save_area.rax = value;
+    }
+
+    /// Returns the guest IA32_STAR.
+    pub(crate) fn star(&self) -> u64 {
+        self.ptr.state_save_area.star
+    }
+
+    /// Sets the guest IA32_STAR.
+    pub(crate) fn set_star(&mut self, value: u64) {
+        self.ptr.state_save_area.star = value;
+    }
+
+    /// Returns the guest IA32_LSTAR.
+    pub(crate) fn lstar(&self) -> u64 {
+        self.ptr.state_save_area.lstar
+    }
+
+    /// Sets the guest IA32_LSTAR.
+    pub(crate) fn set_lstar(&mut self, value: u64) {
+        self.ptr.state_save_area.lstar = value;
+    }
+
+    /// Returns the guest IA32_CSTAR.
+    pub(crate) fn cstar(&self) -> u64 {
+        self.ptr.state_save_area.cstar
+    }
+
+    /// Sets the guest IA32_CSTAR.
+    pub(crate) fn set_cstar(&mut self, value: u64) {
+        self.ptr.state_save_area.cstar = value;
+    }
+
+    /// Returns the guest IA32_FMASK.
+    pub(crate) fn sf_mask(&self) -> u64 {
+        self.ptr.state_save_area.sf_mask
+    }
+
+    /// Sets the guest IA32_FMASK.
+    pub(crate) fn set_sf_mask(&mut self, value: u64) {
+        self.ptr.state_save_area.sf_mask = value;
+    }
+
+    /// Returns the guest IA32_KERNEL_GS_BASE.
+    pub(crate) fn kernel_gs_base(&self) -> u64 {
+        self.ptr.state_save_area.kernel_gs_base
+    }
+
+    /// Sets the guest IA32_KERNEL_GS_BASE.
+    pub(crate) fn set_kernel_gs_base(&mut self, value: u64) {
+        self.ptr.state_save_area.kernel_gs_base = value;
+    }
+
+    /// Returns the guest IA32_SYSENTER_CS.
+    pub(crate) fn sysenter_cs(&self) -> u64 {
+        self.ptr.state_save_area.sysenter_cs
+    }
+
+    /// Sets the guest IA32_SYSENTER_CS.
+    pub(crate) fn set_sysenter_cs(&mut self, value: u64) {
+        self.ptr.state_save_area.sysenter_cs = value;
+    }
+
+    /// Returns the guest IA32_SYSENTER_ESP.
+    pub(crate) fn sysenter_esp(&self) -> u64 {
+        self.ptr.state_save_area.sysenter_esp
+    }
+
+    /// Sets the guest IA32_SYSENTER_ESP.
+    pub(crate) fn set_sysenter_esp(&mut self, value: u64) {
+        self.ptr.state_save_area.sysenter_esp = value;
+    }
+
+    /// Returns the guest IA32_SYSENTER_EIP.
+    pub(crate) fn sysenter_eip(&self) -> u64 {
+        self.ptr.state_save_area.sysenter_eip
+    }
+
+    /// Sets the guest IA32_SYSENTER_EIP.
+    pub(crate) fn set_sysenter_eip(&mut self, value: u64) {
+        self.ptr.state_save_area.sysenter_eip = value;
+    }
+
+    /// Sets the guest CR2.
+    pub(crate) fn set_cr2(&mut self, value: u64) {
+        self.ptr.state_save_area.cr2 = value;
+        self.mark_dirty(CLEAN_CR2);
+    }
+
+    /// Returns the guest IA32_PAT.
+    pub(crate) fn gpat(&self) -> u64 {
+        self.ptr.state_save_area.gpat
+    }
+
+    /// Sets the guest IA32_PAT.
+    pub(crate) fn set_gpat(&mut self, value: u64) {
+        self.ptr.state_save_area.gpat = value;
+        self.mark_dirty(CLEAN_NP);
+    }
+
+    /// Returns the guest IA32_DEBUGCTL.
+    pub(crate) fn dbg_ctl(&self) -> u64 {
+        self.ptr.state_save_area.dbg_ctl
+    }
+
+    /// Sets the guest IA32_DEBUGCTL.
+    pub(crate) fn set_dbg_ctl(&mut self, value: u64) {
+        self.ptr.state_save_area.dbg_ctl = value;
+        self.mark_dirty(CLEAN_LBR);
+    }
+}
+
+/// The virtual machine control block (VMCB), which describes a virtual machine
+/// (guest) to be executed.
+///
+/// See: Appendix B Layout of VMCB
+#[derive(Debug, Default)]
+#[repr(C, align(4096))]
+struct VmcbRaw {
+    control_area: ControlArea,
+    state_save_area: StateSaveArea,
+}
+const _: () = assert!(core::mem::size_of::<VmcbRaw>() == 0x1000);
+
+/// The "metadata" area where we can specify what operations to intercept and
+/// can read details of #VMEXIT.
+///
+/// See: Table B-1. VMCB Layout, Control Area
+#[derive(derivative::Derivative)]
+#[derivative(Debug, Default)]
+#[repr(C)]
+struct ControlArea {
+    intercept_cr_read: u16,   // +0x000
+    intercept_cr_write: u16,  // +0x002
+    intercept_dr_read: u16,   // +0x004
+    intercept_dr_write: u16,  // +0x006
+    intercept_exception: u32, // +0x008
+    intercept_misc1: u32,     // +0x00c
+    intercept_misc2: u32,     // +0x010
+    intercept_misc3: u32,     // +0x014
+    #[derivative(Debug = "ignore", Default(value = "[0; 36]"))]
+    _padding1: [u8; 0x03c - 0x018], // +0x018
+    pause_filter_threshold: u16, // +0x03c
+    pause_filter_count: u16,  // +0x03e
+    iopm_base_pa: u64,        // +0x040
+    msrpm_base_pa: u64,       // +0x048
+    tsc_offset: u64,          // +0x050
+    guest_asid: u32,          // +0x058
+    tlb_control: u32,         // +0x05c
+    vintr: u64,               // +0x060
+    interrupt_shadow: u64,    // +0x068
+    exit_code: u64,           // +0x070
+    exit_info1: u64,          // +0x078
+    exit_info2: u64,          // +0x080
+    exit_int_info: u64,       // +0x088
+    np_enable: u64,           // +0x090
+    avic_apic_bar: u64,       // +0x098
+    guest_pa_pf_ghcb: u64,    // +0x0a0
+    event_inj: u64,           // +0x0a8
+    ncr3: u64,                // +0x0b0
+    lbr_virtualization_enable: u64, // +0x0b8
+    vmcb_clean: u32,          // +0x0c0
+    _reserved: u32,           // +0x0c4
+    nrip: u64,                // +0x0c8
+    num_of_bytes_fetched: u8, // +0x0d0
+    guest_instruction_bytes: [u8; 15], // +0x0d1
+    avic_apic_backing_page_pointer: u64, // +0x0e0
+    #[derivative(Debug = "ignore")]
+    _padding2: u64, // +0x0e8
+    avic_logical_table_pointer: u64, // +0x0f0
+    avic_physical_table_pointer: u64, // +0x0f8
+    #[derivative(Debug = "ignore")]
+    _padding3: u64, // +0x100
+    vmcb_save_state_pointer: u64, // +0x108
+    #[derivative(Debug = "ignore", Default(value = "[0; 720]"))]
+    _padding4: [u8; 0x3e0 - 0x110], // +0x110
+    reserved_for_host: [u8; 0x20], // +0x3e0
+}
+const _: () = assert!(core::mem::size_of::<ControlArea>() == 0x400);
+
+/// The ares to specify and read guest register values.
+///
+/// See: Table B-2. VMCB Layout, State Save Area
+#[derive(derivative::Derivative)]
+#[derivative(Debug, Default)]
+#[repr(C)]
+struct StateSaveArea {
+    es_selector: u16,   // +0x000
+    es_attrib: u16,     // +0x002
+    es_limit: u32,      // +0x004
+    es_base: u64,       // +0x008
+    cs_selector: u16,   // +0x010
+    cs_attrib: u16,     // +0x012
+    cs_limit: u32,      // +0x014
+    cs_base: u64,       // +0x018
+    ss_selector: u16,   // +0x020
+    ss_attrib: u16,     // +0x022
+    ss_limit: u32,      // +0x024
+    ss_base: u64,       // +0x028
+    ds_selector: u16,   // +0x030
+    ds_attrib: u16,     // +0x032
+    ds_limit: u32,      // +0x034
+    ds_base: u64,       // +0x038
+    fs_selector: u16,   // +0x040
+    fs_attrib: u16,     // +0x042
+    fs_limit: u32,      // +0x044
+    fs_base: u64,       // +0x048
+    gs_selector: u16,   // +0x050
+    gs_attrib: u16,     // +0x052
+    gs_limit: u32,      // +0x054
+    gs_base: u64,       // +0x058
+    gdtr_selector: u16, // +0x060 (Reserved)
+    gdtr_attrib: u16,   // +0x062 (Reserved)
+    gdtr_limit: u32,    // +0x064
+    gdtr_base: u64,     // +0x068
+    ldtr_selector: u16, // +0x070 (Reserved)
+    ldtr_attrib: u16,   // +0x072 (Reserved)
+    ldtr_limit: u32,    // +0x074
+    ldtr_base: u64,     // +0x078
+    idtr_selector: u16, // +0x080
+    idtr_attrib: u16,   // +0x082
+    idtr_limit: u32,    // +0x084
+    idtr_base: u64,     // +0x088
+    tr_selector: u16,   // +0x090
+    tr_attrib: u16,     // +0x092
+    tr_limit: u32,      // +0x094
+    tr_base: u64,       // +0x098
+    #[derivative(Debug = "ignore", Default(value = "[0; 43]"))]
+    _padding1: [u8; 0x0cb - 0x0a0], // +0x0a0
+    cpl: u8,            // +0x0cb
+    #[derivative(Debug = "ignore")]
+    _padding2: u32, // +0x0cc
+    efer: u64,          // +0x0d0
+    #[derivative(Debug = "ignore", Default(value = "[0; 112]"))]
+    _padding3: [u8; 0x148 - 0x0d8], // +0x0d8
+    cr4: u64,           // +0x148
+    cr3: u64,           // +0x150
+    cr0: u64,           // +0x158
+    dr7: u64,           // +0x160
+    dr6: u64,           // +0x168
+    rflags: u64,        // +0x170
+    rip: u64,           // +0x178
+    #[derivative(Debug = "ignore", Default(value = "[0; 88]"))]
+    _padding4: [u8; 0x1d8 - 0x180], // +0x180
+    rsp: u64,           // +0x1d8
+    s_cet: u64,         // +0x1e0
+    ssp: u64,           // +0x1e8
+    isst_addr: u64,     // +0x1f0
+    rax: u64,           // +0x1f8
+    star: u64,          // +0x200
+    lstar: u64,         // +0x208
+    cstar: u64,         // +0x210
+    sf_mask: u64,       // +0x218
+    kernel_gs_base: u64, // +0x220
+    sysenter_cs: u64,   // +0x228
+    sysenter_esp: u64,  // +0x230
+    sysenter_eip: u64,  // +0x238
+    cr2: u64,           // +0x240
+    #[derivative(Debug = "ignore", Default(value = "[0; 32]"))]
+    _padding5: [u8; 0x268 - 0x248], // +0x248
+    gpat: u64,          // +0x268
+    dbg_ctl: u64,       // +0x270
+    br_from: u64,       // +0x278
+    br_to: u64,         // +0x280
+    last_excep_from: u64, // +0x288
+    last_excep_to: u64, // +0x290
+    #[derivative(Debug = "ignore", Default(value = "[0; 72]"))]
+    _padding6: [u8; 0x2e0 - 0x298], // +0x298
+    spec_ctl: u64,      // +0x2e0
+}
+const _: () = assert!(core::mem::size_of::<StateSaveArea>() == 0x2e8);
+
+const VMCB_AREAS_SIZE: usize =
+    core::mem::size_of::<ControlArea>() + core::mem::size_of::<StateSaveArea>();
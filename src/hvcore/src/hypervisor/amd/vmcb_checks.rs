@@ -0,0 +1,274 @@
+//! This module implements a software checker of the consistency checks VMRUN
+//! performs on the VMCB. VMRUN failing any of them causes #VMEXIT with
+//! VMEXIT_INVALID and nothing else, so the checks are repeated here to tell
+//! which one fails. In debug builds, the VMCB is checked before every VMRUN;
+//! otherwise, only after VMEXIT_INVALID.
+//!
+//! The checks follow "Canonicalization and Consistency Checks" in 15.5.1 Basic
+//! Operation, except those for SEV-ES. VMRUN does not check segment attributes
+//! other than the L and D bits of CS, which are checked along with EFER, CR0 and
+//! CR4.
+
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+
+use super::vmcb::Vmcb;
+use crate::hypervisor::host::SegmentRegister;
+
+/// A consistency check the VMCB violates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Violation {
+    /// The name of the field checked.
+    field: &'static str,
+
+    /// The value of the field.
+    value: u64,
+
+    /// The rule violated.
+    rule: &'static str,
+}
+
+impl core::fmt::Display for Violation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} ({:#x}): {}", self.field, self.value, self.rule)
+    }
+}
+
+/// Returns the first consistency check `vmcb` violates, if any.
+pub(super) fn check(vmcb: &Vmcb) -> Option<Violation> {
+    check_state(&VmcbState::read(vmcb))
+}
+
+/// The fields of the VMCB the checks depend on, along with the capabilities of
+/// the processor.
+#[derive(Clone, Copy, Debug, Default)]
+struct VmcbState {
+    physical_address_width: usize,
+    long_mode_supported: bool,
+
+    efer: u64,
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    dr6: u64,
+    dr7: u64,
+    cs_attributes: u16,
+    intercept_misc2: u32,
+    iopm_base_pa: u64,
+    msrpm_base_pa: u64,
+    event_inj: u64,
+    guest_asid: u32,
+}
+
+impl VmcbState {
+    fn read(vmcb: &Vmcb) -> Self {
+        // See: Table E-4. Extended Feature Identifiers
+        const LONG_MODE_BIT: usize = 29;
+
+        Self {
+            physical_address_width: cpuid!(0x8000_0008).eax.get_bits(0..=7) as usize,
+            long_mode_supported: cpuid!(0x8000_0001).edx.get_bit(LONG_MODE_BIT),
+            efer: vmcb.efer(),
+            cr0: vmcb.cr0(),
+            cr3: vmcb.cr3(),
+            cr4: vmcb.cr4(),
+            dr6: vmcb.dr6(),
+            dr7: vmcb.dr7(),
+            cs_attributes: vmcb.segment(SegmentRegister::Cs).attributes,
+            intercept_misc2: vmcb.intercept_misc2(),
+            iopm_base_pa: vmcb.iopm_base_pa(),
+            msrpm_base_pa: vmcb.msrpm_base_pa(),
+            event_inj: vmcb.event_inj(),
+            guest_asid: vmcb.guest_asid(),
+        }
+    }
+}
+
+/// Returns the first check `state` violates, if any.
+fn check_state(state: &VmcbState) -> Option<Violation> {
+    const EFER_LME: u64 = 1 << 8;
+    const EFER_LMA: u64 = 1 << 10;
+    const EFER_SVME: u64 = 1 << 12;
+    // See: 3.1.7 Extended Feature Enable Register (EFER)
+    const EFER_MBZ: u64 = 0xffff_ffff_ffc0_0000 | 1 << 19 | 1 << 16 | 1 << 9 | 0xfe;
+    const CR0_PE: u64 = 1 << 0;
+    const CR0_NW: u64 = 1 << 29;
+    const CR0_CD: u64 = 1 << 30;
+    const CR0_PG: u64 = 1 << 31;
+    const CR4_PAE: u64 = 1 << 5;
+    // See: 3.1.3 CR4 Register
+    const CR4_MBZ: u64 = !0x00f7_1fff;
+    const CS_L: u16 = 1 << 9;
+    const CS_D: u16 = 1 << 10;
+    const SVM_INTERCEPT_MISC2_VMRUN: u32 = 1 << 0;
+    const IOPM_SIZE: u64 = 0x3000;
+    const MSRPM_SIZE: u64 = 0x2000;
+    const PAGE_MASK: u64 = 0xfff;
+
+    let violation = |field, value, rule| Some(Violation { field, value, rule });
+    let (efer, cr0, cr4) = (state.efer, state.cr0, state.cr4);
+    let long_mode_paging = efer & EFER_LME != 0 && cr0 & CR0_PG != 0;
+    let max_pa = 1u64 << state.physical_address_width;
+
+    if efer & EFER_SVME == 0 {
+        return violation("EFER", efer, "SVME must be 1");
+    }
+    if cr0 & CR0_CD == 0 && cr0 & CR0_NW != 0 {
+        return violation("CR0", cr0, "NW must be 0 if CD is 0");
+    }
+    if cr0 >> 32 != 0 {
+        return violation("CR0", cr0, "bits 63:32 must be 0");
+    }
+    let cr3_width = if long_mode_paging {
+        state.physical_address_width
+    } else {
+        32
+    };
+    if state.cr3 >> cr3_width != 0 {
+        return violation("CR3", state.cr3, "MBZ bits must be 0");
+    }
+    if cr4 & CR4_MBZ != 0 {
+        return violation("CR4", cr4, "MBZ bits must be 0");
+    }
+    if state.dr6 >> 32 != 0 {
+        return violation("DR6", state.dr6, "bits 63:32 must be 0");
+    }
+    if state.dr7 >> 32 != 0 {
+        return violation("DR7", state.dr7, "bits 63:32 must be 0");
+    }
+    if efer & EFER_MBZ != 0 {
+        return violation("EFER", efer, "MBZ bits must be 0");
+    }
+    if efer & (EFER_LME | EFER_LMA) != 0 && !state.long_mode_supported {
+        return violation(
+            "EFER",
+            efer,
+            "LME and LMA must be 0 without long mode support",
+        );
+    }
+    if long_mode_paging && cr4 & CR4_PAE == 0 {
+        return violation("CR4", cr4, "PAE must be 1 if EFER.LME and CR0.PG are 1");
+    }
+    if long_mode_paging && cr0 & CR0_PE == 0 {
+        return violation("CR0", cr0, "PE must be 1 if EFER.LME and CR0.PG are 1");
+    }
+    if long_mode_paging && cr4 & CR4_PAE != 0 && state.cs_attributes & (CS_L | CS_D) == CS_L | CS_D
+    {
+        return violation(
+            "CS attributes",
+            state.cs_attributes.into(),
+            "L and D must not both be 1 in long mode",
+        );
+    }
+    if state.intercept_misc2 & SVM_INTERCEPT_MISC2_VMRUN == 0 {
+        return violation(
+            "Intercept vector 4",
+            state.intercept_misc2.into(),
+            "VMRUN must be intercepted",
+        );
+    }
+    if (state.msrpm_base_pa & !PAGE_MASK) + MSRPM_SIZE > max_pa {
+        return violation(
+            "MSRPM_BASE_PA",
+            state.msrpm_base_pa,
+            "the MSR permissions map must be within the physical address width",
+        );
+    }
+    if (state.iopm_base_pa & !PAGE_MASK) + IOPM_SIZE > max_pa {
+        return violation(
+            "IOPM_BASE_PA",
+            state.iopm_base_pa,
+            "the I/O permissions map must be within the physical address width",
+        );
+    }
+    if let Some(rule) = check_event_injection(state.event_inj) {
+        return violation("EVENTINJ", state.event_inj, rule);
+    }
+    if state.guest_asid == 0 {
+        return violation("ASID", 0, "must not be 0");
+    }
+    None
+}
+
+/// Returns the rule `event_inj` violates, if any.
+// See: 15.20 Event Injection
+fn check_event_injection(event_inj: u64) -> Option<&'static str> {
+    const EXCEPTION: u64 = 3;
+
+    if !event_inj.get_bit(31) {
+        return None;
+    }
+    let vector = event_inj.get_bits(0..=7);
+    match event_inj.get_bits(8..=10) {
+        0 | 2 | 4 => None,
+        EXCEPTION if vector != 2 && vector < 32 => None,
+        EXCEPTION => Some("the vector must be an exception for TYPE 3"),
+        _ => Some("TYPE must not be reserved"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the state of a 64-bit guest.
+    fn valid_state() -> VmcbState {
+        VmcbState {
+            physical_address_width: 48,
+            long_mode_supported: true,
+            efer: 0x1d01,
+            cr0: 0x8005_0033,
+            cr3: 0x1a_d000,
+            cr4: 0x3506f8,
+            dr6: 0xffff_0ff0,
+            dr7: 0x400,
+            cs_attributes: 0x29b,
+            intercept_misc2: 0x207f,
+            iopm_base_pa: 0x1234_5000,
+            msrpm_base_pa: 0x1234_8000,
+            event_inj: 0,
+            guest_asid: 1,
+        }
+    }
+
+    #[test]
+    fn valid_state_passes() {
+        assert_eq!(check_state(&valid_state()), None);
+    }
+
+    #[test]
+    fn first_violation_is_reported() {
+        let mut state = valid_state();
+        state.efer &= !(1 << 12);
+        state.guest_asid = 0;
+        assert_eq!(check_state(&state).unwrap().rule, "SVME must be 1");
+
+        let mut state = valid_state();
+        state.cs_attributes |= 1 << 10;
+        assert_eq!(
+            check_state(&state).unwrap().rule,
+            "L and D must not both be 1 in long mode"
+        );
+
+        let mut state = valid_state();
+        state.msrpm_base_pa = 0xffff_ffff_f000;
+        assert_eq!(check_state(&state).unwrap().field, "MSRPM_BASE_PA");
+    }
+
+    #[test]
+    fn event_injection_is_checked() {
+        // #GP with an error code, NMI, and an external interrupt.
+        assert_eq!(check_event_injection(0x0000_0000_8000_0b0d), None);
+        assert_eq!(check_event_injection(0x8000_0202), None);
+        assert_eq!(check_event_injection(0x8000_00f0), None);
+        assert_eq!(
+            check_event_injection(0x8000_0302),
+            Some("the vector must be an exception for TYPE 3")
+        );
+        assert_eq!(
+            check_event_injection(0x8000_0500),
+            Some("TYPE must not be reserved")
+        );
+        assert_eq!(check_event_injection(0x0000_0500), None);
+    }
+}
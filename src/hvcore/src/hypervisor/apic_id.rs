@@ -1,33 +1,124 @@
 use core::sync::atomic::{AtomicUsize, Ordering};
 
-use alloc::collections::BTreeMap;
-use spin::RwLock;
+use alloc::{boxed::Box, collections::BTreeMap};
+use spin::{Once, RwLock};
 
 use crate::hypervisor::platform_ops;
 
-type ApicId = u8;
-type ProcessorId = usize;
+/// The x2APIC ID, or the xAPIC ID zero-extended where x2APIC IDs are not
+/// enumerated.
+pub(crate) type ApicId = u32;
+pub(crate) type ProcessorId = usize;
 pub(crate) static APIC_ID_MAP: RwLock<BTreeMap<ApicId, ProcessorId>> = RwLock::new(BTreeMap::new());
 pub(crate) static PROCESSOR_COUNT: AtomicUsize = AtomicUsize::new(0);
 
+/// The number of processor IDs that can be assigned: the processors enumerated
+/// by `init` plus the slots reserved for processors brought online later.
+static PROCESSOR_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+
 /// Gets an APIC ID.
+///
+/// The 8-bit initial APIC ID aliases on systems with more than 255 logical
+/// processors, which run in x2APIC mode. Use the 32-bit x2APIC ID where the
+/// processor enumerates it, regardless of the current APIC mode. It is equal
+/// to the xAPIC ID in xAPIC mode, and to the value of the x2APIC ID register
+/// (MSR 0x802) in x2APIC mode.
 pub(crate) fn get() -> ApicId {
+    // "Software must detect the presence of CPUID leaf 0BH by verifying (a)
+    //  the highest leaf index supported by CPUID is >= 0BH, and (b)
+    //  CPUID.0BH:EBX[15:0] reports a non-zero value."
+    // See: (Intel) 10.12.8.1 Consistency of APIC IDs and CPUID
+    // See: (AMD) CPUID Fn0000_000B_EDX Extended Topology Enumeration
+    if x86::cpuid::cpuid!(0x0).eax >= 0xb {
+        let topology = x86::cpuid::cpuid!(0xb);
+        if topology.ebx & 0xffff != 0 {
+            return topology.edx;
+        }
+    }
+
     // See: (AMD) CPUID Fn0000_0001_EBX LocalApicId, LogicalProcessorCount, CLFlush
     // See: (Intel) Table 3-8. Information Returned by CPUID Instruction
-    (x86::cpuid::cpuid!(0x1).ebx >> 24) as _
+    x86::cpuid::cpuid!(0x1).ebx >> 24
 }
 
-pub(crate) fn init() {
-    assert!(PROCESSOR_COUNT.load(Ordering::Relaxed) == 0);
+/// Enumerates the processors, and reserves `hotplug_slots` more processor IDs
+/// for `register_current`.
+pub(crate) fn init(hotplug_slots: usize) {
+    // The map is already built if the system was virtualized before.
+    if PROCESSOR_COUNT.load(Ordering::Relaxed) != 0 {
+        return;
+    }
     platform_ops::get().run_on_all_processors(|| {
         let mut map = APIC_ID_MAP.write();
         assert!(map
             .insert(get(), PROCESSOR_COUNT.fetch_add(1, Ordering::Relaxed))
             .is_none());
     });
+    PROCESSOR_CAPACITY.store(
+        PROCESSOR_COUNT.load(Ordering::Relaxed) + hotplug_slots,
+        Ordering::Relaxed,
+    );
+}
+
+/// Returns the ID of the current processor, assigning a reserved one if the
+/// processor was not enumerated by `init`. `None` if no reserved ID is left.
+pub(crate) fn register_current() -> Option<ProcessorId> {
+    let apic_id = get();
+    let mut map = APIC_ID_MAP.write();
+    if let Some(&id) = map.get(&apic_id) {
+        return Some(id);
+    }
+    let id = PROCESSOR_COUNT.load(Ordering::Relaxed);
+    if id >= capacity() {
+        return None;
+    }
+    let _ = map.insert(apic_id, id);
+    PROCESSOR_COUNT.store(id + 1, Ordering::Relaxed);
+    Some(id)
+}
+
+/// Returns the number of processor IDs that can be assigned, that is, the
+/// upper bound of `PROCESSOR_COUNT`.
+pub(crate) fn capacity() -> usize {
+    PROCESSOR_CAPACITY.load(Ordering::Relaxed)
 }
 
 pub(crate) fn processor_id_from(apic_id: ApicId) -> Option<ProcessorId> {
     let map = APIC_ID_MAP.read();
     map.get(&apic_id).copied()
 }
+
+/// Values of `T` for each processor, indexed by the processor ID.
+///
+/// The values are allocated for all processor IDs `init` can assign on first
+/// use, rather than for a fixed maximum number of processors.
+pub(crate) struct PerProcessor<T> {
+    values: Once<Box<[T]>>,
+}
+
+impl<T: Default> PerProcessor<T> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            values: Once::new(),
+        }
+    }
+
+    /// Returns the value for the processor `id`, allocating the values on first
+    /// use. `None` if `id` is not enumerated. Must not be called before `init`.
+    pub(crate) fn get(&self, id: ProcessorId) -> Option<&T> {
+        self.values
+            .call_once(|| {
+                let count = capacity();
+                assert!(count != 0, "processors are not enumerated yet");
+                (0..count).map(|_| T::default()).collect()
+            })
+            .get(id)
+    }
+
+    /// Returns the value for the processor `id` without allocating, for
+    /// contexts where allocation is not possible. `None` if the values are not
+    /// allocated yet or `id` is not enumerated.
+    pub(crate) fn get_allocated(&self, id: ProcessorId) -> Option<&T> {
+        self.values.get()?.get(id)
+    }
+}
@@ -0,0 +1,305 @@
+//! This module implements trapping of writes the guest makes to the registers
+//! of the local APIC. This lets the embedder of this crate observe or filter
+//! EOIs, IPIs and the configuration of interrupts, such as the LVT and the
+//! timer.
+//!
+//! Enabled with `SharedHostData::apic_virt`, writes to the x2APIC MSRs are
+//! intercepted with MSR interception, and the page of the xAPIC registers, at
+//! IA32_APIC_BASE when all processors are virtualized, is write-protected with
+//! `memory_protection`. Writes to the page are emulated with
+//! `instruction_decoder`. Each write is reported to the observer, which
+//! decides whether it reaches the local APIC. Reads are not intercepted.
+//!
+//! The handlers installed for the x2APIC MSRs replace those in
+//! `SharedHostData::msr_intercepts`, if any.
+//!
+//! ```ignore
+//! let apic_virt = ApicVirtualization::new(|_vcpu: &mut dyn Vcpu, write: &ApicWrite| {
+//!     if let ApicWrite::Ipi(ipi) = write {
+//!         log::info!("IPI {:#x} to {:#x}", ipi.vector, ipi.destination);
+//!     }
+//!     ApicAction::Forward
+//! });
+//! ```
+
+use alloc::boxed::Box;
+use bit_field::BitField;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use crate::hypervisor::{
+    host::{NestedPageFaultInfo, Vcpu},
+    host_window,
+    instruction_decoder::{self, InstructionError, MemoryAccess},
+    memory_protection::{self, Permissions, ViolationAction},
+    msr_intercepts::MsrIntercepts,
+    x86_instructions::rdmsr,
+    SHARED_HOST_DATA,
+};
+
+/// The offsets of the registers in the xAPIC page. The x2APIC MSR of each is
+/// 0x800 plus the offset divided by 16.
+/// See: Table 11-1. Local APIC Register Address Map
+const EOI: u32 = 0xb0;
+const ICR_LOW: u32 = 0x300;
+const ICR_HIGH: u32 = 0x310;
+const SELF_IPI: u32 = 0x3f0;
+
+/// The x2APIC MSRs the guest can write to.
+/// See: Table 11-6. Local APIC Register Address Map Supported by x2APIC
+const X2APIC_WRITABLE_MSRS: [u32; 15] = [
+    0x808, 0x80b, 0x80f, 0x828, 0x82f, 0x830, 0x832, 0x833, 0x834, 0x835, 0x836, 0x837, 0x838,
+    0x83e, 0x83f,
+];
+
+/// An interprocessor interrupt the guest sends.
+/// See: 11.6.1 Interrupt Command Register (ICR)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipi {
+    /// The vector of the interrupt.
+    pub vector: u8,
+
+    /// The delivery mode, for example, 0 for fixed, 4 for NMI, 5 for INIT and
+    /// 6 for Startup IPI.
+    pub delivery_mode: u8,
+
+    /// Whether `destination` is a logical destination.
+    pub logical: bool,
+
+    /// The destination shorthand: 0 for none, 1 for self, 2 for all including
+    /// self, and 3 for all excluding self.
+    pub shorthand: u8,
+
+    /// The APIC ID or the logical destination of the target processors.
+    pub destination: u32,
+}
+
+/// A write to the local APIC by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApicWrite {
+    /// A write to the EOI register.
+    Eoi,
+
+    /// A write sending an IPI, that is, to the low half of the ICR in xAPIC
+    /// mode, to the ICR in x2APIC mode, or to the SELF IPI register.
+    Ipi(Ipi),
+
+    /// A write to any other register at `offset` in the xAPIC page.
+    Register { offset: u32, value: u64 },
+}
+
+impl ApicWrite {
+    /// Classifies the write of `value` to the register at `offset`. `value`
+    /// of the ICR includes the destination in the high 32 bits.
+    fn new(offset: u32, value: u64, x2apic: bool) -> Self {
+        match offset {
+            EOI => Self::Eoi,
+            ICR_LOW => Self::Ipi(Ipi {
+                vector: value.get_bits(0..=7) as u8,
+                delivery_mode: value.get_bits(8..=10) as u8,
+                logical: value.get_bit(11),
+                shorthand: value.get_bits(18..=19) as u8,
+                destination: if x2apic {
+                    value.get_bits(32..=63) as u32
+                } else {
+                    value.get_bits(56..=63) as u32
+                },
+            }),
+            SELF_IPI if x2apic => Self::Ipi(Ipi {
+                vector: value.get_bits(0..=7) as u8,
+                delivery_mode: 0,
+                logical: false,
+                shorthand: 0b01,
+                destination: 0,
+            }),
+            _ => Self::Register { offset, value },
+        }
+    }
+}
+
+/// Whether a write reaches the local APIC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApicAction {
+    /// Let the local APIC take the write.
+    Forward,
+
+    /// Discard the write. The guest resumes after the instruction.
+    Discard,
+}
+
+/// Represents an observer of writes to the local APIC by the guest.
+///
+/// Observers run in the host context with interrupts disabled. They must not
+/// call any platform API and should return as soon as possible.
+pub trait ApicObserver: Send + Sync {
+    /// Handles the write described by `write` on `vcpu`. The write takes
+    /// effect after this returns, unless discarded.
+    fn on_write(&self, vcpu: &mut dyn Vcpu, write: &ApicWrite) -> ApicAction;
+}
+
+impl<F> ApicObserver for F
+where
+    F: Fn(&mut dyn Vcpu, &ApicWrite) -> ApicAction + Send + Sync,
+{
+    fn on_write(&self, vcpu: &mut dyn Vcpu, write: &ApicWrite) -> ApicAction {
+        self(vcpu, write)
+    }
+}
+
+/// The configuration of trapping of local APIC writes. Disabled by default.
+#[derive(Default)]
+pub struct ApicVirtualization {
+    observer: Option<Box<dyn ApicObserver>>,
+}
+
+impl ApicVirtualization {
+    /// Enables trapping of local APIC writes with `observer`.
+    pub fn new(observer: impl ApicObserver + 'static) -> Self {
+        Self {
+            observer: Some(Box::new(observer)),
+        }
+    }
+
+    /// Returns whether trapping is enabled.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.observer.is_some()
+    }
+
+    fn report(&self, vcpu: &mut dyn Vcpu, write: &ApicWrite) -> ApicAction {
+        self.observer
+            .as_ref()
+            .map_or(ApicAction::Forward, |observer| {
+                observer.on_write(vcpu, write)
+            })
+    }
+}
+
+impl core::fmt::Debug for ApicVirtualization {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ApicVirtualization")
+            .field("enabled", &self.is_enabled())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns `intercepts` with the handlers for the x2APIC MSRs. Called from the
+/// guest before any processor is virtualized.
+pub(crate) fn install(intercepts: MsrIntercepts) -> MsrIntercepts {
+    X2APIC_WRITABLE_MSRS
+        .into_iter()
+        .fold(intercepts, |intercepts, msr| {
+            intercepts.on_write(msr, handle_msr_write)
+        })
+}
+
+/// Write-protects the page of the xAPIC registers if the local APIC is in
+/// xAPIC mode. Called from the guest after all processors are virtualized, as
+/// the host may emulate Startup IPIs with the same page until then.
+pub(crate) fn protect_xapic_page() {
+    // See: 11.4.4 Local APIC Status and Location
+    let apic_base = rdmsr(x86::msr::IA32_APIC_BASE);
+    if !apic_base.get_bit(11) || apic_base.get_bit(10) {
+        return;
+    }
+
+    let page = apic_base & !(BASE_PAGE_SIZE as u64 - 1) & ((1 << 52) - 1);
+    if let Err(err) = memory_protection::protect_gpa_range(
+        page,
+        BASE_PAGE_SIZE as u64,
+        Permissions::READ_EXECUTE,
+        handle_xapic_write,
+    ) {
+        log::warn!("Cannot trap writes to the xAPIC page: {err}");
+    }
+}
+
+/// Handles `WRMSR` of an x2APIC MSR.
+fn handle_msr_write(vcpu: &mut dyn Vcpu, msr: u32, value: u64) -> Option<u64> {
+    let write = ApicWrite::new((msr - 0x800) << 4, value, true);
+    let apic_virt = &SHARED_HOST_DATA.get().unwrap().apic_virt;
+    (apic_virt.report(vcpu, &write) == ApicAction::Forward).then_some(value)
+}
+
+/// Handles a write to the page of the xAPIC registers.
+fn handle_xapic_write(vcpu: &mut dyn Vcpu, info: &NestedPageFaultInfo) -> ViolationAction {
+    let result = emulate_xapic_write(vcpu, info);
+    memory_protection::complete(vcpu, result);
+    ViolationAction::Resume
+}
+
+/// Emulates the write to the xAPIC register at `info.gpa` by the instruction at
+/// the guest RIP, and advances RIP past it.
+fn emulate_xapic_write(
+    vcpu: &mut dyn Vcpu,
+    info: &NestedPageFaultInfo,
+) -> Result<(), InstructionError> {
+    // The registers are accessed with 32-bit loads and stores, at 16 byte
+    // aligned offsets.
+    // See: 11.4.1 The Local APIC Block Diagram
+    let instruction = instruction_decoder::fetch(vcpu)?;
+    let Some(MemoryAccess::Write { size: 4, value }) = instruction.access(vcpu.regs()) else {
+        return Err(InstructionError::Unsupported);
+    };
+    let offset = (info.gpa & 0xff0) as u32;
+
+    let full_value = if offset == ICR_LOW {
+        u64::from(unsafe { register(vcpu, info.gpa, ICR_HIGH).read_volatile() }) << 32 | value
+    } else {
+        value
+    };
+
+    let write = ApicWrite::new(offset, full_value, false);
+    let apic_virt = &SHARED_HOST_DATA.get().unwrap().apic_virt;
+    if apic_virt.report(vcpu, &write) == ApicAction::Forward {
+        unsafe { register(vcpu, info.gpa, offset).write_volatile(value as u32) };
+    }
+    instruction_decoder::advance(vcpu, &instruction);
+    Ok(())
+}
+
+/// Returns the xAPIC register at `offset` in the page of `gpa`, mapped with
+/// the host window. Valid until the window is used again on the processor.
+fn register(vcpu: &dyn Vcpu, gpa: u64, offset: u32) -> *mut u32 {
+    // The GPA is the PA as the nested paging structures are identity mapped.
+    let page = host_window::map_uncacheable(vcpu.id(), gpa & !(BASE_PAGE_SIZE as u64 - 1));
+    unsafe { page.add(offset as usize).cast() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_are_classified() {
+        assert_eq!(ApicWrite::new(EOI, 0, false), ApicWrite::Eoi);
+
+        // A fixed IPI with the vector 0xd1 to the APIC ID 3 in xAPIC mode.
+        let ipi = Ipi {
+            vector: 0xd1,
+            delivery_mode: 0,
+            logical: false,
+            shorthand: 0,
+            destination: 3,
+        };
+        assert_eq!(
+            ApicWrite::new(ICR_LOW, (3 << 56) | 0xd1, false),
+            ApicWrite::Ipi(ipi)
+        );
+        assert_eq!(
+            ApicWrite::new(ICR_LOW, (3 << 32) | 0xd1, true),
+            ApicWrite::Ipi(ipi)
+        );
+
+        // SELF IPI is an x2APIC only register.
+        assert!(matches!(
+            ApicWrite::new(SELF_IPI, 0x41, true),
+            ApicWrite::Ipi(Ipi { shorthand: 1, .. })
+        ));
+        assert_eq!(
+            ApicWrite::new(SELF_IPI, 0x41, false),
+            ApicWrite::Register {
+                offset: SELF_IPI,
+                value: 0x41
+            }
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! This module implements markers, `INT3` instructions an introspection tool
+//! places in the guest to be notified when the guest reaches them. Registered
+//! with [`add_marker`], they are handled by the hypervisor and never seen by
+//! the exception handlers of the guest.
+//!
+//! While any marker is registered, #BP causes VM-exit. #BP at a marker calls
+//! its handler, which decides where the guest resumes: after the `INT3` as if
+//! it were a `NOP`, or wherever the handler set RIP to, for example, at the
+//! marker after restoring the original byte. #BP elsewhere is delivered to the
+//! guest as the processor would have. As with the hooks, each processor picks
+//! up changes on the next VM-exit by comparing the generation of the markers
+//! with the one it last applied.
+//!
+//! Placing and removing the `INT3` instructions themselves is up to the tool,
+//! for example, with `guest_memory` or an `ept_hook` shadow page. Register the
+//! marker before placing the instruction, and remove the instruction before
+//! the marker.
+//!
+//! ```ignore
+//! add_marker(function_gva, |vcpu, gva| {
+//!     log::info!("Reached {gva:#x?} with RCX {:#x?}", vcpu.regs().rcx);
+//!     MarkerAction::Skip
+//! })?;
+//! ```
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use spin::Mutex;
+
+use crate::hypervisor::{
+    event::{self, Event},
+    host::{InstructionInfo, Vcpu},
+};
+
+/// Where the guest resumes after the handler of a marker returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarkerAction {
+    /// Resume after the `INT3`, as if it were a `NOP`.
+    Skip,
+
+    /// Resume at RIP as the handler left it, which is initially the address
+    /// of the marker.
+    Resume,
+}
+
+/// Represents a handler called when the guest reaches a marker, with the
+/// address of the marker. Handlers run in the host context with interrupts
+/// disabled. They must not call any platform API and should return as soon as
+/// possible.
+pub type MarkerHandler = dyn Fn(&mut dyn Vcpu, u64) -> MarkerAction + Send + Sync;
+
+/// The errors registration of markers may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarkerError {
+    #[error("a marker is already registered at {gva:#x?}")]
+    AlreadyRegistered { gva: u64 },
+
+    #[error("no marker is registered at {gva:#x?}")]
+    NotRegistered { gva: u64 },
+}
+
+/// Registers the marker at the guest linear address `gva` and calls `handler`
+/// when the guest executes `INT3` there. Changes take effect on each processor
+/// on the next VM-exit on that processor.
+///
+/// # Errors
+///
+/// Returns `Err` if a marker is already registered at `gva`.
+pub fn add_marker(
+    gva: u64,
+    handler: impl Fn(&mut dyn Vcpu, u64) -> MarkerAction + Send + Sync + 'static,
+) -> Result<(), MarkerError> {
+    let mut markers = MARKERS.lock();
+    if markers.contains_key(&gva) {
+        return Err(MarkerError::AlreadyRegistered { gva });
+    }
+    let _ = markers.insert(gva, Arc::new(handler));
+
+    log::debug!("Registered the marker at {gva:#x?}");
+    let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+    Ok(())
+}
+
+/// Unregisters the marker at `gva`. #BP at `gva` is delivered to the guest
+/// afterwards.
+///
+/// # Errors
+///
+/// Returns `Err` if no marker is registered at `gva`.
+pub fn remove_marker(gva: u64) -> Result<(), MarkerError> {
+    if MARKERS.lock().remove(&gva).is_none() {
+        return Err(MarkerError::NotRegistered { gva });
+    }
+
+    log::debug!("Unregistered the marker at {gva:#x?}");
+    let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+    Ok(())
+}
+
+/// Returns whether #BP should be intercepted if the markers changed since
+/// `applied_generation`, updating it. Returns `None` if they did not, or they
+/// cannot be read now.
+pub(crate) fn sync(applied_generation: &mut u64) -> Option<bool> {
+    let generation = GENERATION.load(Ordering::Acquire);
+    if *applied_generation == generation {
+        return None;
+    }
+
+    // Retry on the next VM-exit if the guest on any processor owns the lock.
+    let markers = MARKERS.try_lock()?;
+    *applied_generation = generation;
+    Some(!markers.is_empty())
+}
+
+/// Handles #BP of `INT3` at the guest RIP, described by `info`.
+pub(crate) fn handle_breakpoint(vcpu: &mut dyn Vcpu, info: &InstructionInfo) {
+    const BP_VECTOR: u8 = 3;
+
+    // Let the guest execute `INT3` again if the guest on any processor owns
+    // the lock, as it may be registering the marker.
+    let gva = vcpu.regs().rip;
+    let Some(markers) = MARKERS.try_lock() else {
+        return;
+    };
+    let handler = markers.get(&gva).cloned();
+    drop(markers);
+
+    match handler.map(|handler| handler(vcpu, gva)) {
+        Some(MarkerAction::Skip) => vcpu.regs().rip = info.next_rip,
+        Some(MarkerAction::Resume) => {}
+        None => {
+            // #BP is a trap. The guest receives it with RIP after `INT3`.
+            // See: 6.5 EXCEPTION CLASSIFICATIONS
+            vcpu.regs().rip = info.next_rip;
+            let bp = Event::Exception {
+                vector: BP_VECTOR,
+                error_code: None,
+            };
+            if let Err(err) = event::inject_event(vcpu, bp) {
+                log::error!("Could not inject #BP: {err}");
+            }
+        }
+    }
+}
+
+/// Returns whether the markers are locked by any processor. See
+/// `host::fail_open`.
+pub(crate) fn is_locked() -> bool {
+    MARKERS.is_locked()
+}
+
+/// Incremented whenever a marker is registered or unregistered.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The markers keyed by their addresses.
+static MARKERS: Mutex<BTreeMap<u64, Arc<MarkerHandler>>> = Mutex::new(BTreeMap::new());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_reports_registration() {
+        let mut applied = GENERATION.load(Ordering::Acquire);
+        assert_eq!(sync(&mut applied), None);
+
+        add_marker(0x1000, |_, _| MarkerAction::Skip).unwrap();
+        assert_eq!(
+            add_marker(0x1000, |_, _| MarkerAction::Skip),
+            Err(MarkerError::AlreadyRegistered { gva: 0x1000 })
+        );
+        assert_eq!(sync(&mut applied), Some(true));
+
+        remove_marker(0x1000).unwrap();
+        assert_eq!(sync(&mut applied), Some(false));
+        assert_eq!(sync(&mut applied), None);
+    }
+}
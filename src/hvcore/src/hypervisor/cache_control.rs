@@ -0,0 +1,111 @@
+//! This module implements handling of `INVD` and `WBINVD` (and `WBNOINVD`)
+//! executed by the guest, which invalidate the caches of the processor.
+//!
+//! `INVD` discards modified lines without writing them back. The guest
+//! executes it only where it expects memory to be coherent with the caches,
+//! such as when firmware tears down cache-as-RAM. Under our hypervisor, the
+//! caches also hold the writes of the host, such as to the VMCS, the VMCB and
+//! the stacks, and discarding them corrupts the host. `INVD` always causes
+//! VM-exit on Intel processors, and is intercepted on AMD processors, unless
+//! the policy executes it as is.
+//!
+//! The handling is chosen with `SharedHostData::cache_invalidation`:
+//!
+//! - [`CacheInvalidationPolicy::Execute`]: executes both instructions as the
+//!   guest requested. `INVD` is executed by the host on Intel processors, and
+//!   by the guest without VM-exit on AMD processors.
+//! - [`CacheInvalidationPolicy::WriteBack`]: executes `WBINVD` instead of
+//!   `INVD`, which is what the guest observes when the caches hold no modified
+//!   lines. `WBINVD` is not intercepted.
+//! - [`CacheInvalidationPolicy::Ignore`]: intercepts both instructions and
+//!   completes them without touching the caches, for a guest that does not
+//!   depend on them, such as one whose memory is all write-back, to avoid
+//!   their latency. Not suitable for a guest that does non-coherent DMA or
+//!   changes memory types.
+//!
+//! See: 26.1.2 Instructions That Cause VM Exits Unconditionally
+//! See: 15.9 Instruction Intercepts
+
+use crate::hypervisor::{
+    host::{InstructionInfo, Vcpu},
+    x86_instructions::{invd, wbinvd},
+};
+
+/// How to handle `INVD` and `WBINVD`. See the module documentation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CacheInvalidationPolicy {
+    /// Executes `INVD` and `WBINVD` as is. `INVD` discards the writes of the
+    /// host not yet written back.
+    Execute,
+
+    /// Executes `WBINVD` in place of `INVD`.
+    #[default]
+    WriteBack,
+
+    /// Completes `INVD` and `WBINVD` without invalidating the caches.
+    Ignore,
+}
+
+impl CacheInvalidationPolicy {
+    /// Returns whether `INVD` needs to be intercepted where that is optional.
+    #[cfg(any(feature = "amd", test))]
+    pub(crate) fn intercepts_invd(self) -> bool {
+        self != Self::Execute
+    }
+
+    /// Returns whether `WBINVD` needs to be intercepted.
+    pub(crate) fn intercepts_wbinvd(self) -> bool {
+        self == Self::Ignore
+    }
+}
+
+/// Handles `INVD` executed by the guest on `vcpu` per `policy`.
+pub(crate) fn handle_invd(
+    vcpu: &mut dyn Vcpu,
+    info: &InstructionInfo,
+    policy: CacheInvalidationPolicy,
+) {
+    match policy {
+        CacheInvalidationPolicy::Execute => invd(),
+        CacheInvalidationPolicy::WriteBack => wbinvd(),
+        CacheInvalidationPolicy::Ignore => {}
+    }
+    vcpu.regs().rip = info.next_rip;
+}
+
+/// Handles `WBINVD` or `WBNOINVD` executed by the guest on `vcpu` per
+/// `policy`. `WBNOINVD` is executed as `WBINVD`, which differs only in
+/// performance.
+pub(crate) fn handle_wbinvd(
+    vcpu: &mut dyn Vcpu,
+    info: &InstructionInfo,
+    policy: CacheInvalidationPolicy,
+) {
+    if policy != CacheInvalidationPolicy::Ignore {
+        wbinvd();
+    }
+    vcpu.regs().rip = info.next_rip;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::{host::Guest, test_support::MockGuest};
+
+    #[test]
+    fn ignored_instructions_are_completed() {
+        let info = InstructionInfo { next_rip: 0x1002 };
+        let mut guest = MockGuest::new(0).unwrap();
+        handle_invd(&mut guest, &info, CacheInvalidationPolicy::Ignore);
+        assert_eq!(guest.regs.rip, info.next_rip);
+
+        guest.regs.rip = 0;
+        handle_wbinvd(&mut guest, &info, CacheInvalidationPolicy::Ignore);
+        assert_eq!(guest.regs.rip, info.next_rip);
+
+        // Only ignoring requires intercepting `WBINVD`.
+        assert!(CacheInvalidationPolicy::default().intercepts_invd());
+        assert!(!CacheInvalidationPolicy::default().intercepts_wbinvd());
+        assert!(!CacheInvalidationPolicy::Execute.intercepts_invd());
+    }
+}
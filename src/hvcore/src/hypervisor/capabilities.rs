@@ -0,0 +1,373 @@
+//! This module implements discovery of the optional features of the
+//! virtualization extension, for the embedder to decide which optional
+//! subsystems to enable before `virtualize_system`, for example, dirty tracking
+//! requires the EPT accessed and dirty flags. `virtualize_system` logs the
+//! capabilities of the processor too.
+//!
+//! ```ignore
+//! let capabilities = hv::capabilities::HvCapabilities::detect()?;
+//! shared_host.tsc.scale = capabilities.tsc_scaling.then_some(scale);
+//! ```
+//!
+//! The capabilities are those of the current processor, which are the same on
+//! all processors of the system in practice.
+
+use core::fmt;
+
+use alloc::{string::String, vec::Vec};
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+
+use crate::hypervisor::VirtError;
+
+/// The vendor of the processor, and thus, the virtualization extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Vendor {
+    /// Intel VT-x.
+    Intel,
+
+    /// AMD SVM.
+    Amd,
+}
+
+/// The optional features of the virtualization extension the processor
+/// supports. Features the vendor does not define are `false`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HvCapabilities {
+    pub vendor: Vendor,
+
+    /// The features of EPT (Intel) or NPT (AMD), or `None` if unsupported, in
+    /// which case the hypervisor cannot run.
+    pub nested_paging: Option<NestedPagingCapabilities>,
+
+    /// Whether translations of the guest are tagged in the TLB, so that VM
+    /// transitions do not flush them: VPIDs (Intel), or flushing by ASID
+    /// (AMD).
+    pub tagged_tlb: bool,
+
+    /// The features of APICv (Intel) or AVIC (AMD).
+    pub apic_virtualization: ApicVirtualizationCapabilities,
+
+    /// Whether the monitor trap flag is supported (Intel).
+    pub monitor_trap_flag: bool,
+
+    /// Whether the TSC of the guest can be scaled with `TscConfig::scale`.
+    pub tsc_scaling: bool,
+
+    /// Whether the guest can run in real mode and protected mode without
+    /// paging. Always `true` on AMD processors.
+    pub unrestricted_guest: bool,
+
+    /// Whether EPT violations can be converted to #VE in the guest (Intel).
+    pub virtualization_exceptions: bool,
+
+    /// Whether the guest can switch EPT pointers with VMFUNC (Intel).
+    pub eptp_switching: bool,
+
+    /// Whether the VMX-preemption timer is supported (Intel).
+    pub preemption_timer: bool,
+}
+
+/// The features of EPT (Intel) or NPT (AMD).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NestedPagingCapabilities {
+    pub large_pages_2mb: bool,
+    pub large_pages_1gb: bool,
+
+    /// Whether pages can be executable without being readable (Intel).
+    pub execute_only: bool,
+
+    /// Whether the processor sets the accessed and dirty flags.
+    pub accessed_dirty: bool,
+
+    /// Whether the 5-level paging structures are supported (Intel).
+    pub five_level: bool,
+
+    /// Whether execute permissions can differ for user and supervisor mode:
+    /// MBEC (Intel) or GMET (AMD).
+    pub mode_based_execute: bool,
+}
+
+/// The features of APICv (Intel) or AVIC (AMD).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ApicVirtualizationCapabilities {
+    /// The TPR shadow (Intel).
+    pub tpr_shadow: bool,
+
+    /// APIC-register virtualization (Intel).
+    pub register_virtualization: bool,
+
+    /// Virtual-interrupt delivery (Intel).
+    pub virtual_interrupt_delivery: bool,
+
+    /// Posted interrupts (Intel).
+    pub posted_interrupts: bool,
+
+    /// AVIC (AMD).
+    pub avic: bool,
+
+    /// AVIC for the x2APIC mode (AMD).
+    pub x2avic: bool,
+}
+
+impl HvCapabilities {
+    /// Returns the capabilities of the current processor.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UnsupportedVendor` if the processor is neither Intel nor AMD,
+    /// or its support is not compiled in, and `VmxUnsupported` or
+    /// `SvmUnsupported` if the processor does not implement the extension.
+    pub fn detect() -> Result<Self, VirtError> {
+        let vendor = x86::cpuid::CpuId::new()
+            .get_vendor_info()
+            .ok_or(VirtError::UnsupportedVendor)?;
+        match vendor.as_str() {
+            #[cfg(feature = "intel")]
+            "GenuineIntel" => detect_intel(),
+            #[cfg(feature = "amd")]
+            "AuthenticAMD" => detect_amd(),
+            _ => Err(VirtError::UnsupportedVendor),
+        }
+    }
+}
+
+/// Reads the capability MSRs of VMX. The allowed 1-settings of the controls
+/// are in the upper 32 bits, and are the same in the TRUE MSRs.
+/// See: A.3 VM-EXECUTION CONTROLS
+/// See: A.10 VPID AND EPT CAPABILITIES
+#[cfg(feature = "intel")]
+fn detect_intel() -> Result<HvCapabilities, VirtError> {
+    use crate::hypervisor::{intel::ApicvSupport, x86_instructions::rdmsr};
+
+    const ACTIVATE_SECONDARY_CONTROLS_BIT: usize = 31;
+    const MONITOR_TRAP_FLAG_BIT: usize = 27;
+    const PREEMPTION_TIMER_BIT: usize = 6;
+    const ENABLE_EPT_BIT: usize = 1;
+    const ENABLE_VPID_BIT: usize = 5;
+    const UNRESTRICTED_GUEST_BIT: usize = 7;
+    const ENABLE_VM_FUNCTIONS_BIT: usize = 13;
+    const EPT_VIOLATION_VE_BIT: usize = 18;
+    const MODE_BASED_EXECUTE_BIT: usize = 22;
+    const USE_TSC_SCALING_BIT: usize = 25;
+
+    // See: 23.6 DISCOVERING SUPPORT FOR VMX
+    if !cpuid!(0x1).ecx.get_bit(5) {
+        return Err(VirtError::VmxUnsupported);
+    }
+
+    let allowed1 = |msr: u32| (rdmsr(msr) >> 32) as u32;
+    let pin_based = allowed1(x86::msr::IA32_VMX_PINBASED_CTLS);
+    let primary = allowed1(x86::msr::IA32_VMX_PROCBASED_CTLS);
+    let secondary = if primary.get_bit(ACTIVATE_SECONDARY_CONTROLS_BIT) {
+        allowed1(x86::msr::IA32_VMX_PROCBASED_CTLS2)
+    } else {
+        0
+    };
+    let ept = secondary.get_bit(ENABLE_EPT_BIT);
+    let ept_vpid = if ept || secondary.get_bit(ENABLE_VPID_BIT) {
+        rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP)
+    } else {
+        0
+    };
+    let vm_functions = if secondary.get_bit(ENABLE_VM_FUNCTIONS_BIT) {
+        rdmsr(x86::msr::IA32_VMX_VMFUNC)
+    } else {
+        0
+    };
+    let apic_virtualization = if secondary != 0 {
+        let apicv = ApicvSupport::detect();
+        ApicVirtualizationCapabilities {
+            tpr_shadow: apicv.tpr_shadow,
+            register_virtualization: apicv.apic_register_virtualization,
+            virtual_interrupt_delivery: apicv.virtual_interrupt_delivery,
+            posted_interrupts: apicv.posted_interrupts,
+            ..Default::default()
+        }
+    } else {
+        ApicVirtualizationCapabilities::default()
+    };
+
+    Ok(HvCapabilities {
+        vendor: Vendor::Intel,
+        nested_paging: ept.then(|| NestedPagingCapabilities {
+            large_pages_2mb: ept_vpid.get_bit(16),
+            large_pages_1gb: ept_vpid.get_bit(17),
+            execute_only: ept_vpid.get_bit(0),
+            accessed_dirty: ept_vpid.get_bit(21),
+            five_level: ept_vpid.get_bit(7),
+            mode_based_execute: secondary.get_bit(MODE_BASED_EXECUTE_BIT),
+        }),
+        // The host uses the single-context INVVPID. See `vpid::allocate`.
+        tagged_tlb: secondary.get_bit(ENABLE_VPID_BIT)
+            && ept_vpid.get_bit(32)
+            && ept_vpid.get_bit(41),
+        apic_virtualization,
+        monitor_trap_flag: primary.get_bit(MONITOR_TRAP_FLAG_BIT),
+        tsc_scaling: secondary.get_bit(USE_TSC_SCALING_BIT),
+        unrestricted_guest: secondary.get_bit(UNRESTRICTED_GUEST_BIT),
+        virtualization_exceptions: secondary.get_bit(EPT_VIOLATION_VE_BIT),
+        eptp_switching: vm_functions.get_bit(0),
+        preemption_timer: pin_based.get_bit(PREEMPTION_TIMER_BIT),
+    })
+}
+
+/// Reads the SVM feature identifiers.
+/// See: E.4.10 Function 8000_000Ah—SVM Revision and Feature Identification
+#[cfg(feature = "amd")]
+fn detect_amd() -> Result<HvCapabilities, VirtError> {
+    use crate::hypervisor::amd::AvicSupport;
+
+    // See: Table E-4. Extended Feature Identifiers
+    const PAGE_1GB_BIT: usize = 26;
+
+    if !cpuid!(0x8000_0001).ecx.get_bit(2) {
+        return Err(VirtError::SvmUnsupported);
+    }
+
+    let svm_features = cpuid!(0x8000_000a).edx;
+    let avic = AvicSupport::detect();
+
+    // NPT uses the formats of the long-mode paging structures of the host,
+    // including the accessed and dirty flags.
+    Ok(HvCapabilities {
+        vendor: Vendor::Amd,
+        nested_paging: svm_features.get_bit(0).then(|| NestedPagingCapabilities {
+            large_pages_2mb: true,
+            large_pages_1gb: cpuid!(0x8000_0001).edx.get_bit(PAGE_1GB_BIT),
+            execute_only: false,
+            accessed_dirty: true,
+            five_level: false,
+            mode_based_execute: svm_features.get_bit(17),
+        }),
+        tagged_tlb: svm_features.get_bit(6),
+        apic_virtualization: ApicVirtualizationCapabilities {
+            avic: avic.avic,
+            x2avic: avic.x2avic,
+            ..Default::default()
+        },
+        monitor_trap_flag: false,
+        tsc_scaling: svm_features.get_bit(4),
+        unrestricted_guest: true,
+        virtualization_exceptions: false,
+        eptp_switching: false,
+        preemption_timer: false,
+    })
+}
+
+impl fmt::Display for HvCapabilities {
+    /// Lists the supported features, followed by the unsupported ones the
+    /// vendor defines.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let paging = self.nested_paging.unwrap_or_default();
+        let apic = &self.apic_virtualization;
+        let is_intel = self.vendor == Vendor::Intel;
+        let (extension, nested_paging, tagged_tlb, apic_virtualization) = if is_intel {
+            ("Intel VT-x", "EPT", "VPID", "APICv")
+        } else {
+            ("AMD SVM", "NPT", "flush by ASID", "AVIC")
+        };
+        let paging_features = [
+            ("2MB pages", paging.large_pages_2mb, true),
+            ("1GB pages", paging.large_pages_1gb, true),
+            ("execute-only", paging.execute_only, is_intel),
+            ("A/D flags", paging.accessed_dirty, true),
+            ("5-level", paging.five_level, is_intel),
+            ("mode-based execute", paging.mode_based_execute, true),
+        ];
+        let apic_features = [
+            ("TPR shadow", apic.tpr_shadow, is_intel),
+            (
+                "register virtualization",
+                apic.register_virtualization,
+                is_intel,
+            ),
+            (
+                "virtual-interrupt delivery",
+                apic.virtual_interrupt_delivery,
+                is_intel,
+            ),
+            ("posted interrupts", apic.posted_interrupts, is_intel),
+            ("x2AVIC", apic.x2avic, !is_intel),
+        ];
+        let nested_paging = describe(nested_paging, &paging_features);
+        let apic_virtualization = describe(apic_virtualization, &apic_features);
+        let features = [
+            (nested_paging.as_str(), self.nested_paging.is_some(), true),
+            (tagged_tlb, self.tagged_tlb, true),
+            (
+                apic_virtualization.as_str(),
+                apic.register_virtualization
+                    || apic.virtual_interrupt_delivery
+                    || apic.posted_interrupts
+                    || apic.avic,
+                true,
+            ),
+            ("MTF", self.monitor_trap_flag, is_intel),
+            ("TSC scaling", self.tsc_scaling, true),
+            ("unrestricted guest", self.unrestricted_guest, is_intel),
+            ("#VE", self.virtualization_exceptions, is_intel),
+            ("EPTP switching", self.eptp_switching, is_intel),
+            ("preemption timer", self.preemption_timer, is_intel),
+        ];
+        let names = |supported: bool| {
+            features
+                .iter()
+                .filter(|(_, value, defined)| *defined && *value == supported)
+                .map(|(name, _, _)| *name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        write!(
+            f,
+            "{extension}: {}; unsupported: {}",
+            names(true),
+            names(false)
+        )
+    }
+}
+
+/// Returns `name` followed by the names of the supported `features` the vendor
+/// defines in parentheses, if any.
+fn describe(name: &str, features: &[(&str, bool, bool)]) -> String {
+    let supported = features
+        .iter()
+        .filter(|(_, supported, defined)| *supported && *defined)
+        .map(|(name, _, _)| *name)
+        .collect::<Vec<_>>();
+    if supported.is_empty() {
+        name.into()
+    } else {
+        alloc::format!("{name} ({})", supported.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn capabilities_are_described() {
+        let capabilities = HvCapabilities {
+            vendor: Vendor::Amd,
+            nested_paging: Some(NestedPagingCapabilities {
+                large_pages_2mb: true,
+                accessed_dirty: true,
+                ..Default::default()
+            }),
+            tagged_tlb: true,
+            apic_virtualization: ApicVirtualizationCapabilities::default(),
+            monitor_trap_flag: false,
+            tsc_scaling: true,
+            unrestricted_guest: true,
+            virtualization_exceptions: false,
+            eptp_switching: false,
+            preemption_timer: false,
+        };
+        assert_eq!(
+            capabilities.to_string(),
+            "AMD SVM: NPT (2MB pages, A/D flags), flush by ASID, TSC scaling; unsupported: AVIC"
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! This module implements switching of the supervisor state of Control-flow
+//! Enforcement Technology (CET) between the guest and the host: IA32_S_CET,
+//! SSP and IA32_INTERRUPT_SSP_TABLE_ADDR. An OS with kernel shadow stacks, such
+//! as Windows 11, cannot run with them left to the host.
+//!
+//! The guest values are loaded on VM-entry and saved on VM-exit in the VMCS,
+//! with the "load CET state" VM-entry and VM-exit controls, or in the VMCB. The
+//! host runs with supervisor CET disabled. The state of the user mode, such as
+//! IA32_U_CET and IA32_PL3_SSP, is not used by the host and kept as is.
+//!
+//! If the processor cannot switch the state, CPUID reports CET as unsupported
+//! to the guest.
+// See: 18.3 INTERACTION OF CONTROL-FLOW ENFORCEMENT TECHNOLOGY WITH VMX
+// See: 15.5.1 Basic Operation
+
+use bit_field::BitField;
+use x86::{
+    controlregs::{Cr0, Cr4},
+    cpuid::CpuIdResult,
+};
+
+use crate::hypervisor::x86_instructions::{cr0, cr0_write, cr4, cr4_write, rdmsr};
+
+/// The MSR of the supervisor mode CET settings.
+pub(crate) const IA32_S_CET: u32 = 0x6a2;
+
+/// The MSR of the linear address of the interrupt SSP table.
+pub(crate) const IA32_INTERRUPT_SSP_TABLE_ADDR: u32 = 0x6a8;
+
+/// CR4.CET, which the `x86` crate does not define.
+pub(crate) const CR4_CET: usize = 1 << 23;
+
+/// Returns whether the processor supports either shadow stacks or indirect
+/// branch tracking, and thus, the CET MSRs.
+pub(crate) fn is_supported() -> bool {
+    // See: Table 1-17. Information Returned by CPUID Instruction
+    let leaf_7 = x86::cpuid::cpuid!(7, 0);
+    leaf_7.ecx.get_bit(7) || leaf_7.edx.get_bit(20)
+}
+
+/// Returns whether the processor switches the supervisor CET state between the
+/// guest and the host.
+pub(crate) fn is_switched() -> bool {
+    const LOAD_CET_STATE_ENTRY: usize = 20;
+    const LOAD_CET_STATE_EXIT: usize = 28;
+
+    if !is_supported() {
+        return false;
+    }
+
+    // SVM always does, with the fields in the state save area of the VMCB.
+    let is_intel = x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+    if !is_intel {
+        return true;
+    }
+
+    // See: A.4 VM-EXIT CONTROLS
+    // See: A.5 VM-ENTRY CONTROLS
+    let (entry, exit) = if rdmsr(x86::msr::IA32_VMX_BASIC).get_bit(55) {
+        (
+            x86::msr::IA32_VMX_TRUE_ENTRY_CTLS,
+            x86::msr::IA32_VMX_TRUE_EXIT_CTLS,
+        )
+    } else {
+        (x86::msr::IA32_VMX_ENTRY_CTLS, x86::msr::IA32_VMX_EXIT_CTLS)
+    };
+    rdmsr(entry).get_bit(32 + LOAD_CET_STATE_ENTRY) && rdmsr(exit).get_bit(32 + LOAD_CET_STATE_EXIT)
+}
+
+/// Clears the CET feature flags in `result` of the leaf 7 if the supervisor CET
+/// state is not switched, so that the guest does not enable it.
+pub(crate) fn apply_cpuid(leaf: u32, sub_leaf: u32, result: &mut CpuIdResult) {
+    if leaf == 7 && sub_leaf == 0 && !is_switched() {
+        // CET_SS and CET_IBT.
+        let _ = result.ecx.set_bit(7, false);
+        let _ = result.edx.set_bit(20, false);
+    }
+}
+
+/// Returns the current value of IA32_S_CET, or zero if unsupported.
+pub(crate) fn s_cet() -> u64 {
+    if is_supported() {
+        rdmsr(IA32_S_CET)
+    } else {
+        0
+    }
+}
+
+/// Writes the shadow stack frame for `IRETQ` to return to `rip` in `cs`, and a
+/// shadow stack restore token below it, onto the shadow stack at `ssp`. Returns
+/// the address of the token to switch to the shadow stack with `RSTORSSP`.
+///
+/// # Safety
+///
+/// Supervisor shadow stacks must be disabled, and `ssp` must be the SSP of the
+/// guest to resume on the current address space. The 4 entries below `ssp` are
+/// overwritten.
+// See: IRET/IRETD/IRETQ—Interrupt Return
+// See: RSTORSSP—Restore Saved Shadow Stack Pointer
+pub(crate) unsafe fn push_return_frame(ssp: u64, cs: u64, rip: u64) -> u64 {
+    const MODE_64BIT: u64 = 1 << 0;
+
+    // Shadow stack pages are read-only for ordinary stores. Clear CR0.WP to
+    // write onto it, which requires clearing CR4.CET first.
+    let (original_cr0, original_cr4) = (cr0(), cr4());
+    unsafe {
+        cr4_write(Cr4::from_bits_unchecked(original_cr4.bits() & !CR4_CET));
+        cr0_write(Cr0::from_bits_unchecked(
+            original_cr0.bits() & !Cr0::CR0_WRITE_PROTECT.bits(),
+        ));
+    }
+
+    // IRETQ pops SSP, the return address and CS in this order.
+    let token = (ssp - 8 * 4) as *mut u64;
+    unsafe {
+        token.write((token as u64 + 8) | MODE_64BIT);
+        token.add(1).write(ssp);
+        token.add(2).write(rip);
+        token.add(3).write(cs);
+    }
+
+    cr0_write(original_cr0);
+    cr4_write(original_cr4);
+    token as u64
+}
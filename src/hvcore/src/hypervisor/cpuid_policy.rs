@@ -0,0 +1,338 @@
+//! This module implements customization of the results of the `CPUID`
+//! instruction presented to the guest.
+//!
+//! The result of each `CPUID` is the one from the processor, modified by the
+//! overrides registered for the leaf in order: first the ones for any sub-leaf,
+//! then the ones for the exact sub-leaf.
+
+use alloc::collections::BTreeMap;
+use x86::cpuid::CpuIdResult;
+
+use crate::hypervisor::{
+    HV_CPUID_DETECTION_SUB_LEAF, HV_CPUID_INTERFACE, HV_CPUID_VENDOR_AND_MAX_FUNCTIONS,
+    OUR_HV_VENDOR_NAME_EBX, OUR_HV_VENDOR_NAME_ECX, OUR_HV_VENDOR_NAME_EDX,
+};
+
+/// The range of the leaves reserved for hypervisors.
+const HV_CPUID_LEAF_RANGE: core::ops::RangeInclusive<u32> = 0x4000_0000..=0x4fff_ffff;
+
+/// The registers `CPUID` returns values in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuidRegister {
+    /// EAX.
+    Eax,
+    /// EBX.
+    Ebx,
+    /// ECX.
+    Ecx,
+    /// EDX.
+    Edx,
+}
+
+/// The set of overrides of `CPUID` results.
+///
+/// The default policy hides VMX from the guest and reports our hypervisor
+/// through the hypervisor leaves. Overrides made on top of it may break
+/// detection of our hypervisor if the leaf `0x4000_0000` is changed.
+///
+/// ```ignore
+/// let policy = CpuidPolicy::default()
+///     // Hide AVX-512F.
+///     .clear_bits(7, Some(0), CpuidRegister::Ebx, 1 << 16)
+///     // Report the hypervisor-present bit.
+///     .set_bits(1, None, CpuidRegister::Ecx, 1 << 31);
+/// ```
+#[derive(Clone, Debug)]
+pub struct CpuidPolicy {
+    /// The overrides keyed by the leaf and the sub-leaf, where `None` matches any
+    /// sub-leaf.
+    overrides: BTreeMap<(u32, Option<u32>), Override>,
+}
+
+/// The override of the four registers. Each register value becomes
+/// `(value & and) | or`.
+#[derive(Clone, Copy, Debug)]
+struct Override {
+    and: [u32; 4],
+    or: [u32; 4],
+}
+
+impl Default for Override {
+    fn default() -> Self {
+        Self {
+            and: [u32::MAX; 4],
+            or: [0; 4],
+        }
+    }
+}
+
+impl Default for CpuidPolicy {
+    fn default() -> Self {
+        // On the Intel processor, CPUID.1.ECX[5] indicates if VT-x is supported.
+        // Clear this to prevent other hypervisor tries to use it. On AMD, it is
+        // a reserved bit.
+        // See: Table 3-10. Feature Information Returned in the ECX Register
+        let policy = Self::empty().clear_bits(1, None, CpuidRegister::Ecx, 1 << 5);
+
+        // Likewise, CPUID.8000_0001h.ECX[2] indicates if SVM is supported on the
+        // AMD processor. On Intel, it is a reserved bit.
+        // See: E.4.2 Function 8000_0001h—Extended Processor and Processor Feature Identifiers
+        let policy = policy.clear_bits(0x8000_0001, None, CpuidRegister::Ecx, 1 << 2);
+
+        // If the hypervisor vendor name is asked, return our hypervisor name,
+        // so that `is_our_hypervisor_present` can detect the presence.
+        let leaf = HV_CPUID_VENDOR_AND_MAX_FUNCTIONS;
+        let policy = policy
+            .set_register(leaf, None, CpuidRegister::Ebx, OUR_HV_VENDOR_NAME_EBX)
+            .set_register(leaf, None, CpuidRegister::Ecx, OUR_HV_VENDOR_NAME_ECX)
+            .set_register(leaf, None, CpuidRegister::Edx, OUR_HV_VENDOR_NAME_EDX);
+
+        // Return non "Hv#1" into EAX. This indicate that our hypervisor does NOT
+        // conform to the Microsoft hypervisor interface. This prevents the guest
+        // from using the interface for optimum performance, but simplifies
+        // implementation of our hypervisor. This is required only when testing
+        // in the virtualization platform that supports the Microsoft hypervisor
+        // interface, such as VMware, and not required for a baremetal.
+        // See: Hypervisor Top Level Functional Specification
+        policy.set_register(HV_CPUID_INTERFACE, None, CpuidRegister::Eax, 0)
+    }
+}
+
+impl CpuidPolicy {
+    /// Returns the policy that presents the results from the processor as-is.
+    pub fn empty() -> Self {
+        Self {
+            overrides: BTreeMap::new(),
+        }
+    }
+
+    /// Makes `leaf` and `sub_leaf` return `result`, regardless of the result
+    /// from the processor.
+    #[must_use]
+    pub fn set(self, leaf: u32, sub_leaf: Option<u32>, result: CpuIdResult) -> Self {
+        [
+            CpuidRegister::Eax,
+            CpuidRegister::Ebx,
+            CpuidRegister::Ecx,
+            CpuidRegister::Edx,
+        ]
+        .into_iter()
+        .fold(self, |policy, register| {
+            policy.set_register(leaf, sub_leaf, register, get(&result, register))
+        })
+    }
+
+    /// Makes `register` of `leaf` and `sub_leaf` return `value`.
+    #[must_use]
+    pub fn set_register(
+        self,
+        leaf: u32,
+        sub_leaf: Option<u32>,
+        register: CpuidRegister,
+        value: u32,
+    ) -> Self {
+        self.update(leaf, sub_leaf, register, 0, value)
+    }
+
+    /// Clears `bits` in `register` of `leaf` and `sub_leaf`.
+    #[must_use]
+    pub fn clear_bits(
+        self,
+        leaf: u32,
+        sub_leaf: Option<u32>,
+        register: CpuidRegister,
+        bits: u32,
+    ) -> Self {
+        self.update(leaf, sub_leaf, register, !bits, 0)
+    }
+
+    /// Sets `bits` in `register` of `leaf` and `sub_leaf`.
+    #[must_use]
+    pub fn set_bits(
+        self,
+        leaf: u32,
+        sub_leaf: Option<u32>,
+        register: CpuidRegister,
+        bits: u32,
+    ) -> Self {
+        self.update(leaf, sub_leaf, register, u32::MAX, bits)
+    }
+
+    /// Removes the overrides that reveal our hypervisor, and hides the presence
+    /// of any hypervisor, that is, CPUID.1:ECX[31] and the hypervisor leaves
+    /// return the values from the processor without a hypervisor.
+    ///
+    /// Our hypervisor still returns its name for the leaf `0x4000_0000` and
+    /// the secret sub-leaf, so that it can detect itself.
+    #[must_use]
+    pub fn hide_hypervisor(self) -> Self {
+        // "Bit 31: Not Used. Always returns 0." on bare metal, while hypervisors
+        // set this bit to indicate their presence.
+        // See: Table 1-19. Feature Information Returned in the ECX Register
+        self.without_hypervisor_leaves()
+            .clear_bits(1, None, CpuidRegister::Ecx, 1 << 31)
+    }
+
+    /// Removes the overrides of the hypervisor leaves, so that they return the
+    /// values from the processor, or the hypervisor under ours. Our hypervisor
+    /// still returns its name for the secret sub-leaf.
+    #[must_use]
+    pub(crate) fn without_hypervisor_leaves(mut self) -> Self {
+        self.overrides
+            .retain(|&(leaf, _), _| !HV_CPUID_LEAF_RANGE.contains(&leaf));
+
+        let leaf = HV_CPUID_VENDOR_AND_MAX_FUNCTIONS;
+        let sub_leaf = Some(HV_CPUID_DETECTION_SUB_LEAF);
+        self.set_register(leaf, sub_leaf, CpuidRegister::Ebx, OUR_HV_VENDOR_NAME_EBX)
+            .set_register(leaf, sub_leaf, CpuidRegister::Ecx, OUR_HV_VENDOR_NAME_ECX)
+            .set_register(leaf, sub_leaf, CpuidRegister::Edx, OUR_HV_VENDOR_NAME_EDX)
+    }
+
+    /// Applies the overrides onto `result` of `leaf` and `sub_leaf` from the
+    /// processor.
+    pub(crate) fn apply(&self, leaf: u32, sub_leaf: u32, result: &mut CpuIdResult) {
+        for key in [(leaf, None), (leaf, Some(sub_leaf))] {
+            if let Some(entry) = self.overrides.get(&key) {
+                for (register, (and, or)) in [
+                    &mut result.eax,
+                    &mut result.ebx,
+                    &mut result.ecx,
+                    &mut result.edx,
+                ]
+                .into_iter()
+                .zip(entry.and.iter().zip(entry.or.iter()))
+                {
+                    *register = (*register & and) | or;
+                }
+            }
+        }
+    }
+
+    /// Composes `(value & and) | or` onto the existing override of `register`.
+    fn update(
+        mut self,
+        leaf: u32,
+        sub_leaf: Option<u32>,
+        register: CpuidRegister,
+        and: u32,
+        or: u32,
+    ) -> Self {
+        let entry = self.overrides.entry((leaf, sub_leaf)).or_default();
+        let index = register as usize;
+        entry.and[index] &= and;
+        entry.or[index] = (entry.or[index] & and) | or;
+        self
+    }
+}
+
+fn get(result: &CpuIdResult, register: CpuidRegister) -> u32 {
+    match register {
+        CpuidRegister::Eax => result.eax,
+        CpuidRegister::Ebx => result.ebx,
+        CpuidRegister::Ecx => result.ecx,
+        CpuidRegister::Edx => result.edx,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZERO: CpuIdResult = CpuIdResult {
+        eax: 0,
+        ebx: 0,
+        ecx: 0,
+        edx: 0,
+    };
+    const RESULT: CpuIdResult = CpuIdResult {
+        eax: 0x1111_1111,
+        ebx: 0x2222_2222,
+        ecx: 0xffff_ffff,
+        edx: 0x4444_4444,
+    };
+
+    #[test]
+    fn default_policy() {
+        let policy = CpuidPolicy::default();
+
+        let mut result = RESULT;
+        policy.apply(1, 0, &mut result);
+        assert_eq!(result.ecx, !(1 << 5));
+        assert_eq!(result.eax, RESULT.eax);
+
+        let mut result = RESULT;
+        policy.apply(0x8000_0001, 0, &mut result);
+        assert_eq!(result.ecx, !(1 << 2));
+
+        let mut result = RESULT;
+        policy.apply(HV_CPUID_VENDOR_AND_MAX_FUNCTIONS, 0, &mut result);
+        assert_eq!(result.eax, RESULT.eax);
+        assert_eq!(result.ebx, OUR_HV_VENDOR_NAME_EBX);
+        assert_eq!(result.ecx, OUR_HV_VENDOR_NAME_ECX);
+        assert_eq!(result.edx, OUR_HV_VENDOR_NAME_EDX);
+
+        let mut result = RESULT;
+        policy.apply(0, 0, &mut result);
+        assert_eq!(result, RESULT);
+    }
+
+    #[test]
+    fn hidden_hypervisor() {
+        let policy = CpuidPolicy::default().hide_hypervisor();
+
+        let mut result = RESULT;
+        policy.apply(1, 0, &mut result);
+        assert_eq!(result.ecx, !((1 << 5) | (1 << 31)));
+
+        let mut result = RESULT;
+        policy.apply(HV_CPUID_VENDOR_AND_MAX_FUNCTIONS, 0, &mut result);
+        assert_eq!(result, RESULT);
+
+        let mut result = RESULT;
+        policy.apply(HV_CPUID_INTERFACE, 0, &mut result);
+        assert_eq!(result, RESULT);
+
+        let mut result = RESULT;
+        policy.apply(
+            HV_CPUID_VENDOR_AND_MAX_FUNCTIONS,
+            HV_CPUID_DETECTION_SUB_LEAF,
+            &mut result,
+        );
+        assert_eq!(result.ebx, OUR_HV_VENDOR_NAME_EBX);
+    }
+
+    #[test]
+    fn overrides() {
+        let policy = CpuidPolicy::empty()
+            .clear_bits(7, Some(0), CpuidRegister::Ecx, 0xff)
+            .set_bits(7, Some(0), CpuidRegister::Ecx, 0x1)
+            .set_bits(7, None, CpuidRegister::Ecx, 0xf00)
+            .set(0, None, ZERO)
+            .set_register(0, Some(1), CpuidRegister::Edx, 0x1234);
+
+        let mut result = CpuIdResult {
+            ecx: 0x0000_00ff,
+            ..RESULT
+        };
+        policy.apply(7, 0, &mut result);
+        assert_eq!(result.ecx, 0x0000_0f01);
+
+        // The override for the sub-leaf 0 does not apply to the sub-leaf 1.
+        let mut result = CpuIdResult {
+            ecx: 0x0000_00ff,
+            ..RESULT
+        };
+        policy.apply(7, 1, &mut result);
+        assert_eq!(result.ecx, 0x0000_0fff);
+
+        let mut result = RESULT;
+        policy.apply(0, 1, &mut result);
+        assert_eq!(
+            result,
+            CpuIdResult {
+                edx: 0x1234,
+                ..ZERO
+            }
+        );
+    }
+}
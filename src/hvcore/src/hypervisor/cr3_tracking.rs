@@ -0,0 +1,298 @@
+//! This module implements tracking of address space switches of the guest,
+//! namely, loads of CR3. This lets introspection tools follow the process
+//! running on each processor.
+//!
+//! Enabled with `SharedHostData::cr3_tracking`, loads of CR3 cause VM-exit and
+//! are reported to the observer. The observer may tell to ignore the address
+//! space loaded, and later loads of it are not reported again. Each processor
+//! caches those address spaces, and lets the processor load up to four of them
+//! without VM-exit where supported (the CR3-target values on Intel). Call
+//! [`flush_cache`] when the address spaces ignored so far may become of
+//! interest, for example, after a process exited and its page tables may be
+//! reused.
+//!
+//! ```ignore
+//! struct ProcessTracer;
+//! impl Cr3Observer for ProcessTracer {
+//!     fn on_switch(&self, _vcpu: &mut dyn Vcpu, switch: &Cr3Switch) -> Cr3Interest {
+//!         log::info!("{:#x} -> {:#x}", switch.old_cr3, switch.new_cr3);
+//!         Cr3Interest::Report
+//!     }
+//! }
+//! ```
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::boxed::Box;
+
+use crate::hypervisor::host::Vcpu;
+
+/// The load of CR3 by the guest.
+#[derive(Clone, Copy, Debug)]
+pub struct Cr3Switch {
+    /// The CR3 before the load.
+    pub old_cr3: u64,
+
+    /// The value loaded, including the PCID and the no-flush bit if any.
+    pub new_cr3: u64,
+
+    /// The PCID of the address space loaded. Zero if CR4.PCIDE is clear.
+    pub pcid: u16,
+
+    /// Whether the load keeps TLB entries of the PCID. Always `false` if
+    /// CR4.PCIDE is clear.
+    pub no_flush: bool,
+}
+
+impl Cr3Switch {
+    fn new(old_cr3: u64, new_cr3: u64, cr4: u64) -> Self {
+        const CR4_PCIDE: u64 = 1 << 17;
+        const CR3_NO_FLUSH: u64 = 1 << 63;
+
+        // See: 4.10.1 Process-Context Identifiers (PCIDs)
+        let pcide = cr4 & CR4_PCIDE != 0;
+        Self {
+            old_cr3,
+            new_cr3,
+            pcid: if pcide { (new_cr3 & 0xfff) as u16 } else { 0 },
+            no_flush: pcide && new_cr3 & CR3_NO_FLUSH != 0,
+        }
+    }
+
+    /// Returns the physical address of the top level paging structure loaded.
+    pub fn base(&self) -> u64 {
+        cr3_base(self.new_cr3)
+    }
+}
+
+/// Whether the observer wants to be told about later loads of an address space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cr3Interest {
+    /// Report later loads of the address space.
+    Report,
+
+    /// Do not report loads of the address space until [`flush_cache`].
+    Ignore,
+}
+
+/// Represents an observer of CR3 loads by the guest.
+///
+/// Observers run in the host context with interrupts disabled. They must not
+/// call any platform API and should return as soon as possible.
+pub trait Cr3Observer: Send + Sync {
+    /// Handles the load of CR3 described by `switch` on `vcpu`. The load takes
+    /// effect after this returns.
+    fn on_switch(&self, vcpu: &mut dyn Vcpu, switch: &Cr3Switch) -> Cr3Interest;
+}
+
+impl<F> Cr3Observer for F
+where
+    F: Fn(&mut dyn Vcpu, &Cr3Switch) -> Cr3Interest + Send + Sync,
+{
+    fn on_switch(&self, vcpu: &mut dyn Vcpu, switch: &Cr3Switch) -> Cr3Interest {
+        self(vcpu, switch)
+    }
+}
+
+/// The configuration of CR3 tracking. Disabled by default.
+#[derive(Default)]
+pub struct Cr3Tracking {
+    observer: Option<Box<dyn Cr3Observer>>,
+}
+
+impl Cr3Tracking {
+    /// Enables CR3 tracking with `observer`.
+    pub fn new(observer: impl Cr3Observer + 'static) -> Self {
+        Self {
+            observer: Some(Box::new(observer)),
+        }
+    }
+
+    /// Returns whether CR3 tracking is enabled.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.observer.is_some()
+    }
+
+    /// Reports the load of `new_cr3` on `vcpu` to the observer unless the
+    /// address space is ignored in `cache`. Returns whether the CR3-target
+    /// values of `cache` are updated.
+    pub(crate) fn handle_load(
+        &self,
+        vcpu: &mut dyn Vcpu,
+        cache: &mut Cr3Cache,
+        new_cr3: u64,
+    ) -> bool {
+        let mut updated = cache.sync(generation());
+        if let Some(observer) = &self.observer {
+            if !cache.contains(new_cr3) {
+                let switch = Cr3Switch::new(vcpu.cr3(), new_cr3, vcpu.cr4());
+                if observer.on_switch(vcpu, &switch) == Cr3Interest::Ignore {
+                    cache.insert(new_cr3);
+                    updated = true;
+                }
+            }
+        }
+        updated
+    }
+}
+
+impl core::fmt::Debug for Cr3Tracking {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Cr3Tracking")
+            .field("enabled", &self.is_enabled())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Forgets the address spaces ignored so far on all processors. Takes effect on
+/// each processor at its next CR3 load causing VM-exit.
+pub fn flush_cache() {
+    let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Incremented whenever the caches are to be flushed.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The number of address spaces each processor caches.
+const CACHE_SIZE: usize = 64;
+
+/// The maximum number of CR3-target values.
+pub(crate) const MAX_CR3_TARGETS: usize = 4;
+
+/// The address spaces ignored on a processor.
+#[derive(Debug)]
+pub(crate) struct Cr3Cache {
+    /// The bases of the address spaces, indexed by their page frame numbers.
+    /// Zero is an empty entry.
+    entries: [u64; CACHE_SIZE],
+
+    /// The exact CR3 values recently ignored, to be loaded without VM-exit.
+    targets: [u64; MAX_CR3_TARGETS],
+    target_count: usize,
+    next_target: usize,
+
+    /// The generation of `entries`. See `flush_cache`.
+    generation: u64,
+}
+
+impl Cr3Cache {
+    pub(crate) const fn new() -> Self {
+        Self {
+            entries: [0; CACHE_SIZE],
+            targets: [0; MAX_CR3_TARGETS],
+            target_count: 0,
+            next_target: 0,
+            generation: 0,
+        }
+    }
+
+    /// Returns the CR3 values that may be loaded without VM-exit.
+    pub(crate) fn targets(&self) -> &[u64] {
+        &self.targets[..self.target_count]
+    }
+
+    /// Returns whether the address space of `cr3` is ignored.
+    fn contains(&self, cr3: u64) -> bool {
+        let base = cr3_base(cr3);
+        base != 0 && self.entries[Self::index(base)] == base
+    }
+
+    /// Ignores the address space of `cr3`, evicting the one sharing the entry.
+    /// `cr3` becomes one of the targets, replacing the oldest one if full.
+    fn insert(&mut self, cr3: u64) {
+        let base = cr3_base(cr3);
+        if base == 0 {
+            return;
+        }
+
+        let index = Self::index(base);
+        let evicted = self.entries[index];
+        self.entries[index] = base;
+
+        // Replace the target of the evicted address space, if any, to keep the
+        // targets within the cache.
+        if let Some(position) = self.targets().iter().position(|&t| cr3_base(t) == evicted) {
+            self.targets[position] = cr3;
+            return;
+        }
+        if self.target_count < MAX_CR3_TARGETS {
+            self.targets[self.target_count] = cr3;
+            self.target_count += 1;
+        } else {
+            self.targets[self.next_target] = cr3;
+            self.next_target = (self.next_target + 1) % MAX_CR3_TARGETS;
+        }
+    }
+
+    /// Empties the cache if `generation` is newer. Returns whether emptied.
+    fn sync(&mut self, generation: u64) -> bool {
+        if self.generation == generation {
+            return false;
+        }
+        *self = Self::new();
+        self.generation = generation;
+        true
+    }
+
+    fn index(base: u64) -> usize {
+        (base >> 12) as usize % CACHE_SIZE
+    }
+}
+
+/// Returns the physical address of the paging structure in `cr3`.
+fn cr3_base(cr3: u64) -> u64 {
+    cr3 & 0x000f_ffff_ffff_f000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switch_decodes_pcid() {
+        const CR4_PCIDE: u64 = 1 << 17;
+
+        let switch = Cr3Switch::new(0x1000, (1 << 63) | 0x2000 | 0x12, CR4_PCIDE);
+        assert_eq!(
+            (switch.pcid, switch.no_flush, switch.base()),
+            (0x12, true, 0x2000)
+        );
+
+        let switch = Cr3Switch::new(0x1000, (1 << 63) | 0x2000 | 0x12, 0);
+        assert_eq!((switch.pcid, switch.no_flush), (0, false));
+    }
+
+    #[test]
+    fn cache_keeps_latest_targets() {
+        let mut cache = Cr3Cache::new();
+        for i in 1..=6 {
+            cache.insert(i << 12);
+        }
+        assert!((1..=6).all(|i| cache.contains(i << 12)));
+        assert!(!cache.contains(7 << 12));
+        assert_eq!(cache.targets(), &[5 << 12, 6 << 12, 3 << 12, 4 << 12]);
+    }
+
+    #[test]
+    fn cache_evicts_conflicting_entry_and_target() {
+        let mut cache = Cr3Cache::new();
+        cache.insert(0x1000);
+        cache.insert(0x1000 + (CACHE_SIZE as u64) * 0x1000);
+        assert!(!cache.contains(0x1000));
+        assert_eq!(cache.targets(), &[0x1000 + (CACHE_SIZE as u64) * 0x1000]);
+    }
+
+    #[test]
+    fn cache_is_emptied_by_newer_generation() {
+        let mut cache = Cr3Cache::new();
+        cache.insert(0x1000);
+        assert!(!cache.sync(0));
+        assert!(cache.sync(1));
+        assert!(!cache.contains(0x1000));
+        assert!(cache.targets().is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+//! This module implements configuration of CR0 and CR4 write interception. The
+//! embedder of this crate selects bits of CR0 and CR4 to guard and attaches a
+//! policy to them, for example, to keep CR0.WP, CR4.SMEP and CR4.SMAP set. The
+//! vendor specific code translates the selection into the guest/host masks
+//! (Intel) or the CR write intercepts (AMD).
+//!
+//! Only writes changing any of the guarded bits are passed to the policy. The
+//! other writes are applied as requested. The guest reads the applied value,
+//! with the read shadows (Intel) kept in sync with it.
+
+use alloc::boxed::Box;
+
+use crate::hypervisor::host::Vcpu;
+
+/// The CR0 bits that can be guarded: WP, AM, NW and CD. The others are either
+/// changed by CLTS and LMSW, switch the operating mode, or are fixed for VMX.
+pub const CR0_GUARDABLE_BITS: u64 = (1 << 16) | (1 << 18) | (1 << 29) | (1 << 30);
+
+/// The CR4 bits that can be guarded. The paging mode bits PAE, LA57 and PCIDE,
+/// and VMXE used by the hypervisor, cannot be.
+pub const CR4_GUARDABLE_BITS: u64 =
+    0x1ff_ffff & !((1 << 5) | (1 << 12) | (1 << 13) | (1 << 15) | (1 << 17));
+
+/// Represents a policy on writes to a control register.
+pub trait CrPolicy: Send + Sync {
+    /// Handles `MOV` to the control register changing any of the guarded bits
+    /// from `current` to `value`. Returns the value to actually write, or
+    /// `None` to discard the write, leaving the register unchanged.
+    ///
+    /// To make the instruction fail instead, inject #GP with
+    /// `event::inject_event` and return `None`.
+    fn write(&self, vcpu: &mut dyn Vcpu, current: u64, value: u64) -> Option<u64>;
+}
+
+/// The guarded bits of a control register and the policy on them.
+struct Guard {
+    bits: u64,
+    policy: Box<dyn CrPolicy>,
+}
+
+/// The bits of CR0 and CR4 to guard and the policies on them.
+///
+/// ```ignore
+/// let intercepts = CrIntercepts::new()
+///     .on_cr0(1 << 16, KeepBitsSet)
+///     .on_cr4((1 << 20) | (1 << 21), KeepBitsSet);
+/// ```
+#[derive(Default)]
+pub struct CrIntercepts {
+    cr0: Option<Guard>,
+    cr4: Option<Guard>,
+}
+
+impl CrIntercepts {
+    /// Returns an empty set, which intercepts no write to CR0 or CR4.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Guards `bits` of CR0 and calls `policy` on writes changing any of them.
+    /// Replaces the policy already set for CR0, if any. Bits outside
+    /// `CR0_GUARDABLE_BITS` are ignored.
+    #[must_use]
+    pub fn on_cr0(mut self, bits: u64, policy: impl CrPolicy + 'static) -> Self {
+        self.cr0 = Self::guard(bits & CR0_GUARDABLE_BITS, policy);
+        self
+    }
+
+    /// Guards `bits` of CR4 and calls `policy` on writes changing any of them.
+    /// Replaces the policy already set for CR4, if any. Bits outside
+    /// `CR4_GUARDABLE_BITS` are ignored.
+    #[must_use]
+    pub fn on_cr4(mut self, bits: u64, policy: impl CrPolicy + 'static) -> Self {
+        self.cr4 = Self::guard(bits & CR4_GUARDABLE_BITS, policy);
+        self
+    }
+
+    fn guard(bits: u64, policy: impl CrPolicy + 'static) -> Option<Guard> {
+        (bits != 0).then(|| Guard {
+            bits,
+            policy: Box::new(policy),
+        })
+    }
+
+    /// Returns the guarded bits of CR0.
+    pub(crate) fn cr0_bits(&self) -> u64 {
+        self.cr0.as_ref().map_or(0, |guard| guard.bits)
+    }
+
+    /// Returns the guarded bits of CR4.
+    pub(crate) fn cr4_bits(&self) -> u64 {
+        self.cr4.as_ref().map_or(0, |guard| guard.bits)
+    }
+
+    /// Returns the value to write to the control register `cr` when the guest
+    /// writes `value` to it, consulting the policy if the write changes any of
+    /// the guarded bits of `current`.
+    pub(crate) fn filter(
+        &self,
+        vcpu: &mut dyn Vcpu,
+        cr: u8,
+        current: u64,
+        value: u64,
+    ) -> Option<u64> {
+        let guard = match cr {
+            0 => self.cr0.as_ref(),
+            4 => self.cr4.as_ref(),
+            _ => None,
+        };
+        match guard {
+            Some(guard) if (current ^ value) & guard.bits != 0 => {
+                guard.policy.write(vcpu, current, value)
+            }
+            _ => Some(value),
+        }
+    }
+}
+
+impl core::fmt::Debug for CrIntercepts {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("CrIntercepts")
+            .field("cr0_bits", &format_args!("{:#x}", self.cr0_bits()))
+            .field("cr4_bits", &format_args!("{:#x}", self.cr4_bits()))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Discard;
+    impl CrPolicy for Discard {
+        fn write(&self, _vcpu: &mut dyn Vcpu, _current: u64, _value: u64) -> Option<u64> {
+            None
+        }
+    }
+
+    #[test]
+    fn unguardable_bits_are_ignored() {
+        const CR0_PG: u64 = 1 << 31;
+        const CR0_WP: u64 = 1 << 16;
+        const CR4_VMXE: u64 = 1 << 13;
+
+        let intercepts = CrIntercepts::new()
+            .on_cr0(CR0_PG | CR0_WP, Discard)
+            .on_cr4(CR4_VMXE, Discard);
+        assert_eq!(intercepts.cr0_bits(), CR0_WP);
+        assert_eq!(intercepts.cr4_bits(), 0);
+        assert!(intercepts.cr4.is_none());
+    }
+}
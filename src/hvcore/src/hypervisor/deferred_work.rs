@@ -0,0 +1,78 @@
+//! This module implements the deferred work queue of the host, which lets
+//! VM-exit handlers split heavy work, such as rebuilding nested paging
+//! structures or copying large ranges, across VM-exits. Long VM-exits delay
+//! interrupts for the guest, and the guest may miss timer interrupts.
+//!
+//! Each processor has its own queue. Work queued with [`defer`] runs on the
+//! same processor, in the order queued, one item after the handling of each
+//! subsequent VM-exit. Work may queue another item to continue where it left
+//! off. VM-exits occur only as the guest causes them, so enable
+//! `SharedHostData::preemption_timer` too for queued work to progress
+//! regardless of what the guest does. Work left on devirtualization runs before
+//! the processor is devirtualized.
+//!
+//! ```ignore
+//! // In a VM-exit handler.
+//! deferred_work::defer(vcpu, move |vcpu| rebuild_next_chunk(vcpu, chunk));
+//! ```
+
+use alloc::{boxed::Box, collections::VecDeque};
+use spin::Mutex;
+
+use crate::hypervisor::{host::Vcpu, percpu};
+
+/// Represents work deferred with [`defer`]. Work runs in the host context with
+/// interrupts disabled, under the same constraints as VM-exit handlers.
+pub type DeferredWork = dyn FnOnce(&mut dyn Vcpu) + Send;
+
+/// The work queued on a processor and not run yet.
+#[derive(Default)]
+pub(crate) struct WorkQueue(Mutex<VecDeque<Box<DeferredWork>>>);
+
+impl WorkQueue {
+    fn push(&self, work: Box<DeferredWork>) {
+        self.0.lock().push_back(work);
+    }
+
+    fn pop(&self) -> Option<Box<DeferredWork>> {
+        self.0.lock().pop_front()
+    }
+
+    /// Returns whether the queue is locked by any processor. See
+    /// `host::fail_open`.
+    pub(crate) fn is_locked(&self) -> bool {
+        self.0.is_locked()
+    }
+}
+
+impl core::fmt::Debug for WorkQueue {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("WorkQueue")
+            .field("len", &self.0.lock().len())
+            .finish()
+    }
+}
+
+/// Queues `work` to run on `vcpu` after the handling of a subsequent VM-exit.
+/// Must be called from the host, such as VM-exit handlers.
+pub fn defer(vcpu: &dyn Vcpu, work: impl FnOnce(&mut dyn Vcpu) + Send + 'static) {
+    percpu::get(vcpu.id())
+        .expect("the block is allocated")
+        .deferred_work
+        .push(Box::new(work));
+}
+
+/// Takes the oldest work queued on the current processor, to run after the
+/// current VM-exit is handled. Taking it before handling leaves work queued by
+/// the handlers to the subsequent VM-exits.
+pub(crate) fn take_next() -> Option<Box<DeferredWork>> {
+    percpu::current().deferred_work.pop()
+}
+
+/// Runs all work queued on the current processor, including work queued by
+/// them, on devirtualization.
+pub(crate) fn run_all(vcpu: &mut dyn Vcpu) {
+    while let Some(work) = take_next() {
+        work(vcpu);
+    }
+}
@@ -0,0 +1,216 @@
+//! This module implements tracking of the guest physical pages the guest
+//! writes to, with the accessed and dirty flags of the EPT. This is the
+//! building block of incremental snapshots, for example, for fuzzing.
+//!
+//! Tracking is enabled with `enable_dirty_tracking`, and each processor picks
+//! it up on the next VM-exit. `Vcpu::harvest_dirty_pages` then returns the
+//! pages written since the last harvest, clears their dirty flags, and
+//! invalidates cached EPT translations. As the EPT is shared among processors,
+//! the returned pages include the ones written by any processor. The other
+//! processors invalidate their cached translations on their next VM-exit, and
+//! their writes until then may not be reported. Harvest while the other
+//! processors are idle for exact results.
+//!
+//! Most guest physical memory is mapped with 1GB or 2MB pages, and a write to
+//! such a page reports all 4KB pages in it. Pages split into 4KB pages, for
+//! example, for hooks and protections, are reported individually.
+//!
+//! This is supported only on Intel processors supporting the accessed and
+//! dirty flags for EPT.
+//!
+//! ```ignore
+//! enable_dirty_tracking()?;
+//!
+//! // Later, on a VM-exit, for example, the fuzzer's hypercall.
+//! for gpa in vcpu.harvest_dirty_pages()?.pages() {
+//!     restore_page(gpa);
+//! }
+//! ```
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use alloc::collections::BTreeMap;
+use bit_field::BitField;
+use x86::bits64::paging::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+
+use crate::hypervisor::x86_instructions::rdmsr;
+
+/// The errors dirty tracking may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirtyTrackingError {
+    #[error("the processor does not support the dirty flags for EPT")]
+    Unsupported,
+
+    #[error("dirty tracking is not enabled")]
+    NotEnabled,
+}
+
+/// The set of guest physical pages the guest wrote to, as the bitmaps of the
+/// 4KB pages in each 2MB region.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct DirtyBitmap {
+    /// The bitmaps keyed by the GPA of the 2MB regions. Bit N is set if the
+    /// Nth page in the region is dirty.
+    regions: BTreeMap<u64, [u64; 8]>,
+}
+
+impl DirtyBitmap {
+    /// Creates an empty bitmap.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether the page containing `gpa` is dirty.
+    pub fn is_dirty(&self, gpa: u64) -> bool {
+        let index = page_index(gpa);
+        self.regions
+            .get(&region(gpa))
+            .is_some_and(|bitmap| bitmap[index / 64].get_bit(index % 64))
+    }
+
+    /// Returns the GPAs of the dirty pages in ascending order.
+    pub fn pages(&self) -> impl Iterator<Item = u64> + '_ {
+        self.regions.iter().flat_map(|(&region, bitmap)| {
+            (0..512)
+                .filter(|&index| bitmap[index / 64].get_bit(index % 64))
+                .map(move |index| region + (index * BASE_PAGE_SIZE) as u64)
+        })
+    }
+
+    /// Returns the bitmaps of the 2MB regions with any dirty pages, keyed by
+    /// the GPAs of the regions. Bit N is set if the Nth page in the region is
+    /// dirty.
+    pub fn bitmaps(&self) -> impl Iterator<Item = (u64, &[u64; 8])> {
+        self.regions
+            .iter()
+            .map(|(&region, bitmap)| (region, bitmap))
+    }
+
+    /// Returns the number of the dirty pages.
+    pub fn len(&self) -> usize {
+        self.regions
+            .values()
+            .flatten()
+            .map(|bits| bits.count_ones() as usize)
+            .sum()
+    }
+
+    /// Returns whether no pages are dirty.
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Marks the pages in `len` bytes at `start` as dirty. Both must be page
+    /// aligned.
+    #[cfg(any(feature = "intel", test))]
+    pub(crate) fn insert(&mut self, start: u64, len: u64) {
+        for gpa in (start..start + len).step_by(BASE_PAGE_SIZE) {
+            let index = page_index(gpa);
+            let bitmap = self.regions.entry(region(gpa)).or_default();
+            let _ = bitmap[index / 64].set_bit(index % 64, true);
+        }
+    }
+}
+
+impl core::fmt::Debug for DirtyBitmap {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DirtyBitmap")
+            .field("pages", &self.len())
+            .finish()
+    }
+}
+
+/// Enables tracking of the pages the guest writes to. Changes take effect on
+/// each processor on the next VM-exit on that processor.
+///
+/// While enabled, the processor treats accesses to the guest paging structures
+/// as writes, so pages containing them are reported as dirty, and reading them
+/// may violate protections with `memory_protection` that do not permit writes.
+///
+/// # Errors
+///
+/// Returns `Unsupported` if the processor does not support the dirty flags for
+/// EPT.
+// See: 29.3.5 Accessed and Dirty Flags for EPT
+pub fn enable_dirty_tracking() -> Result<(), DirtyTrackingError> {
+    let is_intel = x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+
+    // "Bit 21 (...) If this bit is 1, accessed and dirty flags for EPT are
+    //  supported"
+    // See: A.10 VPID AND EPT CAPABILITIES
+    if !is_intel || !rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP).get_bit(21) {
+        return Err(DirtyTrackingError::Unsupported);
+    }
+
+    ENABLED.store(true, Ordering::Release);
+    let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+    Ok(())
+}
+
+/// Disables tracking enabled with `enable_dirty_tracking`.
+pub fn disable_dirty_tracking() {
+    ENABLED.store(false, Ordering::Release);
+    let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Returns whether dirty tracking is enabled.
+#[cfg(feature = "intel")]
+pub(crate) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Returns the current generation of dirty tracking. It is incremented every
+/// time tracking is enabled or disabled, or the dirty flags are harvested, to
+/// make each processor update the EPTP and invalidate cached translations.
+#[cfg(feature = "intel")]
+pub(crate) fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Notifies the other processors that the dirty flags are cleared, and returns
+/// the new generation.
+#[cfg(feature = "intel")]
+pub(crate) fn harvested() -> u64 {
+    GENERATION.fetch_add(1, Ordering::AcqRel) + 1
+}
+
+fn region(gpa: u64) -> u64 {
+    gpa & !(LARGE_PAGE_SIZE as u64 - 1)
+}
+
+fn page_index(gpa: u64) -> usize {
+    gpa.get_bits(12..=20) as usize // [20:12]
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitmap() {
+        let mut bitmap = DirtyBitmap::new();
+        assert!(bitmap.is_empty());
+
+        bitmap.insert(0x1000, 0x1000);
+        bitmap.insert(0x1ff000, 0x2000);
+        bitmap.insert(0x4000_0000, 0x20_0000);
+        assert!(bitmap.is_dirty(0x1fff));
+        assert!(!bitmap.is_dirty(0x2000));
+        assert!(bitmap.is_dirty(0x20_0000));
+        assert!(bitmap.is_dirty(0x401f_f000));
+        assert!(!bitmap.is_dirty(0x4020_0000));
+        assert_eq!(bitmap.len(), 3 + 512);
+
+        let pages: alloc::vec::Vec<u64> = bitmap.pages().take(4).collect();
+        assert_eq!(pages, [0x1000, 0x1ff000, 0x20_0000, 0x4000_0000]);
+
+        let bitmaps: alloc::vec::Vec<(u64, [u64; 8])> =
+            bitmap.bitmaps().map(|(gpa, bits)| (gpa, *bits)).collect();
+        assert_eq!(bitmaps[0], (0, [1 << 1, 0, 0, 0, 0, 0, 0, 1 << 63]));
+        assert_eq!(bitmaps[1], (0x20_0000, [1, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(bitmaps[2], (0x4000_0000, [u64::MAX; 8]));
+    }
+}
@@ -0,0 +1,508 @@
+//! This module implements management of execute/read split hooks, also known
+//! as EPT hooks or stealth hooks.
+//!
+//! A hook is made of two physical pages: the original page and a shadow page,
+//! which is a copy of the original page with patches applied. The hypervisor
+//! maps the shadow page for instruction fetches and the original page for data
+//! reads and writes, switching the processor that violates the mapping to
+//! nested paging structures private to it. As a result, the guest executes the
+//! patched code while any read of the page, such as integrity checks, observes
+//! the unmodified contents. On Intel processors, the private structures are
+//! used only while single-stepping the accessing instruction, and an
+//! instruction on a hooked page that reads the same page observes the patched
+//! contents.
+//!
+//! This module only maintains the set of hooks and is vendor agnostic. Each
+//! processor picks up changes on the next VM-exit by comparing the generation
+//! of the hooks with the one it last applied.
+//!
+//! On Intel processors supporting EPTP switching, the manager also maintains
+//! EPT views, which map select guest physical pages to other physical pages
+//! with other permissions. View 0 is the default EPT, and the guest switches
+//! views with `switch_view` (`VMFUNC` leaf 0). This lets an agent in the
+//! guest, for example, run its own code in a view where its hooks are
+//! executable, while the rest of the system runs in view 0. Each
+//! view shares the mappings of view 0 except for the pages set in it, and
+//! dirty tracking with `dirty_tracking` covers view 0 only. Access to a page
+//! that the current view does not permit switches the processor to view 0,
+//! unless a custom VM-exit handler handles it.
+//!
+//! `VMFUNC` causes VM-exit, and the hypervisor switches the view only if the
+//! guest is at CPL 0, since the processor switching views itself would let
+//! user-mode code switch too. Any code at CPL 0 can still switch to any view,
+//! not only the agent. Views are thus no boundary against the kernel of the
+//! guest, and should not map pages the kernel may not see. The private
+//! structures used for single-stepping are not views and cannot be switched
+//! to.
+//!
+//! ```ignore
+//! let view = hook_manager().create_view()?;
+//! hook_manager().set_page_in_view(view, code_pa, shadow_pa, Permissions::EXECUTE_ONLY)?;
+//! switch_view(view);
+//! ```
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{
+    boxed::Box,
+    collections::{btree_map::Entry, BTreeMap},
+    vec::Vec,
+};
+use bit_field::BitField;
+use spin::{Mutex, MutexGuard};
+use x86::bits64::paging::{BASE_PAGE_SIZE, HUGE_PAGE_SIZE};
+
+use crate::hypervisor::{
+    guest_memory::UserAccessGuard,
+    hidden_memory, host_window,
+    memory_protection::Permissions,
+    percpu, platform_ops,
+    support::{zeroed_box, Page},
+    x86_instructions::rdmsr,
+};
+
+/// Returns the hook manager after acquiring its lock.
+///
+/// Changes made through the manager take effect on each processor on the next
+/// VM-exit on that processor.
+pub fn hook_manager() -> MutexGuard<'static, HookManager> {
+    HOOK_MANAGER.lock()
+}
+
+/// Returns the current generation of the hooks. It is incremented every time
+/// a hook is installed or uninstalled.
+pub(crate) fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Returns the hook manager if its lock is available. This is used from the
+/// host, where spinning on the lock could deadlock with the guest on the same
+/// processor that already owns it.
+pub(crate) fn try_hook_manager() -> Option<MutexGuard<'static, HookManager>> {
+    HOOK_MANAGER.try_lock()
+}
+
+/// The collection of installed hooks.
+#[derive(Debug)]
+pub struct HookManager {
+    /// The hooks keyed by the physical address of the original pages.
+    hooks: BTreeMap<u64, Hook>,
+
+    /// The shadow pages of uninstalled hooks with the generation removing them.
+    /// They are freed after all processors apply the generation, since they may
+    /// still be executing them until then.
+    retired: Vec<(u64, Box<Page>)>,
+
+    /// The pages set in each view, indexed by the view number minus one. Each
+    /// is a map of the GPA of a page to the PA it is mapped to and its
+    /// permissions.
+    views: Vec<BTreeMap<u64, (u64, Permissions)>>,
+}
+
+#[derive(Debug)]
+struct Hook {
+    shadow: Box<Page>,
+    shadow_pa: u64,
+}
+
+impl HookManager {
+    const fn new() -> Self {
+        Self {
+            hooks: BTreeMap::new(),
+            retired: Vec::new(),
+            views: Vec::new(),
+        }
+    }
+
+    /// Installs a hook that makes the processors execute `patch` at `address`
+    /// while reads of `address` continue to return the original bytes.
+    ///
+    /// If the page containing `address` is already hooked, `patch` is applied
+    /// onto the existing shadow page. The page must be resident and must stay
+    /// at the same physical address while it is hooked.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if `patch` crosses the page boundary, or
+    /// `HostMemoryHidden` if called from the guest after the heap is hidden
+    /// with `SharedHostData::hide_host_memory`, as are the other methods
+    /// changing hooks and views.
+    pub fn install(&mut self, address: *const u8, patch: &[u8]) -> Result<(), HookError> {
+        if hidden_memory::is_hidden_from_caller() {
+            return Err(HookError::HostMemoryHidden);
+        }
+        let va = address as u64;
+        let page_va = va & !(BASE_PAGE_SIZE as u64 - 1);
+        let ops = platform_ops::get();
+        self.apply(va, ops.pa(address.cast()), patch, |shadow| {
+            shadow.0.copy_from_slice(unsafe {
+                core::slice::from_raw_parts(page_va as _, BASE_PAGE_SIZE)
+            });
+            Some(ops.pa(shadow as *const _ as *const _))
+        })
+    }
+
+    /// Does the same as `install` for the physical address `pa` that the guest
+    /// virtual address `gva` translates to. Must be called from the host on
+    /// the processor `id`, where the pages are accessed through the host
+    /// window instead of `platform_ops`.
+    pub(crate) fn install_pa(
+        &mut self,
+        id: usize,
+        gva: u64,
+        pa: u64,
+        patch: &[u8],
+    ) -> Result<(), HookError> {
+        let page_pa = pa & !(BASE_PAGE_SIZE as u64 - 1);
+        self.apply(gva, pa, patch, |shadow| {
+            let original = host_window::map(id, page_pa);
+            let _guard = UserAccessGuard::new();
+            shadow
+                .0
+                .copy_from_slice(unsafe { core::slice::from_raw_parts(original, BASE_PAGE_SIZE) });
+            host_window::host_pa(id, shadow as *const _ as u64)
+        })
+    }
+
+    /// Applies `patch` at `pa`, which `address` maps to, onto the shadow page
+    /// of the page. If the page is not hooked yet, `init_shadow` is called to
+    /// fill a new shadow page with the original contents and to return its
+    /// physical address.
+    fn apply(
+        &mut self,
+        address: u64,
+        pa: u64,
+        patch: &[u8],
+        init_shadow: impl FnOnce(&mut Page) -> Option<u64>,
+    ) -> Result<(), HookError> {
+        let offset = (pa as usize) % BASE_PAGE_SIZE;
+        if offset + patch.len() > BASE_PAGE_SIZE {
+            return Err(HookError::CrossesPageBoundary {
+                address,
+                len: patch.len(),
+            });
+        }
+
+        self.free_retired();
+        let page_pa = pa & !(BASE_PAGE_SIZE as u64 - 1);
+        let hook = match self.hooks.entry(page_pa) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let mut shadow = zeroed_box::<Page>();
+                let shadow_pa = init_shadow(&mut shadow).ok_or(HookError::NotMapped { address })?;
+                entry.insert(Hook { shadow, shadow_pa })
+            }
+        };
+        hook.shadow.0[offset..offset + patch.len()].copy_from_slice(patch);
+
+        log::debug!(
+            "Hooked {address:#x?} (PA: {page_pa:#x?}) with {} bytes",
+            patch.len()
+        );
+        let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Uninstalls all hooks on the page containing `address`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the page is not hooked, or `HostMemoryHidden` as
+    /// `install` does.
+    pub fn uninstall(&mut self, address: *const u8) -> Result<(), HookError> {
+        if hidden_memory::is_hidden_from_caller() {
+            return Err(HookError::HostMemoryHidden);
+        }
+        let pa = platform_ops::get().pa(address.cast());
+        self.uninstall_pa(address as u64, pa)
+    }
+
+    /// Does the same as `uninstall` for the physical address `pa` that the
+    /// guest virtual address `gva` translates to. Can be called from the host.
+    pub(crate) fn uninstall_pa(&mut self, gva: u64, pa: u64) -> Result<(), HookError> {
+        let page_pa = pa & !(BASE_PAGE_SIZE as u64 - 1);
+        let Some(hook) = self.hooks.remove(&page_pa) else {
+            return Err(HookError::NotHooked { address: gva });
+        };
+        let generation = GENERATION.fetch_add(1, Ordering::AcqRel) + 1;
+        self.retired.push((generation, hook.shadow));
+        self.free_retired();
+
+        log::debug!("Unhooked {gva:#x?} (PA: {page_pa:#x?})");
+        Ok(())
+    }
+
+    /// Frees the shadow pages of uninstalled hooks that all processors stopped
+    /// using.
+    fn free_retired(&mut self) {
+        // Nothing uses the shadow pages if no processor is virtualized.
+        let applied = percpu::try_all().map_or(u64::MAX, |blocks| {
+            blocks
+                .map(|block| block.hook_generation.load(Ordering::Acquire))
+                .min()
+                .unwrap_or(u64::MAX)
+        });
+        self.retired.retain(|(generation, _)| *generation > applied);
+    }
+
+    /// Creates an EPT view that maps the same as view 0 until pages are set
+    /// with `set_page_in_view`, and returns the number of the view.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the processor does not support EPTP switching, or the
+    /// maximum number of views already exist, or `HostMemoryHidden` as
+    /// `install` does.
+    pub fn create_view(&mut self) -> Result<usize, HookError> {
+        const MAX_VIEWS: usize = 512;
+
+        if hidden_memory::is_hidden_from_caller() {
+            return Err(HookError::HostMemoryHidden);
+        }
+
+        // "Bit 13 (...) enable VM functions" and "Bit 0: EPTP switching"
+        // See: A.3.3 Secondary Processor-Based VM-Execution Controls
+        // See: A.11 VM FUNCTIONS
+        let is_intel =
+            x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+        if !is_intel
+            || !(rdmsr(x86::msr::IA32_VMX_PROCBASED_CTLS2) >> 32).get_bit(13)
+            || !rdmsr(x86::msr::IA32_VMX_VMFUNC).get_bit(0)
+        {
+            return Err(HookError::ViewsUnsupported);
+        }
+        if self.views.len() + 1 == MAX_VIEWS {
+            return Err(HookError::TooManyViews);
+        }
+
+        self.views.push(BTreeMap::new());
+        let view = self.views.len();
+        log::debug!("Created the view {view}");
+        let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+        Ok(view)
+    }
+
+    /// Makes `view` map the guest physical page at `gpa` to the physical page
+    /// at `pa` with `permissions`, replacing the previous mapping in the view.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if `view` is not created with `create_view`, the addresses
+    /// are not page aligned, outside the first 512GB or hidden from the guest,
+    /// or `permissions` is not supported. Returns `HostMemoryHidden` as
+    /// `install` does.
+    pub fn set_page_in_view(
+        &mut self,
+        view: usize,
+        gpa: u64,
+        pa: u64,
+        permissions: Permissions,
+    ) -> Result<(), HookError> {
+        if hidden_memory::is_hidden_from_caller() {
+            return Err(HookError::HostMemoryHidden);
+        }
+        let pages = view
+            .checked_sub(1)
+            .and_then(|index| self.views.get_mut(index))
+            .ok_or(HookError::InvalidView { view })?;
+        let page_mask = BASE_PAGE_SIZE as u64 - 1;
+        let limit = HUGE_PAGE_SIZE as u64 * 512;
+        if gpa & page_mask != 0
+            || pa & page_mask != 0
+            || gpa >= limit
+            || hidden_memory::is_hidden(gpa)
+            || hidden_memory::is_hidden(pa)
+        {
+            return Err(HookError::InvalidPage { gpa, pa });
+        }
+        if permissions.check_supported().is_err() {
+            return Err(HookError::UnsupportedPermissions(permissions));
+        }
+
+        let _ = pages.insert(gpa, (pa, permissions));
+        log::debug!("Mapped {gpa:#x?} to {pa:#x?} as {permissions:?} in the view {view}");
+        let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Returns an iterator of the physical addresses of the original and shadow
+    /// pages of each hook.
+    pub(crate) fn hooks(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.hooks.iter().map(|(pa, hook)| (*pa, hook.shadow_pa))
+    }
+
+    /// Returns an iterator of the pages set in each view from view 1. See
+    /// `views` in this struct.
+    #[cfg(any(feature = "intel", test))]
+    pub(crate) fn views(&self) -> impl Iterator<Item = &BTreeMap<u64, (u64, Permissions)>> {
+        self.views.iter()
+    }
+}
+
+/// Switches the EPT view of the current processor to `view` with `VMFUNC`
+/// leaf 0 (EPTP switching), which causes VM-exit. Must be called from the guest
+/// at CPL 0. #UD occurs if it is not, if `view` is neither 0 nor created with
+/// `HookManager::create_view`, or no view is created.
+// See: 26.5.6.3 EPTP Switching
+pub fn switch_view(view: usize) {
+    unsafe { asm!("vmfunc", in("eax") 0, in("ecx") view) };
+}
+
+/// The errors the hook manager may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug)]
+pub enum HookError {
+    #[error("{len:#x?} bytes at {address:#x?} cross the page boundary")]
+    CrossesPageBoundary { address: u64, len: usize },
+
+    #[error("the page of {address:#x?} is not hooked")]
+    NotHooked { address: u64 },
+
+    #[error("the shadow page for {address:#x?} could not be mapped")]
+    NotMapped { address: u64 },
+
+    #[error("the processor does not support EPTP switching")]
+    ViewsUnsupported,
+
+    #[error("no more views can be created")]
+    TooManyViews,
+
+    #[error("the view {view} does not exist")]
+    InvalidView { view: usize },
+
+    #[error("{gpa:#x?} or {pa:#x?} is not page aligned or is hidden, or {gpa:#x?} is not in the first 512GB")]
+    InvalidPage { gpa: u64, pa: u64 },
+
+    #[error("{0:?} is not supported by the processor")]
+    UnsupportedPermissions(Permissions),
+
+    #[error("the heap is hidden from the guest")]
+    HostMemoryHidden,
+}
+
+/// Returns whether the hook manager is locked by any processor. See
+/// `host::fail_open`.
+pub(crate) fn is_locked() -> bool {
+    HOOK_MANAGER.is_locked()
+}
+
+static HOOK_MANAGER: Mutex<HookManager> = Mutex::new(HookManager::new());
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::test_support::{self, with_cpu};
+
+    /// Returns the contents of the shadow page of the only hook in `manager`.
+    fn shadow(manager: &HookManager) -> &[u8; BASE_PAGE_SIZE] {
+        let (_, shadow_pa) = manager.hooks().next().unwrap();
+        unsafe { &*platform_ops::get().va(shadow_pa).cast() }
+    }
+
+    #[test]
+    fn hooks_patch_shadow_pages() {
+        test_support::init_platform_ops();
+        let mut page = zeroed_box::<Page>();
+        page.0.fill(0x90);
+        let page_va = page.0.as_ptr();
+
+        let mut manager = HookManager::new();
+        manager
+            .install(page_va.wrapping_add(0x10), &[0xcc])
+            .unwrap();
+        manager
+            .install(page_va.wrapping_add(0x20), &[0x0f, 0x0b])
+            .unwrap();
+        assert_eq!(manager.hooks().count(), 1);
+        assert_eq!(manager.hooks().next().unwrap().0, page_va as u64);
+
+        let shadow = shadow(&manager);
+        assert_eq!(shadow[0x10], 0xcc);
+        assert_eq!(shadow[0x20..0x22], [0x0f, 0x0b]);
+        assert!(shadow[..0x10].iter().all(|byte| *byte == 0x90));
+        assert!(page.0.iter().all(|byte| *byte == 0x90));
+
+        assert!(matches!(
+            manager.install(page_va.wrapping_add(0xfff), &[0xcc, 0xcc]),
+            Err(HookError::CrossesPageBoundary { len: 2, .. })
+        ));
+
+        // Shadow pages are freed right away while no processor is virtualized.
+        manager.uninstall(page_va.wrapping_add(0x800)).unwrap();
+        assert_eq!(manager.hooks().count(), 0);
+        assert!(manager.retired.is_empty());
+        assert!(matches!(
+            manager.uninstall(page_va),
+            Err(HookError::NotHooked { .. })
+        ));
+    }
+
+    #[test]
+    fn hooks_are_installed_with_physical_addresses() {
+        test_support::init_platform_ops();
+        let mut page = zeroed_box::<Page>();
+        page.0.fill(0x90);
+        let page_pa = page.0.as_ptr() as u64;
+
+        // The host addresses the page by its physical address and hooks the
+        // virtual address of the guest, which differs.
+        let mut manager = HookManager::new();
+        manager
+            .install_pa(0, 0x7000_0010, page_pa + 0x10, &[0xcc])
+            .unwrap();
+        assert_eq!(manager.hooks().next().unwrap().0, page_pa);
+        let shadow = shadow(&manager);
+        assert_eq!(shadow[0x10], 0xcc);
+        assert!(shadow[0x11..].iter().all(|byte| *byte == 0x90));
+
+        manager.uninstall_pa(0x7000_0010, page_pa + 0x10).unwrap();
+        assert_eq!(manager.hooks().count(), 0);
+        assert!(matches!(
+            manager.uninstall_pa(0x7000_0010, page_pa + 0x10),
+            Err(HookError::NotHooked { .. })
+        ));
+    }
+
+    #[test]
+    fn pages_in_views_are_validated() {
+        test_support::init_platform_ops();
+        with_cpu(|cpu| {
+            cpu.wrmsr(x86::msr::IA32_VMX_EPT_VPID_CAP, 0);
+            cpu.wrmsr(x86::msr::IA32_EFER, 1 << 11);
+        });
+
+        let mut manager = HookManager::new();
+        manager.views.push(BTreeMap::new());
+        manager
+            .set_page_in_view(1, 0x1000, 0x5000, Permissions::READ_ONLY)
+            .unwrap();
+        assert_eq!(
+            manager.views().next().unwrap().get(&0x1000),
+            Some(&(0x5000, Permissions::READ_ONLY))
+        );
+
+        assert!(matches!(
+            manager.set_page_in_view(0, 0x1000, 0x5000, Permissions::ALL),
+            Err(HookError::InvalidView { view: 0 })
+        ));
+        assert!(matches!(
+            manager.set_page_in_view(2, 0x1000, 0x5000, Permissions::ALL),
+            Err(HookError::InvalidView { view: 2 })
+        ));
+        assert!(matches!(
+            manager.set_page_in_view(1, 0x1001, 0x5000, Permissions::ALL),
+            Err(HookError::InvalidPage { .. })
+        ));
+        assert!(matches!(
+            manager.set_page_in_view(1, 0x80_0000_0000, 0x5000, Permissions::ALL),
+            Err(HookError::InvalidPage { .. })
+        ));
+        assert!(matches!(
+            manager.set_page_in_view(1, 0x1000, 0x5000, Permissions::EXECUTE_ONLY),
+            Err(HookError::UnsupportedPermissions(_))
+        ));
+    }
+}
@@ -0,0 +1,326 @@
+//! This module implements injection of events, that is, exceptions and
+//! interrupts, into the guest.
+//!
+//! An injected event is delivered through the guest IDT on the next VM-entry,
+//! as if it occurred right before the guest instruction at RIP. Only one event
+//! can be injected at a time. Injecting an exception while another event is
+//! pending is resolved the way the processor does for the exception occurring
+//! during delivery of the other one, including escalation to #DF.
+//!
+//! External interrupts are queued per vCPU instead, and injected one by one in
+//! the order of priority as soon as the guest can receive them, that is, when
+//! RFLAGS.IF is set and nothing blocks them. Until then, the guest runs with
+//! interrupt-window exiting (Intel) or a virtual interrupt (AMD) requested so
+//! that the host is notified once it can.
+//!
+//! ```ignore
+//! // Deliver #UD instead of executing the instruction.
+//! inject_event(vcpu, Event::Exception { vector: 6, error_code: None })?;
+//! ```
+
+use bit_field::BitField;
+
+use crate::hypervisor::host::Vcpu;
+
+/// The events that can be injected into the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A hardware exception with `vector`. `error_code` must be `Some` exactly
+    /// for the exceptions that push an error code: #DF, #TS, #NP, #SS, #GP, #PF
+    /// and #AC.
+    Exception { vector: u8, error_code: Option<u32> },
+
+    /// A non-maskable interrupt.
+    Nmi,
+
+    /// An external interrupt with `vector`.
+    ExternalInterrupt { vector: u8 },
+}
+
+/// The errors injection may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InjectionError {
+    #[error("another event is already pending")]
+    Busy,
+
+    #[error("the exception escalates to a triple fault")]
+    TripleFault,
+}
+
+/// The vector of #DF.
+const DF_VECTOR: u8 = 8;
+
+/// The vector of #PF.
+const PF_VECTOR: u8 = 14;
+
+impl Event {
+    /// Decodes the format shared by the IDT-vectoring information field (Intel)
+    /// and EXITINTINFO (AMD). Returns `None` if `info` is not valid, or for the
+    /// software interrupts and exceptions, which are regenerated when the guest
+    /// re-executes the instruction.
+    // See: Table 25-18. Format of the IDT-Vectoring Information Field
+    // See: Figure 15-1. EXITINTINFO for All Intercepts
+    pub(crate) fn from_raw(info: u32, error_code: u32) -> Option<Self> {
+        const TYPE_EXTERNAL_INTERRUPT: u32 = 0;
+        const TYPE_NMI: u32 = 2;
+        const TYPE_HARDWARE_EXCEPTION: u32 = 3;
+
+        if !info.get_bit(31) {
+            return None;
+        }
+        let vector = info.get_bits(0..=7) as u8;
+        match info.get_bits(8..=10) {
+            TYPE_EXTERNAL_INTERRUPT => Some(Self::ExternalInterrupt { vector }),
+            TYPE_NMI => Some(Self::Nmi),
+            TYPE_HARDWARE_EXCEPTION => Some(Self::Exception {
+                vector,
+                error_code: info.get_bit(11).then_some(error_code),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Encodes into the format shared by the VM-entry interruption-information
+    /// field (Intel) and EVENTINJ (AMD), and the error code.
+    // See: Table 25-17. Format of the VM-Entry Interruption-Information Field
+    // See: Figure 15-4. EVENTINJ Field in the VMCB
+    pub(crate) fn to_raw(self) -> (u32, u32) {
+        const VALID: u32 = 1 << 31;
+        const ERROR_CODE_VALID: u32 = 1 << 11;
+
+        match self {
+            Self::ExternalInterrupt { vector } => (VALID | u32::from(vector), 0),
+            Self::Nmi => (VALID | (2 << 8) | 2, 0),
+            Self::Exception { vector, error_code } => {
+                let info = VALID | (3 << 8) | u32::from(vector);
+                match error_code {
+                    Some(error_code) => (info | ERROR_CODE_VALID, error_code),
+                    None => (info, 0),
+                }
+            }
+        }
+    }
+}
+
+/// Injects `event` into the guest on the next VM-entry. External interrupts
+/// are queued and injected once the guest can receive them instead.
+///
+/// If an exception is already pending, `event` is combined with it according
+/// to the conditions for generating a double fault: the pending exception
+/// becomes #DF, or `event` is returned as `TripleFault` if it is #DF already.
+/// Otherwise, `event` replaces the pending exception, which is regenerated if
+/// the guest re-executes the instruction.
+///
+/// # Errors
+///
+/// Returns `Busy` if an interrupt is pending, or `event` is an NMI and any
+/// event is pending. Returns `TripleFault` as above, in which case the guest
+/// should be shut down.
+pub fn inject_event(vcpu: &mut dyn Vcpu, event: Event) -> Result<(), InjectionError> {
+    if let Event::ExternalInterrupt { vector } = event {
+        vcpu.queue_interrupt(vector);
+        return Ok(());
+    }
+
+    let event = match vcpu.pending_event() {
+        Some(pending) => combine(pending, event)?,
+        None => event,
+    };
+    vcpu.set_pending_event(Some(event));
+    Ok(())
+}
+
+/// Injects #PF for `address` with `error_code` into the guest. CR2 is updated
+/// only if the injection succeeds.
+///
+/// # Errors
+///
+/// See `inject_event`.
+pub fn inject_page_fault(
+    vcpu: &mut dyn Vcpu,
+    address: u64,
+    error_code: u32,
+) -> Result<(), InjectionError> {
+    inject_event(
+        vcpu,
+        Event::Exception {
+            vector: PF_VECTOR,
+            error_code: Some(error_code),
+        },
+    )?;
+    vcpu.set_cr2(address);
+    Ok(())
+}
+
+/// The external interrupts waiting to be injected into the guest. Like the IRR
+/// of the local APIC, each vector is queued at most once, and the highest one is
+/// injected first.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct InterruptQueue {
+    pending: [u64; 4],
+}
+
+impl InterruptQueue {
+    /// Queues `vector`. Does nothing if it is already queued.
+    pub(crate) fn push(&mut self, vector: u8) {
+        let vector = usize::from(vector);
+        let _ = self.pending[vector / 64].set_bit(vector % 64, true);
+    }
+
+    /// Removes and returns the highest vector queued.
+    pub(crate) fn pop(&mut self) -> Option<u8> {
+        let (index, bits) = self
+            .pending
+            .iter_mut()
+            .enumerate()
+            .rev()
+            .find(|(_, bits)| **bits != 0)?;
+        let bit = 63 - bits.leading_zeros() as usize;
+        let _ = bits.set_bit(bit, false);
+        Some((index * 64 + bit) as u8)
+    }
+
+    /// Returns whether no vector is queued.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending.iter().all(|bits| *bits == 0)
+    }
+}
+
+/// The classes of exceptions for generating a double fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Class {
+    Benign,
+    Contributory,
+    PageFault,
+    DoubleFault,
+}
+
+impl Class {
+    // See: Table 6-4. Interrupt and Exception Classes
+    fn of(vector: u8) -> Self {
+        match vector {
+            0 | 10..=13 => Self::Contributory,
+            PF_VECTOR => Self::PageFault,
+            DF_VECTOR => Self::DoubleFault,
+            _ => Self::Benign,
+        }
+    }
+}
+
+/// Returns the event to inject when `second` occurs while `first` is pending.
+// See: Table 6-5. Conditions for Generating a Double Fault
+fn combine(first: Event, second: Event) -> Result<Event, InjectionError> {
+    let (
+        Event::Exception {
+            vector: first_vector,
+            ..
+        },
+        Event::Exception {
+            vector: second_vector,
+            ..
+        },
+    ) = (first, second)
+    else {
+        return Err(InjectionError::Busy);
+    };
+
+    match (Class::of(first_vector), Class::of(second_vector)) {
+        (Class::DoubleFault, Class::Contributory | Class::PageFault) => {
+            Err(InjectionError::TripleFault)
+        }
+        (Class::Contributory, Class::Contributory)
+        | (Class::PageFault, Class::Contributory | Class::PageFault) => Ok(Event::Exception {
+            vector: DF_VECTOR,
+            error_code: Some(0),
+        }),
+        _ => Ok(second),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UD: Event = Event::Exception {
+        vector: 6,
+        error_code: None,
+    };
+    const GP: Event = Event::Exception {
+        vector: 13,
+        error_code: Some(0x10),
+    };
+    const PF: Event = Event::Exception {
+        vector: PF_VECTOR,
+        error_code: Some(0b10),
+    };
+    const DF: Event = Event::Exception {
+        vector: DF_VECTOR,
+        error_code: Some(0),
+    };
+
+    #[test]
+    fn raw_format() {
+        assert_eq!(UD.to_raw(), (0x8000_0306, 0));
+        assert_eq!(GP.to_raw(), (0x8000_0b0d, 0x10));
+        assert_eq!(Event::Nmi.to_raw(), (0x8000_0202, 0));
+        assert_eq!(
+            Event::ExternalInterrupt { vector: 0x30 }.to_raw(),
+            (0x8000_0030, 0)
+        );
+
+        for event in [
+            UD,
+            GP,
+            Event::Nmi,
+            Event::ExternalInterrupt { vector: 0x30 },
+        ] {
+            let (info, error_code) = event.to_raw();
+            assert_eq!(Event::from_raw(info, error_code), Some(event));
+        }
+
+        // Not valid, and a software interrupt (INT3).
+        assert_eq!(Event::from_raw(0x0000_0306, 0), None);
+        assert_eq!(Event::from_raw(0x8000_0403, 0), None);
+    }
+
+    #[test]
+    fn double_fault() {
+        assert_eq!(combine(GP, GP), Ok(DF));
+        assert_eq!(combine(PF, GP), Ok(DF));
+        assert_eq!(combine(PF, PF), Ok(DF));
+        assert_eq!(combine(GP, PF), Ok(PF));
+        assert_eq!(combine(UD, GP), Ok(GP));
+        assert_eq!(combine(GP, UD), Ok(UD));
+        assert_eq!(combine(DF, GP), Err(InjectionError::TripleFault));
+        assert_eq!(combine(DF, PF), Err(InjectionError::TripleFault));
+        assert_eq!(combine(DF, UD), Ok(UD));
+    }
+
+    #[test]
+    fn interrupt_queue() {
+        let mut queue = InterruptQueue::default();
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(), None);
+
+        for vector in [0x30, 0xff, 0x30, 0x00, 0x41] {
+            queue.push(vector);
+        }
+        assert!(!queue.is_empty());
+        assert_eq!(queue.pop(), Some(0xff));
+        assert_eq!(queue.pop(), Some(0x41));
+        assert_eq!(queue.pop(), Some(0x30));
+        assert_eq!(queue.pop(), Some(0x00));
+        assert_eq!(queue.pop(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn interrupts() {
+        assert_eq!(combine(Event::Nmi, GP), Err(InjectionError::Busy));
+        assert_eq!(combine(GP, Event::Nmi), Err(InjectionError::Busy));
+        assert_eq!(
+            combine(UD, Event::ExternalInterrupt { vector: 0x30 }),
+            Err(InjectionError::Busy)
+        );
+    }
+}
@@ -0,0 +1,313 @@
+//! This module implements the event channel, a ring buffer in guest memory the
+//! hypervisor posts events to, so that an agent in the guest can receive them
+//! asynchronously instead of through the logs or hypercalls polling each
+//! source. The agent registers the buffer with
+//! `Hypercall::RegisterEventChannel`, and polls it or, optionally, is notified
+//! with an interrupt injected on the processor each event is posted on.
+//!
+//! The buffer is physically contiguous and made of 64-byte slots. The first
+//! slot is the [`ChannelHeader`], and the rest hold [`EventRecord`]s in the
+//! order of posting. The hypervisor writes a record at `head` and increments
+//! `head`, and the agent reads a record at `tail` and increments `tail`, both
+//! modulo `capacity`. Records posted while the buffer is full are dropped and
+//! counted in `dropped`.
+//!
+//! The buffer must be RAM the guest can read and write, as mapped in the EPT
+//! (Intel) or NPT (AMD), excluding the pages of the hypervisor, such as the
+//! hidden ones and the shadow pages of hooks. As the guest may change the
+//! mappings after registration, the pages are checked again on each post, and
+//! the event is dropped if they no longer qualify.
+//!
+//! The attempts to tamper with the system call entry (`syscall_protection`)
+//! and to write to the monitored code (`integrity`) are posted in addition to
+//! their queues. Custom VM-exit handlers and marker handlers can post their own
+//! events with [`post`].
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use spin::Mutex;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use crate::hypervisor::{
+    ept_hook,
+    guest_memory::{is_writable_ram, UserAccessGuard},
+    host::Vcpu,
+    host_window,
+};
+
+/// The kinds of events posted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum EventKind {
+    /// `syscall_protection::TamperEvent` in the payload.
+    Tamper = 1,
+
+    /// `integrity::IntegrityEvent` in the payload.
+    Integrity = 2,
+
+    /// The payload defined by the embedder of this crate.
+    Custom = 3,
+}
+
+/// The first slot of the buffer.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct ChannelHeader {
+    /// The number of records posted, written by the hypervisor.
+    pub head: u64,
+
+    /// The number of records read, written by the agent.
+    pub tail: u64,
+
+    /// The number of slots for records, written by the hypervisor on
+    /// registration.
+    pub capacity: u64,
+
+    /// The number of records dropped as the buffer was full, written by the
+    /// hypervisor.
+    pub dropped: u64,
+
+    reserved: [u64; 4],
+}
+
+/// A record of an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct EventRecord {
+    /// The kind of the event, one of [`EventKind`].
+    pub kind: u64,
+
+    /// The ID of the processor the event was posted on.
+    pub processor: u64,
+
+    /// The payload specific to `kind`.
+    pub payload: [u64; 6],
+}
+
+/// The size of a slot in bytes.
+const SLOT_SIZE: u64 = 64;
+
+const _: () = assert!(size_of::<ChannelHeader>() as u64 == SLOT_SIZE);
+const _: () = assert!(size_of::<EventRecord>() as u64 == SLOT_SIZE);
+
+/// The errors registration of the channel may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelError {
+    #[error("{gpa:#x?} or {size:#x?} is not page aligned, or `size` is zero")]
+    Misaligned { gpa: u64, size: u64 },
+
+    #[error("{gpa:#x?} + {size:#x?} overflows")]
+    Overflow { gpa: u64, size: u64 },
+
+    #[error("{gpa:#x?} is not RAM the guest can read and write")]
+    Inaccessible { gpa: u64 },
+
+    #[error("{0} cannot be used for notification")]
+    InvalidVector(u64),
+}
+
+/// The registered buffer.
+#[derive(Clone, Copy, Debug)]
+struct Channel {
+    gpa: u64,
+    capacity: u64,
+    vector: Option<u8>,
+}
+
+/// Registers the buffer of `size` bytes at the guest physical address `gpa`,
+/// replacing the one registered, if any, and initializes its header. If
+/// `vector` is non-zero, the interrupt with the vector is injected on every
+/// post. Must be called from the host.
+///
+/// # Errors
+///
+/// Returns `Err` if the buffer is not page aligned, is not accessible as
+/// described in the module documentation, or `vector` is not 32-255.
+pub(crate) fn register(
+    vcpu: &mut dyn Vcpu,
+    gpa: u64,
+    size: u64,
+    vector: u64,
+) -> Result<(), ChannelError> {
+    let channel = validate(gpa, size, vector)?;
+    if let Some(page) = (gpa..gpa + size)
+        .step_by(BASE_PAGE_SIZE)
+        .find(|&page| !is_writable_ram(vcpu, page))
+    {
+        return Err(ChannelError::Inaccessible { gpa: page });
+    }
+    if let Some(manager) = ept_hook::try_hook_manager() {
+        if let Some((_, shadow_pa)) = manager
+            .hooks()
+            .find(|&(_, shadow_pa)| (gpa..gpa + size).contains(&shadow_pa))
+        {
+            return Err(ChannelError::Inaccessible { gpa: shadow_pa });
+        }
+    }
+
+    let header = ChannelHeader {
+        capacity: channel.capacity,
+        ..Default::default()
+    };
+    let page = host_window::map(vcpu.id(), gpa).cast::<ChannelHeader>();
+    {
+        let _guard = UserAccessGuard::new();
+        // Safety: the window maps the guest page, which the guest gave for the
+        // buffer.
+        unsafe { page.write_volatile(header) };
+    }
+    *CHANNEL.lock() = Some(channel);
+    log::debug!("Registered the event channel at {gpa:#x?}: {channel:x?}");
+    Ok(())
+}
+
+/// Unregisters the buffer, if any. Must be called from the host.
+pub(crate) fn unregister() {
+    *CHANNEL.lock() = None;
+}
+
+/// Posts the event of `kind` with `payload` to the buffer, if registered, and
+/// injects the interrupt for notification into `vcpu`, if requested. Returns
+/// whether the event is posted, rather than dropped. Must be called from the
+/// host, such as VM-exit handlers.
+pub fn post(vcpu: &mut dyn Vcpu, kind: EventKind, payload: [u64; 6]) -> bool {
+    let channel = CHANNEL.lock();
+    let Some(channel) = *channel else {
+        return false;
+    };
+    if !is_writable_ram(vcpu, channel.gpa) {
+        return false;
+    }
+
+    let header = host_window::map(vcpu.id(), channel.gpa).cast::<ChannelHeader>();
+    let guard = UserAccessGuard::new();
+    // Safety: the window maps the header of the buffer the guest gave.
+    let (head, tail) = unsafe {
+        (
+            (&raw const (*header).head).read_volatile(),
+            (&raw const (*header).tail).read_volatile(),
+        )
+    };
+    if head.wrapping_sub(tail) >= channel.capacity {
+        // Safety: same as above.
+        unsafe {
+            let dropped = &raw mut (*header).dropped;
+            dropped.write_volatile(dropped.read_volatile() + 1);
+        };
+        return false;
+    }
+    drop(guard);
+
+    let record = EventRecord {
+        kind: kind as u64,
+        processor: vcpu.id() as u64,
+        payload,
+    };
+    let slot = channel.gpa + record_offset(head, channel.capacity);
+    if !is_writable_ram(vcpu, slot) {
+        return false;
+    }
+    let slot = host_window::map(vcpu.id(), slot).cast::<EventRecord>();
+    {
+        let _guard = UserAccessGuard::new();
+        // Safety: the window maps the slot of the buffer the guest gave. A
+        // slot never crosses a page boundary.
+        unsafe { slot.write_volatile(record) };
+    }
+
+    // Publish the record only after it is written. Stores are not reordered
+    // with other stores on x86.
+    compiler_fence(Ordering::Release);
+    let header = host_window::map(vcpu.id(), channel.gpa).cast::<ChannelHeader>();
+    {
+        let _guard = UserAccessGuard::new();
+        // Safety: same as the header above.
+        unsafe { (&raw mut (*header).head).write_volatile(head.wrapping_add(1)) };
+    }
+
+    if let Some(vector) = channel.vector {
+        vcpu.queue_interrupt(vector);
+    }
+    true
+}
+
+/// Returns the channel for the buffer of `size` bytes at `gpa` with `vector`.
+fn validate(gpa: u64, size: u64, vector: u64) -> Result<Channel, ChannelError> {
+    const PAGE_MASK: u64 = BASE_PAGE_SIZE as u64 - 1;
+
+    if gpa & PAGE_MASK != 0 || size & PAGE_MASK != 0 || size == 0 {
+        return Err(ChannelError::Misaligned { gpa, size });
+    }
+    if gpa.checked_add(size).is_none() {
+        return Err(ChannelError::Overflow { gpa, size });
+    }
+    let vector = match vector {
+        0 => None,
+        32..=255 => Some(vector as u8),
+        _ => return Err(ChannelError::InvalidVector(vector)),
+    };
+    Ok(Channel {
+        gpa,
+        capacity: size / SLOT_SIZE - 1,
+        vector,
+    })
+}
+
+/// Returns the offset of the slot for the record of `index` from the start of
+/// the buffer.
+fn record_offset(index: u64, capacity: u64) -> u64 {
+    SLOT_SIZE + (index % capacity) * SLOT_SIZE
+}
+
+/// Returns whether the channel is locked by any processor. See
+/// `host::fail_open`.
+pub(crate) fn is_locked() -> bool {
+    CHANNEL.is_locked()
+}
+
+static CHANNEL: Mutex<Option<Channel>> = Mutex::new(None);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_validated() {
+        let channel = validate(0x1000, 0x2000, 0xf0).unwrap();
+        assert_eq!(channel.capacity, 127);
+        assert_eq!(channel.vector, Some(0xf0));
+        assert_eq!(validate(0x1000, 0x1000, 0).unwrap().vector, None);
+        assert_eq!(
+            validate(0x1800, 0x1000, 0).unwrap_err(),
+            ChannelError::Misaligned {
+                gpa: 0x1800,
+                size: 0x1000
+            }
+        );
+        assert_eq!(
+            validate(0x1000, 0, 0).unwrap_err(),
+            ChannelError::Misaligned {
+                gpa: 0x1000,
+                size: 0
+            }
+        );
+        assert_eq!(
+            validate(0xffff_ffff_ffff_f000, 0x2000, 0).unwrap_err(),
+            ChannelError::Overflow {
+                gpa: 0xffff_ffff_ffff_f000,
+                size: 0x2000
+            }
+        );
+        assert_eq!(
+            validate(0x1000, 0x1000, 2).unwrap_err(),
+            ChannelError::InvalidVector(2)
+        );
+    }
+
+    #[test]
+    fn records_wrap_around() {
+        assert_eq!(record_offset(0, 63), 0x40);
+        assert_eq!(record_offset(62, 63), 0xfc0);
+        assert_eq!(record_offset(63, 63), 0x40);
+    }
+}
@@ -0,0 +1,147 @@
+//! This module implements registration of custom VM-exit handlers. This lets
+//! the embedder of this crate handle select VM-exits without modifying hvcore.
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+
+use crate::hypervisor::host::{Vcpu, VmExitReason};
+
+/// The kinds of VM-exit that custom handlers can be registered for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ExitReason {
+    /// The guest executed the `CPUID` instruction.
+    Cpuid,
+
+    /// The guest executed the `RDMSR` instruction for an intercepted MSR.
+    Rdmsr,
+
+    /// The guest executed the `WRMSR` instruction for an intercepted MSR.
+    Wrmsr,
+
+    /// The guest executed the `XSETBV` instruction.
+    XSetBv,
+
+    /// The guest accessed memory in a way the EPT (Intel) or NPT (AMD) does not
+    /// permit.
+    NestedPageFault,
+
+    /// The guest executed the `VMCALL` (Intel) or `VMMCALL` (AMD) instruction.
+    Hypercall,
+
+    /// The guest executed an I/O instruction for an intercepted port.
+    Io,
+
+    /// The guest executed `MOV` to CR0 or CR4 that may change the guarded bits,
+    /// or to CR3 with CR3 tracking.
+    CrWrite,
+
+    /// The guest executed `MOV` from or to a debug register while any
+    /// hardware breakpoint is set.
+    DrAccess,
+
+    /// The guest executed `INT3` while any marker is registered.
+    Breakpoint,
+
+    /// The period of the preemption timer elapsed.
+    PreemptionTimer,
+}
+
+impl ExitReason {
+    /// Returns the kind of `exit`, or `None` if custom handlers cannot be
+    /// registered for it.
+    fn from_exit(exit: &VmExitReason) -> Option<Self> {
+        match exit {
+            VmExitReason::Cpuid(_) => Some(Self::Cpuid),
+            VmExitReason::Rdmsr(_) => Some(Self::Rdmsr),
+            VmExitReason::Wrmsr(_) => Some(Self::Wrmsr),
+            VmExitReason::XSetBv(_) => Some(Self::XSetBv),
+            VmExitReason::NestedPageFault(_) => Some(Self::NestedPageFault),
+            VmExitReason::Hypercall(_) => Some(Self::Hypercall),
+            VmExitReason::Io(_) => Some(Self::Io),
+            VmExitReason::CrWrite(_) => Some(Self::CrWrite),
+            VmExitReason::DrAccess(_) => Some(Self::DrAccess),
+            VmExitReason::Breakpoint(_) => Some(Self::Breakpoint),
+            VmExitReason::PreemptionTimer => Some(Self::PreemptionTimer),
+            VmExitReason::InitSignal
+            | VmExitReason::StartupIpi
+            | VmExitReason::Nmi
+            | VmExitReason::InterruptWindow
+            | VmExitReason::SingleStep
+            | VmExitReason::DebugException
+            | VmExitReason::ViewSwitch
+            | VmExitReason::VirtualizationInstruction
+            | VmExitReason::Smi
+            | VmExitReason::MachineCheck
+            | VmExitReason::Invd(_)
+            | VmExitReason::Wbinvd(_)
+            | VmExitReason::ExternalInterrupt
+            | VmExitReason::VirtualEoi
+            | VmExitReason::NestedGuest => None,
+        }
+    }
+}
+
+/// What the host should do after a custom handler returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitAction {
+    /// The handler completely handled the VM-exit, including advancing RIP as
+    /// needed. No other handler, including the built-in one, is called.
+    Handled,
+
+    /// The handler did not handle the VM-exit. The next registered handler, or
+    /// the built-in handler if none is left, is called.
+    Continue,
+}
+
+/// Represents a custom VM-exit handler.
+///
+/// Handlers run in the host context with interrupts disabled. They must not
+/// call any platform API and should return as soon as possible.
+pub trait ExitHandler: Send + Sync {
+    /// Handles the VM-exit described by `exit` that occurred on `vcpu`.
+    fn handle(&self, vcpu: &mut dyn Vcpu, exit: &VmExitReason) -> ExitAction;
+}
+
+impl<F> ExitHandler for F
+where
+    F: Fn(&mut dyn Vcpu, &VmExitReason) -> ExitAction + Send + Sync,
+{
+    fn handle(&self, vcpu: &mut dyn Vcpu, exit: &VmExitReason) -> ExitAction {
+        self(vcpu, exit)
+    }
+}
+
+/// A collection of custom VM-exit handlers, keyed by the kinds of VM-exit.
+#[derive(Default)]
+pub struct ExitHandlers {
+    handlers: BTreeMap<ExitReason, Vec<Box<dyn ExitHandler>>>,
+}
+
+impl ExitHandlers {
+    /// Registers `handler` for `reason`. Handlers for the same reason are called
+    /// in the order of registration.
+    pub fn register(&mut self, reason: ExitReason, handler: Box<dyn ExitHandler>) {
+        self.handlers.entry(reason).or_default().push(handler);
+    }
+
+    /// Calls the registered handlers for `exit` until one of them handles it.
+    pub(crate) fn dispatch(&self, vcpu: &mut dyn Vcpu, exit: &VmExitReason) -> ExitAction {
+        let Some(handlers) = ExitReason::from_exit(exit).and_then(|r| self.handlers.get(&r)) else {
+            return ExitAction::Continue;
+        };
+
+        for handler in handlers {
+            if handler.handle(vcpu, exit) == ExitAction::Handled {
+                return ExitAction::Handled;
+            }
+        }
+        ExitAction::Continue
+    }
+}
+
+impl core::fmt::Debug for ExitHandlers {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_map()
+            .entries(self.handlers.iter().map(|(k, v)| (k, v.len())))
+            .finish()
+    }
+}
@@ -0,0 +1,242 @@
+//! This module implements statistics of VM-exits for each processor: the number
+//! of VM-exits and the time to handle them for each reason.
+//!
+//! The time is measured in TSC ticks from the return of VM-exit to the host
+//! loop until the host is about to run the guest again, thus, it includes
+//! custom handlers but not the VM transitions or the architecture specific
+//! handling in `Guest::run`. Statistics can be read with
+//! `Hypercall::ReadExitStats`, or with [`snapshot`] from the host.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::hypervisor::{host::VmExitReason, percpu};
+
+/// The reasons of VM-exit counted separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitKind {
+    Cpuid = 0,
+    Rdmsr = 1,
+    Wrmsr = 2,
+    XSetBv = 3,
+    InitSignal = 4,
+    StartupIpi = 5,
+    Nmi = 6,
+    InterruptWindow = 7,
+    SingleStep = 8,
+    DebugException = 9,
+    ViewSwitch = 10,
+    NestedPageFault = 11,
+    Hypercall = 12,
+    Io = 13,
+    CrWrite = 14,
+    DrAccess = 15,
+    Breakpoint = 16,
+    PreemptionTimer = 17,
+    VirtualizationInstruction = 18,
+    Smi = 19,
+    MachineCheck = 20,
+    Invd = 21,
+    Wbinvd = 22,
+    ExternalInterrupt = 23,
+    VirtualEoi = 24,
+    NestedGuest = 25,
+}
+
+/// The number of `ExitKind`s, thus entries of a snapshot.
+pub const EXIT_KIND_COUNT: usize = 26;
+
+impl ExitKind {
+    /// Returns the kind of `exit`.
+    pub fn from_exit(exit: &VmExitReason) -> Self {
+        match exit {
+            VmExitReason::Cpuid(_) => Self::Cpuid,
+            VmExitReason::Rdmsr(_) => Self::Rdmsr,
+            VmExitReason::Wrmsr(_) => Self::Wrmsr,
+            VmExitReason::XSetBv(_) => Self::XSetBv,
+            VmExitReason::InitSignal => Self::InitSignal,
+            VmExitReason::StartupIpi => Self::StartupIpi,
+            VmExitReason::Nmi => Self::Nmi,
+            VmExitReason::InterruptWindow => Self::InterruptWindow,
+            VmExitReason::SingleStep => Self::SingleStep,
+            VmExitReason::DebugException => Self::DebugException,
+            VmExitReason::ViewSwitch => Self::ViewSwitch,
+            VmExitReason::NestedPageFault(_) => Self::NestedPageFault,
+            VmExitReason::Hypercall(_) => Self::Hypercall,
+            VmExitReason::Io(_) => Self::Io,
+            VmExitReason::CrWrite(_) => Self::CrWrite,
+            VmExitReason::DrAccess(_) => Self::DrAccess,
+            VmExitReason::Breakpoint(_) => Self::Breakpoint,
+            VmExitReason::PreemptionTimer => Self::PreemptionTimer,
+            VmExitReason::VirtualizationInstruction => Self::VirtualizationInstruction,
+            VmExitReason::Smi => Self::Smi,
+            VmExitReason::MachineCheck => Self::MachineCheck,
+            VmExitReason::Invd(_) => Self::Invd,
+            VmExitReason::Wbinvd(_) => Self::Wbinvd,
+            VmExitReason::ExternalInterrupt => Self::ExternalInterrupt,
+            VmExitReason::VirtualEoi => Self::VirtualEoi,
+            VmExitReason::NestedGuest => Self::NestedGuest,
+        }
+    }
+}
+
+/// The statistics of one `ExitKind`, as returned by `Hypercall::ReadExitStats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ExitStatsEntry {
+    /// The number of VM-exits.
+    pub count: u64,
+
+    /// The total time to handle the VM-exits in TSC ticks.
+    pub total_ticks: u64,
+
+    /// The shortest time to handle a VM-exit in TSC ticks. `u64::MAX` if
+    /// `count` is zero.
+    pub min_ticks: u64,
+
+    /// The longest time to handle a VM-exit in TSC ticks.
+    pub max_ticks: u64,
+}
+
+impl ExitStatsEntry {
+    /// Returns the average time to handle a VM-exit in TSC ticks, or zero if
+    /// `count` is zero.
+    pub fn average_ticks(&self) -> u64 {
+        self.total_ticks.checked_div(self.count).unwrap_or(0)
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.total_ticks += other.total_ticks;
+        self.min_ticks = self.min_ticks.min(other.min_ticks);
+        self.max_ticks = self.max_ticks.max(other.max_ticks);
+    }
+}
+
+/// Returns the statistics of the processor `id` indexed by `ExitKind`, or those
+/// summed up across all processors if `id` is `None`. Returns `None` if `id` is
+/// not a valid processor ID, or no processor is virtualized yet.
+pub fn snapshot(id: Option<usize>) -> Option<[ExitStatsEntry; EXIT_KIND_COUNT]> {
+    let blocks = percpu::try_all()?;
+    match id {
+        Some(id) => percpu::get(id).map(|block| block.exit_stats.snapshot()),
+        None => {
+            let mut total = ExitStats::new().snapshot();
+            for block in blocks {
+                for (total, entry) in total.iter_mut().zip(&block.exit_stats.snapshot()) {
+                    total.merge(entry);
+                }
+            }
+            Some(total)
+        }
+    }
+}
+
+/// The statistics of a processor. Only updated by the processor.
+#[derive(Debug)]
+pub(crate) struct ExitStats {
+    entries: [AtomicEntry; EXIT_KIND_COUNT],
+}
+
+impl ExitStats {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: core::array::from_fn(|_| AtomicEntry {
+                count: AtomicU64::new(0),
+                total_ticks: AtomicU64::new(0),
+                min_ticks: AtomicU64::new(u64::MAX),
+                max_ticks: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Records the VM-exit due to `kind` handled in `ticks`. Must only be
+    /// called from the processor owning the statistics.
+    pub(crate) fn record(&self, kind: ExitKind, ticks: u64) {
+        let entry = &self.entries[kind as usize];
+        let _ = entry.count.fetch_add(1, Ordering::Relaxed);
+        let _ = entry.total_ticks.fetch_add(ticks, Ordering::Relaxed);
+        let _ = entry.min_ticks.fetch_min(ticks, Ordering::Relaxed);
+        let _ = entry.max_ticks.fetch_max(ticks, Ordering::Relaxed);
+    }
+
+    /// Returns the total number of VM-exits.
+    pub(crate) fn exit_count(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|entry| entry.count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Returns the copy of the statistics. Entries may be inconsistent with each
+    /// other while the processor is updating them.
+    pub(crate) fn snapshot(&self) -> [ExitStatsEntry; EXIT_KIND_COUNT] {
+        core::array::from_fn(|i| {
+            let entry = &self.entries[i];
+            ExitStatsEntry {
+                count: entry.count.load(Ordering::Relaxed),
+                total_ticks: entry.total_ticks.load(Ordering::Relaxed),
+                min_ticks: entry.min_ticks.load(Ordering::Relaxed),
+                max_ticks: entry.max_ticks.load(Ordering::Relaxed),
+            }
+        })
+    }
+}
+
+#[derive(Debug)]
+struct AtomicEntry {
+    count: AtomicU64,
+    total_ticks: AtomicU64,
+    min_ticks: AtomicU64,
+    max_ticks: AtomicU64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_tracks_min_average_and_max() {
+        let stats = ExitStats::new();
+        stats.record(ExitKind::Cpuid, 30);
+        stats.record(ExitKind::Cpuid, 10);
+        stats.record(ExitKind::Cpuid, 20);
+        stats.record(ExitKind::Io, 100);
+
+        let snapshot = stats.snapshot();
+        let cpuid = snapshot[ExitKind::Cpuid as usize];
+        assert_eq!(cpuid.count, 3);
+        assert_eq!(cpuid.min_ticks, 10);
+        assert_eq!(cpuid.max_ticks, 30);
+        assert_eq!(cpuid.average_ticks(), 20);
+        assert_eq!(snapshot[ExitKind::Io as usize].count, 1);
+        assert_eq!(stats.exit_count(), 4);
+    }
+
+    #[test]
+    fn empty_entry_has_no_average() {
+        let entry = ExitStats::new().snapshot()[ExitKind::Rdmsr as usize];
+        assert_eq!(entry.count, 0);
+        assert_eq!(entry.average_ticks(), 0);
+    }
+
+    #[test]
+    fn merge_combines_entries() {
+        let mut a = ExitStatsEntry {
+            count: 2,
+            total_ticks: 30,
+            min_ticks: 10,
+            max_ticks: 20,
+        };
+        let b = ExitStatsEntry {
+            count: 1,
+            total_ticks: 5,
+            min_ticks: 5,
+            max_ticks: 5,
+        };
+        a.merge(&b);
+        assert_eq!(a.count, 3);
+        assert_eq!(a.total_ticks, 35);
+        assert_eq!(a.min_ticks, 5);
+        assert_eq!(a.max_ticks, 20);
+    }
+}
@@ -0,0 +1,179 @@
+//! This module implements the trace of the latest VM-exits of each processor,
+//! which the panic handler dumps to help reconstruct what led to a hang or a
+//! crash. Enabled with `SharedHostData::exit_trace_len`. The latest VM-exit is
+//! recorded even if not enabled, for the panic handler to show.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::hypervisor::{
+    exit_stats::ExitKind,
+    percpu::{self, PerCpu},
+    x86_instructions::rdtsc,
+};
+
+/// The architecture specific details of a VM-exit.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RawExitInfo {
+    /// The basic exit reason (Intel) or EXITCODE (AMD).
+    pub(crate) code: u64,
+
+    /// The exit qualification (Intel) or EXITINFO1 (AMD).
+    pub(crate) qualification: u64,
+
+    /// The guest physical address for EPT violations (Intel) or nested page
+    /// faults (AMD), or zero.
+    pub(crate) gpa: u64,
+}
+
+/// A recorded VM-exit.
+#[derive(Clone, Copy, Debug)]
+struct TraceEntry {
+    tsc: u64,
+    kind: ExitKind,
+    rip: u64,
+    info: RawExitInfo,
+}
+
+/// The latest VM-exits of a processor, up to the capacity.
+#[derive(Debug)]
+pub(crate) struct ExitTrace {
+    capacity: usize,
+
+    /// Whether the trace is enabled, as opposed to keeping the latest only.
+    enabled: bool,
+    ring: Mutex<Ring>,
+}
+
+#[derive(Debug, Default)]
+struct Ring {
+    entries: Vec<TraceEntry>,
+
+    /// The index of the oldest entry once `entries` is full.
+    next: usize,
+}
+
+impl ExitTrace {
+    /// Creates a trace of up to `capacity` VM-exits. Records only the latest
+    /// one if `capacity` is zero.
+    pub(crate) fn new(capacity: usize) -> Self {
+        let enabled = capacity != 0;
+        let capacity = capacity.max(1);
+        Self {
+            capacity,
+            enabled,
+            ring: Mutex::new(Ring {
+                entries: Vec::with_capacity(capacity),
+                next: 0,
+            }),
+        }
+    }
+
+    /// Records the VM-exit due to `kind` at `rip` with `info`, discarding the
+    /// oldest one if full. Must only be called from the processor owning the
+    /// trace.
+    pub(crate) fn record(&self, kind: ExitKind, rip: u64, info: RawExitInfo) {
+        let entry = TraceEntry {
+            tsc: rdtsc(),
+            kind,
+            rip,
+            info,
+        };
+        let mut ring = self.ring.lock();
+        if ring.entries.len() < self.capacity {
+            ring.entries.push(entry);
+        } else {
+            let next = ring.next;
+            ring.entries[next] = entry;
+            ring.next = (next + 1) % self.capacity;
+        }
+    }
+
+    /// Calls `callback` for each recorded VM-exit from the oldest. Returns
+    /// `false` without calling it if the trace is being updated, for example,
+    /// when the owning processor panicked while recording.
+    fn for_each(&self, mut callback: impl FnMut(&TraceEntry)) -> bool {
+        let Some(ring) = self.ring.try_lock() else {
+            return false;
+        };
+        let (newer, older) = ring.entries.split_at(ring.next);
+        older.iter().chain(newer).for_each(&mut callback);
+        true
+    }
+
+    /// Returns the latest recorded VM-exit, or `None` if nothing is recorded or
+    /// the trace is being updated.
+    fn latest(&self) -> Option<TraceEntry> {
+        let mut latest = None;
+        let _ = self.for_each(|entry| latest = Some(*entry));
+        latest
+    }
+}
+
+impl core::fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "tsc={:#x} {:?} code={:#x} rip={:#x} qualification={:#x} gpa={:#x}",
+            self.tsc, self.kind, self.info.code, self.rip, self.info.qualification, self.info.gpa
+        )
+    }
+}
+
+/// Logs the latest VM-exit on the current processor. Called from the panic
+/// handler.
+pub(crate) fn dump_current() {
+    if let Some(entry) = percpu::find_current().and_then(|block| block.exit_trace.latest()) {
+        log::error!("Latest VM-exit: {entry}");
+    }
+}
+
+/// Logs the traces of all processors if enabled. Called from the panic handler.
+pub(crate) fn dump() {
+    let Some(blocks) = percpu::try_all() else {
+        return;
+    };
+    for block in blocks.filter(|block| block.exit_trace.enabled) {
+        dump_processor(block);
+    }
+}
+
+/// Logs the trace of the processor of `block`, or its latest VM-exit if not
+/// enabled.
+pub(crate) fn dump_processor(block: &PerCpu) {
+    log::error!("Latest VM-exits on the processor {}:", block.id);
+    if !block.exit_trace.for_each(|entry| log::error!("  {entry}")) {
+        log::error!("  (being updated)");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    fn rips(trace: &ExitTrace) -> Vec<u64> {
+        let mut rips = Vec::new();
+        assert!(trace.for_each(|entry| rips.push(entry.rip)));
+        rips
+    }
+
+    #[test]
+    fn keeps_latest_entries_from_oldest() {
+        let trace = ExitTrace::new(3);
+        for rip in 1..=5 {
+            trace.record(ExitKind::Cpuid, rip, RawExitInfo::default());
+        }
+        assert_eq!(rips(&trace), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn keeps_latest_entry_without_capacity() {
+        let trace = ExitTrace::new(0);
+        trace.record(ExitKind::Cpuid, 1, RawExitInfo::default());
+        trace.record(ExitKind::Io, 2, RawExitInfo::default());
+        assert_eq!(rips(&trace), vec![2]);
+        assert_eq!(trace.latest().map(|entry| entry.kind), Some(ExitKind::Io));
+    }
+}
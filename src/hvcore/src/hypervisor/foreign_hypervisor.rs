@@ -0,0 +1,286 @@
+//! This module implements detection of another hypervisor already running
+//! under the current system, such as Hyper-V with VBS, HVCI or Credential Guard
+//! on Windows, or KVM and VMware when the system is a virtual machine.
+//! `virtualize_system` checks for one before touching the virtualization
+//! extension, and fails with a `VirtError` naming it instead of an opaque
+//! failure of VMXON, or VMRUN, later.
+//!
+//! What follows is chosen with `SharedHostData::foreign_hypervisor`:
+//!
+//! - [`ForeignHypervisorPolicy::Refuse`]: never virtualizes under another
+//!   hypervisor.
+//! - [`ForeignHypervisorPolicy::Allow`]: virtualizes under a hypervisor that
+//!   exposes the virtualization extension to the system, as when testing in a
+//!   virtual machine with nested virtualization, and presents our hypervisor
+//!   instead of it to the guest. Refuses under the root partition of Hyper-V,
+//!   as Windows there depends on the hypercalls of Hyper-V.
+//! - [`ForeignHypervisorPolicy::Nest`]: virtualizes under a hypervisor that
+//!   exposes the virtualization extension, including the root partition of
+//!   Hyper-V, and keeps it visible to the guest. The hypervisor leaves of CPUID
+//!   return the values of that hypervisor, `hyperv` and `kvm_clock` are not
+//!   installed, and `VMCALL` and `VMMCALL` from CPL 0 other than ours are
+//!   forwarded to it with the general purpose registers. Accesses to its
+//!   synthetic MSRs reach it as they always cause VM-exits. The fast
+//!   hypercalls of Hyper-V with parameters in XMM registers are not supported.
+//!
+//! Under any hypervisor that hides the virtualization extension, as Hyper-V
+//! does from the root partition unless nested virtualization is enabled for it,
+//! virtualization fails with `VirtError::ForeignHypervisorWithoutExtension`.
+//! Disable the hypervisor, or VBS, HVCI and Credential Guard on Windows.
+
+use core::arch::asm;
+
+use spin::Once;
+use x86::cpuid::cpuid;
+
+use crate::hypervisor::{
+    cpuid_policy::CpuidPolicy, host, is_our_hypervisor_present, registers::Registers, VirtError,
+    HV_CPUID_INTERFACE, HV_CPUID_VENDOR_AND_MAX_FUNCTIONS,
+};
+
+/// What to do under another hypervisor. See the module documentation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ForeignHypervisorPolicy {
+    /// Fails with `VirtError::ForeignHypervisor`.
+    Refuse,
+
+    /// Virtualizes hiding the hypervisor from the guest, except under the root
+    /// partition of Hyper-V.
+    #[default]
+    Allow,
+
+    /// Virtualizes keeping the hypervisor visible to the guest.
+    Nest,
+}
+
+/// The hypervisor found running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForeignHypervisor {
+    /// Hyper-V, or a hypervisor conforming to its interface.
+    HyperV {
+        /// Whether the system is the root partition, which is the case with
+        /// VBS on Windows.
+        root: bool,
+    },
+
+    /// KVM.
+    Kvm,
+
+    /// Xen.
+    Xen,
+
+    /// VMware.
+    VmWare,
+
+    /// VirtualBox.
+    VirtualBox,
+
+    /// Another hypervisor, with its vendor signature.
+    Other([u8; 12]),
+}
+
+impl core::fmt::Display for ForeignHypervisor {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::HyperV { root: true } => write!(f, "the root partition of Hyper-V"),
+            Self::HyperV { root: false } => write!(f, "Hyper-V"),
+            Self::Kvm => write!(f, "KVM"),
+            Self::Xen => write!(f, "Xen"),
+            Self::VmWare => write!(f, "VMware"),
+            Self::VirtualBox => write!(f, "VirtualBox"),
+            Self::Other(signature) => {
+                let name = core::str::from_utf8(signature).unwrap_or("?");
+                write!(f, "the hypervisor {:?}", name.trim_end_matches('\0'))
+            }
+        }
+    }
+}
+
+/// Detects another hypervisor on the current processor and checks whether the
+/// system can be virtualized under it with `policy`, along with
+/// `host::check_support`. Returns the hypervisor to nest under, if any.
+pub(crate) fn check(
+    policy: ForeignHypervisorPolicy,
+) -> Result<Option<ForeignHypervisor>, VirtError> {
+    let foreign = detect();
+    if let Some(foreign) = foreign {
+        log::info!("Found {foreign} running");
+    }
+    decide(foreign, policy, host::check_support())
+}
+
+/// Makes the guest see `foreign`, the hypervisor to nest under with
+/// `ForeignHypervisorPolicy::Nest`.
+pub(crate) fn install(foreign: ForeignHypervisor, cpuid_policy: CpuidPolicy) -> CpuidPolicy {
+    log::info!("Nesting under {foreign}");
+    let _ = NESTED.call_once(|| foreign);
+    cpuid_policy.without_hypervisor_leaves()
+}
+
+/// Returns whether the system is virtualized under another hypervisor with
+/// `ForeignHypervisorPolicy::Nest`.
+pub(crate) fn is_nested() -> bool {
+    NESTED.get().is_some()
+}
+
+/// Forwards the hypercall the guest made with `regs` to the hypervisor nested
+/// under, which sees it made from the system as the guest does, and updates
+/// `regs` with the results. RIP is not advanced.
+pub(crate) fn forward_hypercall(regs: &mut Registers) {
+    let is_intel = x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+    // Safety: the instruction only enters the other hypervisor, which returns
+    // with the results in the registers.
+    unsafe {
+        if is_intel {
+            asm!(
+                "xchg {rbx}, rbx",
+                "vmcall",
+                "xchg {rbx}, rbx",
+                rbx = inout(reg) regs.rbx,
+                inout("rax") regs.rax,
+                inout("rcx") regs.rcx,
+                inout("rdx") regs.rdx,
+                inout("rsi") regs.rsi,
+                inout("rdi") regs.rdi,
+                inout("r8") regs.r8,
+                inout("r9") regs.r9,
+                inout("r10") regs.r10,
+                inout("r11") regs.r11,
+            );
+        } else {
+            asm!(
+                "xchg {rbx}, rbx",
+                "vmmcall",
+                "xchg {rbx}, rbx",
+                rbx = inout(reg) regs.rbx,
+                inout("rax") regs.rax,
+                inout("rcx") regs.rcx,
+                inout("rdx") regs.rdx,
+                inout("rsi") regs.rsi,
+                inout("rdi") regs.rdi,
+                inout("r8") regs.r8,
+                inout("r9") regs.r9,
+                inout("r10") regs.r10,
+                inout("r11") regs.r11,
+            );
+        }
+    };
+}
+
+/// Returns the hypervisor running under the current processor, if any other
+/// than ours.
+fn detect() -> Option<ForeignHypervisor> {
+    // See: Table 1-19. Feature Information Returned in the ECX Register
+    const HYPERVISOR_PRESENT_BIT: u32 = 1 << 31;
+    // See: Hypervisor Top Level Functional Specification
+    const HV_CPUID_FEATURES: u32 = 0x4000_0003;
+    const HV_INTERFACE_SIGNATURE: u32 = u32::from_le_bytes(*b"Hv#1");
+    const CREATE_PARTITIONS: u32 = 1 << 0;
+
+    if cpuid!(1).ecx & HYPERVISOR_PRESENT_BIT == 0 || is_our_hypervisor_present() {
+        return None;
+    }
+    let regs = cpuid!(HV_CPUID_VENDOR_AND_MAX_FUNCTIONS);
+    let mut signature = [0u8; 12];
+    signature[..4].copy_from_slice(&regs.ebx.to_le_bytes());
+    signature[4..8].copy_from_slice(&regs.ecx.to_le_bytes());
+    signature[8..].copy_from_slice(&regs.edx.to_le_bytes());
+    let root = regs.eax >= HV_CPUID_FEATURES
+        && cpuid!(HV_CPUID_INTERFACE).eax == HV_INTERFACE_SIGNATURE
+        && cpuid!(HV_CPUID_FEATURES).ebx & CREATE_PARTITIONS != 0;
+    Some(identify(&signature, root))
+}
+
+/// Returns the hypervisor with the vendor `signature`.
+fn identify(signature: &[u8; 12], root: bool) -> ForeignHypervisor {
+    match signature {
+        b"Microsoft Hv" => ForeignHypervisor::HyperV { root },
+        b"KVMKVMKVM\0\0\0" | b"Linux KVM Hv" => ForeignHypervisor::Kvm,
+        b"XenVMMXenVMM" => ForeignHypervisor::Xen,
+        b"VMwareVMware" => ForeignHypervisor::VmWare,
+        b"VBoxVBoxVBox" => ForeignHypervisor::VirtualBox,
+        _ => ForeignHypervisor::Other(*signature),
+    }
+}
+
+/// Returns whether to virtualize under `foreign` with `policy`, where `support`
+/// is the result of `host::check_support` under it.
+fn decide(
+    foreign: Option<ForeignHypervisor>,
+    policy: ForeignHypervisorPolicy,
+    support: Result<(), VirtError>,
+) -> Result<Option<ForeignHypervisor>, VirtError> {
+    let Some(foreign) = foreign else {
+        return support.map(|()| None);
+    };
+    if policy == ForeignHypervisorPolicy::Refuse {
+        return Err(VirtError::ForeignHypervisor(foreign));
+    }
+    match support {
+        Err(VirtError::VmxUnsupported | VirtError::SvmUnsupported) => {
+            return Err(VirtError::ForeignHypervisorWithoutExtension(foreign));
+        }
+        Err(err) => return Err(err),
+        Ok(()) => {}
+    }
+    match policy {
+        ForeignHypervisorPolicy::Allow if foreign == (ForeignHypervisor::HyperV { root: true }) => {
+            Err(VirtError::ForeignHypervisor(foreign))
+        }
+        ForeignHypervisorPolicy::Nest => Ok(Some(foreign)),
+        _ => Ok(None),
+    }
+}
+
+/// The hypervisor nested under.
+static NESTED: Once<ForeignHypervisor> = Once::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_are_identified() {
+        assert_eq!(
+            identify(b"Microsoft Hv", true),
+            ForeignHypervisor::HyperV { root: true }
+        );
+        assert_eq!(identify(b"KVMKVMKVM\0\0\0", false), ForeignHypervisor::Kvm);
+        assert_eq!(
+            identify(b"TCGTCGTCGTCG", false),
+            ForeignHypervisor::Other(*b"TCGTCGTCGTCG")
+        );
+    }
+
+    #[test]
+    fn policies_are_applied() {
+        use ForeignHypervisorPolicy::{Allow, Nest, Refuse};
+
+        let root = ForeignHypervisor::HyperV { root: true };
+        let kvm = ForeignHypervisor::Kvm;
+        assert_eq!(decide(None, Refuse, Ok(())), Ok(None));
+        assert_eq!(
+            decide(None, Allow, Err(VirtError::VmxUnsupported)),
+            Err(VirtError::VmxUnsupported)
+        );
+        assert_eq!(
+            decide(Some(kvm), Refuse, Ok(())),
+            Err(VirtError::ForeignHypervisor(kvm))
+        );
+        assert_eq!(decide(Some(kvm), Allow, Ok(())), Ok(None));
+        assert_eq!(decide(Some(kvm), Nest, Ok(())), Ok(Some(kvm)));
+        assert_eq!(
+            decide(Some(root), Allow, Ok(())),
+            Err(VirtError::ForeignHypervisor(root))
+        );
+        assert_eq!(decide(Some(root), Nest, Ok(())), Ok(Some(root)));
+        assert_eq!(
+            decide(Some(root), Nest, Err(VirtError::VmxUnsupported)),
+            Err(VirtError::ForeignHypervisorWithoutExtension(root))
+        );
+        assert_eq!(
+            decide(Some(kvm), Nest, Err(VirtError::MissingFeatures)),
+            Err(VirtError::MissingFeatures)
+        );
+    }
+}
@@ -1,17 +1,30 @@
 //! This module implements management of GDT with TSS. TSS is used because Intel
-//! processors require the host GDT to have a valid TSS.
+//! processors require the host GDT to have a valid TSS, and for the IST stacks
+//! of the host exception handlers.
+//!
+//! ```ignore
+//! let gdt_tss = GdtTss::builder()
+//!     .ist(1, IstStack::new(4))
+//!     .build();
+//! ```
 
-use alloc::{boxed::Box, vec::Vec};
+use core::alloc::Layout;
+
+use alloc::{alloc::handle_alloc_error, boxed::Box, vec::Vec};
 use x86::{
     bits64::task::TaskStateSegment,
-    dtables::{lgdt, DescriptorTablePointer},
+    dtables::DescriptorTablePointer,
     segmentation::{
         cs, BuildDescriptor, Descriptor, DescriptorBuilder, GateDescriptorBuilder, SegmentSelector,
     },
-    task::{load_tr, tr},
 };
 
-use super::segment::SegmentDescriptor;
+use super::{
+    interrupt_handlers::{DF_IST_INDEX, MC_IST_INDEX, NMI_IST_INDEX},
+    segment::SegmentDescriptor,
+    support::Page,
+    x86_instructions::{lgdt, ltr, sgdt, tr},
+};
 
 type Gdtr = DescriptorTablePointer<u64>;
 
@@ -26,14 +39,110 @@ impl GdtTss {
             ptr: Box::new(GdtTssRaw::new_from_current()),
         }
     }
+
+    /// Returns the builder of a copy of the current GDT and TSS, with the IST
+    /// stacks to be set.
+    pub fn builder() -> GdtTssBuilder {
+        GdtTssBuilder {
+            ist: [None; IST_ENTRY_COUNT],
+        }
+    }
+}
+
+/// The builder of `GdtTss` with the IST stacks.
+#[derive(Debug)]
+pub struct GdtTssBuilder {
+    ist: [Option<IstStack>; IST_ENTRY_COUNT],
 }
 
+impl GdtTssBuilder {
+    /// Sets `stack` to the IST entry `index`, which is 1 to 7.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of the range.
+    pub fn ist(mut self, index: u8, stack: IstStack) -> Self {
+        assert!((1..=IST_ENTRY_COUNT as u8).contains(&index));
+        self.ist[usize::from(index) - 1] = Some(stack);
+        self
+    }
+
+    /// Sets the stacks to the IST entries `InterruptDescriptorTable` uses for
+    /// NMIs, #DF and #MC.
+    pub fn host_exception_stacks(self) -> Self {
+        const STACK_PAGES: usize = 4;
+
+        self.ist(NMI_IST_INDEX, IstStack::new(STACK_PAGES))
+            .ist(DF_IST_INDEX, IstStack::new(STACK_PAGES))
+            .ist(MC_IST_INDEX, IstStack::new(STACK_PAGES))
+    }
+
+    /// Builds the copy of the current GDT and TSS with the IST stacks. Appends
+    /// a TSS if the GDT does not have one.
+    pub fn build(self) -> GdtTss {
+        let mut gdt_tss = GdtTss::new_from_current();
+        if gdt_tss.tss.is_none() {
+            let _ = gdt_tss.append_tss(TaskStateSegment::new());
+        }
+
+        // The TSS is packed. Update the IST entries by value.
+        let tss = gdt_tss.tss.as_mut().unwrap();
+        let mut ist = tss.ist;
+        for (entry, stack) in ist.iter_mut().zip(&self.ist) {
+            if let Some(stack) = stack {
+                *entry = stack.top;
+            }
+        }
+        tss.ist = ist;
+        gdt_tss.ist_stacks = self.ist;
+
+        // The copy of the TSS is in the box, whose address does not change.
+        gdt_tss.update_tss_descriptor();
+        gdt_tss
+    }
+}
+
+/// A stack for an IST entry, with the guard page below it. The stack is never
+/// freed.
+///
+/// The guard page is made non-present in `SharedHostData::pt`, if specified,
+/// so that overflowing the stack causes #PF instead of corrupting memory.
+#[derive(Clone, Copy, Debug)]
+pub struct IstStack {
+    guard_page: u64,
+    top: u64,
+}
+
+impl IstStack {
+    /// Allocates a stack of `pages` pages, and the guard page.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pages` is zero.
+    pub fn new(pages: usize) -> Self {
+        assert!(pages != 0);
+        let layout = Layout::array::<Page>(pages + 1).unwrap();
+        let ptr = unsafe { alloc::alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
+        Self {
+            guard_page: ptr as u64,
+            top: ptr as u64 + layout.size() as u64,
+        }
+    }
+}
+
+/// The number of the IST entries in the TSS.
+const IST_ENTRY_COUNT: usize = 7;
+
 #[derive(Clone, Debug)]
 pub struct GdtTssRaw {
     pub gdt: Vec<u64>,
     pub cs: SegmentSelector,
     pub tss: Option<TaskStateSegment>,
     pub tr: Option<SegmentSelector>,
+    ist_stacks: [Option<IstStack>; IST_ENTRY_COUNT],
 }
 
 #[derive(thiserror_no_std::Error, Clone, Copy, Debug)]
@@ -44,13 +153,13 @@ pub enum GdtTssError {
 
 impl GdtTssRaw {
     pub fn new_from_current() -> Self {
-        let gdtr = Self::sgdt();
+        let gdtr = sgdt();
 
         let gdt =
             unsafe { core::slice::from_raw_parts(gdtr.base, usize::from(gdtr.limit + 1) / 8) }
                 .to_vec();
 
-        let tr = unsafe { tr() };
+        let tr = tr();
         let tr = if tr.bits() == 0 { None } else { Some(tr) };
 
         let tss = if let Some(tr) = tr {
@@ -62,7 +171,13 @@ impl GdtTssRaw {
         };
 
         let cs = cs();
-        Self { gdt, cs, tss, tr }
+        Self {
+            gdt,
+            cs,
+            tss,
+            tr,
+            ist_stacks: [None; IST_ENTRY_COUNT],
+        }
     }
 
     pub fn append_tss(&mut self, tss: TaskStateSegment) -> &Self {
@@ -81,16 +196,35 @@ impl GdtTssRaw {
         self
     }
 
+    /// Returns the addresses of the guard pages of the IST stacks.
+    pub(crate) fn guard_pages(&self) -> impl Iterator<Item = u64> + '_ {
+        self.ist_stacks
+            .iter()
+            .flatten()
+            .map(|stack| stack.guard_page)
+    }
+
+    /// Updates the TSS descriptor to point to this copy of the TSS.
+    fn update_tss_descriptor(&mut self) {
+        // The TSS descriptor is 16 bytes long, and its upper 8 bytes contain
+        // bits 63:32 of the base address.
+        // See: 8.2.3 TSS Descriptor in 64-bit mode
+        let tss = self.tss.as_ref().unwrap();
+        let index = usize::from(self.tr.unwrap().index());
+        self.gdt[index] = Self::task_segment_descriptor(tss).as_u64();
+        self.gdt[index + 1] = tss as *const _ as u64 >> 32;
+    }
+
     pub fn apply(&self) -> Result<(), GdtTssError> {
-        if unsafe { tr() }.bits() != 0 {
+        if tr().bits() != 0 {
             return Err(GdtTssError::TssAlreadyInUse);
         }
 
         let gdtr = Gdtr::new_from_slice(&self.gdt);
-        unsafe { lgdt(&gdtr) };
+        lgdt(&gdtr);
 
         if let Some(tr) = self.tr {
-            unsafe { load_tr(tr) };
+            ltr(tr);
         }
 
         Ok(())
@@ -106,10 +240,90 @@ impl GdtTssRaw {
             .dpl(x86::Ring::Ring0)
             .finish()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::test_support::with_cpu;
+
+    /// The null, 64-bit code and data descriptors.
+    const GDT: [u64; 3] = [0, 0x00af_9b00_0000_ffff, 0x00cf_9300_0000_ffff];
+
+    /// Makes the mock processor use `gdt` and `tr`.
+    fn load(gdt: &[u64], tr: u16) {
+        with_cpu(|cpu| {
+            cpu.gdtr_base = gdt.as_ptr() as u64;
+            cpu.gdtr_limit = (gdt.len() * 8 - 1) as u16;
+            cpu.tr = tr;
+        });
+    }
+
+    /// Returns the base address of the TSS descriptor at `index`.
+    fn tss_base(gdt: &[u64], index: usize) -> u64 {
+        let low = gdt[index];
+        ((low >> 16) & 0xff_ffff) | ((low >> 56) << 24) | (gdt[index + 1] << 32)
+    }
+
+    #[test]
+    fn build_appends_tss() {
+        let gdt = GDT.to_vec();
+        load(&gdt, 0);
+
+        let stack = IstStack::new(1);
+        let gdt_tss = GdtTss::builder().ist(2, stack).build();
+        assert_eq!(gdt_tss.gdt[..3], GDT);
+        assert_eq!(gdt_tss.gdt.len(), 5);
+        assert_eq!(gdt_tss.tr.unwrap().index(), 3);
+
+        let tss = gdt_tss.tss.as_ref().unwrap();
+        let ist = tss.ist;
+        assert_eq!(ist, [0, stack.top, 0, 0, 0, 0, 0]);
+        assert_eq!(tss_base(&gdt_tss.gdt, 3), tss as *const _ as u64);
+        assert_eq!(
+            gdt_tss.guard_pages().collect::<Vec<_>>(),
+            [stack.guard_page]
+        );
+    }
+
+    #[test]
+    fn build_copies_existing_tss() {
+        let mut tss = Box::new(TaskStateSegment::new());
+        tss.rsp[0] = 0x1234_5000;
+        let mut gdt = GDT.to_vec();
+        gdt.push(GdtTssRaw::task_segment_descriptor(&tss).as_u64());
+        gdt.push(&*tss as *const _ as u64 >> 32);
+        load(&gdt, 3 << 3);
+
+        let gdt_tss = GdtTss::builder().host_exception_stacks().build();
+        assert_eq!(gdt_tss.gdt.len(), 5);
+        assert_eq!(gdt_tss.tr.unwrap().index(), 3);
+
+        let copy = gdt_tss.tss.as_ref().unwrap();
+        let rsp = copy.rsp;
+        assert_eq!(rsp[0], 0x1234_5000);
+        assert_eq!(tss_base(&gdt_tss.gdt, 3), copy as *const _ as u64);
+        assert_eq!(gdt_tss.guard_pages().count(), 3);
+
+        // The original GDT and TSS are unchanged.
+        assert_eq!(tss_base(&gdt, 3), &*tss as *const _ as u64);
+        let ist = tss.ist;
+        assert_eq!(ist, [0; IST_ENTRY_COUNT]);
+    }
+
+    #[test]
+    fn apply_loads_gdt_and_tr() {
+        let gdt = GDT.to_vec();
+        load(&gdt, 0);
+        let gdt_tss = GdtTss::builder().build();
+        gdt_tss.apply().unwrap();
+        with_cpu(|cpu| {
+            assert_eq!(cpu.gdtr_base, gdt_tss.gdt.as_ptr() as u64);
+            assert_eq!(cpu.gdtr_limit, 5 * 8 - 1);
+            assert_eq!(cpu.tr, 3 << 3);
+        });
 
-    fn sgdt() -> Gdtr {
-        let mut gdtr = Gdtr::default();
-        unsafe { x86::dtables::sgdt(&mut gdtr) };
-        gdtr
+        // Applying again fails, as the TSS is in use.
+        assert!(matches!(gdt_tss.apply(), Err(GdtTssError::TssAlreadyInUse)));
     }
 }
@@ -0,0 +1,811 @@
+//! This module implements translation of guest virtual addresses (GVAs) to
+//! guest physical addresses (GPAs) by walking the guest paging structures, and
+//! access to guest memory on top of it.
+//!
+//! Only 4-level and 5-level paging, that is, 64-bit mode and compatibility
+//! mode, are supported, in addition to paging being disabled, as in real mode,
+//! where GVAs are GPAs.
+//!
+//! [`read_guest`] and [`write_guest`] access guest memory regardless of the
+//! guest permissions, for the host inspecting the guest. [`read_guest_checked`]
+//! and [`write_guest_checked`] instead act as the current guest instruction
+//! would, honoring the U/S and R/W flags, CR0.WP and SMAP, so that emulating an
+//! instruction or writing results of a hypercall never writes to pages the
+//! guest itself could not, such as user pages from the kernel under SMAP.
+//!
+//! The host accesses the guest paging structures and memory at the GPAs the
+//! EPT (Intel) or NPT (AMD) maps to the same PAs, as checked with
+//! `Vcpu::resolve_gpa`. The other pages, such as the ones hidden with
+//! `hidden_memory`, are not accessed. Paging structure entries in them read as
+//! not present.
+
+use core::ops::Range;
+
+use bit_field::BitField;
+use x86::{
+    bits64::paging::{BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE},
+    controlregs::{Cr0, Cr4},
+    cpuid::cpuid,
+};
+
+use crate::hypervisor::{
+    event::{self, Event},
+    host::{GpaMapping, Vcpu},
+    host_window,
+    x86_instructions::{clac, cr4, stac},
+};
+
+/// The guest register values that control address translation.
+#[derive(Clone, Copy, Debug)]
+pub struct PagingContext {
+    /// The guest CR0.
+    pub cr0: u64,
+    /// The guest CR3. When CR4.PCIDE is set, bits 11:0 are the PCID and are
+    /// not part of the address of the top level paging structure.
+    pub cr3: u64,
+    /// The guest CR4.
+    pub cr4: u64,
+    /// The guest IA32_EFER.
+    pub efer: u64,
+    /// The physical-address width of the processor (MAXPHYADDR).
+    pub max_phys_addr_bits: u8,
+}
+
+impl PagingContext {
+    /// Returns the context for the current state of `vcpu`.
+    pub fn from_vcpu(vcpu: &dyn Vcpu) -> Self {
+        Self {
+            cr0: vcpu.cr0(),
+            cr3: vcpu.cr3(),
+            cr4: vcpu.cr4(),
+            efer: vcpu.efer(),
+            // See: Table 1-17. Information Returned by CPUID Instruction
+            max_phys_addr_bits: cpuid!(0x8000_0008).eax.get_bits(0..=7) as u8,
+        }
+    }
+}
+
+/// The sizes of pages a GVA can be mapped with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    /// 4KB page mapped by a PTE.
+    Size4Kb,
+    /// 2MB page mapped by a PDE.
+    Size2Mb,
+    /// 1GB page mapped by a PDPTE.
+    Size1Gb,
+}
+
+/// The result of successful translation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Translation {
+    /// The GPA the GVA translates to.
+    pub gpa: u64,
+    /// The size of the page that maps the GVA.
+    pub page_size: PageSize,
+    /// Whether the R/W flags are set at all levels.
+    pub writable: bool,
+    /// Whether the U/S flags are set at all levels.
+    pub user: bool,
+    /// Whether the XD flag is set at any level while IA32_EFER.NXE is set.
+    pub no_execute: bool,
+}
+
+/// The errors translation may return. `level` is 1 for a PTE, 2 for a PDE, 3
+/// for a PDPTE, 4 for a PML4E and 5 for a PML5E.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TranslationError {
+    #[error("the guest is in 32-bit or PAE paging, which is unsupported")]
+    UnsupportedPagingMode,
+
+    #[error("{gva:#x?} is not canonical")]
+    NonCanonical { gva: u64 },
+
+    #[error("{gva:#x?} is not present at level {level}")]
+    NotPresent { gva: u64, level: u8 },
+
+    #[error("{gva:#x?} has reserved bits set at level {level}")]
+    ReservedBit { gva: u64, level: u8 },
+
+    #[error("{gva:#x?} is not accessible with the guest permissions")]
+    AccessDenied { gva: u64 },
+
+    #[error("{gpa:#x?} is not mapped to the same PA for the guest")]
+    Inaccessible { gpa: u64 },
+}
+
+/// An access to guest memory the host makes on behalf of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestAccess {
+    /// Whether the access writes to memory.
+    pub write: bool,
+    /// Whether the access is a user-mode access, that is, made at CPL 3.
+    pub user: bool,
+    /// Whether RFLAGS.AC is set, which allows supervisor-mode access to user
+    /// pages under SMAP.
+    pub alignment_check: bool,
+}
+
+impl GuestAccess {
+    /// Returns the access the current guest instruction of `vcpu` makes.
+    pub fn from_vcpu(vcpu: &mut dyn Vcpu, write: bool) -> Self {
+        const RFLAGS_AC: u64 = 1 << 18;
+
+        Self {
+            write,
+            user: vcpu.cpl() == 3,
+            alignment_check: vcpu.regs().rflags & RFLAGS_AC != 0,
+        }
+    }
+}
+
+/// Returns whether `access` to the page of `translation` is permitted under
+/// `context`, as the processor would check for a data access.
+// See: 4.6.1 Determination of Access Rights
+pub fn is_permitted(
+    context: &PagingContext,
+    translation: &Translation,
+    access: GuestAccess,
+) -> bool {
+    let cr0 = Cr0::from_bits_truncate(context.cr0 as _);
+    let cr4 = Cr4::from_bits_truncate(context.cr4 as _);
+    if !cr0.contains(Cr0::CR0_ENABLE_PAGING) {
+        return true;
+    }
+
+    if access.user {
+        return translation.user && (!access.write || translation.writable);
+    }
+    if translation.user && cr4.contains(Cr4::CR4_ENABLE_SMAP) && !access.alignment_check {
+        return false;
+    }
+    !access.write || translation.writable || !cr0.contains(Cr0::CR0_WRITE_PROTECT)
+}
+
+/// Translates `gva` to a GPA under `context`. `read_entry` is called with the
+/// GPA of each paging structure entry to walk and returns its value.
+///
+/// This function does not update the accessed and dirty flags, nor does it
+/// check permissions. Callers should check the returned permissions
+/// themselves as needed.
+///
+/// # Errors
+///
+/// Returns `Err` if the guest paging mode is unsupported, `gva` is not
+/// canonical, or any entry to walk is not present or has reserved bits set.
+// See: 4.1.1 Four Paging Modes
+// See: 4.5 4-Level Paging and 5-Level Paging
+pub fn translate(
+    context: &PagingContext,
+    gva: u64,
+    mut read_entry: impl FnMut(u64) -> u64,
+) -> Result<Translation, TranslationError> {
+    const PRESENT: usize = 0;
+    const WRITABLE: usize = 1;
+    const USER: usize = 2;
+    const PAGE_SIZE: usize = 7;
+    const EXECUTE_DISABLE: usize = 63;
+
+    let cr0 = Cr0::from_bits_truncate(context.cr0 as _);
+    let cr4 = Cr4::from_bits_truncate(context.cr4 as _);
+    let efer_lma = context.efer.get_bit(10);
+    let efer_nxe = context.efer.get_bit(11);
+    // Without paging, linear addresses are 32 bits and used as physical
+    // addresses with no protection.
+    if !cr0.contains(Cr0::CR0_ENABLE_PAGING) {
+        return Ok(Translation {
+            gpa: gva & u64::from(u32::MAX),
+            page_size: PageSize::Size4Kb,
+            writable: true,
+            user: true,
+            no_execute: false,
+        });
+    }
+    if !efer_lma {
+        return Err(TranslationError::UnsupportedPagingMode);
+    }
+
+    let levels: u8 = if cr4.contains(Cr4::CR4_ENABLE_LA57) {
+        5
+    } else {
+        4
+    };
+    let va_bits = 12 + 9 * u32::from(levels);
+    let sign_extension = (gva as i64) >> (va_bits - 1);
+    if sign_extension != 0 && sign_extension != -1 {
+        return Err(TranslationError::NonCanonical { gva });
+    }
+
+    // Bits 51:MAXPHYADDR are reserved in all entries, and so is the XD flag
+    // unless IA32_EFER.NXE is set.
+    let phys_addr_limit = 1u64 << context.max_phys_addr_bits;
+    let addr_mask = (phys_addr_limit - 1) & !(BASE_PAGE_SIZE as u64 - 1);
+    let mut reserved_mask = ((1u64 << 52) - 1) & !(phys_addr_limit - 1);
+    if !efer_nxe {
+        reserved_mask |= 1 << EXECUTE_DISABLE;
+    }
+
+    // CR3 bits 11:0 are either PCID or the PWT and PCD flags. Neither is an
+    // address bit.
+    let mut table = context.cr3 & addr_mask;
+    let mut writable = true;
+    let mut user = true;
+    let mut no_execute = false;
+    for level in (1..=levels).rev() {
+        let shift = 12 + 9 * u32::from(level - 1);
+        let index = (gva >> shift) & 0x1ff;
+        let entry = read_entry(table + index * 8);
+        if !entry.get_bit(PRESENT) {
+            return Err(TranslationError::NotPresent { gva, level });
+        }
+
+        let is_leaf = level == 1 || ((level == 2 || level == 3) && entry.get_bit(PAGE_SIZE));
+        let mut reserved = reserved_mask;
+        if level >= 4 {
+            // The PS flag is reserved in PML4Es and PML5Es.
+            reserved |= 1 << PAGE_SIZE;
+        } else if is_leaf && level != 1 {
+            // Bits 29:13 (1GB) or 20:13 (2MB) are reserved. Bit 12 is PAT.
+            reserved |= ((1u64 << shift) - 1) & !((1u64 << 13) - 1);
+        }
+        if entry & reserved != 0 {
+            return Err(TranslationError::ReservedBit { gva, level });
+        }
+
+        writable &= entry.get_bit(WRITABLE);
+        user &= entry.get_bit(USER);
+        no_execute |= efer_nxe && entry.get_bit(EXECUTE_DISABLE);
+
+        if is_leaf {
+            let (page_size, size) = match level {
+                1 => (PageSize::Size4Kb, BASE_PAGE_SIZE),
+                2 => (PageSize::Size2Mb, LARGE_PAGE_SIZE),
+                _ => (PageSize::Size1Gb, HUGE_PAGE_SIZE),
+            };
+            let offset_mask = size as u64 - 1;
+            return Ok(Translation {
+                gpa: (entry & addr_mask & !offset_mask) | (gva & offset_mask),
+                page_size,
+                writable,
+                user,
+                no_execute,
+            });
+        }
+        table = entry & addr_mask;
+    }
+    unreachable!()
+}
+
+/// Translates `gva` under the current guest paging structures of `vcpu`. Must
+/// be called from the host, such as VM-exit handlers.
+///
+/// # Errors
+///
+/// Returns `Err` if `gva` cannot be translated.
+pub fn translate_guest(vcpu: &dyn Vcpu, gva: u64) -> Result<Translation, TranslationError> {
+    translate(&PagingContext::from_vcpu(vcpu), gva, |gpa| {
+        read_entry(vcpu, gpa)
+    })
+}
+
+/// Reads guest memory at `gva` into `buffer` under the current guest paging
+/// structures of `vcpu`. Must be called from the host, such as VM-exit handlers.
+///
+/// # Errors
+///
+/// Returns `Err` if any page in the range cannot be translated. `buffer` may be
+/// partially filled in that case.
+pub fn read_guest(vcpu: &dyn Vcpu, gva: u64, buffer: &mut [u8]) -> Result<(), TranslationError> {
+    for_each_page(vcpu, gva, buffer.len(), None, |host_va, range| {
+        let src = unsafe { core::slice::from_raw_parts(host_va, range.len()) };
+        buffer[range].copy_from_slice(src);
+    })
+}
+
+/// Writes `data` to guest memory at `gva` under the current guest paging
+/// structures of `vcpu`. Must be called from the host, such as VM-exit handlers.
+///
+/// The guest permissions are not checked, so read-only pages are written too.
+///
+/// # Errors
+///
+/// Returns `Err` if any page in the range cannot be translated. No memory is
+/// written in that case.
+pub fn write_guest(vcpu: &dyn Vcpu, gva: u64, data: &[u8]) -> Result<(), TranslationError> {
+    // Translate all pages first, so that either all or none of `data` is
+    // written.
+    for_each_page(vcpu, gva, data.len(), None, |_, _| {})?;
+    for_each_page(vcpu, gva, data.len(), None, |host_va, range| {
+        let dst = unsafe { core::slice::from_raw_parts_mut(host_va, range.len()) };
+        dst.copy_from_slice(&data[range]);
+    })
+}
+
+/// Does the same as `read_guest`, but only if the current guest instruction of
+/// `vcpu` is permitted to read all pages in the range.
+///
+/// # Errors
+///
+/// Returns `Err` if any page in the range cannot be translated, or
+/// `TranslationError::AccessDenied` if the guest is not permitted to read it.
+pub fn read_guest_checked(
+    vcpu: &mut dyn Vcpu,
+    gva: u64,
+    buffer: &mut [u8],
+) -> Result<(), TranslationError> {
+    let access = GuestAccess::from_vcpu(vcpu, false);
+    for_each_page(vcpu, gva, buffer.len(), Some(access), |host_va, range| {
+        let src = unsafe { core::slice::from_raw_parts(host_va, range.len()) };
+        buffer[range].copy_from_slice(src);
+    })
+}
+
+/// Does the same as `write_guest`, but only if the current guest instruction
+/// of `vcpu` is permitted to write to all pages in the range.
+///
+/// # Errors
+///
+/// Returns `Err` if any page in the range cannot be translated, or
+/// `TranslationError::AccessDenied` if the guest is not permitted to write to
+/// it. No memory is written in either case.
+pub fn write_guest_checked(
+    vcpu: &mut dyn Vcpu,
+    gva: u64,
+    data: &[u8],
+) -> Result<(), TranslationError> {
+    let access = GuestAccess::from_vcpu(vcpu, true);
+    for_each_page(vcpu, gva, data.len(), Some(access), |_, _| {})?;
+    for_each_page(vcpu, gva, data.len(), Some(access), |host_va, range| {
+        let dst = unsafe { core::slice::from_raw_parts_mut(host_va, range.len()) };
+        dst.copy_from_slice(&data[range]);
+    })
+}
+
+/// Calls `callback` for each page in `len` bytes from `gva`, with the host
+/// linear address of the part in the page and the range of the part within the
+/// `len` bytes. Each page must permit `access` if specified.
+fn for_each_page(
+    vcpu: &dyn Vcpu,
+    gva: u64,
+    len: usize,
+    access: Option<GuestAccess>,
+    mut callback: impl FnMut(*mut u8, Range<usize>),
+) -> Result<(), TranslationError> {
+    let id = vcpu.id();
+    let context = PagingContext::from_vcpu(vcpu);
+    let mut done = 0;
+    while done < len {
+        let current = gva.wrapping_add(done as u64);
+        let size = (len - done).min(BASE_PAGE_SIZE - (current as usize % BASE_PAGE_SIZE));
+        let translation = translate(&context, current, |gpa| read_entry(vcpu, gpa))?;
+        if access.is_some_and(|access| !is_permitted(&context, &translation, access)) {
+            return Err(TranslationError::AccessDenied { gva: current });
+        }
+
+        // The permissions in the EPT or NPT are not checked, as the caller may
+        // access memory the guest cannot, such as when emulating a write to a
+        // protected page.
+        if vcpu.resolve_gpa(translation.gpa).is_none() {
+            return Err(TranslationError::Inaccessible {
+                gpa: translation.gpa,
+            });
+        }
+        let host_va = host_window::map(id, translation.gpa);
+        let _guard = UserAccessGuard::new();
+        callback(host_va, done..done + size);
+        done += size;
+    }
+    Ok(())
+}
+
+/// Reads the guest paging structure entry at `gpa`, or returns 0, that is, not
+/// present, if the host does not access the page. See the module documentation.
+fn read_entry(vcpu: &dyn Vcpu, gpa: u64) -> u64 {
+    if vcpu.resolve_gpa(gpa).is_none() {
+        return 0;
+    }
+    let entry = host_window::map(vcpu.id(), gpa).cast::<u64>();
+    let _guard = UserAccessGuard::new();
+    // Safety: the window maps the guest page the entry is in.
+    unsafe { entry.read_volatile() }
+}
+
+/// Returns whether the page containing `gpa` is RAM the guest can read and
+/// write, and the host accesses at the same PA, such as for the structures the
+/// guest gives the host to fill.
+pub(crate) fn is_writable_ram(vcpu: &dyn Vcpu, gpa: u64) -> bool {
+    vcpu.resolve_gpa(gpa)
+        .is_some_and(|mapping| mapping.ram && mapping.permissions.write)
+}
+
+/// Reads guest memory at the GPA `gpa` into `buffer`, with each page checked
+/// with `resolve` as `Vcpu::resolve_gpa` would, for the structures the guest
+/// refers to by GPAs, such as the ones of a nested guest.
+///
+/// # Errors
+///
+/// Returns `Inaccessible` if any page in the range is not mapped to the same
+/// PA for the guest. `buffer` may be partially filled in that case.
+pub(crate) fn read_physical(
+    id: usize,
+    gpa: u64,
+    buffer: &mut [u8],
+    resolve: impl Fn(u64) -> Option<GpaMapping>,
+) -> Result<(), TranslationError> {
+    for_each_physical_page(id, gpa, buffer.len(), resolve, |host_va, range| {
+        let src = unsafe { core::slice::from_raw_parts(host_va, range.len()) };
+        buffer[range].copy_from_slice(src);
+    })
+}
+
+/// Writes `data` to guest memory at the GPA `gpa`. See `read_physical`.
+///
+/// # Errors
+///
+/// Returns `Inaccessible` as `read_physical` does. No memory is written in
+/// that case.
+pub(crate) fn write_physical(
+    id: usize,
+    gpa: u64,
+    data: &[u8],
+    resolve: impl Fn(u64) -> Option<GpaMapping>,
+) -> Result<(), TranslationError> {
+    for_each_physical_page(id, gpa, data.len(), &resolve, |_, _| {})?;
+    for_each_physical_page(id, gpa, data.len(), &resolve, |host_va, range| {
+        let dst = unsafe { core::slice::from_raw_parts_mut(host_va, range.len()) };
+        dst.copy_from_slice(&data[range]);
+    })
+}
+
+/// Calls `callback` for each page in `len` bytes from the GPA `gpa` like
+/// `for_each_page`.
+fn for_each_physical_page(
+    id: usize,
+    gpa: u64,
+    len: usize,
+    resolve: impl Fn(u64) -> Option<GpaMapping>,
+    mut callback: impl FnMut(*mut u8, Range<usize>),
+) -> Result<(), TranslationError> {
+    let mut done = 0;
+    while done < len {
+        let current = gpa.wrapping_add(done as u64);
+        let size = (len - done).min(BASE_PAGE_SIZE - (current as usize % BASE_PAGE_SIZE));
+        if resolve(current).is_none() {
+            return Err(TranslationError::Inaccessible { gpa: current });
+        }
+        let host_va = host_window::map(id, current);
+        let _guard = UserAccessGuard::new();
+        callback(host_va, done..done + size);
+        done += size;
+    }
+    Ok(())
+}
+
+/// Injects the exception the processor would raise for `err` from the guest
+/// memory access of the current instruction, which writes to memory if
+/// `write`. Returns `false` if no exception is injected.
+pub(crate) fn inject_fault(vcpu: &mut dyn Vcpu, err: &TranslationError, write: bool) -> bool {
+    // See: 4.7 Page-Fault Exceptions
+    const PF_PRESENT: u32 = 1 << 0;
+    const PF_WRITE: u32 = 1 << 1;
+    const PF_USER: u32 = 1 << 2;
+    const PF_RESERVED: u32 = 1 << 3;
+    const GP_VECTOR: u8 = 13;
+
+    let mut error_code = if write { PF_WRITE } else { 0 };
+    if vcpu.cpl() == 3 {
+        error_code |= PF_USER;
+    }
+    let gp = Event::Exception {
+        vector: GP_VECTOR,
+        error_code: Some(0),
+    };
+    let result = match *err {
+        TranslationError::NotPresent { gva, .. } => event::inject_page_fault(vcpu, gva, error_code),
+        TranslationError::ReservedBit { gva, .. } => {
+            event::inject_page_fault(vcpu, gva, error_code | PF_PRESENT | PF_RESERVED)
+        }
+        // The host does not access the pages hidden from the guest.
+        TranslationError::NonCanonical { .. } | TranslationError::Inaccessible { .. } => {
+            event::inject_event(vcpu, gp)
+        }
+        TranslationError::AccessDenied { gva } => {
+            event::inject_page_fault(vcpu, gva, error_code | PF_PRESENT)
+        }
+        // The address cannot be translated under 32-bit or PAE paging, and the
+        // access cannot be completed. Fail the instruction instead of skipping
+        // it, which the guest would be unaware of.
+        TranslationError::UnsupportedPagingMode => {
+            log::warn!("Failing a memory access under an unsupported paging mode");
+            event::inject_event(vcpu, gp)
+        }
+    };
+    if let Err(err) = result {
+        log::error!("Failed to inject an exception: {err}");
+        return false;
+    }
+    true
+}
+
+/// Allows supervisor-mode access to user pages while alive, if SMAP is enabled
+/// in the host.
+///
+/// The host CR4, copied from the guest, has SMAP enabled if the guest does.
+/// Every dereference of guest memory mapped with `host_window` is made while
+/// one is alive, so that it does not fault regardless of how the host paging
+/// structures map the memory. SMAP is in effect again right after the access,
+/// so the rest of the host still faults on accidental access to user pages.
+pub(crate) struct UserAccessGuard {
+    smap: bool,
+}
+
+impl UserAccessGuard {
+    pub(crate) fn new() -> Self {
+        // STAC and CLAC cause #UD without SMAP support, which CR4.SMAP being
+        // set implies.
+        let smap = cr4().contains(Cr4::CR4_ENABLE_SMAP);
+        if smap {
+            stac();
+        }
+        Self { smap }
+    }
+}
+
+impl Drop for UserAccessGuard {
+    fn drop(&mut self) {
+        if self.smap {
+            clac();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+
+    use super::*;
+    use crate::hypervisor::test_support::with_cpu;
+
+    const CR0_PG: u64 = 1 << 31;
+    const CR4_LA57: u64 = 1 << 12;
+    const CR4_PCIDE: u64 = 1 << 17;
+    const EFER_LMA_NXE: u64 = (1 << 10) | (1 << 11);
+    const P_RW: u64 = 0b11;
+    const P_RW_US: u64 = 0b111;
+    const PS: u64 = 1 << 7;
+    const XD: u64 = 1 << 63;
+
+    fn context(cr3: u64, cr4: u64) -> PagingContext {
+        PagingContext {
+            cr0: CR0_PG,
+            cr3,
+            cr4,
+            efer: EFER_LMA_NXE,
+            max_phys_addr_bits: 39,
+        }
+    }
+
+    /// Returns the GPA of the entry indexed by the `level` bits of `gva` in
+    /// the table at `table`.
+    fn entry_gpa(table: u64, gva: u64, level: u32) -> u64 {
+        table + ((gva >> (12 + 9 * (level - 1))) & 0x1ff) * 8
+    }
+
+    #[test]
+    fn translate_4kb() {
+        let gva = 0xffff_f805_1234_5678;
+        let mut memory = BTreeMap::new();
+        let _ = memory.insert(entry_gpa(0x1000, gva, 4), 0x2000 | P_RW_US);
+        let _ = memory.insert(entry_gpa(0x2000, gva, 3), 0x3000 | P_RW_US);
+        let _ = memory.insert(entry_gpa(0x3000, gva, 2), 0x4000 | P_RW);
+        let _ = memory.insert(entry_gpa(0x4000, gva, 1), 0xab_c000 | P_RW_US | XD);
+
+        // PCID in CR3 bits 11:0 must be ignored.
+        let translation = translate(&context(0x1000 | 0x123, CR4_PCIDE), gva, |gpa| {
+            memory.get(&gpa).copied().unwrap_or(0)
+        })
+        .unwrap();
+        assert_eq!(
+            translation,
+            Translation {
+                gpa: 0xab_c678,
+                page_size: PageSize::Size4Kb,
+                writable: true,
+                user: false,
+                no_execute: true,
+            }
+        );
+    }
+
+    #[test]
+    fn translate_large_pages() {
+        let gva = 0x7fff_1234_5678;
+        let mut memory = BTreeMap::new();
+        let _ = memory.insert(entry_gpa(0x1000, gva, 4), 0x2000 | P_RW);
+        let _ = memory.insert(entry_gpa(0x2000, gva, 3), 0x3000 | P_RW);
+        let _ = memory.insert(entry_gpa(0x3000, gva, 2), 0x4000_0000 | P_RW | PS);
+        let read = |gpa| memory.get(&gpa).copied().unwrap_or(0);
+
+        let translation = translate(&context(0x1000, 0), gva, read).unwrap();
+        assert_eq!(translation.gpa, 0x4014_5678);
+        assert_eq!(translation.page_size, PageSize::Size2Mb);
+
+        // 1GB page.
+        let _ = memory.insert(entry_gpa(0x2000, gva, 3), 0x40_0000_0000 | P_RW | PS);
+        let read = |gpa| memory.get(&gpa).copied().unwrap_or(0);
+        let translation = translate(&context(0x1000, 0), gva, read).unwrap();
+        assert_eq!(translation.gpa, 0x40_1234_5678);
+        assert_eq!(translation.page_size, PageSize::Size1Gb);
+
+        // Bits 29:13 are reserved for 1GB pages.
+        let _ = memory.insert(entry_gpa(0x2000, gva, 3), 0x40_0000_2000 | P_RW | PS);
+        let read = |gpa| memory.get(&gpa).copied().unwrap_or(0);
+        assert_eq!(
+            translate(&context(0x1000, 0), gva, read),
+            Err(TranslationError::ReservedBit { gva, level: 3 })
+        );
+
+        // So are bits above MAXPHYADDR.
+        let _ = memory.insert(entry_gpa(0x2000, gva, 3), 0x80_0000_0000 | P_RW | PS);
+        let read = |gpa| memory.get(&gpa).copied().unwrap_or(0);
+        assert_eq!(
+            translate(&context(0x1000, 0), gva, read),
+            Err(TranslationError::ReservedBit { gva, level: 3 })
+        );
+    }
+
+    #[test]
+    fn translate_5_level() {
+        let gva = 0x00ff_0000_0000_1234;
+        let mut memory = BTreeMap::new();
+        let _ = memory.insert(entry_gpa(0x1000, gva, 5), 0x2000 | P_RW);
+        let _ = memory.insert(entry_gpa(0x2000, gva, 4), 0x3000 | P_RW);
+        let _ = memory.insert(entry_gpa(0x3000, gva, 3), 0x4000 | P_RW);
+        let _ = memory.insert(entry_gpa(0x4000, gva, 2), 0x5000 | P_RW);
+        let _ = memory.insert(entry_gpa(0x5000, gva, 1), 0x6000 | P_RW);
+        let read = |gpa| memory.get(&gpa).copied().unwrap_or(0);
+
+        let translation = translate(&context(0x1000, CR4_LA57), gva, read).unwrap();
+        assert_eq!(translation.gpa, 0x6234);
+
+        // The same address is not canonical with 4-level paging.
+        let translation = translate(&context(0x1000, 0), gva, read);
+        assert_eq!(translation, Err(TranslationError::NonCanonical { gva }));
+    }
+
+    #[test]
+    fn translate_errors() {
+        let gva = 0x1000;
+        let mut memory = BTreeMap::new();
+        let _ = memory.insert(entry_gpa(0x1000, gva, 4), 0x2000 | P_RW);
+        let read = |gpa| memory.get(&gpa).copied().unwrap_or(0);
+        assert_eq!(
+            translate(&context(0x1000, 0), gva, read),
+            Err(TranslationError::NotPresent { gva, level: 3 })
+        );
+
+        // PS is reserved in PML4Es.
+        let _ = memory.insert(entry_gpa(0x1000, gva, 4), 0x2000 | P_RW | PS);
+        let read = |gpa| memory.get(&gpa).copied().unwrap_or(0);
+        assert_eq!(
+            translate(&context(0x1000, 0), gva, read),
+            Err(TranslationError::ReservedBit { gva, level: 4 })
+        );
+
+        // XD is reserved without IA32_EFER.NXE.
+        let _ = memory.insert(entry_gpa(0x1000, gva, 4), 0x2000 | P_RW | XD);
+        let mut context = context(0x1000, 0);
+        context.efer = 1 << 10;
+        let read = |gpa| memory.get(&gpa).copied().unwrap_or(0);
+        assert_eq!(
+            translate(&context, gva, read),
+            Err(TranslationError::ReservedBit { gva, level: 4 })
+        );
+
+        context.efer = 0;
+        assert_eq!(
+            translate(&context, gva, read),
+            Err(TranslationError::UnsupportedPagingMode)
+        );
+    }
+
+    #[test]
+    fn translate_without_paging() {
+        let mut context = context(0x1000, 0);
+        context.cr0 = 0;
+        context.efer = 0;
+        let translation = translate(&context, 0x1_0000_7c00, |_| unreachable!()).unwrap();
+        assert_eq!(translation.gpa, 0x7c00);
+        assert!(translation.writable && translation.user && !translation.no_execute);
+    }
+
+    #[test]
+    fn permissions_are_checked() {
+        const CR0_WP: u64 = 1 << 16;
+        const CR4_SMAP: u64 = 1 << 21;
+
+        let page = |writable, user| Translation {
+            gpa: 0x1000,
+            page_size: PageSize::Size4Kb,
+            writable,
+            user,
+            no_execute: false,
+        };
+        let access = |write, user, alignment_check| GuestAccess {
+            write,
+            user,
+            alignment_check,
+        };
+        let mut context = context(0x1000, CR4_SMAP);
+        context.cr0 |= CR0_WP;
+
+        // User-mode access requires U/S, and R/W to write.
+        assert!(is_permitted(
+            &context,
+            &page(true, true),
+            access(true, true, false)
+        ));
+        assert!(!is_permitted(
+            &context,
+            &page(true, false),
+            access(false, true, false)
+        ));
+        assert!(!is_permitted(
+            &context,
+            &page(false, true),
+            access(true, true, false)
+        ));
+
+        // Supervisor-mode access to user pages under SMAP requires RFLAGS.AC.
+        assert!(!is_permitted(
+            &context,
+            &page(true, true),
+            access(true, false, false)
+        ));
+        assert!(is_permitted(
+            &context,
+            &page(true, true),
+            access(true, false, true)
+        ));
+        context.cr4 = 0;
+        assert!(is_permitted(
+            &context,
+            &page(true, true),
+            access(false, false, false)
+        ));
+
+        // Supervisor-mode writes to read-only pages are permitted only without CR0.WP.
+        assert!(!is_permitted(
+            &context,
+            &page(false, false),
+            access(true, false, false)
+        ));
+        context.cr0 &= !CR0_WP;
+        assert!(is_permitted(
+            &context,
+            &page(false, false),
+            access(true, false, false)
+        ));
+
+        // Nothing is checked without paging.
+        context.cr0 = 0;
+        context.cr4 = CR4_SMAP;
+        assert!(is_permitted(
+            &context,
+            &page(true, true),
+            access(true, false, false)
+        ));
+    }
+
+    #[test]
+    fn user_access_is_allowed_only_within_guard() {
+        const CR4_SMAP: u64 = 1 << 21;
+
+        with_cpu(|cpu| cpu.cr4 = CR4_SMAP);
+        {
+            let _guard = UserAccessGuard::new();
+            assert!(with_cpu(|cpu| cpu.rflags_ac));
+        }
+        assert!(!with_cpu(|cpu| cpu.rflags_ac));
+    }
+}
@@ -0,0 +1,209 @@
+//! This module implements identification of the guest OS and its version, so
+//! that hooks can adjust offsets of OS structures per version. The guest is
+//! identified by reading well-known structures through `guest_memory`:
+//!
+//! - Windows: KUSER_SHARED_DATA, which is mapped at a fixed address in every
+//!   address space and holds the version numbers.
+//! - Linux: the banner, "Linux version X.Y.Z ...", which is searched in the
+//!   kernel image IA32_LSTAR points into.
+//!
+//! Identification fails until the guest kernel sets up these, for example,
+//! while the UEFI version runs before the OS boots.
+
+use spin::Once;
+
+use crate::hypervisor::{guest_memory, host::Vcpu};
+
+/// The guest OS and its version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestOs {
+    /// Windows, with the version in KUSER_SHARED_DATA.
+    Windows {
+        /// NtMajorVersion, for example, 10 for Windows 10 and 11.
+        major: u32,
+        /// NtMinorVersion.
+        minor: u32,
+        /// NtBuildNumber, for example, 22631 for Windows 11 23H2. Zero if the
+        /// version of Windows does not report it (before Windows 10).
+        build: u32,
+    },
+
+    /// Linux, with the version in the banner.
+    Linux {
+        /// The major version, for example, 6 for Linux 6.1.
+        major: u32,
+        /// The minor version.
+        minor: u32,
+        /// The patch level, or zero if the banner does not have it.
+        patch: u32,
+        /// The guest virtual address of the banner.
+        banner: u64,
+    },
+}
+
+/// Returns the guest OS identified on the first successful call, identifying it
+/// with `identify` under the current guest state of `vcpu` until then. Must be
+/// called from the host, such as VM-exit handlers.
+pub fn get(vcpu: &dyn Vcpu) -> Option<GuestOs> {
+    if let Some(os) = IDENTIFIED.get() {
+        return Some(*os);
+    }
+    let os = identify(vcpu)?;
+    Some(*IDENTIFIED.call_once(|| os))
+}
+
+/// Identifies the guest OS under the current guest state of `vcpu`. Must be
+/// called from the host, such as VM-exit handlers. Searching the Linux banner
+/// reads up to `LINUX_SEARCH_SIZE` bytes of guest memory, so cache the result
+/// or use `get`.
+pub fn identify(vcpu: &dyn Vcpu) -> Option<GuestOs> {
+    identify_windows(vcpu).or_else(|| identify_linux(vcpu))
+}
+
+/// The maximum number of bytes from the page IA32_LSTAR points to to search
+/// the Linux banner in.
+pub const LINUX_SEARCH_SIZE: usize = 64 * 1024 * 1024;
+
+fn identify_windows(vcpu: &dyn Vcpu) -> Option<GuestOs> {
+    // The kernel mode and user mode addresses of KUSER_SHARED_DATA. The user
+    // mode one is mapped in the address spaces of processes only.
+    const KUSER_SHARED_DATA: [u64; 2] = [0xffff_f780_0000_0000, 0x7ffe_0000];
+
+    let mut data = [0u8; KUSER_SHARED_DATA_SIZE];
+    KUSER_SHARED_DATA
+        .into_iter()
+        .find(|&gva| guest_memory::read_guest(vcpu, gva, &mut data).is_ok())
+        .and_then(|_| parse_kuser_shared_data(&data))
+}
+
+fn identify_linux(vcpu: &dyn Vcpu) -> Option<GuestOs> {
+    const CHUNK_SIZE: usize = 4096;
+
+    let lstar = vcpu.read_msr(x86::msr::IA32_LSTAR);
+    if lstar == 0 {
+        return None;
+    }
+
+    // Keep the end of the previous chunk to find the banner across chunks.
+    let start = lstar & !(CHUNK_SIZE as u64 - 1);
+    let mut buffer = [0u8; BANNER_MAX_LEN + CHUNK_SIZE];
+    for offset in (0..LINUX_SEARCH_SIZE).step_by(CHUNK_SIZE) {
+        let gva = start + offset as u64;
+        buffer.copy_within(CHUNK_SIZE.., 0);
+        guest_memory::read_guest(vcpu, gva, &mut buffer[BANNER_MAX_LEN..]).ok()?;
+        if let Some((index, (major, minor, patch))) = find_linux_banner(&buffer) {
+            let banner = (gva - BANNER_MAX_LEN as u64).wrapping_add(index as u64);
+            return Some(GuestOs::Linux {
+                major,
+                minor,
+                patch,
+                banner,
+            });
+        }
+    }
+    None
+}
+
+/// The size of KUSER_SHARED_DATA to read, up to NtBuildNumber.
+const KUSER_SHARED_DATA_SIZE: usize = 0x274;
+
+/// Returns the Windows version in `data`, the head of KUSER_SHARED_DATA, if it
+/// looks valid.
+// See: https://www.geoffchappell.com/studies/windows/km/ntoskrnl/inc/api/ntexapi_x/kuser_shared_data/index.htm
+fn parse_kuser_shared_data(data: &[u8; KUSER_SHARED_DATA_SIZE]) -> Option<GuestOs> {
+    const NT_BUILD_NUMBER: usize = 0x260;
+    const NT_MAJOR_VERSION: usize = 0x26c;
+    const NT_MINOR_VERSION: usize = 0x270;
+    const WINDOWS_VISTA_MAJOR: u32 = 6;
+    const WINDOWS_10_MAJOR: u32 = 10;
+
+    let read = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    let major = read(NT_MAJOR_VERSION);
+    let minor = read(NT_MINOR_VERSION);
+    if !(WINDOWS_VISTA_MAJOR..=WINDOWS_10_MAJOR).contains(&major) || minor > 3 {
+        return None;
+    }
+    let build = if major == WINDOWS_10_MAJOR {
+        read(NT_BUILD_NUMBER) & 0xffff
+    } else {
+        0
+    };
+    Some(GuestOs::Windows {
+        major,
+        minor,
+        build,
+    })
+}
+
+/// The prefix of the Linux banner, `linux_banner`.
+const BANNER_PREFIX: &[u8] = b"Linux version ";
+
+/// The maximum length of the prefix and the version in the banner to parse.
+const BANNER_MAX_LEN: usize = 64;
+
+/// Returns the index of the Linux banner in `bytes` and the version in it, if
+/// any. A prefix not followed by a version, such as in log messages, is
+/// skipped.
+fn find_linux_banner(bytes: &[u8]) -> Option<(usize, (u32, u32, u32))> {
+    (0..bytes.len().saturating_sub(BANNER_PREFIX.len()))
+        .filter(|&index| bytes[index..].starts_with(BANNER_PREFIX))
+        .find_map(|index| {
+            let version_start = index + BANNER_PREFIX.len();
+            let version_end = (index + BANNER_MAX_LEN).min(bytes.len());
+            parse_linux_version(&bytes[version_start..version_end]).map(|version| (index, version))
+        })
+}
+
+/// Parses "X.Y[.Z]" at the start of `bytes`, followed by any of a space, '-',
+/// '+' and '_'.
+fn parse_linux_version(bytes: &[u8]) -> Option<(u32, u32, u32)> {
+    let end = bytes
+        .iter()
+        .position(|&byte| matches!(byte, b' ' | b'-' | b'+' | b'_'))?;
+    let version = core::str::from_utf8(&bytes[..end]).ok()?;
+    let mut numbers = version.split('.').map(str::parse::<u32>);
+    let major = numbers.next()?.ok()?;
+    let minor = numbers.next()?.ok()?;
+    let patch = numbers.next().unwrap_or(Ok(0)).ok()?;
+    if numbers.next().is_some() {
+        return None;
+    }
+    Some((major, minor, patch))
+}
+
+static IDENTIFIED: Once<GuestOs> = Once::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_version_is_parsed() {
+        let mut data = [0u8; KUSER_SHARED_DATA_SIZE];
+        data[0x260..0x264].copy_from_slice(&0xf000_5867u32.to_le_bytes());
+        data[0x26c..0x270].copy_from_slice(&10u32.to_le_bytes());
+        assert_eq!(
+            parse_kuser_shared_data(&data),
+            Some(GuestOs::Windows {
+                major: 10,
+                minor: 0,
+                build: 22631,
+            })
+        );
+
+        data[0x26c..0x270].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(parse_kuser_shared_data(&data), None);
+    }
+
+    #[test]
+    fn linux_banner_is_found() {
+        let bytes = b"\0Linux version %s\0Linux version 6.1.0-13-amd64 (debian-kernel@";
+        assert_eq!(find_linux_banner(bytes), Some((18, (6, 1, 0))));
+        assert_eq!(
+            find_linux_banner(b"Linux version 5.15.133+ (build"),
+            Some((0, (5, 15, 133)))
+        );
+        assert_eq!(find_linux_banner(b"Linux version 6 (x)"), None);
+        assert_eq!(find_linux_banner(b"no banner"), None);
+    }
+}
@@ -0,0 +1,95 @@
+//! This module implements hiding of the memory of the hypervisor from the
+//! guest. Once requested, each processor maps the pages of the heap given to
+//! `allocator::init` and `allocator::extend` to a read-only, non-executable
+//! dummy page in the EPT (Intel) or NPT (AMD) on the next VM-exit. The heap
+//! holds nearly all data of the host, such as the stacks, the VMCS, VMCB and
+//! nested paging structures, so that the guest cannot find or patch them by
+//! scanning physical memory. Writes to the hidden pages are discarded, and
+//! execution of them causes #GP.
+//!
+//! The image of the hypervisor is not hidden, as the guest executes it, for
+//! example, to return from `virtualize_system`.
+//!
+//! The guest cannot use the heap once hidden, as it reads the dummy page
+//! instead. The APIs that use the heap from the guest, such as
+//! `HookManager::install` and `protect_gpa_range`, fail with
+//! [`is_hidden_from_caller`] then. The host still sees the heap.
+
+use alloc::vec::Vec;
+use spin::Once;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use crate::hypervisor::{
+    guest_memory::TranslationError,
+    host::{NestedPageFaultInfo, Vcpu},
+    instruction_decoder::{self, InstructionError},
+    is_our_hypervisor_present, memory_protection, platform_ops,
+};
+
+/// Requests hiding the heap from the guest. Must be called in the guest after
+/// all processors are virtualized, as the guest uses the heap until then.
+pub(crate) fn request() {
+    let _ = HIDDEN_PAGES.call_once(|| {
+        let ops = platform_ops::get();
+        let mut pages: Vec<u64> = heap_ranges()
+            .into_iter()
+            .flat_map(|heap| heap.step_by(BASE_PAGE_SIZE))
+            .map(|va| ops.pa(va as *const _))
+            .collect();
+        pages.sort_unstable();
+        pages
+    });
+}
+
+/// Returns the physical addresses of the pages to hide in ascending order, if
+/// requested.
+pub(crate) fn pages() -> Option<&'static [u64]> {
+    HIDDEN_PAGES.get().map(Vec::as_slice)
+}
+
+/// Returns whether the page containing `pa` is hidden, or is to be hidden.
+pub(crate) fn is_hidden(pa: u64) -> bool {
+    pages().is_some_and(|pages| {
+        pages
+            .binary_search(&(pa & !(BASE_PAGE_SIZE as u64 - 1)))
+            .is_ok()
+    })
+}
+
+/// Returns whether the heap is hidden from the caller, that is, hiding is
+/// requested and the caller is the guest on a virtualized processor.
+pub(crate) fn is_hidden_from_caller() -> bool {
+    pages().is_some() && is_our_hypervisor_present()
+}
+
+/// Handles the violation of `info` if it is an access to a hidden page:
+/// discards the write by skipping the instruction, or delivers #GP for
+/// execution. Returns `false` if the page is not hidden.
+pub(crate) fn handle_violation(vcpu: &mut dyn Vcpu, info: &NestedPageFaultInfo) -> bool {
+    if !is_hidden(info.gpa) {
+        return false;
+    }
+    let result = if info.execute {
+        Err(InstructionError::Fetch(TranslationError::Inaccessible {
+            gpa: info.gpa,
+        }))
+    } else {
+        instruction_decoder::skip(vcpu)
+    };
+    memory_protection::complete(vcpu, result);
+    true
+}
+
+#[cfg(not(test))]
+fn heap_ranges() -> Vec<core::ops::Range<usize>> {
+    crate::hypervisor::allocator::heap_ranges()
+}
+
+// The global allocator is not used in tests.
+#[cfg(test)]
+fn heap_ranges() -> Vec<core::ops::Range<usize>> {
+    Vec::new()
+}
+
+/// The physical addresses of the pages to hide from the guest.
+static HIDDEN_PAGES: Once<Vec<u64>> = Once::new();
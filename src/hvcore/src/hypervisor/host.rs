@@ -1,22 +1,61 @@
 //! This module implements architecture agnostic parts of the host code.
 
+use core::arch::{asm, global_asm};
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use num_traits::FromPrimitive;
+use spin::Mutex;
 use x86::{
-    controlregs::{Cr4, Xcr0},
+    bits64::paging::BASE_PAGE_SIZE,
+    controlregs::{Cr0, Cr4},
     cpuid::cpuid,
+    debugregs::Dr7,
+    dtables::DescriptorTablePointer,
+    segmentation::SegmentSelector,
 };
 
 use crate::hypervisor::{
-    apic_id,
-    registers::Registers,
-    x86_instructions::{cr4, cr4_write, rdmsr, wrmsr, xsetbv},
-    HV_CPUID_INTERFACE, HV_CPUID_VENDOR_AND_MAX_FUNCTIONS, OUR_HV_VENDOR_NAME_EBX,
-    OUR_HV_VENDOR_NAME_ECX, OUR_HV_VENDOR_NAME_EDX,
+    apic_id, breakpoint_marker, cache_control, cet, deferred_work,
+    dirty_tracking::{DirtyBitmap, DirtyTrackingError},
+    ept_hook,
+    event::{self, Event},
+    event_channel,
+    exit_handlers::{ExitAction, ExitHandlers},
+    exit_stats::{self, ExitKind, ExitStatsEntry},
+    exit_trace::RawExitInfo,
+    foreign_hypervisor, guest_memory,
+    hw_breakpoint::{self, DebugState},
+    hypercall::{
+        Hypercall, HypercallStatus, HYPERCALL_ABI_VERSION, HYPERCALL_MAGIC, HYPERCALL_PONG,
+    },
+    instruction_decoder::CodeSize,
+    integrity::{self, MAX_INTEGRITY_EVENTS},
+    logger, long_mode, machine_check,
+    memory_protection::{self, Permissions},
+    mmio, percpu,
+    registers::{ExtendedRegisters, Registers},
+    self_test,
+    single_step::{SingleStepCallback, SingleStepError},
+    smm, snapshot,
+    syscall_protection::{self, MAX_TAMPER_EVENTS},
+    virtualization_exception::{self, VeError},
+    watchdog,
+    x86_instructions::{
+        cr0_write, cr4, cr4_write, in_port, lidt, lldt, out_port, rdtsc, wrmsr, xsetbv,
+    },
+    xsave, HvError, VirtError, SHARED_HOST_DATA,
 };
 
-use super::{amd::Amd, intel::Intel};
+#[cfg(feature = "amd")]
+use super::amd::Amd;
+#[cfg(feature = "intel")]
+use super::intel::Intel;
 
 /// The entry point of the hypervisor.
-pub(crate) fn main(registers: &Registers) -> ! {
+pub(crate) extern "win64" fn main(
+    registers: &Registers,
+    extended: Option<&ExtendedRegisters>,
+) -> ! {
     // Disable interrupt for a couple of reasons. (1) to avoid panic due to
     // interrupt, and (2) to avoid inconsistent guest initial state.
     //
@@ -35,70 +74,538 @@ pub(crate) fn main(registers: &Registers) -> ! {
     // never observed it causing the described issues.
     unsafe { x86::irq::disable() };
 
-    // Start the host on the current processor.
+    // Take ownership of the extended registers. The original is on the stack
+    // the guest resumes with, and is not dropped there, since the guest skips
+    // to the end of `virtualize_current_processor`.
+    let extended = extended.map(|extended| unsafe { core::ptr::read(extended) });
+
+    // Start the host on the current processor. `check_support` has rejected
+    // the vendor not compiled in.
+    #[cfg(all(feature = "intel", feature = "amd"))]
     if x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel" {
-        virtualize_core::<Intel>(registers)
+        virtualize_core::<Intel>(registers, extended)
     } else {
-        virtualize_core::<Amd>(registers)
+        virtualize_core::<Amd>(registers, extended)
+    }
+    #[cfg(all(feature = "intel", not(feature = "amd")))]
+    virtualize_core::<Intel>(registers, extended);
+    #[cfg(all(feature = "amd", not(feature = "intel")))]
+    virtualize_core::<Amd>(registers, extended);
+}
+
+/// Checks whether the current processor supports the virtualization extension
+/// and features the hypervisor requires.
+pub(crate) fn check_support() -> Result<(), VirtError> {
+    let cpuid = x86::cpuid::CpuId::new();
+    let vendor = cpuid
+        .get_vendor_info()
+        .ok_or(VirtError::UnsupportedVendor)?;
+    match vendor.as_str() {
+        #[cfg(feature = "intel")]
+        "GenuineIntel" => <Intel as Architecture>::VirtualizationExtension::check_support(),
+        #[cfg(feature = "amd")]
+        "AuthenticAMD" => <Amd as Architecture>::VirtualizationExtension::check_support(),
+        _ => Err(VirtError::UnsupportedVendor),
     }
 }
 
-/// Enables the virtualization extension, sets up and runs the guest indefinitely.
-fn virtualize_core<Arch: Architecture>(registers: &Registers) -> ! {
+/// Enables the virtualization extension, sets up and runs the guest until
+/// devirtualization is requested. If setting up fails, resumes the guest
+/// without the hypervisor instead.
+fn virtualize_core<Arch: Architecture>(
+    registers: &Registers,
+    extended: Option<ExtendedRegisters>,
+) -> ! {
     log::info!("Initializing the guest");
 
-    // Enable processor's virtualization technology.
-    let mut vt = Arch::VirtualizationExtension::default();
-    vt.enable();
+    let (mut vt, mut guest) = match set_up::<Arch>(registers, extended) {
+        Ok(set_up) => set_up,
+        Err(error) => abort_virtualization(registers, error),
+    };
 
-    // Create a new (empty) guest instance and set up its initial state.
-    let id = apic_id::processor_id_from(apic_id::get()).unwrap();
-    let guest = &mut Arch::Guest::new(id);
-    guest.activate();
-    guest.initialize(registers);
+    let shared_host = SHARED_HOST_DATA.get().unwrap();
+    let exit_handlers = &shared_host.exit_handlers;
+    if shared_host.syscall_protection {
+        syscall_protection::lock_current(&mut guest);
+    }
+    if shared_host.fail_open {
+        *percpu::current().fail_open.lock() = Some(FailOpenContext {
+            vt: core::ptr::addr_of_mut!(vt).cast(),
+            guest: core::ptr::addr_of_mut!(guest).cast(),
+            resume: resume_on_panic::<Arch>,
+            shared_data_locked: Arch::Guest::is_shared_data_locked,
+        });
+    }
 
     log::info!("Starting the guest");
+    smm::start_counting();
     loop {
-        // Then, run the guest until VM-exit occurs. Some of events are handled
-        // within the architecture specific code and nothing to do here.
-        match guest.run() {
-            VmExitReason::Cpuid(info) => handle_cpuid(guest, &info),
-            VmExitReason::Rdmsr(info) => handle_rdmsr(guest, &info),
-            VmExitReason::Wrmsr(info) => handle_wrmsr(guest, &info),
-            VmExitReason::XSetBv(info) => handle_xsetbv(guest, &info),
-            VmExitReason::InitSignal | VmExitReason::StartupIpi | VmExitReason::NestedPageFault => {
+        // Then, run the guest until VM-exit occurs.
+        let exit_reason = guest.run();
+        let start = rdtsc();
+        let percpu = percpu::current();
+        percpu.heartbeat.enter(start);
+        let exit_kind = ExitKind::from_exit(&exit_reason);
+        percpu
+            .exit_trace
+            .record(exit_kind, guest.regs().rip, guest.exit_info());
+        // The deferred work is for the guest, not its nested guest, whose state
+        // the `Vcpu` methods access instead.
+        let work = if guest.in_nested_guest() {
+            None
+        } else {
+            deferred_work::take_next()
+        };
+        let devirtualize = handle_exit(&mut guest, exit_handlers, &exit_reason);
+        if let Some(work) = work {
+            work(&mut guest);
+        }
+        if devirtualize {
+            deferred_work::run_all(&mut guest);
+        }
+        percpu.heartbeat.leave();
+        percpu
+            .exit_stats
+            .record(exit_kind, rdtsc().wrapping_sub(start));
+        if shared_host.watchdog.is_enabled() {
+            watchdog::check(&shared_host.watchdog);
+        }
+        if devirtualize {
+            break;
+        }
+    }
+
+    // Devirtualization is requested. Take the guest state, disable the
+    // virtualization extension, free per-processor data structures, and resume
+    // the guest without the hypervisor.
+    log::info!("Devirtualizing the current processor");
+    let _ = percpu::current().fail_open.lock().take();
+    syscall_protection::unlock(guest.id());
+    let mut state = guest.deactivate();
+    vt.disable();
+    drop(guest);
+    drop(vt);
+    restore_guest(&mut state)
+}
+
+/// What is needed to resume the guest without the hypervisor when the host
+/// panics on the current processor. See `SharedHostData::fail_open`.
+#[derive(Debug)]
+pub(crate) struct FailOpenContext {
+    vt: *mut (),
+    guest: *mut (),
+    resume: unsafe fn(*mut (), *mut ()) -> !,
+    shared_data_locked: fn() -> bool,
+}
+
+/// Resumes the guest without the hypervisor if the current processor panicked
+/// in the host with `SharedHostData::fail_open`. Returns otherwise, including
+/// when this panicked again while resuming, and when any lock shared across
+/// processors stays held.
+pub(crate) fn fail_open() {
+    // Give the other processors a while to release the locks they hold. The
+    // locks never released are held by this processor.
+    const MAX_SPINS: u32 = 1 << 24;
+
+    let Some(percpu) = percpu::find_current() else {
+        return;
+    };
+
+    // Only resume from the host handling a VM-exit, not from the guest.
+    if !percpu.heartbeat.is_entered() {
+        return;
+    }
+    let Some(context) = percpu
+        .fail_open
+        .try_lock()
+        .and_then(|mut context| context.take())
+    else {
+        return;
+    };
+
+    // The locks held by this processor are never released, as nothing on the
+    // host stack is dropped, and the other processors would hang on them.
+    // Resume the guest only if no such lock stays held.
+    if (0..MAX_SPINS).all(|_| {
+        let held = shared_locks_held(&context);
+        if held {
+            core::hint::spin_loop();
+        }
+        held
+    }) {
+        log::error!("Not resuming the guest as this processor may hold locks");
+        return;
+    }
+
+    log::error!("Resuming the guest without the hypervisor");
+    unsafe { (context.resume)(context.vt, context.guest) }
+}
+
+/// Returns whether any lock the hosts of all processors take is held.
+fn shared_locks_held(context: &FailOpenContext) -> bool {
+    // The heap is that of the platform in tests.
+    #[cfg(not(test))]
+    if crate::hypervisor::allocator::is_locked() {
+        return true;
+    }
+    apic_id::APIC_ID_MAP.reader_count() != 0
+        || apic_id::APIC_ID_MAP.writer_count() != 0
+        || breakpoint_marker::is_locked()
+        || ept_hook::is_locked()
+        || event_channel::is_locked()
+        || hw_breakpoint::is_locked()
+        || integrity::is_locked()
+        || memory_protection::is_locked()
+        || mmio::is_locked()
+        || percpu::all()
+            .any(|percpu| percpu.deferred_work.is_locked() || percpu.posted_interrupts.is_locked())
+        || snapshot::is_locked()
+        || syscall_protection::is_locked()
+        || virtualization_exception::is_locked()
+        || (context.shared_data_locked)()
+}
+
+/// Devirtualizes the current processor with the guest and the virtualization
+/// extension in the state at the time of panic.
+///
+/// Nothing on the host stack is dropped. See `fail_open` for locks.
+unsafe fn resume_on_panic<Arch: Architecture>(vt: *mut (), guest: *mut ()) -> ! {
+    let vt = unsafe { &mut *vt.cast::<Arch::VirtualizationExtension>() };
+    let guest = unsafe { &mut *guest.cast::<Arch::Guest>() };
+    percpu::current().heartbeat.leave();
+    syscall_protection::unlock(guest.id());
+    let mut state = guest.deactivate();
+    vt.disable();
+    restore_guest(&mut state)
+}
+
+/// Handles the VM-exit due to `exit_reason`. Returns `true` if devirtualization
+/// is requested.
+fn handle_exit<T: Guest>(
+    guest: &mut T,
+    exit_handlers: &ExitHandlers,
+    exit_reason: &VmExitReason,
+) -> bool {
+    // Give custom handlers registered by the embedder a chance to handle the
+    // VM-exit first.
+    if exit_handlers.dispatch(guest, exit_reason) == ExitAction::Handled {
+        return false;
+    }
+
+    // Otherwise, handle it with the built-in handlers. Some of events are
+    // handled within the architecture specific code and nothing to do here.
+    match exit_reason {
+        VmExitReason::Cpuid(info) => handle_cpuid(guest, info),
+        VmExitReason::Rdmsr(info) => handle_rdmsr(guest, info),
+        VmExitReason::Wrmsr(info) => handle_wrmsr(guest, info),
+        VmExitReason::XSetBv(info) => handle_xsetbv(guest, info),
+        VmExitReason::Hypercall(info) => return handle_hypercall(guest, info),
+        VmExitReason::Io(info) => {
+            // Let the guest execute the write entering a sleep state again
+            // without the hypervisor. See `power`.
+            let sleep_detection = &SHARED_HOST_DATA.get().unwrap().sleep_detection;
+            if guest.can_devirtualize() && sleep_detection.is_entering_sleep(info, guest.regs().rax)
+            {
+                return true;
             }
+            handle_io(guest, info);
         }
+        VmExitReason::CrWrite(info) => handle_cr_write(guest, info),
+        VmExitReason::DrAccess(info) => hw_breakpoint::handle_dr_access(guest, info),
+        VmExitReason::Breakpoint(info) => breakpoint_marker::handle_breakpoint(guest, info),
+        VmExitReason::NestedPageFault(info) => guest.handle_nested_page_fault(info),
+        VmExitReason::PreemptionTimer => {
+            SHARED_HOST_DATA
+                .get()
+                .unwrap()
+                .preemption_timer
+                .expire(guest);
+        }
+        VmExitReason::InitSignal => syscall_protection::unlock(guest.id()),
+        VmExitReason::StartupIpi
+        | VmExitReason::Nmi
+        | VmExitReason::InterruptWindow
+        | VmExitReason::SingleStep
+        | VmExitReason::DebugException
+        | VmExitReason::ViewSwitch
+        | VmExitReason::VirtualizationInstruction
+        | VmExitReason::NestedGuest
+        | VmExitReason::Smi
+        | VmExitReason::ExternalInterrupt
+        | VmExitReason::VirtualEoi => {}
+        VmExitReason::MachineCheck => machine_check::handle(guest),
+        VmExitReason::Invd(info) => {
+            let policy = SHARED_HOST_DATA.get().unwrap().cache_invalidation;
+            cache_control::handle_invd(guest, info, policy);
+        }
+        VmExitReason::Wbinvd(info) => {
+            let policy = SHARED_HOST_DATA.get().unwrap().cache_invalidation;
+            cache_control::handle_wbinvd(guest, info, policy);
+        }
+    }
+    false
+}
+
+/// Enables processor's virtualization technology, and creates a new guest
+/// instance with the initial state based on `registers` and `extended`.
+fn set_up<Arch: Architecture>(
+    registers: &Registers,
+    extended: Option<ExtendedRegisters>,
+) -> Result<(Arch::VirtualizationExtension, Arch::Guest), HvError> {
+    let apic_id = apic_id::get();
+    let id = apic_id::processor_id_from(apic_id).ok_or(HvError::UnknownProcessor(apic_id))?;
+
+    let mut vt = Arch::VirtualizationExtension::new()?;
+    vt.enable()?;
+
+    let guest = Arch::Guest::new(id).and_then(|mut guest| {
+        guest.activate()?;
+        guest.initialize(registers, extended)?;
+        Ok(guest)
+    });
+    match guest {
+        Ok(guest) => Ok((vt, guest)),
+        Err(error) => {
+            vt.disable();
+            Err(error)
+        }
+    }
+}
+
+/// Records `error` for `virtualize_system`, and resumes the guest with
+/// `registers` without the hypervisor. This function must be called outside
+/// VMX or SVM operation, before the system register values are changed for the
+/// host.
+fn abort_virtualization(registers: &Registers, error: HvError) -> ! {
+    log::error!("Could not virtualize the current processor: {error}");
+    *SETUP_ERROR.lock() = Some(error);
+
+    // The extended registers are not used by the host code, and are left as
+    // they were captured. So is IA32_S_CET.
+    unsafe {
+        resume_guest(
+            registers,
+            u64::from(x86::segmentation::cs().bits()),
+            u64::from(x86::segmentation::ss().bits()),
+            core::ptr::null_mut(),
+            cet::s_cet(),
+        )
+    }
+}
+
+/// Returns the error the last attempt to virtualize the current processor
+/// failed with, if any. Processors are virtualized one by one, so the error
+/// is always of the current processor when taken right after the attempt.
+pub(crate) fn take_setup_error() -> Option<HvError> {
+    SETUP_ERROR.lock().take()
+}
+
+/// The error the last attempt to virtualize a processor failed with.
+static SETUP_ERROR: Mutex<Option<HvError>> = Mutex::new(None);
+
+/// Switches to the guest system register values in `state` and jumps to the
+/// guest. This function must be called outside VMX or SVM operation.
+fn restore_guest(state: &mut GuestSystemState) -> ! {
+    const TSS_BUSY_FLAG: u64 = 1 << 41;
+
+    unsafe {
+        // Load the guest descriptor tables first. Segment registers are loaded
+        // based on them.
+        x86::dtables::lgdt(&state.gdtr);
+        lidt(&state.idtr);
+
+        // The TSS descriptor for the guest TR is marked as busy. Clear it to
+        // load it again. Otherwise, LTR causes #GP.
+        // See: LTR—Load Task Register
+        if state.tr != 0 {
+            let descriptor = (state.gdtr.base as u64 + u64::from(state.tr & !0b111)) as *mut u64;
+            *descriptor &= !TSS_BUSY_FLAG;
+            x86::task::load_tr(SegmentSelector::from_raw(state.tr));
+        }
+        lldt(SegmentSelector::from_raw(state.ldtr));
+
+        // Loading FS and GS overwrites their bases. Restore bases afterwards.
+        x86::segmentation::load_ds(SegmentSelector::from_raw(state.ds));
+        x86::segmentation::load_es(SegmentSelector::from_raw(state.es));
+        x86::segmentation::load_fs(SegmentSelector::from_raw(state.fs));
+        x86::segmentation::load_gs(SegmentSelector::from_raw(state.gs));
+        wrmsr(x86::msr::IA32_FS_BASE, state.fs_base);
+        wrmsr(x86::msr::IA32_GS_BASE, state.gs_base);
+
+        x86::debugregs::dr7_write(Dr7(state.dr7 as _));
+        cr0_write(Cr0::from_bits_unchecked(state.cr0 as _));
+        cr4_write(Cr4::from_bits_unchecked(state.cr4 as _));
+        x86::controlregs::cr3_write(state.cr3);
+
+        // Finally, load CS, SS and the registers and jump to the guest.
+        let extended = state
+            .extended
+            .as_mut()
+            .map_or(core::ptr::null_mut(), ExtendedRegisters::prepare);
+        resume_guest(
+            &state.registers,
+            u64::from(state.cs),
+            u64::from(state.ss),
+            extended,
+            state.s_cet,
+        )
     }
 }
 
+/// Loads IA32_S_CET with `s_cet`, and then, jumps to the guest with
+/// `restore_registers`. If `s_cet` enables supervisor shadow stacks, `IRETQ`
+/// pops the return frame from the shadow stack of the guest at
+/// `Registers::ssp`, which is switched to right before it.
+unsafe fn resume_guest(
+    registers: &Registers,
+    cs: u64,
+    ss: u64,
+    extended: *mut ExtendedRegisters,
+    s_cet: u64,
+) -> ! {
+    const SH_STK_EN: u64 = 1 << 0;
+    const SUPPRESS_AND_TRACKER: u64 = 0b11 << 10;
+
+    if !cet::is_supported() {
+        unsafe { restore_registers(registers, cs, ss, extended) };
+    }
+
+    // The current SSP is not of the guest. Disable supervisor CET first. The
+    // indirect branch tracker state is of the guest instruction VM-exit
+    // occurred at, and does not apply to the jump to the guest.
+    wrmsr(cet::IA32_S_CET, 0);
+    let s_cet = s_cet & !SUPPRESS_AND_TRACKER;
+    if s_cet & SH_STK_EN == 0 || cr4().bits() & cet::CR4_CET == 0 {
+        wrmsr(cet::IA32_S_CET, s_cet);
+        unsafe { restore_registers(registers, cs, ss, extended) };
+    }
+
+    // Enable shadow stacks and switch to the shadow stack of the guest with the
+    // restore token, then pop the token. Nothing is pushed onto or popped from
+    // the shadow stack until `IRETQ`.
+    // See: 17.2.3 Supervisor Shadow Stack Token
+    let token = unsafe { cet::push_return_frame(registers.ssp, cs, registers.rip) };
+    unsafe {
+        asm!(
+            "wrmsr",
+            "rstorssp [{token}]",
+            "incsspq {one}",
+            "mov rcx, {registers}",
+            "mov rdx, {cs}",
+            "jmp restore_registers",
+            token = in(reg) token,
+            one = in(reg) 1u64,
+            registers = in(reg) registers,
+            cs = in(reg) cs,
+            in("ecx") cet::IA32_S_CET,
+            in("eax") s_cet as u32,
+            in("edx") (s_cet >> 32) as u32,
+            in("r8") ss,
+            in("r9") extended,
+            options(noreturn),
+        )
+    }
+}
+
+extern "win64" {
+    /// Loads `registers` along with `cs`, `ss` and `extended` if not null,
+    /// including the guest DR0-DR3 and DR6 in it, and jumps to the guest RIP
+    /// with IRETQ.
+    fn restore_registers(
+        registers: &Registers,
+        cs: u64,
+        ss: u64,
+        extended: *mut ExtendedRegisters,
+    ) -> !;
+}
+global_asm!(include_str!("capture_registers.inc"));
+global_asm!(
+    r#"
+    .align 16
+    .global restore_registers
+    restore_registers:
+        # Build the stack frame for IRETQ.
+        push    r8
+        push    [rcx + registers_rsp]
+        push    [rcx + registers_rflags]
+        push    rdx
+        push    [rcx + registers_rip]
+
+        # Load the extended registers if `extended` is given.
+        test    r9, r9
+        jz      .RestoreExtendedLoaded
+        mov     r10, [r9 + extended_guest]
+        mov     eax, -1
+        mov     edx, -1
+        cmp     qword ptr [r9 + extended_use_xsave], 0
+        jz      .RestoreWithFxrstor
+        xrstor64 [r10]
+        jmp     .RestoreDebugRegisters
+
+    .RestoreWithFxrstor:
+        fxrstor64 [r10]
+
+    .RestoreDebugRegisters:
+        mov     rax, [r9 + extended_guest_dr0]
+        mov     dr0, rax
+        mov     rax, [r9 + extended_guest_dr1]
+        mov     dr1, rax
+        mov     rax, [r9 + extended_guest_dr2]
+        mov     dr2, rax
+        mov     rax, [r9 + extended_guest_dr3]
+        mov     dr3, rax
+        mov     rax, [r9 + extended_guest_dr6]
+        mov     dr6, rax
+
+    .RestoreExtendedLoaded:
+
+        movaps  xmm0, [rcx + registers_xmm0]
+        movaps  xmm1, [rcx + registers_xmm1]
+        movaps  xmm2, [rcx + registers_xmm2]
+        movaps  xmm3, [rcx + registers_xmm3]
+        movaps  xmm4, [rcx + registers_xmm4]
+        movaps  xmm5, [rcx + registers_xmm5]
+        mov     rax, [rcx + registers_rax]
+        mov     rbx, [rcx + registers_rbx]
+        mov     rdx, [rcx + registers_rdx]
+        mov     rdi, [rcx + registers_rdi]
+        mov     rsi, [rcx + registers_rsi]
+        mov     rbp, [rcx + registers_rbp]
+        mov      r8, [rcx + registers_r8]
+        mov      r9, [rcx + registers_r9]
+        mov     r10, [rcx + registers_r10]
+        mov     r11, [rcx + registers_r11]
+        mov     r12, [rcx + registers_r12]
+        mov     r13, [rcx + registers_r13]
+        mov     r14, [rcx + registers_r14]
+        mov     r15, [rcx + registers_r15]
+        mov     rcx, [rcx + registers_rcx]
+        iretq
+"#
+);
+
 fn handle_cpuid<T: Guest>(guest: &mut T, info: &InstructionInfo) {
     let leaf = guest.regs().rax as u32;
     let sub_leaf = guest.regs().rcx as u32;
     log::trace!("CPUID {leaf:#x?} {sub_leaf:#x?}");
     let mut cpuid_result = cpuid!(leaf, sub_leaf);
+    let policy = &SHARED_HOST_DATA.get().unwrap().cpuid_policy;
+    policy.apply(leaf, sub_leaf, &mut cpuid_result);
+    cet::apply_cpuid(leaf, sub_leaf, &mut cpuid_result);
 
-    if leaf == 1 {
-        // On the Intel processor, CPUID.1.ECX[5] indicates if VT-x is supported.
-        // Clear this to prevent other hypervisor tries to use it. On AMD, it is
-        // a reserved bit.
-        // See: Table 3-10. Feature Information Returned in the ECX Register
-        cpuid_result.ecx &= !(1 << 5);
-    } else if leaf == HV_CPUID_VENDOR_AND_MAX_FUNCTIONS {
-        // If the hypervisor vendor name is asked, return our hypervisor name,
-        // so that `is_our_hypervisor_present` can detect the presence.
-        cpuid_result.ebx = OUR_HV_VENDOR_NAME_EBX;
-        cpuid_result.ecx = OUR_HV_VENDOR_NAME_ECX;
-        cpuid_result.edx = OUR_HV_VENDOR_NAME_EDX;
-    } else if leaf == HV_CPUID_INTERFACE {
-        // Return non "Hv#1" into EAX. This indicate that our hypervisor does NOT
-        // conform to the Microsoft hypervisor interface. This prevents the guest
-        // from using the interface for optimum performance, but simplifies
-        // implementation of our hypervisor. This is required only when testing
-        // in the virtualization platform that supports the Microsoft hypervisor
-        // interface, such as VMware, and not required for a baremetal.
-        // See: Hypervisor Top Level Functional Specification
-        cpuid_result.eax = 0;
+    // Report the state components consistently with the other leaves.
+    match (leaf, sub_leaf) {
+        (0xd, 0) => {
+            let supported = xsave::supported_xcr0(policy);
+            cpuid_result.eax &= supported as u32;
+            cpuid_result.edx &= (supported >> 32) as u32;
+        }
+        (0xd, 1) => {
+            let supported = xsave::supported_xss(policy).unwrap_or(0);
+            cpuid_result.ecx &= supported as u32;
+            cpuid_result.edx &= (supported >> 32) as u32;
+        }
+        _ => {}
     }
 
     guest.regs().rax = u64::from(cpuid_result.eax);
@@ -108,32 +615,239 @@ fn handle_cpuid<T: Guest>(guest: &mut T, info: &InstructionInfo) {
     guest.regs().rip = info.next_rip;
 }
 
-/// Handles the `RDMSR` instruction for the range not covered by MSR bitmaps.
+/// Handles the `RDMSR` instruction for intercepted MSRs and the range not
+/// covered by MSR bitmaps.
 fn handle_rdmsr<T: Guest>(guest: &mut T, info: &InstructionInfo) {
     let msr = guest.regs().rcx as u32;
     log::trace!("RDMSR {msr:#x?}");
 
-    // Passthrough any MSR access. Beware of that VM-exit occurs even for an
-    // invalid MSR access which causes #GP(0).
+    // Passthrough any MSR access unless the callback overrides it. Beware of that
+    // VM-exit occurs even for an invalid MSR access which causes #GP(0).
     // See: 26.1.1 Relative Priority of Faults and VM Exits
     //
     // One solution is to catch the exception and inject it into the guest.
-    let value = rdmsr(msr);
+    let msr_intercepts = &SHARED_HOST_DATA.get().unwrap().msr_intercepts;
+    let value = msr_intercepts
+        .read_handler(msr)
+        .and_then(|handler| handler(guest, msr))
+        .unwrap_or_else(|| guest.read_msr(msr));
 
     guest.regs().rax = value & 0xffff_ffff;
     guest.regs().rdx = value >> 32;
     guest.regs().rip = info.next_rip;
 }
 
-/// Handles the `WRMSR` instruction for the range not covered by MSR bitmaps.
+/// Handles the `WRMSR` instruction for intercepted MSRs and the range not
+/// covered by MSR bitmaps.
 fn handle_wrmsr<T: Guest>(guest: &mut T, info: &InstructionInfo) {
     let msr = guest.regs().rcx as u32;
     let value = (guest.regs().rax & 0xffff_ffff) | ((guest.regs().rdx & 0xffff_ffff) << 32);
     log::trace!("WRMSR {msr:#x?} {value:#x?}");
 
+    // IA32_XSS is intercepted only to validate the value against CPUID. See
+    // `xsave::install`.
+    let shared_host = SHARED_HOST_DATA.get().unwrap();
+    if msr == xsave::IA32_XSS
+        && !xsave::is_valid_xss(value, xsave::supported_xss(&shared_host.cpuid_policy))
+    {
+        inject_gp(guest);
+        return;
+    }
+
+    // IA32_EFER is intercepted to keep LMA consistent with the rest of the
+    // guest state. See `long_mode::install`.
+    if msr == x86::msr::IA32_EFER
+        && !long_mode::is_valid_efer_write(
+            guest.efer(),
+            guest.cr0(),
+            value,
+            long_mode::reserved_efer_bits(),
+        )
+    {
+        inject_gp(guest);
+        return;
+    }
+
+    // Leaving long mode, as with kexec, discards the system call entry. Let the
+    // guest set up a new one. See `syscall_protection`.
+    const EFER_LME: u64 = 1 << 8;
+    if msr == x86::msr::IA32_EFER && value & EFER_LME == 0 {
+        syscall_protection::unlock(guest.id());
+    }
+
     // See the comment in `handle_rdmsr`.
-    wrmsr(msr, value);
+    let msr_intercepts = &shared_host.msr_intercepts;
+    let value = match msr_intercepts.write_handler(msr) {
+        Some(handler) => handler(guest, msr, value),
+        None => Some(value),
+    };
+    if let Some(value) = value {
+        guest.write_msr(msr, value);
+    }
+
+    guest.regs().rip = info.next_rip;
+}
+
+/// Handles `MOV` to CR0 or CR4 intercepted for the guarded bits or CR0.PG, or
+/// to CR3 intercepted for CR3 tracking.
+fn handle_cr_write<T: Guest>(guest: &mut T, info: &CrWriteInfo) {
+    const CS_L: u16 = 1 << 9;
+
+    if info.cr == 3 {
+        let cr3_tracking = &SHARED_HOST_DATA.get().unwrap().cr3_tracking;
+        let mut cache = percpu::current().cr3_cache.lock();
+        if cr3_tracking.handle_load(guest, &mut cache, info.value) {
+            guest.set_cr3_targets(cache.targets());
+        }
+        guest.write_cr(3, info.value);
+        guest.regs().rip = info.next_rip;
+        return;
+    }
+
+    let current = if info.cr == 0 {
+        guest.cr0()
+    } else {
+        guest.cr4()
+    };
+    log::trace!("CR{} {current:#x?} -> {:#x?}", info.cr, info.value);
+
+    // Switching the paging mode is validated as the processor would, since
+    // the write is applied by the host. See `long_mode`.
+    if info.cr == 0 {
+        let long = guest.segment(SegmentRegister::Cs).attributes & CS_L != 0;
+        if !long_mode::is_valid_cr0_write(current, info.value, guest.cr4(), guest.efer(), long) {
+            inject_gp(guest);
+            return;
+        }
+    }
+    let cr_intercepts = &SHARED_HOST_DATA.get().unwrap().cr_intercepts;
+    if let Some(value) = cr_intercepts.filter(guest, info.cr, current, info.value) {
+        guest.write_cr(info.cr, value);
+    }
+    guest.regs().rip = info.next_rip;
+}
 
+/// Handles I/O instructions for intercepted ports.
+fn handle_io<T: Guest>(guest: &mut T, info: &IoInstructionInfo) {
+    let (port, size) = (info.port, info.size);
+    let mask = u32::MAX >> (32 - u32::from(size) * 8);
+    let handler = SHARED_HOST_DATA.get().unwrap().io_intercepts.handler(port);
+    log::trace!("I/O {port:#x?} {info:x?}");
+
+    let read = |guest: &mut T| {
+        let value = handler
+            .and_then(|handler| handler.read(guest, port, size))
+            .unwrap_or_else(|| in_port(port, size));
+        value & mask
+    };
+    let write = |guest: &mut T, value: u32| {
+        let value = match handler {
+            Some(handler) => handler.write(guest, port, size, value & mask),
+            None => Some(value & mask),
+        };
+        if let Some(value) = value {
+            out_port(port, size, value & mask);
+        }
+    };
+
+    if !info.string {
+        if info.is_in {
+            let value = u64::from(read(guest));
+            let rax = &mut guest.regs().rax;
+            *rax = if size == 4 {
+                // 32-bit results are zero-extended to 64 bits.
+                value
+            } else {
+                (*rax & !u64::from(mask)) | value
+            };
+        } else {
+            let value = guest.regs().rax as u32;
+            write(guest, value);
+        }
+        guest.regs().rip = info.next_rip;
+        return;
+    }
+
+    // Emulate INS and OUTS one element at a time. The element is written to
+    // ES:(E/R)DI for INS and read from the segment, DS unless overridden, at
+    // (E/R)SI for OUTS. The index register is decremented when RFLAGS.DF is
+    // set, and the index and count registers are of the address size, which
+    // the address-size prefix changes.
+    // See: IN/INS/INSB/INSW/INSD and OUT/OUTS/OUTSB/OUTSW/OUTSD
+    // See: 3.4.4 Segment Loading Instructions in IA-32e Mode
+    const RFLAGS_DF: u64 = 1 << 10;
+    let address_mask = u64::MAX >> (64 - u32::from(info.address_size) * 8);
+    let update = |register: &mut u64, value: u64| {
+        *register = match info.address_size {
+            // Writing the 32-bit register zero-extends it.
+            2 => (*register & !address_mask) | (value & address_mask),
+            _ => value & address_mask,
+        };
+    };
+    let is_64bit = CodeSize::of(guest) == CodeSize::Bits64;
+    let segment = if info.is_in {
+        SegmentRegister::Es
+    } else {
+        info.segment
+    };
+    let base = match segment {
+        SegmentRegister::Fs | SegmentRegister::Gs => guest.segment(segment).base,
+        _ if is_64bit => 0,
+        _ => guest.segment(segment).base,
+    };
+    let linear_address = |offset: u64| {
+        let address = base.wrapping_add(offset & address_mask);
+        if is_64bit {
+            address
+        } else {
+            address & u64::from(u32::MAX)
+        }
+    };
+
+    let count = if info.rep {
+        guest.regs().rcx & address_mask
+    } else {
+        1
+    };
+    let step = if guest.regs().rflags & RFLAGS_DF == 0 {
+        u64::from(size)
+    } else {
+        u64::from(size).wrapping_neg()
+    };
+    for _ in 0..count {
+        let bytes = &mut [0u8; 4][..usize::from(size)];
+        let result = if info.is_in {
+            let value = read(guest);
+            bytes.copy_from_slice(&value.to_le_bytes()[..usize::from(size)]);
+            let address = linear_address(guest.regs().rdi);
+            guest_memory::write_guest_checked(guest, address, bytes).map(|()| &mut guest.regs().rdi)
+        } else {
+            let address = linear_address(guest.regs().rsi);
+            guest_memory::read_guest_checked(guest, address, bytes).map(|()| {
+                let mut value = [0u8; 4];
+                value[..usize::from(size)].copy_from_slice(bytes);
+                write(guest, u32::from_le_bytes(value));
+                &mut guest.regs().rsi
+            })
+        };
+        match result {
+            Ok(index) => update(index, index.wrapping_add(step)),
+            Err(err) => {
+                // Deliver the fault the access would have caused. RIP stays at
+                // the instruction, and RCX and the index register reflect the
+                // elements already transferred, so the guest can resume it.
+                // If no fault can be injected, let the guest retry it.
+                if !guest_memory::inject_fault(guest, &err, info.is_in) {
+                    log::error!("Failed to emulate string I/O: {err}");
+                }
+                return;
+            }
+        }
+        if info.rep {
+            let rcx = &mut guest.regs().rcx;
+            update(rcx, rcx.wrapping_sub(1));
+        }
+    }
     guest.regs().rip = info.next_rip;
 }
 
@@ -141,20 +855,368 @@ fn handle_wrmsr<T: Guest>(guest: &mut T, info: &InstructionInfo) {
 fn handle_xsetbv<T: Guest>(guest: &mut T, info: &InstructionInfo) {
     let xcr: u32 = guest.regs().rcx as u32;
     let value = (guest.regs().rax & 0xffff_ffff) | ((guest.regs().rdx & 0xffff_ffff) << 32);
-    let value = Xcr0::from_bits(value).unwrap();
     log::trace!("XSETBV {xcr:#x?} {value:#x?}");
 
+    // Only XCR0 exists. Validate the value against CPUID the guest sees, as
+    // the processor would against what it supports, instead of letting the
+    // instruction cause #GP(0) in the host.
+    let policy = &SHARED_HOST_DATA.get().unwrap().cpuid_policy;
+    if xcr != 0 || !xsave::is_valid_xcr0(value, xsave::supported_xcr0(policy)) {
+        inject_gp(guest);
+        return;
+    }
+
     // The host CR4 might not have this bit, which is required for executing the
     // `XSETBV` instruction. Set this bit and run the instruction.
     cr4_write(cr4() | Cr4::CR4_ENABLE_OS_XSAVE);
-
-    // XCR may be invalid and this instruction may cause #GP(0). See the comment
-    // in `handle_rdmsr`.
     xsetbv(xcr, value);
 
     guest.regs().rip = info.next_rip;
 }
 
+/// Injects #GP(0) into the guest, leaving RIP at the faulting instruction.
+fn inject_gp<T: Guest>(guest: &mut T) {
+    const GP_VECTOR: u8 = 13;
+
+    let gp = Event::Exception {
+        vector: GP_VECTOR,
+        error_code: Some(0),
+    };
+    if let Err(err) = event::inject_event(guest, gp) {
+        log::error!("Failed to inject #GP: {err}");
+    }
+}
+
+/// Handles the `VMCALL` or `VMMCALL` instruction. See the `hypercall` module
+/// for the ABI. Returns `true` if devirtualization is requested.
+fn handle_hypercall<T: Guest>(guest: &mut T, info: &InstructionInfo) -> bool {
+    if guest.regs().rax != HYPERCALL_MAGIC && foreign_hypervisor::is_nested() && guest.cpl() == 0 {
+        foreign_hypervisor::forward_hypercall(guest.regs());
+        guest.regs().rip = info.next_rip;
+        return false;
+    }
+
+    // Deliver #UD for hypercalls that are not ours, as `VMCALL` and `VMMCALL`
+    // do on the processor without a hypervisor. RIP stays at the instruction.
+    // See: VMCALL—Call to VM Monitor
+    // See: 15.32 VMMCALL Instruction
+    if guest.regs().rax != HYPERCALL_MAGIC {
+        const UD_VECTOR: u8 = 6;
+
+        log::debug!("Rejecting hypercall with {:#x?}", guest.regs().rax);
+        let ud = Event::Exception {
+            vector: UD_VECTOR,
+            error_code: None,
+        };
+        if let Err(err) = event::inject_event(guest, ud) {
+            log::error!("Failed to inject #UD: {err}");
+        }
+        return false;
+    }
+
+    let mut devirtualize = false;
+    let can_devirtualize = guest.can_devirtualize();
+    let (status, output) = if guest.cpl() != 0 {
+        (HypercallStatus::AccessDenied, 0)
+    } else {
+        let regs = guest.regs();
+        log::trace!("Hypercall {:#x?}", regs.rcx);
+        match Hypercall::from_u64(regs.rcx) {
+            Some(Hypercall::Ping) => (HypercallStatus::Success, HYPERCALL_PONG),
+            Some(Hypercall::GetVersion) => (HypercallStatus::Success, HYPERCALL_ABI_VERSION),
+            Some(Hypercall::ReadStats) => {
+                let exit_count = percpu::all()
+                    .map(|percpu| percpu.exit_stats.exit_count())
+                    .sum();
+                (HypercallStatus::Success, exit_count)
+            }
+            Some(Hypercall::InstallHook) => {
+                let (address, patch, len) = (regs.rdx, regs.r8, regs.r9);
+                install_hook(guest, address, patch, len)
+            }
+            Some(Hypercall::UninstallHook) => {
+                let address = regs.rdx;
+                uninstall_hook(guest, address)
+            }
+            Some(Hypercall::Devirtualize) if !can_devirtualize => (HypercallStatus::Busy, 0),
+            Some(Hypercall::Devirtualize) => {
+                devirtualize = true;
+                (HypercallStatus::Success, 0)
+            }
+            Some(Hypercall::TakeSnapshot) => {
+                let mut resumed = *regs;
+                resumed.rax = HypercallStatus::Success as u64;
+                resumed.rdx = 1;
+                resumed.rip = info.next_rip;
+                match snapshot::take_snapshot(&resumed, regs.rdx, regs.r8) {
+                    Ok(()) => (HypercallStatus::Success, 0),
+                    Err(_) => (HypercallStatus::InvalidParameter, 0),
+                }
+            }
+            Some(Hypercall::RestoreSnapshot) => match snapshot::restore_snapshot(guest) {
+                // The registers are restored to the ones on return from
+                // `TakeSnapshot`.
+                Ok(()) => return false,
+                Err(_) => (HypercallStatus::InvalidParameter, 0),
+            },
+            Some(Hypercall::DiscardSnapshot) => match snapshot::discard_snapshot() {
+                Ok(()) => (HypercallStatus::Success, 0),
+                Err(_) => (HypercallStatus::InvalidParameter, 0),
+            },
+            Some(Hypercall::EnableVirtualizationExceptions) => {
+                let info_pa = regs.rdx;
+                if !info_pa.is_multiple_of(BASE_PAGE_SIZE as u64) {
+                    (HypercallStatus::InvalidParameter, 0)
+                } else {
+                    match guest.set_virtualization_exception_info((info_pa != 0).then_some(info_pa))
+                    {
+                        Ok(()) => (HypercallStatus::Success, 0),
+                        Err(_) => (HypercallStatus::NotSupported, 0),
+                    }
+                }
+            }
+            Some(Hypercall::SetLogLevel) => match log::LevelFilter::iter().nth(regs.rdx as usize) {
+                Some(level) => {
+                    let previous = log::max_level();
+                    log::set_max_level(level);
+                    (HypercallStatus::Success, previous as u64)
+                }
+                None => (HypercallStatus::InvalidParameter, 0),
+            },
+            Some(Hypercall::ReadExitStats) => {
+                let (gva, size, id) = (regs.rdx, regs.r8, regs.r9);
+                read_exit_stats(guest, gva, size, id)
+            }
+            Some(Hypercall::ReadAllocatorStats) => {
+                let (gva, size) = (regs.rdx, regs.r8);
+                read_allocator_stats(guest, gva, size)
+            }
+            Some(Hypercall::ReadTamperEvents) => {
+                let (gva, size) = (regs.rdx, regs.r8);
+                read_tamper_events(guest, gva, size)
+            }
+            Some(Hypercall::ReadIntegrityEvents) => {
+                let (gva, size) = (regs.rdx, regs.r8);
+                read_events(
+                    guest,
+                    gva,
+                    size,
+                    MAX_INTEGRITY_EVENTS,
+                    integrity::take_events,
+                )
+            }
+            Some(Hypercall::ReadLog) => {
+                let (gva, size) = (regs.rdx, regs.r8);
+                read_log(guest, gva, size)
+            }
+            Some(Hypercall::ReadSmiCount) => match smm::smi_count(guest) {
+                Some(count) => (HypercallStatus::Success, count),
+                None => (HypercallStatus::NotSupported, 0),
+            },
+            Some(Hypercall::SelfTest) => {
+                let (operation, parameter) = (regs.rdx, regs.r8);
+                self_test::handle_hypercall(guest, operation, parameter)
+            }
+            Some(Hypercall::ExitTest) => {
+                if let Some(port) = SHARED_HOST_DATA.get().unwrap().test_exit_port {
+                    log::info!("Ending the test run with {:#x}", regs.rdx);
+                    out_port(port, 4, regs.rdx as u32);
+                }
+                // QEMU has exited if the device is present.
+                (HypercallStatus::NotSupported, 0)
+            }
+            Some(Hypercall::RegisterEventChannel) => {
+                let (gpa, size, vector) = (regs.rdx, regs.r8, regs.r9);
+                if gpa == 0 {
+                    event_channel::unregister();
+                    (HypercallStatus::Success, 0)
+                } else {
+                    match event_channel::register(guest, gpa, size, vector) {
+                        Ok(()) => (HypercallStatus::Success, 0),
+                        Err(_) => (HypercallStatus::InvalidParameter, 0),
+                    }
+                }
+            }
+            None => (HypercallStatus::InvalidHypercall, 0),
+        }
+    };
+
+    let regs = guest.regs();
+    regs.rax = status as u64;
+    regs.rdx = output;
+    regs.rip = info.next_rip;
+    devirtualize
+}
+
+/// Handles `Hypercall::InstallHook`.
+fn install_hook<T: Guest>(
+    guest: &mut T,
+    address: u64,
+    patch_gva: u64,
+    len: u64,
+) -> (HypercallStatus, u64) {
+    let Some(len) = usize::try_from(len)
+        .ok()
+        .filter(|len| (1..=BASE_PAGE_SIZE).contains(len))
+    else {
+        return (HypercallStatus::InvalidParameter, 0);
+    };
+    if patch_gva == 0 {
+        return (HypercallStatus::InvalidParameter, 0);
+    }
+
+    // The guest on this processor may hold the lock. Let it retry instead of
+    // spinning forever.
+    let Some(mut hook_manager) = ept_hook::try_hook_manager() else {
+        return (HypercallStatus::Busy, 0);
+    };
+
+    // Copy the patch from the guest instead of dereferencing the guest pointer.
+    let mut patch = [0u8; BASE_PAGE_SIZE];
+    let patch = &mut patch[..len];
+    if guest_memory::read_guest_checked(guest, patch_gva, patch).is_err() {
+        return (HypercallStatus::InvalidParameter, 0);
+    }
+    let Ok(translation) = guest_memory::translate_guest(guest, address) else {
+        return (HypercallStatus::InvalidParameter, 0);
+    };
+    if guest.resolve_gpa(translation.gpa).is_none() {
+        return (HypercallStatus::AccessDenied, 0);
+    }
+    match hook_manager.install_pa(guest.id(), address, translation.gpa, patch) {
+        Ok(()) => (HypercallStatus::Success, 0),
+        Err(_) => (HypercallStatus::InvalidParameter, 0),
+    }
+}
+
+/// Handles `Hypercall::UninstallHook`.
+fn uninstall_hook<T: Guest>(guest: &mut T, address: u64) -> (HypercallStatus, u64) {
+    let Some(mut hook_manager) = ept_hook::try_hook_manager() else {
+        return (HypercallStatus::Busy, 0);
+    };
+    let Ok(translation) = guest_memory::translate_guest(guest, address) else {
+        return (HypercallStatus::InvalidParameter, 0);
+    };
+    match hook_manager.uninstall_pa(address, translation.gpa) {
+        Ok(()) => (HypercallStatus::Success, 0),
+        Err(_) => (HypercallStatus::InvalidParameter, 0),
+    }
+}
+
+/// Handles `Hypercall::ReadExitStats`.
+fn read_exit_stats<T: Guest>(
+    guest: &mut T,
+    gva: u64,
+    size: u64,
+    id: u64,
+) -> (HypercallStatus, u64) {
+    let id = if id == u64::MAX {
+        None
+    } else {
+        Some(usize::try_from(id).unwrap_or(usize::MAX))
+    };
+    let Some(snapshot) = exit_stats::snapshot(id) else {
+        return (HypercallStatus::InvalidParameter, 0);
+    };
+
+    let entry_size = core::mem::size_of::<ExitStatsEntry>();
+    let count = snapshot
+        .len()
+        .min(usize::try_from(size).unwrap_or(usize::MAX) / entry_size);
+    // Safety: `ExitStatsEntry` is `repr(C)` with `u64` fields only.
+    let bytes =
+        unsafe { core::slice::from_raw_parts(snapshot.as_ptr().cast::<u8>(), count * entry_size) };
+    match guest_memory::write_guest_checked(guest, gva, bytes) {
+        Ok(()) => (HypercallStatus::Success, count as u64),
+        Err(_) => (HypercallStatus::InvalidParameter, 0),
+    }
+}
+
+/// Handles `Hypercall::ReadAllocatorStats`.
+#[cfg(not(test))]
+fn read_allocator_stats<T: Guest>(guest: &mut T, gva: u64, size: u64) -> (HypercallStatus, u64) {
+    use crate::hypervisor::allocator::{self, AllocatorStats};
+
+    let Some(stats) = allocator::stats() else {
+        return (HypercallStatus::NotSupported, 0);
+    };
+    let len =
+        core::mem::size_of::<AllocatorStats>().min(usize::try_from(size).unwrap_or(usize::MAX));
+    // Safety: `AllocatorStats` is `repr(C)` with `u64` fields only.
+    let bytes =
+        unsafe { core::slice::from_raw_parts(core::ptr::from_ref(&stats).cast::<u8>(), len) };
+    match guest_memory::write_guest_checked(guest, gva, bytes) {
+        Ok(()) => (HypercallStatus::Success, len as u64),
+        Err(_) => (HypercallStatus::InvalidParameter, 0),
+    }
+}
+
+// The global allocator is not used in tests.
+#[cfg(test)]
+fn read_allocator_stats<T: Guest>(_guest: &mut T, _gva: u64, _size: u64) -> (HypercallStatus, u64) {
+    (HypercallStatus::NotSupported, 0)
+}
+
+/// Handles `Hypercall::ReadTamperEvents`.
+fn read_tamper_events<T: Guest>(guest: &mut T, gva: u64, size: u64) -> (HypercallStatus, u64) {
+    if !SHARED_HOST_DATA.get().unwrap().syscall_protection {
+        return (HypercallStatus::NotSupported, 0);
+    }
+    read_events(
+        guest,
+        gva,
+        size,
+        MAX_TAMPER_EVENTS,
+        syscall_protection::take_events,
+    )
+}
+
+/// Moves up to `max` events out with `take_events` into the buffer of `size`
+/// bytes at `gva`, for the hypercalls reading events. `E` must be `repr(C)`
+/// with `u64` sized fields only.
+fn read_events<T: Guest, E>(
+    guest: &mut T,
+    gva: u64,
+    size: u64,
+    max: usize,
+    take_events: fn(usize) -> Vec<E>,
+) -> (HypercallStatus, u64) {
+    // Check that the whole buffer is writable before taking events out, so
+    // that events are not lost on failure.
+    let event_size = core::mem::size_of::<E>();
+    let max = (usize::try_from(size).unwrap_or(usize::MAX) / event_size).min(max);
+    if guest_memory::write_guest_checked(guest, gva, &vec![0u8; max * event_size]).is_err() {
+        return (HypercallStatus::InvalidParameter, 0);
+    }
+    let events = take_events(max);
+    // Safety: `E` is `repr(C)` with `u64` sized fields only.
+    let bytes = unsafe {
+        core::slice::from_raw_parts(events.as_ptr().cast::<u8>(), events.len() * event_size)
+    };
+    match guest_memory::write_guest_checked(guest, gva, bytes) {
+        Ok(()) => (HypercallStatus::Success, events.len() as u64),
+        Err(_) => (HypercallStatus::InvalidParameter, 0),
+    }
+}
+
+/// Handles `Hypercall::ReadLog`.
+fn read_log<T: Guest>(guest: &mut T, gva: u64, size: u64) -> (HypercallStatus, u64) {
+    // The buffer cannot hold more than all processors have.
+    let max_size = percpu::all().count() * logger::LOG_BUFFER_SIZE;
+    let mut buffer = vec![0u8; usize::try_from(size).unwrap_or(usize::MAX).min(max_size)];
+
+    // Check that the whole buffer is writable before taking logs out, so that
+    // logs are not lost on failure.
+    if guest_memory::write_guest_checked(guest, gva, &buffer).is_err() {
+        return (HypercallStatus::InvalidParameter, 0);
+    }
+    let len = logger::drain(&mut buffer);
+    match guest_memory::write_guest_checked(guest, gva, &buffer[..len]) {
+        Ok(()) => (HypercallStatus::Success, len as u64),
+        Err(_) => (HypercallStatus::InvalidParameter, 0),
+    }
+}
+
 /// Represents a processor architecture that implements hardware-assisted virtualization.
 pub(crate) trait Architecture {
     type VirtualizationExtension: Extension;
@@ -162,44 +1224,492 @@ pub(crate) trait Architecture {
 }
 
 /// Represents an implementation of a hardware-assisted virtualization extension.
-pub(crate) trait Extension: Default {
+pub(crate) trait Extension: Sized {
+    /// Checks whether the current processor supports and allows enabling the
+    /// extension with the features the guest requires.
+    fn check_support() -> Result<(), VirtError>;
+
+    /// Allocates the per-processor data structures for the extension.
+    fn new() -> Result<Self, HvError>;
+
     /// Enables the hardware-assisted virtualization extension.
-    fn enable(&mut self);
+    fn enable(&mut self) -> Result<(), HvError>;
+
+    /// Disables the hardware-assisted virtualization extension.
+    fn disable(&mut self);
+}
+
+/// Represents a virtual processor as visible to VM-exit handlers.
+pub trait Vcpu {
+    /// Returns the index of the logical processor this vCPU runs on.
+    fn id(&self) -> usize;
+
+    /// Gets a reference to some of guest registers.
+    fn regs(&mut self) -> &mut Registers;
+
+    /// Returns the current privilege level of the guest.
+    fn cpl(&self) -> u8;
+
+    /// Returns the guest CR0.
+    fn cr0(&self) -> u64;
+
+    /// Returns the guest CR3.
+    fn cr3(&self) -> u64;
+
+    /// Returns the guest CR4.
+    fn cr4(&self) -> u64;
+
+    /// Returns the guest IA32_EFER.
+    fn efer(&self) -> u64;
+
+    /// Returns the selector, base, limit and attributes of the guest `register`.
+    fn segment(&self, register: SegmentRegister) -> GuestSegment;
+
+    /// Returns the guest value of `msr`.
+    fn read_msr(&self, msr: u32) -> u64;
+
+    /// Sets the guest value of `msr` to `value`.
+    fn write_msr(&mut self, msr: u32, value: u64);
+
+    /// Sets the guest CR2 to `value`.
+    fn set_cr2(&mut self, value: u64);
+
+    /// Returns the event to be injected into the guest on the next VM-entry.
+    /// Use `event::inject_event` to inject an event.
+    fn pending_event(&self) -> Option<Event>;
+
+    /// Makes `event` be injected into the guest on the next VM-entry, replacing
+    /// the pending one, if any.
+    fn set_pending_event(&mut self, event: Option<Event>);
+
+    /// Queues the external interrupt `vector` to be injected into the guest as
+    /// soon as it can receive it, or requests it in the virtual APIC with
+    /// `SharedHostData::interrupt_virtualization`. Use `event::inject_event` to
+    /// inject an interrupt.
+    fn queue_interrupt(&mut self, vector: u8);
+
+    /// Makes the guest execute one instruction and then calls `callback` on
+    /// this vCPU in the host. If the guest receives an event instead, such as
+    /// an interrupt, `callback` is called before the first instruction of its
+    /// handler.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Unsupported` if the processor cannot single-step the guest, or
+    /// `Busy` if single-stepping is already requested on this vCPU.
+    fn single_step(&mut self, callback: Box<SingleStepCallback>) -> Result<(), SingleStepError>;
+
+    /// Returns the guest physical pages written since the last harvest on any
+    /// processor, and starts tracking writes again. See `dirty_tracking`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Unsupported` if the processor does not support dirty tracking,
+    /// or `NotEnabled` if `enable_dirty_tracking` has not been called.
+    fn harvest_dirty_pages(&mut self) -> Result<DirtyBitmap, DirtyTrackingError>;
+
+    /// Returns the mapping of the guest physical address `gpa` if the EPT
+    /// (Intel) or NPT (AMD) maps it to the same host physical address, which
+    /// the host may then access on behalf of the guest. `None` if it is mapped
+    /// elsewhere, such as the pages hidden with `hidden_memory`, or not mapped.
+    fn resolve_gpa(&self, gpa: u64) -> Option<GpaMapping>;
+}
+
+/// The segment and descriptor-table registers of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentRegister {
+    Es,
+    Cs,
+    Ss,
+    Ds,
+    Fs,
+    Gs,
+    Ldtr,
+    Tr,
+    Gdtr,
+    Idtr,
+}
+
+impl SegmentRegister {
+    /// Returns the segment register encoded as `number` in the VM-exit
+    /// information: 0 for ES, 1 for CS, 2 for SS, 3 for DS, 4 for FS and 5 for
+    /// GS. DS for any other value.
+    pub(crate) fn from_number(number: u64) -> Self {
+        match number {
+            0 => Self::Es,
+            1 => Self::Cs,
+            2 => Self::Ss,
+            4 => Self::Fs,
+            5 => Self::Gs,
+            _ => Self::Ds,
+        }
+    }
+}
+
+/// The visible and hidden parts of a guest segment register.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GuestSegment {
+    /// The selector. Always zero for GDTR and IDTR.
+    pub selector: u16,
+    /// The base address.
+    pub base: u64,
+    /// The limit in bytes.
+    pub limit: u32,
+    /// The attributes in the format of the VMCB, that is, bits 40-47 and 52-55
+    /// of the segment descriptor. For example, bit 9 is L and bit 10 is D/B.
+    /// Always zero for GDTR and IDTR.
+    pub attributes: u16,
 }
 
 /// Represents an implementation of a guest.
-pub(crate) trait Guest {
+pub(crate) trait Guest: Vcpu {
     /// Creates an empty uninitialized guest, which must be activated with
     /// `activate` first.
-    fn new(id: usize) -> Self;
+    fn new(id: usize) -> Result<Self, HvError>
+    where
+        Self: Sized;
+
+    /// Returns whether the data shared by the guests of all processors, such
+    /// as the nested paging structures, is locked by any processor.
+    fn is_shared_data_locked() -> bool
+    where
+        Self: Sized;
 
     /// Tells the processor to operate on this guest. Must be called before any
     /// other functions are used.
-    fn activate(&mut self);
+    fn activate(&mut self) -> Result<(), HvError>;
 
-    /// Initializes the guest based on `registers` and the current system register
-    /// values.
-    fn initialize(&mut self, registers: &Registers);
+    /// Initializes the guest based on `registers`, `extended` if the extended
+    /// registers are switched, and the current system register values.
+    fn initialize(
+        &mut self,
+        registers: &Registers,
+        extended: Option<ExtendedRegisters>,
+    ) -> Result<(), HvError>;
 
     /// Runs the guest until VM-exit occurs.
     fn run(&mut self) -> VmExitReason;
 
-    /// Gets a reference to some of guest registers.
-    fn regs(&mut self) -> &mut Registers;
+    /// Handles the nested page fault not handled by any custom handler.
+    fn handle_nested_page_fault(&mut self, info: &NestedPageFaultInfo);
+
+    /// Returns the architecture specific details of the last VM-exit.
+    fn exit_info(&self) -> RawExitInfo;
+
+    /// Emulates `MOV` of `value` to the control register `cr`: CR0 or CR4
+    /// intercepted with `SharedHostData::cr_intercepts`, or CR3 intercepted
+    /// with `SharedHostData::cr3_tracking`.
+    fn write_cr(&mut self, cr: u8, value: u64);
+
+    /// Lets the guest load the CR3 values in `targets` without VM-exit where
+    /// supported.
+    fn set_cr3_targets(&mut self, targets: &[u64]);
+
+    /// Returns the state of the debug registers for `hw_breakpoint`.
+    fn debug_state(&mut self) -> &mut DebugState;
+
+    /// Returns the value of the debug register `dr`, 0-3, 6 or 7, the processor
+    /// uses for the guest.
+    fn read_dr(&self, dr: u8) -> u64;
+
+    /// Sets the value of the debug register `dr`, 0-3, 6 or 7, the processor
+    /// uses for the guest.
+    fn write_dr(&mut self, dr: u8, value: u64);
+
+    /// Enables or disables interception of `MOV` from and to the debug
+    /// registers and #DB for `hw_breakpoint`.
+    fn intercept_debug(&mut self, enable: bool);
+
+    /// Enables virtualization exceptions with the information page at
+    /// `info_pa`, or disables them if `None`. See `virtualization_exception`.
+    fn set_virtualization_exception_info(&mut self, info_pa: Option<u64>) -> Result<(), VeError>;
+
+    /// Returns whether the guest is running its own guest with nested
+    /// virtualization, whose state the `Vcpu` methods access then.
+    fn in_nested_guest(&self) -> bool;
+
+    /// Returns whether the processor can be devirtualized now. It cannot while
+    /// the guest is in VMX operation with nested virtualization.
+    fn can_devirtualize(&self) -> bool;
+
+    /// Tells the processor to stop operating on this guest, and returns the
+    /// guest state to resume it without the hypervisor.
+    fn deactivate(&mut self) -> GuestSystemState;
+}
+
+/// The guest register values to resume the guest without the hypervisor.
+#[derive(Debug)]
+pub(crate) struct GuestSystemState {
+    pub(crate) registers: Registers,
+    pub(crate) extended: Option<ExtendedRegisters>,
+    pub(crate) cr0: u64,
+    pub(crate) cr3: u64,
+    pub(crate) cr4: u64,
+    pub(crate) dr7: u64,
+    pub(crate) gdtr: DescriptorTablePointer<u64>,
+    pub(crate) idtr: DescriptorTablePointer<u64>,
+    pub(crate) es: u16,
+    pub(crate) cs: u16,
+    pub(crate) ss: u16,
+    pub(crate) ds: u16,
+    pub(crate) fs: u16,
+    pub(crate) gs: u16,
+    pub(crate) tr: u16,
+    pub(crate) ldtr: u16,
+    pub(crate) fs_base: u64,
+    pub(crate) gs_base: u64,
+    pub(crate) s_cet: u64,
 }
 
 /// The reasons of VM-exit and additional information.
-pub(crate) enum VmExitReason {
+#[derive(Clone, Copy, Debug)]
+pub enum VmExitReason {
+    /// The guest executed the `CPUID` instruction.
     Cpuid(InstructionInfo),
+    /// The guest executed the `RDMSR` instruction.
     Rdmsr(InstructionInfo),
+    /// The guest executed the `WRMSR` instruction.
     Wrmsr(InstructionInfo),
+    /// The guest executed the `XSETBV` instruction.
     XSetBv(InstructionInfo),
+    /// The INIT signal was delivered. Handled in the architecture specific code.
     InitSignal,
+    /// The Startup-IPI was delivered. Handled in the architecture specific code.
     StartupIpi,
-    NestedPageFault,
+    /// An NMI occurred, or the guest became ready to receive a pending NMI.
+    /// Handled in the architecture specific code.
+    Nmi,
+    /// The guest became ready to receive a queued external interrupt. Handled
+    /// in the architecture specific code.
+    InterruptWindow,
+    /// The guest completed the single-step requested with `Vcpu::single_step`.
+    /// Handled in the architecture specific code, which calls the callback.
+    SingleStep,
+    /// #DB intercepted for single-stepping or for `hw_breakpoint` occurred.
+    /// Handled in the architecture specific code, which calls the handlers of
+    /// the breakpoints hit, and injects it into the guest if the guest would
+    /// have received it otherwise.
+    DebugException,
+    /// The guest executed `VMFUNC` to switch EPT views (Intel). Handled in the
+    /// architecture specific code, which switches the view, or injects #UD
+    /// into the guest if it may not.
+    ViewSwitch,
+    /// EPT violation (Intel) or nested page fault (AMD) occurred.
+    NestedPageFault(NestedPageFaultInfo),
+    /// The guest executed the `VMCALL` (Intel) or `VMMCALL` (AMD) instruction.
+    Hypercall(InstructionInfo),
+    /// The guest executed an I/O instruction for an intercepted port.
+    Io(IoInstructionInfo),
+    /// The guest executed `MOV` to CR0 or CR4 that may change the bits guarded
+    /// with `SharedHostData::cr_intercepts`, or to CR3 with
+    /// `SharedHostData::cr3_tracking`.
+    CrWrite(CrWriteInfo),
+    /// The guest executed `MOV` from or to a debug register while any
+    /// breakpoint is set with `hw_breakpoint`.
+    DrAccess(DrAccessInfo),
+    /// The guest executed `INT3` while any marker is registered with
+    /// `breakpoint_marker`. RIP is the address of the instruction.
+    Breakpoint(InstructionInfo),
+    /// The period of `SharedHostData::preemption_timer` elapsed.
+    PreemptionTimer,
+    /// The guest executed an instruction of the virtualization extension, such
+    /// as `VMXON`. Handled in the architecture specific code, which emulates it
+    /// with nested virtualization, or injects #UD into the guest otherwise.
+    VirtualizationInstruction,
+    /// The VM-exit was for nested virtualization, for example, from the nested
+    /// guest into the guest. Handled in the architecture specific code. See
+    /// `SharedHostData::nested_virtualization`.
+    NestedGuest,
+    /// An SMI occurred with `SharedHostData::smi_intercept` (AMD). Handled in
+    /// the architecture specific code, which lets SMM handle it.
+    Smi,
+    /// A machine-check exception occurred in the guest. Handled in the
+    /// architecture independent code, which logs the errors and forwards #MC to
+    /// the guest. See `machine_check`.
+    MachineCheck,
+    /// The guest executed the `INVD` instruction. Handled per
+    /// `SharedHostData::cache_invalidation`.
+    Invd(InstructionInfo),
+    /// The guest executed the `WBINVD` or `WBNOINVD` instruction with
+    /// `CacheInvalidationPolicy::Ignore`.
+    Wbinvd(InstructionInfo),
+    /// An external interrupt occurred with
+    /// `SharedHostData::interrupt_virtualization`. Handled in the architecture
+    /// specific code, which delivers it through the virtual APIC.
+    ExternalInterrupt,
+    /// The guest completed a level-triggered interrupt delivered through the
+    /// virtual APIC. Handled in the architecture specific code, which completes
+    /// it on the local APIC. See `interrupt_virtualization`.
+    VirtualEoi,
+}
+
+/// Additional information of VM-exit caused by an instruction.
+#[derive(Clone, Copy, Debug)]
+pub struct InstructionInfo {
+    /// The next RIP of the guest in case the current instruction is emulated.
+    pub next_rip: u64,
+}
+
+/// Additional information of VM-exit caused by `MOV` to a control register.
+#[derive(Clone, Copy, Debug)]
+pub struct CrWriteInfo {
+    /// The next RIP of the guest in case the current instruction is emulated.
+    pub next_rip: u64,
+    /// The control register written, 0, 3 or 4.
+    pub cr: u8,
+    /// The value the guest writes.
+    pub value: u64,
+}
+
+/// Additional information of VM-exit caused by `MOV` from or to a debug
+/// register.
+#[derive(Clone, Copy, Debug)]
+pub struct DrAccessInfo {
+    /// The next RIP of the guest in case the current instruction is emulated.
+    pub next_rip: u64,
+    /// The debug register accessed, 0-7.
+    pub dr: u8,
+    /// The general purpose register accessed, numbered as in the instruction
+    /// encoding.
+    pub gpr: u8,
+    /// Whether the instruction is `MOV` to the debug register, as opposed to
+    /// from it.
+    pub write: bool,
 }
 
-pub(crate) struct InstructionInfo {
+/// Additional information of VM-exit caused by an I/O instruction.
+#[derive(Clone, Copy, Debug)]
+pub struct IoInstructionInfo {
     /// The next RIP of the guest in case the current instruction is emulated.
-    pub(crate) next_rip: u64,
+    pub next_rip: u64,
+    /// The I/O port accessed.
+    pub port: u16,
+    /// The size of the access in bytes, 1, 2 or 4.
+    pub size: u8,
+    /// Whether the instruction is `IN` or `INS`, as opposed to `OUT` or `OUTS`.
+    pub is_in: bool,
+    /// Whether the instruction is `INS` or `OUTS`.
+    pub string: bool,
+    /// Whether the instruction has the REP prefix.
+    pub rep: bool,
+    /// The address size of `INS` and `OUTS` in bytes, 2, 4 or 8, with the
+    /// address-size prefix applied. Unspecified for `IN` and `OUT`.
+    pub address_size: u8,
+    /// The segment register `OUTS` reads from, with the segment override
+    /// prefix applied. `INS` always writes to ES. Unspecified for `IN` and
+    /// `OUT`.
+    pub segment: SegmentRegister,
+}
+
+/// The mapping of a guest physical address in the EPT (Intel) or NPT (AMD) for
+/// data access, as returned by `Vcpu::resolve_gpa`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GpaMapping {
+    /// The accesses the guest is permitted. Hooked pages are reported with
+    /// the permissions of the original page.
+    pub permissions: Permissions,
+    /// Whether the page is RAM, that is, of the write-back memory type, unlike
+    /// memory-mapped I/O.
+    pub ram: bool,
+}
+
+/// Additional information of EPT violation or nested page fault.
+#[derive(Clone, Copy, Debug)]
+pub struct NestedPageFaultInfo {
+    /// The guest physical address that caused the fault.
+    pub gpa: u64,
+    /// Whether the fault was caused by write access.
+    pub write: bool,
+    /// Whether the fault was caused by instruction fetch.
+    pub execute: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::test_support::MockGuest;
+
+    const INFO: InstructionInfo = InstructionInfo { next_rip: 0x1003 };
+
+    /// Returns the guest about to make the hypercall `rcx` with `rdx`, `r8` and
+    /// `r9`.
+    fn guest(rcx: u64, rdx: u64, r8: u64, r9: u64) -> MockGuest {
+        let mut guest = MockGuest::new(0).unwrap();
+        let regs = guest.regs();
+        regs.rax = HYPERCALL_MAGIC;
+        (regs.rcx, regs.rdx, regs.r8, regs.r9) = (rcx, rdx, r8, r9);
+        regs.rip = 0x1000;
+        guest
+    }
+
+    /// Returns RAX and RDX after `guest` makes the hypercall, and asserts that
+    /// the hypercall completes.
+    fn call(guest: &mut MockGuest) -> (u64, u64) {
+        assert!(!handle_hypercall(guest, &INFO));
+        let regs = guest.regs();
+        assert_eq!(regs.rip, INFO.next_rip);
+        (regs.rax, regs.rdx)
+    }
+
+    #[test]
+    fn hypercalls_are_dispatched() {
+        let success = HypercallStatus::Success as u64;
+        let mut ping = guest(Hypercall::Ping as u64, 0, 0, 0);
+        assert_eq!(call(&mut ping), (success, HYPERCALL_PONG));
+
+        let mut version = guest(Hypercall::GetVersion as u64, 0, 0, 0);
+        assert_eq!(call(&mut version), (success, HYPERCALL_ABI_VERSION));
+
+        let mut invalid = guest(0xffff, 0, 0, 0);
+        let status = HypercallStatus::InvalidHypercall as u64;
+        assert_eq!(call(&mut invalid), (status, 0));
+
+        let mut devirtualize = guest(Hypercall::Devirtualize as u64, 0, 0, 0);
+        assert!(handle_hypercall(&mut devirtualize, &INFO));
+        assert_eq!(devirtualize.regs().rax, success);
+    }
+
+    #[test]
+    fn hypercalls_are_checked() {
+        // Hypercalls from CPL 3 are denied.
+        let mut user = guest(Hypercall::Ping as u64, 0, 0, 0);
+        user.cpl = 3;
+        let status = HypercallStatus::AccessDenied as u64;
+        assert_eq!(call(&mut user), (status, 0));
+
+        // Hypercalls without the magic cause #UD without changing registers.
+        let mut foreign = guest(Hypercall::Ping as u64, 0x1234, 0, 0);
+        foreign.regs().rax = 0x4000_0000;
+        assert!(!handle_hypercall(&mut foreign, &INFO));
+        assert_eq!(
+            (foreign.regs.rax, foreign.regs.rdx, foreign.regs.rip),
+            (0x4000_0000, 0x1234, 0x1000)
+        );
+        assert_eq!(
+            foreign.pending_event,
+            Some(Event::Exception {
+                vector: 6,
+                error_code: None
+            })
+        );
+    }
+
+    #[test]
+    fn hook_hypercalls_are_validated() {
+        let invalid = HypercallStatus::InvalidParameter as u64;
+        let busy = HypercallStatus::Busy as u64;
+
+        // The patch must be one to the page size bytes at a non-null address.
+        for (patch, len) in [(0x2000, 0), (0x2000, 0x1001), (0, 1)] {
+            let mut install = guest(Hypercall::InstallHook as u64, 0x1000, patch, len);
+            assert_eq!(call(&mut install), (invalid, 0));
+        }
+
+        // The hook manager is not waited for.
+        let _hook_manager = ept_hook::hook_manager();
+        let mut install = guest(Hypercall::InstallHook as u64, 0x1000, 0x2000, 1);
+        assert_eq!(call(&mut install), (busy, 0));
+        let mut uninstall = guest(Hypercall::UninstallHook as u64, 0x1000, 0, 0);
+        assert_eq!(call(&mut uninstall), (busy, 0));
+    }
 }
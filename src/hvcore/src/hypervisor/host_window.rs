@@ -0,0 +1,211 @@
+//! This module implements the host window, a per-processor linear address range
+//! in the host address space that can be remapped to any physical address. It
+//! lets the host access guest physical memory regardless of what the host
+//! paging structures map.
+//!
+//! The host runs with its own copy of the PML4 it would otherwise use, with one
+//! unused entry pointing to the paging structures for the window. The rest of
+//! the PML4 entries are captured at virtualization time and share lower level
+//! paging structures with the original.
+
+use alloc::{boxed::Box, vec::Vec};
+#[cfg(not(test))]
+use bit_field::BitField;
+use spin::Once;
+#[cfg(not(test))]
+use x86::cpuid::cpuid;
+use x86::{
+    bits64::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE},
+    controlregs::Cr4,
+};
+
+use crate::hypervisor::{
+    apic_id,
+    paging_structures::{Pd, Pdpt, Pml4, Pt},
+    platform_ops,
+    support::zeroed_box,
+    x86_instructions::{cr3, cr4},
+    HvError, SharedHostData, VirtError,
+};
+#[cfg(not(test))]
+use crate::hypervisor::{
+    guest_memory::{self, PagingContext},
+    x86_instructions::{cr0, rdmsr},
+};
+
+#[cfg(test)]
+use crate::hypervisor::test_support;
+
+/// Builds the host window. Must be called from the guest context before any
+/// processor is virtualized.
+///
+/// # Errors
+///
+/// Returns `Unsupported` if more processors may be enumerated than the window
+/// supports or 5-level paging is enabled, or `NoHostWindowEntry` if the PML4
+/// has no unused entry in the upper half for the window.
+pub(crate) fn init(shared_host: &SharedHostData) -> Result<(), HvError> {
+    let _ = HOST_WINDOW.try_call_once(|| HostWindow::new(shared_host))?;
+    Ok(())
+}
+
+/// Returns the physical address of the PML4 the host should run with.
+pub(crate) fn host_cr3() -> u64 {
+    HOST_WINDOW.get().unwrap().pml4_pa
+}
+
+/// Maps the page containing `pa` to the window of the processor `id`, and
+/// returns the linear address of `pa` through the window. Must be called from
+/// the host on the processor `id`.
+///
+/// The returned address is valid until the next call on the same processor.
+pub(crate) fn map(id: usize, pa: u64) -> *mut u8 {
+    map_page(id, pa, false)
+}
+
+/// Does the same as `map` with the UC memory type, for memory-mapped registers
+/// such as those of the local APIC.
+pub(crate) fn map_uncacheable(id: usize, pa: u64) -> *mut u8 {
+    map_page(id, pa, true)
+}
+
+/// Returns the physical address `va` maps to in the current host address
+/// space, or `None` if `va` is not mapped. The host paging structures are read
+/// through the window of the processor `id`. Must be called from the host on
+/// the processor `id`, where `platform_ops` cannot be used.
+#[cfg(not(test))]
+pub(crate) fn host_pa(id: usize, va: u64) -> Option<u64> {
+    let context = PagingContext {
+        cr0: cr0().bits() as u64,
+        cr3: cr3(),
+        cr4: cr4().bits() as u64,
+        efer: rdmsr(x86::msr::IA32_EFER),
+        // See: Table 1-17. Information Returned by CPUID Instruction
+        max_phys_addr_bits: cpuid!(0x8000_0008).eax.get_bits(0..=7) as u8,
+    };
+    let translation = guest_memory::translate(&context, va, |pa| unsafe {
+        map(id, pa).cast::<u64>().read_volatile()
+    });
+    translation.ok().map(|translation| translation.gpa)
+}
+
+#[cfg(not(test))]
+fn map_page(id: usize, pa: u64, uncacheable: bool) -> *mut u8 {
+    let window = HOST_WINDOW.get().unwrap();
+    let va = window.base + (id * BASE_PAGE_SIZE) as u64;
+
+    let pt = window.pts[id / PT_ENTRY_COUNT];
+    let index = id % PT_ENTRY_COUNT;
+    let mut pte = unsafe { (*pt).0.entries[index] };
+    pte.set_present(true);
+    pte.set_writable(true);
+    pte.set_pfn(pa >> BASE_PAGE_SHIFT);
+    // PCD and PWT select UC with the default PAT.
+    // See: Table 13-11. Selection of PAT Entries with PAT, PCD, and PWT Flags
+    pte.set_write_through(uncacheable);
+    pte.set_cache_disable(uncacheable);
+    unsafe {
+        (*pt).0.entries[index] = pte;
+        x86::tlb::flush(va as _);
+    };
+    (va + (pa % BASE_PAGE_SIZE as u64)) as *mut u8
+}
+
+struct HostWindow {
+    #[allow(dead_code)]
+    pml4: Box<Pml4>,
+    pml4_pa: u64,
+    #[allow(dead_code)]
+    pdpt: Box<Pdpt>,
+    #[allow(dead_code)]
+    pd: Box<Pd>,
+
+    /// The PTs for the window, linked from the consecutive PD entries, where
+    /// the Nth entry across them maps the window for the Nth processor. Each
+    /// processor only updates its own entry.
+    #[cfg_attr(test, allow(dead_code))]
+    pts: Vec<*mut Pt>,
+
+    /// The linear address of the window for the first processor.
+    #[cfg_attr(test, allow(dead_code))]
+    base: u64,
+}
+
+// Safety: `pts` are never freed, and each entry is only accessed by one processor.
+unsafe impl Send for HostWindow {}
+unsafe impl Sync for HostWindow {}
+
+impl HostWindow {
+    fn new(shared_host: &SharedHostData) -> Result<Self, HvError> {
+        let ops = platform_ops::get();
+        let processor_count = apic_id::capacity();
+        if processor_count > PT_ENTRY_COUNT * PT_ENTRY_COUNT {
+            return Err(VirtError::TooManyProcessors(processor_count).into());
+        }
+        if cr4().contains(Cr4::CR4_ENABLE_LA57) {
+            return Err(VirtError::FiveLevelPaging.into());
+        }
+
+        // Copy the PML4 the host would have used: the one in `SharedHostData`
+        // if specified, or the current one.
+        let mut pml4 = zeroed_box::<Pml4>();
+        if let Some(host_pt) = &shared_host.pt {
+            pml4.0.entries = host_pt.pml4.0.entries;
+        } else {
+            let current = ops.va(cr3() & !(BASE_PAGE_SIZE as u64 - 1)).cast::<Pml4>();
+            pml4.0.entries = unsafe { (*current).0.entries };
+        }
+
+        // Link PDPT -> PD -> PTs for the first 2MB per 512 processors of an
+        // unused PML4 entry in the upper half.
+        let mut pd = zeroed_box::<Pd>();
+        let pts: Vec<*mut Pt> = (0..processor_count.div_ceil(PT_ENTRY_COUNT))
+            .map(|_| Box::into_raw(zeroed_box::<Pt>()))
+            .collect();
+        for (pde, &pt) in pd.0.entries.iter_mut().zip(&pts) {
+            pde.set_present(true);
+            pde.set_writable(true);
+            pde.set_pfn(ops.pa(pt as _) >> BASE_PAGE_SHIFT);
+        }
+
+        let mut pdpt = zeroed_box::<Pdpt>();
+        pdpt.0.entries[0].set_present(true);
+        pdpt.0.entries[0].set_writable(true);
+        pdpt.0.entries[0].set_pfn(ops.pa(pd.as_ref() as *const _ as _) >> BASE_PAGE_SHIFT);
+
+        let index = (256..512)
+            .find(|&i| !pml4.0.entries[i].present())
+            .ok_or(HvError::NoHostWindowEntry)?;
+        pml4.0.entries[index].set_present(true);
+        pml4.0.entries[index].set_writable(true);
+        pml4.0.entries[index].set_pfn(ops.pa(pdpt.as_ref() as *const _ as _) >> BASE_PAGE_SHIFT);
+
+        // Sign-extend bit 47 to make the address canonical.
+        let base = 0xffff_0000_0000_0000 | ((index as u64) << 39);
+        let pml4_pa = ops.pa(pml4.as_ref() as *const _ as _);
+        log::debug!("Host window at {base:#x?}");
+        Ok(Self {
+            pml4,
+            pml4_pa,
+            pdpt,
+            pd,
+            pts,
+            base,
+        })
+    }
+}
+
+static HOST_WINDOW: Once<HostWindow> = Once::new();
+
+/// The number of entries in a PT, thus processors per PT of the window.
+const PT_ENTRY_COUNT: usize = 512;
+
+#[cfg(test)]
+pub(crate) fn host_pa(_id: usize, va: u64) -> Option<u64> {
+    test_support::host_pa(va)
+}
+
+#[cfg(test)]
+fn map_page(_id: usize, pa: u64, _uncacheable: bool) -> *mut u8 {
+    test_support::map_host_window(pa)
+}
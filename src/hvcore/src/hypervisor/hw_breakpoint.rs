@@ -0,0 +1,541 @@
+//! This module implements hardware breakpoints set by the host, for example, by
+//! a debugger attached through the embedder of this crate. Set with
+//! [`set_hw_breakpoint`], they use the debug registers of each processor while
+//! remaining invisible to the guest, which keeps using the debug registers for
+//! its own breakpoints.
+//!
+//! While any breakpoint is set, `MOV` from and to the debug registers and #DB
+//! cause VM-exit. The guest reads and writes the values it expects, kept per
+//! processor, and the processor uses those values merged with the breakpoints,
+//! which take precedence over the guest's ones in the same slots. #DB due to
+//! the breakpoints calls their handlers and is not delivered to the guest.
+//! Other #DB is delivered with the guest DR6 updated. As with the hooks, each
+//! processor picks up changes on the next VM-exit by comparing the generation
+//! of the breakpoints with the one it last applied.
+//!
+//! The addresses are linear addresses, thus, the breakpoints hit in any
+//! address space mapping them. On AMD processors, decode assists are required.
+//!
+//! ```ignore
+//! let slot = set_hw_breakpoint(entry_gva, BreakpointKind::Execute, |vcpu, hit| {
+//!     log::info!("Hit {:#x?} with RCX {:#x?}", hit.gva, vcpu.regs().rcx);
+//! })?;
+//! ```
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::sync::Arc;
+use bit_field::BitField;
+use spin::Mutex;
+use x86::{bits64::rflags::RFlags, cpuid::cpuid};
+
+use crate::hypervisor::{
+    event::{self, Event},
+    host::{DrAccessInfo, Guest, Vcpu},
+};
+
+/// The number of breakpoints the debug registers can hold, DR0-DR3.
+pub const BREAKPOINT_COUNT: usize = 4;
+
+/// The types of access a breakpoint hits on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// Hits before the instruction at the address is executed.
+    Execute,
+
+    /// Hits after the instruction writing to the range is executed.
+    Write(BreakpointLength),
+
+    /// Hits after the instruction reading or writing the range is executed.
+    ReadWrite(BreakpointLength),
+}
+
+/// The sizes of the ranges data breakpoints watch. The address of the range
+/// must be aligned to its size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakpointLength {
+    One,
+    Two,
+    Four,
+    Eight,
+}
+
+impl BreakpointKind {
+    /// Returns the size of the range the breakpoint watches.
+    fn len(self) -> u64 {
+        match self {
+            Self::Execute => 1,
+            Self::Write(length) | Self::ReadWrite(length) => match length {
+                BreakpointLength::One => 1,
+                BreakpointLength::Two => 2,
+                BreakpointLength::Four => 4,
+                BreakpointLength::Eight => 8,
+            },
+        }
+    }
+
+    /// Returns the bits of DR7 enabling the breakpoint in `slot`.
+    // See: 18.2.4 Debug Control Register (DR7)
+    fn dr7_bits(self, slot: usize) -> u64 {
+        let (rw, length) = match self {
+            Self::Execute => (0b00, BreakpointLength::One),
+            Self::Write(length) => (0b01, length),
+            Self::ReadWrite(length) => (0b11, length),
+        };
+        let len = match length {
+            BreakpointLength::One => 0b00,
+            BreakpointLength::Two => 0b01,
+            BreakpointLength::Four => 0b11,
+            BreakpointLength::Eight => 0b10,
+        };
+        (1 << (slot * 2)) | (rw << (16 + slot * 4)) | (len << (18 + slot * 4))
+    }
+
+    /// Returns the bits of DR7 configuring the breakpoint in `slot`.
+    fn slot_mask(slot: usize) -> u64 {
+        (0b11 << (slot * 2)) | (0b1111 << (16 + slot * 4))
+    }
+}
+
+/// The breakpoint hit, passed to its handler.
+#[derive(Clone, Copy, Debug)]
+pub struct BreakpointHit {
+    /// The slot returned by `set_hw_breakpoint`.
+    pub slot: usize,
+
+    /// The address of the breakpoint.
+    pub gva: u64,
+
+    /// The kind of the breakpoint.
+    pub kind: BreakpointKind,
+}
+
+/// Represents a handler called when the guest hits a breakpoint. Handlers run
+/// in the host context with interrupts disabled. They must not call any
+/// platform API and should return as soon as possible.
+pub type BreakpointHandler = dyn Fn(&mut dyn Vcpu, &BreakpointHit) + Send + Sync;
+
+/// The errors setting and clearing breakpoints may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakpointError {
+    #[error("all breakpoints are in use")]
+    NoFreeSlot,
+
+    #[error("{gva:#x?} is not aligned to {len} bytes")]
+    Misaligned { gva: u64, len: u64 },
+
+    #[error("decode assists are required to virtualize the debug registers")]
+    Unsupported,
+
+    #[error("no breakpoint is set in slot {0}")]
+    NotSet(usize),
+}
+
+/// Sets the breakpoint of `kind` at `gva` and calls `handler` when the guest
+/// hits it. Returns the slot to clear the breakpoint with. Changes take effect
+/// on each processor on the next VM-exit on that processor.
+///
+/// # Errors
+///
+/// Returns `Err` if all slots are in use, `gva` is not aligned to the length of
+/// `kind`, or the processor cannot virtualize the debug registers.
+pub fn set_hw_breakpoint(
+    gva: u64,
+    kind: BreakpointKind,
+    handler: impl Fn(&mut dyn Vcpu, &BreakpointHit) + Send + Sync + 'static,
+) -> Result<usize, BreakpointError> {
+    // Without decode assists, the register `MOV` from or to a debug register
+    // accesses is unknown.
+    // See: 15.33.1 MOV CRx/DRx Intercepts
+    let is_intel = x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+    if !is_intel && !cpuid!(0x8000_000a).edx.get_bit(7) {
+        return Err(BreakpointError::Unsupported);
+    }
+    let len = kind.len();
+    if gva & (len - 1) != 0 {
+        return Err(BreakpointError::Misaligned { gva, len });
+    }
+
+    let mut breakpoints = BREAKPOINTS.lock();
+    let slot = breakpoints
+        .iter()
+        .position(Option::is_none)
+        .ok_or(BreakpointError::NoFreeSlot)?;
+    breakpoints[slot] = Some(Breakpoint {
+        gva,
+        kind,
+        handler: Arc::new(handler),
+    });
+
+    log::debug!("Set {kind:?} breakpoint {slot} at {gva:#x?}");
+    let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+    Ok(slot)
+}
+
+/// Clears the breakpoint set with `set_hw_breakpoint` in `slot`.
+///
+/// # Errors
+///
+/// Returns `Err` if no breakpoint is set in `slot`.
+pub fn clear_hw_breakpoint(slot: usize) -> Result<(), BreakpointError> {
+    let mut breakpoints = BREAKPOINTS.lock();
+    if breakpoints.get_mut(slot).and_then(Option::take).is_none() {
+        return Err(BreakpointError::NotSet(slot));
+    }
+
+    log::debug!("Cleared breakpoint {slot}");
+    let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+    Ok(())
+}
+
+/// Applies changes of the breakpoints onto the debug registers of `guest` if
+/// any. Called before each VM-entry.
+pub(crate) fn sync<T: Guest>(guest: &mut T) {
+    let generation = GENERATION.load(Ordering::Acquire);
+    if guest.debug_state().generation == generation {
+        return;
+    }
+
+    // Retry on the next VM-exit if the guest on any processor owns the lock,
+    // as spinning on it could deadlock with the guest on this processor.
+    let Some(breakpoints) = BREAKPOINTS.try_lock() else {
+        return;
+    };
+    let host = breakpoints.clone();
+    drop(breakpoints);
+
+    // Save the guest values when the debug registers start to be virtualized.
+    let was_active = guest.debug_state().is_active();
+    if !was_active {
+        let mut values = DebugRegisters::RESET;
+        for (index, dr) in values.dr.iter_mut().enumerate() {
+            *dr = guest.read_dr(index as u8);
+        }
+        values.dr6 = guest.read_dr(6);
+        values.dr7 = guest.read_dr(7);
+        guest.debug_state().guest = values;
+    }
+
+    let state = guest.debug_state();
+    state.generation = generation;
+    state.host = host;
+    match (was_active, state.is_active()) {
+        (false, false) => {}
+        (true, false) => {
+            restore_guest_values(guest);
+            guest.intercept_debug(false);
+        }
+        (_, true) => {
+            guest.write_dr(6, DR6_INIT);
+            apply(guest);
+            guest.intercept_debug(true);
+        }
+    }
+}
+
+/// Emulates `MOV` from or to a debug register against the values the guest
+/// expects.
+pub(crate) fn handle_dr_access<T: Guest>(guest: &mut T, info: &DrAccessInfo) {
+    const CR4_DE: u64 = 1 << 3;
+
+    // The VM-exit takes priority over #GP due to CPL and #UD due to CR4.DE.
+    // See: 26.1.3 Instructions That Cause VM Exits Conditionally
+    if guest.cpl() != 0 {
+        inject_exception(guest, 13, Some(0));
+        return;
+    }
+    if matches!(info.dr, 4 | 5) && guest.cr4() & CR4_DE != 0 {
+        inject_exception(guest, 6, None);
+        return;
+    }
+    // DR4 and DR5 are aliases of DR6 and DR7 unless CR4.DE is set.
+    // See: 18.2.2 Debug Registers DR4 and DR5
+    let dr = match info.dr {
+        4 => 6,
+        5 => 7,
+        dr => dr,
+    };
+
+    let state = guest.debug_state();
+    let active = state.is_active();
+    if active && state.guest.dr7 & DR7_GD != 0 {
+        // General detection: the access delivers #DB instead, which clears GD
+        // for the handler to access the debug registers.
+        // See: 18.2.4 Debug Control Register (DR7)
+        state.guest.dr7 &= !DR7_GD;
+        state.guest.dr6 |= DR6_BD;
+        inject_exception(guest, 1, None);
+        return;
+    }
+
+    if info.write {
+        let value = guest.regs().gpr(info.gpr);
+        log::trace!("MOV DR{dr}, {value:#x?}");
+        if active {
+            guest.debug_state().guest.write(dr, value);
+            apply(guest);
+        } else {
+            guest.write_dr(dr, value);
+        }
+    } else {
+        let value = if active {
+            guest.debug_state().guest.read(dr)
+        } else {
+            guest.read_dr(dr)
+        };
+        guest.regs().set_gpr(info.gpr, value);
+    }
+    guest.regs().rip = info.next_rip;
+}
+
+/// Calls the handlers of the breakpoints #DB with the causes in `dr6` is due
+/// to, and returns the causes of #DB for the guest. The caller injects #DB if
+/// any is returned, or if `dr6` has no cause at all. The guest DR6 is updated
+/// with the causes returned.
+pub(crate) fn handle_debug_exception<T: Guest>(guest: &mut T, dr6: u64) -> u64 {
+    let state = guest.debug_state();
+    if !state.is_active() {
+        return dr6 & DR6_CAUSES;
+    }
+
+    let (hits, causes) = state.split(dr6);
+    state.guest.dr6 |= causes;
+    guest.write_dr(6, DR6_INIT);
+
+    for (slot, breakpoint) in hits.iter().enumerate() {
+        let Some(breakpoint) = breakpoint else {
+            continue;
+        };
+        let hit = BreakpointHit {
+            slot,
+            gva: breakpoint.gva,
+            kind: breakpoint.kind,
+        };
+        log::trace!("Breakpoint hit: {hit:x?}");
+        (breakpoint.handler)(guest, &hit);
+
+        // Execute the instruction without hitting the breakpoint again.
+        // See: 18.3.1.1 Instruction-Breakpoint Exception Condition
+        if breakpoint.kind == BreakpointKind::Execute {
+            guest.regs().rflags |= RFlags::FLAGS_RF.bits();
+        }
+    }
+    causes
+}
+
+/// Reapplies the breakpoints after the processor reset the debug registers on
+/// INIT.
+pub(crate) fn reset<T: Guest>(guest: &mut T) {
+    let state = guest.debug_state();
+    state.guest = DebugRegisters::RESET;
+    if state.is_active() {
+        apply(guest);
+    }
+}
+
+/// Loads the values the guest expects into the debug registers to resume the
+/// guest without the hypervisor.
+pub(crate) fn restore<T: Guest>(guest: &mut T) {
+    if guest.debug_state().is_active() {
+        restore_guest_values(guest);
+        guest.debug_state().host = Default::default();
+    }
+}
+
+/// Loads the guest values merged with the breakpoints into the debug registers.
+fn apply<T: Guest>(guest: &mut T) {
+    let state = guest.debug_state();
+    let values = state.effective();
+    for (index, &dr) in values.dr.iter().enumerate() {
+        guest.write_dr(index as u8, dr);
+    }
+    guest.write_dr(7, values.dr7);
+}
+
+fn restore_guest_values<T: Guest>(guest: &mut T) {
+    let values = guest.debug_state().guest;
+    for (index, &dr) in values.dr.iter().enumerate() {
+        guest.write_dr(index as u8, dr);
+    }
+    guest.write_dr(6, values.dr6);
+    guest.write_dr(7, values.dr7);
+}
+
+fn inject_exception<T: Guest>(guest: &mut T, vector: u8, error_code: Option<u32>) {
+    let exception = Event::Exception { vector, error_code };
+    if let Err(err) = event::inject_event(guest, exception) {
+        log::error!("Could not inject {exception:?}: {err}");
+    }
+}
+
+// See: 18.2.3 Debug Status Register (DR6)
+const DR6_B0_B3: u64 = 0b1111;
+const DR6_BD: u64 = 1 << 13;
+const DR6_BS: u64 = 1 << 14;
+const DR6_BT: u64 = 1 << 15;
+const DR6_CAUSES: u64 = DR6_B0_B3 | DR6_BD | DR6_BS | DR6_BT;
+const DR6_INIT: u64 = 0xffff_0ff0;
+const DR7_GD: u64 = 1 << 13;
+
+/// A breakpoint set with `set_hw_breakpoint`.
+#[derive(Clone)]
+struct Breakpoint {
+    gva: u64,
+    kind: BreakpointKind,
+    handler: Arc<BreakpointHandler>,
+}
+
+impl core::fmt::Debug for Breakpoint {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Breakpoint")
+            .field("gva", &format_args!("{:#x}", self.gva))
+            .field("kind", &self.kind)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The values of DR0-DR3, DR6 and DR7.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct DebugRegisters {
+    dr: [u64; BREAKPOINT_COUNT],
+    dr6: u64,
+    dr7: u64,
+}
+
+impl DebugRegisters {
+    /// The values after INIT.
+    // See: Table 9-1. IA-32 and Intel 64 Processor States Following Power-up,
+    //      Reset, or INIT
+    const RESET: Self = Self {
+        dr: [0; BREAKPOINT_COUNT],
+        dr6: DR6_INIT,
+        dr7: 0x400,
+    };
+
+    fn read(&self, dr: u8) -> u64 {
+        match dr {
+            0..=3 => self.dr[dr as usize],
+            6 => self.dr6,
+            _ => self.dr7,
+        }
+    }
+
+    fn write(&mut self, dr: u8, value: u64) {
+        match dr {
+            0..=3 => self.dr[dr as usize] = value,
+            6 => self.dr6 = value,
+            _ => self.dr7 = value,
+        }
+    }
+}
+
+/// The debug registers of a processor: the values the guest expects and the
+/// breakpoints applied.
+#[derive(Debug)]
+pub(crate) struct DebugState {
+    /// The generation of `host`. See `sync`.
+    generation: u64,
+
+    /// The breakpoints, indexed by their slots.
+    host: [Option<Breakpoint>; BREAKPOINT_COUNT],
+
+    /// The values the guest expects while any breakpoint is set.
+    guest: DebugRegisters,
+}
+
+impl DebugState {
+    pub(crate) const fn new() -> Self {
+        Self {
+            generation: 0,
+            host: [None, None, None, None],
+            guest: DebugRegisters::RESET,
+        }
+    }
+
+    /// Returns whether any breakpoint is applied, thus, the debug registers are
+    /// virtualized.
+    pub(crate) fn is_active(&self) -> bool {
+        self.host.iter().any(Option::is_some)
+    }
+
+    /// Returns the values for the processor: the guest values with the slots
+    /// of the breakpoints replaced. General detection is disabled, as `MOV`
+    /// from and to the debug registers is emulated.
+    fn effective(&self) -> DebugRegisters {
+        let mut values = self.guest;
+        values.dr7 &= !DR7_GD;
+        for (slot, breakpoint) in self.host.iter().enumerate() {
+            if let Some(breakpoint) = breakpoint {
+                values.dr[slot] = breakpoint.gva;
+                values.dr7 = (values.dr7 & !BreakpointKind::slot_mask(slot))
+                    | breakpoint.kind.dr7_bits(slot);
+            }
+        }
+        values
+    }
+
+    /// Splits the causes of #DB in `dr6` into the breakpoints hit and the
+    /// causes for the guest.
+    fn split(&self, dr6: u64) -> ([Option<Breakpoint>; BREAKPOINT_COUNT], u64) {
+        let mut hits: [Option<Breakpoint>; BREAKPOINT_COUNT] = Default::default();
+        let mut causes = dr6 & DR6_CAUSES;
+        for (slot, breakpoint) in self.host.iter().enumerate() {
+            if breakpoint.is_some() {
+                if dr6.get_bit(slot) {
+                    hits[slot].clone_from(breakpoint);
+                }
+                causes.set_bit(slot, false);
+            }
+        }
+        (hits, causes)
+    }
+}
+
+/// Returns whether the breakpoints are locked by any processor. See
+/// `host::fail_open`.
+pub(crate) fn is_locked() -> bool {
+    BREAKPOINTS.is_locked()
+}
+
+/// Incremented whenever a breakpoint is set or cleared.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The breakpoints, indexed by their slots.
+static BREAKPOINTS: Mutex<[Option<Breakpoint>; BREAKPOINT_COUNT]> =
+    Mutex::new([None, None, None, None]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_with(slot: usize, gva: u64, kind: BreakpointKind) -> DebugState {
+        let mut state = DebugState::new();
+        state.host[slot] = Some(Breakpoint {
+            gva,
+            kind,
+            handler: Arc::new(|_, _| {}),
+        });
+        state
+    }
+
+    #[test]
+    fn breakpoints_replace_guest_slots() {
+        let mut state = state_with(1, 0x1000, BreakpointKind::Write(BreakpointLength::Eight));
+        state.guest.dr = [0xa000, 0xb000, 0xc000, 0xd000];
+        // L0, G1 as an execute breakpoint, L2, and GD.
+        state.guest.dr7 = 0x400 | DR7_GD | 0b1_1001;
+
+        let values = state.effective();
+        assert_eq!(values.dr, [0xa000, 0x1000, 0xc000, 0xd000]);
+        assert_eq!(values.dr7, 0x400 | 0b1_0101 | (0b1001 << 20));
+    }
+
+    #[test]
+    fn hits_are_not_reported_to_guest() {
+        let state = state_with(2, 0x1000, BreakpointKind::Execute);
+        let (hits, causes) = state.split(DR6_INIT | DR6_BS | 0b0101);
+        assert!(hits[2].is_some());
+        assert!(hits.iter().filter(|hit| hit.is_some()).count() == 1);
+        assert_eq!(causes, DR6_BS | 0b0001);
+    }
+}
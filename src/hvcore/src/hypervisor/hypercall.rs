@@ -0,0 +1,225 @@
+//! This module defines the hypercall ABI, that is, the interface the guest uses
+//! to talk to the hypervisor with the `VMCALL` (Intel) or `VMMCALL` (AMD)
+//! instruction.
+//!
+//! On hypercall, the guest sets the following registers:
+//! - RAX: [`HYPERCALL_MAGIC`]
+//! - RCX: the hypercall number, one of [`Hypercall`]
+//! - RDX, R8 and R9: hypercall specific parameters
+//!
+//! On return, RAX contains a [`HypercallStatus`] and RDX contains a hypercall
+//! specific output value. Other registers are preserved. Hypercalls are only
+//! accepted from CPL 0. Output buffers are written with the permissions of the
+//! caller, so they must be writable by it; for example, user pages cannot be
+//! written under SMAP unless RFLAGS.AC is set.
+//!
+//! The numbers and semantics of existing hypercalls never change. A new
+//! hypercall may be added with a new number, incrementing the minor version
+//! of [`HYPERCALL_ABI_VERSION`].
+
+use core::arch::asm;
+
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive as _;
+
+/// The value expected in RAX on hypercall. Hypercalls with any other value are
+/// not ours and cause #UD, as `VMCALL` and `VMMCALL` do without a hypervisor,
+/// unless forwarded to the hypervisor we are nested under.
+pub const HYPERCALL_MAGIC: u64 = u64::from_le_bytes(*b"Barevisr");
+
+/// The version of the hypercall ABI, with the major version in bits 31:16 and
+/// the minor version in bits 15:0.
+pub const HYPERCALL_ABI_VERSION: u64 = (1 << 16) | 13;
+
+/// The value returned in RDX for [`Hypercall::Ping`].
+pub const HYPERCALL_PONG: u64 = u64::from_le_bytes(*b"Pong!   ");
+
+/// The hypercall numbers passed in RCX.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum Hypercall {
+    /// Returns [`HYPERCALL_PONG`] in RDX.
+    Ping = 0,
+
+    /// Returns [`HYPERCALL_ABI_VERSION`] in RDX.
+    GetVersion = 1,
+
+    /// Returns the total number of VM-exits handled across all processors in
+    /// RDX.
+    ReadStats = 2,
+
+    /// Installs a hook as `ept_hook::HookManager::install` does. Returns `Busy`
+    /// if the hook manager is in use.
+    /// - RDX: the guest virtual address to hook under the current CR3
+    /// - R8: the guest virtual address of the bytes to patch with
+    /// - R9: the number of the bytes to patch with, up to the page size
+    InstallHook = 3,
+
+    /// Uninstalls hooks as `ept_hook::HookManager::uninstall` does. Returns
+    /// `Busy` if the hook manager is in use.
+    /// - RDX: the guest virtual address in the page to unhook under the
+    ///   current CR3
+    UninstallHook = 4,
+
+    /// Devirtualizes the current processor. The caller resumes execution
+    /// without the hypervisor after the hypercall returns successfully.
+    /// Returns `Busy` while the guest is in VMX operation with nested
+    /// virtualization, as it would lose its own guests.
+    Devirtualize = 5,
+
+    /// Takes a snapshot with `snapshot::take_snapshot`, and returns 0 in RDX.
+    /// Restoring the snapshot resumes the caller from this hypercall with 1 in
+    /// RDX.
+    /// - RDX: the guest physical address of the range to snapshot
+    /// - R8: the size of the range in bytes
+    TakeSnapshot = 6,
+
+    /// Restores the snapshot with `snapshot::restore_snapshot`. Does not return
+    /// to the caller when successful.
+    RestoreSnapshot = 7,
+
+    /// Discards the snapshot with `snapshot::discard_snapshot`.
+    DiscardSnapshot = 8,
+
+    /// Enables virtualization exceptions on the current processor. See
+    /// `virtualization_exception`.
+    /// - RDX: the physical address of the `VeInformation` page, or 0 to
+    ///   disable them
+    EnableVirtualizationExceptions = 9,
+
+    /// Moves the unread logs of all processors into the buffer, and returns
+    /// the number of the moved bytes in RDX. Logs are UTF-8 text lines. The
+    /// oldest logs of a processor are lost if they are not read before its
+    /// buffer is full.
+    /// - RDX: the guest virtual address of the buffer under the current CR3
+    /// - R8: the size of the buffer in bytes
+    ReadLog = 10,
+
+    /// Changes the maximum level of logs to write, and returns the previous
+    /// level in RDX. Levels are numbered as `log::LevelFilter`.
+    /// - RDX: the new level from 0 (`Off`) to 5 (`Trace`)
+    SetLogLevel = 11,
+
+    /// Writes the statistics of VM-exits as an array of
+    /// `exit_stats::ExitStatsEntry` indexed by `exit_stats::ExitKind` into the
+    /// buffer, and returns the number of the written entries in RDX.
+    /// - RDX: the guest virtual address of the buffer under the current CR3
+    /// - R8: the size of the buffer in bytes
+    /// - R9: the processor ID, or `u64::MAX` for the sum across all processors
+    ReadExitStats = 12,
+
+    /// Writes the usage of the heap as `allocator::AllocatorStats` into the
+    /// buffer, and returns the number of the written bytes in RDX. The buffer
+    /// smaller than the structure receives its head only.
+    /// - RDX: the guest virtual address of the buffer under the current CR3
+    /// - R8: the size of the buffer in bytes
+    ReadAllocatorStats = 13,
+
+    /// Moves the unread attempts to tamper with the system call entry as an
+    /// array of `syscall_protection::TamperEvent` into the buffer, from the
+    /// oldest, and returns the number of the moved events in RDX. Returns
+    /// `NotSupported` unless `SharedHostData::syscall_protection` is enabled.
+    /// - RDX: the guest virtual address of the buffer under the current CR3
+    /// - R8: the size of the buffer in bytes
+    ReadTamperEvents = 14,
+
+    /// Moves the unread attempts to write to the code monitored with
+    /// `integrity` as an array of `integrity::IntegrityEvent` into the buffer,
+    /// from the oldest, and returns the number of the moved events in RDX.
+    /// - RDX: the guest virtual address of the buffer under the current CR3
+    /// - R8: the size of the buffer in bytes
+    ReadIntegrityEvents = 15,
+
+    /// Registers the ring buffer of `event_channel` the hypervisor posts events
+    /// to, replacing the one registered, if any.
+    /// - RDX: the page-aligned guest physical address of the buffer, or 0 to
+    ///   unregister it
+    /// - R8: the size of the buffer in bytes, a multiple of the page size
+    /// - R9: the vector of the interrupt to inject on every post from 32 to
+    ///   255, or 0 not to inject
+    ///
+    /// The buffer must stay resident, be physically contiguous, and be RAM the
+    /// guest can read and write.
+    RegisterEventChannel = 16,
+
+    /// Returns the number of SMIs observed on the current processor since it
+    /// was virtualized in RDX. Returns `NotSupported` if SMIs are not counted
+    /// on the processor. See `smm`.
+    ReadSmiCount = 17,
+
+    /// Performs an operation of `self_test`, which runs the self-test from the
+    /// guest with `self_test::run`.
+    /// - RDX: the operation, one of `self_test::SelfTestOperation`
+    /// - R8: the operation specific parameter
+    SelfTest = 18,
+
+    /// Ends the end-to-end test run by writing RDX to the `isa-debug-exit`
+    /// device of QEMU at `SharedHostData::test_exit_port`, which makes QEMU
+    /// exit with `(RDX << 1) | 1` as the exit status. Does not return if the
+    /// device is present. Returns `NotSupported` if the port is not set or the
+    /// device is absent.
+    /// - RDX: the exit code, 0 if the tests passed
+    ExitTest = 19,
+}
+
+/// The status codes returned in RAX.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum HypercallStatus {
+    /// The hypercall completed successfully.
+    Success = 0,
+
+    /// The hypercall number is unknown.
+    InvalidHypercall = 1,
+
+    /// One or more parameters are invalid.
+    InvalidParameter = 2,
+
+    /// The hypercall is not supported in this environment.
+    NotSupported = 3,
+
+    /// The hypercall was issued from CPL other than 0.
+    AccessDenied = 4,
+
+    /// What the hypercall needs is in use by the guest. Retry the hypercall.
+    Busy = 5,
+}
+
+/// Issues `hypercall` with `rdx`, `r8` and `r9` as parameters, and returns the
+/// output value in RDX.
+///
+/// # Errors
+///
+/// Returns `Err` if the hypercall does not succeed.
+pub fn issue(hypercall: Hypercall, rdx: u64, r8: u64, r9: u64) -> Result<u64, HypercallStatus> {
+    let status: u64;
+    let output: u64;
+    let is_intel = x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+    if is_intel {
+        unsafe {
+            asm!(
+                "vmcall",
+                inout("rax") HYPERCALL_MAGIC => status,
+                in("rcx") hypercall as u64,
+                inout("rdx") rdx => output,
+                in("r8") r8,
+                in("r9") r9,
+            );
+        };
+    } else {
+        unsafe {
+            asm!(
+                "vmmcall",
+                inout("rax") HYPERCALL_MAGIC => status,
+                in("rcx") hypercall as u64,
+                inout("rdx") rdx => output,
+                in("r8") r8,
+                in("r9") r9,
+            );
+        };
+    }
+
+    match HypercallStatus::from_u64(status) {
+        Some(HypercallStatus::Success) => Ok(output),
+        Some(status) => Err(status),
+        None => Err(HypercallStatus::InvalidHypercall),
+    }
+}
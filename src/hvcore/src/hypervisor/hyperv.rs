@@ -0,0 +1,243 @@
+//! This module implements emulation of a minimal subset of the Microsoft
+//! hypervisor interface, the Hyper-V enlightenments. Enabled with
+//! `SharedHostData::hyperv_enlightenments`, the guest sees the "Hv#1" interface
+//! in CPUID and can use the partition reference counter and the reference TSC
+//! page. Windows then uses them as the clock source instead of the legacy
+//! timers, such as the ACPI PM timer and the HPET, and relaxes its watchdogs,
+//! which significantly reduces VM-exits on Windows guests.
+//!
+//! The synthetic MSRs emulated are:
+//! - HV_X64_MSR_GUEST_OS_ID and HV_X64_MSR_HYPERCALL. The hypercall page is
+//!   filled with code returning HV_STATUS_INVALID_HYPERCALL_CODE, as no
+//!   hypercall is reported as available.
+//! - HV_X64_MSR_VP_INDEX, which is the index of the processor.
+//! - HV_X64_MSR_TIME_REF_COUNT, computed from the host TSC.
+//! - HV_X64_MSR_REFERENCE_TSC, only if the TSC is invariant and the guest TSC
+//!   is neither scaled nor compensated with `SharedHostData::tsc`, as the page
+//!   is shared across processors and assumes the guest TSC runs in the host
+//!   frequency.
+//!
+//! The enlightenments require the TSC frequency enumerated with CPUID, and are
+//! disabled with a warning otherwise.
+// See: Hypervisor Top Level Functional Specification, 2 Feature and Interface
+//      Discovery, 3 Hypercall Interface, and 12 Partition Reference Counter
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bit_field::BitField;
+use x86::{bits64::paging::BASE_PAGE_SIZE, cpuid::CpuIdResult};
+
+use crate::hypervisor::{
+    cpuid_policy::CpuidRegister,
+    guest_memory::{is_writable_ram, UserAccessGuard},
+    host::Vcpu,
+    host_window, tsc,
+    x86_instructions::rdtsc,
+    SharedHostData, HV_CPUID_INTERFACE, HV_CPUID_VENDOR_AND_MAX_FUNCTIONS,
+};
+
+const HV_CPUID_VERSION: u32 = 0x4000_0002;
+const HV_CPUID_FEATURES: u32 = 0x4000_0003;
+const HV_CPUID_ENLIGHTENMENT_INFO: u32 = 0x4000_0004;
+const HV_CPUID_IMPLEMENTATION_LIMITS: u32 = 0x4000_0005;
+
+const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+const HV_X64_MSR_HYPERCALL: u32 = 0x4000_0001;
+const HV_X64_MSR_VP_INDEX: u32 = 0x4000_0002;
+const HV_X64_MSR_TIME_REF_COUNT: u32 = 0x4000_0020;
+const HV_X64_MSR_REFERENCE_TSC: u32 = 0x4000_0021;
+
+/// The number of the partition reference counter ticks per second (a tick is
+/// 100ns).
+const REFERENCE_COUNTER_FREQUENCY: u64 = 10_000_000;
+
+/// Modifies `shared_host.cpuid_policy` to report the enlightenments, and
+/// installs the handlers of the synthetic MSRs into `shared_host.msr_intercepts`.
+/// Called from the guest before any processor is virtualized.
+pub(crate) fn install(shared_host: &mut SharedHostData) {
+    // See: 2.4.4 Hypervisor Feature Identification
+    const ACCESS_PARTITION_REFERENCE_COUNTER: u32 = 1 << 1;
+    const ACCESS_HYPERCALL_MSRS: u32 = 1 << 5;
+    const ACCESS_VP_INDEX: u32 = 1 << 6;
+    const ACCESS_PARTITION_REFERENCE_TSC: u32 = 1 << 9;
+    // See: 2.4.5 Implementation Recommendations
+    const USE_RELAXED_TIMING: u32 = 1 << 5;
+    const NEVER_NOTIFY_LONG_SPIN_WAIT: u32 = 0xffff_ffff;
+
+    let Some(frequency) = tsc::frequency() else {
+        log::warn!("Hyper-V enlightenments disabled: the TSC frequency is unknown");
+        return;
+    };
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+
+    let mut privileges =
+        ACCESS_PARTITION_REFERENCE_COUNTER | ACCESS_HYPERCALL_MSRS | ACCESS_VP_INDEX;
+    let mut intercepts = core::mem::take(&mut shared_host.msr_intercepts)
+        .on_read(HV_X64_MSR_GUEST_OS_ID, |_, _| {
+            Some(GUEST_OS_ID.load(Ordering::Relaxed))
+        })
+        .on_write(HV_X64_MSR_GUEST_OS_ID, |_, _, value| {
+            GUEST_OS_ID.store(value, Ordering::Relaxed);
+            None
+        })
+        .on_read(HV_X64_MSR_HYPERCALL, |_, _| {
+            Some(HYPERCALL.load(Ordering::Relaxed))
+        })
+        .on_write(HV_X64_MSR_HYPERCALL, |vcpu, _, value| {
+            write_hypercall_msr(vcpu, value);
+            None
+        })
+        .on_read(HV_X64_MSR_VP_INDEX, |vcpu, _| Some(vcpu.id() as u64))
+        .on_write(HV_X64_MSR_VP_INDEX, |_, _, _| None)
+        .on_read(HV_X64_MSR_TIME_REF_COUNT, |_, _| {
+            Some(reference_time(rdtsc(), FREQUENCY.load(Ordering::Relaxed)))
+        })
+        .on_write(HV_X64_MSR_TIME_REF_COUNT, |_, _, _| None);
+    if tsc::is_constant_across_processors(&shared_host.tsc) {
+        privileges |= ACCESS_PARTITION_REFERENCE_TSC;
+        intercepts = intercepts
+            .on_read(HV_X64_MSR_REFERENCE_TSC, |_, _| {
+                Some(REFERENCE_TSC.load(Ordering::Relaxed))
+            })
+            .on_write(HV_X64_MSR_REFERENCE_TSC, |vcpu, _, value| {
+                write_reference_tsc_msr(vcpu, value);
+                None
+            });
+    }
+    shared_host.msr_intercepts = intercepts;
+
+    // See: 2.4 Hypervisor CPUID Leaves
+    let result = |eax, ebx, ecx, edx| CpuIdResult { eax, ebx, ecx, edx };
+    // The hypervisor leaves are read only with "Bit 31: Hypervisor present".
+    shared_host.cpuid_policy = core::mem::take(&mut shared_host.cpuid_policy)
+        .set_bits(1, None, CpuidRegister::Ecx, 1 << 31)
+        .set_register(
+            HV_CPUID_VENDOR_AND_MAX_FUNCTIONS,
+            None,
+            CpuidRegister::Eax,
+            HV_CPUID_IMPLEMENTATION_LIMITS,
+        )
+        .set_register(
+            HV_CPUID_INTERFACE,
+            None,
+            CpuidRegister::Eax,
+            u32::from_le_bytes(*b"Hv#1"),
+        )
+        // Version 10.0, build 14393, the version Windows Server 2016 reports.
+        .set(HV_CPUID_VERSION, None, result(14393, 0x000a_0000, 0, 0))
+        .set(HV_CPUID_FEATURES, None, result(privileges, 0, 0, 0))
+        .set(
+            HV_CPUID_ENLIGHTENMENT_INFO,
+            None,
+            result(USE_RELAXED_TIMING, NEVER_NOTIFY_LONG_SPIN_WAIT, 0, 0),
+        )
+        // The maximum numbers of processors are not reported.
+        .set(HV_CPUID_IMPLEMENTATION_LIMITS, None, result(0, 0, 0, 0));
+}
+
+/// Handles a write to HV_X64_MSR_HYPERCALL: fills the hypercall page when it is
+/// enabled.
+// See: 3.13 Establishing the Hypercall Interface
+fn write_hypercall_msr(vcpu: &mut dyn Vcpu, value: u64) {
+    const ENABLE: usize = 0;
+    const LOCKED: usize = 1;
+
+    // mov eax, 2 (HV_STATUS_INVALID_HYPERCALL_CODE); xor edx, edx; ret
+    const STUB: [u8; 8] = [0xb8, 0x02, 0x00, 0x00, 0x00, 0x31, 0xd2, 0xc3];
+
+    let current = HYPERCALL.load(Ordering::Relaxed);
+    if current.get_bit(LOCKED) {
+        return;
+    }
+    // The hypercall page cannot be enabled until the guest OS ID is set.
+    let value = if GUEST_OS_ID.load(Ordering::Relaxed) == 0 {
+        value & !(1 << ENABLE)
+    } else {
+        value
+    };
+    if value.get_bit(ENABLE) && is_writable_ram(vcpu, page_address(value)) {
+        let page = host_window::map(vcpu.id(), page_address(value));
+        let _guard = UserAccessGuard::new();
+        // Safety: the window maps the guest page, which the guest gave for
+        // the hypercall page.
+        unsafe {
+            page.write_bytes(0, BASE_PAGE_SIZE);
+            page.copy_from_nonoverlapping(STUB.as_ptr(), STUB.len());
+        };
+    }
+    HYPERCALL.store(value, Ordering::Relaxed);
+}
+
+/// Handles a write to HV_X64_MSR_REFERENCE_TSC: fills the reference TSC page
+/// when it is enabled.
+// See: 12.7 Partition Reference Time Enlightenment
+fn write_reference_tsc_msr(vcpu: &mut dyn Vcpu, value: u64) {
+    const ENABLE: usize = 0;
+
+    if value.get_bit(ENABLE) && is_writable_ram(vcpu, page_address(value)) {
+        let page = host_window::map(vcpu.id(), page_address(value)).cast::<ReferenceTscPage>();
+        let contents = ReferenceTscPage {
+            sequence: 1,
+            reserved: 0,
+            scale: reference_tsc_scale(FREQUENCY.load(Ordering::Relaxed)),
+            offset: 0,
+        };
+        let _guard = UserAccessGuard::new();
+        // Safety: the window maps the guest page, which the guest gave for
+        // the reference TSC page.
+        unsafe { page.write_volatile(contents) };
+    }
+    REFERENCE_TSC.store(value, Ordering::Relaxed);
+}
+
+/// The head of the reference TSC page. The guest computes the reference time
+/// as `((TSC * scale) >> 64) + offset` while `sequence` is unchanged.
+#[repr(C)]
+struct ReferenceTscPage {
+    sequence: u32,
+    reserved: u32,
+    scale: u64,
+    offset: i64,
+}
+
+/// Returns the guest physical address of the page an MSR value points to.
+fn page_address(value: u64) -> u64 {
+    value & !(BASE_PAGE_SIZE as u64 - 1)
+}
+
+/// Returns the partition reference time for `tsc` of the `frequency` in Hz.
+fn reference_time(tsc: u64, frequency: u64) -> u64 {
+    (u128::from(tsc) * u128::from(REFERENCE_COUNTER_FREQUENCY) / u128::from(frequency)) as u64
+}
+
+/// Returns the scale of the reference TSC page for the TSC `frequency` in Hz.
+fn reference_tsc_scale(frequency: u64) -> u64 {
+    ((u128::from(REFERENCE_COUNTER_FREQUENCY) << 64) / u128::from(frequency)) as u64
+}
+
+/// The values of the partition-wide synthetic MSRs.
+static GUEST_OS_ID: AtomicU64 = AtomicU64::new(0);
+static HYPERCALL: AtomicU64 = AtomicU64::new(0);
+static REFERENCE_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// The TSC frequency in Hz.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reference_time_is_in_100ns() {
+        const FREQUENCY: u64 = 3_000_000_000;
+        assert_eq!(reference_time(FREQUENCY, FREQUENCY), 10_000_000);
+        assert_eq!(reference_time(300, FREQUENCY), 1);
+
+        // The reference TSC page yields the same time.
+        let tsc = 123_456_789_012_345;
+        let scale = reference_tsc_scale(FREQUENCY);
+        let from_page = ((u128::from(tsc) * u128::from(scale)) >> 64) as u64;
+        assert!(reference_time(tsc, FREQUENCY) - from_page <= 1);
+        assert_eq!(reference_tsc_scale(20_000_000), 1 << 63);
+    }
+}
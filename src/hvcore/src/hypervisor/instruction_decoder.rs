@@ -0,0 +1,847 @@
+//! This module implements a minimal decoder of x86 instructions, for VM-exit
+//! handlers that need the length of the guest instruction or the memory
+//! operand of it, which the processor does not report for every VM-exit.
+//!
+//! The decoder determines the length and the operands of instructions in the
+//! one-byte, two-byte and three-byte opcode maps, including VEX and EVEX
+//! encoded ones, in 16-bit, 32-bit and 64-bit code. It does not validate them:
+//! an invalid instruction is decoded as if it were valid where the length can
+//! be determined. Only `MOV` and `MOVZX` between a general purpose register or
+//! an immediate and memory can be emulated, which is the common form of access
+//! to memory-mapped registers.
+//!
+//! ```ignore
+//! // Complete the access to a protected page on behalf of the guest.
+//! protect_gpa_range(pa, len, Permissions::READ_EXECUTE, |vcpu, info| {
+//!     log::info!("Write to {:#x?}", info.gpa);
+//!     ViolationAction::Emulate
+//! })?;
+//! ```
+
+use bit_field::BitField;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use crate::hypervisor::{
+    guest_memory::{self, TranslationError},
+    host::{SegmentRegister, Vcpu},
+    registers::Registers,
+};
+
+/// The maximum length of an instruction in bytes.
+/// See: 2.3.11 AVX Instruction Length
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// The default operand and address size of code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodeSize {
+    Bits16,
+    Bits32,
+    Bits64,
+}
+
+impl CodeSize {
+    /// Returns the size of the code the guest currently executes.
+    pub fn of(vcpu: &dyn Vcpu) -> Self {
+        const EFER_LMA: u64 = 1 << 10;
+
+        // See: 3.4.5 Segment Descriptors
+        let attributes = vcpu.segment(SegmentRegister::Cs).attributes;
+        if vcpu.efer() & EFER_LMA != 0 && attributes.get_bit(9) {
+            Self::Bits64
+        } else if attributes.get_bit(10) {
+            Self::Bits32
+        } else {
+            Self::Bits16
+        }
+    }
+
+    /// Returns the default address size in bytes.
+    pub(crate) fn address_size(self) -> u8 {
+        match self {
+            Self::Bits16 => 2,
+            Self::Bits32 => 4,
+            Self::Bits64 => 8,
+        }
+    }
+
+    /// Returns the default operand size in bytes, which is 4 for 64-bit code.
+    fn operand_size(self) -> u8 {
+        match self {
+            Self::Bits16 => 2,
+            Self::Bits32 | Self::Bits64 => 4,
+        }
+    }
+}
+
+/// The opcode maps.
+/// See: Table A-2. One-byte Opcode Map
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpcodeMap {
+    /// The one-byte opcodes.
+    Primary,
+    /// The two-byte opcodes, `0F xx`.
+    Secondary,
+    /// The three-byte opcodes, `0F 38 xx`.
+    Map0F38,
+    /// The three-byte opcodes, `0F 3A xx`.
+    Map0F3A,
+}
+
+/// The errors decoding and emulation may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstructionError {
+    #[error("the instruction cannot be fetched: {0}")]
+    Fetch(TranslationError),
+
+    #[error("the memory operand cannot be accessed: {0}")]
+    Access(TranslationError),
+
+    #[error("the instruction is longer than 15 bytes")]
+    TooLong,
+
+    #[error("the bytes end in the middle of the instruction")]
+    Truncated,
+
+    #[error("the instruction is not supported")]
+    Unsupported,
+}
+
+/// A decoded instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    /// The length in bytes.
+    pub len: usize,
+    /// The code size the instruction was decoded for.
+    pub code_size: CodeSize,
+    /// The opcode map the opcode is in.
+    pub map: OpcodeMap,
+    /// The last byte of the opcode.
+    pub opcode: u8,
+    /// Whether the instruction is VEX or EVEX encoded.
+    pub vex: bool,
+    /// The ModR/M byte, if any.
+    pub modrm: Option<u8>,
+    /// The SIB byte, if any.
+    pub sib: Option<u8>,
+    /// The sign-extended displacement, or zero. For EVEX encoded instructions,
+    /// an 8-bit displacement is not scaled.
+    pub displacement: i64,
+    /// The immediate, or zero. Immediates of the size of the operand are
+    /// sign-extended, as are 8-bit immediates, except for those of `ENTER`,
+    /// the far pointers and the offsets of `MOV` (A0-A3).
+    pub immediate: u64,
+    /// The operand size in bytes, for instructions that have one.
+    pub operand_size: u8,
+    /// The address size in bytes.
+    pub address_size: u8,
+    /// The REX prefix, or the equivalent bits of VEX and EVEX, or zero.
+    pub rex: u8,
+    /// The segment override prefix, if any.
+    pub segment: Option<SegmentRegister>,
+    /// Whether the LOCK prefix is present.
+    pub lock: bool,
+    /// Whether the REP or REPE prefix is present.
+    pub rep: bool,
+    /// Whether the REPNE prefix is present.
+    pub repne: bool,
+}
+
+/// A memory access of an instruction that can be emulated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryAccess {
+    /// The instruction reads `size` bytes. Complete it with
+    /// [`Instruction::complete_read`].
+    Read { size: u8 },
+
+    /// The instruction writes the low `size` bytes of `value`.
+    Write { size: u8, value: u64 },
+}
+
+impl Instruction {
+    /// Returns the `reg` field of the ModR/M byte extended by REX.R.
+    pub fn reg(&self) -> Option<u8> {
+        self.modrm
+            .map(|modrm| (modrm >> 3) & 0b111 | (self.rex.get_bit(2) as u8) << 3)
+    }
+
+    /// Returns the register the `r/m` field of the ModR/M byte extended by
+    /// REX.B designates, or `None` if the operand is in memory.
+    pub fn rm_register(&self) -> Option<u8> {
+        let modrm = self.modrm?;
+        (modrm >> 6 == 0b11).then(|| modrm & 0b111 | (self.rex.get_bit(0) as u8) << 3)
+    }
+
+    /// Returns the guest linear address of the memory operand, or `None` if
+    /// the instruction does not have one in the ModR/M byte.
+    pub fn memory_address(&self, vcpu: &mut dyn Vcpu) -> Option<u64> {
+        let modrm = self.modrm?;
+        let (mode, rm) = (modrm >> 6, modrm & 0b111);
+        if mode == 0b11 {
+            return None;
+        }
+
+        let regs = *vcpu.regs();
+        let (offset, default_segment) = if self.address_size == 2 {
+            // See: Table 2-1. 16-Bit Addressing Forms with the ModR/M Byte
+            let (bx, bp, si, di) = (regs.rbx, regs.rbp, regs.rsi, regs.rdi);
+            let (base, segment) = match rm {
+                0b000 => (bx.wrapping_add(si), SegmentRegister::Ds),
+                0b001 => (bx.wrapping_add(di), SegmentRegister::Ds),
+                0b010 => (bp.wrapping_add(si), SegmentRegister::Ss),
+                0b011 => (bp.wrapping_add(di), SegmentRegister::Ss),
+                0b100 => (si, SegmentRegister::Ds),
+                0b101 => (di, SegmentRegister::Ds),
+                0b110 if mode == 0b00 => (0, SegmentRegister::Ds),
+                0b110 => (bp, SegmentRegister::Ss),
+                _ => (bx, SegmentRegister::Ds),
+            };
+            (base.wrapping_add(self.displacement as u64), segment)
+        } else {
+            // See: Table 2-2. 32-Bit Addressing Forms with the ModR/M Byte
+            let mut segment = SegmentRegister::Ds;
+            let mut offset = self.displacement as u64;
+            if let Some(sib) = self.sib {
+                // See: Table 2-3. 32-Bit Addressing Forms with the SIB Byte
+                let base = sib & 0b111 | (self.rex.get_bit(0) as u8) << 3;
+                let index = (sib >> 3) & 0b111 | (self.rex.get_bit(1) as u8) << 3;
+                if !(sib & 0b111 == 0b101 && mode == 0b00) {
+                    offset = offset.wrapping_add(regs.gpr(base));
+                    if base & 0b111 == 0b100 || base & 0b111 == 0b101 {
+                        segment = SegmentRegister::Ss;
+                    }
+                }
+                if index != 0b100 {
+                    offset = offset.wrapping_add(regs.gpr(index) << (sib >> 6));
+                }
+            } else if rm == 0b101 && mode == 0b00 {
+                // RIP-relative in 64-bit mode, and disp32 only otherwise.
+                // See: 2.2.1.6 RIP-Relative Addressing
+                if self.code_size == CodeSize::Bits64 {
+                    offset = offset.wrapping_add(regs.rip.wrapping_add(self.len as u64));
+                }
+            } else {
+                let base = rm | (self.rex.get_bit(0) as u8) << 3;
+                offset = offset.wrapping_add(regs.gpr(base));
+                if rm == 0b101 {
+                    segment = SegmentRegister::Ss;
+                }
+            }
+            (offset, segment)
+        };
+
+        let offset = offset & mask(self.address_size);
+        let segment = self.segment.unwrap_or(default_segment);
+        let base = match (self.code_size, segment) {
+            (CodeSize::Bits64, SegmentRegister::Fs | SegmentRegister::Gs)
+            | (CodeSize::Bits16 | CodeSize::Bits32, _) => vcpu.segment(segment).base,
+            (CodeSize::Bits64, _) => 0,
+        };
+        Some(base.wrapping_add(offset))
+    }
+
+    /// Returns the memory access of the instruction if it can be emulated,
+    /// that is, if it is one of the following with a memory operand:
+    /// `MOV r/m, r` (88, 89), `MOV r, r/m` (8A, 8B), `MOV r/m, imm` (C6 /0,
+    /// C7 /0) and `MOVZX r, r/m` (0F B6, 0F B7).
+    pub fn access(&self, regs: &Registers) -> Option<MemoryAccess> {
+        if self.vex || self.lock || self.rm_register().is_some() {
+            return None;
+        }
+        let reg = self.reg()?;
+        let size = self.operand_size;
+        match (self.map, self.opcode) {
+            (OpcodeMap::Primary, 0x88) => Some(MemoryAccess::Write {
+                size: 1,
+                value: self.read_register(regs, reg, 1),
+            }),
+            (OpcodeMap::Primary, 0x89) => Some(MemoryAccess::Write {
+                size,
+                value: self.read_register(regs, reg, size),
+            }),
+            (OpcodeMap::Primary, 0x8a) | (OpcodeMap::Secondary, 0xb6) => {
+                Some(MemoryAccess::Read { size: 1 })
+            }
+            (OpcodeMap::Primary, 0x8b) => Some(MemoryAccess::Read { size }),
+            (OpcodeMap::Secondary, 0xb7) => Some(MemoryAccess::Read { size: 2 }),
+            (OpcodeMap::Primary, 0xc6) if reg & 0b111 == 0 => Some(MemoryAccess::Write {
+                size: 1,
+                value: self.immediate,
+            }),
+            (OpcodeMap::Primary, 0xc7) if reg & 0b111 == 0 => Some(MemoryAccess::Write {
+                size,
+                value: self.immediate,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Completes the [`MemoryAccess::Read`] of the instruction with `value`
+    /// read from memory, updating the destination register in `regs`.
+    pub fn complete_read(&self, regs: &mut Registers, value: u64) {
+        let Some(reg) = self.reg() else {
+            return;
+        };
+        match (self.map, self.opcode) {
+            (OpcodeMap::Primary, 0x8a) => self.write_register(regs, reg, 1, value),
+            (OpcodeMap::Primary, _) => {
+                self.write_register(regs, reg, self.operand_size, value);
+            }
+            (_, 0xb6) => self.write_register(regs, reg, self.operand_size, value & 0xff),
+            (_, _) => self.write_register(regs, reg, self.operand_size, value & 0xffff),
+        }
+    }
+
+    /// Returns the low `size` bytes of the register `reg`. Without REX, 8-bit
+    /// registers 4 to 7 are AH, CH, DH and BH.
+    fn read_register(&self, regs: &Registers, reg: u8, size: u8) -> u64 {
+        if size == 1 && self.rex == 0 && (4..8).contains(&reg) {
+            (regs.gpr(reg - 4) >> 8) & 0xff
+        } else {
+            regs.gpr(reg) & mask(size)
+        }
+    }
+
+    /// Writes the low `size` bytes of `value` to the register `reg`. 32-bit
+    /// writes clear the upper 32 bits, while 8-bit and 16-bit writes keep the
+    /// other bits.
+    /// See: 3.4.1.1 General-Purpose Registers in 64-Bit Mode
+    fn write_register(&self, regs: &mut Registers, reg: u8, size: u8, value: u64) {
+        let value = value & mask(size);
+        if size == 1 && self.rex == 0 && (4..8).contains(&reg) {
+            let current = regs.gpr(reg - 4);
+            regs.set_gpr(reg - 4, (current & !0xff00) | (value << 8));
+        } else if size >= 4 {
+            regs.set_gpr(reg, value);
+        } else {
+            let current = regs.gpr(reg);
+            regs.set_gpr(reg, (current & !mask(size)) | value);
+        }
+    }
+}
+
+/// Decodes the instruction at the start of `bytes` for `code_size`.
+///
+/// # Errors
+///
+/// Returns `Err` if `bytes` is shorter than the instruction, the instruction
+/// exceeds 15 bytes, or the length of it cannot be determined.
+pub fn decode(bytes: &[u8], code_size: CodeSize) -> Result<Instruction, InstructionError> {
+    Decoder {
+        bytes,
+        position: 0,
+        code_size,
+    }
+    .decode()
+}
+
+/// Fetches and decodes the instruction at the guest RIP.
+///
+/// # Errors
+///
+/// Returns `Err` if the instruction cannot be fetched or decoded.
+pub fn fetch(vcpu: &mut dyn Vcpu) -> Result<Instruction, InstructionError> {
+    let code_size = CodeSize::of(vcpu);
+    let rip = vcpu.regs().rip;
+    let gva = if code_size == CodeSize::Bits64 {
+        rip
+    } else {
+        vcpu.segment(SegmentRegister::Cs).base.wrapping_add(rip)
+    };
+
+    // The instruction may not extend to the next page, which may not be mapped.
+    let mut bytes = [0u8; MAX_INSTRUCTION_LEN];
+    let in_page = (BASE_PAGE_SIZE - (gva as usize % BASE_PAGE_SIZE)).min(bytes.len());
+    guest_memory::read_guest(vcpu, gva, &mut bytes[..in_page]).map_err(InstructionError::Fetch)?;
+    let mut len = in_page;
+    if in_page < bytes.len()
+        && guest_memory::read_guest(vcpu, gva + in_page as u64, &mut bytes[in_page..]).is_ok()
+    {
+        len = bytes.len();
+    }
+    decode(&bytes[..len], code_size)
+}
+
+/// Advances the guest RIP past the instruction at it without executing it.
+///
+/// # Errors
+///
+/// Returns `Err` if the instruction cannot be fetched or decoded. RIP is not
+/// changed in that case.
+pub fn skip(vcpu: &mut dyn Vcpu) -> Result<(), InstructionError> {
+    let instruction = fetch(vcpu)?;
+    advance(vcpu, &instruction);
+    Ok(())
+}
+
+/// Emulates the instruction at the guest RIP and advances RIP past it. The
+/// memory operand is accessed through the guest paging structures, regardless
+/// of the permissions in them or in the EPT (Intel) or NPT (AMD).
+///
+/// # Errors
+///
+/// Returns `Err` if the instruction cannot be fetched or decoded, is not
+/// supported by [`Instruction::access`], or the memory operand cannot be
+/// accessed. RIP is not changed in that case, and the instruction is not
+/// executed.
+pub fn emulate(vcpu: &mut dyn Vcpu) -> Result<(), InstructionError> {
+    let instruction = fetch(vcpu)?;
+    let access = instruction
+        .access(vcpu.regs())
+        .ok_or(InstructionError::Unsupported)?;
+    let gva = instruction
+        .memory_address(vcpu)
+        .ok_or(InstructionError::Unsupported)?;
+    match access {
+        MemoryAccess::Read { size } => {
+            let mut value = [0u8; 8];
+            guest_memory::read_guest(vcpu, gva, &mut value[..usize::from(size)])
+                .map_err(InstructionError::Access)?;
+            instruction.complete_read(vcpu.regs(), u64::from_le_bytes(value));
+        }
+        MemoryAccess::Write { size, value } => {
+            guest_memory::write_guest(vcpu, gva, &value.to_le_bytes()[..usize::from(size)])
+                .map_err(InstructionError::Access)?;
+        }
+    }
+    advance(vcpu, &instruction);
+    Ok(())
+}
+
+/// Advances the guest RIP by the length of `instruction`, wrapping around
+/// within the code size.
+pub(crate) fn advance(vcpu: &mut dyn Vcpu, instruction: &Instruction) {
+    let regs = vcpu.regs();
+    let next_rip = regs.rip.wrapping_add(instruction.len as u64);
+    regs.rip = match instruction.code_size {
+        CodeSize::Bits64 => next_rip,
+        _ => next_rip & mask(instruction.code_size.operand_size()),
+    };
+}
+
+/// Returns the mask of the low `size` bytes.
+fn mask(size: u8) -> u64 {
+    if size >= 8 {
+        u64::MAX
+    } else {
+        (1 << (u32::from(size) * 8)) - 1
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    code_size: CodeSize,
+}
+
+impl Decoder<'_> {
+    fn next(&mut self) -> Result<u8, InstructionError> {
+        if self.position == MAX_INSTRUCTION_LEN {
+            return Err(InstructionError::TooLong);
+        }
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or(InstructionError::Truncated)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).copied()
+    }
+
+    /// Reads the little-endian value of `size` bytes, sign-extended.
+    fn signed(&mut self, size: u8) -> Result<u64, InstructionError> {
+        let unsigned = self.unsigned(size)?;
+        let shift = 64 - u32::from(size) * 8;
+        Ok((((unsigned << shift) as i64) >> shift) as u64)
+    }
+
+    /// Reads the little-endian value of `size` bytes, zero-extended.
+    fn unsigned(&mut self, size: u8) -> Result<u64, InstructionError> {
+        let mut value = 0;
+        for i in 0..size {
+            value |= u64::from(self.next()?) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    fn decode(mut self) -> Result<Instruction, InstructionError> {
+        let long_mode = self.code_size == CodeSize::Bits64;
+        let mut instruction = Instruction {
+            len: 0,
+            code_size: self.code_size,
+            map: OpcodeMap::Primary,
+            opcode: 0,
+            vex: false,
+            modrm: None,
+            sib: None,
+            displacement: 0,
+            immediate: 0,
+            operand_size: self.code_size.operand_size(),
+            address_size: self.code_size.address_size(),
+            rex: 0,
+            segment: None,
+            lock: false,
+            rep: false,
+            repne: false,
+        };
+
+        // Legacy prefixes, then REX, which is ignored unless right before the
+        // opcode.
+        // See: 2.1.1 Instruction Prefixes
+        let mut operand_override = false;
+        let mut address_override = false;
+        let mut opcode = loop {
+            let byte = self.next()?;
+            match byte {
+                0xf0 => instruction.lock = true,
+                0xf2 => (instruction.repne, instruction.rep) = (true, false),
+                0xf3 => (instruction.rep, instruction.repne) = (true, false),
+                0x26 => instruction.segment = Some(SegmentRegister::Es),
+                0x2e => instruction.segment = Some(SegmentRegister::Cs),
+                0x36 => instruction.segment = Some(SegmentRegister::Ss),
+                0x3e => instruction.segment = Some(SegmentRegister::Ds),
+                0x64 => instruction.segment = Some(SegmentRegister::Fs),
+                0x65 => instruction.segment = Some(SegmentRegister::Gs),
+                0x66 => operand_override = true,
+                0x67 => address_override = true,
+                0x40..=0x4f if long_mode => {
+                    instruction.rex = byte;
+                    continue;
+                }
+                _ => break byte,
+            }
+            instruction.rex = 0;
+        };
+
+        // Only FS and GS overrides are effective in 64-bit mode.
+        if long_mode
+            && !matches!(
+                instruction.segment,
+                None | Some(SegmentRegister::Fs | SegmentRegister::Gs)
+            )
+        {
+            instruction.segment = None;
+        }
+
+        if address_override {
+            instruction.address_size = match self.code_size {
+                CodeSize::Bits16 => 4,
+                CodeSize::Bits32 => 2,
+                CodeSize::Bits64 => 4,
+            };
+        }
+        if operand_override {
+            instruction.operand_size = if self.code_size == CodeSize::Bits16 {
+                4
+            } else {
+                2
+            };
+        }
+
+        // VEX and EVEX. Outside 64-bit mode, C4, C5 and 62 are LES, LDS and
+        // BOUND unless the next byte has the mod field of 11b.
+        // See: 2.3.5 The VEX Prefix
+        let is_vex = matches!(opcode, 0xc4 | 0xc5 | 0x62)
+            && (long_mode || self.peek().is_some_and(|byte| byte >> 6 == 0b11));
+        if is_vex {
+            if instruction.rex != 0 || operand_override || instruction.lock {
+                return Err(InstructionError::Unsupported);
+            }
+            instruction.vex = true;
+            let (map, wrxb) = match opcode {
+                0xc5 => {
+                    let byte1 = self.next()?;
+                    (1, (!byte1 >> 7) << 2)
+                }
+                0xc4 => {
+                    let byte1 = self.next()?;
+                    let byte2 = self.next()?;
+                    (byte1 & 0b1_1111, (!byte1 >> 5) & 0b111 | (byte2 >> 7) << 3)
+                }
+                _ => {
+                    // See: 2.7.1 Instruction Format and EVEX
+                    let byte1 = self.next()?;
+                    let byte2 = self.next()?;
+                    let _ = self.next()?;
+                    (byte1 & 0b111, (!byte1 >> 5) & 0b111 | (byte2 >> 7) << 3)
+                }
+            };
+            instruction.rex = 0x40 | wrxb;
+            instruction.map = match map {
+                1 => OpcodeMap::Secondary,
+                2 => OpcodeMap::Map0F38,
+                3 => OpcodeMap::Map0F3A,
+                _ => return Err(InstructionError::Unsupported),
+            };
+            instruction.opcode = self.next()?;
+            self.decode_modrm(&mut instruction)?;
+            if instruction.map == OpcodeMap::Map0F3A {
+                instruction.immediate = self.unsigned(1)?;
+            }
+            instruction.len = self.position;
+            return Ok(instruction);
+        }
+
+        if instruction.rex.get_bit(3) {
+            instruction.operand_size = 8;
+        }
+
+        if opcode == 0x0f {
+            opcode = self.next()?;
+            instruction.map = match opcode {
+                0x38 => OpcodeMap::Map0F38,
+                0x3a => OpcodeMap::Map0F3A,
+                _ => OpcodeMap::Secondary,
+            };
+            if instruction.map != OpcodeMap::Secondary {
+                opcode = self.next()?;
+            }
+        }
+        instruction.opcode = opcode;
+
+        // Near branches and stack operations default to 64-bit operands in
+        // 64-bit mode.
+        // See: Table 2-5. Instructions Not Requiring REX Prefix in 64-Bit Mode
+        let z = instruction.operand_size.min(4);
+        let immediate = match instruction.map {
+            OpcodeMap::Primary => {
+                if primary_has_modrm(opcode, long_mode) {
+                    self.decode_modrm(&mut instruction)?;
+                }
+                let reg = instruction.modrm.map_or(0, |modrm| (modrm >> 3) & 0b111);
+                match opcode {
+                    0x9a | 0xea if long_mode => return Err(InstructionError::Unsupported),
+                    0x9a | 0xea => {
+                        instruction.immediate = self.unsigned(z)?;
+                        let _ = self.unsigned(2)?;
+                        None
+                    }
+                    0xa0..=0xa3 => {
+                        instruction.immediate = self.unsigned(instruction.address_size)?;
+                        None
+                    }
+                    0xc8 => {
+                        instruction.immediate = self.unsigned(3)?;
+                        None
+                    }
+                    0xb8..=0xbf => Some(instruction.operand_size),
+                    0xc2 | 0xca => Some(2),
+                    0xf6 if reg < 2 => Some(1),
+                    0xf7 if reg < 2 => Some(z),
+                    0x05 | 0x0d | 0x15 | 0x1d | 0x25 | 0x2d | 0x35 | 0x3d | 0x68 | 0x69 | 0x81
+                    | 0xa9 | 0xc7 | 0xe8 | 0xe9 => Some(z),
+                    0x04
+                    | 0x0c
+                    | 0x14
+                    | 0x1c
+                    | 0x24
+                    | 0x2c
+                    | 0x34
+                    | 0x3c
+                    | 0x6a
+                    | 0x6b
+                    | 0x70..=0x80
+                    | 0x82
+                    | 0x83
+                    | 0xa8
+                    | 0xb0..=0xb7
+                    | 0xc0
+                    | 0xc1
+                    | 0xc6
+                    | 0xcd
+                    | 0xd4
+                    | 0xd5
+                    | 0xe0..=0xe7
+                    | 0xeb => Some(1),
+                    _ => None,
+                }
+            }
+            OpcodeMap::Secondary => {
+                if secondary_has_modrm(opcode) {
+                    self.decode_modrm(&mut instruction)?;
+                }
+                match opcode {
+                    0x80..=0x8f => Some(z),
+                    0x0f | 0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6 => Some(1),
+                    _ => None,
+                }
+            }
+            OpcodeMap::Map0F38 => {
+                self.decode_modrm(&mut instruction)?;
+                None
+            }
+            OpcodeMap::Map0F3A => {
+                self.decode_modrm(&mut instruction)?;
+                Some(1)
+            }
+        };
+        if let Some(size) = immediate {
+            instruction.immediate = self.signed(size)?;
+        }
+
+        instruction.len = self.position;
+        Ok(instruction)
+    }
+
+    /// Decodes the ModR/M byte and the SIB byte and displacement it implies.
+    /// See: 2.1.5 Addressing-Mode Encoding of ModR/M and SIB Bytes
+    fn decode_modrm(&mut self, instruction: &mut Instruction) -> Result<(), InstructionError> {
+        let modrm = self.next()?;
+        instruction.modrm = Some(modrm);
+        let (mode, rm) = (modrm >> 6, modrm & 0b111);
+        if mode == 0b11 {
+            return Ok(());
+        }
+
+        let displacement_size = if instruction.address_size == 2 {
+            match mode {
+                0b00 if rm == 0b110 => 2,
+                0b00 => 0,
+                0b01 => 1,
+                _ => 2,
+            }
+        } else {
+            let mut base = rm;
+            if rm == 0b100 {
+                let sib = self.next()?;
+                instruction.sib = Some(sib);
+                base = sib & 0b111;
+            }
+            match mode {
+                0b00 if base == 0b101 => 4,
+                0b00 => 0,
+                0b01 => 1,
+                _ => 4,
+            }
+        };
+        if displacement_size != 0 {
+            instruction.displacement = self.signed(displacement_size)? as i64;
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether the one-byte `opcode` has the ModR/M byte.
+/// See: Table A-2. One-byte Opcode Map
+fn primary_has_modrm(opcode: u8, long_mode: bool) -> bool {
+    match opcode {
+        0x00..=0x3f => opcode & 0b100 == 0,
+        0x62 => !long_mode,
+        0x63 | 0x69 | 0x6b | 0x80..=0x8f | 0xc0 | 0xc1 | 0xc4..=0xc7 | 0xd0..=0xd3 => true,
+        0xd8..=0xdf | 0xf6 | 0xf7 | 0xfe | 0xff => true,
+        _ => false,
+    }
+}
+
+/// Returns whether the two-byte `opcode` has the ModR/M byte.
+/// See: Table A-3. Two-byte Opcode Map
+fn secondary_has_modrm(opcode: u8) -> bool {
+    !matches!(
+        opcode,
+        0x05..=0x09 | 0x0b | 0x0e | 0x30..=0x37 | 0x77 | 0x80..=0x8f | 0xa0..=0xa2 | 0xa8..=0xaa
+            | 0xc8..=0xcf
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn len(bytes: &[u8], code_size: CodeSize) -> usize {
+        decode(bytes, code_size).unwrap().len
+    }
+
+    #[test]
+    fn decode_lengths() {
+        // mov dword ptr [rip+0x1000], 0x12345678
+        assert_eq!(
+            len(
+                &[0xc7, 0x05, 0x00, 0x10, 0x00, 0x00, 0x78, 0x56, 0x34, 0x12],
+                CodeSize::Bits64
+            ),
+            10
+        );
+        // mov rax, 0x1122334455667788
+        assert_eq!(
+            len(&[0x48, 0xb8, 1, 2, 3, 4, 5, 6, 7, 8], CodeSize::Bits64),
+            10
+        );
+        // mov ax, word ptr [bp+si+0x10], in 16-bit code
+        assert_eq!(len(&[0x8b, 0x42, 0x10], CodeSize::Bits16), 3);
+        // lock cmpxchg dword ptr [rsp+rax*4+0x10], ecx
+        assert_eq!(
+            len(&[0xf0, 0x0f, 0xb1, 0x4c, 0x84, 0x10], CodeSize::Bits64),
+            6
+        );
+        // vpxor ymm0, ymm1, ymmword ptr [rax]
+        assert_eq!(len(&[0xc5, 0xf5, 0xef, 0x00], CodeSize::Bits64), 4);
+        // vpalignr xmm0, xmm1, xmm2, 8
+        assert_eq!(
+            len(&[0xc4, 0xe3, 0x71, 0x0f, 0xc2, 0x08], CodeSize::Bits64),
+            6
+        );
+        // les eax, [eax] is not VEX in 32-bit code.
+        assert_eq!(len(&[0xc4, 0x00], CodeSize::Bits32), 2);
+        // vmcall
+        assert_eq!(len(&[0x0f, 0x01, 0xc1], CodeSize::Bits64), 3);
+        // mov ax, 0x1234 with the operand-size prefix
+        assert_eq!(len(&[0x66, 0xb8, 0x34, 0x12], CodeSize::Bits32), 4);
+
+        assert_eq!(
+            decode(&[0x8b], CodeSize::Bits64),
+            Err(InstructionError::Truncated)
+        );
+        assert_eq!(
+            decode(&[0x66; 16], CodeSize::Bits64),
+            Err(InstructionError::TooLong)
+        );
+    }
+
+    #[test]
+    fn access_uses_register_sizes() {
+        let mut regs = Registers {
+            rax: 0x1111_2222_3333_4455,
+            ..Default::default()
+        };
+
+        // mov byte ptr [rcx], ah
+        let instruction = decode(&[0x88, 0x21], CodeSize::Bits64).unwrap();
+        assert_eq!(
+            instruction.access(&regs),
+            Some(MemoryAccess::Write {
+                size: 1,
+                value: 0x44
+            })
+        );
+
+        // mov eax, dword ptr [rcx] clears the upper 32 bits.
+        let instruction = decode(&[0x8b, 0x01], CodeSize::Bits64).unwrap();
+        assert_eq!(
+            instruction.access(&regs),
+            Some(MemoryAccess::Read { size: 4 })
+        );
+        instruction.complete_read(&mut regs, 0xaaaa_bbbb);
+        assert_eq!(regs.rax, 0xaaaa_bbbb);
+
+        // movzx eax, byte ptr [rcx]
+        let instruction = decode(&[0x0f, 0xb6, 0x01], CodeSize::Bits64).unwrap();
+        instruction.complete_read(&mut regs, 0x1ff);
+        assert_eq!(regs.rax, 0xff);
+
+        // mov qword ptr [rcx], -1 sign-extends the immediate.
+        let instruction = decode(
+            &[0x48, 0xc7, 0x01, 0xff, 0xff, 0xff, 0xff],
+            CodeSize::Bits64,
+        )
+        .unwrap();
+        assert_eq!(
+            instruction.access(&regs),
+            Some(MemoryAccess::Write {
+                size: 8,
+                value: u64::MAX
+            })
+        );
+    }
+}
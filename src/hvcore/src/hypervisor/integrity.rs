@@ -0,0 +1,239 @@
+//! This module implements monitoring of the integrity of the guest kernel code
+//! on top of `memory_protection`. The pages of the code are write-protected
+//! through the EPT (Intel) or NPT (AMD), and each attempt to write to them is
+//! reported as an [`IntegrityEvent`], with the RIP of the writing instruction
+//! and the CR3 of the writing process. The guest reads the events with
+//! `Hypercall::ReadIntegrityEvents`, or receive them through `event_channel`.
+//!
+//! The ranges to monitor are either given with [`protect_range`], or
+//! discovered with [`protect_kernel_text`], which finds the code sections of
+//! the Windows kernel with `windows_kernel`. Depending on [`Response`], writes
+//! are completed after being reported, or discarded.
+//!
+//! ```ignore
+//! // From the guest, for example, the driver of the embedder.
+//! integrity::protect_range(text_start, text_len, Response::Block)?;
+//! ```
+
+use alloc::{collections::VecDeque, vec::Vec};
+use spin::Mutex;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use crate::hypervisor::{
+    event_channel::{self, EventKind},
+    guest_memory::{self, TranslationError},
+    host::{NestedPageFaultInfo, Vcpu},
+    memory_protection::{self, Permissions, ProtectionError, ViolationAction},
+    platform_ops,
+    windows_kernel::{KernelError, KernelImage},
+    x86_instructions::rdtsc,
+};
+
+/// What to do with an attempt to write to the monitored code after reporting
+/// it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Response {
+    /// Let the guest complete the write by single-stepping the writing
+    /// instruction with `ViolationAction::Allow`, which works for any
+    /// instruction, such as `REP MOVSB` of `memcpy` patching the code.
+    Report,
+
+    /// Discard the write and resume the guest after the instruction.
+    Block,
+}
+
+/// An attempt to write to the monitored code, as returned by
+/// `Hypercall::ReadIntegrityEvents`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct IntegrityEvent {
+    /// The ID of the processor the attempt was made on.
+    pub processor: u64,
+
+    /// The guest RIP of the writing instruction.
+    pub rip: u64,
+
+    /// The guest CR3 at the attempt, which identifies the writing process.
+    pub cr3: u64,
+
+    /// The guest physical address written to.
+    pub gpa: u64,
+
+    /// Whether the write was blocked (1) or completed (0).
+    pub blocked: u64,
+
+    /// The TSC when the attempt was made.
+    pub tsc: u64,
+}
+
+/// The maximum number of events kept until read. The oldest events are lost if
+/// not read before more events are reported.
+pub const MAX_INTEGRITY_EVENTS: usize = 64;
+
+/// The errors protection of the code may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug)]
+pub enum IntegrityError {
+    #[error("{0}")]
+    Protection(ProtectionError),
+
+    #[error("{0}")]
+    Translation(TranslationError),
+
+    #[error("{0}")]
+    Kernel(KernelError),
+
+    #[error("protections are being updated by the guest")]
+    Busy,
+}
+
+impl From<ProtectionError> for IntegrityError {
+    fn from(value: ProtectionError) -> Self {
+        Self::Protection(value)
+    }
+}
+
+impl From<TranslationError> for IntegrityError {
+    fn from(value: TranslationError) -> Self {
+        Self::Translation(value)
+    }
+}
+
+/// Write-protects `len` bytes of the code at the linear address `start` in the
+/// current address space, and reports writes to them. The range is extended
+/// to the page boundaries. Must be called from the guest, with the range
+/// resident, and before any processor is virtualized if the range is the
+/// code of this crate itself.
+///
+/// # Errors
+///
+/// Returns `Err` if any page of the range is already protected or cannot be
+/// protected. The pages protected before the failing one stay protected.
+pub fn protect_range(start: u64, len: u64, response: Response) -> Result<(), IntegrityError> {
+    for page in pages(start, len) {
+        let gpa = platform_ops::get().pa(page as *const _);
+        memory_protection::protect_gpa_range(
+            gpa,
+            BASE_PAGE_SIZE as u64,
+            Permissions::READ_EXECUTE,
+            move |vcpu, info| handle_violation(vcpu, info, response),
+        )?;
+    }
+    Ok(())
+}
+
+/// Finds the Windows kernel under the current guest state of `vcpu`, and
+/// write-protects its code sections as `protect_range` does. Returns the number
+/// of the bytes protected. Must be called from the host, such as VM-exit
+/// handlers, after the kernel has initialized, as the discardable sections are
+/// not protected. Pages already protected, such as the system call handler with
+/// `SharedHostData::syscall_protection`, and those not present are skipped.
+///
+/// # Errors
+///
+/// Returns `Kernel` if the kernel is not found, or `Busy` if the guest on any
+/// processor updates protections. In the latter case, retry on a later VM-exit.
+pub fn protect_kernel_text(vcpu: &mut dyn Vcpu, response: Response) -> Result<u64, IntegrityError> {
+    let image = KernelImage::locate(vcpu).map_err(IntegrityError::Kernel)?;
+    let mut protected = 0;
+    for section in image.code_sections() {
+        for page in pages(section.start, section.end - section.start) {
+            let Ok(translation) = guest_memory::translate_guest(vcpu, page) else {
+                continue;
+            };
+            let gpa = translation.gpa & !(BASE_PAGE_SIZE as u64 - 1);
+            match memory_protection::try_protect_gpa_range(
+                gpa,
+                BASE_PAGE_SIZE as u64,
+                Permissions::READ_EXECUTE,
+                move |vcpu, info| handle_violation(vcpu, info, response),
+            ) {
+                Some(Ok(())) => protected += BASE_PAGE_SIZE as u64,
+                Some(Err(ProtectionError::Overlaps { .. })) => {}
+                Some(Err(err)) => return Err(err.into()),
+                None => return Err(IntegrityError::Busy),
+            }
+        }
+    }
+    log::info!(
+        "Protected {protected:#x?} bytes of the kernel code at {:#x?}",
+        image.base
+    );
+    Ok(protected)
+}
+
+/// Moves up to `max` of the unread events out, from the oldest. Must be called
+/// from the host.
+pub(crate) fn take_events(max: usize) -> Vec<IntegrityEvent> {
+    let mut events = EVENTS.lock();
+    let count = max.min(events.len());
+    events.drain(..count).collect()
+}
+
+/// Returns the linear addresses of the pages `len` bytes at `start` span.
+fn pages(start: u64, len: u64) -> impl Iterator<Item = u64> {
+    let first = start & !(BASE_PAGE_SIZE as u64 - 1);
+    (first..start + len).step_by(BASE_PAGE_SIZE)
+}
+
+/// Handles a write to the monitored code.
+fn handle_violation(
+    vcpu: &mut dyn Vcpu,
+    info: &NestedPageFaultInfo,
+    response: Response,
+) -> ViolationAction {
+    let event = IntegrityEvent {
+        processor: vcpu.id() as u64,
+        rip: vcpu.regs().rip,
+        cr3: vcpu.cr3(),
+        gpa: info.gpa,
+        blocked: u64::from(response == Response::Block),
+        tsc: rdtsc(),
+    };
+    log::warn!("Write to the kernel code: {event:x?}");
+
+    let mut events = EVENTS.lock();
+    if events.len() == MAX_INTEGRITY_EVENTS {
+        let _ = events.pop_front();
+    }
+    events.push_back(event);
+    drop(events);
+
+    let _ = event_channel::post(
+        vcpu,
+        EventKind::Integrity,
+        [
+            event.processor,
+            event.rip,
+            event.cr3,
+            event.gpa,
+            event.blocked,
+            event.tsc,
+        ],
+    );
+
+    match response {
+        Response::Report => ViolationAction::Allow,
+        Response::Block => ViolationAction::Skip,
+    }
+}
+
+/// Returns whether the events are locked by any processor. See
+/// `host::fail_open`.
+pub(crate) fn is_locked() -> bool {
+    EVENTS.is_locked()
+}
+
+static EVENTS: Mutex<VecDeque<IntegrityEvent>> = Mutex::new(VecDeque::new());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_extended_to_pages() {
+        assert!(pages(0x1000, 0x1000).eq([0x1000]));
+        assert!(pages(0x1fff, 2).eq([0x1000, 0x2000]));
+        assert!(pages(0x1800, 0x1000).eq([0x1000, 0x2000]));
+        assert_eq!(pages(0x1000, 0).count(), 0);
+    }
+}
@@ -0,0 +1,338 @@
+//! This module implements the APIC virtualization features of VMX, collectively
+//! called APICv, for `interrupt_virtualization`.
+//!
+//! With virtual-interrupt delivery, the processor delivers the interrupts
+//! requested in the virtual-APIC page to the guest, in the order of their
+//! priority against the virtual TPR, and virtualizes EOI, TPR and self-IPIs
+//! of the x2APIC mode. External-interrupt exiting with "acknowledge interrupt
+//! on exit" lets the host request the interrupts of the local APIC there, and
+//! posted interrupts let the other processors request them while the guest
+//! runs. The EOI-exit bitmap makes EOIs of the level-triggered interrupts cause
+//! VM-exit. See `interrupt_virtualization` for how they are used together.
+//!
+//! See: 30.1 VIRTUAL APIC STATE
+//! See: 30.2 EVALUATION AND DELIVERY OF VIRTUAL INTERRUPTS
+//! See: 30.6 POSTED-INTERRUPT PROCESSING
+
+use bit_field::BitField;
+
+use crate::hypervisor::{
+    intel::vmcs,
+    interrupt_virtualization::{
+        is_level_triggered, local_apic_vectors, self_ipi, write_eois, DeferredEois,
+        VirtualApicPage, X2APIC_EOI, X2APIC_IRR0, X2APIC_ISR0, X2APIC_PPR, X2APIC_SELF_IPI,
+        X2APIC_TPR,
+    },
+    percpu, platform_ops,
+    support::{Page, PageBox},
+    x86_instructions::{rdmsr, wrmsr},
+    HvError, SHARED_HOST_DATA,
+};
+
+/// The APIC virtualization features the processor supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ApicvSupport {
+    pub(crate) tpr_shadow: bool,
+    pub(crate) x2apic_virtualization: bool,
+    pub(crate) apic_register_virtualization: bool,
+    pub(crate) virtual_interrupt_delivery: bool,
+    pub(crate) posted_interrupts: bool,
+    pub(crate) acknowledge_interrupt_on_exit: bool,
+}
+
+impl ApicvSupport {
+    /// Returns the features the current processor supports. The allowed
+    /// 1-settings are reported in the upper 32 bits of the capability MSRs.
+    /// See: A.3 VM-EXECUTION CONTROLS
+    /// See: A.4 VM-EXIT CONTROLS
+    pub(crate) fn detect() -> Self {
+        let allowed1 = |msr: u32, bits: u32| (rdmsr(msr) >> 32) as u32 & bits == bits;
+        let (primary, pin_based, exit) = if rdmsr(x86::msr::IA32_VMX_BASIC).get_bit(55) {
+            (
+                x86::msr::IA32_VMX_TRUE_PROCBASED_CTLS,
+                x86::msr::IA32_VMX_TRUE_PINBASED_CTLS,
+                x86::msr::IA32_VMX_TRUE_EXIT_CTLS,
+            )
+        } else {
+            (
+                x86::msr::IA32_VMX_PROCBASED_CTLS,
+                x86::msr::IA32_VMX_PINBASED_CTLS,
+                x86::msr::IA32_VMX_EXIT_CTLS,
+            )
+        };
+        let secondary = x86::msr::IA32_VMX_PROCBASED_CTLS2;
+        Self {
+            tpr_shadow: allowed1(
+                primary,
+                vmcs::control::PrimaryControls::USE_TPR_SHADOW.bits(),
+            ),
+            x2apic_virtualization: allowed1(
+                secondary,
+                vmcs::control::SecondaryControls::VIRTUALIZE_X2APIC.bits(),
+            ),
+            apic_register_virtualization: allowed1(
+                secondary,
+                vmcs::control::SecondaryControls::VIRTUALIZE_APIC_REGISTER.bits(),
+            ),
+            virtual_interrupt_delivery: allowed1(
+                secondary,
+                vmcs::control::SecondaryControls::VIRTUAL_INTERRUPT_DELIVERY.bits(),
+            ),
+            posted_interrupts: allowed1(
+                pin_based,
+                vmcs::control::PinbasedControls::POSTED_INTERRUPTS.bits(),
+            ),
+            acknowledge_interrupt_on_exit: allowed1(
+                exit,
+                vmcs::control::ExitControls::ACK_INTERRUPT_ON_EXIT.bits(),
+            ),
+        }
+    }
+
+    /// Returns whether posted interrupts and all the features they and the
+    /// x2APIC mode require are supported.
+    pub(crate) fn is_complete(self) -> bool {
+        self.tpr_shadow
+            && self.x2apic_virtualization
+            && self.apic_register_virtualization
+            && self.virtual_interrupt_delivery
+            && self.posted_interrupts
+            && self.acknowledge_interrupt_on_exit
+    }
+}
+
+/// Logs the APIC virtualization features of the current processor.
+pub(crate) fn report_support() {
+    let support = ApicvSupport::detect();
+    let enabled = SHARED_HOST_DATA.get().is_some_and(|shared_host| {
+        shared_host
+            .interrupt_virtualization
+            .notification_vector()
+            .is_some()
+    });
+    if !support.is_complete() {
+        log::debug!("APICv is partially supported: {support:?}");
+    } else if enabled {
+        log::debug!("APICv is supported and used for the guest in the x2APIC mode");
+    } else {
+        log::debug!("APICv is supported but not enabled");
+    }
+}
+
+/// The APICv state of a vCPU.
+pub(crate) struct Apicv {
+    /// The virtual-APIC page.
+    virtual_apic: PageBox<VirtualApicPage>,
+
+    /// The MSR bitmaps of the vCPU, which are the shared ones with the x2APIC
+    /// MSRs virtualized.
+    msr_bitmaps: PageBox<Page>,
+
+    /// The level-triggered interrupts whose EOIs cause VM-exit.
+    eois: DeferredEois,
+
+    /// The vector notifying the processor of the posted interrupts.
+    notification_vector: u8,
+}
+
+impl Apicv {
+    /// Returns the state for the current processor if enabled with
+    /// `SharedHostData::interrupt_virtualization` and usable, with the MSR
+    /// bitmaps built from `shared_msr_bitmaps`. See `interrupt_virtualization`
+    /// for when it is not usable.
+    pub(crate) fn new(shared_msr_bitmaps: &Page) -> Result<Option<Self>, HvError> {
+        const READ_LOW: usize = 0x0;
+        const WRITE_LOW: usize = 0x800;
+
+        let shared_host = SHARED_HOST_DATA.get().unwrap();
+        let Some(notification_vector) = shared_host.interrupt_virtualization.notification_vector()
+        else {
+            return Ok(None);
+        };
+        if notification_vector < 32 {
+            log::warn!("APICv is not used with the notification vector {notification_vector:#x}");
+            return Ok(None);
+        }
+        // The virtual APIC of L1 would have to be kept out of VMCS02 and
+        // synchronized around each VM-entry to L2. See `nested`.
+        if super::nested::is_enabled() {
+            log::warn!("APICv is not used with nested virtualization");
+            return Ok(None);
+        }
+        let x2apic_handled = (0x800..=0x8ff).any(|msr| {
+            shared_host.msr_intercepts.read_handler(msr).is_some()
+                || shared_host.msr_intercepts.write_handler(msr).is_some()
+        });
+        // See: 11.12.1 Detecting and Enabling x2APIC Mode
+        let x2apic_mode = rdmsr(x86::msr::IA32_APIC_BASE).get_bit(10);
+        if !ApicvSupport::detect().is_complete()
+            || shared_host.apic_virt.is_enabled()
+            || x2apic_handled
+            || !x2apic_mode
+        {
+            return Ok(None);
+        }
+
+        // Reads of the x2APIC MSRs are virtualized from the virtual-APIC page
+        // unless intercepted. Intercept those the local APIC serves, so that
+        // the guest reads them from the local APIC with VM-exit. Writes are
+        // intercepted except those the processor virtualizes. Writes to
+        // IA32_APIC_BASE are intercepted to see the guest leaving the x2APIC
+        // mode.
+        // See: 30.5 VIRTUALIZING MSR-BASED APIC ACCESSES
+        let mut msr_bitmaps = PageBox::<Page>::try_new()?;
+        msr_bitmaps.0.copy_from_slice(&shared_msr_bitmaps.0);
+        let mut set_bit = |base: usize, msr: u32, intercept: bool| {
+            let _ = msr_bitmaps.0[base + msr as usize / 8].set_bit(msr as usize % 8, intercept);
+        };
+        for msr in 0x800..=0x8ff {
+            let virtualized = matches!(msr, X2APIC_TPR | X2APIC_PPR)
+                || (X2APIC_ISR0..X2APIC_ISR0 + 8).contains(&msr)
+                || (X2APIC_IRR0..X2APIC_IRR0 + 8).contains(&msr);
+            set_bit(READ_LOW, msr, !virtualized);
+            set_bit(
+                WRITE_LOW,
+                msr,
+                !matches!(msr, X2APIC_TPR | X2APIC_EOI | X2APIC_SELF_IPI),
+            );
+        }
+        set_bit(WRITE_LOW, x86::msr::IA32_APIC_BASE, true);
+
+        Ok(Some(Self {
+            virtual_apic: PageBox::try_new()?,
+            msr_bitmaps,
+            eois: DeferredEois::default(),
+            notification_vector,
+        }))
+    }
+
+    /// Returns the physical address of the MSR bitmaps of the vCPU.
+    pub(crate) fn msr_bitmaps_pa(&self) -> u64 {
+        self.msr_bitmaps.pa()
+    }
+
+    /// Initializes the VMCS fields for APICv, and moves the TPR and the
+    /// interrupts in service of the local APIC into the virtual APIC. Those in
+    /// service are completed on the local APIC when the guest completes them,
+    /// as when the processor is virtualized from an interrupt handler. Must be
+    /// called while setting up the host.
+    pub(crate) fn initialize(&mut self) {
+        let percpu = percpu::current();
+        let descriptor = &percpu.posted_interrupts;
+        vmcs::control::VIRT_APIC_ADDR_FULL.write(self.virtual_apic.pa());
+        vmcs::control::POSTED_INTERRUPT_NOTIFICATION_VECTOR
+            .write(u16::from(self.notification_vector));
+        vmcs::control::POSTED_INTERRUPT_DESC_ADDR_FULL
+            .write(platform_ops::get().pa(core::ptr::from_ref(descriptor).cast()));
+
+        self.virtual_apic.set_tpr(rdmsr(X2APIC_TPR) as u32);
+        wrmsr(X2APIC_TPR, 0);
+        let in_service = local_apic_vectors(X2APIC_ISR0);
+        self.virtual_apic.set_in_service(in_service);
+        for vector in in_service.iter() {
+            self.eois.defer(vector);
+        }
+        self.update_eoi_exits();
+        vmcs::guest::INTERRUPT_STATUS.write(u16::from(in_service.highest().unwrap_or(0)) << 8);
+
+        descriptor.enable(self.notification_vector, percpu.apic_id);
+    }
+
+    /// Requests `vector` in the virtual APIC, to be delivered to the guest
+    /// once it can receive it.
+    pub(crate) fn request(&mut self, vector: u8) {
+        // RVI is the highest vector requested. VM-entry evaluates it against
+        // the virtual PPR.
+        // See: 30.2.1 Evaluation of Pending Virtual Interrupts
+        self.virtual_apic.request(vector);
+        let status = vmcs::guest::INTERRUPT_STATUS.read();
+        if vector > status as u8 {
+            vmcs::guest::INTERRUPT_STATUS.write(status & 0xff00 | u16::from(vector));
+        }
+    }
+
+    /// Requests the interrupts posted to the processor while it was in the
+    /// host. Must be called right before VM-entry.
+    pub(crate) fn take_posted(&mut self) {
+        if let Some(posted) = percpu::current().posted_interrupts.take() {
+            for vector in posted.iter() {
+                self.request(vector);
+            }
+        }
+    }
+
+    /// Handles VM-exit due to an external interrupt, which the processor
+    /// acknowledged on the local APIC. Requests it in the virtual APIC, and
+    /// completes it on the local APIC unless level-triggered.
+    pub(crate) fn handle_external_interrupt(&mut self) {
+        // See: Table 25-19. Format of the VM-Exit Interruption-Information Field
+        let vector = vmcs::ro::VMEXIT_INTERRUPTION_INFO.read().get_bits(0..=7) as u8;
+
+        // The notification is processed in the guest. One acknowledged here
+        // arrived just as the guest exited.
+        if vector == self.notification_vector {
+            write_eois(1);
+            self.take_posted();
+            return;
+        }
+
+        if is_level_triggered(vector) {
+            self.eois.defer(vector);
+            self.update_eoi_exits();
+        } else {
+            write_eois(1);
+        }
+        self.request(vector);
+    }
+
+    /// Handles VM-exit due to EOI of a level-triggered interrupt, completing it
+    /// on the local APIC.
+    pub(crate) fn handle_virtual_eoi(&mut self) {
+        // The exit qualification is the vector completed.
+        // See: 28.2.1 Basic VM-Exit Information
+        let vector = vmcs::ro::EXIT_QUALIFICATION.read() as u8;
+        write_eois(self.eois.complete(vector));
+        self.update_eoi_exits();
+    }
+
+    /// Resets the virtual APIC on INIT, completing the interrupts the EOIs of
+    /// which are deferred on the local APIC.
+    pub(crate) fn reset(&mut self) {
+        write_eois(self.eois.take_all());
+        self.update_eoi_exits();
+        self.virtual_apic.reset();
+        vmcs::guest::INTERRUPT_STATUS.write(0);
+        let _ = percpu::current().posted_interrupts.take();
+    }
+
+    /// Stops posting to the processor, and moves the state of the virtual APIC
+    /// back into the local APIC, for the guest to use the local APIC again.
+    ///
+    /// The interrupts requested are requested on the local APIC with self-IPIs,
+    /// and the level-triggered ones are completed on the local APIC, which the
+    /// I/O APIC delivers again if still asserted. The interrupts in service in
+    /// the guest are not in service on the local APIC, where EOI is ignored
+    /// without one in service.
+    pub(crate) fn deactivate(&mut self) {
+        let posted = percpu::current().posted_interrupts.disable();
+        let deferred = self.eois.deferred();
+        write_eois(self.eois.take_all());
+        let requested = self.virtual_apic.requested();
+        for vector in (0..=u8::MAX).filter(|&vector| {
+            (requested.contains(vector) || posted.contains(vector)) && !deferred.contains(vector)
+        }) {
+            self_ipi(vector);
+        }
+        wrmsr(X2APIC_TPR, u64::from(self.virtual_apic.tpr()));
+    }
+
+    /// Writes the EOI-exit bitmap with the interrupts the EOIs of which are
+    /// deferred.
+    fn update_eoi_exits(&self) {
+        let words = self.eois.deferred().words();
+        vmcs::control::EOI_EXIT0_FULL.write(words[0]);
+        vmcs::control::EOI_EXIT1_FULL.write(words[1]);
+        vmcs::control::EOI_EXIT2_FULL.write(words[2]);
+        vmcs::control::EOI_EXIT3_FULL.write(words[3]);
+    }
+}
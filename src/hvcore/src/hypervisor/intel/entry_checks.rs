@@ -0,0 +1,1093 @@
+//! This module implements a software validator of the guest-state area of the
+//! current VMCS. VM-entry failing due to invalid guest state reports only that
+//! one of the many checks failed, so the checks are repeated here one by one,
+//! and every check violated is logged with the field and its value.
+//!
+//! The checks follow 27.3.1 Checks on the Guest State Area, except those on
+//! features this hypervisor never enables, such as entry to SMM, VMCS
+//! shadowing, and loading of IA32_BNDCFGS, IA32_RTIT_CTL, IA32_PKRS and the LBR
+//! state. Passing all checks here does not guarantee that VM-entry succeeds.
+
+use alloc::vec::Vec;
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+
+use super::vmcs::{
+    self,
+    control::{EntryControls, PinbasedControls, SecondaryControls},
+};
+use crate::hypervisor::x86_instructions::rdmsr;
+
+/// A check the guest state violates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Violation {
+    /// The name of the field checked.
+    field: &'static str,
+
+    /// The value of the field.
+    value: u64,
+
+    /// The rule violated.
+    rule: &'static str,
+}
+
+impl core::fmt::Display for Violation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} ({:#x}): {}", self.field, self.value, self.rule)
+    }
+}
+
+/// Checks the guest state of the current VMCS, and logs every check violated.
+pub(super) fn log_violations() {
+    let violations = check(&GuestState::read());
+    if violations.is_empty() {
+        log::error!("No guest-state check found violated");
+    }
+    for violation in &violations {
+        log::error!("Guest-state check violated: {violation}");
+    }
+}
+
+/// A segment register in the guest-state area.
+#[derive(Clone, Copy, Debug, Default)]
+struct Segment {
+    selector: u16,
+    base: u64,
+    limit: u32,
+    access_rights: u32,
+}
+
+impl Segment {
+    fn read(
+        selector: vmcs::Field16,
+        base: vmcs::FieldNatural,
+        limit: vmcs::Field32,
+        access_rights: vmcs::Field32,
+    ) -> Self {
+        Self {
+            selector: selector.read(),
+            base: base.read(),
+            limit: limit.read(),
+            access_rights: access_rights.read(),
+        }
+    }
+
+    // See: Table 25-2. Format of Access Rights
+    fn segment_type(&self) -> u32 {
+        self.access_rights.get_bits(0..=3)
+    }
+
+    fn is_system(&self) -> bool {
+        !self.access_rights.get_bit(4)
+    }
+
+    fn dpl(&self) -> u32 {
+        self.access_rights.get_bits(5..=6)
+    }
+
+    fn is_present(&self) -> bool {
+        self.access_rights.get_bit(7)
+    }
+
+    fn has_reserved_bits(&self) -> bool {
+        self.access_rights.get_bits(8..=11) != 0 || self.access_rights.get_bits(17..=31) != 0
+    }
+
+    fn is_long(&self) -> bool {
+        self.access_rights.get_bit(13)
+    }
+
+    fn is_unusable(&self) -> bool {
+        self.access_rights.get_bit(16)
+    }
+
+    fn rpl(&self) -> u32 {
+        u32::from(self.selector.get_bits(0..=1))
+    }
+
+    /// Returns whether the granularity is consistent with the limit.
+    fn is_granularity_valid(&self) -> bool {
+        let granularity = self.access_rights.get_bit(15);
+        (self.limit.get_bits(0..=11) == 0xfff || !granularity)
+            && (self.limit.get_bits(20..=31) == 0 || granularity)
+    }
+}
+
+/// The guest-state area, along with the controls and the capabilities the
+/// checks depend on.
+#[derive(Clone, Copy, Debug, Default)]
+struct GuestState {
+    entry_controls: u32,
+    pin_based_controls: u32,
+    secondary_controls: u32,
+    entry_interruption_info: u32,
+    cr0_fixed0: u64,
+    cr0_fixed1: u64,
+    cr4_fixed0: u64,
+    cr4_fixed1: u64,
+    physical_address_width: usize,
+    linear_address_width: usize,
+
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    dr7: u64,
+    rip: u64,
+    rflags: u64,
+    debugctl: u64,
+    pat: u64,
+    efer: u64,
+    sysenter_esp: u64,
+    sysenter_eip: u64,
+    s_cet: u64,
+    ssp: u64,
+    interrupt_ssp_table: u64,
+    es: Segment,
+    cs: Segment,
+    ss: Segment,
+    ds: Segment,
+    fs: Segment,
+    gs: Segment,
+    ldtr: Segment,
+    tr: Segment,
+    gdtr_base: u64,
+    gdtr_limit: u32,
+    idtr_base: u64,
+    idtr_limit: u32,
+    activity_state: u32,
+    interruptibility_state: u32,
+    pending_debug_exceptions: u64,
+    link_pointer: u64,
+    pdptes: [u64; 4],
+}
+
+impl GuestState {
+    /// Reads the state from the current VMCS.
+    fn read() -> Self {
+        const IA32_VMX_CR0_FIXED0: u32 = 0x486;
+        const IA32_VMX_CR0_FIXED1: u32 = 0x487;
+        const IA32_VMX_CR4_FIXED0: u32 = 0x488;
+        const IA32_VMX_CR4_FIXED1: u32 = 0x489;
+
+        use vmcs::guest;
+
+        let entry_controls = vmcs::control::VMENTRY_CONTROLS.read();
+        let load_cet = entry_controls & ENTRY_LOAD_CET_STATE != 0;
+        let address_sizes = cpuid!(0x8000_0008).eax;
+        Self {
+            entry_controls,
+            pin_based_controls: vmcs::control::PINBASED_EXEC_CONTROLS.read(),
+            secondary_controls: vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS.read(),
+            entry_interruption_info: vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD.read(),
+            cr0_fixed0: rdmsr(IA32_VMX_CR0_FIXED0),
+            cr0_fixed1: rdmsr(IA32_VMX_CR0_FIXED1),
+            cr4_fixed0: rdmsr(IA32_VMX_CR4_FIXED0),
+            cr4_fixed1: rdmsr(IA32_VMX_CR4_FIXED1),
+            physical_address_width: address_sizes.get_bits(0..=7) as usize,
+            linear_address_width: address_sizes.get_bits(8..=15) as usize,
+
+            cr0: guest::CR0.read(),
+            cr3: guest::CR3.read(),
+            cr4: guest::CR4.read(),
+            dr7: guest::DR7.read(),
+            rip: guest::RIP.read(),
+            rflags: guest::RFLAGS.read(),
+            debugctl: guest::IA32_DEBUGCTL_FULL.read(),
+            pat: guest::IA32_PAT_FULL.read(),
+            efer: guest::IA32_EFER_FULL.read(),
+            sysenter_esp: guest::IA32_SYSENTER_ESP.read(),
+            sysenter_eip: guest::IA32_SYSENTER_EIP.read(),
+            // The fields exist only if the processor supports CET.
+            s_cet: if load_cet {
+                guest::IA32_S_CET.read()
+            } else {
+                0
+            },
+            ssp: if load_cet { guest::SSP.read() } else { 0 },
+            interrupt_ssp_table: if load_cet {
+                guest::IA32_INTERRUPT_SSP_TABLE_ADDR.read()
+            } else {
+                0
+            },
+            es: Segment::read(
+                guest::ES_SELECTOR,
+                guest::ES_BASE,
+                guest::ES_LIMIT,
+                guest::ES_ACCESS_RIGHTS,
+            ),
+            cs: Segment::read(
+                guest::CS_SELECTOR,
+                guest::CS_BASE,
+                guest::CS_LIMIT,
+                guest::CS_ACCESS_RIGHTS,
+            ),
+            ss: Segment::read(
+                guest::SS_SELECTOR,
+                guest::SS_BASE,
+                guest::SS_LIMIT,
+                guest::SS_ACCESS_RIGHTS,
+            ),
+            ds: Segment::read(
+                guest::DS_SELECTOR,
+                guest::DS_BASE,
+                guest::DS_LIMIT,
+                guest::DS_ACCESS_RIGHTS,
+            ),
+            fs: Segment::read(
+                guest::FS_SELECTOR,
+                guest::FS_BASE,
+                guest::FS_LIMIT,
+                guest::FS_ACCESS_RIGHTS,
+            ),
+            gs: Segment::read(
+                guest::GS_SELECTOR,
+                guest::GS_BASE,
+                guest::GS_LIMIT,
+                guest::GS_ACCESS_RIGHTS,
+            ),
+            ldtr: Segment::read(
+                guest::LDTR_SELECTOR,
+                guest::LDTR_BASE,
+                guest::LDTR_LIMIT,
+                guest::LDTR_ACCESS_RIGHTS,
+            ),
+            tr: Segment::read(
+                guest::TR_SELECTOR,
+                guest::TR_BASE,
+                guest::TR_LIMIT,
+                guest::TR_ACCESS_RIGHTS,
+            ),
+            gdtr_base: guest::GDTR_BASE.read(),
+            gdtr_limit: guest::GDTR_LIMIT.read(),
+            idtr_base: guest::IDTR_BASE.read(),
+            idtr_limit: guest::IDTR_LIMIT.read(),
+            activity_state: guest::ACTIVITY_STATE.read(),
+            interruptibility_state: guest::INTERRUPTIBILITY_STATE.read(),
+            pending_debug_exceptions: guest::PENDING_DBG_EXCEPTIONS.read(),
+            link_pointer: guest::LINK_PTR_FULL.read(),
+            pdptes: [
+                guest::PDPTE0_FULL.read(),
+                guest::PDPTE1_FULL.read(),
+                guest::PDPTE2_FULL.read(),
+                guest::PDPTE3_FULL.read(),
+            ],
+        }
+    }
+
+    fn is_ia32e_mode(&self) -> bool {
+        self.entry_controls & EntryControls::IA32E_MODE_GUEST.bits() != 0
+    }
+
+    fn is_unrestricted(&self) -> bool {
+        self.secondary_controls & SecondaryControls::UNRESTRICTED_GUEST.bits() != 0
+    }
+
+    fn is_virtual_8086(&self) -> bool {
+        self.rflags & RFLAGS_VM != 0
+    }
+
+    fn is_canonical(&self, address: u64) -> bool {
+        let shift = 64 - self.linear_address_width;
+        (((address << shift) as i64) >> shift) as u64 == address
+    }
+}
+
+/// The "load CET state" VM-entry control.
+const ENTRY_LOAD_CET_STATE: u32 = 1 << 20;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_WP: u64 = 1 << 16;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_PCIDE: u64 = 1 << 17;
+const CR4_CET: u64 = 1 << 23;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_VM: u64 = 1 << 17;
+
+/// Collects violations.
+#[derive(Default)]
+struct Checker(Vec<Violation>);
+
+impl Checker {
+    fn require(&mut self, satisfied: bool, field: &'static str, value: u64, rule: &'static str) {
+        if !satisfied {
+            self.0.push(Violation { field, value, rule });
+        }
+    }
+}
+
+/// Returns the checks `state` violates.
+fn check(state: &GuestState) -> Vec<Violation> {
+    let mut checker = Checker::default();
+    check_registers(state, &mut checker);
+    check_segments(state, &mut checker);
+    check_descriptor_tables(state, &mut checker);
+    check_rip_and_rflags(state, &mut checker);
+    check_non_register_state(state, &mut checker);
+    check_pdptes(state, &mut checker);
+    checker.0
+}
+
+// See: 27.3.1.1 Checks on Guest Control Registers, Debug Registers, and MSRs
+fn check_registers(state: &GuestState, checker: &mut Checker) {
+    const DEBUGCTL_RESERVED: u64 = 0xffff_ffff_ffff_003c;
+    const EFER_SCE: u64 = 1 << 0;
+    const EFER_LME: u64 = 1 << 8;
+    const EFER_LMA: u64 = 1 << 10;
+    const EFER_NXE: u64 = 1 << 11;
+
+    let cr0 = state.cr0;
+    let cr4 = state.cr4;
+
+    // PE and PG are not fixed for the unrestricted guest.
+    let mut cr0_fixed0 = state.cr0_fixed0;
+    if state.is_unrestricted() {
+        cr0_fixed0 &= !(CR0_PE | CR0_PG);
+    }
+    checker.require(
+        cr0 & cr0_fixed0 == cr0_fixed0,
+        "CR0",
+        cr0,
+        "bits fixed to 1 in VMX operation must be 1",
+    );
+    checker.require(
+        cr0 & !state.cr0_fixed1 == 0,
+        "CR0",
+        cr0,
+        "bits fixed to 0 in VMX operation must be 0",
+    );
+    checker.require(
+        cr0 & CR0_PG == 0 || cr0 & CR0_PE != 0,
+        "CR0",
+        cr0,
+        "PE must be 1 if PG is 1",
+    );
+    checker.require(
+        cr4 & state.cr4_fixed0 == state.cr4_fixed0,
+        "CR4",
+        cr4,
+        "bits fixed to 1 in VMX operation must be 1",
+    );
+    checker.require(
+        cr4 & !state.cr4_fixed1 == 0,
+        "CR4",
+        cr4,
+        "bits fixed to 0 in VMX operation must be 0",
+    );
+    checker.require(
+        cr4 & CR4_CET == 0 || cr0 & CR0_WP != 0,
+        "CR4",
+        cr4,
+        "CET must be 0 if CR0.WP is 0",
+    );
+    if state.is_ia32e_mode() {
+        checker.require(
+            cr0 & CR0_PG != 0,
+            "CR0",
+            cr0,
+            "PG must be 1 with the \"IA-32e mode guest\" control",
+        );
+        checker.require(
+            cr4 & CR4_PAE != 0,
+            "CR4",
+            cr4,
+            "PAE must be 1 with the \"IA-32e mode guest\" control",
+        );
+    } else {
+        checker.require(
+            cr4 & CR4_PCIDE == 0,
+            "CR4",
+            cr4,
+            "PCIDE must be 0 without the \"IA-32e mode guest\" control",
+        );
+    }
+    checker.require(
+        state.cr3 >> state.physical_address_width == 0,
+        "CR3",
+        state.cr3,
+        "bits beyond the physical-address width must be 0",
+    );
+
+    if state.entry_controls & EntryControls::LOAD_DEBUG_CONTROLS.bits() != 0 {
+        checker.require(
+            state.debugctl & DEBUGCTL_RESERVED == 0,
+            "IA32_DEBUGCTL",
+            state.debugctl,
+            "reserved bits 5:2 and 63:16 must be 0",
+        );
+        checker.require(
+            state.dr7 >> 32 == 0,
+            "DR7",
+            state.dr7,
+            "bits 63:32 must be 0",
+        );
+    }
+    checker.require(
+        state.is_canonical(state.sysenter_esp),
+        "IA32_SYSENTER_ESP",
+        state.sysenter_esp,
+        "must be canonical",
+    );
+    checker.require(
+        state.is_canonical(state.sysenter_eip),
+        "IA32_SYSENTER_EIP",
+        state.sysenter_eip,
+        "must be canonical",
+    );
+    if state.entry_controls & ENTRY_LOAD_CET_STATE != 0 {
+        checker.require(
+            state.is_canonical(state.s_cet) && state.s_cet.get_bits(2..=5) == 0,
+            "IA32_S_CET",
+            state.s_cet,
+            "must be canonical, and reserved bits 5:2 must be 0",
+        );
+        checker.require(
+            state.is_canonical(state.interrupt_ssp_table),
+            "IA32_INTERRUPT_SSP_TABLE_ADDR",
+            state.interrupt_ssp_table,
+            "must be canonical",
+        );
+        checker.require(
+            state.is_canonical(state.ssp) && state.ssp.get_bits(0..=1) == 0,
+            "SSP",
+            state.ssp,
+            "must be canonical, and bits 1:0 must be 0",
+        );
+    }
+    if state.entry_controls & EntryControls::LOAD_IA32_PAT.bits() != 0 {
+        checker.require(
+            state
+                .pat
+                .to_le_bytes()
+                .iter()
+                .all(|memory_type| matches!(memory_type, 0 | 1 | 4..=7)),
+            "IA32_PAT",
+            state.pat,
+            "each entry must be a valid memory type",
+        );
+    }
+    if state.entry_controls & EntryControls::LOAD_IA32_EFER.bits() != 0 {
+        let efer = state.efer;
+        checker.require(
+            efer & !(EFER_SCE | EFER_LME | EFER_LMA | EFER_NXE) == 0,
+            "IA32_EFER",
+            efer,
+            "reserved bits must be 0",
+        );
+        checker.require(
+            (efer & EFER_LMA != 0) == state.is_ia32e_mode(),
+            "IA32_EFER",
+            efer,
+            "LMA must equal the \"IA-32e mode guest\" control",
+        );
+        checker.require(
+            cr0 & CR0_PG == 0 || (efer & EFER_LMA != 0) == (efer & EFER_LME != 0),
+            "IA32_EFER",
+            efer,
+            "LMA must equal LME if CR0.PG is 1",
+        );
+    }
+}
+
+// See: 27.3.1.2 Checks on Guest Segment Registers
+fn check_segments(state: &GuestState, checker: &mut Checker) {
+    let unrestricted = state.is_unrestricted();
+    let (cs, ss, tr, ldtr) = (&state.cs, &state.ss, &state.tr, &state.ldtr);
+    let data_segments = [
+        ("ES", &state.es),
+        ("DS", &state.ds),
+        ("FS", &state.fs),
+        ("GS", &state.gs),
+    ];
+    let usable_segments = || {
+        [("CS", cs), ("SS", ss)]
+            .into_iter()
+            .chain(data_segments)
+            .filter(|(_, segment)| !segment.is_unusable())
+    };
+
+    // Selectors.
+    checker.require(
+        !tr.selector.get_bit(2),
+        "TR selector",
+        tr.selector.into(),
+        "TI must be 0",
+    );
+    if !ldtr.is_unusable() {
+        checker.require(
+            !ldtr.selector.get_bit(2),
+            "LDTR selector",
+            ldtr.selector.into(),
+            "TI must be 0",
+        );
+    }
+    if !state.is_virtual_8086() && !unrestricted {
+        checker.require(
+            ss.rpl() == cs.rpl(),
+            "SS selector",
+            ss.selector.into(),
+            "RPL must equal the RPL of CS",
+        );
+    }
+
+    // Bases.
+    checker.require(
+        state.is_canonical(tr.base),
+        "TR base",
+        tr.base,
+        "must be canonical",
+    );
+    for (name, segment) in [("FS base", &state.fs), ("GS base", &state.gs)] {
+        checker.require(
+            state.is_canonical(segment.base),
+            name,
+            segment.base,
+            "must be canonical",
+        );
+    }
+    if !ldtr.is_unusable() {
+        checker.require(
+            state.is_canonical(ldtr.base),
+            "LDTR base",
+            ldtr.base,
+            "must be canonical",
+        );
+    }
+    checker.require(
+        cs.base >> 32 == 0,
+        "CS base",
+        cs.base,
+        "bits 63:32 must be 0",
+    );
+    for (name, segment) in [
+        ("SS base", ss),
+        ("DS base", &state.ds),
+        ("ES base", &state.es),
+    ] {
+        if !segment.is_unusable() {
+            checker.require(
+                segment.base >> 32 == 0,
+                name,
+                segment.base,
+                "bits 63:32 must be 0",
+            );
+        }
+    }
+
+    // Virtual-8086 mode has its own rules for the bases, limits and access
+    // rights, and no other rules apply to them.
+    if state.is_virtual_8086() {
+        for (name, segment) in [("CS", cs), ("SS", ss)].into_iter().chain(data_segments) {
+            checker.require(
+                segment.base == u64::from(segment.selector) << 4,
+                name,
+                segment.base,
+                "base must be the selector shifted left by 4 in virtual-8086 mode",
+            );
+            checker.require(
+                segment.limit == 0xffff,
+                name,
+                segment.limit.into(),
+                "limit must be FFFFH in virtual-8086 mode",
+            );
+            checker.require(
+                segment.access_rights == 0xf3,
+                name,
+                segment.access_rights.into(),
+                "access rights must be F3H in virtual-8086 mode",
+            );
+        }
+    } else {
+        check_code_and_data_segments(state, checker, &data_segments);
+        for (name, segment) in usable_segments() {
+            checker.require(
+                segment.is_present(),
+                name,
+                segment.access_rights.into(),
+                "P must be 1",
+            );
+            checker.require(
+                !segment.has_reserved_bits(),
+                name,
+                segment.access_rights.into(),
+                "reserved bits 11:8 and 31:17 of the access rights must be 0",
+            );
+            checker.require(
+                segment.is_granularity_valid(),
+                name,
+                segment.access_rights.into(),
+                "G must be consistent with the limit",
+            );
+        }
+    }
+
+    // TR and LDTR.
+    let tr_type_valid = if state.is_ia32e_mode() {
+        tr.segment_type() == 11
+    } else {
+        matches!(tr.segment_type(), 3 | 11)
+    };
+    checker.require(
+        tr_type_valid,
+        "TR",
+        tr.access_rights.into(),
+        "type must be a busy TSS",
+    );
+    checker.require(
+        tr.is_system() && tr.is_present() && !tr.is_unusable(),
+        "TR",
+        tr.access_rights.into(),
+        "S must be 0, P must be 1, and TR must be usable",
+    );
+    checker.require(
+        !tr.has_reserved_bits() && tr.is_granularity_valid(),
+        "TR",
+        tr.access_rights.into(),
+        "reserved bits must be 0, and G must be consistent with the limit",
+    );
+    if !ldtr.is_unusable() {
+        checker.require(
+            ldtr.segment_type() == 2 && ldtr.is_system() && ldtr.is_present(),
+            "LDTR",
+            ldtr.access_rights.into(),
+            "type must be 2, S must be 0, and P must be 1",
+        );
+        checker.require(
+            !ldtr.has_reserved_bits() && ldtr.is_granularity_valid(),
+            "LDTR",
+            ldtr.access_rights.into(),
+            "reserved bits must be 0, and G must be consistent with the limit",
+        );
+    }
+}
+
+/// Checks the types and the DPLs of CS, SS and `data_segments` outside
+/// virtual-8086 mode.
+fn check_code_and_data_segments(
+    state: &GuestState,
+    checker: &mut Checker,
+    data_segments: &[(&'static str, &Segment)],
+) {
+    let unrestricted = state.is_unrestricted();
+    let (cs, ss) = (&state.cs, &state.ss);
+
+    let cs_type = cs.segment_type();
+    checker.require(
+        matches!(cs_type, 9 | 11 | 13 | 15) || (unrestricted && cs_type == 3),
+        "CS",
+        cs.access_rights.into(),
+        "type must be an accessed code segment, or 3 for the unrestricted guest",
+    );
+    checker.require(
+        !cs.is_system(),
+        "CS",
+        cs.access_rights.into(),
+        "S must be 1",
+    );
+    match cs_type {
+        3 => checker.require(
+            cs.dpl() == 0,
+            "CS",
+            cs.access_rights.into(),
+            "DPL must be 0 for type 3",
+        ),
+        9 | 11 => checker.require(
+            cs.dpl() == ss.dpl(),
+            "CS",
+            cs.access_rights.into(),
+            "DPL must equal the DPL of SS for a non-conforming code segment",
+        ),
+        13 | 15 => checker.require(
+            cs.dpl() <= ss.dpl(),
+            "CS",
+            cs.access_rights.into(),
+            "DPL must not be greater than the DPL of SS for a conforming code segment",
+        ),
+        _ => {}
+    }
+    if state.is_ia32e_mode() && cs.is_long() {
+        checker.require(
+            !cs.access_rights.get_bit(14),
+            "CS",
+            cs.access_rights.into(),
+            "D/B must be 0 for a 64-bit code segment",
+        );
+    }
+
+    if !ss.is_unusable() {
+        checker.require(
+            matches!(ss.segment_type(), 3 | 7) && !ss.is_system(),
+            "SS",
+            ss.access_rights.into(),
+            "type must be a read/write data segment, and S must be 1",
+        );
+    }
+    if !unrestricted {
+        checker.require(
+            ss.dpl() == ss.rpl(),
+            "SS",
+            ss.access_rights.into(),
+            "DPL must equal the RPL of the selector",
+        );
+    }
+    if cs_type == 3 || state.cr0 & CR0_PE == 0 {
+        checker.require(
+            ss.dpl() == 0,
+            "SS",
+            ss.access_rights.into(),
+            "DPL must be 0 if the type of CS is 3 or CR0.PE is 0",
+        );
+    }
+
+    for &(name, segment) in data_segments {
+        if segment.is_unusable() {
+            continue;
+        }
+        let segment_type = segment.segment_type();
+        checker.require(
+            segment_type.get_bit(0) && (!segment_type.get_bit(3) || segment_type.get_bit(1)),
+            name,
+            segment.access_rights.into(),
+            "type must be accessed, and readable if a code segment",
+        );
+        checker.require(
+            !segment.is_system(),
+            name,
+            segment.access_rights.into(),
+            "S must be 1",
+        );
+        if !unrestricted && segment_type <= 11 {
+            checker.require(
+                segment.dpl() >= segment.rpl(),
+                name,
+                segment.access_rights.into(),
+                "DPL must not be less than the RPL of the selector",
+            );
+        }
+    }
+}
+
+// See: 27.3.1.3 Checks on Guest Descriptor-Table Registers
+fn check_descriptor_tables(state: &GuestState, checker: &mut Checker) {
+    for (name, base, limit) in [
+        ("GDTR", state.gdtr_base, state.gdtr_limit),
+        ("IDTR", state.idtr_base, state.idtr_limit),
+    ] {
+        checker.require(
+            state.is_canonical(base),
+            name,
+            base,
+            "base must be canonical",
+        );
+        checker.require(
+            limit >> 16 == 0,
+            name,
+            limit.into(),
+            "bits 31:16 of the limit must be 0",
+        );
+    }
+}
+
+// See: 27.3.1.4 Checks on Guest RIP, RFLAGS, and SSP
+fn check_rip_and_rflags(state: &GuestState, checker: &mut Checker) {
+    const RFLAGS_RESERVED: u64 = 0xffff_ffff_ffc0_8028;
+    const RFLAGS_FIXED1: u64 = 1 << 1;
+    const EXTERNAL_INTERRUPT: u32 = 0;
+
+    if state.is_ia32e_mode() && state.cs.is_long() {
+        checker.require(
+            state.is_canonical(state.rip),
+            "RIP",
+            state.rip,
+            "must be canonical",
+        );
+    } else {
+        checker.require(
+            state.rip >> 32 == 0,
+            "RIP",
+            state.rip,
+            "bits 63:32 must be 0 outside 64-bit mode",
+        );
+    }
+
+    let rflags = state.rflags;
+    checker.require(
+        rflags & RFLAGS_RESERVED == 0 && rflags & RFLAGS_FIXED1 != 0,
+        "RFLAGS",
+        rflags,
+        "reserved bits must be 0, and bit 1 must be 1",
+    );
+    if state.is_ia32e_mode() || state.cr0 & CR0_PE == 0 {
+        checker.require(
+            rflags & RFLAGS_VM == 0,
+            "RFLAGS",
+            rflags,
+            "VM must be 0 in IA-32e mode or if CR0.PE is 0",
+        );
+    }
+    let info = state.entry_interruption_info;
+    if info.get_bit(31) && info.get_bits(8..=10) == EXTERNAL_INTERRUPT {
+        checker.require(
+            rflags & RFLAGS_IF != 0,
+            "RFLAGS",
+            rflags,
+            "IF must be 1 to inject an external interrupt",
+        );
+    }
+}
+
+// See: 27.3.1.5 Checks on Guest Non-Register State
+fn check_non_register_state(state: &GuestState, checker: &mut Checker) {
+    const HLT: u32 = 1;
+    const BLOCKING_BY_STI: u32 = 1 << 0;
+    const BLOCKING_BY_MOV_SS: u32 = 1 << 1;
+    const BLOCKING_BY_SMI: u32 = 1 << 2;
+    const BLOCKING_BY_NMI: u32 = 1 << 3;
+    const EXTERNAL_INTERRUPT: u32 = 0;
+    const NMI: u32 = 2;
+    const PENDING_DEBUG_RESERVED: u64 = 0xffff_ffff_fffe_aff0;
+    const PENDING_DEBUG_BS: u64 = 1 << 14;
+    const RFLAGS_TF: u64 = 1 << 8;
+    const DEBUGCTL_BTF: u64 = 1 << 1;
+
+    let activity = state.activity_state;
+    checker.require(
+        activity <= 3,
+        "Activity state",
+        activity.into(),
+        "must be 0-3",
+    );
+    if state.ss.dpl() != 0 {
+        checker.require(
+            activity != HLT,
+            "Activity state",
+            activity.into(),
+            "must not be HLT if the DPL of SS is not 0",
+        );
+    }
+
+    let interruptibility = state.interruptibility_state;
+    checker.require(
+        interruptibility.get_bits(5..=31) == 0,
+        "Interruptibility state",
+        interruptibility.into(),
+        "reserved bits 31:5 must be 0",
+    );
+    checker.require(
+        interruptibility & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS)
+            != BLOCKING_BY_STI | BLOCKING_BY_MOV_SS,
+        "Interruptibility state",
+        interruptibility.into(),
+        "blocking by STI and by MOV SS must not both be 1",
+    );
+    checker.require(
+        state.rflags & RFLAGS_IF != 0 || interruptibility & BLOCKING_BY_STI == 0,
+        "Interruptibility state",
+        interruptibility.into(),
+        "blocking by STI must be 0 if RFLAGS.IF is 0",
+    );
+    checker.require(
+        interruptibility & BLOCKING_BY_SMI == 0,
+        "Interruptibility state",
+        interruptibility.into(),
+        "blocking by SMI must be 0 outside SMM",
+    );
+    let info = state.entry_interruption_info;
+    if info.get_bit(31) {
+        match info.get_bits(8..=10) {
+            EXTERNAL_INTERRUPT => checker.require(
+                interruptibility & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) == 0,
+                "Interruptibility state",
+                interruptibility.into(),
+                "blocking by STI and by MOV SS must be 0 to inject an external interrupt",
+            ),
+            NMI => {
+                checker.require(
+                    interruptibility & BLOCKING_BY_MOV_SS == 0,
+                    "Interruptibility state",
+                    interruptibility.into(),
+                    "blocking by MOV SS must be 0 to inject an NMI",
+                );
+                if state.pin_based_controls & PinbasedControls::VIRTUAL_NMIS.bits() != 0 {
+                    checker.require(
+                        interruptibility & BLOCKING_BY_NMI == 0,
+                        "Interruptibility state",
+                        interruptibility.into(),
+                        "blocking by NMI must be 0 to inject an NMI with virtual NMIs",
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let pending = state.pending_debug_exceptions;
+    checker.require(
+        pending & PENDING_DEBUG_RESERVED == 0,
+        "Pending debug exceptions",
+        pending,
+        "reserved bits 11:4, 13, 15 and 63:17 must be 0",
+    );
+    if interruptibility & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) != 0 || activity == HLT {
+        let single_step = state.rflags & RFLAGS_TF != 0 && state.debugctl & DEBUGCTL_BTF == 0;
+        checker.require(
+            (pending & PENDING_DEBUG_BS != 0) == single_step,
+            "Pending debug exceptions",
+            pending,
+            "BS must be 1 exactly if RFLAGS.TF is 1 and IA32_DEBUGCTL.BTF is 0, \
+             with blocking by STI or MOV SS, or in the HLT state",
+        );
+    }
+
+    checker.require(
+        state.link_pointer == u64::MAX,
+        "VMCS link pointer",
+        state.link_pointer,
+        "must be FFFFFFFF_FFFFFFFFH, as no shadow VMCS is used",
+    );
+}
+
+// See: 27.3.1.6 Checks on Guest Page-Directory-Pointer-Table Entries
+fn check_pdptes(state: &GuestState, checker: &mut Checker) {
+    const PDPTE_PRESENT: u64 = 1 << 0;
+    const PDPTE_RESERVED: u64 = 0b1_1110_0110;
+
+    if state.cr0 & CR0_PG == 0 || state.cr4 & CR4_PAE == 0 || state.is_ia32e_mode() {
+        return;
+    }
+    let reserved = PDPTE_RESERVED | !0u64 << state.physical_address_width;
+    for (pdpte, name) in state
+        .pdptes
+        .into_iter()
+        .zip(["PDPTE0", "PDPTE1", "PDPTE2", "PDPTE3"])
+    {
+        if pdpte & PDPTE_PRESENT != 0 {
+            checker.require(
+                pdpte & reserved == 0,
+                name,
+                pdpte,
+                "reserved bits must be 0 if present",
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the state of a 64-bit guest in the kernel.
+    fn valid_state() -> GuestState {
+        let data = Segment {
+            selector: 0x2b,
+            base: 0,
+            limit: 0xffff_ffff,
+            access_rights: 0xc0f3,
+        };
+        GuestState {
+            entry_controls: EntryControls::IA32E_MODE_GUEST.bits(),
+            pin_based_controls: PinbasedControls::VIRTUAL_NMIS.bits(),
+            secondary_controls: SecondaryControls::UNRESTRICTED_GUEST.bits(),
+            cr0_fixed0: 0x8000_0021,
+            cr0_fixed1: 0xffff_ffff,
+            cr4_fixed0: 0x2000,
+            cr4_fixed1: 0x3727ff,
+            physical_address_width: 39,
+            linear_address_width: 48,
+            cr0: 0x8005_0033,
+            cr3: 0x1a_d000,
+            cr4: 0x2526f8,
+            rip: 0xffff_f800_1234_5678,
+            rflags: 0x246,
+            pat: 0x0007_0106_0007_0106,
+            sysenter_eip: 0xffff_f800_0000_1000,
+            es: data,
+            cs: Segment {
+                selector: 0x10,
+                base: 0,
+                limit: 0,
+                access_rights: 0x209b,
+            },
+            ss: Segment {
+                selector: 0x18,
+                base: 0,
+                limit: 0,
+                access_rights: 0x4093,
+            },
+            ds: data,
+            fs: Segment {
+                selector: 0x53,
+                limit: 0x3c00,
+                access_rights: 0x40f3,
+                ..data
+            },
+            gs: data,
+            ldtr: Segment {
+                access_rights: 0x1_0000,
+                ..Default::default()
+            },
+            tr: Segment {
+                selector: 0x40,
+                base: 0xffff_f800_1000_0000,
+                limit: 0x67,
+                access_rights: 0x8b,
+            },
+            gdtr_base: 0xffff_f800_2000_0000,
+            gdtr_limit: 0x57,
+            idtr_base: 0xffff_f800_3000_0000,
+            idtr_limit: 0xfff,
+            link_pointer: u64::MAX,
+            ..Default::default()
+        }
+    }
+
+    fn violated_rules(state: &GuestState) -> Vec<(&'static str, &'static str)> {
+        check(state)
+            .into_iter()
+            .map(|violation| (violation.field, violation.rule))
+            .collect()
+    }
+
+    #[test]
+    fn valid_state_passes() {
+        assert_eq!(check(&valid_state()), []);
+    }
+
+    #[test]
+    fn violations_are_reported() {
+        let mut state = valid_state();
+        state.cr0 &= !CR0_PE;
+        state.tr.selector |= 1 << 2;
+        state.rflags &= !(1 << 1);
+        assert_eq!(
+            violated_rules(&state),
+            [
+                ("CR0", "PE must be 1 if PG is 1"),
+                ("TR selector", "TI must be 0"),
+                ("RFLAGS", "reserved bits must be 0, and bit 1 must be 1"),
+            ]
+        );
+
+        let mut state = valid_state();
+        state.cs.access_rights = 0x20b3;
+        state.gdtr_base = 0x8000_0000_0000;
+        assert_eq!(
+            violated_rules(&state),
+            [
+                ("CS", "DPL must be 0 for type 3"),
+                ("GDTR", "base must be canonical"),
+            ]
+        );
+    }
+
+    #[test]
+    fn restrictions_apply_without_unrestricted_guest() {
+        let mut state = valid_state();
+        state.secondary_controls = 0;
+        state.ds.access_rights = 0xc093;
+        assert_eq!(
+            violated_rules(&state),
+            [("DS", "DPL must not be less than the RPL of the selector")]
+        );
+    }
+}
@@ -1,80 +1,924 @@
-use core::ptr::addr_of;
+use core::{
+    alloc::Layout,
+    arch::asm,
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use x86::bits64::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+use alloc::{
+    alloc::handle_alloc_error,
+    boxed::Box,
+    collections::{btree_map, BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use bit_field::BitField;
+use num_traits::FromPrimitive;
+use x86::bits64::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE};
 
-use crate::{hypervisor::intel::mtrr::MemoryType, hypervisor::platform_ops};
+use crate::hypervisor::{
+    dirty_tracking::DirtyBitmap,
+    ept_hook::HookManager,
+    hidden_memory,
+    host::GpaMapping,
+    memory_protection::{Permissions, Protections},
+    mtrr::{MemoryType, Mtrr},
+    platform_ops,
+    support::{try_zeroed_box, zeroed_box, Page},
+    x86_instructions::rdmsr,
+    HvError,
+};
 
-use super::mtrr::Mtrr;
-
-#[repr(C, align(4096))]
+/// The EPT paging structures with the EPT PDs and PTs allocated on demand to
+/// split 1GB and 2MB pages, and the hooks applied onto them.
 pub(crate) struct Epts {
-    pml4: Pml4,
-    pdpt: Pdpt,
-    pd: [Pd; 512],
-    pt: Pt,
+    ptr: Box<EptsRaw>,
+
+    /// The EPT PDs for split 1GB pages, keyed by the PDPT index.
+    pds: BTreeMap<usize, Box<Pd>>,
+
+    /// The EPT PTs for split 2MB pages, keyed by the GPA of the 2MB pages.
+    pts: BTreeMap<u64, Box<Pt>>,
+
+    /// The hooks applied onto the EPT, as a map of the GPA of an original page
+    /// to the PA of its shadow page.
+    hooks: BTreeMap<u64, u64>,
+
+    /// The protections applied onto the EPT, as a map of the GPA of a page to
+    /// its permissions.
+    protections: BTreeMap<u64, Permissions>,
+
+    /// The zeroed page the hidden pages are mapped to.
+    dummy_page: Box<Page>,
+
+    /// Whether the accessed and dirty flags are enabled.
+    access_dirty: bool,
+
+    /// The EPT views other than the default one, indexed by the view number
+    /// minus one.
+    views: Vec<EptView>,
+
+    /// The GPAs of the pages where violations are convertible to
+    /// virtualization exceptions.
+    convertible: BTreeSet<u64>,
 }
 
 impl Epts {
-    pub(crate) fn build_identify(&mut self) {
-        let mtrr = Mtrr::new();
-        log::trace!("{mtrr:#x?}");
+    pub(crate) fn new() -> Result<Self, HvError> {
+        Ok(Self {
+            ptr: try_zeroed_box::<EptsRaw>()?,
+            pds: BTreeMap::new(),
+            pts: BTreeMap::new(),
+            hooks: BTreeMap::new(),
+            protections: BTreeMap::new(),
+            dummy_page: try_zeroed_box::<Page>()?,
+            access_dirty: false,
+            views: Vec::new(),
+            convertible: BTreeSet::new(),
+        })
+    }
+
+    /// Builds the identity map of the first 512GB with the memory types per
+    /// the current MTRRs. See `update_memory_types`.
+    pub(crate) fn build_identify(&mut self) -> Result<(), HvError> {
         log::trace!("Initializing EPTs");
 
-        let ops = platform_ops::get();
+        // "Bit 17 (...) If this bit is 1, the logical processor allows software
+        //  to configure a EPT PDPTE to map a 1-Gbyte page"
+        // See: A.10 VPID AND EPT CAPABILITIES
+        let page_1gb = rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP).get_bit(17);
 
-        let mut pa = 0u64;
+        let ops = platform_ops::get();
+        let pml4e = &mut self.ptr.pml4.0.entries[0];
+        pml4e.set_readable(true);
+        pml4e.set_writable(true);
+        pml4e.set_executable(true);
+        pml4e.set_pfn(ops.pa(addr_of!(self.ptr.pdpt) as _) >> BASE_PAGE_SHIFT);
 
-        self.pml4.0.entries[0].set_readable(true);
-        self.pml4.0.entries[0].set_writable(true);
-        self.pml4.0.entries[0].set_executable(true);
-        self.pml4.0.entries[0].set_pfn(ops.pa(addr_of!(self.pdpt) as _) >> BASE_PAGE_SHIFT);
-        for (i, pdpte) in self.pdpt.0.entries.iter_mut().enumerate() {
+        // Map everything with 1GB pages, and split them into 2MB pages right
+        // away if the processor does not support 1GB pages. The memory types
+        // are filled and the pages are split further as needed below.
+        for pdpt_index in 0..self.ptr.pdpt.0.entries.len() {
+            let pdpte = &mut self.ptr.pdpt.0.entries[pdpt_index];
             pdpte.set_readable(true);
             pdpte.set_writable(true);
             pdpte.set_executable(true);
-            pdpte.set_pfn(ops.pa(addr_of!(self.pd[i]) as _) >> BASE_PAGE_SHIFT);
-            for pde in &mut self.pd[i].0.entries {
-                if pa == 0 {
-                    // First 2MB is managed by 4KB EPT PTs so MTRR memory types
-                    // are properly reflected into the EPT memory memory types.
-                    pde.set_readable(true);
-                    pde.set_writable(true);
-                    pde.set_executable(true);
-                    pde.set_pfn(ops.pa(addr_of!(self.pt) as _) >> BASE_PAGE_SHIFT);
-                    for pte in &mut self.pt.0.entries {
-                        let memory_type =
-                            mtrr.find(pa..pa + BASE_PAGE_SIZE as u64)
-                                .unwrap_or_else(|| {
-                                    panic!("Could not resolve a memory type for {pa:#x?}")
-                                });
-                        pte.set_readable(true);
-                        pte.set_writable(true);
-                        pte.set_executable(true);
-                        pte.set_memory_type(memory_type as u64);
-                        pte.set_pfn(pa >> BASE_PAGE_SHIFT);
-                        pa += BASE_PAGE_SIZE as u64;
+            pdpte.set_large(true);
+            pdpte.set_pfn((pdpt_index * HUGE_PAGE_SIZE) as u64 >> BASE_PAGE_SHIFT);
+
+            // Violations are not converted to virtualization exceptions unless
+            // requested with `apply_convertible_pages`.
+            pdpte.set_suppress_ve(true);
+            if !page_1gb {
+                let _ = try_pd(&mut self.ptr.pdpt, &mut self.pds, pdpt_index)?;
+            }
+        }
+
+        self.update_memory_types(&Mtrr::new());
+        Ok(())
+    }
+
+    /// Updates the memory types of the identity map to the ones `mtrr`
+    /// specifies. Each page takes the memory type of its range, and is split
+    /// into smaller pages if the range has multiple memory types. Pages once
+    /// split are not merged back. The caller must invalidate cached EPT
+    /// translations with the INVEPT instruction.
+    ///
+    /// The ignore PAT memory type bits are left clear, so the effective memory
+    /// type of each access is the combination of this and the guest PAT, as it
+    /// would be with the MTRRs without EPT.
+    // See: 29.3.7.2 Memory Type Used for Translated Guest-Physical Addresses
+    pub(crate) fn update_memory_types(&mut self, mtrr: &Mtrr) {
+        log::trace!("{mtrr:#x?}");
+
+        let find = |pa: u64, size: usize| mtrr.find(pa..pa + size as u64);
+        for pdpt_index in 0..self.ptr.pdpt.0.entries.len() {
+            let pa = (pdpt_index * HUGE_PAGE_SIZE) as u64;
+            let pdpte = &mut self.ptr.pdpt.0.entries[pdpt_index];
+            if pdpte.large() {
+                if let Some(memory_type) = find(pa, HUGE_PAGE_SIZE) {
+                    set_memory_type(pdpte, memory_type);
+                    continue;
+                }
+            }
+
+            for pd_index in 0..512 {
+                let pa = pa + (pd_index * LARGE_PAGE_SIZE) as u64;
+                let pde =
+                    &mut pd(&mut self.ptr.pdpt, &mut self.pds, pdpt_index).0.entries[pd_index];
+                if pde.large() {
+                    if let Some(memory_type) = find(pa, LARGE_PAGE_SIZE) {
+                        set_memory_type(pde, memory_type);
+                        continue;
                     }
-                } else {
-                    // For the rest of GPAes, manage them with 2MB large page EPTs.
-                    // We assume MTRR memory types are configured for 2MB or greater
-                    // granularity.
-                    let memory_type = mtrr
-                        .find(pa..pa + LARGE_PAGE_SIZE as u64)
+                }
+
+                for (pt_index, pte) in self.pt(pa).0.entries.iter_mut().enumerate() {
+                    let pa = pa + (pt_index * BASE_PAGE_SIZE) as u64;
+                    let memory_type = find(pa, BASE_PAGE_SIZE)
                         .unwrap_or_else(|| panic!("Could not resolve a memory type for {pa:#x?}"));
-                    pde.set_readable(true);
-                    pde.set_writable(true);
-                    pde.set_executable(true);
-                    pde.set_memory_type(memory_type as u64);
-                    pde.set_large(true);
-                    pde.set_pfn(pa >> BASE_PAGE_SHIFT);
-                    pa += LARGE_PAGE_SIZE as u64;
+                    set_memory_type(pte, memory_type);
                 }
             }
         }
+
+        let pages: Vec<u64> = self
+            .views
+            .iter()
+            .flat_map(|view| view.pages.keys().copied())
+            .collect();
+        for gpa in pages {
+            self.refresh_views(gpa);
+        }
     }
 
     /// Returns an EPT pointer for this EPT.
     pub(crate) fn eptp(&self) -> EptPointer {
+        let mut eptp = self.ptr.eptp();
+        eptp.set_enable_access_dirty(self.access_dirty);
+        eptp
+    }
+
+    /// Enables or disables the accessed and dirty flags in the EPTP returned by
+    /// `eptp`. The caller must update the EPTP of each processor.
+    pub(crate) fn set_access_dirty(&mut self, enable: bool) {
+        self.access_dirty = enable;
+    }
+
+    /// Returns the EPTP of the view that `eptp`, an EPTP of this EPT or any of
+    /// its views, points to, with the current flags.
+    pub(crate) fn eptp_of_view(&self, eptp: u64) -> EptPointer {
+        self.view_eptp(self.view_index(eptp)).unwrap()
+    }
+
+    /// Returns the EPTP of `view` with the current flags, or `None` if the view
+    /// does not exist. The step views are not numbered, and cannot be got.
+    pub(crate) fn view_eptp(&self, view: usize) -> Option<EptPointer> {
+        let mut view_eptp = match view {
+            0 => self.ptr.eptp(),
+            view => self.views.get(view - 1)?.ptr.eptp(),
+        };
+        view_eptp.set_enable_access_dirty(self.access_dirty);
+        Some(view_eptp)
+    }
+
+    /// Returns whether any view other than the default one is created.
+    pub(crate) fn has_views(&self) -> bool {
+        !self.views.is_empty()
+    }
+
+    /// Returns the number of the view that `eptp`, an EPTP of this EPT or any
+    /// of its views, points to.
+    pub(crate) fn view_index(&self, eptp: u64) -> usize {
+        let eptp = EptPointer(eptp);
+        self.views
+            .iter()
+            .position(|view| view.ptr.eptp().pfn() == eptp.pfn())
+            .map_or(0, |index| index + 1)
+    }
+
+    /// Invalidates cached translations derived from this EPT on the current
+    /// processor, including the ones derived from the views.
+    pub(crate) fn invalidate(&self) {
+        let invalidation = if self.views.is_empty() {
+            InveptType::SingleContext
+        } else {
+            InveptType::AllContext
+        };
+        invept(invalidation, self.eptp());
+    }
+
+    /// Updates the EPT views to reflect the views in `hook_manager`. The caller
+    /// must invalidate cached EPT translations with `invalidate`.
+    pub(crate) fn apply_views(&mut self, hook_manager: &HookManager) {
+        for (index, pages) in hook_manager.views().enumerate() {
+            if index == self.views.len() {
+                let view = EptView::new(&self.ptr);
+                self.views.push(view);
+            }
+            for (&gpa, &page) in pages {
+                if self.views[index].pages.insert(gpa, page) != Some(page) {
+                    // Split the page in the default view first, so that the view
+                    // can copy the PT.
+                    let _ = self.pt(gpa);
+                    self.refresh_view(index, gpa);
+                }
+            }
+        }
+    }
+
+    /// Reflects the mapping of `gpa` in the default view into the other views.
+    /// Must be called after updating the mapping.
+    fn refresh_views(&mut self, gpa: u64) {
+        for index in 0..self.views.len() {
+            self.refresh_view(index, gpa);
+        }
+    }
+
+    /// Makes the view at `index` map the 2MB region containing `gpa` as the
+    /// default view does, except the pages set in the view.
+    //
+    // The view shares the EPT PDs and PTs of the default view, except where the
+    // pages set in the view are. For such regions, the view has copies of them
+    // to be updated from the default view.
+    fn refresh_view(&mut self, index: usize, gpa: u64) {
+        let ops = platform_ops::get();
+        let pdpt_index = gpa.get_bits(30..=38) as usize; // [38:30]
+        let pd_index = gpa.get_bits(21..=29) as usize; // [29:21]
+        let region = gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+        let view = &mut self.views[index];
+        let has_pages = view
+            .pages
+            .range(region..region + LARGE_PAGE_SIZE as u64)
+            .next()
+            .is_some();
+        if !has_pages && !view.pds.contains_key(&pdpt_index) {
+            view.ptr.pdpt.0.entries[pdpt_index] = self.ptr.pdpt.0.entries[pdpt_index];
+            return;
+        }
+
+        // Copy the PD, keeping the PDEs pointing to the copied PTs. Make it fully
+        // initialized before linking, as other processors may be walking this
+        // EPT.
+        let primary_pd = &self.pds[&pdpt_index];
+        let view_pd = view.pds.entry(pdpt_index).or_insert_with(zeroed_box::<Pd>);
+        for (i, pde) in view_pd.0.entries.iter_mut().enumerate() {
+            let region = (gpa & !(HUGE_PAGE_SIZE as u64 - 1)) + (i * LARGE_PAGE_SIZE) as u64;
+            if !view.pts.contains_key(&region) {
+                *pde = primary_pd.0.entries[i];
+            }
+        }
+        let mut new_pdpte = self.ptr.pdpt.0.entries[pdpt_index];
+        new_pdpte.set_pfn(ops.pa(view_pd.as_ref() as *const _ as _) >> BASE_PAGE_SHIFT);
+        view.ptr.pdpt.0.entries[pdpt_index] = new_pdpte;
+        if !has_pages {
+            return;
+        }
+
+        // Copy the PT, then map the pages set in the view.
+        let primary_pt = &self.pts[&region];
+        let view_pt = view.pts.entry(region).or_insert_with(zeroed_box::<Pt>);
+        view_pt.0.entries = primary_pt.0.entries;
+        for (&gpa, &(pa, permissions)) in view.pages.range(region..region + LARGE_PAGE_SIZE as u64)
+        {
+            let pte = &mut view_pt.0.entries[gpa.get_bits(12..=20) as usize];
+            let mut new_pte = *pte;
+            new_pte.set_pfn(pa >> BASE_PAGE_SHIFT);
+            set_permissions(&mut new_pte, permissions);
+            *pte = new_pte;
+        }
+        let mut new_pde = primary_pd.0.entries[pd_index];
+        new_pde.set_pfn(ops.pa(view_pt.as_ref() as *const _ as _) >> BASE_PAGE_SHIFT);
+        view_pd.0.entries[pd_index] = new_pde;
+    }
+
+    /// Adds the pages mapped with the dirty flag set to `bitmap`, and clears
+    /// the flags. The caller must invalidate cached EPT translations with the
+    /// INVEPT instruction.
+    pub(crate) fn harvest_dirty(&mut self, bitmap: &mut DirtyBitmap) {
+        for pdpt_index in 0..self.ptr.pdpt.0.entries.len() {
+            let pa = (pdpt_index * HUGE_PAGE_SIZE) as u64;
+            let pdpte = &mut self.ptr.pdpt.0.entries[pdpt_index];
+            if pdpte.large() {
+                if take_dirty(pdpte) {
+                    bitmap.insert(pa, HUGE_PAGE_SIZE as u64);
+                }
+                continue;
+            }
+
+            let pd = self.pds.get_mut(&pdpt_index).unwrap();
+            for (pd_index, pde) in pd.0.entries.iter_mut().enumerate() {
+                let pa = pa + (pd_index * LARGE_PAGE_SIZE) as u64;
+                if pde.large() {
+                    if take_dirty(pde) {
+                        bitmap.insert(pa, LARGE_PAGE_SIZE as u64);
+                    }
+                    continue;
+                }
+
+                let pt = self.pts.get_mut(&pa).unwrap();
+                for (pt_index, pte) in pt.0.entries.iter_mut().enumerate() {
+                    if take_dirty(pte) {
+                        bitmap.insert(
+                            pa + (pt_index * BASE_PAGE_SIZE) as u64,
+                            BASE_PAGE_SIZE as u64,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Updates the EPT to reflect hooks in `hook_manager`. The caller must
+    /// invalidate cached EPT translations with the INVEPT instruction.
+    pub(crate) fn apply_hooks(&mut self, hook_manager: &HookManager) {
+        // Restore the identity mapping of the pages no longer hooked.
+        let removed: Vec<u64> = self
+            .hooks
+            .keys()
+            .copied()
+            .filter(|&gpa| !hook_manager.hooks().any(|(pa, _)| pa == gpa))
+            .collect();
+        for gpa in removed {
+            let _ = self.hooks.remove(&gpa);
+            let permissions = self.permissions(gpa);
+            let pte = self.pte(gpa);
+            let mut new_pte = *pte;
+            new_pte.set_pfn(gpa >> BASE_PAGE_SHIFT);
+            set_permissions(&mut new_pte, permissions);
+            *pte = new_pte;
+            self.refresh_views(gpa);
+        }
+
+        // Map the shadow pages for execution for the newly hooked pages.
+        for (gpa, shadow_pa) in hook_manager.hooks() {
+            if self.hooks.insert(gpa, shadow_pa) != Some(shadow_pa) {
+                self.map_shadow_page(gpa);
+            }
+        }
+    }
+
+    /// Updates the EPT to reflect `protections`. The caller must invalidate
+    /// cached EPT translations with the INVEPT instruction.
+    pub(crate) fn apply_protections(&mut self, protections: &Protections) {
+        let pages = protections.pages();
+        let removed: Vec<u64> = self
+            .protections
+            .keys()
+            .copied()
+            .filter(|gpa| !pages.contains_key(gpa))
+            .collect();
+        let changed: Vec<(u64, Permissions)> = pages
+            .iter()
+            .map(|(&gpa, &permissions)| (gpa, permissions))
+            .filter(|(gpa, permissions)| self.protections.get(gpa) != Some(permissions))
+            .collect();
+        self.protections = pages;
+
+        // Hooked pages are left as they are. Their protection is applied once
+        // they are unhooked.
+        for gpa in removed
+            .into_iter()
+            .chain(changed.into_iter().map(|(gpa, _)| gpa))
+        {
+            if !self.is_hooked(gpa) {
+                self.restore_protection(gpa);
+            }
+        }
+    }
+
+    /// Maps the page containing `gpa` with the permissions of its protection.
+    pub(crate) fn restore_protection(&mut self, gpa: u64) {
+        if hidden_memory::is_hidden(gpa) {
+            return;
+        }
+        let permissions = self.permissions(gpa);
+        let pte = self.pte(gpa);
+        let mut new_pte = *pte;
+        set_permissions(&mut new_pte, permissions);
+        *pte = new_pte;
+        self.refresh_views(gpa);
+    }
+
+    /// Maps the pages at `gpas` to the zeroed dummy page as read-only, so that
+    /// the guest can neither observe their contents nor write to the dummy page.
+    /// The protections of the pages are no longer applied. The caller must
+    /// invalidate cached EPT translations with the INVEPT instruction.
+    pub(crate) fn hide(&mut self, gpas: &[u64]) {
+        let dummy_pa = platform_ops::get().pa(self.dummy_page.as_ref() as *const _ as _);
+        for &gpa in gpas {
+            let pte = self.pte(gpa);
+            let mut new_pte = *pte;
+            set_permissions(&mut new_pte, Permissions::READ_ONLY);
+            new_pte.set_pfn(dummy_pa >> BASE_PAGE_SHIFT);
+            *pte = new_pte;
+            self.refresh_views(gpa);
+        }
+    }
+
+    /// Updates the EPT to make violations on `pages` convertible to
+    /// virtualization exceptions, and the others not. The caller must
+    /// invalidate cached EPT translations with `invalidate`.
+    pub(crate) fn apply_convertible_pages(&mut self, pages: &BTreeSet<u64>) {
+        let changed: Vec<(u64, bool)> = self
+            .convertible
+            .difference(pages)
+            .map(|&gpa| (gpa, false))
+            .chain(pages.difference(&self.convertible).map(|&gpa| (gpa, true)))
+            .collect();
+        self.convertible = pages.clone();
+
+        for (gpa, convertible) in changed {
+            let pte = self.pte(gpa);
+            let mut new_pte = *pte;
+            new_pte.set_suppress_ve(!convertible);
+            *pte = new_pte;
+            self.refresh_views(gpa);
+        }
+    }
+
+    /// Returns the permissions of the page containing `gpa` per the protection
+    /// applied onto the EPT.
+    fn permissions(&self, gpa: u64) -> Permissions {
+        let gpa = gpa & !(BASE_PAGE_SIZE as u64 - 1);
+        self.protections
+            .get(&gpa)
+            .copied()
+            .unwrap_or(Permissions::ALL)
+    }
+
+    /// Returns the mapping of the page containing `gpa` if the EPT maps it to
+    /// the same PA. Hooked pages are reported as their original pages, which
+    /// remain at the same PA. See `Vcpu::resolve_gpa`.
+    pub(crate) fn resolve(&self, gpa: u64) -> Option<GpaMapping> {
+        let page = gpa & !(BASE_PAGE_SIZE as u64 - 1);
+        let (entry, size) = self.entry(gpa)?;
+        let ram = MemoryType::from_u64(entry.memory_type()) == Some(MemoryType::WriteBack);
+        if self.is_hooked(page) {
+            return Some(GpaMapping {
+                permissions: self.permissions(page),
+                ram,
+            });
+        }
+        let pa = (entry.pfn() << BASE_PAGE_SHIFT) + (gpa & (size as u64 - 1));
+        if pa & !(BASE_PAGE_SIZE as u64 - 1) != page {
+            return None;
+        }
+        Some(GpaMapping {
+            permissions: Permissions {
+                read: entry.readable(),
+                write: entry.writable(),
+                execute: entry.executable(),
+            },
+            ram,
+        })
+    }
+
+    /// Returns the entry of the default view mapping the page containing `gpa`
+    /// as is, that is, the shadow page for a hooked page, for nested
+    /// virtualization. See `shadow_ept`.
+    pub(crate) fn leaf(&self, gpa: u64) -> Option<EptLeaf> {
+        let (entry, size) = self.entry(gpa)?;
+        Some(EptLeaf {
+            pa: (entry.pfn() << BASE_PAGE_SHIFT) + (gpa & (size as u64 - 1)),
+            permissions: Permissions {
+                read: entry.readable(),
+                write: entry.writable(),
+                execute: entry.executable(),
+            },
+            memory_type: entry.memory_type(),
+        })
+    }
+
+    /// Sets the accessed and dirty flags of the entry mapping the page
+    /// containing `gpa`, for the write to it the processor made through
+    /// another EPT, which has the flags set instead. The flags are set
+    /// atomically, as other processors may set them concurrently.
+    pub(crate) fn mark_dirty(&mut self, gpa: u64) {
+        const EPT_ACCESSED_DIRTY: u64 = 0b11 << 8;
+
+        if gpa.get_bits(39..=63) != 0 {
+            return;
+        }
+        let pdpt_index = gpa.get_bits(30..=38) as usize; // [38:30]
+        let pd_index = gpa.get_bits(21..=29) as usize; // [29:21]
+        let pt_index = gpa.get_bits(12..=20) as usize; // [20:12]
+        let entry = match self.pds.get_mut(&pdpt_index) {
+            None => &mut self.ptr.pdpt.0.entries[pdpt_index],
+            Some(pd) => match self.pts.get_mut(&(gpa & !(LARGE_PAGE_SIZE as u64 - 1))) {
+                None => &mut pd.0.entries[pd_index],
+                Some(pt) => &mut pt.0.entries[pt_index],
+            },
+        };
+
+        // Safety: the pointer is valid and aligned for `u64`, and the entry is
+        // only accessed atomically while borrowed.
+        let entry = unsafe { AtomicU64::from_ptr(addr_of_mut!(entry.0)) };
+        let _ = entry.fetch_or(EPT_ACCESSED_DIRTY, Ordering::Relaxed);
+    }
+
+    /// Returns the entry mapping the page containing `gpa` and the size of the
+    /// page, or `None` if the EPT does not map `gpa`.
+    fn entry(&self, gpa: u64) -> Option<(Entry, usize)> {
+        if gpa.get_bits(39..=63) != 0 {
+            return None;
+        }
+
+        let pdpt_index = gpa.get_bits(30..=38) as usize; // [38:30]
+        let pd_index = gpa.get_bits(21..=29) as usize; // [29:21]
+        let pt_index = gpa.get_bits(12..=20) as usize; // [20:12]
+        Some(match self.pds.get(&pdpt_index) {
+            None => (self.ptr.pdpt.0.entries[pdpt_index], HUGE_PAGE_SIZE),
+            Some(pd) => match self.pts.get(&(gpa & !(LARGE_PAGE_SIZE as u64 - 1))) {
+                None => (pd.0.entries[pd_index], LARGE_PAGE_SIZE),
+                Some(pt) => (pt.0.entries[pt_index], BASE_PAGE_SIZE),
+            },
+        })
+    }
+
+    /// Returns whether `gpa` is in a hooked page.
+    pub(crate) fn is_hooked(&self, gpa: u64) -> bool {
+        self.hooks
+            .contains_key(&(gpa & !(BASE_PAGE_SIZE as u64 - 1)))
+    }
+
+    /// Maps the shadow page of the hooked page containing `gpa` as execute-only.
+    /// Data accesses to the page cause EPT violations, and are completed with
+    /// the original page mapped in the step view of the processor. See
+    /// `map_in_step_view`.
+    fn map_shadow_page(&mut self, gpa: u64) {
+        let gpa = gpa & !(BASE_PAGE_SIZE as u64 - 1);
+        let shadow_pa = self.hooks[&gpa];
+
+        // "Bit 0 (...) If this bit is 1, the processor supports execute-only
+        //  translations by EPT."
+        // See: A.10 VPID AND EPT CAPABILITIES
+        //
+        // Without the support, the shadow page has to be readable too. Reads
+        // then observe the patched contents, but the hook still functions.
+        let execute_only = rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP).get_bit(0);
+        let pte = self.pte(gpa);
+        let mut new_pte = *pte;
+        new_pte.set_readable(!execute_only);
+        new_pte.set_writable(false);
+        new_pte.set_executable(true);
+        new_pte.set_pfn(shadow_pa >> BASE_PAGE_SHIFT);
+        *pte = new_pte;
+        self.refresh_views(gpa);
+    }
+
+    /// Returns the PA of the shadow page of the hooked page containing `gpa`.
+    pub(crate) fn shadow_pa(&self, gpa: u64) -> Option<u64> {
+        self.hooks
+            .get(&(gpa & !(BASE_PAGE_SIZE as u64 - 1)))
+            .copied()
+    }
+
+    /// Maps the page containing `gpa` to `pa` with `permissions` in `view`,
+    /// which maps the other pages as the default view does, and returns the
+    /// EPTP of `view`. The pages mapped earlier are kept until `view` is
+    /// cleared with `StepView::clear`.
+    pub(crate) fn map_in_step_view(
+        &self,
+        view: &mut StepView,
+        gpa: u64,
+        pa: u64,
+        permissions: Permissions,
+    ) -> EptPointer {
+        let ops = platform_ops::get();
+        if !view.active {
+            let mut pml4e = self.ptr.pml4.0.entries[0];
+            pml4e.set_pfn(ops.pa(addr_of!(view.ptr.pdpt) as _) >> BASE_PAGE_SHIFT);
+            view.ptr.pml4.0.entries[0] = pml4e;
+            view.ptr.pdpt.0.entries = self.ptr.pdpt.0.entries;
+            view.active = true;
+        }
+
+        // Copy the EPT PD and PT for the page from the default view, or split
+        // the copies of the 1GB and 2MB pages. Make them fully initialized
+        // before linking, as the processor may walk them speculatively.
+        let pdpt_index = gpa.get_bits(30..=38) as usize; // [38:30]
+        let pd_index = gpa.get_bits(21..=29) as usize; // [29:21]
+        let region = gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+        let pdpte = &mut view.ptr.pdpt.0.entries[pdpt_index];
+        let pd = view.pds.entry(pdpt_index).or_insert_with(|| {
+            let mut pd = zeroed_box::<Pd>();
+            match self.pds.get(&pdpt_index) {
+                Some(primary) => {
+                    pd.0.entries = primary.0.entries;
+                    let mut new_pdpte = *pdpte;
+                    new_pdpte.set_pfn(ops.pa(pd.as_ref() as *const _ as _) >> BASE_PAGE_SHIFT);
+                    *pdpte = new_pdpte;
+                }
+                None => split_1gb(pdpte, &mut pd),
+            }
+            pd
+        });
+        let pde = &mut pd.0.entries[pd_index];
+        let pt = view.pts.entry(region).or_insert_with(|| {
+            let mut pt = zeroed_box::<Pt>();
+            match self.pts.get(&region) {
+                Some(primary) => {
+                    pt.0.entries = primary.0.entries;
+                    let mut new_pde = *pde;
+                    new_pde.set_pfn(ops.pa(pt.as_ref() as *const _ as _) >> BASE_PAGE_SHIFT);
+                    *pde = new_pde;
+                }
+                None => split_2mb(pde, &mut pt),
+            }
+            pt
+        });
+
+        let pte = &mut pt.0.entries[gpa.get_bits(12..=20) as usize];
+        let mut new_pte = *pte;
+        new_pte.set_pfn(pa >> BASE_PAGE_SHIFT);
+        set_permissions(&mut new_pte, permissions);
+        *pte = new_pte;
+
+        // Only this processor uses the view. Invalidate the translations cached
+        // for the previous step, if any, and for this mapping.
+        let mut eptp = view.ptr.eptp();
+        eptp.set_enable_access_dirty(self.access_dirty);
+        invept(InveptType::SingleContext, eptp);
+        eptp
+    }
+
+    /// Returns the EPT PTE for `gpa`, splitting the EPT PDPTE and PDE for it if
+    /// they map a 1GB or 2MB page. The PTE should be updated at once, as other
+    /// processors may be walking the EPT.
+    fn pte(&mut self, gpa: u64) -> &mut Entry {
+        let pt_index = gpa.get_bits(12..=20) as usize; // [20:12]
+        &mut self.pt(gpa).0.entries[pt_index]
+    }
+
+    /// Returns the EPT PT for the 2MB region containing `gpa`, splitting the
+    /// pages mapping the region into 4KB pages if needed.
+    fn pt(&mut self, gpa: u64) -> &mut Pt {
+        let pdpt_index = gpa.get_bits(30..=38) as usize; // [38:30]
+        let pd_index = gpa.get_bits(21..=29) as usize; // [29:21]
+        assert!(gpa.get_bits(39..=47) == 0, "{gpa:#x?} is not mapped");
+
+        let pde = &mut pd(&mut self.ptr.pdpt, &mut self.pds, pdpt_index).0.entries[pd_index];
+        let large_page_gpa = gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+        self.pts.entry(large_page_gpa).or_insert_with(|| {
+            let mut pt = zeroed_box::<Pt>();
+            split_2mb(pde, &mut pt);
+            pt
+        })
+    }
+}
+
+/// Returns the EPT PD for the 1GB region at `pdpt_index`, splitting the page
+/// mapping the region into 2MB pages if needed.
+fn pd<'a>(pdpt: &mut Pdpt, pds: &'a mut BTreeMap<usize, Box<Pd>>, pdpt_index: usize) -> &'a mut Pd {
+    try_pd(pdpt, pds, pdpt_index).unwrap_or_else(|_| handle_alloc_error(Layout::new::<Pd>()))
+}
+
+/// Returns the EPT PD like `pd`, or `OutOfMemory` if the heap is exhausted.
+fn try_pd<'a>(
+    pdpt: &mut Pdpt,
+    pds: &'a mut BTreeMap<usize, Box<Pd>>,
+    pdpt_index: usize,
+) -> Result<&'a mut Pd, HvError> {
+    let pdpte = &mut pdpt.0.entries[pdpt_index];
+    match pds.entry(pdpt_index) {
+        btree_map::Entry::Occupied(entry) => Ok(entry.into_mut()),
+        btree_map::Entry::Vacant(entry) => {
+            let mut pd = try_zeroed_box::<Pd>()?;
+            split_1gb(pdpte, &mut pd);
+            Ok(entry.insert(pd))
+        }
+    }
+}
+
+/// Clears the dirty flag of `entry` mapping a page, and returns whether it was
+/// set. The flag is cleared atomically, as other processors may set the flags
+/// of the entry concurrently.
+fn take_dirty(entry: &mut Entry) -> bool {
+    const EPT_DIRTY: u64 = 1 << 9;
+
+    // Safety: the pointer is valid and aligned for `u64`, and the entry is
+    // only accessed atomically while borrowed.
+    let entry = unsafe { AtomicU64::from_ptr(addr_of_mut!(entry.0)) };
+    entry.fetch_and(!EPT_DIRTY, Ordering::Relaxed) & EPT_DIRTY != 0
+}
+
+/// Updates the permissions of `entry` to `permissions`.
+fn set_permissions(entry: &mut Entry, permissions: Permissions) {
+    entry.set_readable(permissions.read);
+    entry.set_writable(permissions.write);
+    entry.set_executable(permissions.execute);
+}
+
+/// Updates the memory type of `entry` mapping a page at once, as other
+/// processors may be walking the EPT.
+fn set_memory_type(entry: &mut Entry, memory_type: MemoryType) {
+    let mut new_entry = *entry;
+    new_entry.set_memory_type(memory_type as u64);
+    *entry = new_entry;
+}
+
+/// Updates the `pdpte` to point to `pd` to split the page from 1GB to 2MBs.
+fn split_1gb(pdpte: &mut Entry, pd: &mut Pd) {
+    assert!(pdpte.large());
+
+    let pages_per_2mb = (LARGE_PAGE_SIZE / BASE_PAGE_SIZE) as u64;
+    for (i, pde) in pd.0.entries.iter_mut().enumerate() {
+        pde.set_readable(pdpte.readable());
+        pde.set_writable(pdpte.writable());
+        pde.set_executable(pdpte.executable());
+        pde.set_memory_type(pdpte.memory_type());
+        pde.set_dirty(pdpte.dirty());
+        pde.set_suppress_ve(pdpte.suppress_ve());
+        pde.set_large(true);
+        pde.set_pfn(pdpte.pfn() + i as u64 * pages_per_2mb);
+    }
+
+    // The memory type field is reserved for EPT PDPTEs referencing EPT PDs.
+    // Update the PDPTE at once, as other processors may be walking the EPT.
+    // See: Table 29-3. Format of an EPT Page-Directory-Pointer-Table Entry
+    //      (PDPTE) that References an EPT Page Directory
+    let pd_pa = platform_ops::get().pa(pd as *mut _ as _);
+    let mut new_pdpte = *pdpte;
+    new_pdpte.set_memory_type(0);
+    new_pdpte.set_large(false);
+    new_pdpte.set_pfn(pd_pa >> BASE_PAGE_SHIFT);
+    *pdpte = new_pdpte;
+}
+
+/// Updates the `pde` to point to `pt` to split the page from 2MB to 4KBs.
+fn split_2mb(pde: &mut Entry, pt: &mut Pt) {
+    assert!(pde.large());
+
+    for (pfn, pte) in (pde.pfn()..).zip(pt.0.entries.iter_mut()) {
+        pte.set_readable(pde.readable());
+        pte.set_writable(pde.writable());
+        pte.set_executable(pde.executable());
+        pte.set_memory_type(pde.memory_type());
+        pte.set_dirty(pde.dirty());
+        pte.set_suppress_ve(pde.suppress_ve());
+        pte.set_pfn(pfn);
+    }
+
+    // The memory type field is reserved for EPT PDEs referencing EPT PTs.
+    // Update the PDE at once, as other processors may be walking the EPT.
+    // See: Table 29-5. Format of an EPT Page-Directory Entry (PDE) that
+    //      References an EPT Page Table
+    let pt_pa = platform_ops::get().pa(pt as *mut _ as _);
+    let mut new_pde = *pde;
+    new_pde.set_memory_type(0);
+    new_pde.set_large(false);
+    new_pde.set_pfn(pt_pa >> BASE_PAGE_SHIFT);
+    *pde = new_pde;
+}
+
+/// The EPT entry mapping a page, as returned by `Epts::leaf`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct EptLeaf {
+    /// The PA the GPA is mapped to.
+    pub(crate) pa: u64,
+    /// The permissions of the entry.
+    pub(crate) permissions: Permissions,
+    /// The EPT memory type of the entry.
+    pub(crate) memory_type: u64,
+}
+
+/// The types of the INVEPT instruction.
+///
+/// See: Table 31-1. INVEPT Descriptor
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum InveptType {
+    SingleContext = 1,
+    AllContext = 2,
+}
+
+/// The wrapper of the INVEPT instruction.
+///
+/// See: INVEPT - Invalidate Translations Derived from EPT
+pub(crate) fn invept(invalidation: InveptType, eptp: EptPointer) {
+    let descriptor = [eptp.0, 0u64];
+    let flags: u64;
+    unsafe {
+        asm!(
+            "invept {}, [{}]",
+            "pushfq",
+            "pop {}",
+            in(reg) invalidation as u64,
+            in(reg) &descriptor,
+            lateout(reg) flags,
+        );
+    };
+    assert!(flags & 0b100_0001 == 0, "INVEPT failed: {invalidation:?}");
+}
+
+/// An EPT view where select pages are mapped differently from the default view.
+/// See `ept_hook`.
+struct EptView {
+    ptr: Box<EptsRaw>,
+
+    /// The copies of the EPT PDs of the default view for the 1GB regions
+    /// containing the pages set in the view, keyed by the PDPT index.
+    pds: BTreeMap<usize, Box<Pd>>,
+
+    /// The copies of the EPT PTs of the default view for the 2MB regions
+    /// containing the pages set in the view, keyed by the GPA of the regions.
+    pts: BTreeMap<u64, Box<Pt>>,
+
+    /// The pages set in the view. See `HookManager::views`.
+    pages: BTreeMap<u64, (u64, Permissions)>,
+}
+
+impl EptView {
+    fn new(primary: &EptsRaw) -> Self {
+        let mut ptr = zeroed_box::<EptsRaw>();
+        ptr.pdpt.0.entries = primary.pdpt.0.entries;
+        let pdpt_pa = platform_ops::get().pa(addr_of!(ptr.pdpt) as _);
+        let mut pml4e = primary.pml4.0.entries[0];
+        pml4e.set_pfn(pdpt_pa >> BASE_PAGE_SHIFT);
+        ptr.pml4.0.entries[0] = pml4e;
+        Self {
+            ptr,
+            pds: BTreeMap::new(),
+            pts: BTreeMap::new(),
+            pages: BTreeMap::new(),
+        }
+    }
+}
+
+/// An EPT view private to a processor, where pages are mapped differently from
+/// the default view only while the processor single-steps an instruction, so
+/// that the other processors are not permitted the access meanwhile. Unlike
+/// `EptView`, the view is not kept in sync with the default view, but copies it
+/// on the first page mapped with `Epts::map_in_step_view` after `clear`.
+pub(crate) struct StepView {
+    ptr: Box<EptsRaw>,
+
+    /// The copies of the EPT PDs of the default view for the 1GB regions
+    /// containing the mapped pages, keyed by the PDPT index.
+    pds: BTreeMap<usize, Box<Pd>>,
+
+    /// The copies of the EPT PTs of the default view for the 2MB regions
+    /// containing the mapped pages, keyed by the GPA of the regions.
+    pts: BTreeMap<u64, Box<Pt>>,
+
+    /// Whether any page is mapped since the last `clear`.
+    active: bool,
+}
+
+impl StepView {
+    pub(crate) fn new() -> Result<Self, HvError> {
+        Ok(Self {
+            ptr: try_zeroed_box::<EptsRaw>()?,
+            pds: BTreeMap::new(),
+            pts: BTreeMap::new(),
+            active: false,
+        })
+    }
+
+    /// Unmaps the pages mapped with `Epts::map_in_step_view`. The processor
+    /// must not use the view until a page is mapped again.
+    pub(crate) fn clear(&mut self) {
+        self.pds.clear();
+        self.pts.clear();
+        self.active = false;
+    }
+
+    /// Returns whether the page containing `gpa` is mapped with `pa`.
+    pub(crate) fn maps(&self, gpa: u64, pa: u64) -> bool {
+        let region = gpa & !(LARGE_PAGE_SIZE as u64 - 1);
+        self.pts.get(&region).is_some_and(|pt| {
+            pt.0.entries[gpa.get_bits(12..=20) as usize].pfn() == pa >> BASE_PAGE_SHIFT
+        })
+    }
+}
+
+#[repr(C, align(4096))]
+struct EptsRaw {
+    pml4: Pml4,
+    pdpt: Pdpt,
+}
+
+impl EptsRaw {
+    fn eptp(&self) -> EptPointer {
         let mut eptp = EptPointer::default();
         let ept_pml4_pa = platform_ops::get().pa(addr_of!(*self) as *const _);
         eptp.set_pfn(ept_pml4_pa >> BASE_PAGE_SHIFT);
@@ -104,11 +948,11 @@ bitfield::bitfield! {
     #[derive(Clone, Copy, Default)]
     pub struct EptPointer(u64);
     impl Debug;
-    memory_type, set_memory_type: 2, 0;
-    page_levels_minus_one, set_page_levels_minus_one: 5, 3;
+    pub memory_type, set_memory_type: 2, 0;
+    pub page_levels_minus_one, set_page_levels_minus_one: 5, 3;
     enable_access_dirty, set_enable_access_dirty: 6;
     enable_sss, set_enable_sss: 7;
-    pfn, set_pfn: 51, 12;
+    pub pfn, set_pfn: 51, 12;
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -139,5 +983,124 @@ bitfield::bitfield! {
     executable, set_executable: 2;
     memory_type, set_memory_type: 5, 3;
     large, set_large: 7;
+    dirty, set_dirty: 9;
     pfn, set_pfn: 51, 12;
+    suppress_ve, set_suppress_ve: 63;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::{
+        intel::shadow_ept::{walk, Ept12Features, Ept12Walk},
+        test_support::{self, with_cpu},
+    };
+
+    const FEATURES: Ept12Features = Ept12Features {
+        execute_only: true,
+        page_1gb: true,
+        page_2mb: true,
+        pa_bits: 48,
+    };
+
+    /// Builds the identity map with the MTRRs specifying write-back for all
+    /// memory but UC for the 4KB page at 0xc000_5000.
+    fn build(page_1gb: bool) -> Epts {
+        const MTRR_ENABLE_WB: u64 = (1 << 11) | MemoryType::WriteBack as u64;
+        const PHYSMASK_4KB_VALID: u64 = 0xffff_ffff_f000 | (1 << 11);
+
+        test_support::init_platform_ops();
+        with_cpu(|cpu| {
+            cpu.wrmsr(x86::msr::IA32_VMX_EPT_VPID_CAP, u64::from(page_1gb) << 17);
+            cpu.wrmsr(x86::msr::IA32_MTRR_DEF_TYPE, MTRR_ENABLE_WB);
+            cpu.wrmsr(x86::msr::IA32_MTRRCAP, 1);
+            cpu.wrmsr(x86::msr::IA32_MTRR_PHYSBASE0, 0xc000_5000);
+            cpu.wrmsr(x86::msr::IA32_MTRR_PHYSMASK0, PHYSMASK_4KB_VALID);
+        });
+        let mut epts = Epts::new().unwrap();
+        epts.build_identify().unwrap();
+        epts
+    }
+
+    /// Translates `gpa` with `epts` by the reference walker.
+    fn translate(epts: &Epts, gpa: u64) -> Ept12Walk {
+        walk(epts.eptp().pfn() << BASE_PAGE_SHIFT, gpa, &FEATURES, |pa| {
+            Some(unsafe { platform_ops::get().va(pa).cast::<u64>().read() })
+        })
+    }
+
+    /// Checks the identity map translates each GPA to itself with all
+    /// permissions, and the memory type of the MTRRs, splitting pages only
+    /// where the memory type changes.
+    #[test]
+    fn identity_map_translates_to_itself() {
+        for page_1gb in [false, true] {
+            let epts = build(page_1gb);
+            for (gpa, size, memory_type) in [
+                (0x0, HUGE_PAGE_SIZE, MemoryType::WriteBack),
+                (0x1f_f123, HUGE_PAGE_SIZE, MemoryType::WriteBack),
+                (0xc000_5abc, BASE_PAGE_SIZE, MemoryType::Uncachable),
+                (0xc000_4fff, BASE_PAGE_SIZE, MemoryType::WriteBack),
+                (0xc000_6000, BASE_PAGE_SIZE, MemoryType::WriteBack),
+                (0xc020_0000, LARGE_PAGE_SIZE, MemoryType::WriteBack),
+                (0x1_2345_6789, HUGE_PAGE_SIZE, MemoryType::WriteBack),
+                (0x7f_ffff_ffff, HUGE_PAGE_SIZE, MemoryType::WriteBack),
+            ] {
+                let Ept12Walk::Mapped(leaf) = translate(&epts, gpa) else {
+                    panic!("{gpa:#x} is not mapped");
+                };
+                // 1GB pages are split into 2MB pages without their support.
+                let size = if size == HUGE_PAGE_SIZE && !page_1gb {
+                    LARGE_PAGE_SIZE
+                } else {
+                    size
+                };
+                assert_eq!(leaf.translate(gpa), gpa, "{gpa:#x}");
+                assert_eq!(leaf.size, size as u64, "{gpa:#x}");
+                assert_eq!(leaf.memory_type, memory_type as u64, "{gpa:#x}");
+                assert_eq!(leaf.permissions, Permissions::ALL);
+            }
+            assert_eq!(translate(&epts, 0x80_0000_0000), Ept12Walk::NotPresent);
+        }
+    }
+
+    /// Sets each valid combination of permissions on a 1GB page, splits it
+    /// down to 4KB pages, and checks the pages split from it translate as
+    /// before.
+    #[test]
+    fn splits_preserve_translations() {
+        for bits in 0..8 {
+            let permissions = Permissions {
+                read: bits & 1 != 0,
+                write: bits & 2 != 0,
+                execute: bits & 4 != 0,
+            };
+            // Write without read is a misconfiguration, and no permission is
+            // not present.
+            if (permissions.write && !permissions.read) || bits == 0 {
+                continue;
+            }
+
+            let mut epts = build(true);
+            set_permissions(&mut epts.ptr.pdpt.0.entries[5], permissions);
+            let gpa = 0x1_4060_7000;
+            let Ept12Walk::Mapped(before) = translate(&epts, gpa) else {
+                panic!("{gpa:#x} is not mapped");
+            };
+
+            let _ = epts.pt(gpa);
+            for gpa in [gpa - 0x1000, gpa, gpa + 0x1000, gpa + 0x20_0000] {
+                let Ept12Walk::Mapped(after) = translate(&epts, gpa) else {
+                    panic!("{gpa:#x} is not mapped");
+                };
+                assert_eq!(after.translate(gpa), gpa, "{gpa:#x}");
+                assert_eq!(after.permissions, before.permissions, "{gpa:#x}");
+                assert_eq!(after.memory_type, before.memory_type, "{gpa:#x}");
+            }
+            let Ept12Walk::Mapped(split) = translate(&epts, gpa) else {
+                unreachable!();
+            };
+            assert_eq!(split.size, BASE_PAGE_SIZE as u64);
+        }
+    }
 }
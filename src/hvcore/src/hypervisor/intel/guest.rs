@@ -1,44 +1,416 @@
 //! This module implements a guest management.
 
-use core::{arch::global_asm, ptr::addr_of};
+use core::{arch::global_asm, ptr::addr_of, sync::atomic::Ordering};
 
 use alloc::{
     boxed::Box,
     format,
     string::{String, ToString},
 };
-use spin::Lazy;
+use bit_field::BitField;
+use spin::{Once, RwLock};
 use x86::{
     bits64::{paging::BASE_PAGE_SIZE, rflags::RFlags},
     controlregs::{Cr0, Cr4},
     debugregs::{dr0_write, dr1_write, dr2_write, dr3_write, dr6_write, Dr6},
+    dtables::DescriptorTablePointer,
     segmentation::{
         cs, ds, es, fs, gs, ss, CodeSegmentType, DataSegmentType, SystemDescriptorTypes64,
     },
-    vmx::vmcs,
 };
 
 use crate::hypervisor::{
-    host::{Guest, InstructionInfo, VmExitReason},
-    platform_ops,
-    registers::Registers,
+    breakpoint_marker, cet,
+    dirty_tracking::{self, DirtyBitmap, DirtyTrackingError},
+    ept_hook,
+    event::{self, Event, InterruptQueue},
+    exit_trace::RawExitInfo,
+    hidden_memory,
+    host::{
+        CrWriteInfo, DrAccessInfo, GpaMapping, Guest, GuestSegment, GuestSystemState,
+        InstructionInfo, IoInstructionInfo, NestedPageFaultInfo, SegmentRegister, Vcpu,
+        VmExitReason,
+    },
+    host_window,
+    hw_breakpoint::{self, DebugState},
+    instruction_decoder::{self, CodeSize},
+    interrupt_handlers::take_host_nmi,
+    long_mode,
+    memory_protection::{self, Permissions, ViolationAction},
+    mtrr::Mtrr,
+    percpu,
+    preemption_timer::TimerDeadline,
+    registers::{is_xsave_supported, ExtendedRegisters, Registers},
     segment::SegmentDescriptor,
-    support::{zeroed_box, Page},
-    x86_instructions::{cr0, cr3, cr4, lar, ldtr, lsl, rdmsr, sgdt, sidt, tr, write_cr2},
-    SHARED_HOST_DATA,
+    single_step::{SingleStep, SingleStepCallback, SingleStepError},
+    support::{Page, PageBox},
+    tlb::{self, FlushScope},
+    tsc::TscCompensation,
+    virtualization_exception::{self, VeError},
+    x86_instructions::{
+        cr0, cr3, cr4, cr4_write, dr, dr_write, lar, ldtr, lsl, rdmsr, rdtsc, sgdt, sidt, tr,
+        write_cr2, wrmsr,
+    },
+    HvError, SHARED_HOST_DATA,
 };
 
-use super::epts::Epts;
+use super::{
+    apicv::Apicv,
+    entry_checks,
+    epts::{Epts, StepView},
+    nested::{
+        self, Emulation, NestedVmx, Route, EXIT_REASON_EXCEPTION_OR_NMI,
+        EXIT_REASON_EXTERNAL_INTERRUPT,
+    },
+    vmcs::{self, vmclear, vmptrld, Vmcs},
+    vpid::{self, InvvpidType},
+};
 
 /// Representation of a guest.
 pub(crate) struct VmxGuest {
     id: usize,
     registers: Registers,
     vmcs: Vmcs,
+
+    /// The generation of the hooks last applied onto the EPT by this processor.
+    hook_generation: u64,
+
+    /// The generation of the protections last applied onto the EPT by this
+    /// processor.
+    protection_generation: u64,
+
+    /// The EPT view mapping the pages accessed by the instruction being
+    /// single-stepped on this processor. See `step_with_page`.
+    step_view: StepView,
+
+    /// The EPTP to restore when the single-step ends, if the step view is in
+    /// use.
+    step_eptp: Option<u64>,
+
+    /// Whether this processor hid the memory of the hypervisor in the EPT.
+    host_memory_hidden: bool,
+
+    /// The generation of dirty tracking last applied onto the EPTP by this
+    /// processor.
+    dirty_tracking_generation: u64,
+
+    /// The TSC compensation for the time spent in the host.
+    tsc: TscCompensation,
+
+    /// The extended registers switched with the host's, if requested.
+    extended: Option<ExtendedRegisters>,
+
+    /// Whether an NMI is pending to be injected into the guest.
+    nmi_pending: bool,
+
+    /// Whether NMI-window exiting is enabled.
+    nmi_window_exiting: bool,
+
+    /// The external interrupts to be injected into the guest.
+    interrupts: InterruptQueue,
+
+    /// Whether interrupt-window exiting is enabled.
+    interrupt_window_exiting: bool,
+
+    /// The single-step requested on this vCPU, performed with the MTF.
+    single_step: SingleStep,
+
+    /// The debug registers virtualized for `hw_breakpoint`.
+    debug: DebugState,
+
+    /// Whether `VMFUNC` causes VM-exit to switch EPT views. See `switch_view`.
+    vm_functions: bool,
+
+    /// Whether virtualization exceptions are enabled.
+    ve_enabled: bool,
+
+    /// The generation of the convertible pages last applied onto the EPT by
+    /// this processor.
+    convertible_generation: u64,
+
+    /// The generation of the breakpoint markers last applied onto the exception
+    /// bitmap by this processor.
+    marker_generation: u64,
+
+    /// The deadline of `preemption_timer`, if enabled and supported.
+    timer: Option<TimerDeadline>,
+
+    /// The number of TSC ticks per decrement of the timer, as a power of two.
+    timer_rate: u8,
+
+    /// The VPID tagging the translations of the guest, if supported.
+    vpid: Option<u16>,
+
+    /// The APICv state, if external interrupts are delivered through the
+    /// virtual APIC. See `interrupt_virtualization`.
+    apicv: Option<Apicv>,
+
+    /// The VMX operation of the guest with nested virtualization, if entered.
+    /// See `nested`.
+    nested: Option<Box<NestedVmx>>,
+}
+
+impl Vcpu for VmxGuest {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn regs(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    fn cpl(&self) -> u8 {
+        // "The value of the DPL field for SS is always equal to the logical
+        //  processor's current privilege level (CPL)."
+        // See: 25.4.1 Guest Register State
+        let access_rights = VmxSegmentAccessRights(vmcs::guest::SS_ACCESS_RIGHTS.read() as _);
+        access_rights.descriptor_privilege_level() as u8
+    }
+
+    fn cr0(&self) -> u64 {
+        vmcs::guest::CR0.read()
+    }
+
+    fn cr3(&self) -> u64 {
+        vmcs::guest::CR3.read()
+    }
+
+    fn cr4(&self) -> u64 {
+        vmcs::guest::CR4.read()
+    }
+
+    fn efer(&self) -> u64 {
+        // IA32_EFER is switched on VM-entry and VM-exit. See `initialize_control`.
+        vmcs::guest::IA32_EFER_FULL.read()
+    }
+
+    fn segment(&self, register: SegmentRegister) -> GuestSegment {
+        let (selector, base, limit, access_rights) = match register {
+            SegmentRegister::Es => (
+                vmcs::guest::ES_SELECTOR,
+                vmcs::guest::ES_BASE,
+                vmcs::guest::ES_LIMIT,
+                vmcs::guest::ES_ACCESS_RIGHTS,
+            ),
+            SegmentRegister::Cs => (
+                vmcs::guest::CS_SELECTOR,
+                vmcs::guest::CS_BASE,
+                vmcs::guest::CS_LIMIT,
+                vmcs::guest::CS_ACCESS_RIGHTS,
+            ),
+            SegmentRegister::Ss => (
+                vmcs::guest::SS_SELECTOR,
+                vmcs::guest::SS_BASE,
+                vmcs::guest::SS_LIMIT,
+                vmcs::guest::SS_ACCESS_RIGHTS,
+            ),
+            SegmentRegister::Ds => (
+                vmcs::guest::DS_SELECTOR,
+                vmcs::guest::DS_BASE,
+                vmcs::guest::DS_LIMIT,
+                vmcs::guest::DS_ACCESS_RIGHTS,
+            ),
+            SegmentRegister::Fs => (
+                vmcs::guest::FS_SELECTOR,
+                vmcs::guest::FS_BASE,
+                vmcs::guest::FS_LIMIT,
+                vmcs::guest::FS_ACCESS_RIGHTS,
+            ),
+            SegmentRegister::Gs => (
+                vmcs::guest::GS_SELECTOR,
+                vmcs::guest::GS_BASE,
+                vmcs::guest::GS_LIMIT,
+                vmcs::guest::GS_ACCESS_RIGHTS,
+            ),
+            SegmentRegister::Ldtr => (
+                vmcs::guest::LDTR_SELECTOR,
+                vmcs::guest::LDTR_BASE,
+                vmcs::guest::LDTR_LIMIT,
+                vmcs::guest::LDTR_ACCESS_RIGHTS,
+            ),
+            SegmentRegister::Tr => (
+                vmcs::guest::TR_SELECTOR,
+                vmcs::guest::TR_BASE,
+                vmcs::guest::TR_LIMIT,
+                vmcs::guest::TR_ACCESS_RIGHTS,
+            ),
+            SegmentRegister::Gdtr => {
+                return GuestSegment {
+                    selector: 0,
+                    base: vmcs::guest::GDTR_BASE.read(),
+                    limit: vmcs::guest::GDTR_LIMIT.read(),
+                    attributes: 0,
+                }
+            }
+            SegmentRegister::Idtr => {
+                return GuestSegment {
+                    selector: 0,
+                    base: vmcs::guest::IDTR_BASE.read(),
+                    limit: vmcs::guest::IDTR_LIMIT.read(),
+                    attributes: 0,
+                }
+            }
+        };
+        // The access rights have the bits 52-55 of the descriptor at 12-15.
+        // See: Table 25-2. Format of Access Rights
+        let access_rights = access_rights.read();
+        GuestSegment {
+            selector: selector.read(),
+            base: base.read(),
+            limit: limit.read(),
+            attributes: ((access_rights & 0xff) | ((access_rights >> 4) & 0xf00)) as u16,
+        }
+    }
+
+    fn read_msr(&self, msr: u32) -> u64 {
+        // Some MSRs are switched on VM-entry and VM-exit. Read the guest values
+        // from the VMCS.
+        match msr {
+            x86::msr::IA32_EFER => self.efer(),
+            x86::msr::IA32_FS_BASE => vmcs::guest::FS_BASE.read(),
+            x86::msr::IA32_GS_BASE => vmcs::guest::GS_BASE.read(),
+            x86::msr::IA32_SYSENTER_CS => u64::from(vmcs::guest::IA32_SYSENTER_CS.read()),
+            x86::msr::IA32_SYSENTER_ESP => vmcs::guest::IA32_SYSENTER_ESP.read(),
+            x86::msr::IA32_SYSENTER_EIP => vmcs::guest::IA32_SYSENTER_EIP.read(),
+            x86::msr::IA32_DEBUGCTL => vmcs::guest::IA32_DEBUGCTL_FULL.read(),
+            cet::IA32_S_CET if cet::is_switched() => vmcs::guest::IA32_S_CET.read(),
+            cet::IA32_INTERRUPT_SSP_TABLE_ADDR if cet::is_switched() => {
+                vmcs::guest::IA32_INTERRUPT_SSP_TABLE_ADDR.read()
+            }
+            _ => rdmsr(msr),
+        }
+    }
+
+    fn write_msr(&mut self, msr: u32, value: u64) {
+        const EFER_LMA: u64 = 1 << 10;
+
+        match msr {
+            // LMA is read-only, and VM-entry requires it to match the "IA-32e
+            // mode guest" VM-entry control. Keep it as is.
+            // See: 27.3.1.1 Checks on Guest Control Registers, Debug Registers, and MSRs
+            x86::msr::IA32_EFER => {
+                vmcs::guest::IA32_EFER_FULL.write((value & !EFER_LMA) | (self.efer() & EFER_LMA))
+            }
+            x86::msr::IA32_FS_BASE => vmcs::guest::FS_BASE.write(value),
+            x86::msr::IA32_GS_BASE => vmcs::guest::GS_BASE.write(value),
+            x86::msr::IA32_SYSENTER_CS => vmcs::guest::IA32_SYSENTER_CS.write(value as u32),
+            x86::msr::IA32_SYSENTER_ESP => vmcs::guest::IA32_SYSENTER_ESP.write(value),
+            x86::msr::IA32_SYSENTER_EIP => vmcs::guest::IA32_SYSENTER_EIP.write(value),
+            x86::msr::IA32_DEBUGCTL => vmcs::guest::IA32_DEBUGCTL_FULL.write(value),
+            cet::IA32_S_CET if cet::is_switched() => vmcs::guest::IA32_S_CET.write(value),
+            cet::IA32_INTERRUPT_SSP_TABLE_ADDR if cet::is_switched() => {
+                vmcs::guest::IA32_INTERRUPT_SSP_TABLE_ADDR.write(value);
+            }
+            // Only the x2APIC mode is virtualized. Move the state of the virtual
+            // APIC back into the local APIC while it is still in the mode.
+            // See: 11.12.1 Detecting and Enabling x2APIC Mode
+            x86::msr::IA32_APIC_BASE if self.apicv.is_some() => {
+                if !value.get_bit(10) {
+                    self.disable_apicv();
+                }
+                wrmsr(msr, value);
+            }
+            x86::msr::IA32_MTRR_DEF_TYPE => {
+                // The MTRRs do not apply to accesses through the EPT, which
+                // specifies memory types instead. Reflect the MTRRs updated by
+                // the guest into the EPT. Updating MTRRs completes with this
+                // write, and the guest updates them on every processor, each of
+                // which invalidates its own cached EPT translations.
+                // See: 12.11.7.2 MemTypeSet() Function
+                // See: 12.11.8 MTRR Considerations in MP Systems
+                wrmsr(msr, value);
+                let mut epts = shared_guest_data().epts.write();
+                epts.update_memory_types(&Mtrr::new());
+                tlb::flush_guest(self, FlushScope::GuestPhysical);
+            }
+            _ => wrmsr(msr, value),
+        }
+    }
+
+    fn set_cr2(&mut self, value: u64) {
+        // CR2 is not switched on VM-entry and VM-exit. The current value is
+        // the guest's.
+        write_cr2(value);
+    }
+
+    fn pending_event(&self) -> Option<Event> {
+        Event::from_raw(
+            vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD.read(),
+            vmcs::control::VMENTRY_EXCEPTION_ERR_CODE.read(),
+        )
+    }
+
+    fn set_pending_event(&mut self, event: Option<Event>) {
+        // The instruction length is not needed as software interrupts and
+        // exceptions are not injected.
+        // See: 27.6.1.1 Details of Vectored-Event Injection
+        //
+        // Exceptions push no error code in real mode, and VM-entry fails if one
+        // is to be delivered while the unrestricted guest has CR0.PE cleared.
+        // See: 27.2.1.3 Checks on VM-Entry Control Fields
+        let real_mode = vmcs::guest::CR0.read() & Cr0::CR0_PROTECTED_MODE.bits() as u64 == 0;
+        let event = event.map(|event| match event {
+            Event::Exception { vector, .. } if real_mode => Event::Exception {
+                vector,
+                error_code: None,
+            },
+            _ => event,
+        });
+        let (info, error_code) = event.map_or((0, 0), Event::to_raw);
+        vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD.write(info);
+        vmcs::control::VMENTRY_EXCEPTION_ERR_CODE.write(error_code);
+    }
+
+    fn queue_interrupt(&mut self, vector: u8) {
+        match &mut self.apicv {
+            Some(apicv) => apicv.request(vector),
+            None => self.interrupts.push(vector),
+        }
+    }
+
+    fn single_step(&mut self, callback: Box<SingleStepCallback>) -> Result<(), SingleStepError> {
+        // The higher 32bits of the capability MSR indicate the controls that
+        // can be 1. See `adjust_vmx_control`.
+        let mtf = vmcs::control::PrimaryControls::MONITOR_TRAP_FLAG;
+        let allowed1 = rdmsr(x86::msr::IA32_VMX_PROCBASED_CTLS) >> 32;
+        if allowed1 & u64::from(mtf.bits()) == 0 {
+            return Err(SingleStepError::Unsupported);
+        }
+
+        self.single_step.request(callback)?;
+        update_primary_controls(mtf, true);
+        Ok(())
+    }
+
+    fn harvest_dirty_pages(&mut self) -> Result<DirtyBitmap, DirtyTrackingError> {
+        if !dirty_tracking::is_enabled() {
+            return Err(DirtyTrackingError::NotEnabled);
+        }
+
+        // Make sure this processor tracks with the current EPTP before clearing
+        // the flags.
+        self.sync_dirty_tracking();
+
+        let mut bitmap = DirtyBitmap::new();
+        let mut epts = shared_guest_data().epts.write();
+        epts.harvest_dirty(&mut bitmap);
+        tlb::flush_guest(self, FlushScope::GuestPhysical);
+        self.dirty_tracking_generation = dirty_tracking::harvested();
+        Ok(bitmap)
+    }
+
+    fn resolve_gpa(&self, gpa: u64) -> Option<GpaMapping> {
+        match &self.nested {
+            Some(nested) if nested.in_l2() => nested.resolve(self.id, gpa),
+            _ => shared_guest_data().epts.read().resolve(gpa),
+        }
+    }
 }
 
 impl Guest for VmxGuest {
-    fn new(id: usize) -> Self {
+    fn new(id: usize) -> Result<Self, HvError> {
+        let _ = SHARED_GUEST_DATA.try_call_once(SharedGuestData::new)?;
+
         // The processor is now in VMX root operation. This means that the processor
         // can execute other VMX instructions and almost ready for configuring a VMCS
         // with the VMREAD and VMWRITE instructions. Before doing so, we need to make
@@ -54,14 +426,44 @@ impl Guest for VmxGuest {
         //  software should execute VMCLEAR on a VMCS region before making the
         //  corresponding VMCS active with VMPTRLD for the first time."
         // See: 25.11.3 Initializing a VMCS
-        Self {
+        Ok(Self {
             id,
             registers: Registers::default(),
-            vmcs: Vmcs::new(),
-        }
+            vmcs: Vmcs::new()?,
+            hook_generation: 0,
+            protection_generation: 0,
+            step_view: StepView::new()?,
+            step_eptp: None,
+            host_memory_hidden: false,
+            dirty_tracking_generation: 0,
+            tsc: TscCompensation::new(id, &SHARED_HOST_DATA.get().unwrap().tsc, Self::tsc_scale()),
+            extended: None,
+            nmi_pending: false,
+            nmi_window_exiting: false,
+            interrupts: InterruptQueue::default(),
+            interrupt_window_exiting: false,
+            single_step: SingleStep::default(),
+            debug: DebugState::new(),
+            vm_functions: false,
+            ve_enabled: false,
+            convertible_generation: 0,
+            marker_generation: 0,
+            timer: Self::preemption_timer(),
+            // See: A.6 MISCELLANEOUS DATA
+            timer_rate: rdmsr(x86::msr::IA32_VMX_MISC).get_bits(0..=4) as u8,
+            vpid: vpid::allocate(id),
+            apicv: Apicv::new(&shared_guest_data().msr_bitmaps)?,
+            nested: None,
+        })
     }
 
-    fn activate(&mut self) {
+    fn is_shared_data_locked() -> bool {
+        SHARED_GUEST_DATA
+            .get()
+            .is_some_and(|data| data.epts.reader_count() != 0 || data.epts.writer_count() != 0)
+    }
+
+    fn activate(&mut self) -> Result<(), HvError> {
         // To make the VMCS "active" and "current" execute the VMPTRLD instruction.
         // This instruction requires that the revision identifier is initialized,
         // which was done in `Vmcs::new`.
@@ -71,45 +473,500 @@ impl Guest for VmxGuest {
         //  VMCS region whose VMCS revision identifier differs from that used by
         //  the processor."
         // See: 25.2 FORMAT OF THE VMCS REGION
-        vmptrld(&mut self.vmcs);
+        vmptrld(&mut self.vmcs)
 
         // The processor now have an associated VMCS (called a current VMCS) and
         // able to execute the VMREAD and VMWRITE instructions. Let us program it.
     }
 
-    fn initialize(&mut self, registers: &Registers) {
+    fn initialize(
+        &mut self,
+        registers: &Registers,
+        extended: Option<ExtendedRegisters>,
+    ) -> Result<(), HvError> {
         self.registers = *registers;
+        self.extended = extended;
         self.initialize_control();
+        if let Some(apicv) = &mut self.apicv {
+            apicv.initialize();
+        }
         self.initialize_guest();
-        self.initialize_host();
+        self.initialize_host()
     }
 
     fn run(&mut self) -> VmExitReason {
+        if self.nested.as_ref().is_some_and(|nested| nested.in_l2()) {
+            return self.run_l2();
+        }
+
+        self.sync_hooks();
+        self.sync_protections();
+        self.sync_hidden_memory();
+        self.sync_dirty_tracking();
+        self.sync_convertible_pages();
+        hw_breakpoint::sync(self);
+        self.sync_breakpoint_markers();
+        self.flush_tlb();
+        self.inject_pending_nmi();
+        self.inject_pending_interrupt();
+        if let Some(apicv) = &mut self.apicv {
+            apicv.take_posted();
+        }
+        if self.tsc.enabled() {
+            vmcs::control::TSC_OFFSET_FULL.write(self.tsc.on_entry());
+        }
+        if let Some(timer) = &self.timer {
+            vmcs::guest::VMX_PREEMPTION_TIMER_VALUE
+                .write(timer.timer_value(rdtsc(), self.timer_rate));
+        }
+
+        if let Err(err) = self.run_current() {
+            panic!("{err}");
+        }
+        self.handle_vm_exit()
+    }
+
+    fn exit_info(&self) -> RawExitInfo {
+        const VMX_EXIT_REASON_EPT_VIOLATION: u64 = 48;
+
+        let code = u64::from(vmcs::ro::EXIT_REASON.read() as u16);
+        RawExitInfo {
+            code,
+            qualification: vmcs::ro::EXIT_QUALIFICATION.read(),
+            gpa: if code == VMX_EXIT_REASON_EPT_VIOLATION {
+                vmcs::ro::GUEST_PHYSICAL_ADDR_FULL.read()
+            } else {
+                0
+            },
+        }
+    }
+
+    fn write_cr(&mut self, cr: u8, value: u64) {
+        // MOV to control registers may invalidate TLB entries, for example,
+        // when CR4.PGE changes. Flush those of the guest for simplicity, which
+        // VM-entry does regardless without VPIDs.
+        // See: 4.10.4.1 Operations that Invalidate TLBs and Paging-Structure Caches
+        tlb::flush_guest(self, FlushScope::GuestLinear);
+
+        // Bit 63 is not part of CR3 but asks not to invalidate TLB entries of
+        // the PCID, which are invalidated above regardless. PAE paging, which
+        // would need the PDPTEs loaded, is not supported.
+        if cr == 3 {
+            const CR3_NO_FLUSH: u64 = 1 << 63;
+            vmcs::guest::CR3.write(value & !CR3_NO_FLUSH);
+            return;
+        }
+
+        // The guest reads the guarded bits from the read shadows. Keep them as
+        // written, while the actual register has the bits fixed for VMX.
+        // See: 26.3 CHANGES TO INSTRUCTION BEHAVIOR IN VMX NON-ROOT OPERATION
+        if cr == 4 {
+            vmcs::control::CR4_READ_SHADOW.write(value);
+            let cr4 = unsafe { Cr4::from_bits_unchecked(value as usize) };
+            vmcs::guest::CR4.write(get_adjusted_guest_cr4(cr4).bits() as u64);
+            return;
+        }
+
+        // CR0.PG is always guarded, for example, for the processor started with
+        // INIT-SIPI-SIPI to enable paging, and the guest leaving long mode for
+        // kexec. Update IA32_EFER.LMA as the processor would, and the "IA-32e
+        // mode guest" VM-entry control, which VM-entry requires to match it.
+        // See: 10.8.5 Initializing IA-32e Mode
+        // See: 27.3.1.1 Checks on Guest Control Registers, Debug Registers, and MSRs
+        const EFER_LMA: u64 = 1 << 10;
+        let paging = Cr0::CR0_ENABLE_PAGING.bits() as u64;
+        if (vmcs::guest::CR0.read() ^ value) & paging != 0 {
+            let efer = long_mode::update_lma(self.efer(), value);
+            vmcs::guest::IA32_EFER_FULL.write(efer);
+            let ia32e_mode_guest = efer & EFER_LMA != 0;
+            let ia32e = vmcs::control::EntryControls::IA32E_MODE_GUEST.bits();
+            let controls = vmcs::control::VMENTRY_CONTROLS.read();
+            vmcs::control::VMENTRY_CONTROLS.write(if ia32e_mode_guest {
+                controls | ia32e
+            } else {
+                controls & !ia32e
+            });
+        }
+        vmcs::control::CR0_READ_SHADOW.write(value);
+        let cr0 = unsafe { Cr0::from_bits_unchecked(value as usize) };
+        vmcs::guest::CR0.write(get_adjusted_guest_cr0(cr0).bits() as u64);
+    }
+
+    fn set_cr3_targets(&mut self, targets: &[u64]) {
+        // MOV to CR3 does not cause VM-exit if the value equals to any of the
+        // first CR3-target count values. The processor supports up to four.
+        // See: 25.6.7 CR3-Target Controls
+        // See: A.6 MISCELLANEOUS DATA
+        let supported = rdmsr(x86::msr::IA32_VMX_MISC).get_bits(16..=24) as usize;
+        let count = targets.len().min(supported);
+        let fields = [
+            vmcs::control::CR3_TARGET_VALUE0,
+            vmcs::control::CR3_TARGET_VALUE1,
+            vmcs::control::CR3_TARGET_VALUE2,
+            vmcs::control::CR3_TARGET_VALUE3,
+        ];
+        for (field, &target) in fields.iter().zip(&targets[..count]) {
+            field.write(target);
+        }
+        vmcs::control::CR3_TARGET_COUNT.write(count as u32);
+    }
+
+    fn debug_state(&mut self) -> &mut DebugState {
+        &mut self.debug
+    }
+
+    fn read_dr(&self, index: u8) -> u64 {
+        // Only DR7 is switched through the VMCS. The others are switched with
+        // the extended registers if requested, and are left the guest values
+        // otherwise.
+        match (index, &self.extended) {
+            (7, _) => vmcs::guest::DR7.read(),
+            (_, Some(extended)) => extended.dr(index),
+            (_, None) => dr(index),
+        }
+    }
+
+    fn write_dr(&mut self, index: u8, value: u64) {
+        match (index, &mut self.extended) {
+            (7, _) => vmcs::guest::DR7.write(value),
+            (_, Some(extended)) => extended.set_dr(index, value),
+            (_, None) => dr_write(index, value),
+        }
+    }
+
+    fn intercept_debug(&mut self, enable: bool) {
+        const DB_VECTOR: u32 = 1;
+
+        // See: 25.6.3 Exception Bitmap
+        update_primary_controls(vmcs::control::PrimaryControls::MOV_DR_EXITING, enable);
+        let bitmap = vmcs::control::EXCEPTION_BITMAP.read();
+        vmcs::control::EXCEPTION_BITMAP.write(if enable {
+            bitmap | 1 << DB_VECTOR
+        } else {
+            bitmap & !(1 << DB_VECTOR)
+        });
+    }
+
+    fn handle_nested_page_fault(&mut self, info: &NestedPageFaultInfo) {
+        // Writes to and execution of the pages hidden from the guest.
+        if hidden_memory::handle_violation(self, info) {
+            return;
+        }
+
+        // One of the accesses we restrict through EPT is the one to hooked pages.
+        // The shared EPT always maps the shadow pages for execution only. Let
+        // the guest complete a data access with the original page mapped in
+        // the step view of this processor, so that the other processors never
+        // observe the original page executable or the shadow page writable.
+        // L2 sees the same pages through EPT02 instead. See `nested`.
+        let shadow_pa = shared_guest_data().epts.read().shadow_pa(info.gpa);
+        if let Some(shadow_pa) = shadow_pa {
+            match &mut self.nested {
+                Some(nested) if nested.uses_ept02() => nested.map_hooked(info, shadow_pa),
+                _ => self.handle_hooked_page_violation(info, shadow_pa),
+            }
+            return;
+        }
+
+        // Another is access to protected pages.
+        match memory_protection::handle_violation(self, info) {
+            Some(ViolationAction::Allow) => {
+                self.allow_access_once(info.gpa);
+                return;
+            }
+            Some(ViolationAction::Resume) => return,
+            Some(ViolationAction::Skip) => {
+                let result = instruction_decoder::skip(self);
+                memory_protection::complete(self, result);
+                return;
+            }
+            Some(ViolationAction::Emulate) => {
+                let result = instruction_decoder::emulate(self);
+                memory_protection::complete(self, result);
+                return;
+            }
+            None => {}
+        }
+
+        // The page may have been unprotected but not yet applied on this
+        // processor. Let the guest retry after applying it.
+        if self.protection_generation != memory_protection::generation() {
+            return;
+        }
+
+        // The last is access the current view does not permit. Let the guest
+        // retry it in the default view. L2 is not in any view.
+        let ept02 = self
+            .nested
+            .as_ref()
+            .is_some_and(|nested| nested.uses_ept02());
+        let epts = shared_guest_data().epts.read();
+        let eptp = epts.eptp();
+        if !ept02 && epts.view_index(self.current_eptp()) != 0 {
+            self.set_current_eptp(eptp.0);
+            if self.ve_enabled {
+                vmcs::control::EPTP_INDEX.write(0);
+            }
+            return;
+        }
+        drop(epts);
+
+        // Other than that, nobody but custom handlers expects this.
+        log::error!("{:#x?}", self.vmcs);
+        panic!("Unhandled EPT violation: {info:#x?}");
+    }
+
+    fn set_virtualization_exception_info(&mut self, info_pa: Option<u64>) -> Result<(), VeError> {
+        // The higher 32bits of the capability MSR indicate the controls that
+        // can be 1. See `adjust_vmx_control`.
+        let ve = vmcs::control::SecondaryControls::EPT_VIOLATION_VE;
+        let allowed1 = rdmsr(x86::msr::IA32_VMX_PROCBASED_CTLS2) >> 32;
+        if allowed1 & u64::from(ve.bits()) == 0 {
+            return Err(VeError::Unsupported);
+        }
+
+        // The processor reports the EPTP index as the current view on #VE, and
+        // VMFUNC keeps it up to date.
+        // See: 25.6.20 Controls for Virtualization Exceptions
+        if let Some(info_pa) = info_pa {
+            let epts = shared_guest_data().epts.read();
+            let view = epts.view_index(self.current_eptp());
+            vmcs::control::VIRT_EXCEPTION_INFO_ADDR_FULL.write(info_pa);
+            vmcs::control::EPTP_INDEX.write(view as u16);
+        }
+        update_secondary_controls(ve, info_pa.is_some());
+        self.ve_enabled = info_pa.is_some();
+        Ok(())
+    }
+
+    fn in_nested_guest(&self) -> bool {
+        self.nested.as_ref().is_some_and(|nested| nested.in_l2())
+    }
+
+    fn can_devirtualize(&self) -> bool {
+        // Devirtualizing would leave the guest in VMX operation it entered
+        // without the processor. See `nested`.
+        self.nested.is_none()
+    }
+
+    fn deactivate(&mut self) -> GuestSystemState {
+        if let Some(mut nested) = self.nested.take() {
+            nested.release(self.vmcs.pa());
+        }
+        hw_breakpoint::restore(self);
+        if let Some(apicv) = &mut self.apicv {
+            apicv.deactivate();
+        }
+
+        // VM-exit clears or loads some of MSRs from the host-state fields, which
+        // we do not configure. Restore them from the guest-state fields.
+        // See: 28.5.1 Loading Host Control Registers, Debug Registers, MSRs
+        wrmsr(
+            x86::msr::IA32_DEBUGCTL,
+            vmcs::guest::IA32_DEBUGCTL_FULL.read(),
+        );
+        wrmsr(
+            x86::msr::IA32_SYSENTER_CS,
+            u64::from(vmcs::guest::IA32_SYSENTER_CS.read()),
+        );
+        wrmsr(
+            x86::msr::IA32_SYSENTER_ESP,
+            vmcs::guest::IA32_SYSENTER_ESP.read(),
+        );
+        wrmsr(
+            x86::msr::IA32_SYSENTER_EIP,
+            vmcs::guest::IA32_SYSENTER_EIP.read(),
+        );
+
+        // So are IA32_EFER and IA32_PAT with the "load IA32_EFER" and "load
+        // IA32_PAT" VM-exit controls. The guest is in long mode as the host is,
+        // so only SCE and NXE of IA32_EFER may differ.
+        wrmsr(x86::msr::IA32_EFER, vmcs::guest::IA32_EFER_FULL.read());
+        wrmsr(x86::msr::IA32_PAT, vmcs::guest::IA32_PAT_FULL.read());
+
+        // So are the CET MSRs with the "load CET state" VM-exit control. Only
+        // IA32_S_CET is left to be restored at the end, since it enables CET
+        // for the current code too.
+        let s_cet = if cet::is_switched() {
+            wrmsr(
+                cet::IA32_INTERRUPT_SSP_TABLE_ADDR,
+                vmcs::guest::IA32_INTERRUPT_SSP_TABLE_ADDR.read(),
+            );
+            self.registers.ssp = vmcs::guest::SSP.read();
+            vmcs::guest::IA32_S_CET.read()
+        } else {
+            cet::s_cet()
+        };
+
+        let state = GuestSystemState {
+            registers: self.registers,
+            extended: self.extended.take(),
+            cr0: vmcs::guest::CR0.read(),
+            cr3: vmcs::guest::CR3.read(),
+            // CR4.VMXE is set only for VMX operation. Do not leave it set.
+            cr4: vmcs::guest::CR4.read() & !(Cr4::CR4_ENABLE_VMX.bits() as u64),
+            dr7: vmcs::guest::DR7.read(),
+            gdtr: DescriptorTablePointer {
+                base: vmcs::guest::GDTR_BASE.read() as _,
+                limit: vmcs::guest::GDTR_LIMIT.read() as _,
+            },
+            idtr: DescriptorTablePointer {
+                base: vmcs::guest::IDTR_BASE.read() as _,
+                limit: vmcs::guest::IDTR_LIMIT.read() as _,
+            },
+            es: vmcs::guest::ES_SELECTOR.read() as _,
+            cs: vmcs::guest::CS_SELECTOR.read() as _,
+            ss: vmcs::guest::SS_SELECTOR.read() as _,
+            ds: vmcs::guest::DS_SELECTOR.read() as _,
+            fs: vmcs::guest::FS_SELECTOR.read() as _,
+            gs: vmcs::guest::GS_SELECTOR.read() as _,
+            tr: vmcs::guest::TR_SELECTOR.read() as _,
+            ldtr: vmcs::guest::LDTR_SELECTOR.read() as _,
+            fs_base: vmcs::guest::FS_BASE.read(),
+            gs_base: vmcs::guest::GS_BASE.read(),
+            s_cet,
+        };
+
+        // Make the VMCS inactive to free it.
+        // See: 25.11.1 Software Use of Virtual-Machine Control Structures
+        vmclear(&mut self.vmcs).unwrap();
+        state
+    }
+}
+
+impl VmxGuest {
+    /// Runs the guest with the current VMCS until VM-exit occurs. Fails if
+    /// VM-entry failed with VMfail.
+    fn run_current(&mut self) -> Result<(), String> {
+        vmcs::guest::RIP.write(self.registers.rip);
+        vmcs::guest::RSP.write(self.registers.rsp);
+        vmcs::guest::RFLAGS.write(self.registers.rflags);
+
+        // Execute the guest until VM-exit occurs.
+        log::trace!("Entering the guest");
+        let extended = self
+            .extended
+            .as_mut()
+            .map_or(core::ptr::null_mut(), ExtendedRegisters::prepare);
+        let flags = unsafe { run_vmx_guest(&mut self.registers, extended) };
+        self.tsc.on_exit();
+        vmx_succeed(RFlags::from_raw(flags))?;
+        log::trace!("Exited the guest");
+
+        self.registers.rip = vmcs::guest::RIP.read();
+        self.registers.rsp = vmcs::guest::RSP.read();
+        self.registers.rflags = vmcs::guest::RFLAGS.read();
+        Ok(())
+    }
+
+    /// Returns the reason of the VM-exit from the current VMCS, after the
+    /// handling specific to VMX.
+    fn handle_vm_exit(&mut self) -> VmExitReason {
+        const VMX_EXIT_REASON_EXCEPTION_OR_NMI: u16 = 0;
+        const VMX_EXIT_REASON_EXTERNAL_INTERRUPT: u16 = 1;
         const VMX_EXIT_REASON_INIT: u16 = 3;
         const VMX_EXIT_REASON_SIPI: u16 = 4;
+        const VMX_EXIT_REASON_INTERRUPT_WINDOW: u16 = 7;
+        const VMX_EXIT_REASON_NMI_WINDOW: u16 = 8;
         const VMX_EXIT_REASON_CPUID: u16 = 10;
+        const VMX_EXIT_REASON_INVD: u16 = 13;
+        const VMX_EXIT_REASON_VMCALL: u16 = 18;
+        const VMX_EXIT_REASON_VMCLEAR: u16 = 19;
+        const VMX_EXIT_REASON_VMXON: u16 = 27;
+        const VMX_EXIT_REASON_INVEPT: u16 = 50;
+        const VMX_EXIT_REASON_INVVPID: u16 = 53;
+        const VMX_EXIT_REASON_CR_ACCESS: u16 = 28;
+        const VMX_EXIT_REASON_DR_ACCESS: u16 = 29;
+        const VMX_EXIT_REASON_IO: u16 = 30;
+        const VMX_EXIT_REASON_ENTRY_FAILURE_GUEST_STATE: u16 = 33;
+        const VMX_EXIT_REASON_MONITOR_TRAP_FLAG: u16 = 37;
+        const VMX_EXIT_REASON_ENTRY_FAILURE_MACHINE_CHECK: u16 = 41;
+        const VMX_EXIT_REASON_VIRTUALIZED_EOI: u16 = 45;
         const VMX_EXIT_REASON_RDMSR: u16 = 31;
         const VMX_EXIT_REASON_WRMSR: u16 = 32;
+        const VMX_EXIT_REASON_EPT_VIOLATION: u16 = 48;
+        const VMX_EXIT_REASON_PREEMPTION_TIMER: u16 = 52;
+        const VMX_EXIT_REASON_WBINVD: u16 = 54;
         const VMX_EXIT_REASON_XSETBV: u16 = 55;
+        const VMX_EXIT_REASON_VMFUNC: u16 = 59;
 
-        vmwrite(vmcs::guest::RIP, self.registers.rip);
-        vmwrite(vmcs::guest::RSP, self.registers.rsp);
-        vmwrite(vmcs::guest::RFLAGS, self.registers.rflags);
-
-        // Execute the guest until VM-exit occurs.
-        log::trace!("Entering the guest");
-        let flags = unsafe { run_vmx_guest(&mut self.registers) };
-        if let Err(err) = vmx_succeed(RFlags::from_raw(flags)) {
-            panic!("{err}");
-        }
-        log::trace!("Exited the guest");
-
-        self.registers.rip = vmread(vmcs::guest::RIP);
-        self.registers.rsp = vmread(vmcs::guest::RSP);
-        self.registers.rflags = vmread(vmcs::guest::RFLAGS);
+        self.reinject_vectoring_event();
 
         // Return VM-exit reason.
-        match vmread(vmcs::ro::EXIT_REASON) as u16 {
+        match vmcs::ro::EXIT_REASON.read() as u16 {
+            // Only #DB for `hw_breakpoint`, #BP for `breakpoint_marker` and #MC
+            // for `machine_check` are intercepted. Otherwise, this is an NMI,
+            // which is blocked until the next VM-entry. Inject it into the guest.
+            // See: Table 25-19. Format of the VM-Exit Interruption-Information Field
+            VMX_EXIT_REASON_EXCEPTION_OR_NMI => {
+                const NMI: u32 = 2;
+                const BP_VECTOR: u32 = 3;
+                const MC_VECTOR: u32 = 18;
+                let interruption_info = vmcs::ro::VMEXIT_INTERRUPTION_INFO.read();
+                if interruption_info.get_bits(8..=10) == NMI {
+                    // An NMI from the watchdog arriving after the host resumed
+                    // the guest is not for the guest.
+                    self.nmi_pending |= !percpu::current().heartbeat.take_dump_request();
+                    VmExitReason::Nmi
+                } else if interruption_info.get_bits(0..=7) == BP_VECTOR {
+                    VmExitReason::Breakpoint(InstructionInfo {
+                        next_rip: self.registers.rip
+                            + u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read()),
+                    })
+                } else if interruption_info.get_bits(0..=7) == MC_VECTOR {
+                    VmExitReason::MachineCheck
+                } else {
+                    self.handle_debug_exception(interruption_info)
+                }
+            }
+            // A machine-check event occurred while the guest state was being
+            // loaded. The guest state is not modified, and the event is
+            // handled as #MC in the guest.
+            // See: 27.8 VM-ENTRY FAILURES DURING OR AFTER LOADING GUEST STATE
+            VMX_EXIT_REASON_ENTRY_FAILURE_MACHINE_CHECK => VmExitReason::MachineCheck,
+            // One of the checks on the guest state failed, and the processor
+            // does not tell which. Repeat them in software to tell.
+            // See: 27.8 VM-ENTRY FAILURES DURING OR AFTER LOADING GUEST STATE
+            VMX_EXIT_REASON_ENTRY_FAILURE_GUEST_STATE => {
+                log::error!("{:#x?}", self.vmcs);
+                entry_checks::log_violations();
+                panic!(
+                    "VM-entry failed due to invalid guest state: {:#x}",
+                    vmcs::ro::EXIT_QUALIFICATION.read()
+                )
+            }
+            // External interrupts and EOIs cause VM-exit only with APICv.
+            VMX_EXIT_REASON_EXTERNAL_INTERRUPT => {
+                self.apicv.as_mut().unwrap().handle_external_interrupt();
+                VmExitReason::ExternalInterrupt
+            }
+            VMX_EXIT_REASON_VIRTUALIZED_EOI => {
+                self.apicv.as_mut().unwrap().handle_virtual_eoi();
+                VmExitReason::VirtualEoi
+            }
+            VMX_EXIT_REASON_NMI_WINDOW => VmExitReason::Nmi,
+            VMX_EXIT_REASON_INTERRUPT_WINDOW => VmExitReason::InterruptWindow,
+            VMX_EXIT_REASON_MONITOR_TRAP_FLAG => {
+                // "the MTF VM exit (...) will occur after executing the first
+                //  instruction in the guest" or before the first instruction of
+                //  the handler of an event being delivered.
+                // See: 26.5.2 Monitor Trap Flag
+                update_primary_controls(vmcs::control::PrimaryControls::MONITOR_TRAP_FLAG, false);
+                if let Some(callback) = self.single_step.complete() {
+                    callback(self);
+                }
+                self.end_step_view();
+                if let Some(nested) = &mut self.nested {
+                    nested.end_steps();
+                }
+                VmExitReason::SingleStep
+            }
+            // INIT is blocked in VMX root operation of the guest until VMXOFF.
+            // See: 26.2 OTHER CAUSES OF VM EXITS
+            VMX_EXIT_REASON_INIT if self.nested.is_some() => {
+                self.nested.as_mut().unwrap().latch_init();
+                VmExitReason::NestedGuest
+            }
             VMX_EXIT_REASON_INIT => {
                 self.handle_init_signal();
                 VmExitReason::InitSignal
@@ -119,67 +976,679 @@ impl Guest for VmxGuest {
                 VmExitReason::StartupIpi
             }
             VMX_EXIT_REASON_CPUID => VmExitReason::Cpuid(InstructionInfo {
-                next_rip: self.registers.rip + vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
+                next_rip: self.registers.rip + u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read()),
+            }),
+            VMX_EXIT_REASON_VMCALL => VmExitReason::Hypercall(InstructionInfo {
+                next_rip: self.registers.rip + u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read()),
             }),
+            VMX_EXIT_REASON_IO => {
+                // See: Table 28-5. Exit Qualification for I/O Instructions
+                let qualification = vmcs::ro::EXIT_QUALIFICATION.read();
+                let string = qualification.get_bit(4);
+
+                // The address size and the segment of INS and OUTS are reported
+                // in the VM-exit instruction information if IA32_VMX_BASIC[54]
+                // is set. Otherwise, assume the defaults without prefixes.
+                // See: Table 28-8. Format of the VM-Exit Instruction-Information
+                //      Field as Used for INS and OUTS
+                // See: A.1 BASIC VMX INFORMATION
+                let (address_size, segment) =
+                    if string && rdmsr(x86::msr::IA32_VMX_BASIC).get_bit(54) {
+                        let info = u64::from(vmcs::ro::VMEXIT_INSTRUCTION_INFO.read());
+                        (
+                            2 << info.get_bits(7..=9),
+                            SegmentRegister::from_number(info.get_bits(15..=17)),
+                        )
+                    } else {
+                        (CodeSize::of(self).address_size(), SegmentRegister::Ds)
+                    };
+                VmExitReason::Io(IoInstructionInfo {
+                    next_rip: self.registers.rip
+                        + u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read()),
+                    port: qualification.get_bits(16..=31) as u16,
+                    size: qualification.get_bits(0..=2) as u8 + 1,
+                    is_in: qualification.get_bit(3),
+                    string,
+                    rep: qualification.get_bit(5),
+                    address_size,
+                    segment,
+                })
+            }
+            VMX_EXIT_REASON_CR_ACCESS => {
+                // Only MOV to CR0 and CR4 changing the bits set in the guest/host
+                // masks, and MOV to CR3 with CR3-load exiting, cause this
+                // VM-exit. The guarded bits exclude those CLTS and LMSW change.
+                // See: Table 28-3. Exit Qualification for Control-Register Accesses
+                let qualification = vmcs::ro::EXIT_QUALIFICATION.read();
+                assert!(
+                    qualification.get_bits(4..=5) == 0,
+                    "Unexpected control register access: {qualification:#x}"
+                );
+                // CR4.VMXE cannot be cleared in VMX operation.
+                // See: 24.7 ENABLING AND ENTERING VMX OPERATION
+                let cr = qualification.get_bits(0..=3) as u8;
+                let value = self.registers.gpr(qualification.get_bits(8..=11) as u8);
+                if cr == 4
+                    && self.nested.is_some()
+                    && value & Cr4::CR4_ENABLE_VMX.bits() as u64 == 0
+                {
+                    let gp = Event::Exception {
+                        vector: 13,
+                        error_code: Some(0),
+                    };
+                    if let Err(err) = event::inject_event(self, gp) {
+                        log::error!("Could not inject #GP: {err}");
+                    }
+                    return VmExitReason::NestedGuest;
+                }
+                VmExitReason::CrWrite(CrWriteInfo {
+                    next_rip: self.registers.rip
+                        + u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read()),
+                    cr,
+                    value,
+                })
+            }
+            VMX_EXIT_REASON_DR_ACCESS => {
+                // See: Table 28-4. Exit Qualification for MOV DR
+                let qualification = vmcs::ro::EXIT_QUALIFICATION.read();
+                VmExitReason::DrAccess(DrAccessInfo {
+                    next_rip: self.registers.rip
+                        + u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read()),
+                    dr: qualification.get_bits(0..=2) as u8,
+                    gpr: qualification.get_bits(8..=11) as u8,
+                    write: !qualification.get_bit(4),
+                })
+            }
             VMX_EXIT_REASON_RDMSR => VmExitReason::Rdmsr(InstructionInfo {
-                next_rip: self.registers.rip + vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
+                next_rip: self.registers.rip + u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read()),
             }),
             VMX_EXIT_REASON_WRMSR => VmExitReason::Wrmsr(InstructionInfo {
-                next_rip: self.registers.rip + vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
+                next_rip: self.registers.rip + u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read()),
             }),
             VMX_EXIT_REASON_XSETBV => VmExitReason::XSetBv(InstructionInfo {
-                next_rip: self.registers.rip + vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN),
+                next_rip: self.registers.rip + u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read()),
+            }),
+            VMX_EXIT_REASON_INVD => VmExitReason::Invd(InstructionInfo {
+                next_rip: self.registers.rip + u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read()),
+            }),
+            VMX_EXIT_REASON_WBINVD => VmExitReason::Wbinvd(InstructionInfo {
+                next_rip: self.registers.rip + u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read()),
             }),
+            VMX_EXIT_REASON_EPT_VIOLATION => {
+                // See: Table 28-7. Exit Qualification for EPT Violations
+                let qualification = vmcs::ro::EXIT_QUALIFICATION.read();
+                self.reblock_nmi_if_unblocked(qualification);
+                VmExitReason::NestedPageFault(NestedPageFaultInfo {
+                    gpa: vmcs::ro::GUEST_PHYSICAL_ADDR_FULL.read(),
+                    write: qualification.get_bit(1),
+                    execute: qualification.get_bit(2),
+                })
+            }
+            VMX_EXIT_REASON_PREEMPTION_TIMER => {
+                if let Some(timer) = &mut self.timer {
+                    timer.restart(rdtsc());
+                }
+                VmExitReason::PreemptionTimer
+            }
+            VMX_EXIT_REASON_VMCLEAR..=VMX_EXIT_REASON_VMXON
+            | VMX_EXIT_REASON_INVEPT
+            | VMX_EXIT_REASON_INVVPID => {
+                // VMX instructions cause VM-exits unconditionally in VMX
+                // non-root operation. Emulate them with nested virtualization.
+                // Otherwise, VMX is not reported to the guest. Deliver #UD as
+                // the processor without VMX does.
+                // See: 26.1.2 Instructions That Cause VM Exits Unconditionally
+                if nested::is_enabled() {
+                    return self.emulate_vmx_instruction();
+                }
+                let ud = Event::Exception {
+                    vector: 6,
+                    error_code: None,
+                };
+                if let Err(err) = event::inject_event(self, ud) {
+                    log::error!("Could not inject #UD: {err}");
+                }
+                VmExitReason::VirtualizationInstruction
+            }
+            VMX_EXIT_REASON_VMFUNC => self.switch_view(),
             _ => {
                 log::error!("{:#x?}", self.vmcs);
                 panic!(
                     "Unhandled VM-exit reason: {:?}",
-                    vmread(vmcs::ro::EXIT_REASON)
+                    vmcs::ro::EXIT_REASON.read()
                 )
             }
         }
     }
 
-    fn regs(&mut self) -> &mut Registers {
-        &mut self.registers
+    /// Injects the event being delivered when the VM-exit occurred again, so
+    /// that it is not lost. VM-exit clears the VM-entry interruption
+    /// information, so nothing else is pending.
+    // See: 28.2.4 Information for VM Exits During Event Delivery
+    fn reinject_vectoring_event(&mut self) {
+        self.set_pending_event(Event::from_raw(
+            vmcs::ro::IDT_VECTORING_INFO.read(),
+            vmcs::ro::IDT_VECTORING_ERR_CODE.read(),
+        ));
+    }
+
+    /// Emulates the VMX instruction the guest executed with nested
+    /// virtualization. See `nested`.
+    fn emulate_vmx_instruction(&mut self) -> VmExitReason {
+        const VMX_EXIT_REASON_VMLAUNCH: u32 = 20;
+        const VMX_EXIT_REASON_VMRESUME: u32 = 24;
+
+        // The window exits are requested in VMCS02 while L2 runs, and again in
+        // VMCS01 after L1 resumes.
+        let reason = u32::from(vmcs::ro::EXIT_REASON.read() as u16);
+        if matches!(reason, VMX_EXIT_REASON_VMLAUNCH | VMX_EXIT_REASON_VMRESUME) {
+            self.clear_window_exiting();
+        }
+
+        let vmcs01 = self.vmcs.pa();
+        let keep_dr7 = self.debug.is_active();
+        let mut state = self.nested.take();
+        let emulation = nested::emulate(&mut state, self, vmcs01, reason, keep_dr7);
+        self.nested = state;
+        match emulation {
+            Emulation::Completed | Emulation::Entered => VmExitReason::VirtualizationInstruction,
+            Emulation::InitLatched => {
+                self.handle_init_signal();
+                VmExitReason::InitSignal
+            }
+        }
+    }
+
+    /// Runs L2 with VMCS02 until VM-exit occurs, after applying the pending
+    /// changes for L1 onto VMCS01 and EPT01. Returns the reason of the VM-exit
+    /// L0 handles for L2, or `NestedGuest` if nothing else is to be done,
+    /// including when the VM-exit is reflected into L1. See `nested`.
+    fn run_l2(&mut self) -> VmExitReason {
+        // See: Table 25-19. Format of the VM-Exit Interruption-Information Field
+        const NMI_INTERRUPTION_INFO: u32 = 1 << 31 | 2 << 8 | 2;
+
+        vmptrld(&mut self.vmcs).unwrap();
+        self.sync_hooks();
+        self.sync_protections();
+        self.sync_hidden_memory();
+        self.sync_dirty_tracking();
+        self.sync_convertible_pages();
+        hw_breakpoint::sync(self);
+        self.sync_breakpoint_markers();
+        self.nested.as_mut().unwrap().load_vmcs02();
+        self.flush_tlb();
+        let nested = self.nested.as_mut().unwrap();
+        nested.refresh_ept02();
+        // Without EPT12, L2 runs with EPT01 of the default view, unless in the
+        // step view.
+        if !nested.uses_ept02() && self.step_eptp.is_none() {
+            vmcs::control::EPTP_FULL.write(shared_guest_data().epts.read().eptp().0);
+        }
+
+        // NMIs and external interrupts for L1 cause VM-exits from L2 if L1
+        // intercepts them. Otherwise, they are injected into L2. An external
+        // interrupt not acknowledged on VM-exit stays queued for L1.
+        // See: 26.2 OTHER CAUSES OF VM EXITS
+        self.nmi_pending |= take_host_nmi();
+        if self.nmi_pending && nested.l1_pin_based(vmcs::control::PinbasedControls::NMI_EXITING) {
+            self.nmi_pending = false;
+            return self
+                .reflect_into_l1(Some((EXIT_REASON_EXCEPTION_OR_NMI, NMI_INTERRUPTION_INFO)));
+        }
+        if !self.interrupts.is_empty()
+            && nested.l1_pin_based(vmcs::control::PinbasedControls::EXTERNAL_INTERRUPT_EXITING)
+        {
+            let info = if nested.l1_acknowledges_interrupts() {
+                u32::from(self.interrupts.pop().unwrap()) | 1 << 31
+            } else {
+                0
+            };
+            return self.reflect_into_l1(Some((EXIT_REASON_EXTERNAL_INTERRUPT, info)));
+        }
+        self.inject_pending_nmi();
+        self.inject_pending_interrupt();
+
+        // Injecting the events may have cleared the window exits L1 requests.
+        let nested = self.nested.as_ref().unwrap();
+        let l1_controls = [
+            vmcs::control::PrimaryControls::INTERRUPT_WINDOW_EXITING,
+            vmcs::control::PrimaryControls::NMI_WINDOW_EXITING,
+            vmcs::control::PrimaryControls::MONITOR_TRAP_FLAG,
+        ]
+        .into_iter()
+        .filter(|&controls| nested.l1_primary(controls))
+        .collect();
+        update_primary_controls(l1_controls, true);
+        if self.tsc.enabled() {
+            vmcs::control::TSC_OFFSET_FULL
+                .write(self.tsc.on_entry().wrapping_add(nested.tsc_offset12()));
+        }
+        if let Some(timer) = &self.timer {
+            vmcs::guest::VMX_PREEMPTION_TIMER_VALUE
+                .write(timer.timer_value(rdtsc(), self.timer_rate));
+        }
+
+        if self.run_current().is_err() {
+            let vmcs01 = self.vmcs.pa();
+            let mut state = self.nested.take().unwrap();
+            state.fail_entry(self, vmcs01);
+            self.nested = Some(state);
+            return VmExitReason::NestedGuest;
+        }
+
+        let l0_step = self.single_step.is_pending();
+        let mut state = self.nested.take().unwrap();
+        let route = state.route(self, l0_step);
+        self.nested = Some(state);
+        match route {
+            Route::Reflect => self.reflect_into_l1(None),
+            Route::Host => self.handle_vm_exit(),
+            Route::NestedPageFault(_) | Route::Resolved => {
+                self.reinject_vectoring_event();
+                self.reblock_nmi_if_unblocked(vmcs::ro::EXIT_QUALIFICATION.read());
+                match route {
+                    Route::NestedPageFault(info) => VmExitReason::NestedPageFault(info),
+                    _ => VmExitReason::NestedGuest,
+                }
+            }
+        }
+    }
+
+    /// Reflects the VM-exit from L2, or the one `synthetic` describes, into L1,
+    /// which resumes with VMCS01. See `NestedVmx::reflect`.
+    fn reflect_into_l1(&mut self, synthetic: Option<(u32, u32)>) -> VmExitReason {
+        let vmcs01 = self.vmcs.pa();
+        let keep_dr7 = self.debug.is_active();
+        let mut state = self.nested.take().unwrap();
+        state.reflect(self, vmcs01, synthetic, keep_dr7);
+        self.nested = Some(state);
+
+        // VMCS01 has no window exits since VMLAUNCH or VMRESUME, and the step
+        // L0 requested for L2 completes in L1 instead.
+        self.nmi_window_exiting = false;
+        self.interrupt_window_exiting = false;
+        if self.single_step.is_pending() {
+            update_primary_controls(vmcs::control::PrimaryControls::MONITOR_TRAP_FLAG, true);
+        }
+        VmExitReason::NestedGuest
+    }
+
+    /// Disables NMI-window and interrupt-window exiting in the current VMCS.
+    fn clear_window_exiting(&mut self) {
+        self.nmi_window_exiting = false;
+        self.interrupt_window_exiting = false;
+        update_primary_controls(
+            vmcs::control::PrimaryControls::NMI_WINDOW_EXITING
+                | vmcs::control::PrimaryControls::INTERRUPT_WINDOW_EXITING,
+            false,
+        );
+    }
+
+    /// Applies changes of the hooks onto the EPT if any. Each processor does
+    /// this and invalidates its own cached EPT translations, instead of sending
+    /// IPIs to other processors from the host.
+    fn sync_hooks(&mut self) {
+        let generation = ept_hook::generation();
+        if self.hook_generation == generation {
+            return;
+        }
+
+        // Try again on the next VM-exit if the guest on this processor owns the
+        // lock of the hook manager.
+        let Some(hook_manager) = ept_hook::try_hook_manager() else {
+            return;
+        };
+
+        let mut epts = shared_guest_data().epts.write();
+        epts.apply_hooks(&hook_manager);
+        epts.apply_views(&hook_manager);
+        if epts.has_views() {
+            self.enable_vm_functions();
+        }
+        tlb::flush_guest(self, FlushScope::GuestPhysical);
+        self.hook_generation = generation;
+        percpu::current()
+            .hook_generation
+            .store(generation, Ordering::Release);
+    }
+
+    /// Applies changes of the protections onto the EPT if any, the same way as
+    /// `sync_hooks`.
+    fn sync_protections(&mut self) {
+        let generation = memory_protection::generation();
+        if self.protection_generation == generation {
+            return;
+        }
+
+        let Some(protections) = memory_protection::try_protections() else {
+            return;
+        };
+
+        let mut epts = shared_guest_data().epts.write();
+        epts.apply_protections(&protections);
+        tlb::flush_guest(self, FlushScope::GuestPhysical);
+        self.protection_generation = generation;
+    }
+
+    /// Hides the memory of the hypervisor in the EPT once requested.
+    fn sync_hidden_memory(&mut self) {
+        if self.host_memory_hidden {
+            return;
+        }
+        let Some(pages) = hidden_memory::pages() else {
+            return;
+        };
+
+        let mut epts = shared_guest_data().epts.write();
+        epts.hide(pages);
+        tlb::flush_guest(self, FlushScope::GuestPhysical);
+        self.host_memory_hidden = true;
+    }
+
+    /// Updates the EPTP to enable or disable the accessed and dirty flags, and
+    /// invalidates cached EPT translations to have the processor set the flags
+    /// cleared on harvest, if dirty tracking changed.
+    fn sync_dirty_tracking(&mut self) {
+        let generation = dirty_tracking::generation();
+        if self.dirty_tracking_generation == generation {
+            return;
+        }
+
+        // Keep the guest in the current view.
+        let mut epts = shared_guest_data().epts.write();
+        epts.set_access_dirty(dirty_tracking::is_enabled());
+        let eptp = epts.eptp_of_view(self.current_eptp());
+        drop(epts);
+        self.set_current_eptp(eptp.0);
+        tlb::flush_guest(self, FlushScope::GuestPhysical);
+        self.dirty_tracking_generation = generation;
+    }
+
+    /// Applies changes of the pages convertible to virtualization exceptions
+    /// onto the EPT if any, the same way as `sync_hooks`.
+    fn sync_convertible_pages(&mut self) {
+        let generation = virtualization_exception::generation();
+        if self.convertible_generation == generation {
+            return;
+        }
+
+        let Some(pages) = virtualization_exception::try_convertible_pages() else {
+            return;
+        };
+
+        let mut epts = shared_guest_data().epts.write();
+        epts.apply_convertible_pages(&pages);
+        tlb::flush_guest(self, FlushScope::GuestPhysical);
+        self.convertible_generation = generation;
+    }
+
+    /// Performs the flushes requested with `tlb` since the last VM-entry.
+    fn flush_tlb(&mut self) {
+        // Translations of guest linear addresses are invalidated on VM-entry
+        // if VPIDs are not enabled.
+        // See: 29.4.3.3 Guidelines for Use of the INVVPID Instruction
+        let flushes = tlb::take();
+        if flushes.physical {
+            shared_guest_data().epts.read().invalidate();
+            if let Some(nested) = &mut self.nested {
+                nested.reset_ept02();
+            }
+        }
+        if let (true, Some(vpid)) = (flushes.linear, self.vpid) {
+            vpid::invvpid(InvvpidType::SingleContext, vpid);
+        }
+    }
+
+    /// Intercepts #BP while any breakpoint marker is registered.
+    fn sync_breakpoint_markers(&mut self) {
+        const BP_VECTOR: u32 = 3;
+
+        if let Some(intercept) = breakpoint_marker::sync(&mut self.marker_generation) {
+            let bitmap = vmcs::control::EXCEPTION_BITMAP.read();
+            vmcs::control::EXCEPTION_BITMAP.write(if intercept {
+                bitmap | 1 << BP_VECTOR
+            } else {
+                bitmap & !(1 << BP_VECTOR)
+            });
+        }
+    }
+
+    /// Lets the guest switch EPT views with `VMFUNC` leaf 0, if not yet.
+    ///
+    /// VM functions are enabled with none of them enabled in the VM-function
+    /// controls, so that `VMFUNC` causes VM-exit instead of #UD, and the view
+    /// is switched by `switch_view`. EPTP switching by the processor would let
+    /// any code in the guest, including at CPL 3, switch views.
+    // See: 25.6.14 VM-Function Controls
+    // See: 26.5.6.1 Enabling VM Functions
+    fn enable_vm_functions(&mut self) {
+        if self.vm_functions {
+            return;
+        }
+        vmcs::control::VM_FUNCTION_CONTROLS_FULL.write(0);
+        update_secondary_controls(vmcs::control::SecondaryControls::ENABLE_VM_FUNCTIONS, true);
+        self.vm_functions = true;
+    }
+
+    /// Switches to the EPT view in ECX as `VMFUNC` leaf 0 does, if the guest
+    /// executed it at CPL 0 for a view that exists. Otherwise, delivers #UD as
+    /// if VM functions were not enabled. L2 is not in any view, and gets #UD
+    /// too.
+    // See: 26.5.6.3 EPTP Switching
+    fn switch_view(&mut self) -> VmExitReason {
+        let view = self.registers.rcx as u32 as usize;
+        let eptp = (self.registers.rax as u32 == 0 && self.cpl() == 0 && !self.in_nested_guest())
+            .then(|| shared_guest_data().epts.read().view_eptp(view))
+            .flatten();
+        let Some(eptp) = eptp else {
+            let ud = Event::Exception {
+                vector: 6,
+                error_code: None,
+            };
+            if let Err(err) = event::inject_event(self, ud) {
+                log::error!("Could not inject #UD: {err}");
+            }
+            return VmExitReason::ViewSwitch;
+        };
+
+        // The step view, if active, is left as is, and the guest is in the new
+        // view after the step. See `set_current_eptp`.
+        self.set_current_eptp(eptp.0);
+        if self.ve_enabled {
+            vmcs::control::EPTP_INDEX.write(view as u16);
+        }
+        self.registers.rip += u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read());
+        VmExitReason::ViewSwitch
+    }
+
+    /// Handles the EPT violation on the hooked page containing `info.gpa`,
+    /// whose shadow page is at `shadow_pa`.
+    ///
+    /// A data access is single-stepped with the original page mapped readable
+    /// and writable in the step view. If the instruction is on the hooked page
+    /// too, fetching it then violates the step view, and the shadow page is
+    /// mapped readable and executable in its place. The reads of the page by
+    /// that instruction observe the shadow page, and a write is emulated.
+    fn handle_hooked_page_violation(&mut self, info: &NestedPageFaultInfo, shadow_pa: u64) {
+        if self.step_view.maps(info.gpa, shadow_pa) {
+            self.end_step_view();
+            let result = instruction_decoder::emulate(self);
+            memory_protection::complete(self, result);
+        } else if info.execute && self.step_eptp.is_some() {
+            self.step_with_page(info.gpa, shadow_pa, Permissions::READ_EXECUTE);
+        } else if info.execute {
+            // The shadow page is executable in the EPT. The processor cached
+            // the translation before the hook was applied.
+            tlb::flush_guest(self, FlushScope::GuestPhysical);
+        } else {
+            self.step_with_page(info.gpa, info.gpa, Permissions::READ_WRITE);
+        }
+    }
+
+    /// Maps the page containing `gpa` to `pa` with `permissions` in the step
+    /// view and switches to it until the current instruction completes. The
+    /// other pages are mapped as in the default view for the instruction.
+    fn step_with_page(&mut self, gpa: u64, pa: u64, permissions: Permissions) {
+        if !self.request_step(gpa) {
+            return;
+        }
+
+        let epts = shared_guest_data().epts.read();
+        let eptp = epts.map_in_step_view(&mut self.step_view, gpa, pa, permissions);
+        drop(epts);
+        if self.step_eptp.is_none() {
+            self.step_eptp = Some(vmcs::control::EPTP_FULL.read());
+        }
+        vmcs::control::EPTP_FULL.write(eptp.0);
+    }
+
+    /// Switches back from the step view to the EPTP in use before
+    /// `step_with_page`, if the step view is in use.
+    fn end_step_view(&mut self) {
+        if let Some(eptp) = self.step_eptp.take() {
+            vmcs::control::EPTP_FULL.write(eptp);
+            self.step_view.clear();
+        }
+    }
+
+    /// Returns the EPTP of the view the guest is in, excluding the step view.
+    fn current_eptp(&self) -> u64 {
+        self.step_eptp
+            .unwrap_or_else(|| vmcs::control::EPTP_FULL.read())
+    }
+
+    /// Updates the EPTP returned by `current_eptp`.
+    fn set_current_eptp(&mut self, eptp: u64) {
+        match &mut self.step_eptp {
+            Some(step_eptp) => *step_eptp = eptp,
+            None => vmcs::control::EPTP_FULL.write(eptp),
+        }
+    }
+
+    /// Lets the guest complete the access to the protected page containing
+    /// `gpa` by lifting the protection in the step view while single-stepping
+    /// the instruction. The protection stays in effect on the other processors.
+    fn allow_access_once(&mut self, gpa: u64) {
+        // L2 single-steps with the page mapped in EPT02 instead. See `nested`.
+        match &mut self.nested {
+            Some(nested) if nested.uses_ept02() => {
+                nested.map_for_step();
+                let _ = self.request_step(gpa);
+            }
+            _ => self.step_with_page(gpa, gpa, Permissions::ALL),
+        }
+    }
+
+    /// Requests the single-step of the instruction accessing `gpa`. Returns
+    /// whether the MTF is armed. The step may be already requested for another
+    /// page of the same instruction, or for other purposes.
+    fn request_step(&mut self, gpa: u64) -> bool {
+        match self.single_step(Box::new(|_| {})) {
+            Ok(()) | Err(SingleStepError::Busy) => true,
+            Err(err) => {
+                log::error!("Could not single-step the access to {gpa:#x?}: {err}");
+                false
+            }
+        }
+    }
+
+    /// Stops delivering external interrupts through the virtual APIC, for the
+    /// guest leaving the x2APIC mode. See `apicv`.
+    fn disable_apicv(&mut self) {
+        let Some(mut apicv) = self.apicv.take() else {
+            return;
+        };
+        apicv.deactivate();
+
+        let pin_based = vmcs::control::PinbasedControls::EXTERNAL_INTERRUPT_EXITING
+            | vmcs::control::PinbasedControls::POSTED_INTERRUPTS;
+        vmcs::control::PINBASED_EXEC_CONTROLS
+            .write(vmcs::control::PINBASED_EXEC_CONTROLS.read() & !pin_based.bits());
+        vmcs::control::VMEXIT_CONTROLS.write(
+            vmcs::control::VMEXIT_CONTROLS.read()
+                & !vmcs::control::ExitControls::ACK_INTERRUPT_ON_EXIT.bits(),
+        );
+        update_primary_controls(vmcs::control::PrimaryControls::USE_TPR_SHADOW, false);
+        update_secondary_controls(
+            vmcs::control::SecondaryControls::VIRTUALIZE_X2APIC
+                | vmcs::control::SecondaryControls::VIRTUALIZE_APIC_REGISTER
+                | vmcs::control::SecondaryControls::VIRTUAL_INTERRUPT_DELIVERY,
+            false,
+        );
+        vmcs::guest::INTERRUPT_STATUS.write(0);
+        vmcs::control::MSR_BITMAPS_ADDR_FULL.write(shared_guest_data().msr_bitmaps.pa());
     }
-}
 
-impl VmxGuest {
     /// Initializes the control fields of the VMCS.
     fn initialize_control(&self) {
+        const EXIT_LOAD_CET_STATE: u32 = 1 << 28;
+        const ENTRY_LOAD_CET_STATE: u32 = 1 << 20;
+
         // - Set HOST_ADDRESS_SPACE_SIZE to run the host on the 64bit mode.
         // - Set IA32E_MODE_GUEST to run the guest on the 64bit mode.
-        vmwrite(
-            vmcs::control::VMEXIT_CONTROLS,
-            Self::adjust_vmx_control(
-                VmxControl::VmExit,
-                vmcs::control::ExitControls::HOST_ADDRESS_SPACE_SIZE.bits() as _,
-            ),
-        );
-        vmwrite(
-            vmcs::control::VMENTRY_CONTROLS,
-            Self::adjust_vmx_control(
-                VmxControl::VmEntry,
-                vmcs::control::EntryControls::IA32E_MODE_GUEST.bits() as _,
-            ),
-        );
-
-        // Nothing to enable in the PINBASED_EXEC_CONTROLS.
-        vmwrite(
-            vmcs::control::PINBASED_EXEC_CONTROLS,
-            Self::adjust_vmx_control(VmxControl::PinBased, 0),
-        );
+        // - Set "save IA32_EFER" and "load IA32_EFER" to switch IA32_EFER, so
+        //   that the guest can clear LME while the host keeps long mode.
+        // - Set "save IA32_PAT" and "load IA32_PAT" to switch IA32_PAT, so that
+        //   the memory types of the host mappings do not depend on the guest.
+        // - Set "load CET state" to switch the supervisor CET state. See `cet`.
+        let mut exit_controls = (vmcs::control::ExitControls::HOST_ADDRESS_SPACE_SIZE
+            | vmcs::control::ExitControls::SAVE_IA32_EFER
+            | vmcs::control::ExitControls::LOAD_IA32_EFER
+            | vmcs::control::ExitControls::SAVE_IA32_PAT
+            | vmcs::control::ExitControls::LOAD_IA32_PAT)
+            .bits();
+        let mut entry_controls = (vmcs::control::EntryControls::IA32E_MODE_GUEST
+            | vmcs::control::EntryControls::LOAD_IA32_EFER
+            | vmcs::control::EntryControls::LOAD_IA32_PAT)
+            .bits();
+        if cet::is_switched() {
+            exit_controls |= EXIT_LOAD_CET_STATE;
+            entry_controls |= ENTRY_LOAD_CET_STATE;
+        }
+        // - Set "acknowledge interrupt on exit" with APICv. See `apicv`.
+        if self.apicv.is_some() {
+            exit_controls |= vmcs::control::ExitControls::ACK_INTERRUPT_ON_EXIT.bits();
+        }
+        vmcs::control::VMEXIT_CONTROLS
+            .write(Self::adjust_vmx_control(VmxControl::VmExit, exit_controls as _) as u32);
+        vmcs::control::VMENTRY_CONTROLS
+            .write(Self::adjust_vmx_control(VmxControl::VmEntry, entry_controls as _) as u32);
+
+        // NMIs cause VM-exit, so that NMIs occurring in the host are not lost.
+        // NMIs occurring in either are injected into the guest. Virtual NMIs are
+        // required for NMI-window exiting.
+        //
+        // The VMX-preemption timer is activated for `preemption_timer`, if
+        // enabled.
+        let mut pin_based = vmcs::control::PinbasedControls::NMI_EXITING
+            | vmcs::control::PinbasedControls::VIRTUAL_NMIS;
+        if self.timer.is_some() {
+            pin_based |= vmcs::control::PinbasedControls::VMX_PREEMPTION_TIMER;
+        }
+        // With APICv, external interrupts cause VM-exit and are acknowledged,
+        // and the posted interrupts are processed. See `apicv`.
+        if self.apicv.is_some() {
+            pin_based |= vmcs::control::PinbasedControls::EXTERNAL_INTERRUPT_EXITING
+                | vmcs::control::PinbasedControls::POSTED_INTERRUPTS;
+        }
+        vmcs::control::PINBASED_EXEC_CONTROLS
+            .write(Self::adjust_vmx_control(VmxControl::PinBased, pin_based.bits() as _) as u32);
 
         // The processor-based VM-execution controls govern the handling of
         // synchronous events, mainly those caused by the execution of specific
         // instructions.
         //
         // - MSR bitmaps are used; this is not to cause VM-exit as much as possible.
-        //   We are setting the MSR bitmaps that are all cleared. This prevents
-        //   VM-exits from occurring when 0x0 - 0x1fff and 0xc0000000 - 0xc0001fff
-        //   are accessed. VM-exit still occurs if outside the range is accessed,
-        //   and it is not possible to prevent this.
+        //   We are setting the MSR bitmaps that are cleared except for MSRs in
+        //   `SharedHostData::msr_intercepts` and writes to IA32_MTRR_DEF_TYPE.
+        //   This prevents VM-exits from occurring when the other MSRs in 0x0 -
+        //   0x1fff and 0xc0000000 - 0xc0001fff are accessed. VM-exit still
+        //   occurs if outside the range is accessed, and it is not possible to
+        //   prevent this.
+        //
+        // - The I/O bitmaps are used only if any port is set in
+        //   `SharedHostData::io_intercepts`. Otherwise, no I/O instruction
+        //   causes VM-exit.
         //
         // - The secondary processor-based controls are used; this is to:
         //   - Enable EPT and unrestricted guest to allow a real-mode guest, which
@@ -189,32 +1658,102 @@ impl VmxGuest {
         //     instructions. Those instructions are used in Windows 10+. If those
         //     are not set, attempt to execute them causes #UD, which results in
         //     a bug check.
-        vmwrite(
-            vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
-            Self::adjust_vmx_control(
-                VmxControl::ProcessorBased,
-                (vmcs::control::PrimaryControls::USE_MSR_BITMAPS
-                    | vmcs::control::PrimaryControls::SECONDARY_CONTROLS)
-                    .bits() as _,
-            ),
-        );
-        vmwrite(
-            vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS,
-            Self::adjust_vmx_control(
-                VmxControl::ProcessorBased2,
-                (vmcs::control::SecondaryControls::ENABLE_EPT
-                    | vmcs::control::SecondaryControls::UNRESTRICTED_GUEST
-                    | vmcs::control::SecondaryControls::ENABLE_RDTSCP
-                    | vmcs::control::SecondaryControls::ENABLE_INVPCID
-                    | vmcs::control::SecondaryControls::ENABLE_XSAVES_XRSTORS)
-                    .bits() as _,
-            ),
-        );
+        let mut primary_controls = vmcs::control::PrimaryControls::USE_MSR_BITMAPS
+            | vmcs::control::PrimaryControls::SECONDARY_CONTROLS;
+        if !SHARED_HOST_DATA.get().unwrap().io_intercepts.is_empty() {
+            primary_controls |= vmcs::control::PrimaryControls::USE_IO_BITMAPS;
+        }
+        // - CR3-load exiting is used for `SharedHostData::cr3_tracking`.
+        if SHARED_HOST_DATA.get().unwrap().cr3_tracking.is_enabled() {
+            primary_controls |= vmcs::control::PrimaryControls::CR3_LOAD_EXITING;
+        }
+        // - TSC offsetting is used if the guest TSC is compensated or scaled.
+        //   The TSC multiplier applies only when TSC offsetting is enabled.
+        //   See: 27.3 CHANGES TO INSTRUCTION BEHAVIOR IN VMX NON-ROOT OPERATION
+        let tsc_scale = self.tsc.scale();
+        if self.tsc.enabled() || tsc_scale.is_some() {
+            primary_controls |= vmcs::control::PrimaryControls::USE_TSC_OFFSETTING;
+        }
+        let mut secondary_controls = vmcs::control::SecondaryControls::empty();
+        // - The TPR shadow, the x2APIC virtualization and virtual-interrupt
+        //   delivery are used with APICv, along with the MSR bitmaps of the
+        //   vCPU. See `apicv`.
+        if self.apicv.is_some() {
+            primary_controls |= vmcs::control::PrimaryControls::USE_TPR_SHADOW;
+            secondary_controls |= vmcs::control::SecondaryControls::VIRTUALIZE_X2APIC
+                | vmcs::control::SecondaryControls::VIRTUALIZE_APIC_REGISTER
+                | vmcs::control::SecondaryControls::VIRTUAL_INTERRUPT_DELIVERY;
+        }
+        if tsc_scale.is_some() {
+            secondary_controls |= vmcs::control::SecondaryControls::USE_TSC_SCALING;
+        }
+        // - VPIDs are used if supported. Invalidate translations tagged with
+        //   the VPID left from earlier virtualization, if any.
+        if let Some(vpid) = self.vpid {
+            secondary_controls |= vmcs::control::SecondaryControls::ENABLE_VPID;
+            vmcs::control::VPID.write(vpid);
+            vpid::invvpid(InvvpidType::SingleContext, vpid);
+        }
+        // - WBINVD exiting is used if `SharedHostData::cache_invalidation`
+        //   ignores it. INVD causes VM-exit regardless.
+        if SHARED_HOST_DATA
+            .get()
+            .unwrap()
+            .cache_invalidation
+            .intercepts_wbinvd()
+        {
+            secondary_controls |= vmcs::control::SecondaryControls::WBINVD_EXITING;
+        }
+        vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS.write(Self::adjust_vmx_control(
+            VmxControl::ProcessorBased,
+            primary_controls.bits() as _,
+        ) as u32);
+        vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS.write(Self::adjust_vmx_control(
+            VmxControl::ProcessorBased2,
+            (vmcs::control::SecondaryControls::ENABLE_EPT
+                | vmcs::control::SecondaryControls::UNRESTRICTED_GUEST
+                | vmcs::control::SecondaryControls::ENABLE_RDTSCP
+                | vmcs::control::SecondaryControls::ENABLE_INVPCID
+                | vmcs::control::SecondaryControls::ENABLE_XSAVES_XRSTORS
+                | secondary_controls)
+                .bits() as _,
+        ) as u32);
+        if let Some(scale) = tsc_scale {
+            // The TSC multiplier has 48 fractional bits.
+            vmcs::control::TSC_MULTIPLIER_FULL.write(scale << 16);
+        }
 
-        let msr_bitmaps_va = SHARED_GUEST_DATA.msr_bitmaps.as_ref() as *const _;
-        let msr_bitmaps_pa = platform_ops::get().pa(msr_bitmaps_va as *const _);
-        vmwrite(vmcs::control::MSR_BITMAPS_ADDR_FULL, msr_bitmaps_pa);
-        vmwrite(vmcs::control::EPTP_FULL, SHARED_GUEST_DATA.epts.eptp().0);
+        // Writes to CR0 and CR4 changing the bits set in the guest/host masks
+        // cause VM-exit. The guest reads those bits from the read shadows.
+        // See: 25.6.6 Guest/Host Masks and Read Shadows for CR0 and CR4
+        let cr_intercepts = &SHARED_HOST_DATA.get().unwrap().cr_intercepts;
+        // CR0.PG is guarded regardless, to switch the paging mode consistently.
+        // See `write_cr`.
+        vmcs::control::CR0_GUEST_HOST_MASK
+            .write(cr_intercepts.cr0_bits() | Cr0::CR0_ENABLE_PAGING.bits() as u64);
+        // CR4.VMXE is guarded with nested virtualization, so that the guest
+        // reads it as written. See `nested`.
+        let mut cr4_mask = cr_intercepts.cr4_bits();
+        if nested::is_enabled() {
+            cr4_mask |= Cr4::CR4_ENABLE_VMX.bits() as u64;
+        }
+        vmcs::control::CR4_GUEST_HOST_MASK.write(cr4_mask);
+
+        // #MC causes VM-exit, so that the errors are logged before #MC is
+        // injected into the guest. See `machine_check`.
+        // See: 25.6.3 Exception Bitmap
+        const MC_VECTOR: u32 = 18;
+        vmcs::control::EXCEPTION_BITMAP.write(1 << MC_VECTOR);
+
+        vmcs::control::MSR_BITMAPS_ADDR_FULL.write(
+            self.apicv
+                .as_ref()
+                .map_or(shared_guest_data().msr_bitmaps.pa(), Apicv::msr_bitmaps_pa),
+        );
+        let io_bitmaps_pa = shared_guest_data().io_bitmaps.pa();
+        vmcs::control::IO_BITMAP_A_ADDR_FULL.write(io_bitmaps_pa);
+        vmcs::control::IO_BITMAP_B_ADDR_FULL.write(io_bitmaps_pa + BASE_PAGE_SIZE as u64);
+        vmcs::control::EPTP_FULL.write(shared_guest_data().epts.read().eptp().0);
     }
 
     /// Initializes the guest-state fields of the VMCS.
@@ -224,123 +1763,102 @@ impl VmxGuest {
         let idtr = sidt();
         let gdtr = sgdt();
 
-        vmwrite(vmcs::guest::ES_SELECTOR, es().bits());
-        vmwrite(vmcs::guest::CS_SELECTOR, cs().bits());
-        vmwrite(vmcs::guest::SS_SELECTOR, ss().bits());
-        vmwrite(vmcs::guest::DS_SELECTOR, ds().bits());
-        vmwrite(vmcs::guest::FS_SELECTOR, fs().bits());
-        vmwrite(vmcs::guest::GS_SELECTOR, gs().bits());
-        vmwrite(vmcs::guest::TR_SELECTOR, tr().bits());
-        vmwrite(vmcs::guest::LDTR_SELECTOR, ldtr().bits());
-
-        vmwrite(vmcs::guest::ES_LIMIT, lsl(es()));
-        vmwrite(vmcs::guest::CS_LIMIT, lsl(cs()));
-        vmwrite(vmcs::guest::SS_LIMIT, lsl(ss()));
-        vmwrite(vmcs::guest::DS_LIMIT, lsl(ds()));
-        vmwrite(vmcs::guest::FS_LIMIT, lsl(fs()));
-        vmwrite(vmcs::guest::GS_LIMIT, lsl(gs()));
-        vmwrite(vmcs::guest::TR_LIMIT, lsl(tr()));
-
-        vmwrite(
-            vmcs::guest::ES_ACCESS_RIGHTS,
-            Self::access_rights(lar(es())),
-        );
-        vmwrite(
-            vmcs::guest::CS_ACCESS_RIGHTS,
-            Self::access_rights(lar(cs())),
-        );
-        vmwrite(
-            vmcs::guest::SS_ACCESS_RIGHTS,
-            Self::access_rights(lar(ss())),
-        );
-        vmwrite(
-            vmcs::guest::DS_ACCESS_RIGHTS,
-            Self::access_rights(lar(ds())),
-        );
-        vmwrite(
-            vmcs::guest::FS_ACCESS_RIGHTS,
-            Self::access_rights(lar(fs())),
-        );
-        vmwrite(
-            vmcs::guest::GS_ACCESS_RIGHTS,
-            Self::access_rights(lar(gs())),
-        );
-        vmwrite(
-            vmcs::guest::TR_ACCESS_RIGHTS,
-            Self::access_rights(lar(tr())),
-        );
-        vmwrite(vmcs::guest::LDTR_ACCESS_RIGHTS, Self::access_rights(0));
-
-        vmwrite(vmcs::guest::FS_BASE, rdmsr(x86::msr::IA32_FS_BASE));
-        vmwrite(vmcs::guest::GS_BASE, rdmsr(x86::msr::IA32_GS_BASE));
-        vmwrite(
-            vmcs::guest::TR_BASE,
+        vmcs::guest::ES_SELECTOR.write(es().bits());
+        vmcs::guest::CS_SELECTOR.write(cs().bits());
+        vmcs::guest::SS_SELECTOR.write(ss().bits());
+        vmcs::guest::DS_SELECTOR.write(ds().bits());
+        vmcs::guest::FS_SELECTOR.write(fs().bits());
+        vmcs::guest::GS_SELECTOR.write(gs().bits());
+        vmcs::guest::TR_SELECTOR.write(tr().bits());
+        vmcs::guest::LDTR_SELECTOR.write(ldtr().bits());
+
+        vmcs::guest::ES_LIMIT.write(lsl(es()));
+        vmcs::guest::CS_LIMIT.write(lsl(cs()));
+        vmcs::guest::SS_LIMIT.write(lsl(ss()));
+        vmcs::guest::DS_LIMIT.write(lsl(ds()));
+        vmcs::guest::FS_LIMIT.write(lsl(fs()));
+        vmcs::guest::GS_LIMIT.write(lsl(gs()));
+        vmcs::guest::TR_LIMIT.write(lsl(tr()));
+
+        vmcs::guest::ES_ACCESS_RIGHTS.write(Self::access_rights(lar(es())));
+        vmcs::guest::CS_ACCESS_RIGHTS.write(Self::access_rights(lar(cs())));
+        vmcs::guest::SS_ACCESS_RIGHTS.write(Self::access_rights(lar(ss())));
+        vmcs::guest::DS_ACCESS_RIGHTS.write(Self::access_rights(lar(ds())));
+        vmcs::guest::FS_ACCESS_RIGHTS.write(Self::access_rights(lar(fs())));
+        vmcs::guest::GS_ACCESS_RIGHTS.write(Self::access_rights(lar(gs())));
+        vmcs::guest::TR_ACCESS_RIGHTS.write(Self::access_rights(lar(tr())));
+        vmcs::guest::LDTR_ACCESS_RIGHTS.write(Self::access_rights(0));
+
+        vmcs::guest::FS_BASE.write(rdmsr(x86::msr::IA32_FS_BASE));
+        vmcs::guest::GS_BASE.write(rdmsr(x86::msr::IA32_GS_BASE));
+        vmcs::guest::TR_BASE.write(
             SegmentDescriptor::try_from_gdtr(&gdtr, tr())
                 .unwrap()
                 .base(),
         );
 
-        vmwrite(vmcs::guest::GDTR_BASE, gdtr.base as u64);
-        vmwrite(vmcs::guest::GDTR_LIMIT, gdtr.limit);
-        vmwrite(vmcs::guest::IDTR_BASE, idtr.base as u64);
-        vmwrite(vmcs::guest::IDTR_LIMIT, idtr.limit);
-
-        vmwrite(
-            vmcs::guest::IA32_DEBUGCTL_FULL,
-            rdmsr(x86::msr::IA32_DEBUGCTL),
-        );
-        vmwrite(
-            vmcs::guest::IA32_SYSENTER_CS,
-            rdmsr(x86::msr::IA32_SYSENTER_CS),
-        );
-        vmwrite(
-            vmcs::guest::IA32_SYSENTER_EIP,
-            rdmsr(x86::msr::IA32_SYSENTER_EIP),
-        );
-        vmwrite(
-            vmcs::guest::IA32_SYSENTER_ESP,
-            rdmsr(x86::msr::IA32_SYSENTER_ESP),
-        );
+        vmcs::guest::GDTR_BASE.write(gdtr.base as u64);
+        vmcs::guest::GDTR_LIMIT.write(gdtr.limit.into());
+        vmcs::guest::IDTR_BASE.write(idtr.base as u64);
+        vmcs::guest::IDTR_LIMIT.write(idtr.limit.into());
+
+        vmcs::guest::IA32_EFER_FULL.write(rdmsr(x86::msr::IA32_EFER));
+        vmcs::guest::IA32_PAT_FULL.write(rdmsr(x86::msr::IA32_PAT));
+        vmcs::guest::IA32_DEBUGCTL_FULL.write(rdmsr(x86::msr::IA32_DEBUGCTL));
+        vmcs::guest::IA32_SYSENTER_CS.write(rdmsr(x86::msr::IA32_SYSENTER_CS) as u32);
+        vmcs::guest::IA32_SYSENTER_EIP.write(rdmsr(x86::msr::IA32_SYSENTER_EIP));
+        vmcs::guest::IA32_SYSENTER_ESP.write(rdmsr(x86::msr::IA32_SYSENTER_ESP));
+        if cet::is_switched() {
+            vmcs::guest::IA32_S_CET.write(rdmsr(cet::IA32_S_CET));
+            vmcs::guest::SSP.write(self.registers.ssp);
+            vmcs::guest::IA32_INTERRUPT_SSP_TABLE_ADDR
+                .write(rdmsr(cet::IA32_INTERRUPT_SSP_TABLE_ADDR));
+        }
 
         // "If the "VMCS shadowing" VM-execution control is 1, (...). Otherwise,
         //  software should set this field to FFFFFFFF_FFFFFFFFH to avoid VM-entry
         //  failures."
         // See: 25.4.2 Guest Non-Register State
-        vmwrite(vmcs::guest::LINK_PTR_FULL, u64::MAX);
+        vmcs::guest::LINK_PTR_FULL.write(u64::MAX);
 
-        vmwrite(vmcs::guest::CR0, cr0().bits() as u64);
-        vmwrite(vmcs::guest::CR3, cr3());
-        vmwrite(vmcs::guest::CR4, cr4().bits() as u64);
+        vmcs::guest::CR0.write(cr0().bits() as u64);
+        vmcs::guest::CR3.write(cr3());
+        vmcs::guest::CR4.write(cr4().bits() as u64);
+        vmcs::control::CR0_READ_SHADOW.write(cr0().bits() as u64);
+        // CR4.VMXE is set only for VMX operation of the host.
+        vmcs::control::CR4_READ_SHADOW
+            .write(cr4().bits() as u64 & !(Cr4::CR4_ENABLE_VMX.bits() as u64));
 
-        vmwrite(vmcs::guest::DR7, unsafe { x86::debugregs::dr7() }.0 as u64);
+        vmcs::guest::DR7.write(unsafe { x86::debugregs::dr7() }.0 as u64);
 
-        vmwrite(vmcs::guest::RSP, self.registers.rsp);
-        vmwrite(vmcs::guest::RIP, self.registers.rip);
-        vmwrite(vmcs::guest::RFLAGS, self.registers.rflags);
+        vmcs::guest::RSP.write(self.registers.rsp);
+        vmcs::guest::RIP.write(self.registers.rip);
+        vmcs::guest::RFLAGS.write(self.registers.rflags);
     }
 
     /// Initializes the host-state fields of the VMCS.
-    fn initialize_host(&self) {
+    fn initialize_host(&self) -> Result<(), HvError> {
         let shared_host = SHARED_HOST_DATA.get().unwrap();
 
-        // Use a custom CR3 if specified. Otherwise, use the current.
-        let cr3 = if let Some(host_pt) = &shared_host.pt {
-            addr_of!(*host_pt.as_ref()) as u64
-        } else {
-            cr3()
-        };
+        // Use the copy of the custom CR3 if specified, or the current, with the
+        // host window.
+        let cr3 = host_window::host_cr3();
 
         // Use a custom GDT, TR, and TSS if specified. Otherwise, use the current.
         let (gdt_base, tr, tss_base) = if let Some(host_gdt_and_tss) = &shared_host.gdts {
-            let gdt_base = addr_of!(host_gdt_and_tss[self.id].gdt[0]) as u64;
-            let tr = host_gdt_and_tss[self.id].tr.unwrap();
-            let tss = host_gdt_and_tss[self.id].tss.as_ref().unwrap();
+            let invalid_gdt = HvError::InvalidHostGdt(self.id);
+            let host_gdt_and_tss = host_gdt_and_tss.get(self.id).ok_or(invalid_gdt)?;
+            let gdt_base = addr_of!(host_gdt_and_tss.gdt[0]) as u64;
+            let tr = host_gdt_and_tss.tr.ok_or(invalid_gdt)?;
+            let tss = host_gdt_and_tss.tss.as_ref().ok_or(invalid_gdt)?;
             let tss_base = tss as *const _ as u64;
             (gdt_base, tr, tss_base)
         } else {
             let gdtr = sgdt();
             let tr = tr();
-            let tss_base = SegmentDescriptor::try_from_gdtr(&gdtr, tr).unwrap().base();
+            let tss_base = SegmentDescriptor::try_from_gdtr(&gdtr, tr)
+                .map_err(|_| HvError::InvalidHostGdt(self.id))?
+                .base();
             (gdtr.base as u64, tr, tss_base)
         };
 
@@ -356,28 +1874,187 @@ impl VmxGuest {
         // "In the selector field for each of CS, SS, DS, ES, FS, GS, and TR,
         //  the RPL (bits 1:0) and the TI flag (bit 2) must be 0."
         // See: 27.2.3 Checks on Host Segment and Descriptor-Table Registers
-        vmwrite(vmcs::host::ES_SELECTOR, es().bits() & !0b111);
-        vmwrite(vmcs::host::CS_SELECTOR, cs().bits() & !0b111);
-        vmwrite(vmcs::host::SS_SELECTOR, ss().bits() & !0b111);
-        vmwrite(vmcs::host::DS_SELECTOR, ds().bits() & !0b111);
-        vmwrite(vmcs::host::FS_SELECTOR, fs().bits() & !0b111);
-        vmwrite(vmcs::host::GS_SELECTOR, gs().bits() & !0b111);
-        vmwrite(vmcs::host::TR_SELECTOR, tr.bits() & !0b111);
+        vmcs::host::ES_SELECTOR.write(es().bits() & !0b111);
+        vmcs::host::CS_SELECTOR.write(cs().bits() & !0b111);
+        vmcs::host::SS_SELECTOR.write(ss().bits() & !0b111);
+        vmcs::host::DS_SELECTOR.write(ds().bits() & !0b111);
+        vmcs::host::FS_SELECTOR.write(fs().bits() & !0b111);
+        vmcs::host::GS_SELECTOR.write(gs().bits() & !0b111);
+        vmcs::host::TR_SELECTOR.write(tr.bits() & !0b111);
+
+        // Let the host switch the extended registers with `XSAVE`.
+        if self.extended.is_some() && is_xsave_supported() {
+            cr4_write(cr4() | Cr4::CR4_ENABLE_OS_XSAVE);
+        }
 
-        vmwrite(vmcs::host::CR0, cr0().bits() as u64);
-        vmwrite(vmcs::host::CR3, cr3);
-        vmwrite(vmcs::host::CR4, cr4().bits() as u64);
+        vmcs::host::CR0.write(cr0().bits() as u64);
+        vmcs::host::CR3.write(cr3);
+        vmcs::host::CR4.write(cr4().bits() as u64);
+
+        vmcs::host::IA32_EFER_FULL.write(rdmsr(x86::msr::IA32_EFER));
+        vmcs::host::IA32_PAT_FULL.write(rdmsr(x86::msr::IA32_PAT));
+        vmcs::host::FS_BASE.write(rdmsr(x86::msr::IA32_FS_BASE));
+        vmcs::host::GS_BASE.write(percpu::host_gs_base(
+            self.id,
+            rdmsr(x86::msr::IA32_GS_BASE),
+            shared_host,
+        ));
+        vmcs::host::TR_BASE.write(tss_base);
+        vmcs::host::GDTR_BASE.write(gdt_base);
+        vmcs::host::IDTR_BASE.write(idt_base);
+
+        // Run the host with supervisor CET disabled, which does not use the
+        // shadow stack of the guest then.
+        if cet::is_switched() {
+            vmcs::host::IA32_S_CET.write(0);
+            vmcs::host::SSP.write(0);
+            vmcs::host::IA32_INTERRUPT_SSP_TABLE_ADDR.write(0);
+        }
+        Ok(())
+    }
 
-        vmwrite(vmcs::host::FS_BASE, rdmsr(x86::msr::IA32_FS_BASE));
-        vmwrite(vmcs::host::GS_BASE, rdmsr(x86::msr::IA32_GS_BASE));
-        vmwrite(vmcs::host::TR_BASE, tss_base);
-        vmwrite(vmcs::host::GDTR_BASE, gdt_base);
-        vmwrite(vmcs::host::IDTR_BASE, idt_base);
+    /// Injects the NMI pending for the guest if the guest can receive it now.
+    /// Otherwise, enables NMI-window exiting to retry as soon as it can.
+    fn inject_pending_nmi(&mut self) {
+        const BLOCKING_BY_MOV_SS: u32 = 1 << 1;
+        const BLOCKING_BY_NMI: u32 = 1 << 3;
+
+        // Take the NMI that occurred in the host, if any.
+        self.nmi_pending |= take_host_nmi();
+
+        // "If the "virtual NMIs" VM-execution control is 1, bit 3 (blocking by
+        //  NMI) must be 0 if the valid bit (bit 31) in the VM-entry
+        //  interruption-information field is 1 and the interruption type (bits
+        //  10:8) in that field has value 2 (indicating NMI)."
+        // "Bit 1 (blocking by MOV-SS) must be 0 if the valid bit (bit 31) in the
+        //  VM-entry interruption-information field is 1 and the interruption type
+        //  (bits 10:8) in that field has value 2, indicating NMI."
+        // See: 27.3.1.5 Checks on Guest Non-Register State
+        if self.nmi_pending {
+            let interruptibility = vmcs::guest::INTERRUPTIBILITY_STATE.read();
+            if interruptibility & (BLOCKING_BY_MOV_SS | BLOCKING_BY_NMI) == 0
+                && event::inject_event(self, Event::Nmi).is_ok()
+            {
+                self.nmi_pending = false;
+            }
+        }
+
+        if self.nmi_window_exiting != self.nmi_pending {
+            self.nmi_window_exiting = self.nmi_pending;
+            update_primary_controls(
+                vmcs::control::PrimaryControls::NMI_WINDOW_EXITING,
+                self.nmi_pending,
+            );
+        }
+    }
+
+    /// Injects the highest external interrupt queued for the guest if the guest
+    /// can receive it now. Otherwise, enables interrupt-window exiting to retry
+    /// as soon as it can.
+    fn inject_pending_interrupt(&mut self) {
+        const BLOCKING_BY_STI: u32 = 1 << 0;
+        const BLOCKING_BY_MOV_SS: u32 = 1 << 1;
+
+        // External interrupts are recognized only when RFLAGS.IF is set and
+        // neither STI nor MOV SS blocks them. Also, only one event can be
+        // injected at a time.
+        // See: 25.4.2 Guest Non-Register State
+        if !self.interrupts.is_empty()
+            && RFlags::from_raw(self.registers.rflags).contains(RFlags::FLAGS_IF)
+            && vmcs::guest::INTERRUPTIBILITY_STATE.read() & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS)
+                == 0
+            && self.pending_event().is_none()
+        {
+            let vector = self.interrupts.pop().unwrap();
+            self.set_pending_event(Some(Event::ExternalInterrupt { vector }));
+        }
+
+        let pending = !self.interrupts.is_empty();
+        if self.interrupt_window_exiting != pending {
+            self.interrupt_window_exiting = pending;
+            update_primary_controls(
+                vmcs::control::PrimaryControls::INTERRUPT_WINDOW_EXITING,
+                pending,
+            );
+        }
+    }
+
+    /// Blocks NMIs again if the VM-exit occurred while the guest executed IRET,
+    /// which unblocked NMIs. Otherwise, the guest could receive an NMI before
+    /// re-executing IRET.
+    /// Handles #DB intercepted for `hw_breakpoint`. Calls the handlers of the
+    /// breakpoints hit, and injects #DB into the guest for the other causes.
+    fn handle_debug_exception(&mut self, interruption_info: u32) -> VmExitReason {
+        const PRIVILEGED_SOFTWARE_EXCEPTION: u32 = 5;
+
+        // DR6 is not updated on VM-exit due to #DB. The exit qualification has
+        // the causes in the same format instead.
+        // See: Table 28-1. Exit Qualification for Debug Exceptions
+        let qualification = vmcs::ro::EXIT_QUALIFICATION.read();
+        let causes = hw_breakpoint::handle_debug_exception(self, qualification);
+        if causes == 0 && qualification.get_bits(0..=3) != 0 {
+            return VmExitReason::DebugException;
+        }
+
+        // VM-exit due to `INT1` occurs before the instruction completes. Inject
+        // #DB as if it completed.
+        if interruption_info.get_bits(8..=10) == PRIVILEGED_SOFTWARE_EXCEPTION {
+            self.registers.rip += u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read());
+        }
+        let debug_exception = Event::Exception {
+            vector: 1,
+            error_code: None,
+        };
+        if let Err(err) = event::inject_event(self, debug_exception) {
+            log::error!("Could not inject #DB: {err}");
+        }
+        VmExitReason::DebugException
+    }
+
+    fn reblock_nmi_if_unblocked(&self, qualification: u64) {
+        const BLOCKING_BY_NMI: u32 = 1 << 3;
+
+        // See: 28.2.3 Information About NMI Unblocking Due to IRET
+        if qualification.get_bit(12) {
+            let interruptibility = vmcs::guest::INTERRUPTIBILITY_STATE.read();
+            vmcs::guest::INTERRUPTIBILITY_STATE.write(interruptibility | BLOCKING_BY_NMI);
+        }
+    }
+
+    /// Returns `TscConfig::scale` if the processor supports TSC scaling.
+    fn tsc_scale() -> Option<u64> {
+        let scale = SHARED_HOST_DATA.get().unwrap().tsc.scale?;
+
+        // The higher 32bits of the capability MSR indicate the controls that
+        // can be 1. See `adjust_vmx_control`.
+        let allowed1 = rdmsr(x86::msr::IA32_VMX_PROCBASED_CTLS2) >> 32;
+        if allowed1 & u64::from(vmcs::control::SecondaryControls::USE_TSC_SCALING.bits()) == 0 {
+            log::warn!("TSC scaling is not supported. Ignoring the TSC scale");
+            return None;
+        }
+        Some(scale)
+    }
+
+    /// Returns the deadline of `preemption_timer` starting now, or `None` if it
+    /// is disabled or the VMX-preemption timer is not supported.
+    fn preemption_timer() -> Option<TimerDeadline> {
+        let timer = &SHARED_HOST_DATA.get().unwrap().preemption_timer;
+        if !timer.is_enabled() {
+            return None;
+        }
+
+        // See: A.3.1 Pin-Based VM-Execution Controls
+        let allowed1 = rdmsr(x86::msr::IA32_VMX_PINBASED_CTLS) >> 32;
+        if allowed1 & u64::from(vmcs::control::PinbasedControls::VMX_PREEMPTION_TIMER.bits()) == 0 {
+            log::warn!("The VMX-preemption timer is not supported. Ignoring the timer");
+            return None;
+        }
+        Some(TimerDeadline::new(rdtsc(), timer.period()))
     }
 
     /// Returns the VM control value that is adjusted in consideration with the
     /// VMX capability MSR.
-    fn adjust_vmx_control(control: VmxControl, requested_value: u64) -> u64 {
+    pub(super) fn adjust_vmx_control(control: VmxControl, requested_value: u64) -> u64 {
         const IA32_VMX_BASIC_VMX_CONTROLS_FLAG: u64 = 1 << 55;
 
         // This determines the right VMX capability MSR based on the value of
@@ -474,62 +2151,65 @@ impl VmxGuest {
     // See: Table 9-1. IA-32 and Intel 64 Processor States Following Power-up,
     //      Reset, or INIT
     fn handle_init_signal(&mut self) {
+        // INIT discards the event being delivered and the queued interrupts, if
+        // any, as it resets the local APIC.
+        self.set_pending_event(None);
+        self.interrupts = InterruptQueue::default();
+        if let Some(apicv) = &mut self.apicv {
+            apicv.reset();
+        }
+
         self.registers.rflags = RFlags::FLAGS_A1.bits();
-        vmwrite(vmcs::guest::RFLAGS, self.registers.rflags);
+        vmcs::guest::RFLAGS.write(self.registers.rflags);
 
         self.registers.rip = 0xfff0;
-        vmwrite(vmcs::guest::RIP, self.registers.rip);
+        vmcs::guest::RIP.write(self.registers.rip);
 
         write_cr2(0);
-        vmwrite(vmcs::guest::CR3, 0u64);
-        vmwrite(vmcs::control::CR0_READ_SHADOW, 0u64);
-        vmwrite(vmcs::control::CR4_READ_SHADOW, 0u64);
+        vmcs::guest::CR3.write(0);
+        tlb::flush_guest(self, FlushScope::GuestLinear);
+        vmcs::control::CR0_READ_SHADOW.write(0);
+        vmcs::control::CR4_READ_SHADOW.write(0);
 
         // Actual guest CR0 and CR4 must fulfill requirements for VMX. Apply those.
-        vmwrite(
-            vmcs::guest::CR0,
-            get_adjusted_guest_cr0(Cr0::CR0_EXTENSION_TYPE).bits() as u64,
-        );
-        vmwrite(
-            vmcs::guest::CR4,
-            get_adjusted_guest_cr4(Cr4::empty()).bits() as u64,
-        );
+        vmcs::guest::CR0.write(get_adjusted_guest_cr0(Cr0::CR0_EXTENSION_TYPE).bits() as u64);
+        vmcs::guest::CR4.write(get_adjusted_guest_cr4(Cr4::empty()).bits() as u64);
 
         let mut access_rights = VmxSegmentAccessRights(0);
         access_rights.set_segment_type(CodeSegmentType::ExecuteReadAccessed as u32);
         access_rights.set_descriptor_type(true);
         access_rights.set_present(true);
 
-        vmwrite(vmcs::guest::CS_SELECTOR, 0xf000u64);
-        vmwrite(vmcs::guest::CS_BASE, 0xffff_0000u64);
-        vmwrite(vmcs::guest::CS_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::CS_ACCESS_RIGHTS, access_rights.0);
+        vmcs::guest::CS_SELECTOR.write(0xf000);
+        vmcs::guest::CS_BASE.write(0xffff_0000);
+        vmcs::guest::CS_LIMIT.write(0xffff);
+        vmcs::guest::CS_ACCESS_RIGHTS.write(access_rights.0);
 
         access_rights.set_segment_type(DataSegmentType::ReadWriteAccessed as u32);
-        vmwrite(vmcs::guest::SS_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::SS_BASE, 0u64);
-        vmwrite(vmcs::guest::SS_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::SS_ACCESS_RIGHTS, access_rights.0);
-
-        vmwrite(vmcs::guest::DS_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::DS_BASE, 0u64);
-        vmwrite(vmcs::guest::DS_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::DS_ACCESS_RIGHTS, access_rights.0);
-
-        vmwrite(vmcs::guest::ES_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::ES_BASE, 0u64);
-        vmwrite(vmcs::guest::ES_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::ES_ACCESS_RIGHTS, access_rights.0);
-
-        vmwrite(vmcs::guest::FS_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::FS_BASE, 0u64);
-        vmwrite(vmcs::guest::FS_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::FS_ACCESS_RIGHTS, access_rights.0);
-
-        vmwrite(vmcs::guest::GS_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::GS_BASE, 0u64);
-        vmwrite(vmcs::guest::GS_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::GS_ACCESS_RIGHTS, access_rights.0);
+        vmcs::guest::SS_SELECTOR.write(0);
+        vmcs::guest::SS_BASE.write(0);
+        vmcs::guest::SS_LIMIT.write(0xffff);
+        vmcs::guest::SS_ACCESS_RIGHTS.write(access_rights.0);
+
+        vmcs::guest::DS_SELECTOR.write(0);
+        vmcs::guest::DS_BASE.write(0);
+        vmcs::guest::DS_LIMIT.write(0xffff);
+        vmcs::guest::DS_ACCESS_RIGHTS.write(access_rights.0);
+
+        vmcs::guest::ES_SELECTOR.write(0);
+        vmcs::guest::ES_BASE.write(0);
+        vmcs::guest::ES_LIMIT.write(0xffff);
+        vmcs::guest::ES_ACCESS_RIGHTS.write(access_rights.0);
+
+        vmcs::guest::FS_SELECTOR.write(0);
+        vmcs::guest::FS_BASE.write(0);
+        vmcs::guest::FS_LIMIT.write(0xffff);
+        vmcs::guest::FS_ACCESS_RIGHTS.write(access_rights.0);
+
+        vmcs::guest::GS_SELECTOR.write(0);
+        vmcs::guest::GS_BASE.write(0);
+        vmcs::guest::GS_LIMIT.write(0xffff);
+        vmcs::guest::GS_ACCESS_RIGHTS.write(access_rights.0);
 
         let extended_model_id = x86::cpuid::CpuId::new()
             .get_feature_info()
@@ -544,25 +2224,25 @@ impl VmxGuest {
         self.registers.rbp = 0x0;
 
         self.registers.rsp = 0x0;
-        vmwrite(vmcs::guest::RSP, self.registers.rsp);
+        vmcs::guest::RSP.write(self.registers.rsp);
 
-        vmwrite(vmcs::guest::GDTR_BASE, 0u64);
-        vmwrite(vmcs::guest::GDTR_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::IDTR_BASE, 0u64);
-        vmwrite(vmcs::guest::IDTR_LIMIT, 0xffffu64);
+        vmcs::guest::GDTR_BASE.write(0);
+        vmcs::guest::GDTR_LIMIT.write(0xffff);
+        vmcs::guest::IDTR_BASE.write(0);
+        vmcs::guest::IDTR_LIMIT.write(0xffff);
 
         access_rights.set_segment_type(SystemDescriptorTypes64::LDT as u32);
         access_rights.set_descriptor_type(false);
-        vmwrite(vmcs::guest::LDTR_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::LDTR_BASE, 0u64);
-        vmwrite(vmcs::guest::LDTR_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::LDTR_ACCESS_RIGHTS, access_rights.0);
+        vmcs::guest::LDTR_SELECTOR.write(0);
+        vmcs::guest::LDTR_BASE.write(0);
+        vmcs::guest::LDTR_LIMIT.write(0xffff);
+        vmcs::guest::LDTR_ACCESS_RIGHTS.write(access_rights.0);
 
         access_rights.set_segment_type(SystemDescriptorTypes64::TssBusy as u32);
-        vmwrite(vmcs::guest::TR_SELECTOR, 0u64);
-        vmwrite(vmcs::guest::TR_BASE, 0u64);
-        vmwrite(vmcs::guest::TR_LIMIT, 0xffffu64);
-        vmwrite(vmcs::guest::TR_ACCESS_RIGHTS, access_rights.0);
+        vmcs::guest::TR_SELECTOR.write(0);
+        vmcs::guest::TR_BASE.write(0);
+        vmcs::guest::TR_LIMIT.write(0xffff);
+        vmcs::guest::TR_ACCESS_RIGHTS.write(access_rights.0);
 
         unsafe {
             dr0_write(0);
@@ -571,7 +2251,8 @@ impl VmxGuest {
             dr3_write(0);
             dr6_write(Dr6::from_bits_unchecked(0xffff0ff0));
         };
-        vmwrite(vmcs::guest::DR7, 0x400u64);
+        vmcs::guest::DR7.write(0x400);
+        hw_breakpoint::reset(self);
 
         self.registers.r8 = 0;
         self.registers.r9 = 0;
@@ -582,13 +2263,18 @@ impl VmxGuest {
         self.registers.r14 = 0;
         self.registers.r15 = 0;
 
-        vmwrite(vmcs::guest::IA32_EFER_FULL, 0u64);
-        vmwrite(vmcs::guest::FS_BASE, 0u64);
-        vmwrite(vmcs::guest::GS_BASE, 0u64);
+        vmcs::guest::IA32_EFER_FULL.write(0);
+        vmcs::guest::FS_BASE.write(0);
+        vmcs::guest::GS_BASE.write(0);
+        if cet::is_switched() {
+            vmcs::guest::IA32_S_CET.write(0);
+            vmcs::guest::SSP.write(0);
+            vmcs::guest::IA32_INTERRUPT_SSP_TABLE_ADDR.write(0);
+        }
 
-        let mut vmentry_controls = vmread(vmcs::control::VMENTRY_CONTROLS);
-        vmentry_controls &= !(vmcs::control::EntryControls::IA32E_MODE_GUEST.bits() as u64);
-        vmwrite(vmcs::control::VMENTRY_CONTROLS, vmentry_controls);
+        let mut vmentry_controls = vmcs::control::VMENTRY_CONTROLS.read();
+        vmentry_controls &= !vmcs::control::EntryControls::IA32E_MODE_GUEST.bits();
+        vmcs::control::VMENTRY_CONTROLS.write(vmentry_controls);
 
         // "All the processors on the system bus (...) execute the multiple processor
         //  (MP) initialization protocol. ... The application (non-BSP) processors
@@ -602,10 +2288,7 @@ impl VmxGuest {
         //  power-up or hardware reset ... . This state is also referred to at the
         //  "wait-for-SIPI" state."
         // See: 10.4.7.3 Local APIC State After an INIT Reset ("Wait-for-SIPI" State)
-        vmwrite(
-            vmcs::guest::ACTIVITY_STATE,
-            GuestActivityState::WaitForSipi as u32,
-        );
+        vmcs::guest::ACTIVITY_STATE.write(GuestActivityState::WaitForSipi as u32);
     }
 
     /// Handles VM-exit due to the Startup-IPI (SIPI) signal.
@@ -614,54 +2297,80 @@ impl VmxGuest {
         //  vector information in bits 7:0. Bits 63:8 of the exit qualification are
         //  cleared to 0."
         // See: 27.2.1 Basic VM-Exit Information
-        let vector = vmread(vmcs::ro::EXIT_QUALIFICATION);
+        let vector = vmcs::ro::EXIT_QUALIFICATION.read();
 
         // "At the end of the boot-strap procedure, the BSP sets ... broadcasts a
         //  SIPI message to all the APs in the system. Here, the SIPI message contains
         //  a vector to the BIOS AP initialization code (at 000VV000H, where VV is the
         //  vector contained in the SIPI message)."
         // See: 8.4.3 MP Initialization Protocol Algorithm for MP Systems
-        vmwrite(vmcs::guest::CS_SELECTOR, vector << 8);
-        vmwrite(vmcs::guest::CS_BASE, vector << 12);
+        vmcs::guest::CS_SELECTOR.write((vector << 8) as u16);
+        vmcs::guest::CS_BASE.write(vector << 12);
         self.registers.rip = 0;
-        vmwrite(vmcs::guest::RIP, self.registers.rip);
+        vmcs::guest::RIP.write(self.registers.rip);
 
         // Done. Note that the 2nd SIPI will be ignored if that occurs after this.
         // "If a logical processor is not in the wait-for-SIPI activity state when a
         //  SIPI arrives, no VM exit occurs and the SIPI is discarded"
         // See: 25.2 OTHER CAUSES OF VM EXITS
-        vmwrite(
-            vmcs::guest::ACTIVITY_STATE,
-            GuestActivityState::Active as u32,
-        );
+        vmcs::guest::ACTIVITY_STATE.write(GuestActivityState::Active as u32);
     }
 }
 
-struct SharedGuestData {
-    msr_bitmaps: Box<Page>,
-    epts: Box<Epts>,
+pub(super) struct SharedGuestData {
+    pub(super) msr_bitmaps: PageBox<Page>,
+    pub(super) io_bitmaps: PageBox<[Page; 2]>,
+    pub(super) epts: RwLock<Epts>,
 }
 
-static SHARED_GUEST_DATA: Lazy<SharedGuestData> = Lazy::new(|| {
-    let mut epts = zeroed_box::<Epts>();
-    epts.build_identify();
+impl SharedGuestData {
+    fn new() -> Result<Self, HvError> {
+        let mut epts = Epts::new()?;
+        epts.build_identify()?;
 
-    SharedGuestData {
-        msr_bitmaps: zeroed_box::<Page>(),
-        epts,
+        let shared_host = SHARED_HOST_DATA.get().unwrap();
+        let mut msr_bitmaps = PageBox::<Page>::try_new()?;
+        shared_host
+            .msr_intercepts
+            .build_vmx_bitmaps(&mut msr_bitmaps.0);
+
+        // Intercept writes to IA32_MTRR_DEF_TYPE to reflect MTRR updates into the
+        // EPT. See `VmxGuest::write_msr`.
+        let msr = x86::msr::IA32_MTRR_DEF_TYPE as usize;
+        msr_bitmaps.0[0x800 + msr / 8] |= 1 << (msr % 8);
+
+        // The I/O bitmaps A and B are not required to be contiguous, but building
+        // them at once is simpler.
+        let mut io_bitmaps = PageBox::<[Page; 2]>::try_new()?;
+        let io_bitmaps_bytes = unsafe { &mut *io_bitmaps.as_mut_ptr().cast::<[u8; 0x2000]>() };
+        shared_host.io_intercepts.build_bitmap(io_bitmaps_bytes);
+
+        Ok(Self {
+            msr_bitmaps,
+            io_bitmaps,
+            epts: RwLock::new(epts),
+        })
     }
-});
+}
+
+/// Returns the data shared across processors, initialized by the first
+/// `VmxGuest::new`.
+pub(super) fn shared_guest_data() -> &'static SharedGuestData {
+    SHARED_GUEST_DATA.get().unwrap()
+}
+
+static SHARED_GUEST_DATA: Once<SharedGuestData> = Once::new();
 
-extern "C" {
+extern "win64" {
     /// Runs the guest until VM-exit occurs.
-    fn run_vmx_guest(registers: &mut Registers) -> u64;
+    fn run_vmx_guest(registers: &mut Registers, extended: *mut ExtendedRegisters) -> u64;
 }
 global_asm!(include_str!("../capture_registers.inc"));
 global_asm!(include_str!("run_guest.S"));
 
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug)]
-enum VmxControl {
+pub(super) enum VmxControl {
     PinBased,
     ProcessorBased,
     ProcessorBased2,
@@ -689,15 +2398,33 @@ enum GuestActivityState {
     WaitForSipi = 3,
 }
 
+/// Sets or clears `controls` in the primary processor-based VM-execution
+/// controls.
+fn update_primary_controls(controls: vmcs::control::PrimaryControls, set: bool) {
+    let bits = controls.bits();
+    let current = vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS.read();
+    let updated = if set { current | bits } else { current & !bits };
+    vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS.write(updated);
+}
+
+/// Sets or clears `controls` in the secondary processor-based VM-execution
+/// controls.
+fn update_secondary_controls(controls: vmcs::control::SecondaryControls, set: bool) {
+    let bits = controls.bits();
+    let current = vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS.read();
+    let updated = if set { current | bits } else { current & !bits };
+    vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS.write(updated);
+}
+
 /// Returns the CR0 value after the FIXED0 and FIXED1 MSR values are applied
 /// for the guest.
-fn get_adjusted_guest_cr0(cr0: Cr0) -> Cr0 {
+pub(super) fn get_adjusted_guest_cr0(cr0: Cr0) -> Cr0 {
     // Adjust the CR0 register according to the fixed0 and fixed1 MSR values.
     let mut new_cr0 = get_adjusted_cr0(cr0);
 
     // Read the secondary processor-based VM-execution controls to check for UnrestrictedGuest support.
-    let secondary_proc_based_ctls2 = vmread(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS);
-    let unrestricted_guest = secondary_proc_based_ctls2 as u32
+    let secondary_proc_based_ctls2 = vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS.read();
+    let unrestricted_guest = secondary_proc_based_ctls2
         & vmcs::control::SecondaryControls::UNRESTRICTED_GUEST.bits()
         != 0;
 
@@ -712,7 +2439,7 @@ fn get_adjusted_guest_cr0(cr0: Cr0) -> Cr0 {
 
 /// Returns the CR4 value after the FIXED0 and FIXED1 MSR values are applied
 /// for the guest.
-fn get_adjusted_guest_cr4(cr4: Cr4) -> Cr4 {
+pub(super) fn get_adjusted_guest_cr4(cr4: Cr4) -> Cr4 {
     get_adjusted_cr4(cr4)
 }
 
@@ -804,69 +2531,6 @@ bitfield::bitfield! {
     // compatibility with future processors.
 }
 
-#[derive(Default, derive_deref::Deref, derive_deref::DerefMut)]
-struct Vmcs {
-    ptr: Box<VmcsRaw>,
-}
-
-impl Vmcs {
-    fn new() -> Self {
-        let mut vmcs = zeroed_box::<VmcsRaw>();
-        vmcs.revision_id = rdmsr(x86::msr::IA32_VMX_BASIC) as _;
-        vmclear(&mut vmcs);
-        Self { ptr: vmcs }
-    }
-}
-
-/// The region of memory that the logical processor uses to represent a virtual
-/// CPU. Called virtual-machine control data structure (VMCS).
-///
-/// See: 25.2 FORMAT OF THE VMCS REGION
-#[derive(derivative::Derivative)]
-#[derivative(Default, Debug)]
-#[repr(C, align(4096))]
-struct VmcsRaw {
-    revision_id: u32,
-    abort_indicator: u32,
-    #[derivative(Default(value = "[0; 4088]"), Debug = "ignore")]
-    data: [u8; 4088],
-}
-const _: () = assert!(core::mem::size_of::<VmcsRaw>() == BASE_PAGE_SIZE);
-
-/// The wrapper of the VMCLEAR instruction.
-fn vmclear(vmcs_region: &mut VmcsRaw) {
-    let va = vmcs_region as *const _;
-    let pa = platform_ops::get().pa(va as *const _);
-    unsafe { x86::bits64::vmx::vmclear(pa).unwrap() };
-}
-
-/// The wrapper of the VMPTRLD instruction.
-fn vmptrld(vmcs_region: &mut VmcsRaw) {
-    let va = vmcs_region as *const _;
-    let pa = platform_ops::get().pa(va as *const _);
-    unsafe { x86::bits64::vmx::vmptrld(pa).unwrap() }
-}
-
-/// The wrapper of the VMREAD instruction.
-fn vmread(encoding: u32) -> u64 {
-    unsafe { x86::bits64::vmx::vmread(encoding) }.unwrap()
-}
-
-/// The wrapper of the VMREAD instruction. Returns zero on error.
-fn vmread_relaxed(encoding: u32) -> u64 {
-    unsafe { x86::bits64::vmx::vmread(encoding) }.unwrap_or(0)
-}
-
-/// The wrapper of the VMWRITE instruction.
-fn vmwrite<T: Into<u64>>(encoding: u32, value: T)
-where
-    u64: From<T>,
-{
-    let value = u64::from(value);
-    unsafe { x86::bits64::vmx::vmwrite(encoding, value) }
-        .unwrap_or_else(|_| panic!("Could not write {value:x?} to {encoding:x?}"));
-}
-
 /// Checks that the latest VMX instruction succeeded.
 ///
 /// See: 31.2 CONVENTIONS
@@ -875,7 +2539,7 @@ fn vmx_succeed(flags: RFlags) -> Result<(), String> {
         // See: 31.4 VM INSTRUCTION ERROR NUMBERS
         Err(format!(
             "VmFailValid with {}",
-            vmread(vmcs::ro::VM_INSTRUCTION_ERROR)
+            vmcs::ro::VM_INSTRUCTION_ERROR.read()
         ))
     } else if flags.contains(RFlags::FLAGS_CF) {
         Err("VmFailInvalid".to_string())
@@ -883,204 +2547,3 @@ fn vmx_succeed(flags: RFlags) -> Result<(), String> {
         Ok(())
     }
 }
-
-impl core::fmt::Debug for Vmcs {
-    #[rustfmt::skip]
-    #[allow(clippy::too_many_lines)]
-    fn fmt(&self, format: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        // Dump the current VMCS. Not that this is not exhaustive.
-        format.debug_struct("Vmcs")
-        .field("Current VMCS", &(self as *const _))
-        .field("Revision ID", &self.revision_id)
-
-        // 16-Bit Guest-State Fields
-        .field("Guest ES Selector                              ", &vmread_relaxed(vmcs::guest::ES_SELECTOR))
-        .field("Guest CS Selector                              ", &vmread_relaxed(vmcs::guest::CS_SELECTOR))
-        .field("Guest SS Selector                              ", &vmread_relaxed(vmcs::guest::SS_SELECTOR))
-        .field("Guest DS Selector                              ", &vmread_relaxed(vmcs::guest::DS_SELECTOR))
-        .field("Guest FS Selector                              ", &vmread_relaxed(vmcs::guest::FS_SELECTOR))
-        .field("Guest GS Selector                              ", &vmread_relaxed(vmcs::guest::GS_SELECTOR))
-        .field("Guest LDTR Selector                            ", &vmread_relaxed(vmcs::guest::LDTR_SELECTOR))
-        .field("Guest TR Selector                              ", &vmread_relaxed(vmcs::guest::TR_SELECTOR))
-        .field("Guest interrupt status                         ", &vmread_relaxed(vmcs::guest::INTERRUPT_STATUS))
-        .field("PML index                                      ", &vmread_relaxed(vmcs::guest::PML_INDEX))
-
-        // 64-Bit Guest-State Fields
-        .field("VMCS link pointer                              ", &vmread_relaxed(vmcs::guest::LINK_PTR_FULL))
-        .field("Guest IA32_DEBUGCTL                            ", &vmread_relaxed(vmcs::guest::IA32_DEBUGCTL_FULL))
-        .field("Guest IA32_PAT                                 ", &vmread_relaxed(vmcs::guest::IA32_PAT_FULL))
-        .field("Guest IA32_EFER                                ", &vmread_relaxed(vmcs::guest::IA32_EFER_FULL))
-        .field("Guest IA32_PERF_GLOBAL_CTRL                    ", &vmread_relaxed(vmcs::guest::IA32_PERF_GLOBAL_CTRL_FULL))
-        .field("Guest PDPTE0                                   ", &vmread_relaxed(vmcs::guest::PDPTE0_FULL))
-        .field("Guest PDPTE1                                   ", &vmread_relaxed(vmcs::guest::PDPTE1_FULL))
-        .field("Guest PDPTE2                                   ", &vmread_relaxed(vmcs::guest::PDPTE2_FULL))
-        .field("Guest PDPTE3                                   ", &vmread_relaxed(vmcs::guest::PDPTE3_FULL))
-        .field("Guest IA32_BNDCFGS                             ", &vmread_relaxed(vmcs::guest::IA32_BNDCFGS_FULL))
-        .field("Guest IA32_RTIT_CTL                            ", &vmread_relaxed(vmcs::guest::IA32_RTIT_CTL_FULL))
-
-        // 32-Bit Guest-State Fields
-        .field("Guest ES Limit                                 ", &vmread_relaxed(vmcs::guest::ES_LIMIT))
-        .field("Guest CS Limit                                 ", &vmread_relaxed(vmcs::guest::CS_LIMIT))
-        .field("Guest SS Limit                                 ", &vmread_relaxed(vmcs::guest::SS_LIMIT))
-        .field("Guest DS Limit                                 ", &vmread_relaxed(vmcs::guest::DS_LIMIT))
-        .field("Guest FS Limit                                 ", &vmread_relaxed(vmcs::guest::FS_LIMIT))
-        .field("Guest GS Limit                                 ", &vmread_relaxed(vmcs::guest::GS_LIMIT))
-        .field("Guest LDTR Limit                               ", &vmread_relaxed(vmcs::guest::LDTR_LIMIT))
-        .field("Guest TR Limit                                 ", &vmread_relaxed(vmcs::guest::TR_LIMIT))
-        .field("Guest GDTR limit                               ", &vmread_relaxed(vmcs::guest::GDTR_LIMIT))
-        .field("Guest IDTR limit                               ", &vmread_relaxed(vmcs::guest::IDTR_LIMIT))
-        .field("Guest ES access rights                         ", &vmread_relaxed(vmcs::guest::ES_ACCESS_RIGHTS))
-        .field("Guest CS access rights                         ", &vmread_relaxed(vmcs::guest::CS_ACCESS_RIGHTS))
-        .field("Guest SS access rights                         ", &vmread_relaxed(vmcs::guest::SS_ACCESS_RIGHTS))
-        .field("Guest DS access rights                         ", &vmread_relaxed(vmcs::guest::DS_ACCESS_RIGHTS))
-        .field("Guest FS access rights                         ", &vmread_relaxed(vmcs::guest::FS_ACCESS_RIGHTS))
-        .field("Guest GS access rights                         ", &vmread_relaxed(vmcs::guest::GS_ACCESS_RIGHTS))
-        .field("Guest LDTR access rights                       ", &vmread_relaxed(vmcs::guest::LDTR_ACCESS_RIGHTS))
-        .field("Guest TR access rights                         ", &vmread_relaxed(vmcs::guest::TR_ACCESS_RIGHTS))
-        .field("Guest interruptibility state                   ", &vmread_relaxed(vmcs::guest::INTERRUPTIBILITY_STATE))
-        .field("Guest activity state                           ", &vmread_relaxed(vmcs::guest::ACTIVITY_STATE))
-        .field("Guest SMBASE                                   ", &vmread_relaxed(vmcs::guest::SMBASE))
-        .field("Guest IA32_SYSENTER_CS                         ", &vmread_relaxed(vmcs::guest::IA32_SYSENTER_CS))
-        .field("VMX-preemption timer value                     ", &vmread_relaxed(vmcs::guest::VMX_PREEMPTION_TIMER_VALUE))
-
-        // Natural-Width Guest-State Fields
-        .field("Guest CR0                                      ", &vmread_relaxed(vmcs::guest::CR0))
-        .field("Guest CR3                                      ", &vmread_relaxed(vmcs::guest::CR3))
-        .field("Guest CR4                                      ", &vmread_relaxed(vmcs::guest::CR4))
-        .field("Guest ES Base                                  ", &vmread_relaxed(vmcs::guest::ES_BASE))
-        .field("Guest CS Base                                  ", &vmread_relaxed(vmcs::guest::CS_BASE))
-        .field("Guest SS Base                                  ", &vmread_relaxed(vmcs::guest::SS_BASE))
-        .field("Guest DS Base                                  ", &vmread_relaxed(vmcs::guest::DS_BASE))
-        .field("Guest FS Base                                  ", &vmread_relaxed(vmcs::guest::FS_BASE))
-        .field("Guest GS Base                                  ", &vmread_relaxed(vmcs::guest::GS_BASE))
-        .field("Guest LDTR base                                ", &vmread_relaxed(vmcs::guest::LDTR_BASE))
-        .field("Guest TR base                                  ", &vmread_relaxed(vmcs::guest::TR_BASE))
-        .field("Guest GDTR base                                ", &vmread_relaxed(vmcs::guest::GDTR_BASE))
-        .field("Guest IDTR base                                ", &vmread_relaxed(vmcs::guest::IDTR_BASE))
-        .field("Guest DR7                                      ", &vmread_relaxed(vmcs::guest::DR7))
-        .field("Guest RSP                                      ", &vmread_relaxed(vmcs::guest::RSP))
-        .field("Guest RIP                                      ", &vmread_relaxed(vmcs::guest::RIP))
-        .field("Guest RFLAGS                                   ", &vmread_relaxed(vmcs::guest::RFLAGS))
-        .field("Guest pending debug exceptions                 ", &vmread_relaxed(vmcs::guest::PENDING_DBG_EXCEPTIONS))
-        .field("Guest IA32_SYSENTER_ESP                        ", &vmread_relaxed(vmcs::guest::IA32_SYSENTER_ESP))
-        .field("Guest IA32_SYSENTER_EIP                        ", &vmread_relaxed(vmcs::guest::IA32_SYSENTER_EIP))
-
-        // 16-Bit Host-State Fields
-        .field("Host ES Selector                               ", &vmread_relaxed(vmcs::host::ES_SELECTOR))
-        .field("Host CS Selector                               ", &vmread_relaxed(vmcs::host::CS_SELECTOR))
-        .field("Host SS Selector                               ", &vmread_relaxed(vmcs::host::SS_SELECTOR))
-        .field("Host DS Selector                               ", &vmread_relaxed(vmcs::host::DS_SELECTOR))
-        .field("Host FS Selector                               ", &vmread_relaxed(vmcs::host::FS_SELECTOR))
-        .field("Host GS Selector                               ", &vmread_relaxed(vmcs::host::GS_SELECTOR))
-        .field("Host TR Selector                               ", &vmread_relaxed(vmcs::host::TR_SELECTOR))
-
-        // 64-Bit Host-State Fields
-        .field("Host IA32_PAT                                  ", &vmread_relaxed(vmcs::host::IA32_PAT_FULL))
-        .field("Host IA32_EFER                                 ", &vmread_relaxed(vmcs::host::IA32_EFER_FULL))
-        .field("Host IA32_PERF_GLOBAL_CTRL                     ", &vmread_relaxed(vmcs::host::IA32_PERF_GLOBAL_CTRL_FULL))
-
-        // 32-Bit Host-State Fields
-        .field("Host IA32_SYSENTER_CS                          ", &vmread_relaxed(vmcs::host::IA32_SYSENTER_CS))
-
-        // Natural-Width Host-State Fields
-        .field("Host CR0                                       ", &vmread_relaxed(vmcs::host::CR0))
-        .field("Host CR3                                       ", &vmread_relaxed(vmcs::host::CR3))
-        .field("Host CR4                                       ", &vmread_relaxed(vmcs::host::CR4))
-        .field("Host FS Base                                   ", &vmread_relaxed(vmcs::host::FS_BASE))
-        .field("Host GS Base                                   ", &vmread_relaxed(vmcs::host::GS_BASE))
-        .field("Host TR base                                   ", &vmread_relaxed(vmcs::host::TR_BASE))
-        .field("Host GDTR base                                 ", &vmread_relaxed(vmcs::host::GDTR_BASE))
-        .field("Host IDTR base                                 ", &vmread_relaxed(vmcs::host::IDTR_BASE))
-        .field("Host IA32_SYSENTER_ESP                         ", &vmread_relaxed(vmcs::host::IA32_SYSENTER_ESP))
-        .field("Host IA32_SYSENTER_EIP                         ", &vmread_relaxed(vmcs::host::IA32_SYSENTER_EIP))
-        .field("Host RSP                                       ", &vmread_relaxed(vmcs::host::RSP))
-        .field("Host RIP                                       ", &vmread_relaxed(vmcs::host::RIP))
-
-        // 16-Bit Control Fields
-        .field("Virtual-processor identifier                   ", &vmread_relaxed(vmcs::control::VPID))
-        .field("Posted-interrupt notification vector           ", &vmread_relaxed(vmcs::control::POSTED_INTERRUPT_NOTIFICATION_VECTOR))
-        .field("EPTP index                                     ", &vmread_relaxed(vmcs::control::EPTP_INDEX))
-
-        // 64-Bit Control Fields
-        .field("Address of I/O bitmap A                        ", &vmread_relaxed(vmcs::control::IO_BITMAP_A_ADDR_FULL))
-        .field("Address of I/O bitmap B                        ", &vmread_relaxed(vmcs::control::IO_BITMAP_B_ADDR_FULL))
-        .field("Address of MSR bitmaps                         ", &vmread_relaxed(vmcs::control::MSR_BITMAPS_ADDR_FULL))
-        .field("VM-exit MSR-store address                      ", &vmread_relaxed(vmcs::control::VMEXIT_MSR_STORE_ADDR_FULL))
-        .field("VM-exit MSR-load address                       ", &vmread_relaxed(vmcs::control::VMEXIT_MSR_LOAD_ADDR_FULL))
-        .field("VM-entry MSR-load address                      ", &vmread_relaxed(vmcs::control::VMENTRY_MSR_LOAD_ADDR_FULL))
-        .field("Executive-VMCS pointer                         ", &vmread_relaxed(vmcs::control::EXECUTIVE_VMCS_PTR_FULL))
-        .field("PML address                                    ", &vmread_relaxed(vmcs::control::PML_ADDR_FULL))
-        .field("TSC offset                                     ", &vmread_relaxed(vmcs::control::TSC_OFFSET_FULL))
-        .field("Virtual-APIC address                           ", &vmread_relaxed(vmcs::control::VIRT_APIC_ADDR_FULL))
-        .field("APIC-access address                            ", &vmread_relaxed(vmcs::control::APIC_ACCESS_ADDR_FULL))
-        .field("Posted-interrupt descriptor address            ", &vmread_relaxed(vmcs::control::POSTED_INTERRUPT_DESC_ADDR_FULL))
-        .field("VM-function controls                           ", &vmread_relaxed(vmcs::control::VM_FUNCTION_CONTROLS_FULL))
-        .field("EPT pointer                                    ", &vmread_relaxed(vmcs::control::EPTP_FULL))
-        .field("EOI-exit bitmap 0                              ", &vmread_relaxed(vmcs::control::EOI_EXIT0_FULL))
-        .field("EOI-exit bitmap 1                              ", &vmread_relaxed(vmcs::control::EOI_EXIT1_FULL))
-        .field("EOI-exit bitmap 2                              ", &vmread_relaxed(vmcs::control::EOI_EXIT2_FULL))
-        .field("EOI-exit bitmap 3                              ", &vmread_relaxed(vmcs::control::EOI_EXIT3_FULL))
-        .field("EPTP-list address                              ", &vmread_relaxed(vmcs::control::EPTP_LIST_ADDR_FULL))
-        .field("VMREAD-bitmap address                          ", &vmread_relaxed(vmcs::control::VMREAD_BITMAP_ADDR_FULL))
-        .field("VMWRITE-bitmap address                         ", &vmread_relaxed(vmcs::control::VMWRITE_BITMAP_ADDR_FULL))
-        .field("Virtualization-exception information address   ", &vmread_relaxed(vmcs::control::VIRT_EXCEPTION_INFO_ADDR_FULL))
-        .field("XSS-exiting bitmap                             ", &vmread_relaxed(vmcs::control::XSS_EXITING_BITMAP_FULL))
-        .field("ENCLS-exiting bitmap                           ", &vmread_relaxed(vmcs::control::ENCLS_EXITING_BITMAP_FULL))
-        .field("Sub-page-permission-table pointer              ", &vmread_relaxed(vmcs::control::SUBPAGE_PERM_TABLE_PTR_FULL))
-        .field("TSC multiplier                                 ", &vmread_relaxed(vmcs::control::TSC_MULTIPLIER_FULL))
-
-        // 32-Bit Control Fields
-        .field("Pin-based VM-execution controls                ", &vmread_relaxed(vmcs::control::PINBASED_EXEC_CONTROLS))
-        .field("Primary processor-based VM-execution controls  ", &vmread_relaxed(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS))
-        .field("Exception bitmap                               ", &vmread_relaxed(vmcs::control::EXCEPTION_BITMAP))
-        .field("Page-fault error-code mask                     ", &vmread_relaxed(vmcs::control::PAGE_FAULT_ERR_CODE_MASK))
-        .field("Page-fault error-code match                    ", &vmread_relaxed(vmcs::control::PAGE_FAULT_ERR_CODE_MATCH))
-        .field("CR3-target count                               ", &vmread_relaxed(vmcs::control::CR3_TARGET_COUNT))
-        .field("Primary VM-exit controls                       ", &vmread_relaxed(vmcs::control::VMEXIT_CONTROLS))
-        .field("VM-exit MSR-store count                        ", &vmread_relaxed(vmcs::control::VMEXIT_MSR_STORE_COUNT))
-        .field("VM-exit MSR-load count                         ", &vmread_relaxed(vmcs::control::VMEXIT_MSR_LOAD_COUNT))
-        .field("VM-entry controls                              ", &vmread_relaxed(vmcs::control::VMENTRY_CONTROLS))
-        .field("VM-entry MSR-load count                        ", &vmread_relaxed(vmcs::control::VMENTRY_MSR_LOAD_COUNT))
-        .field("VM-entry interruption-information field        ", &vmread_relaxed(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD))
-        .field("VM-entry exception error code                  ", &vmread_relaxed(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE))
-        .field("VM-entry instruction length                    ", &vmread_relaxed(vmcs::control::VMENTRY_INSTRUCTION_LEN))
-        .field("TPR threshold                                  ", &vmread_relaxed(vmcs::control::TPR_THRESHOLD))
-        .field("Secondary processor-based VM-execution controls", &vmread_relaxed(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS))
-        .field("PLE_Gap                                        ", &vmread_relaxed(vmcs::control::PLE_GAP))
-        .field("PLE_Window                                     ", &vmread_relaxed(vmcs::control::PLE_WINDOW))
-
-        // Natural-Width Control Fields
-        .field("CR0 guest/host mask                            ", &vmread_relaxed(vmcs::control::CR0_GUEST_HOST_MASK))
-        .field("CR4 guest/host mask                            ", &vmread_relaxed(vmcs::control::CR4_GUEST_HOST_MASK))
-        .field("CR0 read shadow                                ", &vmread_relaxed(vmcs::control::CR0_READ_SHADOW))
-        .field("CR4 read shadow                                ", &vmread_relaxed(vmcs::control::CR4_READ_SHADOW))
-        .field("CR3-target value 0                             ", &vmread_relaxed(vmcs::control::CR3_TARGET_VALUE0))
-        .field("CR3-target value 1                             ", &vmread_relaxed(vmcs::control::CR3_TARGET_VALUE1))
-        .field("CR3-target value 2                             ", &vmread_relaxed(vmcs::control::CR3_TARGET_VALUE2))
-        .field("CR3-target value 3                             ", &vmread_relaxed(vmcs::control::CR3_TARGET_VALUE3))
-
-        // 16-Bit Read-Only Data Fields
-
-        // 64-Bit Read-Only Data Fields
-        .field("Guest-physical address                         ", &vmread_relaxed(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL))
-
-        // 32-Bit Read-Only Data Fields
-        .field("VM-instruction error                           ", &vmread_relaxed(vmcs::ro::VM_INSTRUCTION_ERROR))
-        .field("Exit reason                                    ", &vmread_relaxed(vmcs::ro::EXIT_REASON))
-        .field("VM-exit interruption information               ", &vmread_relaxed(vmcs::ro::VMEXIT_INTERRUPTION_INFO))
-        .field("VM-exit interruption error code                ", &vmread_relaxed(vmcs::ro::VMEXIT_INTERRUPTION_ERR_CODE))
-        .field("IDT-vectoring information field                ", &vmread_relaxed(vmcs::ro::IDT_VECTORING_INFO))
-        .field("IDT-vectoring error code                       ", &vmread_relaxed(vmcs::ro::IDT_VECTORING_ERR_CODE))
-        .field("VM-exit instruction length                     ", &vmread_relaxed(vmcs::ro::VMEXIT_INSTRUCTION_LEN))
-        .field("VM-exit instruction information                ", &vmread_relaxed(vmcs::ro::VMEXIT_INSTRUCTION_INFO))
-
-        // Natural-Width Read-Only Data Fields
-        .field("Exit qualification                             ", &vmread_relaxed(vmcs::ro::EXIT_QUALIFICATION))
-        .field("I/O RCX                                        ", &vmread_relaxed(vmcs::ro::IO_RCX))
-        .field("I/O RSI                                        ", &vmread_relaxed(vmcs::ro::IO_RSI))
-        .field("I/O RDI                                        ", &vmread_relaxed(vmcs::ro::IO_RDI))
-        .field("I/O RIP                                        ", &vmread_relaxed(vmcs::ro::IO_RIP))
-        .field("Guest-linear address                           ", &vmread_relaxed(vmcs::ro::GUEST_LINEAR_ADDR))
-        .finish_non_exhaustive()
-    }
-}
@@ -4,10 +4,19 @@
 
 use super::host::Architecture;
 
+mod apicv;
+mod entry_checks;
 mod epts;
 mod guest;
-mod mtrr;
+mod nested;
+mod shadow_ept;
+mod vmcs;
+mod vmcs12;
 mod vmx;
+mod vpid;
+
+pub(crate) use apicv::ApicvSupport;
+pub(crate) use nested::install_nested_virtualization;
 
 /// The Intel processor implements VMX as a virtualization extension.
 pub(crate) struct Intel;
@@ -0,0 +1,2295 @@
+//! This module implements nested virtualization, which lets the guest run its
+//! own hypervisor, such as Hyper-V, WSL2 or KVM, when enabled with
+//! `SharedHostData::nested_virtualization`. See `vmcs12` for the terms.
+//!
+//! VMX is reported to L1 with the capabilities in `capability`, the subset of
+//! those of the processor this module can emulate. The VMX instructions L1
+//! executes cause VM-exits, and are emulated against VMCS12. With VMCS
+//! shadowing, VMREAD and VMWRITE of most fields access the shadow VMCS without
+//! VM-exits instead, which is kept in sync with VMCS12 around the other
+//! instructions and VM-exits from L2.
+//!
+//! VMLAUNCH and VMRESUME merge VMCS12 and VMCS01 into VMCS02 and run L2 with
+//! it. Each VM-exit from L2 is either reflected into L1 as a VM-exit from
+//! VMCS12, when it is due to the controls of L1, or handled by L0, resuming L2
+//! afterwards. If L1 enables EPT, L2 runs with EPT02 (see `shadow_ept`).
+//! Otherwise, L2 shares the guest-physical address space of L1 and runs with
+//! the EPT of L1.
+//!
+//! The limitations are:
+//! - L1 must execute the VMX instructions in 64-bit mode, and its host must
+//!   run in 64-bit mode.
+//! - The VMX-preemption timer, VPIDs for L2, APIC virtualization, PML, VM
+//!   functions and #VE are not reported. L1 itself does not use APICv either.
+//! - The VM-entry MSR-load and VM-exit MSR-store and MSR-load lists support
+//!   only the MSRs in `is_listable_msr`.
+//! - L0 handles the VM-exits from L2 due to its own controls against the state
+//!   of L2: I/O to the ports in `io_intercepts`, the MSRs in `msr_intercepts`,
+//!   and EPT violations due to the pages of L1 that L0 hides, hooks or
+//!   protects. The custom VM-exit handlers see the state of L2 for them too.
+//! - Hardware breakpoints, breakpoint markers, EPT views and the CR and CR3
+//!   intercepts apply to L1 only.
+//! - The processor cannot be devirtualized while L1 is in VMX operation.
+// See: CHAPTER 26 VMX NON-ROOT OPERATION
+// See: CHAPTER 31 VMX INSTRUCTION REFERENCE
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::{boxed::Box, vec::Vec};
+use bit_field::BitField;
+use x86::{
+    bits64::{paging::BASE_PAGE_SIZE, rflags::RFlags},
+    controlregs::{Cr0, Cr4},
+    cpuid::cpuid,
+};
+
+use crate::hypervisor::{
+    cet,
+    cpuid_policy::CpuidRegister,
+    dirty_tracking,
+    event::{self, Event},
+    guest_memory::{self, TranslationError},
+    host::{GpaMapping, NestedPageFaultInfo, SegmentRegister, Vcpu},
+    memory_protection::Permissions,
+    mtrr::MemoryType,
+    registers::Registers,
+    support::{Page, PageBox},
+    tlb::{self, FlushScope},
+    x86_instructions::rdmsr,
+    HvError, SharedHostData, SHARED_HOST_DATA,
+};
+
+use super::{
+    epts::Epts,
+    guest::{
+        get_adjusted_guest_cr0, get_adjusted_guest_cr4, shared_guest_data, VmxControl, VmxGuest,
+    },
+    shadow_ept::{self, Ept02, Ept12Features, Ept12Leaf, Ept12Walk},
+    vmcs::{
+        self, control::EntryControls, control::ExitControls, control::PinbasedControls,
+        control::PrimaryControls, control::SecondaryControls, try_vmread, try_vmwrite, vmclear,
+        vmptrld, vmptrld_pa, Field, Vmcs, Width, FIELDS,
+    },
+    vmcs12::{self, FieldError, Vmcs12, REGION_SIZE},
+};
+
+const IA32_VMX_BASIC: u32 = 0x480;
+const IA32_VMX_PINBASED_CTLS: u32 = 0x481;
+const IA32_VMX_PROCBASED_CTLS: u32 = 0x482;
+const IA32_VMX_EXIT_CTLS: u32 = 0x483;
+const IA32_VMX_ENTRY_CTLS: u32 = 0x484;
+const IA32_VMX_MISC: u32 = 0x485;
+const IA32_VMX_CR0_FIXED0: u32 = 0x486;
+const IA32_VMX_CR0_FIXED1: u32 = 0x487;
+const IA32_VMX_CR4_FIXED0: u32 = 0x488;
+const IA32_VMX_CR4_FIXED1: u32 = 0x489;
+const IA32_VMX_VMCS_ENUM: u32 = 0x48a;
+const IA32_VMX_PROCBASED_CTLS2: u32 = 0x48b;
+const IA32_VMX_EPT_VPID_CAP: u32 = 0x48c;
+const IA32_VMX_TRUE_PINBASED_CTLS: u32 = 0x48d;
+const IA32_VMX_TRUE_PROCBASED_CTLS: u32 = 0x48e;
+const IA32_VMX_TRUE_EXIT_CTLS: u32 = 0x48f;
+const IA32_VMX_TRUE_ENTRY_CTLS: u32 = 0x490;
+const IA32_VMX_VMFUNC: u32 = 0x491;
+
+// See: APPENDIX C VMX BASIC EXIT REASONS
+pub(super) const EXIT_REASON_EXCEPTION_OR_NMI: u32 = 0;
+pub(super) const EXIT_REASON_EXTERNAL_INTERRUPT: u32 = 1;
+const EXIT_REASON_INTERRUPT_WINDOW: u32 = 7;
+const EXIT_REASON_NMI_WINDOW: u32 = 8;
+const EXIT_REASON_VMCLEAR: u32 = 19;
+const EXIT_REASON_VMLAUNCH: u32 = 20;
+const EXIT_REASON_VMPTRLD: u32 = 21;
+const EXIT_REASON_VMPTRST: u32 = 22;
+const EXIT_REASON_VMREAD: u32 = 23;
+const EXIT_REASON_VMRESUME: u32 = 24;
+const EXIT_REASON_VMWRITE: u32 = 25;
+const EXIT_REASON_VMXOFF: u32 = 26;
+const EXIT_REASON_VMXON: u32 = 27;
+const EXIT_REASON_IO: u32 = 30;
+const EXIT_REASON_RDMSR: u32 = 31;
+const EXIT_REASON_WRMSR: u32 = 32;
+const EXIT_REASON_ENTRY_FAILURE_GUEST_STATE: u32 = 33;
+const EXIT_REASON_ENTRY_FAILURE_MSR_LOADING: u32 = 34;
+const EXIT_REASON_MONITOR_TRAP_FLAG: u32 = 37;
+const EXIT_REASON_EPT_VIOLATION: u32 = 48;
+const EXIT_REASON_EPT_MISCONFIGURATION: u32 = 49;
+const EXIT_REASON_INVEPT: u32 = 50;
+const EXIT_REASON_PREEMPTION_TIMER: u32 = 52;
+const EXIT_REASON_INVVPID: u32 = 53;
+const EXIT_REASON_WBINVD: u32 = 54;
+
+/// Bit 31 of the exit reason, set for VM-entry failures.
+const EXIT_REASON_ENTRY_FAILURE: u32 = 1 << 31;
+
+// See: 31.4 VM INSTRUCTION ERROR NUMBERS
+const ERROR_VMCLEAR_INVALID_ADDRESS: u32 = 2;
+const ERROR_VMCLEAR_VMXON_POINTER: u32 = 3;
+const ERROR_VMLAUNCH_NON_CLEAR: u32 = 4;
+const ERROR_VMRESUME_NON_LAUNCHED: u32 = 5;
+const ERROR_INVALID_CONTROL_FIELDS: u32 = 7;
+const ERROR_INVALID_HOST_STATE: u32 = 8;
+const ERROR_VMPTRLD_INVALID_ADDRESS: u32 = 9;
+const ERROR_VMPTRLD_VMXON_POINTER: u32 = 10;
+const ERROR_VMPTRLD_INCORRECT_REVISION: u32 = 11;
+const ERROR_VMXON_IN_VMX_ROOT: u32 = 15;
+const ERROR_ENTRY_BLOCKED_BY_MOV_SS: u32 = 26;
+const ERROR_INVALID_INVEPT_INVVPID_OPERAND: u32 = 28;
+
+/// Whether `install_nested_virtualization` reported VMX to the guest.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Modifies `shared_host.cpuid_policy` to report VMX, and installs the handlers
+/// of the VMX capability MSRs into `shared_host.msr_intercepts`. Called from
+/// the guest before any processor is virtualized.
+pub(crate) fn install_nested_virtualization(shared_host: &mut SharedHostData) {
+    const VMX: u32 = 1 << 5;
+
+    shared_host.cpuid_policy =
+        core::mem::take(&mut shared_host.cpuid_policy).set_bits(1, None, CpuidRegister::Ecx, VMX);
+    let mut intercepts = core::mem::take(&mut shared_host.msr_intercepts);
+    for msr in IA32_VMX_BASIC..=IA32_VMX_VMFUNC {
+        intercepts = intercepts.on_read(msr, |_, msr| Some(capability(msr)));
+    }
+    shared_host.msr_intercepts = intercepts;
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Returns whether the VMX instructions are emulated for the guest.
+pub(super) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns the value of the VMX capability MSR `msr` reported to L1. Each
+/// control is reported as allowed to be 1 only if the processor supports it
+/// and this module emulates it, and as required to be 1 as the processor
+/// requires.
+// See: APPENDIX A VMX CAPABILITY REPORTING FACILITY
+fn capability(msr: u32) -> u64 {
+    const PIN_BASED: u32 = PinbasedControls::EXTERNAL_INTERRUPT_EXITING.bits()
+        | PinbasedControls::NMI_EXITING.bits()
+        | PinbasedControls::VIRTUAL_NMIS.bits();
+    const PRIMARY: u32 = PrimaryControls::INTERRUPT_WINDOW_EXITING.bits()
+        | PrimaryControls::USE_TSC_OFFSETTING.bits()
+        | PrimaryControls::HLT_EXITING.bits()
+        | PrimaryControls::INVLPG_EXITING.bits()
+        | PrimaryControls::MWAIT_EXITING.bits()
+        | PrimaryControls::RDPMC_EXITING.bits()
+        | PrimaryControls::RDTSC_EXITING.bits()
+        | PrimaryControls::CR3_LOAD_EXITING.bits()
+        | PrimaryControls::CR3_STORE_EXITING.bits()
+        | PrimaryControls::CR8_LOAD_EXITING.bits()
+        | PrimaryControls::CR8_STORE_EXITING.bits()
+        | PrimaryControls::NMI_WINDOW_EXITING.bits()
+        | PrimaryControls::MOV_DR_EXITING.bits()
+        | PrimaryControls::UNCOND_IO_EXITING.bits()
+        | PrimaryControls::USE_IO_BITMAPS.bits()
+        | PrimaryControls::MONITOR_TRAP_FLAG.bits()
+        | PrimaryControls::USE_MSR_BITMAPS.bits()
+        | PrimaryControls::MONITOR_EXITING.bits()
+        | PrimaryControls::PAUSE_EXITING.bits()
+        | PrimaryControls::SECONDARY_CONTROLS.bits();
+    const SECONDARY: u32 = SecondaryControls::ENABLE_EPT.bits()
+        | SecondaryControls::DTABLE_EXITING.bits()
+        | SecondaryControls::ENABLE_RDTSCP.bits()
+        | SecondaryControls::ENABLE_VPID.bits()
+        | SecondaryControls::WBINVD_EXITING.bits()
+        | SecondaryControls::UNRESTRICTED_GUEST.bits()
+        | SecondaryControls::RDRAND_EXITING.bits()
+        | SecondaryControls::ENABLE_INVPCID.bits()
+        | SecondaryControls::RDSEED_EXITING.bits()
+        | SecondaryControls::ENABLE_XSAVES_XRSTORS.bits();
+    const EXIT: u32 = ExitControls::SAVE_DEBUG_CONTROLS.bits()
+        | ExitControls::HOST_ADDRESS_SPACE_SIZE.bits()
+        | ExitControls::ACK_INTERRUPT_ON_EXIT.bits()
+        | ExitControls::SAVE_IA32_PAT.bits()
+        | ExitControls::LOAD_IA32_PAT.bits()
+        | ExitControls::SAVE_IA32_EFER.bits()
+        | ExitControls::LOAD_IA32_EFER.bits();
+    const ENTRY: u32 = EntryControls::LOAD_DEBUG_CONTROLS.bits()
+        | EntryControls::IA32E_MODE_GUEST.bits()
+        | EntryControls::LOAD_IA32_PAT.bits()
+        | EntryControls::LOAD_IA32_EFER.bits();
+
+    // See: A.10 VPID AND EPT CAPABILITIES
+    const EPT_VPID_CAP: u64 = 1 << 0 // execute-only
+        | 1 << 6 // 4-level
+        | 1 << 8 // UC
+        | 1 << 14 // WB
+        | 1 << 16 // 2MB pages
+        | 1 << 17 // 1GB pages
+        | 1 << 20 // INVEPT
+        | 1 << 25 // single-context INVEPT
+        | 1 << 26 // all-context INVEPT
+        | 1 << 32 // INVVPID
+        | 0b1111 << 40; // the INVVPID types
+
+    // See: A.6 MISCELLANEOUS DATA
+    const MISC: u64 = 1 << 5 // IA32E is stored on VM-exit
+        | 1 << 6 // HLT activity state
+        | 0x1ff << 16 // CR3-target values
+        | 1 << 29 // VMWRITE to any field
+        | 1 << 30; // zero instruction length for software events
+
+    // Each control is allowed to be 0 as the processor allows, and to be 1
+    // only if also supported here. The TRUE MSRs fall back to the others if
+    // the processor does not have them, as they are always reported.
+    let controls = |msr: u32, true_msr: u32, supported: u32| {
+        let msr = if msr == true_msr && !rdmsr(IA32_VMX_BASIC).get_bit(55) {
+            true_msr - (IA32_VMX_TRUE_PINBASED_CTLS - IA32_VMX_PINBASED_CTLS)
+        } else {
+            msr
+        };
+        let value = rdmsr(msr);
+        let allowed0 = value as u32;
+        let allowed1 = ((value >> 32) as u32 & supported) | allowed0;
+        u64::from(allowed0) | u64::from(allowed1) << 32
+    };
+    let secondary_supported = rdmsr(IA32_VMX_PROCBASED_CTLS).get_bit(63);
+    match msr {
+        // Report a VMCS region of 4KB, the write-back memory type, and the TRUE
+        // MSRs, with the revision identifier of the processor.
+        // See: A.1 BASIC VMX INFORMATION
+        IA32_VMX_BASIC => {
+            let basic = rdmsr(IA32_VMX_BASIC);
+            u64::from(basic as u32 & !(1 << 31))
+                | (REGION_SIZE as u64) << 32
+                | u64::from(MemoryType::WriteBack as u8) << 50
+                | basic & 1 << 54
+                | 1 << 55
+        }
+        IA32_VMX_PINBASED_CTLS | IA32_VMX_TRUE_PINBASED_CTLS => {
+            controls(msr, IA32_VMX_TRUE_PINBASED_CTLS, PIN_BASED)
+        }
+        IA32_VMX_PROCBASED_CTLS | IA32_VMX_TRUE_PROCBASED_CTLS => {
+            let supported = if secondary_supported {
+                PRIMARY
+            } else {
+                PRIMARY & !PrimaryControls::SECONDARY_CONTROLS.bits()
+            };
+            controls(msr, IA32_VMX_TRUE_PROCBASED_CTLS, supported)
+        }
+        IA32_VMX_EXIT_CTLS | IA32_VMX_TRUE_EXIT_CTLS => {
+            controls(msr, IA32_VMX_TRUE_EXIT_CTLS, EXIT)
+        }
+        IA32_VMX_ENTRY_CTLS | IA32_VMX_TRUE_ENTRY_CTLS => {
+            controls(msr, IA32_VMX_TRUE_ENTRY_CTLS, ENTRY)
+        }
+        IA32_VMX_PROCBASED_CTLS2 if secondary_supported => {
+            u64::from((rdmsr(IA32_VMX_PROCBASED_CTLS2) >> 32) as u32 & SECONDARY) << 32
+        }
+        IA32_VMX_EPT_VPID_CAP if secondary_supported => rdmsr(IA32_VMX_EPT_VPID_CAP) & EPT_VPID_CAP,
+        IA32_VMX_MISC => rdmsr(IA32_VMX_MISC) & MISC,
+        IA32_VMX_CR0_FIXED0 | IA32_VMX_CR0_FIXED1 | IA32_VMX_CR4_FIXED0 | IA32_VMX_CR4_FIXED1 => {
+            rdmsr(msr)
+        }
+        IA32_VMX_VMCS_ENUM => vmcs12::max_index() << 1,
+        _ => 0,
+    }
+}
+
+/// The result of `emulate`.
+pub(super) enum Emulation {
+    /// The instruction completed, failed, or raised an exception in L1.
+    Completed,
+    /// VMLAUNCH or VMRESUME entered L2 with VMCS02, which is current now.
+    Entered,
+    /// VMXOFF left VMX operation with INIT latched, which is to be handled now.
+    InitLatched,
+}
+
+/// How a VM-exit from L2 is handled. See `NestedVmx::route`.
+pub(super) enum Route {
+    /// The VM-exit is to be reflected into L1 with `NestedVmx::reflect`.
+    Reflect,
+    /// L0 handles the VM-exit as it would for L1, and resumes L2.
+    Host,
+    /// L0 handles the EPT violation EPT01 caused at the L1 GPA, and resumes
+    /// L2. See `NestedVmx::map_hooked` and `NestedVmx::map_for_step`.
+    NestedPageFault(NestedPageFaultInfo),
+    /// L0 handled the VM-exit, and L2 resumes.
+    Resolved,
+}
+
+/// The state of VMX operation of L1 on a processor, from VMXON to VMXOFF.
+pub(super) struct NestedVmx {
+    /// The L1 GPA of the VMXON region.
+    vmxon_pa: u64,
+
+    /// The VMCS L0 runs L2 with, whose host-state fields are those of VMCS01.
+    vmcs02: Vmcs,
+
+    /// The L1 GPA and the fields of the current VMCS12, if any.
+    current: Option<(u64, Box<Vmcs12>)>,
+
+    /// The shadow VMCS of VMCS12, if the processor supports VMCS shadowing.
+    shadow: Option<ShadowVmcs>,
+
+    /// The MSR bitmaps and the I/O bitmaps of VMCS02, merged from those of L0
+    /// and L1.
+    msr_bitmaps: PageBox<Page>,
+    io_bitmaps: PageBox<[Page; 2]>,
+
+    /// EPT02, used if L1 enables EPT.
+    ept02: Ept02,
+
+    /// The EPTP12 EPT02 caches the translations of, if any.
+    eptp12: Option<u64>,
+
+    /// The generation of the dirty pages harvested, when EPT02 was last reset.
+    harvested: u64,
+
+    /// The TSC offset of L2 relative to L1.
+    tsc_offset12: u64,
+
+    /// RIP, RSP and RFLAGS of L1 at VMLAUNCH or VMRESUME, for the VM-entry
+    /// failing in the processor. See `fail_entry`.
+    l1_registers: (u64, u64, u64),
+
+    /// Whether L2 is running, that is, VMCS02 is current.
+    in_l2: bool,
+
+    /// Whether INIT arrived while L1 was in VMX root operation, which blocks
+    /// INIT until VMXOFF.
+    // See: 26.2 OTHER CAUSES OF VM EXITS
+    init_latched: bool,
+
+    /// The exit reason and the exit qualification to report instead of those
+    /// of the VM-exit from L2 on the next `reflect`.
+    exit_override: Option<(u32, u64)>,
+
+    /// The L2 GPA and the EPT12 translation of the last EPT violation L0
+    /// handles. See `Route::NestedPageFault`.
+    fault: Option<(u64, Ept12Leaf)>,
+
+    /// The L2 GPAs mapped in EPT02 only for the instruction being
+    /// single-stepped. See `map_for_step`.
+    steps: Vec<u64>,
+}
+
+/// The shadow VMCS of VMCS12, and the VMREAD and VMWRITE bitmaps making the
+/// fields in it accessible to L1 without VM-exits.
+// See: 26.5.10 VMCS Shadowing
+struct ShadowVmcs {
+    vmcs: Vmcs,
+    vmread_bitmap: PageBox<Page>,
+    vmwrite_bitmap: PageBox<Page>,
+
+    /// The encodings of the fields of VMCS12 the processor accepts in the
+    /// shadow VMCS.
+    fields: Vec<u32>,
+}
+
+impl ShadowVmcs {
+    /// Creates the shadow VMCS if the processor supports VMCS shadowing, with
+    /// VMCS01 at `vmcs01` made current again.
+    fn try_new(vmcs01: u64) -> Result<Option<Self>, HvError> {
+        let allowed1 = rdmsr(IA32_VMX_PROCBASED_CTLS2) >> 32;
+        if !rdmsr(IA32_VMX_PROCBASED_CTLS).get_bit(63)
+            || allowed1 & u64::from(SecondaryControls::VMCS_SHADOWING.bits()) == 0
+        {
+            return Ok(None);
+        }
+
+        let mut shadow = Self {
+            vmcs: Vmcs::new_shadow()?,
+            vmread_bitmap: PageBox::try_new()?,
+            vmwrite_bitmap: PageBox::try_new()?,
+            fields: Vec::new(),
+        };
+        shadow.vmread_bitmap.0.fill(u8::MAX);
+        shadow.vmwrite_bitmap.0.fill(u8::MAX);
+
+        // Let VMREAD and VMWRITE of the fields the processor accepts, including
+        // the high halves of the 64-bit fields, access the shadow VMCS.
+        vmptrld(&mut shadow.vmcs)?;
+        for &(_, encoding) in FIELDS {
+            if !try_vmwrite(encoding, 0) {
+                continue;
+            }
+            let mut encodings = alloc::vec![encoding];
+            if Width::of(encoding) == Width::Bits64 && try_vmwrite(encoding | 1, 0) {
+                encodings.push(encoding | 1);
+            }
+            for encoding in encodings {
+                let index = (encoding & 0x7fff) as usize;
+                shadow.vmread_bitmap.0[index / 8] &= !(1 << (index % 8));
+                shadow.vmwrite_bitmap.0[index / 8] &= !(1 << (index % 8));
+            }
+            shadow.fields.push(encoding);
+        }
+        vmclear(&mut shadow.vmcs)?;
+        vmptrld_pa(vmcs01)?;
+        Ok(Some(shadow))
+    }
+
+    /// Copies the fields from `vmcs12` into the shadow VMCS, with VMCS01 at
+    /// `vmcs01` made current again.
+    fn store(&mut self, vmcs12: &Vmcs12, vmcs01: u64) {
+        vmptrld(&mut self.vmcs).unwrap();
+        for &encoding in &self.fields {
+            let _ = try_vmwrite(encoding, vmcs12.read(u64::from(encoding)).unwrap());
+        }
+        vmclear(&mut self.vmcs).unwrap();
+        vmptrld_pa(vmcs01).unwrap();
+    }
+
+    /// Copies the fields L1 may have written from the shadow VMCS into
+    /// `vmcs12`, with VMCS01 at `vmcs01` made current again.
+    fn load(&mut self, vmcs12: &mut Vmcs12, vmcs01: u64) {
+        vmptrld(&mut self.vmcs).unwrap();
+        for &encoding in &self.fields {
+            if let Some(value) = try_vmread(encoding) {
+                let _ = vmcs12.write(u64::from(encoding), value, true);
+            }
+        }
+        vmclear(&mut self.vmcs).unwrap();
+        vmptrld_pa(vmcs01).unwrap();
+    }
+}
+
+impl NestedVmx {
+    /// Enters VMX operation of L1 with the VMXON region at `vmxon_pa`, with
+    /// VMCS01 at `vmcs01` made current again.
+    fn try_new(vmxon_pa: u64, vmcs01: u64) -> Result<Self, HvError> {
+        let mut nested = Self {
+            vmxon_pa,
+            vmcs02: Vmcs::new()?,
+            current: None,
+            shadow: ShadowVmcs::try_new(vmcs01)?,
+            msr_bitmaps: PageBox::try_new()?,
+            io_bitmaps: PageBox::try_new()?,
+            ept02: Ept02::try_new()?,
+            eptp12: None,
+            harvested: dirty_tracking::harvested(),
+            tsc_offset12: 0,
+            l1_registers: (0, 0, 0),
+            in_l2: false,
+            init_latched: false,
+            exit_override: None,
+            fault: None,
+            steps: Vec::new(),
+        };
+
+        // L2 exits to the host of L0 as L1 does.
+        let host_fields: Vec<(u32, u64)> = FIELDS
+            .iter()
+            .filter(|&&(_, encoding)| encoding.get_bits(10..=11) == 3)
+            .filter_map(|&(_, encoding)| try_vmread(encoding).map(|value| (encoding, value)))
+            .collect();
+        let cet_fields = [
+            vmcs::host::IA32_S_CET.encoding(),
+            vmcs::host::SSP.encoding(),
+            vmcs::host::IA32_INTERRUPT_SSP_TABLE_ADDR.encoding(),
+        ]
+        .map(|encoding| (encoding, try_vmread(encoding).unwrap_or(0)));
+        vmptrld(&mut nested.vmcs02)?;
+        for &(encoding, value) in &host_fields {
+            let _ = try_vmwrite(encoding, value);
+        }
+        if cet::is_switched() {
+            for (encoding, value) in cet_fields {
+                let _ = try_vmwrite(encoding, value);
+            }
+        }
+        vmptrld_pa(vmcs01)?;
+        Ok(nested)
+    }
+
+    /// Returns whether L2 is running.
+    pub(super) fn in_l2(&self) -> bool {
+        self.in_l2
+    }
+
+    /// Returns whether L2 runs with EPT02.
+    pub(super) fn uses_ept02(&self) -> bool {
+        self.in_l2 && self.eptp12.is_some()
+    }
+
+    /// Latches INIT arrived in VMX root operation of L1 until VMXOFF.
+    pub(super) fn latch_init(&mut self) {
+        self.init_latched = true;
+    }
+
+    /// Returns the current VMCS12 while L2 runs.
+    fn vmcs12(&self) -> &Vmcs12 {
+        &self.current.as_ref().unwrap().1
+    }
+
+    /// Returns whether L1 sets `controls` in the primary processor-based
+    /// VM-execution controls of the current VMCS12.
+    pub(super) fn l1_primary(&self, controls: PrimaryControls) -> bool {
+        self.vmcs12()
+            .get(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32
+            & controls.bits()
+            != 0
+    }
+
+    /// Returns whether L1 sets `controls` in the pin-based VM-execution
+    /// controls of the current VMCS12.
+    pub(super) fn l1_pin_based(&self, controls: PinbasedControls) -> bool {
+        self.vmcs12().get(vmcs::control::PINBASED_EXEC_CONTROLS) as u32 & controls.bits() != 0
+    }
+
+    /// Returns whether L1 acknowledges external interrupts on VM-exit.
+    pub(super) fn l1_acknowledges_interrupts(&self) -> bool {
+        self.vmcs12().get(vmcs::control::VMEXIT_CONTROLS) as u32
+            & ExitControls::ACK_INTERRUPT_ON_EXIT.bits()
+            != 0
+    }
+
+    /// Returns the TSC offset of L2 relative to L1.
+    pub(super) fn tsc_offset12(&self) -> u64 {
+        self.tsc_offset12
+    }
+
+    /// Makes VMCS02 current. Called before L2 resumes after VMCS01 was made
+    /// current to update it.
+    pub(super) fn load_vmcs02(&mut self) {
+        vmptrld(&mut self.vmcs02).unwrap();
+    }
+
+    /// Returns the mapping of the L2 GPA `gpa` if it is mapped to the same L1
+    /// GPA, which is then mapped to the same PA for L1. See
+    /// `Vcpu::resolve_gpa`.
+    pub(super) fn resolve(&self, id: usize, gpa: u64) -> Option<GpaMapping> {
+        let epts = shared_guest_data().epts.read();
+        let Some(eptp12) = self.eptp12 else {
+            return epts.resolve(gpa);
+        };
+        let Ept12Walk::Mapped(leaf) = walk_ept12(&epts, id, eptp12, gpa) else {
+            return None;
+        };
+        if leaf.translate(gpa) != gpa {
+            return None;
+        }
+        epts.resolve(gpa).map(|mapping| GpaMapping {
+            permissions: intersect(mapping.permissions, leaf.permissions),
+            ram: mapping.ram,
+        })
+    }
+
+    /// Unmaps everything from EPT02 if it caches translations stale for the
+    /// current EPTP12. Called before L2 resumes.
+    pub(super) fn refresh_ept02(&mut self) {
+        let harvested = dirty_tracking::harvested();
+        if self.harvested != harvested {
+            self.harvested = harvested;
+            self.ept02.reset();
+        }
+    }
+
+    /// Unmaps everything from EPT02, for the change of EPT01.
+    pub(super) fn reset_ept02(&mut self) {
+        self.ept02.reset();
+    }
+
+    /// Maps the page of the last EPT violation L0 handles in EPT02 to the
+    /// original page for data accesses, or to the shadow page at `shadow_pa`
+    /// for execution, as the hooked page at the L1 GPA `info.gpa`. The others
+    /// see the page as the step view does for L1, without single-stepping.
+    pub(super) fn map_hooked(&mut self, info: &NestedPageFaultInfo, shadow_pa: u64) {
+        let Some((gpa, leaf)) = self.fault.take() else {
+            return;
+        };
+        let (pa, permissions) = if info.execute {
+            let execute_only = rdmsr(IA32_VMX_EPT_VPID_CAP).get_bit(0);
+            let permissions = if execute_only {
+                Permissions::EXECUTE_ONLY
+            } else {
+                Permissions::READ_EXECUTE
+            };
+            (shadow_pa, permissions)
+        } else {
+            let epts = shared_guest_data().epts.read();
+            let Some(original) = epts.leaf(info.gpa) else {
+                return;
+            };
+            (original.pa, Permissions::READ_WRITE)
+        };
+        let permissions = intersect(permissions, leaf.permissions);
+        self.ept02.map(
+            gpa,
+            pa,
+            BASE_PAGE_SIZE as u64,
+            permissions,
+            leaf.memory_type,
+            leaf.ignore_pat,
+        );
+    }
+
+    /// Maps the page of the last EPT violation L0 handles in EPT02 to the page
+    /// of the L1 GPA with the permissions of EPT12 only, until `end_steps`. The caller
+    /// single-steps the instruction. See `VmxGuest::allow_access_once`.
+    pub(super) fn map_for_step(&mut self) {
+        let Some((gpa, leaf)) = self.fault.take() else {
+            return;
+        };
+        let Some(original) = shared_guest_data().epts.read().leaf(leaf.translate(gpa)) else {
+            return;
+        };
+        self.ept02.map(
+            gpa,
+            original.pa,
+            BASE_PAGE_SIZE as u64,
+            leaf.permissions,
+            leaf.memory_type,
+            leaf.ignore_pat,
+        );
+        self.steps.push(gpa);
+    }
+
+    /// Unmaps the pages mapped with `map_for_step`, so that the next accesses
+    /// are mapped with the permissions of EPT01 again.
+    pub(super) fn end_steps(&mut self) {
+        for gpa in core::mem::take(&mut self.steps) {
+            self.ept02.unmap(gpa);
+        }
+    }
+
+    /// Makes VMCS01 at `vmcs01` current, and clears VMCS02 and the shadow
+    /// VMCS, for leaving VMX operation of L1 or devirtualizing the processor.
+    pub(super) fn release(&mut self, vmcs01: u64) {
+        if self.in_l2 {
+            vmptrld_pa(vmcs01).unwrap();
+            self.in_l2 = false;
+        }
+        let _ = vmclear(&mut self.vmcs02);
+        if let Some(shadow) = &mut self.shadow {
+            let _ = vmclear(&mut shadow.vmcs);
+        }
+        disable_shadowing();
+    }
+}
+
+/// Returns the accesses both `a` and `b` permit.
+fn intersect(a: Permissions, b: Permissions) -> Permissions {
+    Permissions {
+        read: a.read && b.read,
+        write: a.write && b.write,
+        execute: a.execute && b.execute,
+    }
+}
+
+/// Returns the mapping of the L1 GPA `gpa` if it is RAM L1 can read and write,
+/// for the structures L1 refers to by GPAs.
+fn resolve_l1(epts: &Epts, gpa: u64) -> Option<GpaMapping> {
+    epts.resolve(gpa)
+        .filter(|mapping| mapping.ram && mapping.permissions.write)
+}
+
+/// Reads `buffer.len()` bytes from the L1 GPA `gpa`.
+fn read_l1(id: usize, gpa: u64, buffer: &mut [u8]) -> Result<(), TranslationError> {
+    let epts = shared_guest_data().epts.read();
+    guest_memory::read_physical(id, gpa, buffer, |gpa| resolve_l1(&epts, gpa))
+}
+
+/// Writes `data` to the L1 GPA `gpa`.
+fn write_l1(id: usize, gpa: u64, data: &[u8]) -> Result<(), TranslationError> {
+    let epts = shared_guest_data().epts.read();
+    guest_memory::write_physical(id, gpa, data, |gpa| resolve_l1(&epts, gpa))
+}
+
+/// Returns the EPT features reported to L1.
+fn ept12_features() -> Ept12Features {
+    let capabilities = capability(IA32_VMX_EPT_VPID_CAP);
+    Ept12Features {
+        execute_only: capabilities.get_bit(0),
+        page_2mb: capabilities.get_bit(16),
+        page_1gb: capabilities.get_bit(17),
+        pa_bits: pa_bits(),
+    }
+}
+
+/// Returns the physical-address width of the processor (MAXPHYADDR).
+fn pa_bits() -> u8 {
+    cpuid!(0x8000_0008).eax.get_bits(0..=7) as u8
+}
+
+/// Translates the L2 GPA `gpa` with EPT12 of `eptp12`.
+fn walk_ept12(epts: &Epts, id: usize, eptp12: u64, gpa: u64) -> Ept12Walk {
+    shadow_ept::walk(
+        eptp12 & !(BASE_PAGE_SIZE as u64 - 1),
+        gpa,
+        &ept12_features(),
+        |entry_gpa| {
+            let mut entry = [0u8; 8];
+            guest_memory::read_physical(id, entry_gpa, &mut entry, |gpa| {
+                epts.resolve(gpa).filter(|mapping| mapping.ram)
+            })
+            .ok()?;
+            Some(u64::from_le_bytes(entry))
+        },
+    )
+}
+
+/// Disables VMCS shadowing in the current VMCS01.
+fn disable_shadowing() {
+    let secondary = vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS.read();
+    vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS
+        .write(secondary & !SecondaryControls::VMCS_SHADOWING.bits());
+    vmcs::guest::LINK_PTR_FULL.write(u64::MAX);
+}
+
+/// A VMX instruction outcome, reported to L1 in RFLAGS.
+// See: 31.2 CONVENTIONS
+enum Outcome {
+    /// VMsucceed.
+    Succeed,
+    /// VMfailInvalid.
+    FailInvalid,
+    /// VMfailValid with the VM-instruction error number, or VMfailInvalid if
+    /// there is no current VMCS.
+    FailValid(u32),
+}
+
+/// Emulates the VMX instruction with the exit reason `reason` L1 executed,
+/// with VMCS01 at `vmcs01` current. `nested` is the VMX operation of L1 if
+/// any, updated by VMXON and VMXOFF. `keep_dr7` tells L0 manages DR7 of L1.
+// See: 31.3 VMX INSTRUCTIONS
+pub(super) fn emulate(
+    nested: &mut Option<Box<NestedVmx>>,
+    vcpu: &mut dyn Vcpu,
+    vmcs01: u64,
+    reason: u32,
+    keep_dr7: bool,
+) -> Emulation {
+    const CR4_VMXE: u64 = 1 << 13;
+    const EFER_LMA: u64 = 1 << 10;
+
+    // VMX operation is supported only in 64-bit mode, including VMX
+    // instructions. Deliver #UD as VMXON does without CR4.VMXE, and the others
+    // without VMX operation, unlike the processor in the other modes.
+    let long_mode =
+        vcpu.efer() & EFER_LMA != 0 && vcpu.segment(SegmentRegister::Cs).attributes.get_bit(9);
+    let vmxe = vmcs::control::CR4_READ_SHADOW.read() & CR4_VMXE != 0;
+    if !long_mode || (reason == EXIT_REASON_VMXON && !vmxe) {
+        inject_exception(vcpu, 6, None);
+        return Emulation::Completed;
+    }
+    let Some(state) = nested else {
+        if reason == EXIT_REASON_VMXON {
+            if let Some(state) = vmxon(vcpu, vmcs01) {
+                *nested = Some(state);
+            }
+        } else {
+            inject_exception(vcpu, 6, None);
+        }
+        return Emulation::Completed;
+    };
+    if vcpu.cpl() != 0 {
+        inject_exception(vcpu, 13, Some(0));
+        return Emulation::Completed;
+    }
+
+    // Take the fields L1 wrote through the shadow VMCS.
+    if let (Some(shadow), Some((_, vmcs12))) = (&mut state.shadow, &mut state.current) {
+        shadow.load(vmcs12, vmcs01);
+    }
+
+    let outcome = match reason {
+        EXIT_REASON_VMXON => Some(Outcome::FailValid(ERROR_VMXON_IN_VMX_ROOT)),
+        EXIT_REASON_VMXOFF => {
+            let init_latched = state.init_latched;
+            if let Some((pa, vmcs12)) = &state.current {
+                store_vmcs12(vcpu.id(), *pa, vmcs12);
+            }
+            state.release(vmcs01);
+            *nested = None;
+            complete(vcpu, None, &Outcome::Succeed);
+            return if init_latched {
+                Emulation::InitLatched
+            } else {
+                Emulation::Completed
+            };
+        }
+        EXIT_REASON_VMPTRLD => state.vmptrld(vcpu),
+        EXIT_REASON_VMPTRST => state.vmptrst(vcpu),
+        EXIT_REASON_VMCLEAR => state.vmclear(vcpu),
+        EXIT_REASON_VMREAD => state.vmread(vcpu),
+        EXIT_REASON_VMWRITE => state.vmwrite(vcpu),
+        EXIT_REASON_INVEPT => state.invept(vcpu),
+        EXIT_REASON_INVVPID => invvpid(vcpu),
+        EXIT_REASON_VMLAUNCH | EXIT_REASON_VMRESUME => {
+            match state.enter(vcpu, vmcs01, reason == EXIT_REASON_VMLAUNCH, keep_dr7) {
+                Ok(true) => return Emulation::Entered,
+                Ok(false) => None,
+                Err(outcome) => Some(outcome),
+            }
+        }
+        _ => unreachable!(),
+    };
+
+    // An instruction that faulted on its memory operand has not completed.
+    if let Some(outcome) = outcome {
+        let vmcs12 = state.current.as_mut().map(|(_, vmcs12)| vmcs12.as_mut());
+        complete(vcpu, vmcs12, &outcome);
+    }
+    state.sync_shadow(vmcs01);
+    Emulation::Completed
+}
+
+/// Emulates VMXON outside VMX operation. Returns the VMX operation entered.
+fn vmxon(vcpu: &mut dyn Vcpu, vmcs01: u64) -> Option<Box<NestedVmx>> {
+    if vcpu.cpl() != 0 || !has_fixed_cr0_bits() {
+        inject_exception(vcpu, 13, Some(0));
+        return None;
+    }
+    let gva = memory_operand(vcpu)?;
+    let mut pa = [0u8; 8];
+    if let Err(err) = guest_memory::read_guest_checked(vcpu, gva, &mut pa) {
+        let _ = guest_memory::inject_fault(vcpu, &err, false);
+        return None;
+    }
+    let pa = u64::from_le_bytes(pa);
+    let mut revision = [0u8; 4];
+    if !is_valid_pa(pa) || read_l1(vcpu.id(), pa, &mut revision).is_err() {
+        complete(vcpu, None, &Outcome::FailInvalid);
+        return None;
+    }
+    if u32::from_le_bytes(revision) != capability(IA32_VMX_BASIC) as u32 {
+        complete(vcpu, None, &Outcome::FailInvalid);
+        return None;
+    }
+    match NestedVmx::try_new(pa, vmcs01) {
+        Ok(nested) => {
+            complete(vcpu, None, &Outcome::Succeed);
+            Some(Box::new(nested))
+        }
+        Err(err) => {
+            log::error!("Could not enter VMX operation of the guest: {err}");
+            vmptrld_pa(vmcs01).unwrap();
+            complete(vcpu, None, &Outcome::FailInvalid);
+            None
+        }
+    }
+}
+
+/// Returns whether CR0 of L1 has the bits VMX operation requires set.
+fn has_fixed_cr0_bits() -> bool {
+    let mask = vmcs::control::CR0_GUEST_HOST_MASK.read();
+    let cr0 = (vmcs::guest::CR0.read() & !mask) | (vmcs::control::CR0_READ_SHADOW.read() & mask);
+    let fixed0 = capability(IA32_VMX_CR0_FIXED0);
+    cr0 & fixed0 == fixed0
+}
+
+/// Returns whether `pa` is 4KB-aligned and within the physical-address width.
+fn is_valid_pa(pa: u64) -> bool {
+    pa & (BASE_PAGE_SIZE as u64 - 1) == 0 && pa >> pa_bits() == 0
+}
+
+/// Writes `vmcs12` into the VMCS region at the L1 GPA `pa`.
+fn store_vmcs12(id: usize, pa: u64, vmcs12: &Vmcs12) {
+    let mut region = [0u8; REGION_SIZE];
+    if read_l1(id, pa, &mut region).is_ok() {
+        vmcs12.to_region(&mut region);
+        let _ = write_l1(id, pa, &region);
+    }
+}
+
+/// Advances RIP of L1 past the VMX instruction, and reports `outcome` in
+/// RFLAGS and in the VM-instruction error field of `vmcs12`.
+fn complete(vcpu: &mut dyn Vcpu, vmcs12: Option<&mut Vmcs12>, outcome: &Outcome) {
+    let arithmetic = RFlags::FLAGS_CF
+        | RFlags::FLAGS_PF
+        | RFlags::FLAGS_AF
+        | RFlags::FLAGS_ZF
+        | RFlags::FLAGS_SF
+        | RFlags::FLAGS_OF;
+    let flags = match (outcome, vmcs12) {
+        (Outcome::Succeed, _) => RFlags::empty(),
+        (Outcome::FailInvalid, _) | (Outcome::FailValid(_), None) => RFlags::FLAGS_CF,
+        (Outcome::FailValid(error), Some(vmcs12)) => {
+            vmcs12.set(vmcs::ro::VM_INSTRUCTION_ERROR, u64::from(*error));
+            RFlags::FLAGS_ZF
+        }
+    };
+    let length = u64::from(vmcs::ro::VMEXIT_INSTRUCTION_LEN.read());
+    let regs = vcpu.regs();
+    regs.rip += length;
+    regs.rflags = (regs.rflags & !arithmetic.bits()) | flags.bits();
+}
+
+/// Injects the exception `vector` into the guest.
+fn inject_exception(vcpu: &mut dyn Vcpu, vector: u8, error_code: Option<u32>) {
+    let exception = Event::Exception { vector, error_code };
+    if let Err(err) = event::inject_event(vcpu, exception) {
+        log::error!("Could not inject exception {vector}: {err}");
+    }
+}
+
+/// Returns the linear address of the memory operand of the VMX instruction,
+/// or injects #UD for a register operand. In 64-bit mode, only the FS and GS
+/// bases apply.
+// See: Table 28-13. Format of the VM-Exit Instruction-Information Field as
+//      Used for VMCLEAR, VMPTRLD, VMPTRST, VMXON, XRSTORS, and XSAVES
+fn memory_operand(vcpu: &mut dyn Vcpu) -> Option<u64> {
+    let info = u64::from(vmcs::ro::VMEXIT_INSTRUCTION_INFO.read());
+    if info.get_bit(10) {
+        inject_exception(vcpu, 6, None);
+        return None;
+    }
+    let regs = *vcpu.regs();
+    let mut address = vmcs::ro::EXIT_QUALIFICATION.read();
+    if !info.get_bit(22) {
+        address =
+            address.wrapping_add(regs.gpr(info.get_bits(18..=21) as u8) << info.get_bits(0..=1));
+    }
+    if !info.get_bit(27) {
+        address = address.wrapping_add(regs.gpr(info.get_bits(23..=26) as u8));
+    }
+    address &= match info.get_bits(7..=9) {
+        0 => 0xffff,
+        1 => 0xffff_ffff,
+        _ => u64::MAX,
+    };
+    let segment = SegmentRegister::from_number(info.get_bits(15..=17));
+    if matches!(segment, SegmentRegister::Fs | SegmentRegister::Gs) {
+        address = address.wrapping_add(vcpu.segment(segment).base);
+    }
+    Some(address)
+}
+
+/// Reads the 64-bit memory operand of the VMX instruction, or injects the
+/// fault.
+fn read_operand(vcpu: &mut dyn Vcpu) -> Option<u64> {
+    let gva = memory_operand(vcpu)?;
+    let mut value = [0u8; 8];
+    if let Err(err) = guest_memory::read_guest_checked(vcpu, gva, &mut value) {
+        let _ = guest_memory::inject_fault(vcpu, &err, false);
+        return None;
+    }
+    Some(u64::from_le_bytes(value))
+}
+
+/// Emulates INVVPID. VMCS02 does not enable VPIDs, so that VM-entry and
+/// VM-exit invalidate the translations of L2, and only the operand is checked.
+// See: INVVPID—Invalidate Translations Based on VPID
+fn invvpid(vcpu: &mut dyn Vcpu) -> Option<Outcome> {
+    let info = u64::from(vmcs::ro::VMEXIT_INSTRUCTION_INFO.read());
+    let invalidation = vcpu.regs().gpr(info.get_bits(28..=31) as u8);
+    let gva = memory_operand(vcpu)?;
+    let mut descriptor = [0u8; 16];
+    if let Err(err) = guest_memory::read_guest_checked(vcpu, gva, &mut descriptor) {
+        let _ = guest_memory::inject_fault(vcpu, &err, false);
+        return None;
+    }
+    let vpid = u64::from_le_bytes(descriptor[..8].try_into().unwrap());
+    let address = u64::from_le_bytes(descriptor[8..].try_into().unwrap());
+    let canonical = ((address as i64) << 16 >> 16) as u64 == address;
+    let supported =
+        invalidation < 4 && capability(IA32_VMX_EPT_VPID_CAP).get_bit(40 + invalidation as usize);
+    if !supported
+        || vpid >> 16 != 0
+        || (invalidation != 2 && vpid == 0)
+        || (invalidation == 0 && !canonical)
+    {
+        return Some(Outcome::FailValid(ERROR_INVALID_INVEPT_INVVPID_OPERAND));
+    }
+    Some(Outcome::Succeed)
+}
+
+impl NestedVmx {
+    /// Emulates VMPTRLD.
+    fn vmptrld(&mut self, vcpu: &mut dyn Vcpu) -> Option<Outcome> {
+        let pa = read_operand(vcpu)?;
+        if !is_valid_pa(pa) {
+            return Some(Outcome::FailValid(ERROR_VMPTRLD_INVALID_ADDRESS));
+        }
+        if pa == self.vmxon_pa {
+            return Some(Outcome::FailValid(ERROR_VMPTRLD_VMXON_POINTER));
+        }
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|(current, _)| *current == pa)
+        {
+            return Some(Outcome::Succeed);
+        }
+        let mut region = [0u8; REGION_SIZE];
+        if read_l1(vcpu.id(), pa, &mut region).is_err() {
+            return Some(Outcome::FailValid(ERROR_VMPTRLD_INVALID_ADDRESS));
+        }
+        let revision = u32::from_le_bytes(region[..4].try_into().unwrap());
+        if revision != capability(IA32_VMX_BASIC) as u32 {
+            return Some(Outcome::FailValid(ERROR_VMPTRLD_INCORRECT_REVISION));
+        }
+        if let Some((current, vmcs12)) = &self.current {
+            store_vmcs12(vcpu.id(), *current, vmcs12);
+        }
+        self.current = Some((pa, Box::new(Vmcs12::from_region(&region))));
+        Some(Outcome::Succeed)
+    }
+
+    /// Emulates VMPTRST.
+    fn vmptrst(&mut self, vcpu: &mut dyn Vcpu) -> Option<Outcome> {
+        let gva = memory_operand(vcpu)?;
+        let pa = self.current.as_ref().map_or(u64::MAX, |(pa, _)| *pa);
+        if let Err(err) = guest_memory::write_guest_checked(vcpu, gva, &pa.to_le_bytes()) {
+            let _ = guest_memory::inject_fault(vcpu, &err, true);
+            return None;
+        }
+        Some(Outcome::Succeed)
+    }
+
+    /// Emulates VMCLEAR.
+    fn vmclear(&mut self, vcpu: &mut dyn Vcpu) -> Option<Outcome> {
+        const LAUNCH_STATE_OFFSET: u64 = 8;
+
+        let pa = read_operand(vcpu)?;
+        if !is_valid_pa(pa) {
+            return Some(Outcome::FailValid(ERROR_VMCLEAR_INVALID_ADDRESS));
+        }
+        if pa == self.vmxon_pa {
+            return Some(Outcome::FailValid(ERROR_VMCLEAR_VMXON_POINTER));
+        }
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|(current, _)| *current == pa)
+        {
+            let (_, mut vmcs12) = self.current.take().unwrap();
+            vmcs12.set_launched(false);
+            store_vmcs12(vcpu.id(), pa, &vmcs12);
+            return Some(Outcome::Succeed);
+        }
+        if write_l1(vcpu.id(), pa + LAUNCH_STATE_OFFSET, &[0; 8]).is_err() {
+            return Some(Outcome::FailValid(ERROR_VMCLEAR_INVALID_ADDRESS));
+        }
+        Some(Outcome::Succeed)
+    }
+
+    /// Emulates VMREAD.
+    fn vmread(&mut self, vcpu: &mut dyn Vcpu) -> Option<Outcome> {
+        // See: Table 28-15. Format of the VM-Exit Instruction-Information Field
+        //      as Used for VMREAD and VMWRITE
+        let info = u64::from(vmcs::ro::VMEXIT_INSTRUCTION_INFO.read());
+        let Some((_, vmcs12)) = &self.current else {
+            return Some(Outcome::FailInvalid);
+        };
+        let encoding = vcpu.regs().gpr(info.get_bits(28..=31) as u8);
+        let value = match vmcs12.read(encoding) {
+            Ok(value) => value,
+            Err(err) => return Some(Outcome::FailValid(err as u32)),
+        };
+        if info.get_bit(10) {
+            vcpu.regs().set_gpr(info.get_bits(3..=6) as u8, value);
+        } else {
+            let gva = memory_operand(vcpu)?;
+            if let Err(err) = guest_memory::write_guest_checked(vcpu, gva, &value.to_le_bytes()) {
+                let _ = guest_memory::inject_fault(vcpu, &err, true);
+                return None;
+            }
+        }
+        Some(Outcome::Succeed)
+    }
+
+    /// Emulates VMWRITE.
+    fn vmwrite(&mut self, vcpu: &mut dyn Vcpu) -> Option<Outcome> {
+        let info = u64::from(vmcs::ro::VMEXIT_INSTRUCTION_INFO.read());
+        if self.current.is_none() {
+            return Some(Outcome::FailInvalid);
+        }
+        let encoding = vcpu.regs().gpr(info.get_bits(28..=31) as u8);
+        let value = if info.get_bit(10) {
+            vcpu.regs().gpr(info.get_bits(3..=6) as u8)
+        } else {
+            let gva = memory_operand(vcpu)?;
+            let mut value = [0u8; 8];
+            if let Err(err) = guest_memory::read_guest_checked(vcpu, gva, &mut value) {
+                let _ = guest_memory::inject_fault(vcpu, &err, false);
+                return None;
+            }
+            u64::from_le_bytes(value)
+        };
+        let write_read_only = capability(IA32_VMX_MISC).get_bit(29);
+        let (_, vmcs12) = self.current.as_mut().unwrap();
+        Some(match vmcs12.write(encoding, value, write_read_only) {
+            Ok(()) => Outcome::Succeed,
+            Err(err @ (FieldError::Unsupported | FieldError::ReadOnly)) => {
+                Outcome::FailValid(err as u32)
+            }
+        })
+    }
+
+    /// Emulates INVEPT. EPT02 is reset for any EPTP, so that it never caches
+    /// translations of EPT12 L1 invalidated.
+    // See: INVEPT—Invalidate Translations Derived from EPT
+    fn invept(&mut self, vcpu: &mut dyn Vcpu) -> Option<Outcome> {
+        let info = u64::from(vmcs::ro::VMEXIT_INSTRUCTION_INFO.read());
+        let invalidation = vcpu.regs().gpr(info.get_bits(28..=31) as u8);
+        let gva = memory_operand(vcpu)?;
+        let mut descriptor = [0u8; 16];
+        if let Err(err) = guest_memory::read_guest_checked(vcpu, gva, &mut descriptor) {
+            let _ = guest_memory::inject_fault(vcpu, &err, false);
+            return None;
+        }
+        let capabilities = capability(IA32_VMX_EPT_VPID_CAP);
+        let supported = match invalidation {
+            1 => capabilities.get_bit(25),
+            2 => capabilities.get_bit(26),
+            _ => false,
+        };
+        if !supported {
+            return Some(Outcome::FailValid(ERROR_INVALID_INVEPT_INVVPID_OPERAND));
+        }
+        self.ept02.reset();
+        self.eptp12 = None;
+        Some(Outcome::Succeed)
+    }
+
+    /// Copies the current VMCS12 into the shadow VMCS and lets L1 access it
+    /// through VMCS01 at `vmcs01`, if VMCS shadowing is supported.
+    fn sync_shadow(&mut self, vmcs01: u64) {
+        let Some(shadow) = &mut self.shadow else {
+            return;
+        };
+        let secondary = vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS.read();
+        vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS
+            .write(secondary | SecondaryControls::VMCS_SHADOWING.bits());
+        vmcs::control::VMREAD_BITMAP_ADDR_FULL.write(shadow.vmread_bitmap.pa());
+        vmcs::control::VMWRITE_BITMAP_ADDR_FULL.write(shadow.vmwrite_bitmap.pa());
+        match &self.current {
+            Some((_, vmcs12)) => {
+                shadow.store(vmcs12, vmcs01);
+                vmcs::guest::LINK_PTR_FULL.write(shadow.vmcs.pa());
+            }
+            // VMREAD and VMWRITE fail without a current VMCS.
+            None => vmcs::guest::LINK_PTR_FULL.write(u64::MAX),
+        }
+    }
+}
+
+impl NestedVmx {
+    /// Emulates VMLAUNCH if `launch`, or VMRESUME. Returns whether L2 is
+    /// entered with VMCS02 current, or `false` if the VM-entry failed on the
+    /// guest state or MSR loading, which returns to the host of L1 as the
+    /// processor does. The checks on the guest state are left to the processor
+    /// except those this module depends on.
+    // See: CHAPTER 27 VM ENTRIES
+    fn enter(
+        &mut self,
+        vcpu: &mut dyn Vcpu,
+        vmcs01: u64,
+        launch: bool,
+        keep_dr7: bool,
+    ) -> Result<bool, Outcome> {
+        const BLOCKING_BY_MOV_SS: u32 = 1 << 1;
+
+        let Some((_, vmcs12)) = &self.current else {
+            return Err(Outcome::FailInvalid);
+        };
+        if vmcs::guest::INTERRUPTIBILITY_STATE.read() & BLOCKING_BY_MOV_SS != 0 {
+            return Err(Outcome::FailValid(ERROR_ENTRY_BLOCKED_BY_MOV_SS));
+        }
+        if launch && vmcs12.is_launched() {
+            return Err(Outcome::FailValid(ERROR_VMLAUNCH_NON_CLEAR));
+        }
+        if !launch && !vmcs12.is_launched() {
+            return Err(Outcome::FailValid(ERROR_VMRESUME_NON_LAUNCHED));
+        }
+        if !has_valid_controls(vmcs12) {
+            return Err(Outcome::FailValid(ERROR_INVALID_CONTROL_FIELDS));
+        }
+        if !has_valid_host_state(vmcs12) {
+            return Err(Outcome::FailValid(ERROR_INVALID_HOST_STATE));
+        }
+        if self.merge_bitmaps(vcpu.id()).is_err() {
+            return Err(Outcome::FailValid(ERROR_INVALID_CONTROL_FIELDS));
+        }
+
+        let id = vcpu.id();
+        let vmcs12 = &self.current.as_ref().unwrap().1;
+        let pdptes = match check_guest_state(id, vmcs12) {
+            Ok(pdptes) => pdptes,
+            Err((reason, qualification)) => {
+                let vmcs12 = &mut self.current.as_mut().unwrap().1;
+                vmcs12.set(
+                    vmcs::ro::EXIT_REASON,
+                    u64::from(reason | EXIT_REASON_ENTRY_FAILURE),
+                );
+                vmcs12.set(vmcs::ro::EXIT_QUALIFICATION, qualification);
+                self.exit_to_l1(vcpu, vmcs01, false, keep_dr7);
+                return Ok(false);
+            }
+        };
+        let regs = *vcpu.regs();
+        self.l1_registers = (regs.rip, regs.rsp, regs.rflags);
+
+        // Take the state of L1 VMCS02 inherits from VMCS01.
+        let pin01 = vmcs::control::PINBASED_EXEC_CONTROLS.read();
+        let primary01 = vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS.read();
+        let secondary01 = vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS.read();
+        let exit01 = vmcs::control::VMEXIT_CONTROLS.read();
+        let entry01 = vmcs::control::VMENTRY_CONTROLS.read();
+        let tsc_offset01 = if primary01 & PrimaryControls::USE_TSC_OFFSETTING.bits() != 0 {
+            vmcs::control::TSC_OFFSET_FULL.read()
+        } else {
+            0
+        };
+        let tsc_multiplier01 = (secondary01 & SecondaryControls::USE_TSC_SCALING.bits() != 0)
+            .then(|| vmcs::control::TSC_MULTIPLIER_FULL.read());
+        let dr7_01 = vmcs::guest::DR7.read();
+        let debugctl01 = vmcs::guest::IA32_DEBUGCTL_FULL.read();
+        let efer01 = vmcs::guest::IA32_EFER_FULL.read();
+        let pat01 = vmcs::guest::IA32_PAT_FULL.read();
+        let cet01 = [
+            vmcs::guest::IA32_S_CET.encoding(),
+            vmcs::guest::SSP.encoding(),
+            vmcs::guest::IA32_INTERRUPT_SSP_TABLE_ADDR.encoding(),
+        ]
+        .map(|encoding| (encoding, try_vmread(encoding).unwrap_or(0)));
+        let eptp01 = shared_guest_data().epts.read().eptp().0;
+
+        let pin12 = vmcs12.get(vmcs::control::PINBASED_EXEC_CONTROLS) as u32;
+        let primary12 = vmcs12.get(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32;
+        let secondary12 = secondary_controls12(vmcs12);
+        let exit12 = vmcs12.get(vmcs::control::VMEXIT_CONTROLS) as u32;
+        let entry12 = vmcs12.get(vmcs::control::VMENTRY_CONTROLS) as u32;
+        let eptp12 = (secondary12 & SecondaryControls::ENABLE_EPT.bits() != 0)
+            .then(|| vmcs12.get(vmcs::control::EPTP_FULL));
+        if eptp12 != self.eptp12 {
+            self.ept02.reset();
+            self.eptp12 = eptp12;
+        }
+
+        // L0 keeps intercepting NMIs, the VMX-preemption timer, and the I/O
+        // and MSRs in its bitmaps, and L1 gets the rest of the controls it
+        // sets. See `route`.
+        let pin02 = PinbasedControls::NMI_EXITING.bits()
+            | PinbasedControls::VIRTUAL_NMIS.bits()
+            | pin12
+            | pin01 & PinbasedControls::VMX_PREEMPTION_TIMER.bits();
+        let l1_bitmaps = PrimaryControls::USE_IO_BITMAPS.bits()
+            | PrimaryControls::UNCOND_IO_EXITING.bits()
+            | PrimaryControls::USE_MSR_BITMAPS.bits();
+        let mut primary02 = (primary12 & !l1_bitmaps)
+            | PrimaryControls::USE_IO_BITMAPS.bits()
+            | PrimaryControls::SECONDARY_CONTROLS.bits()
+            | primary01 & PrimaryControls::USE_TSC_OFFSETTING.bits()
+            | primary12 & PrimaryControls::USE_MSR_BITMAPS.bits();
+        let secondary02 = (secondary12
+            & !(SecondaryControls::ENABLE_VPID.bits() | SecondaryControls::VMCS_SHADOWING.bits()))
+            | SecondaryControls::ENABLE_EPT.bits()
+            | secondary01
+                & (SecondaryControls::WBINVD_EXITING.bits()
+                    | SecondaryControls::USE_TSC_SCALING.bits());
+        let exit02 = (exit01 & !ExitControls::ACK_INTERRUPT_ON_EXIT.bits())
+            | exit12 & ExitControls::ACK_INTERRUPT_ON_EXIT.bits()
+            | ExitControls::SAVE_DEBUG_CONTROLS.bits();
+        let entry02 = (entry01 & !EntryControls::IA32E_MODE_GUEST.bits())
+            | entry12 & EntryControls::IA32E_MODE_GUEST.bits()
+            | EntryControls::LOAD_DEBUG_CONTROLS.bits();
+        self.tsc_offset12 = if primary12 & PrimaryControls::USE_TSC_OFFSETTING.bits() != 0 {
+            primary02 |= PrimaryControls::USE_TSC_OFFSETTING.bits();
+            vmcs12.get(vmcs::control::TSC_OFFSET_FULL)
+        } else {
+            0
+        };
+        let eptp02 = match eptp12 {
+            Some(_) => self.ept02.eptp().0,
+            None => eptp01,
+        };
+
+        // The debug controls, IA32_EFER and IA32_PAT are loaded from VMCS02
+        // regardless, as L1 has them if VMCS12 does not load them.
+        let ia32e = entry12 & EntryControls::IA32E_MODE_GUEST.bits() != 0;
+        let load_debug = entry12 & EntryControls::LOAD_DEBUG_CONTROLS.bits() != 0;
+        let (dr7, debugctl) = if load_debug {
+            (
+                vmcs12.get(vmcs::guest::DR7),
+                vmcs12.get(vmcs::guest::IA32_DEBUGCTL_FULL),
+            )
+        } else {
+            (dr7_01, debugctl01)
+        };
+        let efer = if entry12 & EntryControls::LOAD_IA32_EFER.bits() != 0 {
+            vmcs12.get(vmcs::guest::IA32_EFER_FULL)
+        } else if ia32e {
+            efer01 | EFER_LMA | EFER_LME
+        } else {
+            efer01 & !(EFER_LMA | EFER_LME)
+        };
+        let pat = if entry12 & EntryControls::LOAD_IA32_PAT.bits() != 0 {
+            vmcs12.get(vmcs::guest::IA32_PAT_FULL)
+        } else {
+            pat01
+        };
+
+        vmptrld(&mut self.vmcs02).unwrap();
+        for &(_, encoding) in FIELDS {
+            if is_copied_guest_field(encoding) {
+                let _ = try_vmwrite(encoding, vmcs12.read(u64::from(encoding)).unwrap());
+            }
+        }
+        vmcs::guest::DR7.write(dr7);
+        vmcs::guest::IA32_DEBUGCTL_FULL.write(debugctl);
+        vmcs::guest::IA32_EFER_FULL.write(efer);
+        vmcs::guest::IA32_PAT_FULL.write(pat);
+        let pdpte_fields = [
+            vmcs::guest::PDPTE0_FULL,
+            vmcs::guest::PDPTE1_FULL,
+            vmcs::guest::PDPTE2_FULL,
+            vmcs::guest::PDPTE3_FULL,
+        ];
+        for (field, pdpte) in pdpte_fields.into_iter().zip(pdptes) {
+            field.write(if eptp12.is_some() {
+                vmcs12.get(field)
+            } else {
+                pdpte
+            });
+        }
+        if cet::is_switched() {
+            for (encoding, value) in cet01 {
+                let _ = try_vmwrite(encoding, value);
+            }
+        }
+        vmcs::guest::LINK_PTR_FULL.write(u64::MAX);
+
+        vmcs::control::PINBASED_EXEC_CONTROLS
+            .write(VmxGuest::adjust_vmx_control(VmxControl::PinBased, u64::from(pin02)) as u32);
+        vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS.write(VmxGuest::adjust_vmx_control(
+            VmxControl::ProcessorBased,
+            u64::from(primary02),
+        ) as u32);
+        vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS.write(VmxGuest::adjust_vmx_control(
+            VmxControl::ProcessorBased2,
+            u64::from(secondary02),
+        ) as u32);
+        vmcs::control::VMEXIT_CONTROLS
+            .write(VmxGuest::adjust_vmx_control(VmxControl::VmExit, u64::from(exit02)) as u32);
+        vmcs::control::VMENTRY_CONTROLS
+            .write(VmxGuest::adjust_vmx_control(VmxControl::VmEntry, u64::from(entry02)) as u32);
+        vmcs::control::TSC_OFFSET_FULL.write(tsc_offset01.wrapping_add(self.tsc_offset12));
+        if let Some(multiplier) = tsc_multiplier01 {
+            vmcs::control::TSC_MULTIPLIER_FULL.write(multiplier);
+        }
+        if secondary12 & SecondaryControls::ENABLE_XSAVES_XRSTORS.bits() != 0 {
+            vmcs::control::XSS_EXITING_BITMAP_FULL
+                .write(vmcs12.get(vmcs::control::XSS_EXITING_BITMAP_FULL));
+        }
+        vmcs::control::EPTP_FULL.write(eptp02);
+        vmcs::control::MSR_BITMAPS_ADDR_FULL.write(self.msr_bitmaps.pa());
+        vmcs::control::IO_BITMAP_A_ADDR_FULL.write(self.io_bitmaps.pa());
+        vmcs::control::IO_BITMAP_B_ADDR_FULL.write(self.io_bitmaps.pa() + BASE_PAGE_SIZE as u64);
+
+        // #MC is intercepted for `machine_check`, and delivered to L2 if L1
+        // does not intercept it. See `route`.
+        const MC_VECTOR: u32 = 18;
+        vmcs::control::EXCEPTION_BITMAP
+            .write(vmcs12.get(vmcs::control::EXCEPTION_BITMAP) as u32 | 1 << MC_VECTOR);
+        let copied_controls = [
+            vmcs::control::PAGE_FAULT_ERR_CODE_MASK.encoding(),
+            vmcs::control::PAGE_FAULT_ERR_CODE_MATCH.encoding(),
+            vmcs::control::CR3_TARGET_COUNT.encoding(),
+            vmcs::control::CR3_TARGET_VALUE0.encoding(),
+            vmcs::control::CR3_TARGET_VALUE1.encoding(),
+            vmcs::control::CR3_TARGET_VALUE2.encoding(),
+            vmcs::control::CR3_TARGET_VALUE3.encoding(),
+            vmcs::control::CR0_GUEST_HOST_MASK.encoding(),
+            vmcs::control::CR4_GUEST_HOST_MASK.encoding(),
+            vmcs::control::CR0_READ_SHADOW.encoding(),
+            vmcs::control::CR4_READ_SHADOW.encoding(),
+            vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD.encoding(),
+            vmcs::control::VMENTRY_EXCEPTION_ERR_CODE.encoding(),
+            vmcs::control::VMENTRY_INSTRUCTION_LEN.encoding(),
+        ];
+        for encoding in copied_controls {
+            let _ = try_vmwrite(encoding, vmcs12.read(u64::from(encoding)).unwrap());
+        }
+        vmcs::control::VMEXIT_MSR_STORE_COUNT.write(0);
+        vmcs::control::VMEXIT_MSR_LOAD_COUNT.write(0);
+        vmcs::control::VMENTRY_MSR_LOAD_COUNT.write(0);
+
+        let rip = vmcs12.get(vmcs::guest::RIP);
+        let rsp = vmcs12.get(vmcs::guest::RSP);
+        let rflags = vmcs12.get(vmcs::guest::RFLAGS);
+        let msr_load = msr_list(
+            vmcs12,
+            vmcs::control::VMENTRY_MSR_LOAD_COUNT,
+            vmcs::control::VMENTRY_MSR_LOAD_ADDR_FULL,
+        );
+        for (index, entry) in msr_load.enumerate() {
+            let mut entry_bytes = [0u8; 16];
+            let _ = read_l1(id, entry, &mut entry_bytes);
+            let msr = u32::from_le_bytes(entry_bytes[..4].try_into().unwrap());
+            let value = u64::from_le_bytes(entry_bytes[8..].try_into().unwrap());
+            debug_assert!(is_listable_msr(msr), "{index}: {msr:#x}");
+            vcpu.write_msr(msr, value);
+        }
+        let regs = vcpu.regs();
+        regs.rip = rip;
+        regs.rsp = rsp;
+        regs.rflags = rflags;
+        self.in_l2 = true;
+        Ok(true)
+    }
+
+    /// Reads the MSR and I/O bitmaps of L1 into those of VMCS02, merged with
+    /// those of L0. I/O unconditionally intercepted is intercepted for every
+    /// port with the bitmaps.
+    fn merge_bitmaps(&mut self, id: usize) -> Result<(), TranslationError> {
+        let vmcs12 = &self.current.as_ref().unwrap().1;
+        let primary12 = vmcs12.get(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32;
+        let shared = shared_guest_data();
+
+        if primary12 & PrimaryControls::USE_MSR_BITMAPS.bits() != 0 {
+            let bitmaps = vmcs12.get(vmcs::control::MSR_BITMAPS_ADDR_FULL);
+            read_l1(id, bitmaps, &mut self.msr_bitmaps.0)?;
+            for (merged, l0) in self.msr_bitmaps.0.iter_mut().zip(shared.msr_bitmaps.0) {
+                *merged |= l0;
+            }
+        }
+
+        let l0_io = vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS.read()
+            & PrimaryControls::USE_IO_BITMAPS.bits()
+            != 0;
+        let fields = [
+            vmcs::control::IO_BITMAP_A_ADDR_FULL,
+            vmcs::control::IO_BITMAP_B_ADDR_FULL,
+        ];
+        for (index, field) in fields.into_iter().enumerate() {
+            let merged = &mut self.io_bitmaps[index].0;
+            if primary12 & PrimaryControls::USE_IO_BITMAPS.bits() != 0 {
+                read_l1(id, vmcs12.get(field), merged)?;
+            } else if primary12 & PrimaryControls::UNCOND_IO_EXITING.bits() != 0 {
+                merged.fill(u8::MAX);
+            } else {
+                merged.fill(0);
+            }
+            if l0_io {
+                for (merged, l0) in merged.iter_mut().zip(shared.io_bitmaps[index].0) {
+                    *merged |= l0;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes VMCS01 at `vmcs01` current again after VMCS02 failed VM-entry in
+    /// the processor, and reports the VM-instruction error to L1 as VMLAUNCH
+    /// or VMRESUME failing.
+    pub(super) fn fail_entry(&mut self, vcpu: &mut dyn Vcpu, vmcs01: u64) {
+        let error = vmcs::ro::VM_INSTRUCTION_ERROR.read();
+        log::warn!("VM-entry to the nested guest failed: {error}");
+        vmptrld_pa(vmcs01).unwrap();
+        self.in_l2 = false;
+
+        let (rip, rsp, rflags) = self.l1_registers;
+        let regs = vcpu.regs();
+        regs.rip = rip;
+        regs.rsp = rsp;
+        regs.rflags = rflags;
+        let vmcs12 = self.current.as_mut().map(|(_, vmcs12)| vmcs12.as_mut());
+        complete(vcpu, vmcs12, &Outcome::FailValid(error));
+        self.sync_shadow(vmcs01);
+    }
+
+    /// Decides how the VM-exit from L2 is handled. `l0_step` tells whether L0
+    /// single-steps L2.
+    pub(super) fn route(&mut self, vcpu: &mut dyn Vcpu, l0_step: bool) -> Route {
+        const NMI: u32 = 2;
+        const MC_VECTOR: u32 = 18;
+
+        let reason = vmcs::ro::EXIT_REASON.read();
+        if reason & EXIT_REASON_ENTRY_FAILURE != 0 {
+            return Route::Reflect;
+        }
+        let vmcs12 = self.vmcs12();
+        let reflect_if = |reflect: bool| if reflect { Route::Reflect } else { Route::Host };
+        match reason & 0xffff {
+            EXIT_REASON_EXCEPTION_OR_NMI => {
+                let info = vmcs::ro::VMEXIT_INTERRUPTION_INFO.read();
+                if info.get_bits(8..=10) == NMI {
+                    Route::Host
+                } else if info.get_bits(0..=7) == MC_VECTOR {
+                    let bitmap = vmcs12.get(vmcs::control::EXCEPTION_BITMAP);
+                    reflect_if(bitmap.get_bit(MC_VECTOR as usize))
+                } else {
+                    Route::Reflect
+                }
+            }
+            EXIT_REASON_INTERRUPT_WINDOW => {
+                reflect_if(self.l1_primary(PrimaryControls::INTERRUPT_WINDOW_EXITING))
+            }
+            EXIT_REASON_NMI_WINDOW => {
+                reflect_if(self.l1_primary(PrimaryControls::NMI_WINDOW_EXITING))
+            }
+            EXIT_REASON_MONITOR_TRAP_FLAG => {
+                reflect_if(!l0_step && self.l1_primary(PrimaryControls::MONITOR_TRAP_FLAG))
+            }
+            EXIT_REASON_IO => reflect_if(self.l1_intercepts_io(vcpu.id())),
+            EXIT_REASON_RDMSR | EXIT_REASON_WRMSR => {
+                let msr = vcpu.regs().rcx as u32;
+                let write = reason & 0xffff == EXIT_REASON_WRMSR;
+                reflect_if(self.l1_intercepts_msr(vcpu.id(), msr, write))
+            }
+            EXIT_REASON_WBINVD => reflect_if(
+                secondary_controls12(vmcs12) & SecondaryControls::WBINVD_EXITING.bits() != 0,
+            ),
+            EXIT_REASON_EPT_VIOLATION if self.eptp12.is_some() => self.fill_ept02(vcpu.id()),
+            EXIT_REASON_EPT_VIOLATION
+            | EXIT_REASON_EPT_MISCONFIGURATION
+            | EXIT_REASON_PREEMPTION_TIMER => Route::Host,
+            _ => Route::Reflect,
+        }
+    }
+
+    /// Returns whether L1 intercepts the I/O instruction L2 executed.
+    // See: 26.1.3 Instructions That Cause VM Exits Conditionally
+    fn l1_intercepts_io(&self, id: usize) -> bool {
+        if !self.l1_primary(PrimaryControls::USE_IO_BITMAPS) {
+            return self.l1_primary(PrimaryControls::UNCOND_IO_EXITING);
+        }
+        // See: Table 28-5. Exit Qualification for I/O Instructions
+        let qualification = vmcs::ro::EXIT_QUALIFICATION.read();
+        let port = qualification.get_bits(16..=31) as u32;
+        let size = qualification.get_bits(0..=2) as u32 + 1;
+        let vmcs12 = self.vmcs12();
+        (port..port + size).any(|port| {
+            let bitmap = match port {
+                0..=0x7fff => vmcs12.get(vmcs::control::IO_BITMAP_A_ADDR_FULL),
+                0x8000..=0xffff => vmcs12.get(vmcs::control::IO_BITMAP_B_ADDR_FULL),
+                // An access wrapping around the port space is intercepted.
+                _ => return true,
+            };
+            let index = u64::from(port & 0x7fff);
+            let mut byte = [0u8];
+            read_l1(id, bitmap + index / 8, &mut byte).is_err()
+                || byte[0].get_bit(index as usize % 8)
+        })
+    }
+
+    /// Returns whether L1 intercepts RDMSR or WRMSR of `msr` L2 executed.
+    // See: 25.6.9 MSR-Bitmap Address
+    fn l1_intercepts_msr(&self, id: usize, msr: u32, write: bool) -> bool {
+        if !self.l1_primary(PrimaryControls::USE_MSR_BITMAPS) {
+            return true;
+        }
+        let offset = match msr {
+            0..=0x1fff => 0,
+            0xc000_0000..=0xc000_1fff => 1024,
+            _ => return true,
+        } + if write { 2048 } else { 0 };
+        let index = u64::from(msr & 0x1fff);
+        let bitmap = self.vmcs12().get(vmcs::control::MSR_BITMAPS_ADDR_FULL);
+        let mut byte = [0u8];
+        read_l1(id, bitmap + offset + index / 8, &mut byte).is_err()
+            || byte[0].get_bit(index as usize % 8)
+    }
+
+    /// Handles the EPT violation on EPT02 by translating the L2 GPA with EPT12
+    /// and EPT01, and mapping the page in EPT02 with the permissions of both.
+    /// The violation of EPT12 is reflected into L1, and that of EPT01 is
+    /// handled by L0.
+    // See: 29.3.3.2 EPT Violations
+    fn fill_ept02(&mut self, id: usize) -> Route {
+        const EPT_UC: u64 = MemoryType::Uncachable as u64;
+
+        // See: Table 28-7. Exit Qualification for EPT Violations
+        let gpa = vmcs::ro::GUEST_PHYSICAL_ADDR_FULL.read();
+        let qualification = vmcs::ro::EXIT_QUALIFICATION.read();
+        let info = NestedPageFaultInfo {
+            gpa,
+            write: qualification.get_bit(1),
+            execute: qualification.get_bit(2),
+        };
+        let mut epts = shared_guest_data().epts.write();
+        let leaf12 = match walk_ept12(&epts, id, self.eptp12.unwrap(), gpa) {
+            Ept12Walk::Mapped(leaf) => leaf,
+            // The processor reports the permissions of the EPT02 entries, none
+            // of which is present either.
+            Ept12Walk::NotPresent => return Route::Reflect,
+            Ept12Walk::Misconfiguration => {
+                self.exit_override = Some((EXIT_REASON_EPT_MISCONFIGURATION, 0));
+                return Route::Reflect;
+            }
+        };
+        if !leaf12.permissions.permits(&info) {
+            let mut qualification = qualification;
+            let _ = qualification
+                .set_bit(3, leaf12.permissions.read)
+                .set_bit(4, leaf12.permissions.write)
+                .set_bit(5, leaf12.permissions.execute);
+            self.exit_override = Some((EXIT_REASON_EPT_VIOLATION, qualification));
+            return Route::Reflect;
+        }
+
+        let gpa1 = leaf12.translate(gpa);
+        let leaf01 = epts.leaf(gpa1);
+        let permissions = leaf01.map_or(Permissions::NONE, |leaf| {
+            intersect(leaf.permissions, leaf12.permissions)
+        });
+        let Some(leaf01) = leaf01.filter(|_| permissions.permits(&info)) else {
+            self.fault = Some((gpa, leaf12));
+            return Route::NestedPageFault(NestedPageFaultInfo { gpa: gpa1, ..info });
+        };
+
+        // The writes through EPT02 do not set the dirty flags of EPT01. Set
+        // them on write, and let the other accesses fault on write again.
+        let permissions = if dirty_tracking::is_enabled() && !info.write {
+            Permissions {
+                write: false,
+                ..permissions
+            }
+        } else {
+            if dirty_tracking::is_enabled() {
+                epts.mark_dirty(gpa1);
+            }
+            permissions
+        };
+        drop(epts);
+        let memory_type = if leaf12.memory_type == EPT_UC {
+            EPT_UC
+        } else {
+            leaf01.memory_type
+        };
+        self.ept02.map(
+            gpa,
+            leaf01.pa,
+            BASE_PAGE_SIZE as u64,
+            permissions,
+            memory_type,
+            leaf12.ignore_pat,
+        );
+        Route::Resolved
+    }
+
+    /// Reflects the VM-exit from L2 into L1 as a VM-exit from VMCS12, and
+    /// makes VMCS01 at `vmcs01` current with the host state of VMCS12 loaded.
+    /// `synthetic` is the exit reason and the VM-exit interruption information
+    /// of the VM-exit L0 causes instead, if any. `keep_dr7` tells L0 manages
+    /// DR7 of L1. See `hw_breakpoint`.
+    // See: CHAPTER 28 VM EXITS
+    pub(super) fn reflect(
+        &mut self,
+        vcpu: &mut dyn Vcpu,
+        vmcs01: u64,
+        synthetic: Option<(u32, u32)>,
+        keep_dr7: bool,
+    ) {
+        const NMI: u32 = 2;
+
+        let id = vcpu.id();
+        let regs = *vcpu.regs();
+        let exit_override = self.exit_override.take();
+        let (_, vmcs12) = self.current.as_mut().unwrap();
+
+        // Record the VM-exit information.
+        for &(_, encoding) in FIELDS {
+            if vmcs12::is_read_only(encoding)
+                && encoding != vmcs::ro::VM_INSTRUCTION_ERROR.encoding()
+            {
+                if let Some(value) = try_vmread(encoding) {
+                    let _ = vmcs12.write(u64::from(encoding), value, true);
+                }
+            }
+        }
+        if let Some((reason, info)) = synthetic {
+            // The event being injected into L2 was being delivered.
+            // See: 28.2.4 Information for VM Exits During Event Delivery
+            vmcs12.set(vmcs::ro::EXIT_REASON, u64::from(reason));
+            vmcs12.set(vmcs::ro::EXIT_QUALIFICATION, 0);
+            vmcs12.set(vmcs::ro::VMEXIT_INTERRUPTION_INFO, u64::from(info));
+            vmcs12.set(
+                vmcs::ro::IDT_VECTORING_INFO,
+                u64::from(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD.read()),
+            );
+            vmcs12.set(
+                vmcs::ro::IDT_VECTORING_ERR_CODE,
+                u64::from(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE.read()),
+            );
+            vmcs12.set(
+                vmcs::ro::VMEXIT_INSTRUCTION_LEN,
+                u64::from(vmcs::control::VMENTRY_INSTRUCTION_LEN.read()),
+            );
+        } else if let Some((reason, qualification)) = exit_override {
+            vmcs12.set(vmcs::ro::EXIT_REASON, u64::from(reason));
+            vmcs12.set(vmcs::ro::EXIT_QUALIFICATION, qualification);
+        }
+        let reason = vmcs12.get(vmcs::ro::EXIT_REASON) as u32;
+        let entry_failure = reason & EXIT_REASON_ENTRY_FAILURE != 0;
+        if !entry_failure {
+            save_guest_state(vmcs12, &regs, self.eptp12.is_some());
+            vmcs12.set_launched(true);
+        }
+
+        // VM-exit clears the valid bit of the VM-entry interruption
+        // information.
+        // See: 28.2.1 Basic VM-Exit Information
+        let entry_info = vmcs12.get(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD);
+        vmcs12.set(
+            vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD,
+            entry_info & !(1 << 31),
+        );
+
+        // Store the MSRs of L2 while VMCS02 is current to read them from.
+        // See: 28.4 SAVING MSRS
+        if !entry_failure {
+            let msr_store = msr_list(
+                vmcs12,
+                vmcs::control::VMEXIT_MSR_STORE_COUNT,
+                vmcs::control::VMEXIT_MSR_STORE_ADDR_FULL,
+            );
+            for entry in msr_store {
+                let mut msr = [0u8; 4];
+                if read_l1(id, entry, &mut msr).is_ok() && is_listable_msr(u32::from_le_bytes(msr))
+                {
+                    let value = vcpu.read_msr(u32::from_le_bytes(msr));
+                    let _ = write_l1(id, entry + 8, &value.to_le_bytes());
+                }
+            }
+        }
+
+        vmptrld_pa(vmcs01).unwrap();
+        self.in_l2 = false;
+        let nmi = reason & 0xffff == EXIT_REASON_EXCEPTION_OR_NMI
+            && vmcs12
+                .get(vmcs::ro::VMEXIT_INTERRUPTION_INFO)
+                .get_bits(8..=10)
+                == u64::from(NMI);
+        self.exit_to_l1(vcpu, vmcs01, nmi, keep_dr7);
+    }
+
+    /// Loads the host state of VMCS12 and the VM-exit MSR-load list into L1,
+    /// with VMCS01 at `vmcs01` current. `nmi` tells the VM-exit is due to an
+    /// NMI, which stays blocked.
+    // See: 28.5 LOADING HOST STATE
+    fn exit_to_l1(&mut self, vcpu: &mut dyn Vcpu, vmcs01: u64, nmi: bool, keep_dr7: bool) {
+        const BLOCKING_BY_NMI: u32 = 1 << 3;
+
+        let id = vcpu.id();
+        let vmcs12 = &self.current.as_ref().unwrap().1;
+        load_host_state(vcpu, vmcs12, keep_dr7);
+        vmcs::guest::INTERRUPTIBILITY_STATE.write(if nmi { BLOCKING_BY_NMI } else { 0 });
+        vmcs::guest::ACTIVITY_STATE.write(0);
+        vmcs::guest::PENDING_DBG_EXCEPTIONS.write(0);
+        vcpu.set_pending_event(None);
+
+        // Loading an MSR not supported causes VMX abort in the processor, which
+        // is not emulated.
+        // See: 28.6 LOADING MSRS
+        let msr_load = msr_list(
+            vmcs12,
+            vmcs::control::VMEXIT_MSR_LOAD_COUNT,
+            vmcs::control::VMEXIT_MSR_LOAD_ADDR_FULL,
+        );
+        for entry in msr_load {
+            let mut entry_bytes = [0u8; 16];
+            if read_l1(id, entry, &mut entry_bytes).is_err() {
+                continue;
+            }
+            let msr = u32::from_le_bytes(entry_bytes[..4].try_into().unwrap());
+            let value = u64::from_le_bytes(entry_bytes[8..].try_into().unwrap());
+            if is_listable_msr(msr) {
+                vcpu.write_msr(msr, value);
+            } else {
+                log::warn!("Ignoring MSR {msr:#x} in the VM-exit MSR-load list");
+            }
+        }
+        self.sync_shadow(vmcs01);
+    }
+}
+
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+
+/// Returns the secondary processor-based VM-execution controls of `vmcs12`,
+/// which are 0 unless activated.
+fn secondary_controls12(vmcs12: &Vmcs12) -> u32 {
+    let primary = vmcs12.get(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32;
+    if primary & PrimaryControls::SECONDARY_CONTROLS.bits() != 0 {
+        vmcs12.get(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS) as u32
+    } else {
+        0
+    }
+}
+
+/// Returns whether `value` of a VM-execution, VM-exit or VM-entry control is
+/// allowed by the capability MSR `msr` reported to L1.
+fn is_allowed(value: u32, msr: u32) -> bool {
+    let capability = capability(msr);
+    let allowed0 = capability as u32;
+    let allowed1 = (capability >> 32) as u32;
+    value & allowed0 == allowed0 && value & !allowed1 == 0
+}
+
+/// Returns whether `address` is canonical with 48-bit linear addresses.
+fn is_canonical(address: u64) -> bool {
+    ((address as i64) << 16 >> 16) as u64 == address
+}
+
+/// Checks the VM-execution, VM-exit and VM-entry control fields of `vmcs12`.
+// See: 27.2.1 Checks on VMX Controls
+fn has_valid_controls(vmcs12: &Vmcs12) -> bool {
+    let pin = vmcs12.get(vmcs::control::PINBASED_EXEC_CONTROLS) as u32;
+    let primary = vmcs12.get(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS) as u32;
+    let secondary = secondary_controls12(vmcs12);
+    if !is_allowed(pin, IA32_VMX_TRUE_PINBASED_CTLS)
+        || !is_allowed(primary, IA32_VMX_TRUE_PROCBASED_CTLS)
+        || !is_allowed(secondary, IA32_VMX_PROCBASED_CTLS2)
+        || !is_allowed(
+            vmcs12.get(vmcs::control::VMEXIT_CONTROLS) as u32,
+            IA32_VMX_TRUE_EXIT_CTLS,
+        )
+        || !is_allowed(
+            vmcs12.get(vmcs::control::VMENTRY_CONTROLS) as u32,
+            IA32_VMX_TRUE_ENTRY_CTLS,
+        )
+    {
+        return false;
+    }
+
+    if vmcs12.get(vmcs::control::CR3_TARGET_COUNT) > 4 {
+        return false;
+    }
+    let bitmaps = [
+        (
+            PrimaryControls::USE_IO_BITMAPS,
+            vmcs12.get(vmcs::control::IO_BITMAP_A_ADDR_FULL),
+        ),
+        (
+            PrimaryControls::USE_IO_BITMAPS,
+            vmcs12.get(vmcs::control::IO_BITMAP_B_ADDR_FULL),
+        ),
+        (
+            PrimaryControls::USE_MSR_BITMAPS,
+            vmcs12.get(vmcs::control::MSR_BITMAPS_ADDR_FULL),
+        ),
+    ];
+    if bitmaps
+        .iter()
+        .any(|&(control, pa)| primary & control.bits() != 0 && !is_valid_pa(pa))
+    {
+        return false;
+    }
+
+    let nmi_exiting = pin & PinbasedControls::NMI_EXITING.bits() != 0;
+    let virtual_nmis = pin & PinbasedControls::VIRTUAL_NMIS.bits() != 0;
+    if (!nmi_exiting && virtual_nmis)
+        || (!virtual_nmis && primary & PrimaryControls::NMI_WINDOW_EXITING.bits() != 0)
+    {
+        return false;
+    }
+
+    // See: 25.6.11 Extended-Page-Table Pointer (EPTP)
+    let ept = secondary & SecondaryControls::ENABLE_EPT.bits() != 0;
+    if ept {
+        let eptp = vmcs12.get(vmcs::control::EPTP_FULL);
+        let capabilities = capability(IA32_VMX_EPT_VPID_CAP);
+        let memory_type = match eptp.get_bits(0..=2) {
+            0 => capabilities.get_bit(8),
+            6 => capabilities.get_bit(14),
+            _ => false,
+        };
+        if !memory_type
+            || eptp.get_bits(3..=5) != 3
+            || eptp.get_bits(6..=11) != 0
+            || eptp >> pa_bits() != 0
+        {
+            return false;
+        }
+    }
+    if !ept && secondary & SecondaryControls::UNRESTRICTED_GUEST.bits() != 0 {
+        return false;
+    }
+
+    let lists = [
+        (
+            vmcs::control::VMEXIT_MSR_STORE_COUNT,
+            vmcs::control::VMEXIT_MSR_STORE_ADDR_FULL,
+        ),
+        (
+            vmcs::control::VMEXIT_MSR_LOAD_COUNT,
+            vmcs::control::VMEXIT_MSR_LOAD_ADDR_FULL,
+        ),
+        (
+            vmcs::control::VMENTRY_MSR_LOAD_COUNT,
+            vmcs::control::VMENTRY_MSR_LOAD_ADDR_FULL,
+        ),
+    ];
+    lists.into_iter().all(|(count, address)| {
+        let address = vmcs12.get(address);
+        vmcs12.get(count) == 0 || (address & 0xf == 0 && address >> pa_bits() == 0)
+    })
+}
+
+/// Checks the host-state fields of `vmcs12`, for the host in 64-bit mode.
+// See: 27.2.2 Checks on Host Control Registers, MSRs, and SSP
+// See: 27.2.3 Checks on Host Segment and Descriptor-Table Registers
+// See: 27.2.4 Checks Related to Address-Space Size
+fn has_valid_host_state(vmcs12: &Vmcs12) -> bool {
+    let exit = vmcs12.get(vmcs::control::VMEXIT_CONTROLS) as u32;
+    if exit & ExitControls::HOST_ADDRESS_SPACE_SIZE.bits() == 0 {
+        return false;
+    }
+    let fixed = |value: u64, fixed0: u32, fixed1: u32| {
+        let fixed0 = capability(fixed0);
+        let fixed1 = capability(fixed1);
+        value & fixed0 == fixed0 && value & !fixed1 == 0
+    };
+    let cr4 = vmcs12.get(vmcs::host::CR4);
+    if !fixed(
+        vmcs12.get(vmcs::host::CR0),
+        IA32_VMX_CR0_FIXED0,
+        IA32_VMX_CR0_FIXED1,
+    ) || !fixed(cr4, IA32_VMX_CR4_FIXED0, IA32_VMX_CR4_FIXED1)
+        || cr4 & Cr4::CR4_ENABLE_PAE.bits() as u64 == 0
+        || vmcs12.get(vmcs::host::CR3) >> pa_bits() != 0
+    {
+        return false;
+    }
+
+    let addresses = [
+        vmcs12.get(vmcs::host::FS_BASE),
+        vmcs12.get(vmcs::host::GS_BASE),
+        vmcs12.get(vmcs::host::TR_BASE),
+        vmcs12.get(vmcs::host::GDTR_BASE),
+        vmcs12.get(vmcs::host::IDTR_BASE),
+        vmcs12.get(vmcs::host::IA32_SYSENTER_ESP),
+        vmcs12.get(vmcs::host::IA32_SYSENTER_EIP),
+        vmcs12.get(vmcs::host::RIP),
+    ];
+    if !addresses.into_iter().all(is_canonical) {
+        return false;
+    }
+
+    let selectors = [
+        vmcs12.get(vmcs::host::ES_SELECTOR),
+        vmcs12.get(vmcs::host::CS_SELECTOR),
+        vmcs12.get(vmcs::host::SS_SELECTOR),
+        vmcs12.get(vmcs::host::DS_SELECTOR),
+        vmcs12.get(vmcs::host::FS_SELECTOR),
+        vmcs12.get(vmcs::host::GS_SELECTOR),
+        vmcs12.get(vmcs::host::TR_SELECTOR),
+    ];
+    if selectors.iter().any(|selector| selector & 0b111 != 0)
+        || vmcs12.get(vmcs::host::CS_SELECTOR) == 0
+        || vmcs12.get(vmcs::host::TR_SELECTOR) == 0
+    {
+        return false;
+    }
+
+    if exit & ExitControls::LOAD_IA32_EFER.bits() != 0 {
+        let efer = vmcs12.get(vmcs::host::IA32_EFER_FULL);
+        if efer & EFER_LMA == 0 || efer & EFER_LME == 0 {
+            return false;
+        }
+    }
+    if exit & ExitControls::LOAD_IA32_PAT.bits() != 0 {
+        let pat = vmcs12.get(vmcs::host::IA32_PAT_FULL);
+        if pat
+            .to_le_bytes()
+            .iter()
+            .any(|memory_type| !matches!(memory_type, 0 | 1 | 4 | 5 | 6 | 7))
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// Checks the guest state of `vmcs12` this module depends on, and returns the
+/// PDPTEs of L2 if it uses PAE paging without EPT12. Otherwise, returns the
+/// basic exit reason and the exit qualification of the VM-entry failure.
+// See: 27.3.1 Checks on the Guest State Area
+// See: 27.4 LOADING MSRS
+fn check_guest_state(id: usize, vmcs12: &Vmcs12) -> Result<[u64; 4], (u32, u64)> {
+    // See: 28.2.1 Basic VM-Exit Information
+    const QUALIFICATION_DEFAULT: u64 = 0;
+    const QUALIFICATION_PDPTE: u64 = 2;
+    const QUALIFICATION_LINK_POINTER: u64 = 4;
+    const PDPTE_RESERVED: u64 = 0b1_1110_0110;
+
+    if vmcs12.get(vmcs::guest::LINK_PTR_FULL) != u64::MAX {
+        return Err((
+            EXIT_REASON_ENTRY_FAILURE_GUEST_STATE,
+            QUALIFICATION_LINK_POINTER,
+        ));
+    }
+    if vmcs12.get(vmcs::guest::ACTIVITY_STATE) > 1 {
+        return Err((EXIT_REASON_ENTRY_FAILURE_GUEST_STATE, QUALIFICATION_DEFAULT));
+    }
+
+    // The PDPTEs are loaded from the VMCS with EPT, and VMCS02 enables EPT.
+    // See: 4.4.1 PDPTE Registers
+    let mut pdptes = [0u64; 4];
+    let ia32e = vmcs12.get(vmcs::control::VMENTRY_CONTROLS) as u32
+        & EntryControls::IA32E_MODE_GUEST.bits()
+        != 0;
+    let pae_paging = vmcs12.get(vmcs::guest::CR0) & Cr0::CR0_ENABLE_PAGING.bits() as u64 != 0
+        && vmcs12.get(vmcs::guest::CR4) & Cr4::CR4_ENABLE_PAE.bits() as u64 != 0
+        && !ia32e;
+    let ept = secondary_controls12(vmcs12) & SecondaryControls::ENABLE_EPT.bits() != 0;
+    if pae_paging && !ept {
+        let mut bytes = [0u8; 32];
+        let cr3 = vmcs12.get(vmcs::guest::CR3) & !0x1f;
+        if read_l1(id, cr3, &mut bytes).is_err() {
+            return Err((EXIT_REASON_ENTRY_FAILURE_GUEST_STATE, QUALIFICATION_PDPTE));
+        }
+        for (pdpte, bytes) in pdptes.iter_mut().zip(bytes.as_chunks::<8>().0) {
+            *pdpte = u64::from_le_bytes(*bytes);
+            if pdpte.get_bit(0) && (*pdpte & PDPTE_RESERVED != 0 || *pdpte >> pa_bits() != 0) {
+                return Err((EXIT_REASON_ENTRY_FAILURE_GUEST_STATE, QUALIFICATION_PDPTE));
+            }
+        }
+    }
+
+    let msr_load = msr_list(
+        vmcs12,
+        vmcs::control::VMENTRY_MSR_LOAD_COUNT,
+        vmcs::control::VMENTRY_MSR_LOAD_ADDR_FULL,
+    );
+    for (index, entry) in msr_load.enumerate() {
+        let mut msr = [0u8; 4];
+        if read_l1(id, entry, &mut msr).is_err() || !is_listable_msr(u32::from_le_bytes(msr)) {
+            return Err((EXIT_REASON_ENTRY_FAILURE_MSR_LOADING, index as u64 + 1));
+        }
+    }
+    Ok(pdptes)
+}
+
+/// Returns the L1 GPAs of the entries of the MSR list with `count` and
+/// `address` in `vmcs12`.
+// See: 25.7.2 VM-Exit Controls for MSRs
+fn msr_list(
+    vmcs12: &Vmcs12,
+    count: vmcs::Field32,
+    address: vmcs::Field64,
+) -> impl Iterator<Item = u64> {
+    let count = vmcs12.get(count);
+    let address = vmcs12.get(address);
+    (0..count).map(move |index| address + index * 16)
+}
+
+/// Returns whether `msr` may be in the MSR lists. They are the MSRs neither
+/// switched through the VMCS nor intercepted by L0, so that each is loaded
+/// and stored as is.
+fn is_listable_msr(msr: u32) -> bool {
+    matches!(
+        msr,
+        x86::msr::IA32_STAR
+            | x86::msr::IA32_LSTAR
+            | x86::msr::IA32_CSTAR
+            | x86::msr::IA32_FMASK
+            | x86::msr::IA32_KERNEL_GSBASE
+            | x86::msr::IA32_TSC_AUX
+            | x86::msr::IA32_SYSENTER_CS
+            | x86::msr::IA32_SYSENTER_ESP
+            | x86::msr::IA32_SYSENTER_EIP
+    ) && SHARED_HOST_DATA
+        .get()
+        .unwrap()
+        .msr_intercepts
+        .write_handler(msr)
+        .is_none()
+}
+
+/// Returns whether the guest-state field `encoding` is copied between VMCS12
+/// and VMCS02 as is. The others are not supported, or are derived from
+/// VMCS01 or the registers.
+fn is_copied_guest_field(encoding: u32) -> bool {
+    let excluded = [
+        vmcs::guest::INTERRUPT_STATUS.encoding(),
+        vmcs::guest::PML_INDEX.encoding(),
+        vmcs::guest::LINK_PTR_FULL.encoding(),
+        vmcs::guest::IA32_DEBUGCTL_FULL.encoding(),
+        vmcs::guest::IA32_PAT_FULL.encoding(),
+        vmcs::guest::IA32_EFER_FULL.encoding(),
+        vmcs::guest::IA32_PERF_GLOBAL_CTRL_FULL.encoding(),
+        vmcs::guest::PDPTE0_FULL.encoding(),
+        vmcs::guest::PDPTE1_FULL.encoding(),
+        vmcs::guest::PDPTE2_FULL.encoding(),
+        vmcs::guest::PDPTE3_FULL.encoding(),
+        vmcs::guest::IA32_BNDCFGS_FULL.encoding(),
+        vmcs::guest::IA32_RTIT_CTL_FULL.encoding(),
+        vmcs::guest::SMBASE.encoding(),
+        vmcs::guest::VMX_PREEMPTION_TIMER_VALUE.encoding(),
+        vmcs::guest::DR7.encoding(),
+        vmcs::guest::RSP.encoding(),
+        vmcs::guest::RIP.encoding(),
+        vmcs::guest::RFLAGS.encoding(),
+        vmcs::guest::IA32_S_CET.encoding(),
+        vmcs::guest::SSP.encoding(),
+        vmcs::guest::IA32_INTERRUPT_SSP_TABLE_ADDR.encoding(),
+    ];
+    vmcs12::is_guest_state(encoding) && !excluded.contains(&encoding)
+}
+
+/// Saves the guest state of L2 from the current VMCS02 and `regs` into
+/// `vmcs12`. `ept12` tells L1 enables EPT, with which the PDPTEs are saved.
+// See: 28.3 SAVING GUEST STATE
+fn save_guest_state(vmcs12: &mut Vmcs12, regs: &Registers, ept12: bool) {
+    for &(_, encoding) in FIELDS {
+        if is_copied_guest_field(encoding) {
+            if let Some(value) = try_vmread(encoding) {
+                let _ = vmcs12.write(u64::from(encoding), value, true);
+            }
+        }
+    }
+    vmcs12.set(vmcs::guest::RIP, regs.rip);
+    vmcs12.set(vmcs::guest::RSP, regs.rsp);
+    vmcs12.set(vmcs::guest::RFLAGS, regs.rflags);
+
+    let exit = vmcs12.get(vmcs::control::VMEXIT_CONTROLS) as u32;
+    if exit & ExitControls::SAVE_DEBUG_CONTROLS.bits() != 0 {
+        vmcs12.set(vmcs::guest::DR7, vmcs::guest::DR7.read());
+        vmcs12.set(
+            vmcs::guest::IA32_DEBUGCTL_FULL,
+            vmcs::guest::IA32_DEBUGCTL_FULL.read(),
+        );
+    }
+    if exit & ExitControls::SAVE_IA32_EFER.bits() != 0 {
+        vmcs12.set(
+            vmcs::guest::IA32_EFER_FULL,
+            vmcs::guest::IA32_EFER_FULL.read(),
+        );
+    }
+    if exit & ExitControls::SAVE_IA32_PAT.bits() != 0 {
+        vmcs12.set(
+            vmcs::guest::IA32_PAT_FULL,
+            vmcs::guest::IA32_PAT_FULL.read(),
+        );
+    }
+    if ept12 {
+        for field in [
+            vmcs::guest::PDPTE0_FULL,
+            vmcs::guest::PDPTE1_FULL,
+            vmcs::guest::PDPTE2_FULL,
+            vmcs::guest::PDPTE3_FULL,
+        ] {
+            vmcs12.set(field, field.read());
+        }
+    }
+
+    // "IA-32e mode guest" is stored on VM-exit as reported with
+    // IA32_VMX_MISC[5].
+    let ia32e = EntryControls::IA32E_MODE_GUEST.bits();
+    let entry12 = vmcs12.get(vmcs::control::VMENTRY_CONTROLS) as u32;
+    let entry02 = vmcs::control::VMENTRY_CONTROLS.read();
+    vmcs12.set(
+        vmcs::control::VMENTRY_CONTROLS,
+        u64::from((entry12 & !ia32e) | (entry02 & ia32e)),
+    );
+}
+
+/// Loads the host state of `vmcs12` into L1 through the current VMCS01.
+/// `keep_dr7` tells L0 manages DR7 of L1.
+// See: 28.5 LOADING HOST STATE
+fn load_host_state(vcpu: &mut dyn Vcpu, vmcs12: &Vmcs12, keep_dr7: bool) {
+    // See: Table 25-2. Format of Access Rights
+    const CODE_64: u32 = 0xa09b;
+    const DATA: u32 = 0xc093;
+    const TSS_BUSY: u32 = 0x8b;
+    const UNUSABLE: u32 = 1 << 16;
+
+    let cr0 = vmcs12.get(vmcs::host::CR0);
+    let cr4 = vmcs12.get(vmcs::host::CR4);
+    vmcs::control::CR0_READ_SHADOW.write(cr0);
+    let adjusted = get_adjusted_guest_cr0(unsafe { Cr0::from_bits_unchecked(cr0 as usize) });
+    vmcs::guest::CR0.write(adjusted.bits() as u64);
+    vmcs::control::CR4_READ_SHADOW.write(cr4);
+    let adjusted = get_adjusted_guest_cr4(unsafe { Cr4::from_bits_unchecked(cr4 as usize) });
+    vmcs::guest::CR4.write(adjusted.bits() as u64);
+    vmcs::guest::CR3.write(vmcs12.get(vmcs::host::CR3));
+    tlb::flush_guest(vcpu, FlushScope::GuestLinear);
+
+    if !keep_dr7 {
+        vmcs::guest::DR7.write(0x400);
+    }
+    vmcs::guest::IA32_DEBUGCTL_FULL.write(0);
+    vcpu.write_msr(
+        x86::msr::IA32_SYSENTER_CS,
+        vmcs12.get(vmcs::host::IA32_SYSENTER_CS),
+    );
+    vcpu.write_msr(
+        x86::msr::IA32_SYSENTER_ESP,
+        vmcs12.get(vmcs::host::IA32_SYSENTER_ESP),
+    );
+    vcpu.write_msr(
+        x86::msr::IA32_SYSENTER_EIP,
+        vmcs12.get(vmcs::host::IA32_SYSENTER_EIP),
+    );
+
+    // The host of L1 is in 64-bit mode. See `has_valid_host_state`.
+    let exit = vmcs12.get(vmcs::control::VMEXIT_CONTROLS) as u32;
+    let efer = if exit & ExitControls::LOAD_IA32_EFER.bits() != 0 {
+        vmcs12.get(vmcs::host::IA32_EFER_FULL)
+    } else {
+        vmcs::guest::IA32_EFER_FULL.read() | EFER_LMA | EFER_LME
+    };
+    vmcs::guest::IA32_EFER_FULL.write(efer);
+    let entry = vmcs::control::VMENTRY_CONTROLS.read();
+    vmcs::control::VMENTRY_CONTROLS.write(entry | EntryControls::IA32E_MODE_GUEST.bits());
+    if exit & ExitControls::LOAD_IA32_PAT.bits() != 0 {
+        vmcs::guest::IA32_PAT_FULL.write(vmcs12.get(vmcs::host::IA32_PAT_FULL));
+    }
+
+    let data_segments = [
+        (
+            vmcs::host::ES_SELECTOR,
+            vmcs::guest::ES_SELECTOR,
+            vmcs::guest::ES_BASE,
+            vmcs::guest::ES_LIMIT,
+            vmcs::guest::ES_ACCESS_RIGHTS,
+        ),
+        (
+            vmcs::host::SS_SELECTOR,
+            vmcs::guest::SS_SELECTOR,
+            vmcs::guest::SS_BASE,
+            vmcs::guest::SS_LIMIT,
+            vmcs::guest::SS_ACCESS_RIGHTS,
+        ),
+        (
+            vmcs::host::DS_SELECTOR,
+            vmcs::guest::DS_SELECTOR,
+            vmcs::guest::DS_BASE,
+            vmcs::guest::DS_LIMIT,
+            vmcs::guest::DS_ACCESS_RIGHTS,
+        ),
+        (
+            vmcs::host::FS_SELECTOR,
+            vmcs::guest::FS_SELECTOR,
+            vmcs::guest::FS_BASE,
+            vmcs::guest::FS_LIMIT,
+            vmcs::guest::FS_ACCESS_RIGHTS,
+        ),
+        (
+            vmcs::host::GS_SELECTOR,
+            vmcs::guest::GS_SELECTOR,
+            vmcs::guest::GS_BASE,
+            vmcs::guest::GS_LIMIT,
+            vmcs::guest::GS_ACCESS_RIGHTS,
+        ),
+    ];
+    for (host_selector, selector, base, limit, access_rights) in data_segments {
+        let value = vmcs12.get(host_selector);
+        selector.write(value as u16);
+        base.write(0);
+        limit.write(u32::MAX);
+        // The null selector makes the segment unusable, except that SS keeps
+        // DPL, which is CPL.
+        access_rights.write(if value == 0 { DATA | UNUSABLE } else { DATA });
+    }
+    vmcs::guest::FS_BASE.write(vmcs12.get(vmcs::host::FS_BASE));
+    vmcs::guest::GS_BASE.write(vmcs12.get(vmcs::host::GS_BASE));
+    vmcs::guest::CS_SELECTOR.write(vmcs12.get(vmcs::host::CS_SELECTOR) as u16);
+    vmcs::guest::CS_BASE.write(0);
+    vmcs::guest::CS_LIMIT.write(u32::MAX);
+    vmcs::guest::CS_ACCESS_RIGHTS.write(CODE_64);
+    vmcs::guest::TR_SELECTOR.write(vmcs12.get(vmcs::host::TR_SELECTOR) as u16);
+    vmcs::guest::TR_BASE.write(vmcs12.get(vmcs::host::TR_BASE));
+    vmcs::guest::TR_LIMIT.write(0x67);
+    vmcs::guest::TR_ACCESS_RIGHTS.write(TSS_BUSY);
+    vmcs::guest::LDTR_SELECTOR.write(0);
+    vmcs::guest::LDTR_ACCESS_RIGHTS.write(UNUSABLE);
+    vmcs::guest::GDTR_BASE.write(vmcs12.get(vmcs::host::GDTR_BASE));
+    vmcs::guest::GDTR_LIMIT.write(0xffff);
+    vmcs::guest::IDTR_BASE.write(vmcs12.get(vmcs::host::IDTR_BASE));
+    vmcs::guest::IDTR_LIMIT.write(0xffff);
+
+    let regs = vcpu.regs();
+    regs.rip = vmcs12.get(vmcs::host::RIP);
+    regs.rsp = vmcs12.get(vmcs::host::RSP);
+    regs.rflags = RFlags::FLAGS_A1.bits();
+}
@@ -0,0 +1,391 @@
+//! This module implements the EPT the guest builds for its own guest with
+//! nested virtualization, EPT12, and the shadow of it the processor actually
+//! uses for the nested guest, EPT02. See `vmcs12` for the terms.
+//!
+//! EPT02 maps each L2 GPA to the PA EPT12 and then EPT01 translate it to, with
+//! the permissions both permit. It is filled on demand from EPT violations in
+//! L2, and is reset whenever either of them may have changed, that is, when
+//! L1 invalidates cached EPT translations with INVEPT, or when L0 updates EPT01.
+
+use alloc::{boxed::Box, collections::BTreeMap};
+use bit_field::BitField;
+use x86::bits64::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE};
+
+use crate::hypervisor::{
+    memory_protection::Permissions, mtrr::MemoryType, platform_ops, support::try_zeroed_box,
+    HvError,
+};
+
+use super::epts::{invept, EptPointer, InveptType};
+
+/// The EPT features reported to the guest, which its EPT is checked against.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Ept12Features {
+    /// Whether execute-only translations are supported.
+    pub(crate) execute_only: bool,
+    /// Whether EPT PDPTEs may map 1GB pages.
+    pub(crate) page_1gb: bool,
+    /// Whether EPT PDEs may map 2MB pages.
+    pub(crate) page_2mb: bool,
+    /// The physical-address width of the processor (MAXPHYADDR).
+    pub(crate) pa_bits: u8,
+}
+
+/// The translation of a GPA of L2 by EPT12.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Ept12Walk {
+    /// The GPA is mapped by `Ept12Leaf`.
+    Mapped(Ept12Leaf),
+    /// An entry to walk is not present.
+    NotPresent,
+    /// An entry to walk is misconfigured, and the access causes an EPT
+    /// misconfiguration.
+    Misconfiguration,
+}
+
+/// The EPT12 entry mapping a page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Ept12Leaf {
+    /// The L1 GPA of the page.
+    pub(crate) base: u64,
+    /// The size of the page.
+    pub(crate) size: u64,
+    /// The accesses all entries walked permit.
+    pub(crate) permissions: Permissions,
+    /// The EPT memory type.
+    pub(crate) memory_type: u64,
+    /// Whether the guest PAT is ignored.
+    pub(crate) ignore_pat: bool,
+}
+
+impl Ept12Leaf {
+    /// Returns the L1 GPA `gpa` of L2 translates to.
+    pub(crate) fn translate(&self, gpa: u64) -> u64 {
+        self.base + (gpa & (self.size - 1))
+    }
+}
+
+/// Walks EPT12 whose PML4 is at the L1 GPA `pml4` to translate `gpa`, with the
+/// value of each entry read with `read_entry` from its L1 GPA. An entry that
+/// cannot be read is treated as misconfigured.
+// See: 29.3.2 EPT Translation Mechanism
+// See: 29.3.3.1 EPT Misconfigurations
+pub(crate) fn walk(
+    pml4: u64,
+    gpa: u64,
+    features: &Ept12Features,
+    mut read_entry: impl FnMut(u64) -> Option<u64>,
+) -> Ept12Walk {
+    const LARGE: u64 = 1 << 7;
+
+    let pfn_mask = ((1u64 << features.pa_bits) - 1) & !(BASE_PAGE_SIZE as u64 - 1);
+    let mut table = pml4 & pfn_mask;
+    let mut permissions = Permissions::ALL;
+    for level in (1..=4u8).rev() {
+        let shift = 12 + 9 * (u32::from(level) - 1);
+        let index = (gpa >> shift) & 0x1ff;
+        let Some(entry) = read_entry(table + index * 8) else {
+            return Ept12Walk::Misconfiguration;
+        };
+
+        let entry_permissions = Permissions {
+            read: entry.get_bit(0),
+            write: entry.get_bit(1),
+            execute: entry.get_bit(2),
+        };
+        if entry & 0b111 == 0 {
+            return Ept12Walk::NotPresent;
+        }
+        if entry_permissions.write && !entry_permissions.read {
+            return Ept12Walk::Misconfiguration;
+        }
+        if entry_permissions == Permissions::EXECUTE_ONLY && !features.execute_only {
+            return Ept12Walk::Misconfiguration;
+        }
+        if entry.get_bits(usize::from(features.pa_bits)..52) != 0 {
+            return Ept12Walk::Misconfiguration;
+        }
+        permissions = Permissions {
+            read: permissions.read && entry_permissions.read,
+            write: permissions.write && entry_permissions.write,
+            execute: permissions.execute && entry_permissions.execute,
+        };
+
+        let large = entry & LARGE != 0;
+        let leaf = level == 1
+            || (large
+                && match level {
+                    3 => features.page_1gb,
+                    2 => features.page_2mb,
+                    _ => false,
+                });
+        if large && !leaf {
+            return Ept12Walk::Misconfiguration;
+        }
+        if !leaf {
+            // Bits 7:3 are reserved in the entries referencing tables.
+            if entry.get_bits(3..=7) != 0 {
+                return Ept12Walk::Misconfiguration;
+            }
+            table = entry & pfn_mask;
+            continue;
+        }
+
+        // The memory types 2, 3 and 7 are reserved, and so are the address
+        // bits below the page size of large pages.
+        let memory_type = entry.get_bits(3..=5);
+        if matches!(memory_type, 2 | 3 | 7) {
+            return Ept12Walk::Misconfiguration;
+        }
+        let size = 1u64 << shift;
+        if entry & pfn_mask & (size - 1) != 0 {
+            return Ept12Walk::Misconfiguration;
+        }
+        return Ept12Walk::Mapped(Ept12Leaf {
+            base: entry & pfn_mask,
+            size,
+            permissions,
+            memory_type,
+            ignore_pat: entry.get_bit(6),
+        });
+    }
+    unreachable!()
+}
+
+/// EPT02, the EPT the processor uses for L2, owned by each processor.
+pub(crate) struct Ept02 {
+    pml4: Box<Table>,
+
+    /// The EPT PDPTs, PDs and PTs, keyed by their PAs.
+    tables: BTreeMap<u64, Box<Table>>,
+}
+
+impl Ept02 {
+    /// Creates an empty EPT02, which maps nothing.
+    pub(crate) fn try_new() -> Result<Self, HvError> {
+        Ok(Self {
+            pml4: try_zeroed_box::<Table>()?,
+            tables: BTreeMap::new(),
+        })
+    }
+
+    /// Returns the EPTP of this EPT02, with the write-back memory type for the
+    /// paging structures and no accessed and dirty flags.
+    pub(crate) fn eptp(&self) -> EptPointer {
+        let mut eptp = EptPointer::default();
+        eptp.set_pfn(
+            platform_ops::get().pa(self.pml4.as_ref() as *const _ as _) >> BASE_PAGE_SHIFT,
+        );
+        eptp.set_memory_type(MemoryType::WriteBack as _);
+        eptp.set_page_levels_minus_one(3);
+        eptp
+    }
+
+    /// Maps the page of `size` containing the L2 GPA `gpa` to the page
+    /// containing `pa` with `permissions`, `memory_type` and `ignore_pat`. The
+    /// entries replaced are invalidated.
+    pub(crate) fn map(
+        &mut self,
+        gpa: u64,
+        pa: u64,
+        size: u64,
+        permissions: Permissions,
+        memory_type: u64,
+        ignore_pat: bool,
+    ) {
+        // Bound the memory each processor spends on this cache.
+        const MAX_TABLES: usize = 512;
+
+        if self.tables.len() + 3 > MAX_TABLES {
+            self.reset();
+        }
+
+        let leaf_level = match size as usize {
+            HUGE_PAGE_SIZE => 3,
+            LARGE_PAGE_SIZE => 2,
+            _ => 1,
+        };
+        let mut replaced = false;
+        let mut table_pa = None;
+        for level in (leaf_level + 1..=4).rev() {
+            let index = index_of(gpa, level);
+            let entry = self.table_mut(table_pa).0[index];
+            let next_pa = if entry & 0b111 != 0 && entry & LARGE == 0 {
+                entry & PFN_MASK
+            } else {
+                // Replace a large page with a table, leaving the other pages
+                // unmapped until they are accessed again.
+                replaced |= entry & 0b111 != 0;
+                // Start over if out of memory. The access faults again and
+                // maps the page with the tables freed.
+                let Ok(table) = try_zeroed_box::<Table>() else {
+                    self.reset();
+                    return;
+                };
+                let new_pa = platform_ops::get().pa(table.as_ref() as *const _ as _);
+                let _ = self.tables.insert(new_pa, table);
+                new_pa
+            };
+            self.table_mut(table_pa).0[index] = next_pa | 0b111;
+            table_pa = Some(next_pa);
+        }
+
+        let entry = &mut self.table_mut(table_pa).0[index_of(gpa, leaf_level)];
+        replaced |= *entry & 0b111 != 0;
+        *entry = (pa & PFN_MASK & !(size - 1))
+            | u64::from(permissions.read)
+            | u64::from(permissions.write) << 1
+            | u64::from(permissions.execute) << 2
+            | memory_type << 3
+            | u64::from(ignore_pat) << 6
+            | if leaf_level == 1 { 0 } else { LARGE };
+        if replaced {
+            self.invalidate();
+        }
+    }
+
+    /// Unmaps the 4KB page containing the L2 GPA `gpa` if it is mapped as one,
+    /// and invalidates it.
+    pub(crate) fn unmap(&mut self, gpa: u64) {
+        let mut table_pa = None;
+        for level in (2..=4).rev() {
+            let entry = self.table_mut(table_pa).0[index_of(gpa, level)];
+            if entry & 0b111 == 0 || entry & LARGE != 0 {
+                return;
+            }
+            table_pa = Some(entry & PFN_MASK);
+        }
+        self.table_mut(table_pa).0[index_of(gpa, 1)] = 0;
+        self.invalidate();
+    }
+
+    /// Unmaps everything and invalidates cached translations derived from this
+    /// EPT02.
+    pub(crate) fn reset(&mut self) {
+        self.pml4.0.fill(0);
+        self.invalidate();
+        self.tables.clear();
+    }
+
+    /// Invalidates cached translations derived from this EPT02 on the current
+    /// processor.
+    pub(crate) fn invalidate(&self) {
+        invept(InveptType::SingleContext, self.eptp());
+    }
+
+    /// Returns the table at `pa`, or the PML4 for `None`.
+    fn table_mut(&mut self, pa: Option<u64>) -> &mut Table {
+        match pa {
+            Some(pa) => self.tables.get_mut(&pa).unwrap(),
+            None => &mut self.pml4,
+        }
+    }
+}
+
+/// Returns the index of the entry of the paging structure at `level`, 4 for
+/// PML4, translating `gpa`.
+fn index_of(gpa: u64, level: u32) -> usize {
+    ((gpa >> (12 + 9 * (level - 1))) & 0x1ff) as usize
+}
+
+const LARGE: u64 = 1 << 7;
+const PFN_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+#[repr(C, align(4096))]
+struct Table([u64; 512]);
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+
+    use super::*;
+
+    const FEATURES: Ept12Features = Ept12Features {
+        execute_only: true,
+        page_1gb: true,
+        page_2mb: true,
+        pa_bits: 39,
+    };
+
+    /// Returns the EPT12 entries mapping 0x20_3000 to 0x1234_5000 with the
+    /// structures at 0x1000 (PML4), 0x2000 (PDPT), 0x3000 (PD) and 0x4000 (PT).
+    fn ept12(pte: u64) -> BTreeMap<u64, u64> {
+        BTreeMap::from([
+            (0x1000, 0x2000 | 0b111),
+            (0x2000, 0x3000 | 0b111),
+            (0x3000 + 8, 0x4000 | 0b011),
+            (0x4000 + 3 * 8, pte),
+        ])
+    }
+
+    fn walk_in(entries: &BTreeMap<u64, u64>, gpa: u64) -> Ept12Walk {
+        walk(0x1000, gpa, &FEATURES, |pa| {
+            Some(entries.get(&pa).copied().unwrap_or(0))
+        })
+    }
+
+    #[test]
+    fn walk_4kb_page() {
+        let wb = 6 << 3;
+        let entries = ept12(0x1234_5000 | wb | 0b111);
+        let Ept12Walk::Mapped(leaf) = walk_in(&entries, 0x20_3abc) else {
+            panic!("not mapped");
+        };
+        assert_eq!(leaf.translate(0x20_3abc), 0x1234_5abc);
+        assert_eq!(leaf.size, BASE_PAGE_SIZE as u64);
+        assert_eq!(leaf.memory_type, 6);
+        // The PDE does not permit execution.
+        assert_eq!(leaf.permissions, Permissions::READ_WRITE);
+        assert_eq!(walk_in(&entries, 0x20_4000), Ept12Walk::NotPresent);
+    }
+
+    #[test]
+    fn walk_large_pages() {
+        let mut entries = ept12(0);
+        let _ = entries.insert(0x2000 + 8, 0x8000_0000 | LARGE | 0b001);
+        let Ept12Walk::Mapped(leaf) = walk_in(&entries, 0x4123_4567) else {
+            panic!("not mapped");
+        };
+        assert_eq!(leaf.translate(0x4123_4567), 0x8123_4567);
+        assert_eq!(leaf.permissions, Permissions::READ_ONLY);
+
+        // 1GB pages are not supported, or the address is not aligned.
+        let no_1gb = Ept12Features {
+            page_1gb: false,
+            ..FEATURES
+        };
+        let walk_no_1gb = walk(0x1000, 0x4123_4567, &no_1gb, |pa| {
+            Some(entries.get(&pa).copied().unwrap_or(0))
+        });
+        assert_eq!(walk_no_1gb, Ept12Walk::Misconfiguration);
+        let _ = entries.insert(0x2000 + 8, 0x8020_0000 | LARGE | 0b001);
+        assert_eq!(walk_in(&entries, 0x4123_4567), Ept12Walk::Misconfiguration);
+    }
+
+    #[test]
+    fn walk_misconfigurations() {
+        // Write-only, a reserved memory type, and an address beyond MAXPHYADDR.
+        for pte in [
+            0x1234_5000 | 0b010,
+            0x1234_5000 | 2 << 3 | 0b001,
+            1 << 40 | 0b001,
+        ] {
+            assert_eq!(walk_in(&ept12(pte), 0x20_3000), Ept12Walk::Misconfiguration);
+        }
+
+        // Execute-only without the support.
+        let no_execute_only = Ept12Features {
+            execute_only: false,
+            ..FEATURES
+        };
+        let entries = ept12(0x1234_5000 | 0b100);
+        let result = walk(0x1000, 0x20_3000, &no_execute_only, |pa| {
+            Some(entries.get(&pa).copied().unwrap_or(0))
+        });
+        assert_eq!(result, Ept12Walk::Misconfiguration);
+
+        // Unreadable entries.
+        let result = walk(0x1000, 0x20_3000, &FEATURES, |_| None);
+        assert_eq!(result, Ept12Walk::Misconfiguration);
+    }
+}
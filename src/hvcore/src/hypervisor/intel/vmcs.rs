@@ -0,0 +1,1020 @@
+//! This module implements typed accessors of the fields of the current VMCS.
+//!
+//! Each field is a constant named after the one in `x86::vmx::vmcs`, typed with
+//! its width, so that reading or writing a field with a value of a wrong width
+//! is a compile error. The width is also checked against the encoding of the
+//! field at compile time. Only the full encodings of the 64-bit fields are
+//! defined, as the high halves are only for 32-bit hosts.
+//!
+//! ```ignore
+//! let rip = guest::RIP.read();
+//! guest::RIP.write(rip + u64::from(ro::VMEXIT_INSTRUCTION_LEN.read()));
+//! ```
+// See: APPENDIX B FIELD ENCODING IN VMCS
+
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use crate::hypervisor::{platform_ops, support::PageBox, x86_instructions::rdmsr, HvError};
+
+/// The width of a VMCS field, encoded in bits 14:13 of its encoding.
+// See: Table 25-21. Structure of VMCS Component Encoding
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Width {
+    Bits16,
+    Bits64,
+    Bits32,
+    Natural,
+}
+
+impl Width {
+    pub(crate) const fn of(encoding: u32) -> Self {
+        match (encoding >> 13) & 0b11 {
+            0 => Self::Bits16,
+            1 => Self::Bits64,
+            2 => Self::Bits32,
+            _ => Self::Natural,
+        }
+    }
+}
+
+/// A 16-bit VMCS field.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Field16(u32);
+
+impl Field16 {
+    const fn new(encoding: u32) -> Self {
+        assert!(matches!(Width::of(encoding), Width::Bits16));
+        Self(encoding)
+    }
+
+    /// Reads the field of the current VMCS.
+    pub(crate) fn read(self) -> u16 {
+        vmread(self.0) as u16
+    }
+
+    /// Writes `value` to the field of the current VMCS.
+    pub(crate) fn write(self, value: u16) {
+        vmwrite(self.0, u64::from(value));
+    }
+}
+
+/// A 32-bit VMCS field.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Field32(u32);
+
+impl Field32 {
+    const fn new(encoding: u32) -> Self {
+        assert!(matches!(Width::of(encoding), Width::Bits32));
+        Self(encoding)
+    }
+
+    /// Reads the field of the current VMCS.
+    pub(crate) fn read(self) -> u32 {
+        vmread(self.0) as u32
+    }
+
+    /// Writes `value` to the field of the current VMCS.
+    pub(crate) fn write(self, value: u32) {
+        vmwrite(self.0, u64::from(value));
+    }
+}
+
+/// A 64-bit VMCS field, accessed with its full encoding.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Field64(u32);
+
+impl Field64 {
+    const fn new(encoding: u32) -> Self {
+        assert!(matches!(Width::of(encoding), Width::Bits64));
+        assert!(encoding & 1 == 0, "the high encoding is not supported");
+        Self(encoding)
+    }
+
+    /// Reads the field of the current VMCS.
+    pub(crate) fn read(self) -> u64 {
+        vmread(self.0)
+    }
+
+    /// Writes `value` to the field of the current VMCS.
+    pub(crate) fn write(self, value: u64) {
+        vmwrite(self.0, value);
+    }
+}
+
+/// A natural-width VMCS field, which is 64-bit on the 64-bit processor.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FieldNatural(u32);
+
+impl FieldNatural {
+    const fn new(encoding: u32) -> Self {
+        assert!(matches!(Width::of(encoding), Width::Natural));
+        Self(encoding)
+    }
+
+    /// Reads the field of the current VMCS.
+    pub(crate) fn read(self) -> u64 {
+        vmread(self.0)
+    }
+
+    /// Writes `value` to the field of the current VMCS.
+    pub(crate) fn write(self, value: u64) {
+        vmwrite(self.0, value);
+    }
+}
+
+/// A VMCS field of any width.
+pub(crate) trait Field: Copy {
+    /// Returns the encoding of the field.
+    fn encoding(self) -> u32;
+}
+
+impl Field for Field16 {
+    fn encoding(self) -> u32 {
+        self.0
+    }
+}
+
+impl Field for Field32 {
+    fn encoding(self) -> u32 {
+        self.0
+    }
+}
+
+impl Field for Field64 {
+    fn encoding(self) -> u32 {
+        self.0
+    }
+}
+
+impl Field for FieldNatural {
+    fn encoding(self) -> u32 {
+        self.0
+    }
+}
+
+/// VM-execution, VM-exit, and VM-entry control fields.
+pub(crate) mod control {
+    use x86::vmx::vmcs;
+
+    use super::{Field16, Field32, Field64, FieldNatural};
+
+    pub(crate) use x86::vmx::vmcs::control::{
+        EntryControls, ExitControls, PinbasedControls, PrimaryControls, SecondaryControls,
+    };
+
+    /// Virtual-processor identifier (VPID).
+    pub(crate) const VPID: Field16 = Field16::new(vmcs::control::VPID);
+
+    /// Posted-interrupt notification vector.
+    pub(crate) const POSTED_INTERRUPT_NOTIFICATION_VECTOR: Field16 =
+        Field16::new(vmcs::control::POSTED_INTERRUPT_NOTIFICATION_VECTOR);
+
+    /// EPTP index.
+    pub(crate) const EPTP_INDEX: Field16 = Field16::new(vmcs::control::EPTP_INDEX);
+
+    /// Address of I/O bitmap A.
+    pub(crate) const IO_BITMAP_A_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::IO_BITMAP_A_ADDR_FULL);
+
+    /// Address of I/O bitmap B.
+    pub(crate) const IO_BITMAP_B_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::IO_BITMAP_B_ADDR_FULL);
+
+    /// Address of MSR bitmaps.
+    pub(crate) const MSR_BITMAPS_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::MSR_BITMAPS_ADDR_FULL);
+
+    /// VM-exit MSR-store address.
+    pub(crate) const VMEXIT_MSR_STORE_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::VMEXIT_MSR_STORE_ADDR_FULL);
+
+    /// VM-exit MSR-load address.
+    pub(crate) const VMEXIT_MSR_LOAD_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::VMEXIT_MSR_LOAD_ADDR_FULL);
+
+    /// VM-entry MSR-load address.
+    pub(crate) const VMENTRY_MSR_LOAD_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::VMENTRY_MSR_LOAD_ADDR_FULL);
+
+    /// Executive-VMCS pointer.
+    pub(crate) const EXECUTIVE_VMCS_PTR_FULL: Field64 =
+        Field64::new(vmcs::control::EXECUTIVE_VMCS_PTR_FULL);
+
+    /// PML address.
+    pub(crate) const PML_ADDR_FULL: Field64 = Field64::new(vmcs::control::PML_ADDR_FULL);
+
+    /// TSC offset.
+    pub(crate) const TSC_OFFSET_FULL: Field64 = Field64::new(vmcs::control::TSC_OFFSET_FULL);
+
+    /// Virtual-APIC address.
+    pub(crate) const VIRT_APIC_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::VIRT_APIC_ADDR_FULL);
+
+    /// APIC-access address.
+    pub(crate) const APIC_ACCESS_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::APIC_ACCESS_ADDR_FULL);
+
+    /// Posted-interrupt descriptor address.
+    pub(crate) const POSTED_INTERRUPT_DESC_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::POSTED_INTERRUPT_DESC_ADDR_FULL);
+
+    /// VM-function controls.
+    pub(crate) const VM_FUNCTION_CONTROLS_FULL: Field64 =
+        Field64::new(vmcs::control::VM_FUNCTION_CONTROLS_FULL);
+
+    /// EPT pointer.
+    pub(crate) const EPTP_FULL: Field64 = Field64::new(vmcs::control::EPTP_FULL);
+
+    /// EOI-exit bitmap 0.
+    pub(crate) const EOI_EXIT0_FULL: Field64 = Field64::new(vmcs::control::EOI_EXIT0_FULL);
+
+    /// EOI-exit bitmap 1.
+    pub(crate) const EOI_EXIT1_FULL: Field64 = Field64::new(vmcs::control::EOI_EXIT1_FULL);
+
+    /// EOI-exit bitmap 2.
+    pub(crate) const EOI_EXIT2_FULL: Field64 = Field64::new(vmcs::control::EOI_EXIT2_FULL);
+
+    /// EOI-exit bitmap 3.
+    pub(crate) const EOI_EXIT3_FULL: Field64 = Field64::new(vmcs::control::EOI_EXIT3_FULL);
+
+    /// EPTP-list address.
+    pub(crate) const EPTP_LIST_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::EPTP_LIST_ADDR_FULL);
+
+    /// VMREAD-bitmap address.
+    pub(crate) const VMREAD_BITMAP_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::VMREAD_BITMAP_ADDR_FULL);
+
+    /// VMWRITE-bitmap address.
+    pub(crate) const VMWRITE_BITMAP_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::VMWRITE_BITMAP_ADDR_FULL);
+
+    /// Virtualization-exception information address.
+    pub(crate) const VIRT_EXCEPTION_INFO_ADDR_FULL: Field64 =
+        Field64::new(vmcs::control::VIRT_EXCEPTION_INFO_ADDR_FULL);
+
+    /// XSS-exiting bitmap.
+    pub(crate) const XSS_EXITING_BITMAP_FULL: Field64 =
+        Field64::new(vmcs::control::XSS_EXITING_BITMAP_FULL);
+
+    /// ENCLS-exiting bitmap.
+    pub(crate) const ENCLS_EXITING_BITMAP_FULL: Field64 =
+        Field64::new(vmcs::control::ENCLS_EXITING_BITMAP_FULL);
+
+    /// Sub-page-permission-table pointer.
+    pub(crate) const SUBPAGE_PERM_TABLE_PTR_FULL: Field64 =
+        Field64::new(vmcs::control::SUBPAGE_PERM_TABLE_PTR_FULL);
+
+    /// TSC multiplier.
+    pub(crate) const TSC_MULTIPLIER_FULL: Field64 =
+        Field64::new(vmcs::control::TSC_MULTIPLIER_FULL);
+
+    /// Pin-based VM-execution controls.
+    pub(crate) const PINBASED_EXEC_CONTROLS: Field32 =
+        Field32::new(vmcs::control::PINBASED_EXEC_CONTROLS);
+
+    /// Primary processor-based VM-execution controls.
+    pub(crate) const PRIMARY_PROCBASED_EXEC_CONTROLS: Field32 =
+        Field32::new(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+
+    /// Exception bitmap.
+    pub(crate) const EXCEPTION_BITMAP: Field32 = Field32::new(vmcs::control::EXCEPTION_BITMAP);
+
+    /// Page-fault error-code mask.
+    pub(crate) const PAGE_FAULT_ERR_CODE_MASK: Field32 =
+        Field32::new(vmcs::control::PAGE_FAULT_ERR_CODE_MASK);
+
+    /// Page-fault error-code match.
+    pub(crate) const PAGE_FAULT_ERR_CODE_MATCH: Field32 =
+        Field32::new(vmcs::control::PAGE_FAULT_ERR_CODE_MATCH);
+
+    /// CR3-target count.
+    pub(crate) const CR3_TARGET_COUNT: Field32 = Field32::new(vmcs::control::CR3_TARGET_COUNT);
+
+    /// VM-exit controls.
+    pub(crate) const VMEXIT_CONTROLS: Field32 = Field32::new(vmcs::control::VMEXIT_CONTROLS);
+
+    /// VM-exit MSR-store count.
+    pub(crate) const VMEXIT_MSR_STORE_COUNT: Field32 =
+        Field32::new(vmcs::control::VMEXIT_MSR_STORE_COUNT);
+
+    /// VM-exit MSR-load count.
+    pub(crate) const VMEXIT_MSR_LOAD_COUNT: Field32 =
+        Field32::new(vmcs::control::VMEXIT_MSR_LOAD_COUNT);
+
+    /// VM-entry controls.
+    pub(crate) const VMENTRY_CONTROLS: Field32 = Field32::new(vmcs::control::VMENTRY_CONTROLS);
+
+    /// VM-entry MSR-load count.
+    pub(crate) const VMENTRY_MSR_LOAD_COUNT: Field32 =
+        Field32::new(vmcs::control::VMENTRY_MSR_LOAD_COUNT);
+
+    /// VM-entry interruption-information field.
+    pub(crate) const VMENTRY_INTERRUPTION_INFO_FIELD: Field32 =
+        Field32::new(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD);
+
+    /// VM-entry exception error code.
+    pub(crate) const VMENTRY_EXCEPTION_ERR_CODE: Field32 =
+        Field32::new(vmcs::control::VMENTRY_EXCEPTION_ERR_CODE);
+
+    /// VM-entry instruction length.
+    pub(crate) const VMENTRY_INSTRUCTION_LEN: Field32 =
+        Field32::new(vmcs::control::VMENTRY_INSTRUCTION_LEN);
+
+    /// TPR threshold.
+    pub(crate) const TPR_THRESHOLD: Field32 = Field32::new(vmcs::control::TPR_THRESHOLD);
+
+    /// Secondary processor-based VM-execution controls.
+    pub(crate) const SECONDARY_PROCBASED_EXEC_CONTROLS: Field32 =
+        Field32::new(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS);
+
+    /// PLE_Gap.
+    pub(crate) const PLE_GAP: Field32 = Field32::new(vmcs::control::PLE_GAP);
+
+    /// PLE_Window.
+    pub(crate) const PLE_WINDOW: Field32 = Field32::new(vmcs::control::PLE_WINDOW);
+
+    /// CR0 guest/host mask.
+    pub(crate) const CR0_GUEST_HOST_MASK: FieldNatural =
+        FieldNatural::new(vmcs::control::CR0_GUEST_HOST_MASK);
+
+    /// CR4 guest/host mask.
+    pub(crate) const CR4_GUEST_HOST_MASK: FieldNatural =
+        FieldNatural::new(vmcs::control::CR4_GUEST_HOST_MASK);
+
+    /// CR0 read shadow.
+    pub(crate) const CR0_READ_SHADOW: FieldNatural =
+        FieldNatural::new(vmcs::control::CR0_READ_SHADOW);
+
+    /// CR4 read shadow.
+    pub(crate) const CR4_READ_SHADOW: FieldNatural =
+        FieldNatural::new(vmcs::control::CR4_READ_SHADOW);
+
+    /// CR3-target value 0.
+    pub(crate) const CR3_TARGET_VALUE0: FieldNatural =
+        FieldNatural::new(vmcs::control::CR3_TARGET_VALUE0);
+
+    /// CR3-target value 1.
+    pub(crate) const CR3_TARGET_VALUE1: FieldNatural =
+        FieldNatural::new(vmcs::control::CR3_TARGET_VALUE1);
+
+    /// CR3-target value 2.
+    pub(crate) const CR3_TARGET_VALUE2: FieldNatural =
+        FieldNatural::new(vmcs::control::CR3_TARGET_VALUE2);
+
+    /// CR3-target value 3.
+    pub(crate) const CR3_TARGET_VALUE3: FieldNatural =
+        FieldNatural::new(vmcs::control::CR3_TARGET_VALUE3);
+}
+
+/// Guest-state fields.
+pub(crate) mod guest {
+    use x86::vmx::vmcs;
+
+    use super::{Field16, Field32, Field64, FieldNatural};
+
+    /// Guest ES selector.
+    pub(crate) const ES_SELECTOR: Field16 = Field16::new(vmcs::guest::ES_SELECTOR);
+
+    /// Guest CS selector.
+    pub(crate) const CS_SELECTOR: Field16 = Field16::new(vmcs::guest::CS_SELECTOR);
+
+    /// Guest SS selector.
+    pub(crate) const SS_SELECTOR: Field16 = Field16::new(vmcs::guest::SS_SELECTOR);
+
+    /// Guest DS selector.
+    pub(crate) const DS_SELECTOR: Field16 = Field16::new(vmcs::guest::DS_SELECTOR);
+
+    /// Guest FS selector.
+    pub(crate) const FS_SELECTOR: Field16 = Field16::new(vmcs::guest::FS_SELECTOR);
+
+    /// Guest GS selector.
+    pub(crate) const GS_SELECTOR: Field16 = Field16::new(vmcs::guest::GS_SELECTOR);
+
+    /// Guest LDTR selector.
+    pub(crate) const LDTR_SELECTOR: Field16 = Field16::new(vmcs::guest::LDTR_SELECTOR);
+
+    /// Guest TR selector.
+    pub(crate) const TR_SELECTOR: Field16 = Field16::new(vmcs::guest::TR_SELECTOR);
+
+    /// Guest interrupt status.
+    pub(crate) const INTERRUPT_STATUS: Field16 = Field16::new(vmcs::guest::INTERRUPT_STATUS);
+
+    /// PML index.
+    pub(crate) const PML_INDEX: Field16 = Field16::new(vmcs::guest::PML_INDEX);
+
+    /// VMCS link pointer.
+    pub(crate) const LINK_PTR_FULL: Field64 = Field64::new(vmcs::guest::LINK_PTR_FULL);
+
+    /// Guest IA32_DEBUGCTL.
+    pub(crate) const IA32_DEBUGCTL_FULL: Field64 = Field64::new(vmcs::guest::IA32_DEBUGCTL_FULL);
+
+    /// Guest IA32_PAT.
+    pub(crate) const IA32_PAT_FULL: Field64 = Field64::new(vmcs::guest::IA32_PAT_FULL);
+
+    /// Guest IA32_EFER.
+    pub(crate) const IA32_EFER_FULL: Field64 = Field64::new(vmcs::guest::IA32_EFER_FULL);
+
+    /// Guest IA32_PERF_GLOBAL_CTRL.
+    pub(crate) const IA32_PERF_GLOBAL_CTRL_FULL: Field64 =
+        Field64::new(vmcs::guest::IA32_PERF_GLOBAL_CTRL_FULL);
+
+    /// Guest PDPTE0.
+    pub(crate) const PDPTE0_FULL: Field64 = Field64::new(vmcs::guest::PDPTE0_FULL);
+
+    /// Guest PDPTE1.
+    pub(crate) const PDPTE1_FULL: Field64 = Field64::new(vmcs::guest::PDPTE1_FULL);
+
+    /// Guest PDPTE2.
+    pub(crate) const PDPTE2_FULL: Field64 = Field64::new(vmcs::guest::PDPTE2_FULL);
+
+    /// Guest PDPTE3.
+    pub(crate) const PDPTE3_FULL: Field64 = Field64::new(vmcs::guest::PDPTE3_FULL);
+
+    /// Guest IA32_BNDCFGS.
+    pub(crate) const IA32_BNDCFGS_FULL: Field64 = Field64::new(vmcs::guest::IA32_BNDCFGS_FULL);
+
+    /// Guest IA32_RTIT_CTL.
+    pub(crate) const IA32_RTIT_CTL_FULL: Field64 = Field64::new(vmcs::guest::IA32_RTIT_CTL_FULL);
+
+    /// Guest ES limit.
+    pub(crate) const ES_LIMIT: Field32 = Field32::new(vmcs::guest::ES_LIMIT);
+
+    /// Guest CS limit.
+    pub(crate) const CS_LIMIT: Field32 = Field32::new(vmcs::guest::CS_LIMIT);
+
+    /// Guest SS limit.
+    pub(crate) const SS_LIMIT: Field32 = Field32::new(vmcs::guest::SS_LIMIT);
+
+    /// Guest DS limit.
+    pub(crate) const DS_LIMIT: Field32 = Field32::new(vmcs::guest::DS_LIMIT);
+
+    /// Guest FS limit.
+    pub(crate) const FS_LIMIT: Field32 = Field32::new(vmcs::guest::FS_LIMIT);
+
+    /// Guest GS limit.
+    pub(crate) const GS_LIMIT: Field32 = Field32::new(vmcs::guest::GS_LIMIT);
+
+    /// Guest LDTR limit.
+    pub(crate) const LDTR_LIMIT: Field32 = Field32::new(vmcs::guest::LDTR_LIMIT);
+
+    /// Guest TR limit.
+    pub(crate) const TR_LIMIT: Field32 = Field32::new(vmcs::guest::TR_LIMIT);
+
+    /// Guest GDTR limit.
+    pub(crate) const GDTR_LIMIT: Field32 = Field32::new(vmcs::guest::GDTR_LIMIT);
+
+    /// Guest IDTR limit.
+    pub(crate) const IDTR_LIMIT: Field32 = Field32::new(vmcs::guest::IDTR_LIMIT);
+
+    /// Guest ES access rights.
+    pub(crate) const ES_ACCESS_RIGHTS: Field32 = Field32::new(vmcs::guest::ES_ACCESS_RIGHTS);
+
+    /// Guest CS access rights.
+    pub(crate) const CS_ACCESS_RIGHTS: Field32 = Field32::new(vmcs::guest::CS_ACCESS_RIGHTS);
+
+    /// Guest SS access rights.
+    pub(crate) const SS_ACCESS_RIGHTS: Field32 = Field32::new(vmcs::guest::SS_ACCESS_RIGHTS);
+
+    /// Guest DS access rights.
+    pub(crate) const DS_ACCESS_RIGHTS: Field32 = Field32::new(vmcs::guest::DS_ACCESS_RIGHTS);
+
+    /// Guest FS access rights.
+    pub(crate) const FS_ACCESS_RIGHTS: Field32 = Field32::new(vmcs::guest::FS_ACCESS_RIGHTS);
+
+    /// Guest GS access rights.
+    pub(crate) const GS_ACCESS_RIGHTS: Field32 = Field32::new(vmcs::guest::GS_ACCESS_RIGHTS);
+
+    /// Guest LDTR access rights.
+    pub(crate) const LDTR_ACCESS_RIGHTS: Field32 = Field32::new(vmcs::guest::LDTR_ACCESS_RIGHTS);
+
+    /// Guest TR access rights.
+    pub(crate) const TR_ACCESS_RIGHTS: Field32 = Field32::new(vmcs::guest::TR_ACCESS_RIGHTS);
+
+    /// Guest interruptibility state.
+    pub(crate) const INTERRUPTIBILITY_STATE: Field32 =
+        Field32::new(vmcs::guest::INTERRUPTIBILITY_STATE);
+
+    /// Guest activity state.
+    pub(crate) const ACTIVITY_STATE: Field32 = Field32::new(vmcs::guest::ACTIVITY_STATE);
+
+    /// Guest SMBASE.
+    pub(crate) const SMBASE: Field32 = Field32::new(vmcs::guest::SMBASE);
+
+    /// Guest IA32_SYSENTER_CS.
+    pub(crate) const IA32_SYSENTER_CS: Field32 = Field32::new(vmcs::guest::IA32_SYSENTER_CS);
+
+    /// VMX-preemption timer value.
+    pub(crate) const VMX_PREEMPTION_TIMER_VALUE: Field32 =
+        Field32::new(vmcs::guest::VMX_PREEMPTION_TIMER_VALUE);
+
+    /// Guest CR0.
+    pub(crate) const CR0: FieldNatural = FieldNatural::new(vmcs::guest::CR0);
+
+    /// Guest CR3.
+    pub(crate) const CR3: FieldNatural = FieldNatural::new(vmcs::guest::CR3);
+
+    /// Guest CR4.
+    pub(crate) const CR4: FieldNatural = FieldNatural::new(vmcs::guest::CR4);
+
+    /// Guest ES base.
+    pub(crate) const ES_BASE: FieldNatural = FieldNatural::new(vmcs::guest::ES_BASE);
+
+    /// Guest CS base.
+    pub(crate) const CS_BASE: FieldNatural = FieldNatural::new(vmcs::guest::CS_BASE);
+
+    /// Guest SS base.
+    pub(crate) const SS_BASE: FieldNatural = FieldNatural::new(vmcs::guest::SS_BASE);
+
+    /// Guest DS base.
+    pub(crate) const DS_BASE: FieldNatural = FieldNatural::new(vmcs::guest::DS_BASE);
+
+    /// Guest FS base.
+    pub(crate) const FS_BASE: FieldNatural = FieldNatural::new(vmcs::guest::FS_BASE);
+
+    /// Guest GS base.
+    pub(crate) const GS_BASE: FieldNatural = FieldNatural::new(vmcs::guest::GS_BASE);
+
+    /// Guest LDTR base.
+    pub(crate) const LDTR_BASE: FieldNatural = FieldNatural::new(vmcs::guest::LDTR_BASE);
+
+    /// Guest TR base.
+    pub(crate) const TR_BASE: FieldNatural = FieldNatural::new(vmcs::guest::TR_BASE);
+
+    /// Guest GDTR base.
+    pub(crate) const GDTR_BASE: FieldNatural = FieldNatural::new(vmcs::guest::GDTR_BASE);
+
+    /// Guest IDTR base.
+    pub(crate) const IDTR_BASE: FieldNatural = FieldNatural::new(vmcs::guest::IDTR_BASE);
+
+    /// Guest DR7.
+    pub(crate) const DR7: FieldNatural = FieldNatural::new(vmcs::guest::DR7);
+
+    /// Guest RSP.
+    pub(crate) const RSP: FieldNatural = FieldNatural::new(vmcs::guest::RSP);
+
+    /// Guest RIP.
+    pub(crate) const RIP: FieldNatural = FieldNatural::new(vmcs::guest::RIP);
+
+    /// Guest RFLAGS.
+    pub(crate) const RFLAGS: FieldNatural = FieldNatural::new(vmcs::guest::RFLAGS);
+
+    /// Guest pending debug exceptions.
+    pub(crate) const PENDING_DBG_EXCEPTIONS: FieldNatural =
+        FieldNatural::new(vmcs::guest::PENDING_DBG_EXCEPTIONS);
+
+    /// Guest IA32_SYSENTER_ESP.
+    pub(crate) const IA32_SYSENTER_ESP: FieldNatural =
+        FieldNatural::new(vmcs::guest::IA32_SYSENTER_ESP);
+
+    /// Guest IA32_SYSENTER_EIP.
+    pub(crate) const IA32_SYSENTER_EIP: FieldNatural =
+        FieldNatural::new(vmcs::guest::IA32_SYSENTER_EIP);
+
+    /// Guest IA32_S_CET. Not defined in `x86::vmx::vmcs`.
+    pub(crate) const IA32_S_CET: FieldNatural = FieldNatural::new(0x6828);
+
+    /// Guest SSP. Not defined in `x86::vmx::vmcs`.
+    pub(crate) const SSP: FieldNatural = FieldNatural::new(0x682a);
+
+    /// Guest IA32_INTERRUPT_SSP_TABLE_ADDR. Not defined in `x86::vmx::vmcs`.
+    pub(crate) const IA32_INTERRUPT_SSP_TABLE_ADDR: FieldNatural = FieldNatural::new(0x682c);
+}
+
+/// Host-state fields.
+pub(crate) mod host {
+    use x86::vmx::vmcs;
+
+    use super::{Field16, Field32, Field64, FieldNatural};
+
+    /// Host ES selector.
+    pub(crate) const ES_SELECTOR: Field16 = Field16::new(vmcs::host::ES_SELECTOR);
+
+    /// Host CS selector.
+    pub(crate) const CS_SELECTOR: Field16 = Field16::new(vmcs::host::CS_SELECTOR);
+
+    /// Host SS selector.
+    pub(crate) const SS_SELECTOR: Field16 = Field16::new(vmcs::host::SS_SELECTOR);
+
+    /// Host DS selector.
+    pub(crate) const DS_SELECTOR: Field16 = Field16::new(vmcs::host::DS_SELECTOR);
+
+    /// Host FS selector.
+    pub(crate) const FS_SELECTOR: Field16 = Field16::new(vmcs::host::FS_SELECTOR);
+
+    /// Host GS selector.
+    pub(crate) const GS_SELECTOR: Field16 = Field16::new(vmcs::host::GS_SELECTOR);
+
+    /// Host TR selector.
+    pub(crate) const TR_SELECTOR: Field16 = Field16::new(vmcs::host::TR_SELECTOR);
+
+    /// Host IA32_PAT.
+    pub(crate) const IA32_PAT_FULL: Field64 = Field64::new(vmcs::host::IA32_PAT_FULL);
+
+    /// Host IA32_EFER.
+    pub(crate) const IA32_EFER_FULL: Field64 = Field64::new(vmcs::host::IA32_EFER_FULL);
+
+    /// Host IA32_PERF_GLOBAL_CTRL.
+    pub(crate) const IA32_PERF_GLOBAL_CTRL_FULL: Field64 =
+        Field64::new(vmcs::host::IA32_PERF_GLOBAL_CTRL_FULL);
+
+    /// Host IA32_SYSENTER_CS.
+    pub(crate) const IA32_SYSENTER_CS: Field32 = Field32::new(vmcs::host::IA32_SYSENTER_CS);
+
+    /// Host CR0.
+    pub(crate) const CR0: FieldNatural = FieldNatural::new(vmcs::host::CR0);
+
+    /// Host CR3.
+    pub(crate) const CR3: FieldNatural = FieldNatural::new(vmcs::host::CR3);
+
+    /// Host CR4.
+    pub(crate) const CR4: FieldNatural = FieldNatural::new(vmcs::host::CR4);
+
+    /// Host FS base.
+    pub(crate) const FS_BASE: FieldNatural = FieldNatural::new(vmcs::host::FS_BASE);
+
+    /// Host GS base.
+    pub(crate) const GS_BASE: FieldNatural = FieldNatural::new(vmcs::host::GS_BASE);
+
+    /// Host TR base.
+    pub(crate) const TR_BASE: FieldNatural = FieldNatural::new(vmcs::host::TR_BASE);
+
+    /// Host GDTR base.
+    pub(crate) const GDTR_BASE: FieldNatural = FieldNatural::new(vmcs::host::GDTR_BASE);
+
+    /// Host IDTR base.
+    pub(crate) const IDTR_BASE: FieldNatural = FieldNatural::new(vmcs::host::IDTR_BASE);
+
+    /// Host IA32_SYSENTER_ESP.
+    pub(crate) const IA32_SYSENTER_ESP: FieldNatural =
+        FieldNatural::new(vmcs::host::IA32_SYSENTER_ESP);
+
+    /// Host IA32_SYSENTER_EIP.
+    pub(crate) const IA32_SYSENTER_EIP: FieldNatural =
+        FieldNatural::new(vmcs::host::IA32_SYSENTER_EIP);
+
+    /// Host IA32_S_CET. Not defined in `x86::vmx::vmcs`.
+    pub(crate) const IA32_S_CET: FieldNatural = FieldNatural::new(0x6c18);
+
+    /// Host SSP. Not defined in `x86::vmx::vmcs`.
+    pub(crate) const SSP: FieldNatural = FieldNatural::new(0x6c1a);
+
+    /// Host IA32_INTERRUPT_SSP_TABLE_ADDR. Not defined in `x86::vmx::vmcs`.
+    pub(crate) const IA32_INTERRUPT_SSP_TABLE_ADDR: FieldNatural = FieldNatural::new(0x6c1c);
+
+    /// Host RSP.
+    pub(crate) const RSP: FieldNatural = FieldNatural::new(vmcs::host::RSP);
+
+    /// Host RIP.
+    pub(crate) const RIP: FieldNatural = FieldNatural::new(vmcs::host::RIP);
+}
+
+/// VM-exit information fields, which are read-only.
+pub(crate) mod ro {
+    use x86::vmx::vmcs;
+
+    use super::{Field32, Field64, FieldNatural};
+
+    /// Guest-physical address.
+    pub(crate) const GUEST_PHYSICAL_ADDR_FULL: Field64 =
+        Field64::new(vmcs::ro::GUEST_PHYSICAL_ADDR_FULL);
+
+    /// VM-instruction error.
+    pub(crate) const VM_INSTRUCTION_ERROR: Field32 = Field32::new(vmcs::ro::VM_INSTRUCTION_ERROR);
+
+    /// Exit reason.
+    pub(crate) const EXIT_REASON: Field32 = Field32::new(vmcs::ro::EXIT_REASON);
+
+    /// VM-exit interruption information.
+    pub(crate) const VMEXIT_INTERRUPTION_INFO: Field32 =
+        Field32::new(vmcs::ro::VMEXIT_INTERRUPTION_INFO);
+
+    /// VM-exit interruption error code.
+    pub(crate) const VMEXIT_INTERRUPTION_ERR_CODE: Field32 =
+        Field32::new(vmcs::ro::VMEXIT_INTERRUPTION_ERR_CODE);
+
+    /// IDT-vectoring information field.
+    pub(crate) const IDT_VECTORING_INFO: Field32 = Field32::new(vmcs::ro::IDT_VECTORING_INFO);
+
+    /// IDT-vectoring error code.
+    pub(crate) const IDT_VECTORING_ERR_CODE: Field32 =
+        Field32::new(vmcs::ro::IDT_VECTORING_ERR_CODE);
+
+    /// VM-exit instruction length.
+    pub(crate) const VMEXIT_INSTRUCTION_LEN: Field32 =
+        Field32::new(vmcs::ro::VMEXIT_INSTRUCTION_LEN);
+
+    /// VM-exit instruction information.
+    pub(crate) const VMEXIT_INSTRUCTION_INFO: Field32 =
+        Field32::new(vmcs::ro::VMEXIT_INSTRUCTION_INFO);
+
+    /// Exit qualification.
+    pub(crate) const EXIT_QUALIFICATION: FieldNatural =
+        FieldNatural::new(vmcs::ro::EXIT_QUALIFICATION);
+
+    /// I/O RCX.
+    pub(crate) const IO_RCX: FieldNatural = FieldNatural::new(vmcs::ro::IO_RCX);
+
+    /// I/O RSI.
+    pub(crate) const IO_RSI: FieldNatural = FieldNatural::new(vmcs::ro::IO_RSI);
+
+    /// I/O RDI.
+    pub(crate) const IO_RDI: FieldNatural = FieldNatural::new(vmcs::ro::IO_RDI);
+
+    /// I/O RIP.
+    pub(crate) const IO_RIP: FieldNatural = FieldNatural::new(vmcs::ro::IO_RIP);
+
+    /// Guest-linear address.
+    pub(crate) const GUEST_LINEAR_ADDR: FieldNatural =
+        FieldNatural::new(vmcs::ro::GUEST_LINEAR_ADDR);
+}
+
+#[derive(derive_deref::Deref, derive_deref::DerefMut)]
+pub(crate) struct Vmcs {
+    ptr: PageBox<VmcsRaw>,
+}
+
+impl Vmcs {
+    pub(crate) fn new() -> Result<Self, HvError> {
+        let mut vmcs = PageBox::<VmcsRaw>::try_new()?;
+        vmcs.revision_id = rdmsr(x86::msr::IA32_VMX_BASIC) as _;
+        vmclear(&mut vmcs)?;
+        Ok(Self { ptr: vmcs })
+    }
+
+    /// Creates a VMCS with the shadow-VMCS indicator set, which the processor
+    /// uses for the VMREAD and VMWRITE instructions of the guest with VMCS
+    /// shadowing. See `nested`.
+    // See: 25.10 VMCS TYPES: ORDINARY AND SHADOW
+    pub(crate) fn new_shadow() -> Result<Self, HvError> {
+        const SHADOW_VMCS_INDICATOR: u32 = 1 << 31;
+
+        let mut vmcs = PageBox::<VmcsRaw>::try_new()?;
+        vmcs.revision_id = rdmsr(x86::msr::IA32_VMX_BASIC) as u32 | SHADOW_VMCS_INDICATOR;
+        vmclear(&mut vmcs)?;
+        Ok(Self { ptr: vmcs })
+    }
+
+    /// Returns the physical address of the VMCS region.
+    pub(crate) fn pa(&self) -> u64 {
+        self.ptr.pa()
+    }
+}
+
+/// The region of memory that the logical processor uses to represent a virtual
+/// CPU. Called virtual-machine control data structure (VMCS).
+///
+/// See: 25.2 FORMAT OF THE VMCS REGION
+#[derive(derivative::Derivative)]
+#[derivative(Default, Debug)]
+#[repr(C, align(4096))]
+pub(crate) struct VmcsRaw {
+    revision_id: u32,
+    abort_indicator: u32,
+    #[derivative(Default(value = "[0; 4088]"), Debug = "ignore")]
+    data: [u8; 4088],
+}
+const _: () = assert!(core::mem::size_of::<VmcsRaw>() == BASE_PAGE_SIZE);
+
+/// The wrapper of the VMCLEAR instruction.
+pub(crate) fn vmclear(vmcs_region: &mut VmcsRaw) -> Result<(), HvError> {
+    let va = vmcs_region as *const _;
+    let pa = platform_ops::get().pa(va as *const _);
+    unsafe { x86::bits64::vmx::vmclear(pa) }.map_err(|_| HvError::InstructionFailed("VMCLEAR"))
+}
+
+/// The wrapper of the VMPTRLD instruction.
+pub(crate) fn vmptrld(vmcs_region: &mut VmcsRaw) -> Result<(), HvError> {
+    let va = vmcs_region as *const _;
+    vmptrld_pa(platform_ops::get().pa(va as *const _))
+}
+
+/// The wrapper of the VMPTRLD instruction, with the VMCS region at `pa`.
+pub(crate) fn vmptrld_pa(pa: u64) -> Result<(), HvError> {
+    unsafe { x86::bits64::vmx::vmptrld(pa) }.map_err(|_| HvError::InstructionFailed("VMPTRLD"))
+}
+
+/// The wrapper of the VMREAD instruction.
+fn vmread(encoding: u32) -> u64 {
+    unsafe { x86::bits64::vmx::vmread(encoding) }.unwrap()
+}
+
+/// The wrapper of the VMREAD instruction. Returns zero on error.
+fn vmread_relaxed(encoding: u32) -> u64 {
+    try_vmread(encoding).unwrap_or(0)
+}
+
+/// The wrapper of the VMREAD instruction. Returns `None` if the field is not
+/// supported.
+pub(crate) fn try_vmread(encoding: u32) -> Option<u64> {
+    unsafe { x86::bits64::vmx::vmread(encoding) }.ok()
+}
+
+/// The wrapper of the VMWRITE instruction. Returns `false` if the field is not
+/// supported or is read-only.
+pub(crate) fn try_vmwrite(encoding: u32, value: u64) -> bool {
+    unsafe { x86::bits64::vmx::vmwrite(encoding, value) }.is_ok()
+}
+
+/// The wrapper of the VMWRITE instruction.
+fn vmwrite(encoding: u32, value: u64) {
+    unsafe { x86::bits64::vmx::vmwrite(encoding, value) }
+        .unwrap_or_else(|_| panic!("Could not write {value:x?} to {encoding:x?}"));
+}
+
+impl core::fmt::Debug for Vmcs {
+    fn fmt(&self, format: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Dump all fields of the current VMCS. Fields the processor does not
+        // support read as zero.
+        let mut format = format.debug_struct("Vmcs");
+        let _ = format
+            .field("Current VMCS", &(self as *const _))
+            .field("Revision ID", &self.revision_id);
+        for (name, encoding) in FIELDS {
+            let _ = format.field(name, &vmread_relaxed(*encoding));
+        }
+        format.finish()
+    }
+}
+
+/// The names and encodings of all fields, in the order of the dump.
+#[rustfmt::skip]
+pub(crate) const FIELDS: &[(&str, u32)] = &[
+    // Guest-state fields
+    ("Guest ES selector                              ", guest::ES_SELECTOR.0),
+    ("Guest CS selector                              ", guest::CS_SELECTOR.0),
+    ("Guest SS selector                              ", guest::SS_SELECTOR.0),
+    ("Guest DS selector                              ", guest::DS_SELECTOR.0),
+    ("Guest FS selector                              ", guest::FS_SELECTOR.0),
+    ("Guest GS selector                              ", guest::GS_SELECTOR.0),
+    ("Guest LDTR selector                            ", guest::LDTR_SELECTOR.0),
+    ("Guest TR selector                              ", guest::TR_SELECTOR.0),
+    ("Guest interrupt status                         ", guest::INTERRUPT_STATUS.0),
+    ("PML index                                      ", guest::PML_INDEX.0),
+    ("VMCS link pointer                              ", guest::LINK_PTR_FULL.0),
+    ("Guest IA32_DEBUGCTL                            ", guest::IA32_DEBUGCTL_FULL.0),
+    ("Guest IA32_PAT                                 ", guest::IA32_PAT_FULL.0),
+    ("Guest IA32_EFER                                ", guest::IA32_EFER_FULL.0),
+    ("Guest IA32_PERF_GLOBAL_CTRL                    ", guest::IA32_PERF_GLOBAL_CTRL_FULL.0),
+    ("Guest PDPTE0                                   ", guest::PDPTE0_FULL.0),
+    ("Guest PDPTE1                                   ", guest::PDPTE1_FULL.0),
+    ("Guest PDPTE2                                   ", guest::PDPTE2_FULL.0),
+    ("Guest PDPTE3                                   ", guest::PDPTE3_FULL.0),
+    ("Guest IA32_BNDCFGS                             ", guest::IA32_BNDCFGS_FULL.0),
+    ("Guest IA32_RTIT_CTL                            ", guest::IA32_RTIT_CTL_FULL.0),
+    ("Guest ES limit                                 ", guest::ES_LIMIT.0),
+    ("Guest CS limit                                 ", guest::CS_LIMIT.0),
+    ("Guest SS limit                                 ", guest::SS_LIMIT.0),
+    ("Guest DS limit                                 ", guest::DS_LIMIT.0),
+    ("Guest FS limit                                 ", guest::FS_LIMIT.0),
+    ("Guest GS limit                                 ", guest::GS_LIMIT.0),
+    ("Guest LDTR limit                               ", guest::LDTR_LIMIT.0),
+    ("Guest TR limit                                 ", guest::TR_LIMIT.0),
+    ("Guest GDTR limit                               ", guest::GDTR_LIMIT.0),
+    ("Guest IDTR limit                               ", guest::IDTR_LIMIT.0),
+    ("Guest ES access rights                         ", guest::ES_ACCESS_RIGHTS.0),
+    ("Guest CS access rights                         ", guest::CS_ACCESS_RIGHTS.0),
+    ("Guest SS access rights                         ", guest::SS_ACCESS_RIGHTS.0),
+    ("Guest DS access rights                         ", guest::DS_ACCESS_RIGHTS.0),
+    ("Guest FS access rights                         ", guest::FS_ACCESS_RIGHTS.0),
+    ("Guest GS access rights                         ", guest::GS_ACCESS_RIGHTS.0),
+    ("Guest LDTR access rights                       ", guest::LDTR_ACCESS_RIGHTS.0),
+    ("Guest TR access rights                         ", guest::TR_ACCESS_RIGHTS.0),
+    ("Guest interruptibility state                   ", guest::INTERRUPTIBILITY_STATE.0),
+    ("Guest activity state                           ", guest::ACTIVITY_STATE.0),
+    ("Guest SMBASE                                   ", guest::SMBASE.0),
+    ("Guest IA32_SYSENTER_CS                         ", guest::IA32_SYSENTER_CS.0),
+    ("VMX-preemption timer value                     ", guest::VMX_PREEMPTION_TIMER_VALUE.0),
+    ("Guest CR0                                      ", guest::CR0.0),
+    ("Guest CR3                                      ", guest::CR3.0),
+    ("Guest CR4                                      ", guest::CR4.0),
+    ("Guest ES base                                  ", guest::ES_BASE.0),
+    ("Guest CS base                                  ", guest::CS_BASE.0),
+    ("Guest SS base                                  ", guest::SS_BASE.0),
+    ("Guest DS base                                  ", guest::DS_BASE.0),
+    ("Guest FS base                                  ", guest::FS_BASE.0),
+    ("Guest GS base                                  ", guest::GS_BASE.0),
+    ("Guest LDTR base                                ", guest::LDTR_BASE.0),
+    ("Guest TR base                                  ", guest::TR_BASE.0),
+    ("Guest GDTR base                                ", guest::GDTR_BASE.0),
+    ("Guest IDTR base                                ", guest::IDTR_BASE.0),
+    ("Guest DR7                                      ", guest::DR7.0),
+    ("Guest RSP                                      ", guest::RSP.0),
+    ("Guest RIP                                      ", guest::RIP.0),
+    ("Guest RFLAGS                                   ", guest::RFLAGS.0),
+    ("Guest pending debug exceptions                 ", guest::PENDING_DBG_EXCEPTIONS.0),
+    ("Guest IA32_SYSENTER_ESP                        ", guest::IA32_SYSENTER_ESP.0),
+    ("Guest IA32_SYSENTER_EIP                        ", guest::IA32_SYSENTER_EIP.0),
+
+    // Host-state fields
+    ("Host ES selector                               ", host::ES_SELECTOR.0),
+    ("Host CS selector                               ", host::CS_SELECTOR.0),
+    ("Host SS selector                               ", host::SS_SELECTOR.0),
+    ("Host DS selector                               ", host::DS_SELECTOR.0),
+    ("Host FS selector                               ", host::FS_SELECTOR.0),
+    ("Host GS selector                               ", host::GS_SELECTOR.0),
+    ("Host TR selector                               ", host::TR_SELECTOR.0),
+    ("Host IA32_PAT                                  ", host::IA32_PAT_FULL.0),
+    ("Host IA32_EFER                                 ", host::IA32_EFER_FULL.0),
+    ("Host IA32_PERF_GLOBAL_CTRL                     ", host::IA32_PERF_GLOBAL_CTRL_FULL.0),
+    ("Host IA32_SYSENTER_CS                          ", host::IA32_SYSENTER_CS.0),
+    ("Host CR0                                       ", host::CR0.0),
+    ("Host CR3                                       ", host::CR3.0),
+    ("Host CR4                                       ", host::CR4.0),
+    ("Host FS base                                   ", host::FS_BASE.0),
+    ("Host GS base                                   ", host::GS_BASE.0),
+    ("Host TR base                                   ", host::TR_BASE.0),
+    ("Host GDTR base                                 ", host::GDTR_BASE.0),
+    ("Host IDTR base                                 ", host::IDTR_BASE.0),
+    ("Host IA32_SYSENTER_ESP                         ", host::IA32_SYSENTER_ESP.0),
+    ("Host IA32_SYSENTER_EIP                         ", host::IA32_SYSENTER_EIP.0),
+    ("Host RSP                                       ", host::RSP.0),
+    ("Host RIP                                       ", host::RIP.0),
+
+    // VM-execution, VM-exit, and VM-entry control fields
+    ("Virtual-processor identifier (VPID)            ", control::VPID.0),
+    ("Posted-interrupt notification vector           ", control::POSTED_INTERRUPT_NOTIFICATION_VECTOR.0),
+    ("EPTP index                                     ", control::EPTP_INDEX.0),
+    ("Address of I/O bitmap A                        ", control::IO_BITMAP_A_ADDR_FULL.0),
+    ("Address of I/O bitmap B                        ", control::IO_BITMAP_B_ADDR_FULL.0),
+    ("Address of MSR bitmaps                         ", control::MSR_BITMAPS_ADDR_FULL.0),
+    ("VM-exit MSR-store address                      ", control::VMEXIT_MSR_STORE_ADDR_FULL.0),
+    ("VM-exit MSR-load address                       ", control::VMEXIT_MSR_LOAD_ADDR_FULL.0),
+    ("VM-entry MSR-load address                      ", control::VMENTRY_MSR_LOAD_ADDR_FULL.0),
+    ("Executive-VMCS pointer                         ", control::EXECUTIVE_VMCS_PTR_FULL.0),
+    ("PML address                                    ", control::PML_ADDR_FULL.0),
+    ("TSC offset                                     ", control::TSC_OFFSET_FULL.0),
+    ("Virtual-APIC address                           ", control::VIRT_APIC_ADDR_FULL.0),
+    ("APIC-access address                            ", control::APIC_ACCESS_ADDR_FULL.0),
+    ("Posted-interrupt descriptor address            ", control::POSTED_INTERRUPT_DESC_ADDR_FULL.0),
+    ("VM-function controls                           ", control::VM_FUNCTION_CONTROLS_FULL.0),
+    ("EPT pointer                                    ", control::EPTP_FULL.0),
+    ("EOI-exit bitmap 0                              ", control::EOI_EXIT0_FULL.0),
+    ("EOI-exit bitmap 1                              ", control::EOI_EXIT1_FULL.0),
+    ("EOI-exit bitmap 2                              ", control::EOI_EXIT2_FULL.0),
+    ("EOI-exit bitmap 3                              ", control::EOI_EXIT3_FULL.0),
+    ("EPTP-list address                              ", control::EPTP_LIST_ADDR_FULL.0),
+    ("VMREAD-bitmap address                          ", control::VMREAD_BITMAP_ADDR_FULL.0),
+    ("VMWRITE-bitmap address                         ", control::VMWRITE_BITMAP_ADDR_FULL.0),
+    ("Virtualization-exception information address   ", control::VIRT_EXCEPTION_INFO_ADDR_FULL.0),
+    ("XSS-exiting bitmap                             ", control::XSS_EXITING_BITMAP_FULL.0),
+    ("ENCLS-exiting bitmap                           ", control::ENCLS_EXITING_BITMAP_FULL.0),
+    ("Sub-page-permission-table pointer              ", control::SUBPAGE_PERM_TABLE_PTR_FULL.0),
+    ("TSC multiplier                                 ", control::TSC_MULTIPLIER_FULL.0),
+    ("Pin-based VM-execution controls                ", control::PINBASED_EXEC_CONTROLS.0),
+    ("Primary processor-based VM-execution controls  ", control::PRIMARY_PROCBASED_EXEC_CONTROLS.0),
+    ("Exception bitmap                               ", control::EXCEPTION_BITMAP.0),
+    ("Page-fault error-code mask                     ", control::PAGE_FAULT_ERR_CODE_MASK.0),
+    ("Page-fault error-code match                    ", control::PAGE_FAULT_ERR_CODE_MATCH.0),
+    ("CR3-target count                               ", control::CR3_TARGET_COUNT.0),
+    ("VM-exit controls                               ", control::VMEXIT_CONTROLS.0),
+    ("VM-exit MSR-store count                        ", control::VMEXIT_MSR_STORE_COUNT.0),
+    ("VM-exit MSR-load count                         ", control::VMEXIT_MSR_LOAD_COUNT.0),
+    ("VM-entry controls                              ", control::VMENTRY_CONTROLS.0),
+    ("VM-entry MSR-load count                        ", control::VMENTRY_MSR_LOAD_COUNT.0),
+    ("VM-entry interruption-information field        ", control::VMENTRY_INTERRUPTION_INFO_FIELD.0),
+    ("VM-entry exception error code                  ", control::VMENTRY_EXCEPTION_ERR_CODE.0),
+    ("VM-entry instruction length                    ", control::VMENTRY_INSTRUCTION_LEN.0),
+    ("TPR threshold                                  ", control::TPR_THRESHOLD.0),
+    ("Secondary processor-based VM-execution controls", control::SECONDARY_PROCBASED_EXEC_CONTROLS.0),
+    ("PLE_Gap                                        ", control::PLE_GAP.0),
+    ("PLE_Window                                     ", control::PLE_WINDOW.0),
+    ("CR0 guest/host mask                            ", control::CR0_GUEST_HOST_MASK.0),
+    ("CR4 guest/host mask                            ", control::CR4_GUEST_HOST_MASK.0),
+    ("CR0 read shadow                                ", control::CR0_READ_SHADOW.0),
+    ("CR4 read shadow                                ", control::CR4_READ_SHADOW.0),
+    ("CR3-target value 0                             ", control::CR3_TARGET_VALUE0.0),
+    ("CR3-target value 1                             ", control::CR3_TARGET_VALUE1.0),
+    ("CR3-target value 2                             ", control::CR3_TARGET_VALUE2.0),
+    ("CR3-target value 3                             ", control::CR3_TARGET_VALUE3.0),
+
+    // VM-exit information fields, which are read-only
+    ("Guest-physical address                         ", ro::GUEST_PHYSICAL_ADDR_FULL.0),
+    ("VM-instruction error                           ", ro::VM_INSTRUCTION_ERROR.0),
+    ("Exit reason                                    ", ro::EXIT_REASON.0),
+    ("VM-exit interruption information               ", ro::VMEXIT_INTERRUPTION_INFO.0),
+    ("VM-exit interruption error code                ", ro::VMEXIT_INTERRUPTION_ERR_CODE.0),
+    ("IDT-vectoring information field                ", ro::IDT_VECTORING_INFO.0),
+    ("IDT-vectoring error code                       ", ro::IDT_VECTORING_ERR_CODE.0),
+    ("VM-exit instruction length                     ", ro::VMEXIT_INSTRUCTION_LEN.0),
+    ("VM-exit instruction information                ", ro::VMEXIT_INSTRUCTION_INFO.0),
+    ("Exit qualification                             ", ro::EXIT_QUALIFICATION.0),
+    ("I/O RCX                                        ", ro::IO_RCX.0),
+    ("I/O RSI                                        ", ro::IO_RSI.0),
+    ("I/O RDI                                        ", ro::IO_RDI.0),
+    ("I/O RIP                                        ", ro::IO_RIP.0),
+    ("Guest-linear address                           ", ro::GUEST_LINEAR_ADDR.0),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn width() {
+        assert_eq!(Width::of(x86::vmx::vmcs::guest::CS_SELECTOR), Width::Bits16);
+        assert_eq!(Width::of(x86::vmx::vmcs::control::EPTP_FULL), Width::Bits64);
+        assert_eq!(Width::of(x86::vmx::vmcs::ro::EXIT_REASON), Width::Bits32);
+        assert_eq!(Width::of(x86::vmx::vmcs::guest::RIP), Width::Natural);
+    }
+}
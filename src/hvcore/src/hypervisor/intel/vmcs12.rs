@@ -0,0 +1,262 @@
+//! This module implements the VMCS the guest manages for its own guest with
+//! nested virtualization. See `nested`.
+//!
+//! The terms follow the usual ones for nested virtualization: L0 is this
+//! hypervisor, L1 is the guest hypervisor running on it, and L2 is the guest
+//! of L1. VMCS01 is the VMCS L0 runs L1 with, VMCS12 is the VMCS L1 manages for
+//! L2, and VMCS02 is the VMCS L0 actually runs L2 with, merged from the other
+//! two.
+//!
+//! The processor does not access VMCS12. L0 caches its fields in [`Vmcs12`]
+//! while it is current, and stores them into the VMCS region of L1 when it is
+//! cleared or another one is made current. The layout of the region is
+//! specific to this implementation, as it is for the processor:
+//!
+//! - 0: the VMCS revision identifier
+//! - 4: the VMX-abort indicator
+//! - 8: the launch state, 1 if launched
+//! - 16: the value of each field in the order of `vmcs::FIELDS`, as 64 bits
+// See: 25.2 FORMAT OF THE VMCS REGION
+
+use bit_field::BitField;
+
+use super::vmcs::{Field, Width, FIELDS};
+
+/// The size of the VMCS region reported to the guest.
+pub(crate) const REGION_SIZE: usize = 0x1000;
+
+/// The offset of the launch state in the VMCS region.
+const LAUNCH_STATE_OFFSET: usize = 8;
+
+/// The offset of the field values in the VMCS region.
+const VALUES_OFFSET: usize = 16;
+
+const _: () = assert!(VALUES_OFFSET + FIELDS.len() * 8 <= REGION_SIZE);
+
+/// The errors VMREAD and VMWRITE report for a field, as VM-instruction error
+/// numbers.
+// See: 31.4 VM INSTRUCTION ERROR NUMBERS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FieldError {
+    /// VMREAD/VMWRITE from/to unsupported VMCS component.
+    Unsupported = 12,
+    /// VMWRITE to read-only VMCS component.
+    ReadOnly = 13,
+}
+
+/// The fields of a VMCS12 cached while it is current.
+#[derive(Clone)]
+pub(crate) struct Vmcs12 {
+    values: [u64; FIELDS.len()],
+    launched: bool,
+}
+
+impl Vmcs12 {
+    /// Creates a VMCS12 with all fields cleared and in the clear launch state.
+    pub(crate) fn new() -> Self {
+        Self {
+            values: [0; FIELDS.len()],
+            launched: false,
+        }
+    }
+
+    /// Creates a VMCS12 from its VMCS region. The values are truncated to the
+    /// widths of the fields, as the guest may have written anything there.
+    pub(crate) fn from_region(region: &[u8; REGION_SIZE]) -> Self {
+        let mut vmcs12 = Self::new();
+        for (index, &(_, encoding)) in FIELDS.iter().enumerate() {
+            let offset = VALUES_OFFSET + index * 8;
+            let value = u64::from_le_bytes(region[offset..offset + 8].try_into().unwrap());
+            vmcs12.values[index] = truncate(encoding, value);
+        }
+        vmcs12.launched = region[LAUNCH_STATE_OFFSET] != 0;
+        vmcs12
+    }
+
+    /// Stores the launch state and the fields into `region`. The revision
+    /// identifier and the VMX-abort indicator are left as is.
+    pub(crate) fn to_region(&self, region: &mut [u8; REGION_SIZE]) {
+        region[LAUNCH_STATE_OFFSET..VALUES_OFFSET].fill(0);
+        region[LAUNCH_STATE_OFFSET] = u8::from(self.launched);
+        for (index, value) in self.values.iter().enumerate() {
+            let offset = VALUES_OFFSET + index * 8;
+            region[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// Reads the field `encoding` as VMREAD does. The high encoding of a
+    /// 64-bit field reads the higher 32 bits.
+    pub(crate) fn read(&self, encoding: u64) -> Result<u64, FieldError> {
+        let (index, high) = index_of(encoding).ok_or(FieldError::Unsupported)?;
+        let value = self.values[index];
+        Ok(if high { value >> 32 } else { value })
+    }
+
+    /// Writes `value` to the field `encoding` as VMWRITE does. The high
+    /// encoding of a 64-bit field writes the higher 32 bits. The read-only
+    /// fields are written only if `write_read_only`, which the guest is told by
+    /// IA32_VMX_MISC[29].
+    // See: A.6 MISCELLANEOUS DATA
+    pub(crate) fn write(
+        &mut self,
+        encoding: u64,
+        value: u64,
+        write_read_only: bool,
+    ) -> Result<(), FieldError> {
+        let (index, high) = index_of(encoding).ok_or(FieldError::Unsupported)?;
+        if is_read_only(FIELDS[index].1) && !write_read_only {
+            return Err(FieldError::ReadOnly);
+        }
+        let full = FIELDS[index].1;
+        self.values[index] = if high {
+            (self.values[index] & 0xffff_ffff) | (value << 32)
+        } else {
+            truncate(full, value)
+        };
+        Ok(())
+    }
+
+    /// Returns the value of `field`.
+    pub(crate) fn get(&self, field: impl Field) -> u64 {
+        self.read(u64::from(field.encoding())).unwrap()
+    }
+
+    /// Sets `value` to `field`, including a read-only one.
+    pub(crate) fn set(&mut self, field: impl Field, value: u64) {
+        self.write(u64::from(field.encoding()), value, true)
+            .unwrap();
+    }
+
+    /// Returns whether the VMCS12 is in the launched state.
+    pub(crate) fn is_launched(&self) -> bool {
+        self.launched
+    }
+
+    /// Updates the launch state.
+    pub(crate) fn set_launched(&mut self, launched: bool) {
+        self.launched = launched;
+    }
+}
+
+/// Returns the highest index of the field encodings, in bits 9:1 of
+/// IA32_VMX_VMCS_ENUM.
+// See: A.9 VMCS ENUMERATION
+pub(crate) fn max_index() -> u64 {
+    FIELDS
+        .iter()
+        .map(|&(_, encoding)| u64::from(encoding.get_bits(1..=9)))
+        .max()
+        .unwrap()
+}
+
+/// Returns whether `encoding` is of a VM-exit information field, which is
+/// read-only.
+// See: Table 25-21. Structure of VMCS Component Encoding
+pub(crate) fn is_read_only(encoding: u32) -> bool {
+    encoding.get_bits(10..=11) == 1
+}
+
+/// Returns whether `encoding` is of a guest-state field.
+pub(crate) fn is_guest_state(encoding: u32) -> bool {
+    encoding.get_bits(10..=11) == 2
+}
+
+/// Returns the index of `encoding` in `vmcs::FIELDS`, and whether it is the
+/// high encoding of a 64-bit field. Bits 63:15 must be clear.
+// See: VMREAD—Read Field from Virtual-Machine Control Structure
+fn index_of(encoding: u64) -> Option<(usize, bool)> {
+    let encoding = u32::try_from(encoding).ok().filter(|e| e >> 15 == 0)?;
+    let high = Width::of(encoding) == Width::Bits64 && encoding.get_bit(0);
+    let full = if high { encoding & !1 } else { encoding };
+    FIELDS
+        .iter()
+        .position(|&(_, e)| e == full)
+        .map(|index| (index, high))
+}
+
+/// Returns `value` truncated to the width of the field `encoding`.
+fn truncate(encoding: u32, value: u64) -> u64 {
+    match Width::of(encoding) {
+        Width::Bits16 => value & 0xffff,
+        Width::Bits32 => value & 0xffff_ffff,
+        Width::Bits64 | Width::Natural => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x86::vmx::vmcs;
+
+    use super::{
+        super::vmcs::{guest, ro},
+        *,
+    };
+
+    #[test]
+    fn values_are_truncated() {
+        let mut vmcs12 = Vmcs12::new();
+        vmcs12
+            .write(vmcs::guest::CS_SELECTOR.into(), 0x1_0008, false)
+            .unwrap();
+        vmcs12
+            .write(vmcs::guest::CS_LIMIT.into(), u64::MAX, false)
+            .unwrap();
+        assert_eq!(vmcs12.read(vmcs::guest::CS_SELECTOR.into()), Ok(0x8));
+        assert_eq!(vmcs12.read(vmcs::guest::CS_LIMIT.into()), Ok(0xffff_ffff));
+    }
+
+    #[test]
+    fn high_encoding_accesses_upper_half() {
+        let full = u64::from(vmcs::control::EPTP_FULL);
+        let mut vmcs12 = Vmcs12::new();
+        vmcs12.write(full, 0x1234_5678_9abc_d000, false).unwrap();
+        assert_eq!(vmcs12.read(full + 1), Ok(0x1234_5678));
+
+        vmcs12
+            .write(full + 1, 0xffff_ffff_0000_0001, false)
+            .unwrap();
+        assert_eq!(vmcs12.read(full), Ok(0x0000_0001_9abc_d000));
+    }
+
+    #[test]
+    fn read_only_fields() {
+        let exit_reason = u64::from(vmcs::ro::EXIT_REASON);
+        let mut vmcs12 = Vmcs12::new();
+        assert_eq!(
+            vmcs12.write(exit_reason, 48, false),
+            Err(FieldError::ReadOnly)
+        );
+        vmcs12.write(exit_reason, 48, true).unwrap();
+        assert_eq!(vmcs12.read(exit_reason), Ok(48));
+        assert!(!is_read_only(vmcs::guest::RIP));
+        assert!(is_guest_state(vmcs::guest::RIP));
+    }
+
+    #[test]
+    fn unsupported_fields() {
+        let vmcs12 = Vmcs12::new();
+        // Bits 63:15 must be clear, and natural-width fields have no high
+        // encodings.
+        let rip = u64::from(vmcs::guest::RIP);
+        assert_eq!(vmcs12.read(rip | 1 << 32), Err(FieldError::Unsupported));
+        assert_eq!(vmcs12.read(rip + 1), Err(FieldError::Unsupported));
+        assert_eq!(vmcs12.read(0x7fff), Err(FieldError::Unsupported));
+    }
+
+    #[test]
+    fn region_round_trip() {
+        let mut vmcs12 = Vmcs12::new();
+        vmcs12.set(guest::RIP, 0xffff_f800_0000_1000);
+        vmcs12.set(ro::EXIT_REASON, 10);
+        vmcs12.set_launched(true);
+
+        let mut region = [0xcc; REGION_SIZE];
+        vmcs12.to_region(&mut region);
+        assert_eq!(region[..8], [0xcc; 8]);
+
+        let loaded = Vmcs12::from_region(&region);
+        assert!(loaded.is_launched());
+        assert_eq!(loaded.values, vmcs12.values);
+        assert_eq!(max_index(), 0x19);
+    }
+}
@@ -1,40 +1,119 @@
 //! This module implements enablement of Intel VMX.
 
-use alloc::boxed::Box;
+use bit_field::BitField;
+use x86::{controlregs::Cr4, cpuid::cpuid};
 
 use crate::hypervisor::{
     host::Extension,
-    intel::guest::{get_adjusted_cr0, get_adjusted_cr4},
+    intel::{
+        apicv,
+        guest::{get_adjusted_cr0, get_adjusted_cr4},
+    },
     platform_ops,
-    support::zeroed_box,
+    support::PageBox,
     x86_instructions::{cr0, cr0_write, cr4, cr4_write, rdmsr, wrmsr},
+    HvError, VirtError,
 };
 
-#[derive(Default)]
 pub(crate) struct Vmx {
     vmxon_region: Vmxon,
 }
 
 impl Extension for Vmx {
-    fn enable(&mut self) {
+    fn new() -> Result<Self, HvError> {
+        Ok(Self {
+            vmxon_region: Vmxon::new()?,
+        })
+    }
+
+    fn check_support() -> Result<(), VirtError> {
+        // See: 23.6 DISCOVERING SUPPORT FOR VMX
+        if !cpuid!(0x1).ecx.get_bit(5) {
+            return Err(VirtError::VmxUnsupported);
+        }
+
+        // VMXON fails if the lock bit is set without the VMXON-outside-SMX bit.
+        // Software cannot change the MSR until reset once it is locked.
+        // See: 23.7 ENABLING AND ENTERING VMX OPERATION
+        let feature_control = rdmsr(x86::msr::IA32_FEATURE_CONTROL);
+        if feature_control.get_bit(IA32_FEATURE_CONTROL_LOCK_BIT)
+            && !feature_control.get_bit(IA32_FEATURE_CONTROL_ENABLE_VMX_OUTSIDE_SMX_BIT)
+        {
+            return Err(VirtError::VmxDisabledByBios);
+        }
+
+        // The guest runs with EPT and as an unrestricted guest, which require
+        // the secondary processor-based controls. The allowed 1-settings are
+        // reported in the upper 32 bits of the capability MSRs.
+        // See: A.3.2 Primary Processor-Based VM-Execution Controls
+        // See: A.3.3 Secondary Processor-Based VM-Execution Controls
+        const ACTIVATE_SECONDARY_CONTROLS_BIT: usize = 31 + 32;
+        const ENABLE_EPT_BIT: usize = 1 + 32;
+        const UNRESTRICTED_GUEST_BIT: usize = 7 + 32;
+        if !rdmsr(x86::msr::IA32_VMX_PROCBASED_CTLS).get_bit(ACTIVATE_SECONDARY_CONTROLS_BIT) {
+            return Err(VirtError::MissingFeatures);
+        }
+        let secondary = rdmsr(x86::msr::IA32_VMX_PROCBASED_CTLS2);
+        if !secondary.get_bit(ENABLE_EPT_BIT) || !secondary.get_bit(UNRESTRICTED_GUEST_BIT) {
+            return Err(VirtError::MissingFeatures);
+        }
+
+        // IA32_EFER is switched on VM-entry and VM-exit, so that the guest can
+        // leave long mode.
+        // See: A.4 VM-EXIT CONTROLS
+        // See: A.5 VM-ENTRY CONTROLS
+        const SAVE_IA32_EFER_BIT: usize = 20 + 32;
+        const LOAD_IA32_EFER_EXIT_BIT: usize = 21 + 32;
+        const LOAD_IA32_EFER_ENTRY_BIT: usize = 15 + 32;
+        let exit = rdmsr(x86::msr::IA32_VMX_EXIT_CTLS);
+        if !exit.get_bit(SAVE_IA32_EFER_BIT)
+            || !exit.get_bit(LOAD_IA32_EFER_EXIT_BIT)
+            || !rdmsr(x86::msr::IA32_VMX_ENTRY_CTLS).get_bit(LOAD_IA32_EFER_ENTRY_BIT)
+        {
+            return Err(VirtError::MissingFeatures);
+        }
+        apicv::report_support();
+        Ok(())
+    }
+
+    fn enable(&mut self) -> Result<(), HvError> {
         // The current CR0, CR4 and IA32_FEATURE_CONTROL MSR may not satisfy the
         // requirements for enabling VMX. Update them as required,
-        cr0_write(get_adjusted_cr0(cr0()));
-        cr4_write(get_adjusted_cr4(cr4()));
+        let (original_cr0, original_cr4) = (cr0(), cr4());
+        cr0_write(get_adjusted_cr0(original_cr0));
+        cr4_write(get_adjusted_cr4(original_cr4));
         Self::update_feature_control_msr();
 
         // Then, execute the VMXON instruction. Successful execution of the
         // instruction puts the processor into the operation mode called "VMX
-        // root operation" allowing the use of the other VMX instructions.
-        vmxon(&mut self.vmxon_region);
+        // root operation" allowing the use of the other VMX instructions. If it
+        // fails, put CR0 and CR4 back for the guest to resume without VMX.
+        //
+        // SMIs keep the default treatment in VMX operation, as the dual-monitor
+        // treatment is activated only by VMCALL with IA32_SMM_MONITOR_CTL set
+        // up, which is never done. See `smm`.
+        // See: 32.14 DEFAULT TREATMENT OF SMIS AND SMM WITH VMX OPERATION AND SMX OPERATION
+        vmxon(&mut self.vmxon_region).inspect_err(|_| {
+            cr0_write(original_cr0);
+            cr4_write(original_cr4);
+        })
+    }
+
+    fn disable(&mut self) {
+        // Leave VMX operation, then clear CR4.VMXE set in `enable`. Other bits
+        // updated in `enable` are left as-is, as the guest has been running
+        // with them.
+        unsafe { x86::bits64::vmx::vmxoff().unwrap() };
+        cr4_write(cr4() & !Cr4::CR4_ENABLE_VMX);
     }
 }
 
 impl Vmx {
     /// Updates an MSR to satisfy the requirement for entering VMX operation.
     fn update_feature_control_msr() {
-        const IA32_FEATURE_CONTROL_LOCK_BIT_FLAG: u64 = 1 << 0;
-        const IA32_FEATURE_CONTROL_ENABLE_VMX_OUTSIDE_SMX_FLAG: u64 = 1 << 2;
+        const IA32_FEATURE_CONTROL_LOCK_BIT_FLAG: u64 = 1 << IA32_FEATURE_CONTROL_LOCK_BIT;
+        const IA32_FEATURE_CONTROL_ENABLE_VMX_OUTSIDE_SMX_FLAG: u64 =
+            1 << IA32_FEATURE_CONTROL_ENABLE_VMX_OUTSIDE_SMX_BIT;
 
         // If the lock bit is cleared, set it along with the VMXON-outside-SMX
         // operation bit. Without those two bits, the VMXON instruction fails. They
@@ -52,18 +131,21 @@ impl Vmx {
     }
 }
 
+const IA32_FEATURE_CONTROL_LOCK_BIT: usize = 0;
+const IA32_FEATURE_CONTROL_ENABLE_VMX_OUTSIDE_SMX_BIT: usize = 2;
+
 /// Logical representation of a VMXON region.
 #[derive(derive_deref::Deref, derive_deref::DerefMut)]
 struct Vmxon {
-    ptr: Box<VmxonRaw>,
+    ptr: PageBox<VmxonRaw>,
 }
 
-impl Default for Vmxon {
-    fn default() -> Self {
+impl Vmxon {
+    fn new() -> Result<Self, HvError> {
         // The VMXON instruction requires 4KB of a region called "VMXON region".
         // This is a per-logical core data structure and only used for the VMXON
         // instruction.
-        let mut vmxon = zeroed_box::<VmxonRaw>();
+        let mut vmxon = PageBox::<VmxonRaw>::try_new()?;
 
         // "Before executing VMXON, software should write the VMCS revision identifier
         //  (see Section 25.2) to the VMXON region."
@@ -74,7 +156,7 @@ impl Default for Vmxon {
         // See: 25.2 FORMAT OF THE VMCS REGION"
         vmxon.revision_id = rdmsr(x86::msr::IA32_VMX_BASIC) as _;
 
-        Self { ptr: vmxon }
+        Ok(Self { ptr: vmxon })
     }
 }
 
@@ -91,8 +173,8 @@ struct VmxonRaw {
 }
 
 /// The wrapper of the VMXON instruction.
-fn vmxon(vmxon_region: &mut VmxonRaw) {
+fn vmxon(vmxon_region: &mut VmxonRaw) -> Result<(), HvError> {
     let va = vmxon_region as *const _;
     let pa = platform_ops::get().pa(va as *const _);
-    unsafe { x86::bits64::vmx::vmxon(pa).unwrap() };
+    unsafe { x86::bits64::vmx::vmxon(pa) }.map_err(|_| HvError::InstructionFailed("VMXON"))
 }
@@ -0,0 +1,63 @@
+//! This module implements allocation of virtual-processor identifiers (VPIDs)
+//! and the wrapper of the INVVPID instruction.
+//!
+//! Without VPIDs, every VM-entry and VM-exit invalidates all translations of
+//! linear addresses, so the guest refills its TLB after every VM-exit. With a
+//! VPID, the translations of the guest are tagged with it and survive VM
+//! transitions. The host, which runs with the VPID 0000H, is responsible for
+//! invalidating them when it changes what they derive from, for example, on
+//! emulation of MOV to CR3. See `tlb`.
+//! See: 29.1 VIRTUAL PROCESSOR IDENTIFIERS (VPIDS)
+
+use core::arch::asm;
+
+use bit_field::BitField;
+
+use crate::hypervisor::{intel::vmcs, x86_instructions::rdmsr};
+
+/// Returns the VPID of the processor `id`, or `None` if VPIDs are not supported
+/// with the types of INVVPID the host uses.
+pub(crate) fn allocate(id: usize) -> Option<u16> {
+    // The higher 32bits of the capability MSR indicate the controls that can be
+    // 1. See `VmxGuest::adjust_vmx_control`.
+    let allowed1 = rdmsr(x86::msr::IA32_VMX_PROCBASED_CTLS2) >> 32;
+    if allowed1 & u64::from(vmcs::control::SecondaryControls::ENABLE_VPID.bits()) == 0 {
+        return None;
+    }
+
+    // See: A.10 VPID AND EPT CAPABILITIES
+    let capabilities = rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP);
+    if !capabilities.get_bit(32) || !capabilities.get_bit(41) {
+        return None;
+    }
+
+    // The VPID 0000H is for the host.
+    u16::try_from(id + 1).ok()
+}
+
+/// The types of the INVVPID instruction.
+///
+/// See: Table 31-2. INVVPID Descriptor
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum InvvpidType {
+    SingleContext = 1,
+}
+
+/// The wrapper of the INVVPID instruction.
+///
+/// See: INVVPID - Invalidate Translations Based on VPID
+pub(crate) fn invvpid(invalidation: InvvpidType, vpid: u16) {
+    let descriptor = [u64::from(vpid), 0u64];
+    let flags: u64;
+    unsafe {
+        asm!(
+            "invvpid {}, [{}]",
+            "pushfq",
+            "pop {}",
+            in(reg) invalidation as u64,
+            in(reg) &descriptor,
+            lateout(reg) flags,
+        );
+    };
+    assert!(flags & 0b100_0001 == 0, "INVVPID failed: {invalidation:?}");
+}
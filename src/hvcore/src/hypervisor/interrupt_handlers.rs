@@ -1,11 +1,20 @@
 //! This module implements initialization of the host IDT and host interrupt handlers.
 
-use core::arch::global_asm;
+use core::{arch::global_asm, sync::atomic::Ordering};
 
-use alloc::boxed::Box;
-use x86::{bits64::rflags::RFlags, dtables::DescriptorTablePointer, segmentation::SegmentSelector};
+use alloc::{boxed::Box, format, string::String};
+use bit_field::BitField;
+use x86::{
+    bits64::{paging::BASE_PAGE_SIZE, rflags::RFlags},
+    dtables::DescriptorTablePointer,
+    segmentation::SegmentSelector,
+};
 
-use crate::hypervisor::x86_instructions::cr2;
+use crate::hypervisor::{
+    interrupt_virtualization::write_eois,
+    machine_check, percpu, serial_logger, switch_stack,
+    x86_instructions::{cr0, cr2, cr3, cr4},
+};
 
 use super::support::zeroed_box;
 
@@ -20,9 +29,19 @@ impl InterruptDescriptorTable {
         // Build the IDT. Each interrupt handler (ie. asm_interrupt_handlerN) is
         // 16 byte long and can be located from asm_interrupt_handler0.
         let mut idt = zeroed_box::<InterruptDescriptorTableRaw>();
+        //
+        // NMIs, #DF and #MC are delivered on the dedicated stacks, as they may
+        // interrupt any code, including code overflowing its stack. The TSS
+        // must have the stacks. See `GdtTssBuilder::host_exception_stacks`.
         for i in 0..idt.0.len() {
-            let handler = asm_interrupt_handler0 as usize + 0x10 * i;
-            idt.0[i] = InterruptDescriptorTableEntry::new(handler, cs);
+            let handler = asm_interrupt_handler0 as *const () as usize + 0x10 * i;
+            let ist = match i as u64 {
+                NMI_VECTOR => NMI_IST_INDEX,
+                DF_VECTOR => DF_IST_INDEX,
+                MC_VECTOR => MC_IST_INDEX,
+                _ => 0,
+            };
+            idt.0[i] = InterruptDescriptorTableEntry::new(handler, cs, ist);
         }
 
         Self { ptr: idt }
@@ -47,7 +66,7 @@ const _: () = assert!(core::mem::size_of::<InterruptDescriptorTableRaw>() == 409
 pub struct InterruptDescriptorTableEntry {
     offset_low: u16,
     selector: u16,
-    reserved_1: u8,
+    ist: u8,
     gate_type: u8,
     offset_high: u16,
     offset_upper: u32,
@@ -56,13 +75,14 @@ pub struct InterruptDescriptorTableEntry {
 const _: () = assert!(core::mem::size_of::<InterruptDescriptorTableEntry>() == 16);
 
 impl InterruptDescriptorTableEntry {
-    fn new(handler: usize, cs: SegmentSelector) -> Self {
+    pub(crate) fn new(handler: usize, cs: SegmentSelector, ist: u8) -> Self {
         // P=1, DPL=00b, S=0, type=1110b => type_attr=1000_1110b => 0x8E
         const INTERRUPT_GATE: u8 = 0x8E;
+        // See: Figure 6-8. 64-Bit IDT Gate Descriptors
         Self {
             offset_low: handler as _,
             selector: cs.bits(),
-            reserved_1: 0,
+            ist,
             gate_type: INTERRUPT_GATE,
             offset_high: (handler >> 16) as _,
             offset_upper: (handler >> 32) as _,
@@ -99,19 +119,216 @@ struct HostExceptionStack {
     ss: u64,               // Hardware saved
 }
 
+/// Returns and clears whether an NMI occurred in the host on the current
+/// processor.
+pub(crate) fn take_host_nmi() -> bool {
+    percpu::current().host_nmi.swap(false, Ordering::Relaxed)
+}
+
+/// The index of the IST entry in the TSS for NMIs.
+pub(crate) const NMI_IST_INDEX: u8 = 1;
+
+/// The index of the IST entry in the TSS for #DF.
+pub(crate) const DF_IST_INDEX: u8 = 2;
+
+/// The index of the IST entry in the TSS for #MC.
+pub(crate) const MC_IST_INDEX: u8 = 3;
+
 /// The host interrupt handler.
+///
+/// NMIs are recorded for the guest, or captured for the watchdog, and the
+/// interrupted code resumes. So are external interrupts the host takes for the
+/// guest with AVIC. Any other interrupt or exception is fatal: the context is
+/// dumped through the logger, and the current processor is halted.
 #[no_mangle]
-extern "C" fn handle_host_exception(stack: *mut HostExceptionStack) {
+extern "win64" fn handle_host_exception(stack: *mut HostExceptionStack) {
     assert!(!stack.is_null());
     let stack = unsafe { &*stack };
-    panic!(
-        "Exception {} occurred in host: {stack:#x?}, cr2: {:#x?}",
-        stack.exception_number,
+    // The host runs with the GS base identifying the per-processor block after
+    // the first VM-exit. Before that, the block is looked up with the APIC ID.
+    let percpu = percpu::current();
+    if stack.exception_number == NMI_VECTOR {
+        // The NMI is from the watchdog of another processor, which waits for
+        // where the host is.
+        if percpu.heartbeat.take_dump_request() {
+            percpu.heartbeat.capture(stack.rip, stack.rsp);
+            return;
+        }
+
+        // Otherwise, the NMI is for the guest, which is interrupted by the host.
+        // Deliver it to the guest on the next VM-entry.
+        percpu.host_nmi.store(true, Ordering::Relaxed);
+        return;
+    }
+
+    // An external interrupt taken for the guest with AVIC, which is requested
+    // in the APIC backing page, or with the emulation of the APIC timer, which
+    // injects it. Complete it on the local APIC unless the guest does. See
+    // `amd::avic` and `amd::apic_timer`.
+    if let Ok(vector @ 32..) = u8::try_from(stack.exception_number) {
+        if let Some(complete) = percpu.host_interrupts.record(vector) {
+            if complete {
+                write_eois(1);
+            }
+            return;
+        }
+    }
+
+    // An exception while dumping the context of an earlier one. Do not try
+    // again, which would likely cause the same exception.
+    if percpu.in_exception.swap(true, Ordering::Relaxed) {
+        panic!(
+            "Nested exception {} occurred in host",
+            stack.exception_number
+        );
+    }
+
+    // The exception may have occurred while logging. Let us log.
+    serial_logger::release_if_owned();
+
+    // Overflowing the host stack causes #PF on the guard page, which is then
+    // #DF, as the #PF cannot be pushed onto the stack. RIP of #DF is undefined
+    // but usually points to the offending instruction.
+    if matches!(stack.exception_number, PF_VECTOR | DF_VECTOR) && switch_stack::is_guard_page(cr2())
+    {
+        log::error!(
+            "Host stack overflow on CPU {} at RIP {:#x}",
+            percpu.id,
+            stack.rip
+        );
+    }
+    log::error!(
+        "{} occurred in host at {:#x}",
+        exception_name(stack.exception_number),
+        stack.rip
+    );
+    if let Some(error_code) = describe_error_code(stack.exception_number, stack.error_code) {
+        log::error!("Error code {:#x}: {error_code}", stack.error_code);
+    }
+    if stack.exception_number == MC_VECTOR {
+        machine_check::log_errors();
+    }
+    log::error!(
+        "CR0: {:#x?}, CR2: {:#x?}, CR3: {:#x?}, CR4: {:#x?}",
+        cr0(),
         cr2(),
+        cr3(),
+        cr4()
     );
+    log::error!("{stack:#x?}");
+    if stack.exception_number == UD_VECTOR {
+        log::error!("Bytes at RIP: {:02x?}", instruction_bytes(stack.rip));
+    }
+    panic!("Exception {} occurred in host", stack.exception_number);
+}
+
+/// Returns the name of the exception `vector`.
+// See: Table 6-1. Exceptions and Interrupts
+fn exception_name(vector: u64) -> &'static str {
+    match vector {
+        0 => "#DE (Divide Error)",
+        1 => "#DB (Debug)",
+        NMI_VECTOR => "NMI (Non-maskable Interrupt)",
+        3 => "#BP (Breakpoint)",
+        4 => "#OF (Overflow)",
+        5 => "#BR (BOUND Range Exceeded)",
+        UD_VECTOR => "#UD (Invalid Opcode)",
+        7 => "#NM (Device Not Available)",
+        DF_VECTOR => "#DF (Double Fault)",
+        10 => "#TS (Invalid TSS)",
+        11 => "#NP (Segment Not Present)",
+        12 => "#SS (Stack-Segment Fault)",
+        GP_VECTOR => "#GP (General Protection)",
+        PF_VECTOR => "#PF (Page Fault)",
+        16 => "#MF (x87 FPU Floating-Point Error)",
+        17 => "#AC (Alignment Check)",
+        MC_VECTOR => "#MC (Machine Check)",
+        19 => "#XM (SIMD Floating-Point Exception)",
+        20 => "#VE (Virtualization Exception)",
+        21 => "#CP (Control Protection Exception)",
+        9 | 15 | 22..=31 => "Reserved exception",
+        _ => "External interrupt",
+    }
 }
 
+/// Returns the human readable description of `error_code` of the exception
+/// `vector`, if it has an error code with a known format.
+fn describe_error_code(vector: u64, error_code: u64) -> Option<String> {
+    let flag = |bit: u32, set: &'static str, clear: &'static str| {
+        if error_code.get_bit(bit as usize) {
+            set
+        } else {
+            clear
+        }
+    };
+    match vector {
+        // See: Figure 6-11. Page-Fault Error Code
+        PF_VECTOR => Some(format!(
+            "{}, {}, {} mode{}{}{}",
+            flag(0, "protection violation", "not present"),
+            flag(1, "write", "read"),
+            flag(2, "user", "supervisor"),
+            flag(3, ", reserved bit set", ""),
+            flag(4, ", instruction fetch", ""),
+            flag(5, ", protection key", ""),
+        )),
+        // See: 6.13 ERROR CODE
+        10..=13 => {
+            if error_code == 0 {
+                return Some("no selector".into());
+            }
+            let table = if error_code.get_bit(1) {
+                "IDT"
+            } else {
+                flag(2, "LDT", "GDT")
+            };
+            Some(format!(
+                "{table} index {:#x}{}",
+                error_code.get_bits(3..16),
+                flag(0, ", external event", "")
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the bytes at `rip` up to 16 bytes, without crossing the page.
+fn instruction_bytes(rip: u64) -> &'static [u8] {
+    let len = (BASE_PAGE_SIZE as u64 - (rip % BASE_PAGE_SIZE as u64)).min(16);
+    unsafe { core::slice::from_raw_parts(rip as *const u8, len as usize) }
+}
+
+const NMI_VECTOR: u64 = 2;
+const UD_VECTOR: u64 = 6;
+const DF_VECTOR: u64 = 8;
+const GP_VECTOR: u64 = 13;
+const PF_VECTOR: u64 = 14;
+const MC_VECTOR: u64 = 18;
+
 global_asm!(include_str!("interrupt_handlers.S"));
-extern "C" {
+extern "win64" {
     fn asm_interrupt_handler0();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(
+            describe_error_code(PF_VECTOR, 0b1_0101).unwrap(),
+            "protection violation, read, user mode, instruction fetch"
+        );
+        assert_eq!(describe_error_code(GP_VECTOR, 0).unwrap(), "no selector");
+        assert_eq!(
+            describe_error_code(GP_VECTOR, (0x5 << 3) | 0b100).unwrap(),
+            "LDT index 0x5"
+        );
+        assert_eq!(
+            describe_error_code(11, (0x21 << 3) | 0b011).unwrap(),
+            "IDT index 0x21, external event"
+        );
+        assert!(describe_error_code(UD_VECTOR, 0).is_none());
+    }
+}
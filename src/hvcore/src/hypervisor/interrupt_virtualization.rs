@@ -0,0 +1,615 @@
+//! This module implements the architecture independent parts of delivering
+//! external interrupts to the guest through a virtual APIC (APICv on Intel
+//! processors and AVIC on AMD processors), instead of letting the guest take
+//! them from the local APIC it owns.
+//!
+//! Enabled with `SharedHostData::interrupt_virtualization` on a processor that
+//! supports it while the guest uses the local APIC in the x2APIC mode, external
+//! interrupts cause VM-exit and are acknowledged by the host, which requests
+//! them in the virtual APIC. The processor delivers them once the guest can
+//! receive them, and the guest completes them with EOI to the virtual APIC,
+//! without VM-exit. So does it for the interrupts queued with
+//! `Vcpu::queue_interrupt`, without interrupt-window exiting, and fixed IPIs
+//! sent with `ipi::send_ipi` are posted to the target and delivered without
+//! VM-exit even while it runs the guest. The notification of posted interrupts
+//! is an interrupt with the notification vector on Intel processors, and the
+//! AVIC doorbell on AMD processors.
+//!
+//! The local APIC still serves the rest, such as the timer and the IPIs the
+//! guest sends: accesses to the registers other than TPR, PPR, ISR, IRR, EOI
+//! and SELF IPI cause VM-exit and reach the local APIC. EOIs of level-triggered
+//! interrupts cause VM-exit, so that they are completed on the local APIC only
+//! once the guest serviced the device, as the I/O APIC delivers them again
+//! otherwise. Edge-triggered interrupts are completed on the local APIC as soon
+//! as they are acknowledged.
+//!
+//! This trades a VM-exit on every external interrupt, which the guest otherwise
+//! takes directly, for no VM-exit to deliver each interrupt of the host, which
+//! suits a host that interrupts the guest often. The default delivery is kept
+//! with the guest in the xAPIC mode or leaving the x2APIC mode, on a processor
+//! without support, and with `SharedHostData::apic_virt` or handlers for the
+//! virtualized x2APIC MSRs in `SharedHostData::msr_intercepts`, which need the
+//! guest to access the local APIC. On AMD processors, the host IDT is also
+//! required, as the host takes external interrupts through it.
+//!
+//! See: 30.1 VIRTUAL APIC STATE
+//! See: 30.6 POSTED-INTERRUPT PROCESSING
+//! See: 15.29 Advanced Virtual Interrupt Controller
+
+use core::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use bit_field::BitField;
+use spin::Mutex;
+
+use crate::hypervisor::x86_instructions::{rdmsr, wrmsr};
+
+/// How to deliver external interrupts to the guest. See the module
+/// documentation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InterruptVirtualization {
+    /// Lets the guest take external interrupts from the local APIC.
+    #[default]
+    Disabled,
+
+    /// Delivers external interrupts through the virtual APIC where possible.
+    /// `notification_vector` notifies a processor running the guest of the
+    /// interrupts posted to it, on Intel processors. It must be 32 or greater
+    /// and not used by the devices and the guest, as an interrupt with it is
+    /// taken as the notification and not delivered to the guest. A
+    /// notification sent while the target is being devirtualized may still
+    /// reach the guest once. AMD processors use the AVIC doorbell instead and
+    /// ignore the vector.
+    Enabled { notification_vector: u8 },
+}
+
+impl InterruptVirtualization {
+    /// Returns the notification vector if enabled.
+    pub(crate) fn notification_vector(self) -> Option<u8> {
+        match self {
+            Self::Disabled => None,
+            Self::Enabled {
+                notification_vector,
+            } => Some(notification_vector),
+        }
+    }
+}
+
+/// A set of interrupt vectors in the layout of the 256-bit APIC registers,
+/// such as IRR.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct VectorSet([u64; 4]);
+
+impl VectorSet {
+    /// Returns the bits of the set, the lowest vectors first.
+    #[cfg(feature = "intel")]
+    pub(crate) fn words(self) -> [u64; 4] {
+        self.0
+    }
+
+    pub(crate) fn insert(&mut self, vector: u8) {
+        let _ = self.0[usize::from(vector / 64)].set_bit(usize::from(vector % 64), true);
+    }
+
+    pub(crate) fn remove(&mut self, vector: u8) {
+        let _ = self.0[usize::from(vector / 64)].set_bit(usize::from(vector % 64), false);
+    }
+
+    pub(crate) fn contains(self, vector: u8) -> bool {
+        self.0[usize::from(vector / 64)].get_bit(usize::from(vector % 64))
+    }
+
+    /// Returns the highest vector in the set, which has the highest priority.
+    pub(crate) fn highest(self) -> Option<u8> {
+        (0..4)
+            .rev()
+            .find(|&i| self.0[i] != 0)
+            .map(|i| (i * 64 + 63 - self.0[i].leading_zeros() as usize) as u8)
+    }
+
+    /// Returns the vectors in the set, the lowest first.
+    pub(crate) fn iter(self) -> impl Iterator<Item = u8> {
+        (0..=u8::MAX).filter(move |&vector| self.contains(vector))
+    }
+}
+
+/// The level-triggered interrupts acknowledged on the local APIC whose EOIs are
+/// deferred until the guest completes them.
+///
+/// EOI to the local APIC completes the interrupt in service with the highest
+/// priority. An interrupt the guest completes while a higher one is still
+/// deferred, such as one requested but not yet delivered to the guest, is
+/// completed on the local APIC after the higher one.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct DeferredEois {
+    deferred: VectorSet,
+    completed: VectorSet,
+}
+
+impl DeferredEois {
+    /// Defers the EOI of `vector`, which was just acknowledged on the local
+    /// APIC.
+    pub(crate) fn defer(&mut self, vector: u8) {
+        self.deferred.insert(vector);
+    }
+
+    /// Returns the vectors whose EOIs are deferred, thus to cause VM-exit.
+    pub(crate) fn deferred(&self) -> VectorSet {
+        self.deferred
+    }
+
+    /// Returns the vectors whose EOIs are deferred and the guest has not
+    /// completed yet.
+    #[cfg(any(feature = "amd", test))]
+    pub(crate) fn outstanding(&self) -> VectorSet {
+        VectorSet(core::array::from_fn(|i| {
+            self.deferred.0[i] & !self.completed.0[i]
+        }))
+    }
+
+    /// Records that the guest completed `vector`, and returns the number of
+    /// EOIs to write to the local APIC now.
+    pub(crate) fn complete(&mut self, vector: u8) -> usize {
+        if !self.deferred.contains(vector) {
+            return 0;
+        }
+        self.completed.insert(vector);
+
+        let mut eois = 0;
+        while let Some(highest) = self.deferred.highest() {
+            if !self.completed.contains(highest) {
+                break;
+            }
+            self.deferred.remove(highest);
+            self.completed.remove(highest);
+            eois += 1;
+        }
+        eois
+    }
+
+    /// Forgets all deferred EOIs, and returns the number of EOIs to write to
+    /// the local APIC to complete them.
+    pub(crate) fn take_all(&mut self) -> usize {
+        let eois = self.deferred.iter().count();
+        *self = Self::default();
+        eois
+    }
+}
+
+/// The posted-interrupt descriptor of a processor, which the other processors
+/// post fixed IPIs to while the processor runs the guest with interrupt
+/// virtualization. With AVIC, the descriptor is not used, and the IPIs are
+/// requested in the APIC backing page of the processor directly.
+/// See: 30.6 POSTED-INTERRUPT PROCESSING
+#[derive(Debug, Default)]
+#[repr(C, align(64))]
+pub(crate) struct PostedInterrupts {
+    /// The posted-interrupt requests, one bit for each vector.
+    requests: [AtomicU64; 4],
+
+    /// The outstanding-notification bit (0), the notification vector (23:16)
+    /// and the notification destination (63:32).
+    control: AtomicU64,
+
+    _reserved: [u64; 3],
+
+    /// Where the processor accepts posted interrupts. Held while posting, so
+    /// that none is posted once cleared.
+    accepting: Mutex<PostTarget>,
+}
+
+/// Where interrupts posted to a processor are requested.
+#[derive(Clone, Copy, Debug, Default)]
+enum PostTarget {
+    /// Not accepting posted interrupts.
+    #[default]
+    None,
+
+    /// The posted-interrupt requests of the descriptor (Intel).
+    #[cfg(any(feature = "intel", test))]
+    Descriptor,
+
+    /// IRR of the APIC backing page (AMD), which outlives the acceptance.
+    #[cfg(any(feature = "amd", test))]
+    BackingPage(*const VirtualApicPage),
+}
+
+/// How to notify a processor running the guest of an interrupt posted to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Notification {
+    /// A fixed IPI with the notification vector (Intel).
+    #[cfg(any(feature = "intel", test))]
+    Vector(u8),
+
+    /// A write to the AVIC doorbell MSR (AMD).
+    #[cfg(any(feature = "amd", test))]
+    Doorbell,
+}
+
+impl PostedInterrupts {
+    #[cfg(any(feature = "intel", test))]
+    const OUTSTANDING_NOTIFICATION: u64 = 1 << 0;
+
+    /// Starts accepting posted interrupts in the descriptor, notified with
+    /// `notification_vector` sent to the x2APIC ID `apic_id`.
+    #[cfg(any(feature = "intel", test))]
+    pub(crate) fn enable(&self, notification_vector: u8, apic_id: u32) {
+        let mut accepting = self.accepting.lock();
+        for request in &self.requests {
+            request.store(0, Ordering::SeqCst);
+        }
+        self.control.store(
+            u64::from(notification_vector) << 16 | u64::from(apic_id) << 32,
+            Ordering::SeqCst,
+        );
+        *accepting = PostTarget::Descriptor;
+    }
+
+    /// Starts accepting posted interrupts in IRR of `backing_page`, notified
+    /// with the AVIC doorbell. `disable` must be called before `backing_page`
+    /// is freed.
+    #[cfg(any(feature = "amd", test))]
+    pub(crate) fn enable_backing_page(&self, backing_page: &VirtualApicPage) {
+        *self.accepting.lock() = PostTarget::BackingPage(backing_page);
+    }
+
+    /// Stops accepting posted interrupts, and returns those not taken yet. Ones
+    /// requested in the APIC backing page stay there.
+    pub(crate) fn disable(&self) -> VectorSet {
+        let mut accepting = self.accepting.lock();
+        let target = core::mem::take(&mut *accepting);
+        match target {
+            #[cfg(any(feature = "intel", test))]
+            PostTarget::Descriptor => self.take().unwrap_or_default(),
+            #[cfg(any(feature = "amd", test))]
+            PostTarget::BackingPage(_) => VectorSet::default(),
+            PostTarget::None => VectorSet::default(),
+        }
+    }
+
+    /// Takes the posted interrupts if a notification is outstanding, as the
+    /// processor does when it receives the notification in the guest. The
+    /// notification is not sent to a processor in the host, which takes them
+    /// before VM-entry instead.
+    #[cfg(any(feature = "intel", test))]
+    pub(crate) fn take(&self) -> Option<VectorSet> {
+        // Order the load after the heartbeat marking the processor out of the
+        // host. See `post`.
+        fence(Ordering::SeqCst);
+        let control = self
+            .control
+            .fetch_and(!Self::OUTSTANDING_NOTIFICATION, Ordering::SeqCst);
+        if control & Self::OUTSTANDING_NOTIFICATION == 0 {
+            return None;
+        }
+        Some(VectorSet(core::array::from_fn(|i| {
+            self.requests[i].swap(0, Ordering::SeqCst)
+        })))
+    }
+
+    /// Posts `vector` if the processor accepts posted interrupts, and calls
+    /// `notify` with how to notify the processor, unless a notification is
+    /// already outstanding. Returns whether posted.
+    pub(crate) fn post(&self, vector: u8, notify: impl FnOnce(Notification)) -> bool {
+        let accepting = self.accepting.lock();
+        match *accepting {
+            PostTarget::None => false,
+            #[cfg(any(feature = "intel", test))]
+            PostTarget::Descriptor => {
+                self.requests[usize::from(vector / 64)]
+                    .fetch_or(1 << (vector % 64), Ordering::SeqCst);
+                let control = self
+                    .control
+                    .fetch_or(Self::OUTSTANDING_NOTIFICATION, Ordering::SeqCst);
+                if control & Self::OUTSTANDING_NOTIFICATION == 0 {
+                    // Order the loads in `notify` after the store above. See
+                    // `take`.
+                    fence(Ordering::SeqCst);
+                    notify(Notification::Vector(control.get_bits(16..=23) as u8));
+                }
+                true
+            }
+            #[cfg(any(feature = "amd", test))]
+            PostTarget::BackingPage(backing_page) => {
+                // The processor evaluates IRR on VMRUN and on the doorbell, and
+                // tracks no outstanding notification.
+                // See: 15.29 Advanced Virtual Interrupt Controller
+                unsafe { &*backing_page }.request(vector);
+                fence(Ordering::SeqCst);
+                notify(Notification::Doorbell);
+                true
+            }
+        }
+    }
+
+    /// Returns whether the descriptor is locked for posting.
+    pub(crate) fn is_locked(&self) -> bool {
+        self.accepting.is_locked()
+    }
+}
+
+/// The external interrupts the host took through the host IDT for the guest,
+/// with AVIC or the emulation of the timer of the local APIC. See
+/// `interrupt_handlers::handle_host_exception`.
+#[derive(Debug, Default)]
+pub(crate) struct HostInterrupts {
+    accepting: AtomicBool,
+    reinjecting: AtomicBool,
+    timer_vector: AtomicU8,
+    vectors: [AtomicU64; 4],
+}
+
+impl HostInterrupts {
+    /// Sets whether the host takes external interrupts for the guest. They are
+    /// fatal in the host otherwise.
+    #[cfg(feature = "amd")]
+    pub(crate) fn set_accepting(&self, accepting: bool) {
+        self.accepting.store(accepting, Ordering::Relaxed);
+    }
+
+    /// Sets whether the interrupts taken are injected into the guest, which
+    /// completes them on the local APIC, instead of being requested in the
+    /// virtual APIC.
+    #[cfg(feature = "amd")]
+    pub(crate) fn set_reinjecting(&self, reinjecting: bool) {
+        self.reinjecting.store(reinjecting, Ordering::Relaxed);
+    }
+
+    /// Sets the vector of the timer of the local APIC the host took over, if
+    /// any, which is completed in the host and not recorded. See
+    /// `amd::apic_timer`.
+    #[cfg(feature = "amd")]
+    pub(crate) fn set_timer_vector(&self, vector: Option<u8>) {
+        self.timer_vector
+            .store(vector.unwrap_or_default(), Ordering::Relaxed);
+    }
+
+    /// Records `vector` taken in the host, if accepting. Returns whether the
+    /// host completes it on the local APIC, that is, unless the guest does,
+    /// or `None` if not recorded.
+    pub(crate) fn record(&self, vector: u8) -> Option<bool> {
+        if !self.accepting.load(Ordering::Relaxed) {
+            return None;
+        }
+        if vector == self.timer_vector.load(Ordering::Relaxed) {
+            return Some(true);
+        }
+        self.vectors[usize::from(vector / 64)].fetch_or(1 << (vector % 64), Ordering::Relaxed);
+        Some(!self.reinjecting.load(Ordering::Relaxed) && !is_level_triggered(vector))
+    }
+
+    /// Takes the interrupts recorded.
+    #[cfg(feature = "amd")]
+    pub(crate) fn take(&self) -> VectorSet {
+        VectorSet(core::array::from_fn(|i| {
+            self.vectors[i].swap(0, Ordering::Relaxed)
+        }))
+    }
+}
+
+/// The registers of a virtual APIC in the layout of the xAPIC page, which is
+/// that of the virtual-APIC page (Intel) and the APIC backing page (AMD).
+/// See: 30.1.1 Virtualized APIC Registers
+#[repr(C, align(4096))]
+pub(crate) struct VirtualApicPage([AtomicU32; 1024]);
+
+impl VirtualApicPage {
+    const TPR: usize = 0x80;
+    const ISR: usize = 0x100;
+    #[cfg(any(feature = "amd", test))]
+    const TMR: usize = 0x180;
+    const IRR: usize = 0x200;
+
+    pub(crate) fn tpr(&self) -> u32 {
+        self.register(Self::TPR).load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_tpr(&self, value: u32) {
+        self.register(Self::TPR).store(value, Ordering::Relaxed);
+    }
+
+    /// Requests `vector` in IRR.
+    pub(crate) fn request(&self, vector: u8) {
+        self.register(Self::IRR + usize::from(vector / 32) * 0x10)
+            .fetch_or(1 << (vector % 32), Ordering::SeqCst);
+    }
+
+    pub(crate) fn requested(&self) -> VectorSet {
+        self.vectors(Self::IRR)
+    }
+
+    #[cfg(any(feature = "amd", test))]
+    pub(crate) fn in_service(&self) -> VectorSet {
+        self.vectors(Self::ISR)
+    }
+
+    pub(crate) fn set_in_service(&self, vectors: VectorSet) {
+        for (i, word) in vectors.0.iter().enumerate() {
+            self.register(Self::ISR + i * 0x20)
+                .store(*word as u32, Ordering::Relaxed);
+            self.register(Self::ISR + i * 0x20 + 0x10)
+                .store((*word >> 32) as u32, Ordering::Relaxed);
+        }
+    }
+
+    /// Clears `vector` in ISR.
+    #[cfg(any(feature = "amd", test))]
+    pub(crate) fn clear_in_service(&self, vector: u8) {
+        self.register(Self::ISR + usize::from(vector / 32) * 0x10)
+            .fetch_and(!(1 << (vector % 32)), Ordering::SeqCst);
+    }
+
+    /// Sets whether `vector` is level-triggered in TMR.
+    #[cfg(any(feature = "amd", test))]
+    pub(crate) fn set_level_triggered(&self, vector: u8, level: bool) {
+        let register = self.register(Self::TMR + usize::from(vector / 32) * 0x10);
+        if level {
+            register.fetch_or(1 << (vector % 32), Ordering::SeqCst);
+        } else {
+            register.fetch_and(!(1 << (vector % 32)), Ordering::SeqCst);
+        }
+    }
+
+    /// Clears all registers, as INIT does to the local APIC.
+    pub(crate) fn reset(&self) {
+        for register in &self.0 {
+            register.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn register(&self, offset: usize) -> &AtomicU32 {
+        &self.0[offset / 4]
+    }
+
+    /// Returns one of the 256-bit registers, made of eight 32-bit registers
+    /// 16 bytes apart.
+    fn vectors(&self, offset: usize) -> VectorSet {
+        VectorSet(core::array::from_fn(|i| {
+            let low = self.register(offset + i * 0x20).load(Ordering::Relaxed);
+            let high = self
+                .register(offset + i * 0x20 + 0x10)
+                .load(Ordering::Relaxed);
+            u64::from(high) << 32 | u64::from(low)
+        }))
+    }
+}
+
+/// The x2APIC MSRs of the local APIC.
+/// See: Table 11-6. Local APIC Register Address Map Supported by x2APIC
+pub(crate) const X2APIC_TPR: u32 = 0x808;
+pub(crate) const X2APIC_PPR: u32 = 0x80a;
+pub(crate) const X2APIC_EOI: u32 = 0x80b;
+pub(crate) const X2APIC_ISR0: u32 = 0x810;
+pub(crate) const X2APIC_TMR0: u32 = 0x818;
+pub(crate) const X2APIC_IRR0: u32 = 0x820;
+pub(crate) const X2APIC_SELF_IPI: u32 = 0x83f;
+
+/// Returns one of the 256-bit registers of the local APIC, made of the eight
+/// x2APIC MSRs from `first`.
+pub(crate) fn local_apic_vectors(first: u32) -> VectorSet {
+    VectorSet(core::array::from_fn(|i| {
+        let low = rdmsr(first + 2 * i as u32) & 0xffff_ffff;
+        let high = rdmsr(first + 2 * i as u32 + 1) & 0xffff_ffff;
+        high << 32 | low
+    }))
+}
+
+/// Returns whether `vector`, accepted by the local APIC, is level-triggered.
+/// See: 11.8.4 Interrupt Acceptance for Fixed Interrupts
+pub(crate) fn is_level_triggered(vector: u8) -> bool {
+    rdmsr(X2APIC_TMR0 + u32::from(vector / 32)).get_bit(usize::from(vector % 32))
+}
+
+/// Writes `count` EOIs to the local APIC.
+pub(crate) fn write_eois(count: usize) {
+    for _ in 0..count {
+        wrmsr(X2APIC_EOI, 0);
+    }
+}
+
+/// Requests `vector` on the local APIC of the current processor, for the guest
+/// to take once devirtualized.
+pub(crate) fn self_ipi(vector: u8) {
+    wrmsr(X2APIC_SELF_IPI, u64::from(vector));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deferred_eois_complete_in_priority_order() {
+        let mut eois = DeferredEois::default();
+        eois.defer(0x41);
+        eois.defer(0x92);
+        eois.defer(0xb3);
+        assert_eq!(eois.deferred().highest(), Some(0xb3));
+
+        // Not deferred, thus completed on acknowledgement.
+        assert_eq!(eois.complete(0x30), 0);
+        // The local APIC would complete 0xb3 instead.
+        assert_eq!(eois.complete(0x92), 0);
+        assert_eq!(
+            eois.outstanding().iter().collect::<alloc::vec::Vec<_>>(),
+            [0x41, 0xb3]
+        );
+        assert_eq!(eois.complete(0xb3), 2);
+        assert_eq!(
+            eois.deferred().iter().collect::<alloc::vec::Vec<_>>(),
+            [0x41]
+        );
+        assert_eq!(eois.take_all(), 1);
+        assert_eq!(eois.deferred(), VectorSet::default());
+    }
+
+    #[test]
+    fn posted_interrupts_notify_once() {
+        let posted = PostedInterrupts::default();
+        assert!(!posted.post(0x50, |_| panic!("not accepting")));
+
+        posted.enable(0xf2, 3);
+        let mut notifications = 0;
+        assert!(posted.post(0x50, |notification| {
+            assert_eq!(notification, Notification::Vector(0xf2));
+            notifications += 1;
+        }));
+        assert!(posted.post(0xe1, |_| notifications += 1));
+        assert_eq!(notifications, 1);
+
+        let taken = posted.take().unwrap();
+        assert_eq!(taken.iter().collect::<alloc::vec::Vec<_>>(), [0x50, 0xe1]);
+        assert_eq!(posted.take(), None);
+
+        assert!(posted.post(0x60, |_| notifications += 1));
+        assert_eq!(notifications, 2);
+        assert!(posted.disable().contains(0x60));
+        assert!(!posted.post(0x70, |_| panic!("not accepting")));
+    }
+
+    #[test]
+    fn posted_interrupts_request_in_backing_page() {
+        let posted = PostedInterrupts::default();
+        let page = crate::hypervisor::support::zeroed_box::<VirtualApicPage>();
+        posted.enable_backing_page(&page);
+
+        let mut notifications = 0;
+        for vector in [0x41, 0x41, 0xd0] {
+            assert!(posted.post(vector, |notification| {
+                assert_eq!(notification, Notification::Doorbell);
+                notifications += 1;
+            }));
+        }
+        assert_eq!(notifications, 3);
+        assert_eq!(
+            page.requested().iter().collect::<alloc::vec::Vec<_>>(),
+            [0x41, 0xd0]
+        );
+        assert_eq!(posted.disable(), VectorSet::default());
+        assert!(!posted.post(0x42, |_| panic!("not accepting")));
+    }
+
+    #[test]
+    fn virtual_apic_page_uses_xapic_layout() {
+        let page = crate::hypervisor::support::zeroed_box::<VirtualApicPage>();
+        page.request(0x21);
+        page.request(0xff);
+        assert_eq!(page.0[(0x210) / 4].load(Ordering::Relaxed), 1 << 1);
+        assert_eq!(page.0[(0x270) / 4].load(Ordering::Relaxed), 1 << 31);
+        assert_eq!(page.requested().highest(), Some(0xff));
+
+        let mut in_service = VectorSet::default();
+        in_service.insert(0x80);
+        page.set_in_service(in_service);
+        assert_eq!(page.0[(0x140) / 4].load(Ordering::Relaxed), 1);
+
+        page.clear_in_service(0x80);
+        assert_eq!(page.in_service(), VectorSet::default());
+
+        page.set_level_triggered(0x31, true);
+        assert_eq!(page.0[(0x190) / 4].load(Ordering::Relaxed), 1 << 17);
+        page.set_level_triggered(0x31, false);
+        assert_eq!(page.0[(0x190) / 4].load(Ordering::Relaxed), 0);
+
+        page.reset();
+        assert_eq!(page.requested(), VectorSet::default());
+    }
+}
@@ -0,0 +1,107 @@
+//! This module implements configuration of I/O port interception. The embedder
+//! of this crate selects I/O ports to intercept and attaches handlers to them,
+//! and the vendor specific code translates the selection into the I/O bitmaps
+//! (Intel) or the I/O permissions map (AMD).
+
+use alloc::{boxed::Box, collections::BTreeMap};
+
+use crate::hypervisor::host::Vcpu;
+
+/// Represents a handler of I/O instructions on an intercepted port.
+///
+/// The default implementations pass through the access, so that a handler only
+/// observing either direction implements only the method for it.
+pub trait IoHandler: Send + Sync {
+    /// Handles `IN` of `size` bytes from `port`. Returns the value the guest
+    /// reads, or `None` to read the actual port.
+    fn read(&self, vcpu: &mut dyn Vcpu, port: u16, size: u8) -> Option<u32> {
+        let _ = (vcpu, port, size);
+        None
+    }
+
+    /// Handles `OUT` of `value` of `size` bytes to `port`. Returns the value to
+    /// actually write to the port, or `None` to discard the write.
+    fn write(&self, vcpu: &mut dyn Vcpu, port: u16, size: u8, value: u32) -> Option<u32> {
+        let _ = (vcpu, port, size);
+        Some(value)
+    }
+}
+
+/// The set of I/O ports to intercept and their handlers.
+///
+/// ```ignore
+/// let intercepts = IoIntercepts::new().on_port(0x64, KeyboardTracer);
+/// ```
+#[derive(Default)]
+pub struct IoIntercepts {
+    handlers: BTreeMap<u16, Box<dyn IoHandler>>,
+}
+
+impl IoIntercepts {
+    /// Returns an empty set, which intercepts no I/O port.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Intercepts I/O instructions accessing `port` and calls `handler` on them.
+    /// Replaces the handler already set for `port`, if any.
+    ///
+    /// Multi-byte accesses are dispatched to the handler of the first port
+    /// only, and are intercepted if any of the ports is intercepted.
+    #[must_use]
+    pub fn on_port(mut self, port: u16, handler: impl IoHandler + 'static) -> Self {
+        let _ = self.handlers.insert(port, Box::new(handler));
+        self
+    }
+
+    /// Returns the handler for `port`, if any.
+    pub(crate) fn handler(&self, port: u16) -> Option<&dyn IoHandler> {
+        self.handlers.get(&port).map(AsRef::as_ref)
+    }
+
+    /// Returns whether no I/O port is intercepted.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Sets bits in `bitmap` for the intercepted ports, one bit per port. This
+    /// is the format of both the Intel I/O bitmaps A and B concatenated, and the
+    /// AMD I/O permissions map.
+    // See: 25.6.4 I/O-Bitmap Addresses
+    // See: 15.10.1 I/O Permissions Map
+    pub(crate) fn build_bitmap(&self, bitmap: &mut [u8; 0x2000]) {
+        for &port in self.handlers.keys() {
+            bitmap[usize::from(port) / 8] |= 1 << (port % 8);
+        }
+    }
+}
+
+impl core::fmt::Debug for IoIntercepts {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_set().entries(self.handlers.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Nop;
+    impl IoHandler for Nop {}
+
+    #[test]
+    fn bitmap() {
+        let intercepts = IoIntercepts::new()
+            .on_port(0x64, Nop)
+            .on_port(0xcf8, Nop)
+            .on_port(0xcfc, Nop)
+            .on_port(0xffff, Nop);
+
+        let mut bitmap = [0u8; 0x2000];
+        intercepts.build_bitmap(&mut bitmap);
+        assert_eq!(bitmap[0xc], 1 << 4);
+        assert_eq!(bitmap[0x19f], (1 << 0) | (1 << 4));
+        assert_eq!(bitmap[0x1fff], 1 << 7);
+        assert_eq!(bitmap.iter().filter(|&&b| b != 0).count(), 3);
+    }
+}
@@ -0,0 +1,160 @@
+//! This module implements sending interprocessor interrupts (IPIs) from the
+//! host, through the local APIC of the current processor in either the xAPIC
+//! or the x2APIC mode, regardless of the vendor.
+//!
+//! The guest of this hypervisor owns the local APIC, and external interrupts
+//! do not cause VM-exit, so fixed IPIs are delivered to the guest on the target
+//! processor. If the target delivers interrupts through the virtual APIC with
+//! `SharedHostData::interrupt_virtualization`, fixed IPIs are posted to it
+//! instead, and delivered to the guest without VM-exit. NMIs reach the host of
+//! the target on Intel processors: the NMI handler of the host if the target is
+//! in the host, or VM-exit otherwise. On AMD processors, the host runs with GIF
+//! cleared, which holds NMIs pending, and they are delivered to the guest.
+//!
+//! Unlike `platform_ops`, which is only available while setting up the host,
+//! this does not depend on platform API and can be used from the host, for
+//! example, by the watchdog.
+//!
+//! ```ignore
+//! ipi::send_ipi(1, IpiKind::Nmi)?;
+//! ```
+
+use bit_field::BitField;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use crate::hypervisor::{
+    host_window,
+    interrupt_virtualization::Notification,
+    percpu::{self, PerCpu},
+    x86_instructions::{rdmsr, wrmsr},
+};
+
+/// The kinds of IPIs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpiKind {
+    /// An interrupt with the vector.
+    Fixed(u8),
+
+    /// A non-maskable interrupt.
+    Nmi,
+}
+
+/// The errors sending IPIs may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpiError {
+    #[error("the processor {0} is not enumerated")]
+    InvalidProcessor(usize),
+}
+
+/// Sends the `kind` IPI to the processor `id`. Must be called from the host.
+///
+/// # Errors
+///
+/// Returns `InvalidProcessor` if `id` is not a valid processor ID.
+pub fn send_ipi(id: usize, kind: IpiKind) -> Result<(), IpiError> {
+    let target = percpu::get(id).ok_or(IpiError::InvalidProcessor(id))?;
+    send(target, kind);
+    Ok(())
+}
+
+/// Sends the `kind` IPI to all processors excluding the current one. Must be
+/// called from the host.
+pub fn send_ipi_to_others(kind: IpiKind) {
+    for block in percpu::all().filter(|block| block.id != percpu::current().id) {
+        send(block, kind);
+    }
+}
+
+/// Sends the `kind` IPI to `target`, or posts it if a fixed IPI and `target`
+/// accepts posted interrupts. The notification is sent only to a target out of
+/// the host, as it takes the posted interrupts before VM-entry otherwise. See
+/// `PostedInterrupts::take`.
+fn send(target: &PerCpu, kind: IpiKind) {
+    // See: 15.29 Advanced Virtual Interrupt Controller
+    #[cfg(any(feature = "amd", test))]
+    const AVIC_DOORBELL: u32 = 0xc001_011b;
+
+    if let IpiKind::Fixed(vector) = kind {
+        let posted = target.posted_interrupts.post(vector, |notification| {
+            if target.heartbeat.is_entered() {
+                return;
+            }
+            match notification {
+                #[cfg(any(feature = "intel", test))]
+                Notification::Vector(notification_vector) => write_icr(
+                    command_of(IpiKind::Fixed(notification_vector)),
+                    target.apic_id,
+                ),
+                #[cfg(any(feature = "amd", test))]
+                Notification::Doorbell => wrmsr(AVIC_DOORBELL, u64::from(target.apic_id)),
+            }
+        });
+        if posted {
+            return;
+        }
+    }
+    write_icr(command_of(kind), target.apic_id);
+}
+
+/// Returns the low 32 bits of the ICR for `kind` with no shorthand and the
+/// physical destination mode, asserting the level.
+/// See: 11.6.1 Interrupt Command Register (ICR)
+fn command_of(kind: IpiKind) -> u32 {
+    const DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+    const LEVEL_ASSERT: u32 = 1 << 14;
+
+    LEVEL_ASSERT
+        | match kind {
+            IpiKind::Fixed(vector) => u32::from(vector),
+            IpiKind::Nmi => DELIVERY_MODE_NMI,
+        }
+}
+
+/// Writes the ICR of the current processor with `command` to `apic_id`.
+fn write_icr(command: u32, apic_id: u32) {
+    const X2APIC_ICR: u32 = 0x830;
+    const ICR_LOW: usize = 0x300;
+    const ICR_HIGH: usize = 0x310;
+
+    // See: 11.4.4 Local APIC Status and Location
+    let apic_base = rdmsr(x86::msr::IA32_APIC_BASE);
+    if apic_base.get_bit(10) {
+        wrmsr(X2APIC_ICR, u64::from(apic_id) << 32 | u64::from(command));
+        return;
+    }
+
+    // The destination is the 8-bit xAPIC ID in xAPIC mode. The guest may have
+    // written ICR_HIGH for its own IPI and not yet ICR_LOW, so restore it.
+    // Also wait for an IPI of the guest being sent, as writing ICR_LOW while
+    // the delivery status is "send pending" may lose either IPI, and for ours
+    // before restoring.
+    // See: 11.6.1 Interrupt Command Register (ICR)
+    let page = apic_base & !(BASE_PAGE_SIZE as u64 - 1) & ((1 << 52) - 1);
+    let registers = host_window::map_uncacheable(percpu::current().id, page);
+    let icr_low = unsafe { registers.add(ICR_LOW).cast::<u32>() };
+    let icr_high = unsafe { registers.add(ICR_HIGH).cast::<u32>() };
+    let wait_for_idle = || {
+        while unsafe { icr_low.read_volatile() }.get_bit(12) {
+            core::hint::spin_loop();
+        }
+    };
+    unsafe {
+        let saved_high = icr_high.read_volatile();
+        wait_for_idle();
+        icr_high.write_volatile(apic_id << 24);
+        icr_low.write_volatile(command);
+        wait_for_idle();
+        icr_high.write_volatile(saved_high);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_encoded() {
+        assert_eq!(command_of(IpiKind::Fixed(0xd1)), 0x40d1);
+        assert_eq!(command_of(IpiKind::Nmi), 0x4400);
+    }
+}
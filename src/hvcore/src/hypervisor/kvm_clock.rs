@@ -0,0 +1,332 @@
+//! This module implements emulation of the KVM paravirtualized clock, kvmclock.
+//! Enabled with `SharedHostData::kvm_clock`, Linux guests detect KVM with
+//! CPUID and use kvmclock as a stable clocksource, instead of falling back to
+//! the HPET with warnings from the TSC watchdog.
+//!
+//! The KVM leaves are reported at 0x4000_0100, which Linux scans for the KVM
+//! signature, so that they coexist with our hypervisor and the Hyper-V leaves
+//! at 0x4000_0000. Only MSR_KVM_WALL_CLOCK_NEW and MSR_KVM_SYSTEM_TIME_NEW are
+//! emulated. The system time is the guest TSC converted into nanoseconds, and
+//! the wall clock is read from the CMOS RTC once at virtualization, assuming
+//! it is in UTC.
+//!
+//! kvmclock requires the TSC frequency enumerated with CPUID and the guest TSC
+//! that is invariant and neither scaled nor compensated with
+//! `SharedHostData::tsc`, and is disabled with a warning otherwise.
+// See: Documentation/virt/kvm/x86/cpuid.rst and msr.rst in Linux
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86::{bits64::paging::BASE_PAGE_SIZE, cpuid::CpuIdResult};
+
+use crate::hypervisor::{
+    apic_id::PerProcessor,
+    cpuid_policy::CpuidRegister,
+    guest_memory::{is_writable_ram, UserAccessGuard},
+    host::Vcpu,
+    host_window, tsc,
+    x86_instructions::{in_port, out_port, rdtsc},
+    SharedHostData,
+};
+
+const KVM_CPUID_SIGNATURE: u32 = 0x4000_0100;
+const KVM_CPUID_FEATURES: u32 = 0x4000_0101;
+
+const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Modifies `shared_host.cpuid_policy` to report kvmclock, and installs the
+/// handlers of its MSRs into `shared_host.msr_intercepts`. Called from the guest
+/// before any processor is virtualized.
+pub(crate) fn install(shared_host: &mut SharedHostData) {
+    const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+    const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u32 = 1 << 24;
+
+    let Some(frequency) = tsc::frequency() else {
+        log::warn!("kvmclock disabled: the TSC frequency is unknown");
+        return;
+    };
+    if !tsc::is_constant_across_processors(&shared_host.tsc) {
+        log::warn!("kvmclock disabled: the guest TSC is not constant across processors");
+        return;
+    }
+    let (mul, shift) = time_scale(frequency);
+    TIME_SCALE.store(
+        u64::from(mul) | (u64::from(shift as u8) << 32),
+        Ordering::Relaxed,
+    );
+    let boot_time = rtc_unix_time().saturating_mul(NANOSECONDS_PER_SECOND);
+    BOOT_TIME.store(
+        boot_time.saturating_sub(system_time(rdtsc(), mul, shift)),
+        Ordering::Relaxed,
+    );
+
+    shared_host.msr_intercepts = core::mem::take(&mut shared_host.msr_intercepts)
+        .on_read(MSR_KVM_WALL_CLOCK_NEW, |_, _| {
+            Some(WALL_CLOCK.load(Ordering::Relaxed))
+        })
+        .on_write(MSR_KVM_WALL_CLOCK_NEW, |vcpu, _, value| {
+            write_wall_clock_msr(vcpu, value);
+            None
+        })
+        .on_read(MSR_KVM_SYSTEM_TIME_NEW, |vcpu, _| {
+            SYSTEM_TIMES
+                .get(vcpu.id())
+                .map(|value| value.load(Ordering::Relaxed))
+        })
+        .on_write(MSR_KVM_SYSTEM_TIME_NEW, |vcpu, _, value| {
+            write_system_time_msr(vcpu, value);
+            None
+        });
+
+    // The hypervisor leaves are read only with "Bit 31: Hypervisor present".
+    let (ebx, ecx, edx) = signature();
+    shared_host.cpuid_policy = core::mem::take(&mut shared_host.cpuid_policy)
+        .set_bits(1, None, CpuidRegister::Ecx, 1 << 31)
+        .set(
+            KVM_CPUID_SIGNATURE,
+            None,
+            CpuIdResult {
+                eax: KVM_CPUID_FEATURES,
+                ebx,
+                ecx,
+                edx,
+            },
+        )
+        .set(
+            KVM_CPUID_FEATURES,
+            None,
+            CpuIdResult {
+                eax: KVM_FEATURE_CLOCKSOURCE2 | KVM_FEATURE_CLOCKSOURCE_STABLE_BIT,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            },
+        );
+}
+
+/// Returns "KVMKVMKVM\0\0\0" in EBX, ECX and EDX.
+fn signature() -> (u32, u32, u32) {
+    (
+        u32::from_le_bytes(*b"KVMK"),
+        u32::from_le_bytes(*b"VMKV"),
+        u32::from_le_bytes(*b"M\0\0\0"),
+    )
+}
+
+/// struct pvclock_wall_clock: the wall clock time at the system time zero.
+#[repr(C)]
+struct WallClock {
+    version: u32,
+    sec: u32,
+    nsec: u32,
+}
+
+/// struct pvclock_vcpu_time_info. The guest computes the system time as
+/// `system_time + (((TSC - tsc_timestamp) << tsc_shift) * tsc_to_system_mul >> 32)`
+/// while `version` is unchanged and even.
+#[repr(C)]
+struct VcpuTimeInfo {
+    version: u32,
+    pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    pad: [u8; 2],
+}
+
+/// Handles a write to MSR_KVM_WALL_CLOCK_NEW: fills the structure at the guest
+/// physical address written.
+fn write_wall_clock_msr(vcpu: &mut dyn Vcpu, value: u64) {
+    if !fits_in_page::<WallClock>(value) || !is_writable_ram(vcpu, value) {
+        return;
+    }
+    let boot_time = BOOT_TIME.load(Ordering::Relaxed);
+    let contents = WallClock {
+        version: 2,
+        sec: (boot_time / NANOSECONDS_PER_SECOND) as u32,
+        nsec: (boot_time % NANOSECONDS_PER_SECOND) as u32,
+    };
+    let wall_clock = host_window::map(vcpu.id(), value).cast::<WallClock>();
+    let _guard = UserAccessGuard::new();
+    // Safety: the window maps the guest memory, which the guest gave for the
+    // structure.
+    unsafe { wall_clock.write_unaligned(contents) };
+    WALL_CLOCK.store(value, Ordering::Relaxed);
+}
+
+/// Handles a write to MSR_KVM_SYSTEM_TIME_NEW: fills the structure at the guest
+/// physical address written when it is enabled.
+fn write_system_time_msr(vcpu: &mut dyn Vcpu, value: u64) {
+    const ENABLE: u64 = 1 << 0;
+    const PVCLOCK_TSC_STABLE_BIT: u8 = 1 << 0;
+
+    let Some(stored) = SYSTEM_TIMES.get(vcpu.id()) else {
+        return;
+    };
+    let gpa = value & !ENABLE;
+    if value & ENABLE != 0 && fits_in_page::<VcpuTimeInfo>(gpa) && is_writable_ram(vcpu, gpa) {
+        let scale = TIME_SCALE.load(Ordering::Relaxed);
+        let contents = VcpuTimeInfo {
+            version: 2,
+            pad0: 0,
+            tsc_timestamp: 0,
+            system_time: 0,
+            tsc_to_system_mul: scale as u32,
+            tsc_shift: (scale >> 32) as u8 as i8,
+            flags: PVCLOCK_TSC_STABLE_BIT,
+            pad: [0; 2],
+        };
+        let time_info = host_window::map(vcpu.id(), gpa).cast::<VcpuTimeInfo>();
+        let _guard = UserAccessGuard::new();
+        // Safety: the window maps the guest memory, which the guest gave for
+        // the structure.
+        unsafe { time_info.write_unaligned(contents) };
+    }
+    stored.store(value, Ordering::Relaxed);
+}
+
+/// Returns whether `T` at the guest physical address `gpa` does not cross a
+/// page boundary, which the host window can map.
+fn fits_in_page<T>(gpa: u64) -> bool {
+    (gpa as usize % BASE_PAGE_SIZE) + size_of::<T>() <= BASE_PAGE_SIZE
+}
+
+/// Returns the multiplier and the shift converting TSC cycles of `frequency` in
+/// Hz into nanoseconds, with the multiplier in [2^31, 2^32).
+fn time_scale(frequency: u64) -> (u32, i8) {
+    let mut hz = u128::from(frequency);
+    let mut shift = 0i8;
+    while hz <= u128::from(NANOSECONDS_PER_SECOND) {
+        hz <<= 1;
+        shift += 1;
+    }
+    while hz > u128::from(NANOSECONDS_PER_SECOND) * 2 {
+        hz >>= 1;
+        shift -= 1;
+    }
+    let mul = (u128::from(NANOSECONDS_PER_SECOND) << 32) / hz;
+    (mul as u32, shift)
+}
+
+/// Returns the system time in nanoseconds for `tsc` with `mul` and `shift` as
+/// the guest computes from `VcpuTimeInfo`.
+fn system_time(tsc: u64, mul: u32, shift: i8) -> u64 {
+    let shifted = if shift >= 0 {
+        tsc << shift
+    } else {
+        tsc >> -shift
+    };
+    ((u128::from(shifted) * u128::from(mul)) >> 32) as u64
+}
+
+/// Reads the CMOS RTC and returns the time in seconds since the Unix epoch,
+/// assuming that the RTC is in UTC and in the 21st century.
+// See: MC146818A datasheet
+fn rtc_unix_time() -> u64 {
+    const STATUS_A: u8 = 0x0a;
+    const STATUS_B: u8 = 0x0b;
+    const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+    const HOUR_FORMAT_24: u8 = 1 << 1;
+    const BINARY_MODE: u8 = 1 << 2;
+    const HOUR_PM: u8 = 1 << 7;
+
+    let read = |register: u8| {
+        out_port(0x70, 1, u32::from(register));
+        in_port(0x71, 1) as u8
+    };
+
+    // Read until two consecutive reads match to not observe an update.
+    let read_all = || {
+        while read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        [0x00, 0x02, 0x04, 0x07, 0x08, 0x09].map(read)
+    };
+    let mut values = read_all();
+    loop {
+        let again = read_all();
+        if again == values {
+            break;
+        }
+        values = again;
+    }
+
+    let status_b = read(STATUS_B);
+    let [second, minute, hour, day, month, year] = values;
+    let pm = hour & HOUR_PM != 0;
+    let decode = |value: u8| {
+        if status_b & BINARY_MODE == 0 {
+            (value >> 4) * 10 + (value & 0xf)
+        } else {
+            value
+        }
+    };
+    let mut hour = decode(hour & !HOUR_PM);
+    if status_b & HOUR_FORMAT_24 == 0 {
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+    let days = days_from_unix_epoch(
+        2000 + u64::from(decode(year)),
+        u64::from(decode(month)),
+        u64::from(decode(day)),
+    );
+    days * 86_400
+        + u64::from(hour) * 3600
+        + u64::from(decode(minute)) * 60
+        + u64::from(decode(second))
+}
+
+/// Returns the number of days since 1970-01-01 to the date, which must not be
+/// earlier.
+fn days_from_unix_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // Count years from March so that the leap day is the last day of a year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The values of the partition-wide MSR_KVM_WALL_CLOCK_NEW and the
+/// per-processor MSR_KVM_SYSTEM_TIME_NEW.
+static WALL_CLOCK: AtomicU64 = AtomicU64::new(0);
+static SYSTEM_TIMES: PerProcessor<AtomicU64> = PerProcessor::new();
+
+/// The multiplier in the low 32 bits and the shift in the next 8 bits
+/// returned by `time_scale`.
+static TIME_SCALE: AtomicU64 = AtomicU64::new(0);
+
+/// The wall clock time at the system time zero in nanoseconds since the Unix
+/// epoch.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tsc_is_converted_into_nanoseconds() {
+        for frequency in [100_000_000, 1_000_000_000, 2_500_000_000, 3_000_000_000] {
+            let (mul, shift) = time_scale(frequency);
+            assert!(mul >= 1 << 31);
+            let ns = system_time(frequency * 10, mul, shift);
+            assert!(
+                ns.abs_diff(10 * NANOSECONDS_PER_SECOND) <= 10,
+                "{frequency}: {ns}"
+            );
+        }
+    }
+
+    #[test]
+    fn dates_are_converted_into_days() {
+        assert_eq!(days_from_unix_epoch(1970, 1, 1), 0);
+        assert_eq!(days_from_unix_epoch(2000, 3, 1), 11_017);
+        assert_eq!(days_from_unix_epoch(2024, 2, 29), 19_782);
+    }
+}
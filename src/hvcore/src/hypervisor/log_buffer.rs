@@ -0,0 +1,147 @@
+//! This module implements the in-memory ring buffer of logs.
+//!
+//! Each processor writes to its own buffer, so that writing never waits for
+//! other processors. Readers may drain the buffer concurrently from any
+//! processor. When the writer laps the reader, the oldest unread bytes are
+//! overwritten and skipped by the reader.
+
+use core::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use alloc::boxed::Box;
+use spin::Mutex;
+
+/// A ring buffer with a single writer and any number of readers.
+#[derive(Debug)]
+pub(crate) struct LogBuffer {
+    bytes: Box<[AtomicU8]>,
+
+    /// The total number of bytes the writer started to write.
+    reserved: AtomicU64,
+
+    /// The total number of bytes the writer finished writing.
+    committed: AtomicU64,
+
+    /// Whether the writer is writing, to drop messages written re-entrantly,
+    /// for example, from an exception handler.
+    writing: AtomicBool,
+
+    /// The total number of bytes read. Readers are serialized by the lock.
+    read: Mutex<u64>,
+}
+
+impl LogBuffer {
+    pub(crate) fn new(size: usize) -> Self {
+        assert!(size != 0);
+        Self {
+            bytes: (0..size).map(|_| AtomicU8::new(0)).collect(),
+            reserved: AtomicU64::new(0),
+            committed: AtomicU64::new(0),
+            writing: AtomicBool::new(false),
+            read: Mutex::new(0),
+        }
+    }
+
+    /// Appends `data`, overwriting the oldest bytes if the buffer is full.
+    /// Returns `false` if `data` is dropped because this is called while
+    /// writing. Must only be called from the processor owning the buffer.
+    pub(crate) fn write(&self, data: &[u8]) -> bool {
+        if self.writing.swap(true, Ordering::Acquire) {
+            return false;
+        }
+
+        // Only the last `size` bytes can be kept.
+        let size = self.bytes.len();
+        let data = &data[data.len().saturating_sub(size)..];
+        let start = self.committed.load(Ordering::Relaxed);
+        let end = start + data.len() as u64;
+
+        // Announce the bytes to overwrite before overwriting them, so that
+        // readers can detect them. Pairs with the fence in `drain`.
+        self.reserved.store(end, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (i, &byte) in data.iter().enumerate() {
+            self.bytes[Self::index(start + i as u64, size)].store(byte, Ordering::Relaxed);
+        }
+        self.committed.store(end, Ordering::Release);
+
+        self.writing.store(false, Ordering::Release);
+        true
+    }
+
+    /// Moves the oldest unread bytes into `buffer`, and returns the number of
+    /// the moved bytes. The bytes overwritten before being read are skipped.
+    pub(crate) fn drain(&self, buffer: &mut [u8]) -> usize {
+        let size = self.bytes.len() as u64;
+        let mut read = self.read.lock();
+        let committed = self.committed.load(Ordering::Acquire);
+        let start = (*read).max(committed.saturating_sub(size));
+        let len = (committed - start).min(buffer.len() as u64);
+        for (i, byte) in buffer[..len as usize].iter_mut().enumerate() {
+            *byte =
+                self.bytes[Self::index(start + i as u64, size as usize)].load(Ordering::Relaxed);
+        }
+
+        // Discard the bytes the writer may have overwritten while copying.
+        fence(Ordering::Acquire);
+        let valid_from = self.reserved.load(Ordering::Relaxed).saturating_sub(size);
+        let skipped = valid_from.saturating_sub(start).min(len);
+        buffer.copy_within(skipped as usize..len as usize, 0);
+
+        *read = start + len;
+        (len - skipped) as usize
+    }
+
+    fn index(position: u64, size: usize) -> usize {
+        (position % size as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drain_returns_written_bytes_once() {
+        let log = LogBuffer::new(16);
+        assert!(log.write(b"hello "));
+        assert!(log.write(b"world"));
+
+        let mut buffer = [0u8; 32];
+        let len = log.drain(&mut buffer);
+        assert_eq!(&buffer[..len], b"hello world");
+        assert_eq!(log.drain(&mut buffer), 0);
+    }
+
+    #[test]
+    fn drain_continues_from_partial_read() {
+        let log = LogBuffer::new(16);
+        assert!(log.write(b"abcdef"));
+
+        let mut buffer = [0u8; 4];
+        assert_eq!(log.drain(&mut buffer), 4);
+        assert_eq!(&buffer, b"abcd");
+        assert_eq!(log.drain(&mut buffer), 2);
+        assert_eq!(&buffer[..2], b"ef");
+    }
+
+    #[test]
+    fn overwritten_bytes_are_skipped() {
+        let log = LogBuffer::new(8);
+        assert!(log.write(b"0123456"));
+        assert!(log.write(b"789ab"));
+
+        let mut buffer = [0u8; 16];
+        let len = log.drain(&mut buffer);
+        assert_eq!(&buffer[..len], b"456789ab");
+    }
+
+    #[test]
+    fn oversized_write_keeps_last_bytes() {
+        let log = LogBuffer::new(4);
+        assert!(log.write(b"abcdefgh"));
+
+        let mut buffer = [0u8; 8];
+        let len = log.drain(&mut buffer);
+        assert_eq!(&buffer[..len], b"efgh");
+    }
+}
@@ -0,0 +1,149 @@
+//! This module implements the logger of the hypervisor.
+//!
+//! Logs are written to the in-memory ring buffer of the current processor,
+//! which the guest can drain with `Hypercall::ReadLog`, and optionally to the
+//! serial port. Logs written before the per-processor blocks are allocated are
+//! only written to the serial port.
+//!
+//! Each line is prefixed with the APIC ID of the processor and the time since
+//! the logger was set up, derived from the TSC. The time is in seconds if the
+//! processor enumerates the TSC frequency, or in TSC ticks otherwise.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::hypervisor::{
+    apic_id, percpu,
+    serial_logger::{self, SerialConfig},
+    support::InterruptGuard,
+    tsc,
+    x86_instructions::rdtsc,
+};
+
+/// The size of the ring buffer of each processor in bytes.
+pub(crate) const LOG_BUFFER_SIZE: usize = 0x4000;
+
+/// Sets up the logger with `level`, also writing logs to the serial port if
+/// `serial` is specified.
+pub(crate) fn init(level: log::LevelFilter, serial: Option<&SerialConfig>) {
+    if let Some(config) = serial {
+        serial_logger::init(config);
+    }
+    SERIAL.store(serial.is_some(), Ordering::Relaxed);
+    let _ = BASE_TSC.compare_exchange(0, rdtsc(), Ordering::Relaxed, Ordering::Relaxed);
+    TSC_FREQUENCY.store(tsc::frequency().unwrap_or(0), Ordering::Relaxed);
+
+    // The logger is already set if the system was virtualized before.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
+
+/// Moves the unread logs of all processors into `buffer`, and returns the
+/// number of the moved bytes. The logs of each processor are moved in order,
+/// but not interleaved with the logs of other processors by time.
+pub(crate) fn drain(buffer: &mut [u8]) -> usize {
+    let Some(blocks) = percpu::try_all() else {
+        return 0;
+    };
+    let mut len = 0;
+    for block in blocks {
+        len += block.log.drain(&mut buffer[len..]);
+    }
+    len
+}
+
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Trace
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Disable interrupt so that the buffer of the current processor is
+        // written only from the current processor.
+        let _intr_guard = InterruptGuard::new();
+        let apic_id = apic_id::get();
+        let timestamp = Timestamp {
+            ticks: rdtsc().wrapping_sub(BASE_TSC.load(Ordering::Relaxed)),
+            frequency: TSC_FREQUENCY.load(Ordering::Relaxed),
+        };
+        let msg = alloc::format!(
+            "#{}:{}:{:5}: {}\n",
+            apic_id,
+            timestamp,
+            record.level(),
+            record.args()
+        );
+        let block = percpu::find_current();
+        if let Some(block) = block {
+            let _ = block.log.write(msg.as_bytes());
+        }
+        if SERIAL.load(Ordering::Relaxed) {
+            serial_logger::write(&msg, block.map(|block| &block.serial_pending));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// The time since `BASE_TSC`.
+struct Timestamp {
+    ticks: u64,
+
+    /// The TSC frequency in Hz, or 0 if unknown.
+    frequency: u64,
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.frequency == 0 {
+            return write!(f, "{}t", self.ticks);
+        }
+        let secs = self.ticks / self.frequency;
+        let micros = (self.ticks % self.frequency) * 1_000_000 / self.frequency;
+        write!(f, "{secs}.{micros:06}")
+    }
+}
+
+static LOGGER: Logger = Logger;
+
+/// Whether to write logs to the serial port too.
+static SERIAL: AtomicBool = AtomicBool::new(false);
+
+/// The TSC when the logger is first set up.
+static BASE_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// The TSC frequency in Hz, or 0 if unknown.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn timestamp_in_seconds() {
+        let timestamp = Timestamp {
+            ticks: 3_500_250,
+            frequency: 1_000_000,
+        };
+        assert_eq!(timestamp.to_string(), "3.500250");
+    }
+
+    #[test]
+    fn timestamp_in_ticks_without_frequency() {
+        let timestamp = Timestamp {
+            ticks: 1234,
+            frequency: 0,
+        };
+        assert_eq!(timestamp.to_string(), "1234t");
+    }
+}
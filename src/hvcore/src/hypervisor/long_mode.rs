@@ -0,0 +1,148 @@
+//! This module implements validation of the guest writes to IA32_EFER and CR0
+//! that switch the paging mode, such as entering and leaving long mode during
+//! early boot, kexec and resume from hibernation.
+//!
+//! Writes to IA32_EFER are intercepted so that the vendor specific code keeps
+//! IA32_EFER.LMA consistent with the rest of the guest state: with the
+//! "IA-32e mode guest" VM-entry control (Intel), or in the VMCB (AMD). Writes
+//! to CR0 changing PG are intercepted on Intel for the same reason, while the
+//! processor updates the VMCB itself on AMD. Writes that would cause #GP on
+//! bare metal, such as changing IA32_EFER.LME while paging is enabled, cause
+//! #GP instead of making the next VM-entry fail.
+
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+
+use crate::hypervisor::msr_intercepts::MsrIntercepts;
+
+const EFER_SCE: u64 = 1 << 0;
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+const EFER_NXE: u64 = 1 << 11;
+const CR0_PE: u64 = 1 << 0;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PAE: u64 = 1 << 5;
+
+/// Returns `intercepts` with writes to IA32_EFER intercepted, unless a handler
+/// is already set. Called from the guest before any processor is virtualized.
+pub(crate) fn install(intercepts: MsrIntercepts) -> MsrIntercepts {
+    if intercepts.write_handler(x86::msr::IA32_EFER).is_some() {
+        return intercepts;
+    }
+    // The value is validated before the handler is called. See
+    // `is_valid_efer_write`.
+    intercepts.on_write(x86::msr::IA32_EFER, |_, _, value| Some(value))
+}
+
+/// Returns the bits of IA32_EFER that cause #GP, or VM-entry failure, if set.
+pub(crate) fn reserved_efer_bits() -> u64 {
+    // See: 3.1.7 Extended Feature Enable Register (EFER)
+    const AMD_EFER_MBZ: u64 = 0xffff_ffff_ffc0_0000 | 1 << 19 | 1 << 16 | 1 << 9 | 0xfe;
+
+    // Intel defines no bits other than SCE, LME, LMA and NXE. AMD defines more,
+    // and the bits VMRUN requires to be zero are reserved.
+    // See: Table 2-2. IA-32 Architectural MSRs
+    let is_intel = x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+    let mut reserved = if is_intel {
+        !(EFER_SCE | EFER_LME | EFER_LMA | EFER_NXE)
+    } else {
+        AMD_EFER_MBZ
+    };
+
+    // See: Table 1-17. Information Returned by CPUID Instruction
+    let edx = cpuid!(0x8000_0001).edx;
+    if !edx.get_bit(11) {
+        reserved |= EFER_SCE;
+    }
+    if !edx.get_bit(20) {
+        reserved |= EFER_NXE;
+    }
+    if !edx.get_bit(29) {
+        reserved |= EFER_LME | EFER_LMA;
+    }
+    reserved
+}
+
+/// Returns whether `WRMSR` of `value` to IA32_EFER succeeds on bare metal when
+/// the guest has `efer` and `cr0`, given the `reserved` bits. LMA is read-only
+/// and ignored.
+// See: 10.8.5 Initializing IA-32e Mode
+pub(crate) fn is_valid_efer_write(efer: u64, cr0: u64, value: u64, reserved: u64) -> bool {
+    if value & reserved & !EFER_LMA != 0 {
+        return false;
+    }
+    cr0 & CR0_PG == 0 || (efer ^ value) & EFER_LME == 0
+}
+
+/// Returns whether `MOV` of `value` to CR0 succeeds on bare metal when the
+/// guest has `cr0`, `cr4` and `efer`, in 64-bit mode if `long`, as far as
+/// switching the paging mode is concerned.
+// See: MOV—Move to/from Control Registers
+pub(crate) fn is_valid_cr0_write(cr0: u64, value: u64, cr4: u64, efer: u64, long: bool) -> bool {
+    if value & CR0_PG != 0 && value & CR0_PE == 0 {
+        return false;
+    }
+    if (cr0 ^ value) & CR0_PG == 0 {
+        return true;
+    }
+    if value & CR0_PG != 0 {
+        // Activating IA-32e mode requires PAE paging.
+        efer & EFER_LME == 0 || cr4 & CR4_PAE != 0
+    } else {
+        // Leaving IA-32e mode requires leaving 64-bit mode first.
+        !long
+    }
+}
+
+/// Returns IA32_EFER with LMA updated for CR0 of `cr0`, as the processor would
+/// update it on a write to CR0.
+pub(crate) fn update_lma(efer: u64, cr0: u64) -> u64 {
+    if cr0 & CR0_PG != 0 && efer & EFER_LME != 0 {
+        efer | EFER_LMA
+    } else {
+        efer & !EFER_LMA
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CR0_PE_PG: u64 = CR0_PE | CR0_PG;
+    const RESERVED: u64 = !(EFER_SCE | EFER_LME | EFER_LMA | EFER_NXE);
+
+    #[test]
+    fn efer_writes_are_validated() {
+        let valid = |efer, cr0, value| is_valid_efer_write(efer, cr0, value, RESERVED);
+        let efer = EFER_SCE | EFER_LME | EFER_LMA | EFER_NXE;
+        assert!(valid(efer, CR0_PE_PG, efer & !EFER_SCE));
+        assert!(valid(efer, CR0_PE_PG, EFER_LME));
+        assert!(!valid(efer, CR0_PE_PG, EFER_LMA));
+        assert!(!valid(efer, CR0_PE_PG, efer | 1 << 12));
+
+        // LME can be changed only without paging.
+        assert!(valid(0, CR0_PE, EFER_LME));
+        assert!(!valid(0, CR0_PE_PG, EFER_LME));
+    }
+
+    #[test]
+    fn cr0_writes_are_validated() {
+        let valid = |cr0, value, cr4, efer| is_valid_cr0_write(cr0, value, cr4, efer, false);
+        assert!(valid(CR0_PE, CR0_PE_PG, CR4_PAE, EFER_LME));
+        assert!(!valid(CR0_PE, CR0_PE_PG, 0, EFER_LME));
+        assert!(valid(CR0_PE, CR0_PE_PG, 0, 0));
+        assert!(!valid(0, CR0_PG, CR4_PAE, 0));
+
+        // Paging can be disabled only outside 64-bit mode.
+        let efer = EFER_LME | EFER_LMA;
+        assert!(valid(CR0_PE_PG, CR0_PE, CR4_PAE, efer));
+        assert!(!is_valid_cr0_write(CR0_PE_PG, CR0_PE, CR4_PAE, efer, true));
+    }
+
+    #[test]
+    fn lma_follows_paging() {
+        assert_eq!(update_lma(EFER_LME, CR0_PE_PG), EFER_LME | EFER_LMA);
+        assert_eq!(update_lma(EFER_LME | EFER_LMA, CR0_PE), EFER_LME);
+        assert_eq!(update_lma(EFER_NXE, CR0_PE_PG), EFER_NXE);
+    }
+}
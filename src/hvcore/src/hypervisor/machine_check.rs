@@ -0,0 +1,170 @@
+//! This module implements handling of machine-check exceptions (#MC), which
+//! the processor raises on uncorrected hardware errors, such as memory and bus
+//! errors. The details of the errors are logged in the banks of the
+//! machine-check architecture, MCi_STATUS, MCi_ADDR and MCi_MISC.
+//!
+//! #MC in the guest is intercepted, so that the errors are logged. Then, #MC is
+//! injected into the guest, for the operating system to take the same action
+//! as without our hypervisor, such as a bug check. The banks are left as they
+//! are for the guest to read them. If the guest has not enabled #MC with
+//! CR4.MCE, where the processor would enter the shutdown state, the system is
+//! halted with a panic instead. #MC in the host is logged in the same way
+//! before the panic the host exception handler causes.
+//!
+//! # Limitations
+//!
+//! The host does not attempt to recover from corrected or recoverable errors,
+//! and it does not signal machine-check events to the other processors, as the
+//! hardware broadcasts #MC where needed.
+
+use alloc::{format, string::String, vec::Vec};
+use bit_field::BitField;
+use x86::cpuid::cpuid;
+
+use crate::hypervisor::{
+    event::{self, Event},
+    host::Vcpu,
+    x86_instructions::rdmsr,
+};
+
+/// The error logged in a bank.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct BankError {
+    bank: u32,
+    status: u64,
+    addr: Option<u64>,
+    misc: Option<u64>,
+}
+
+/// Handles #MC intercepted in the guest on `vcpu`: logs the errors and injects
+/// #MC into the guest, or halts the system if the guest cannot handle it.
+pub(crate) fn handle(vcpu: &mut dyn Vcpu) {
+    const CR4_MCE: u64 = 1 << 6;
+
+    log::error!("#MC occurred in guest on CPU {}", vcpu.id());
+    log_errors();
+    if vcpu.cr4() & CR4_MCE == 0 {
+        panic!("#MC occurred in guest, which disabled #MC with CR4.MCE");
+    }
+
+    // #MC is an abort without an error code.
+    // See: Interrupt 18—Machine-Check Exception (#MC)
+    let mc = Event::Exception {
+        vector: 18,
+        error_code: None,
+    };
+    if let Err(err) = event::inject_event(vcpu, mc) {
+        panic!("Could not inject #MC: {err}");
+    }
+}
+
+/// Logs IA32_MCG_STATUS and the errors in all banks of the current processor.
+pub(crate) fn log_errors() {
+    // See: Table 1-22. More on Feature Information Returned in the EDX Register
+    const MCE_BIT: u32 = 1 << 7;
+    const MCA_BIT: u32 = 1 << 14;
+
+    if cpuid!(1).edx & (MCE_BIT | MCA_BIT) != MCE_BIT | MCA_BIT {
+        log::error!("The machine-check architecture is unsupported");
+        return;
+    }
+
+    let mcg_status = rdmsr(x86::msr::IA32_MCG_STATUS);
+    log::error!(
+        "IA32_MCG_STATUS: {mcg_status:#x} ({})",
+        describe_mcg_status(mcg_status)
+    );
+    for error in read_errors() {
+        log::error!(
+            "Bank {}: MCi_STATUS: {:#x} ({}), MCi_ADDR: {:#x?}, MCi_MISC: {:#x?}",
+            error.bank,
+            error.status,
+            describe_status(error.status),
+            error.addr,
+            error.misc
+        );
+    }
+}
+
+/// Returns the errors in the banks of the current processor.
+// See: 17.3 MACHINE-CHECK MSRS
+fn read_errors() -> Vec<BankError> {
+    const IA32_MC0_STATUS: u32 = 0x401;
+    const IA32_MC0_ADDR: u32 = 0x402;
+    const IA32_MC0_MISC: u32 = 0x403;
+
+    let count = rdmsr(x86::msr::IA32_MCG_CAP).get_bits(0..=7) as u32;
+    (0..count)
+        .filter_map(|bank| {
+            let status = rdmsr(IA32_MC0_STATUS + bank * 4);
+            if !status.get_bit(63) {
+                return None;
+            }
+            Some(BankError {
+                bank,
+                status,
+                addr: status.get_bit(58).then(|| rdmsr(IA32_MC0_ADDR + bank * 4)),
+                misc: status.get_bit(59).then(|| rdmsr(IA32_MC0_MISC + bank * 4)),
+            })
+        })
+        .collect()
+}
+
+/// Returns the human readable description of IA32_MCG_STATUS.
+// See: Figure 17-3. IA32_MCG_STATUS Register
+fn describe_mcg_status(mcg_status: u64) -> String {
+    let flags = [(0, "RIPV"), (1, "EIPV"), (2, "MCIP"), (3, "LMCE_S")];
+    describe_flags(mcg_status, &flags)
+}
+
+/// Returns the human readable description of MCi_STATUS.
+// See: Figure 17-6. IA32_MCi_STATUS Register
+fn describe_status(status: u64) -> String {
+    let flags = [
+        (62, "OVER"),
+        (61, "UC"),
+        (60, "EN"),
+        (57, "PCC"),
+        (56, "S"),
+        (55, "AR"),
+    ];
+    let mut description = describe_flags(status, &flags);
+    if !description.is_empty() {
+        description.push_str(", ");
+    }
+    description.push_str(&format!(
+        "MCA error code {:#x}, model-specific error code {:#x}",
+        status.get_bits(0..=15),
+        status.get_bits(16..=31)
+    ));
+    description
+}
+
+/// Returns the names of `flags` set in `value`, separated with commas.
+fn describe_flags(value: u64, flags: &[(usize, &str)]) -> String {
+    flags
+        .iter()
+        .filter(|(bit, _)| value.get_bit(*bit))
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_are_described() {
+        assert_eq!(describe_mcg_status(0b101), "RIPV, MCIP");
+        assert_eq!(describe_mcg_status(0), "");
+        assert_eq!(
+            describe_status(0xb200_0000_0001_0135),
+            "UC, EN, PCC, MCA error code 0x135, model-specific error code 0x1"
+        );
+        assert_eq!(
+            describe_status(0x8000_0000_0000_0005),
+            "MCA error code 0x5, model-specific error code 0x0"
+        );
+    }
+}
@@ -0,0 +1,476 @@
+//! This module implements protection of guest physical memory through the EPT
+//! (Intel) or NPT (AMD). The embedder of this crate limits the access the guest
+//! is permitted to guest physical ranges, and a handler is called on its
+//! attempt of any other access. This is the building block of code integrity
+//! monitoring and self-protection.
+//!
+//! This module only maintains the set of protected ranges and is vendor
+//! agnostic. As with the hooks, each processor picks up changes on the next
+//! VM-exit by comparing the generation of the protections with the one it last
+//! applied. Pages in protected ranges are mapped with 4KB pages, and protection
+//! of a page is suspended while the page is hooked with `ept_hook`.
+//!
+//! ```ignore
+//! // Deliver #GP instead of letting the guest write to the kernel code.
+//! protect_gpa_range(code_pa, code_len, Permissions::READ_EXECUTE, |vcpu, info| {
+//!     log::warn!("Blocked write to {:#x?}", info.gpa);
+//!     let gp = Event::Exception { vector: 13, error_code: Some(0) };
+//!     let _ = event::inject_event(vcpu, gp);
+//!     ViolationAction::Resume
+//! })?;
+//! ```
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use bit_field::BitField;
+use spin::{Mutex, MutexGuard};
+use x86::bits64::paging::{BASE_PAGE_SIZE, HUGE_PAGE_SIZE};
+
+use crate::hypervisor::{
+    event::{self, Event},
+    hidden_memory,
+    host::{NestedPageFaultInfo, Vcpu},
+    instruction_decoder::InstructionError,
+    x86_instructions::rdmsr,
+};
+
+/// The types of access the guest is permitted to a protected range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Permissions {
+    /// Whether the guest may read the range.
+    pub read: bool,
+    /// Whether the guest may write to the range. Requires `read`.
+    pub write: bool,
+    /// Whether the guest may execute the range.
+    pub execute: bool,
+}
+
+impl Permissions {
+    /// No access is permitted.
+    pub const NONE: Self = Self::new(false, false, false);
+
+    /// Only reads are permitted.
+    pub const READ_ONLY: Self = Self::new(true, false, false);
+
+    /// Reads and writes are permitted, but not execution.
+    pub const READ_WRITE: Self = Self::new(true, true, false);
+
+    /// Reads and execution are permitted, but not writes.
+    pub const READ_EXECUTE: Self = Self::new(true, false, true);
+
+    /// Only execution is permitted. Supported only on Intel processors
+    /// supporting execute-only translations.
+    pub const EXECUTE_ONLY: Self = Self::new(false, false, true);
+
+    /// Any access is permitted.
+    pub const ALL: Self = Self::new(true, true, true);
+
+    const fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+        }
+    }
+
+    /// Returns whether the access described by `info` is permitted.
+    pub(crate) fn permits(self, info: &NestedPageFaultInfo) -> bool {
+        if info.execute {
+            self.execute
+        } else if info.write {
+            self.write
+        } else {
+            self.read
+        }
+    }
+
+    /// Returns whether the nested paging of the current processor can express
+    /// the permissions.
+    pub(crate) fn check_supported(self) -> Result<(), ProtectionError> {
+        const EFER_NXE: u64 = 1 << 11;
+
+        // Neither EPT nor NPT can express write-only pages.
+        if self.write && !self.read {
+            return Err(ProtectionError::InvalidPermissions(self));
+        }
+
+        let is_intel =
+            x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+        let supported = if is_intel {
+            // "Bit 0 (...) If this bit is 1, the processor supports execute-only
+            //  translations by EPT."
+            // See: A.10 VPID AND EPT CAPABILITIES
+            self != Self::EXECUTE_ONLY || rdmsr(x86::msr::IA32_VMX_EPT_VPID_CAP).get_bit(0)
+        } else {
+            // NPT can express only readable pages, which are non-executable
+            // only with the NX bit. The NX bit is reserved unless the host
+            // enables it.
+            // See: 15.25.5 Nested Table Walk
+            self != Self::EXECUTE_ONLY
+                && (!self.read || self.execute || rdmsr(x86::msr::IA32_EFER) & EFER_NXE != 0)
+        };
+        if !supported {
+            return Err(ProtectionError::Unsupported(self));
+        }
+        Ok(())
+    }
+}
+
+/// Represents a handler called when the guest attempts access a protected range
+/// does not permit. Handlers run in the host context with interrupts disabled.
+/// They must not call any platform API and should return as soon as possible.
+pub type ViolationHandler =
+    dyn Fn(&mut dyn Vcpu, &NestedPageFaultInfo) -> ViolationAction + Send + Sync;
+
+/// What the host should do after a violation handler returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ViolationAction {
+    /// Let the guest complete the access. The page is unprotected only for
+    /// the processor while the guest single-steps the instruction, and stays
+    /// protected on the other processors.
+    Allow,
+
+    /// Resume the guest without permitting the access. The guest retries it
+    /// unless the handler injected an event or changed the protection.
+    Resume,
+
+    /// Resume the guest after the instruction, discarding the access. #GP is
+    /// delivered if the instruction cannot be decoded.
+    Skip,
+
+    /// Complete the access on behalf of the guest and resume it after the
+    /// instruction with `instruction_decoder::emulate`, without
+    /// single-stepping. #GP is delivered if the instruction cannot be decoded
+    /// or emulated, such as `REP MOVS`, in which case the access is not
+    /// completed.
+    Emulate,
+}
+
+/// The errors protection of ranges may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtectionError {
+    #[error("{start:#x?} and {len:#x?} are not page aligned, or `len` is zero")]
+    Misaligned { start: u64, len: u64 },
+
+    #[error("{len:#x?} bytes at {start:#x?} are not in the first 512GB")]
+    OutOfRange { start: u64, len: u64 },
+
+    #[error("{len:#x?} bytes at {start:#x?} overlap with a protected range")]
+    Overlaps { start: u64, len: u64 },
+
+    #[error("{0:?} cannot be used for protection")]
+    InvalidPermissions(Permissions),
+
+    #[error("{0:?} is not supported by the processor")]
+    Unsupported(Permissions),
+
+    #[error("no protected range starts at {start:#x?}")]
+    NotProtected { start: u64 },
+
+    #[error("the heap is hidden from the guest")]
+    HostMemoryHidden,
+}
+
+/// Protects `len` bytes of guest physical memory at `start` so that the guest
+/// may access them only as `permissions`, and calls `handler` on its attempt of
+/// any other access. Changes take effect on each processor on the next VM-exit
+/// on that processor.
+///
+/// The guest code calling this must not access the protected range while that
+/// is not permitted, as violations cannot be handled until the call returns.
+///
+/// # Errors
+///
+/// Returns `Err` if the range is not page aligned, is outside the first 512GB,
+/// or overlaps with another protected range, or if `permissions` is not
+/// supported. Returns `HostMemoryHidden` if called from the guest after the
+/// heap is hidden with `SharedHostData::hide_host_memory`.
+pub fn protect_gpa_range(
+    start: u64,
+    len: u64,
+    permissions: Permissions,
+    handler: impl Fn(&mut dyn Vcpu, &NestedPageFaultInfo) -> ViolationAction + Send + Sync + 'static,
+) -> Result<(), ProtectionError> {
+    if hidden_memory::is_hidden_from_caller() {
+        return Err(ProtectionError::HostMemoryHidden);
+    }
+    protect(
+        &mut PROTECTIONS.lock(),
+        start,
+        len,
+        permissions,
+        Arc::new(handler),
+    )
+}
+
+/// Does the same as `protect_gpa_range` from the host. Returns `None` without
+/// protecting the range if the guest on any processor owns the lock, as the
+/// owner may be on the same processor.
+pub(crate) fn try_protect_gpa_range(
+    start: u64,
+    len: u64,
+    permissions: Permissions,
+    handler: impl Fn(&mut dyn Vcpu, &NestedPageFaultInfo) -> ViolationAction + Send + Sync + 'static,
+) -> Option<Result<(), ProtectionError>> {
+    let mut protections = try_protections()?;
+    Some(protect(
+        &mut protections,
+        start,
+        len,
+        permissions,
+        Arc::new(handler),
+    ))
+}
+
+fn protect(
+    protections: &mut Protections,
+    start: u64,
+    len: u64,
+    permissions: Permissions,
+    handler: Arc<ViolationHandler>,
+) -> Result<(), ProtectionError> {
+    let page_mask = BASE_PAGE_SIZE as u64 - 1;
+    if len == 0 || start & page_mask != 0 || len & page_mask != 0 {
+        return Err(ProtectionError::Misaligned { start, len });
+    }
+    let end = start
+        .checked_add(len)
+        .filter(|&end| end <= HUGE_PAGE_SIZE as u64 * 512)
+        .ok_or(ProtectionError::OutOfRange { start, len })?;
+    permissions.check_supported()?;
+
+    if protections.overlaps(start, end) {
+        return Err(ProtectionError::Overlaps { start, len });
+    }
+    let _ = protections.ranges.insert(
+        start,
+        Protection {
+            end,
+            permissions,
+            handler,
+        },
+    );
+
+    log::debug!("Protected {len:#x?} bytes at {start:#x?} as {permissions:?}");
+    let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+    Ok(())
+}
+
+/// Unprotects the range protected with `protect_gpa_range` at `start`.
+///
+/// # Errors
+///
+/// Returns `Err` if no protected range starts at `start`, or
+/// `HostMemoryHidden` as `protect_gpa_range` does.
+pub fn unprotect_gpa_range(start: u64) -> Result<(), ProtectionError> {
+    if hidden_memory::is_hidden_from_caller() {
+        return Err(ProtectionError::HostMemoryHidden);
+    }
+    unprotect(&mut PROTECTIONS.lock(), start)
+}
+
+/// Does the same as `unprotect_gpa_range` from the host. Returns `None` as
+/// `try_protect_gpa_range` does.
+pub(crate) fn try_unprotect_gpa_range(start: u64) -> Option<Result<(), ProtectionError>> {
+    let mut protections = try_protections()?;
+    Some(unprotect(&mut protections, start))
+}
+
+fn unprotect(protections: &mut Protections, start: u64) -> Result<(), ProtectionError> {
+    if protections.ranges.remove(&start).is_none() {
+        return Err(ProtectionError::NotProtected { start });
+    }
+
+    log::debug!("Unprotected the range at {start:#x?}");
+    let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+    Ok(())
+}
+
+/// Returns the current generation of the protections. It is incremented every
+/// time a range is protected or unprotected.
+pub(crate) fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Returns the protections if its lock is available. This is used from the
+/// host, where spinning on the lock could deadlock with the guest on the same
+/// processor that already owns it.
+pub(crate) fn try_protections() -> Option<MutexGuard<'static, Protections>> {
+    PROTECTIONS.try_lock()
+}
+
+/// Calls the handler of the protected range the guest violated as described
+/// by `info`. Returns `None` if the access is not to any protected range.
+pub(crate) fn handle_violation(
+    vcpu: &mut dyn Vcpu,
+    info: &NestedPageFaultInfo,
+) -> Option<ViolationAction> {
+    // Let the guest retry the access if the guest on any processor owns the
+    // lock.
+    let Some(protections) = try_protections() else {
+        return Some(ViolationAction::Resume);
+    };
+    let protection = protections.find(info.gpa)?;
+
+    // The access may be permitted already if the protection changed but this
+    // processor has not applied it yet. It will be on the next VM-entry.
+    if protection.permissions.permits(info) {
+        return Some(ViolationAction::Resume);
+    }
+
+    // Release the lock so that the handler can change the protections.
+    let handler = protection.handler.clone();
+    drop(protections);
+    Some(handler(vcpu, info))
+}
+
+/// Delivers #GP if `result` of skipping or emulating the instruction for
+/// `ViolationAction::Skip` or `ViolationAction::Emulate` is `Err`.
+pub(crate) fn complete(vcpu: &mut dyn Vcpu, result: Result<(), InstructionError>) {
+    if let Err(err) = result {
+        log::warn!(
+            "Cannot complete the access at {:#x?}: {err}",
+            vcpu.regs().rip
+        );
+        let gp = Event::Exception {
+            vector: 13,
+            error_code: Some(0),
+        };
+        if let Err(err) = event::inject_event(vcpu, gp) {
+            log::error!("Could not inject #GP: {err}");
+        }
+    }
+}
+
+/// The collection of protected ranges.
+pub(crate) struct Protections {
+    /// The protected ranges keyed by their start addresses.
+    ranges: BTreeMap<u64, Protection>,
+}
+
+struct Protection {
+    end: u64,
+    permissions: Permissions,
+    handler: Arc<ViolationHandler>,
+}
+
+impl Protections {
+    const fn new() -> Self {
+        Self {
+            ranges: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the permissions of each protected page, keyed by the addresses
+    /// of the pages.
+    pub(crate) fn pages(&self) -> BTreeMap<u64, Permissions> {
+        self.ranges
+            .iter()
+            .flat_map(|(&start, protection)| {
+                (start..protection.end)
+                    .step_by(BASE_PAGE_SIZE)
+                    .map(|gpa| (gpa, protection.permissions))
+            })
+            .collect()
+    }
+
+    /// Returns the protected range containing `gpa`, if any.
+    fn find(&self, gpa: u64) -> Option<&Protection> {
+        self.ranges
+            .range(..=gpa)
+            .next_back()
+            .map(|(_, protection)| protection)
+            .filter(|protection| gpa < protection.end)
+    }
+
+    /// Returns whether `start..end` overlaps with any protected range.
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.ranges
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, protection)| start < protection.end)
+    }
+}
+
+impl core::fmt::Debug for Protections {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_map()
+            .entries(
+                self.ranges
+                    .iter()
+                    .map(|(&start, protection)| (start..protection.end, protection.permissions)),
+            )
+            .finish()
+    }
+}
+
+/// Returns whether the protections are locked by any processor. See
+/// `host::fail_open`.
+pub(crate) fn is_locked() -> bool {
+    PROTECTIONS.is_locked()
+}
+
+static PROTECTIONS: Mutex<Protections> = Mutex::new(Protections::new());
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protections(ranges: &[(u64, u64, Permissions)]) -> Protections {
+        let mut protections = Protections::new();
+        for &(start, end, permissions) in ranges {
+            let _ = protections.ranges.insert(
+                start,
+                Protection {
+                    end,
+                    permissions,
+                    handler: Arc::new(|_, _| ViolationAction::Resume),
+                },
+            );
+        }
+        protections
+    }
+
+    #[test]
+    fn ranges() {
+        let protections = protections(&[
+            (0x1000, 0x3000, Permissions::READ_ONLY),
+            (0x8000, 0x9000, Permissions::NONE),
+        ]);
+        assert!(protections.find(0xfff).is_none());
+        assert!(protections.find(0x1000).is_some());
+        assert!(protections.find(0x2fff).is_some());
+        assert!(protections.find(0x3000).is_none());
+        assert!(protections.find(0x8800).is_some());
+
+        assert!(protections.overlaps(0x0, 0x2000));
+        assert!(protections.overlaps(0x2000, 0x9000));
+        assert!(!protections.overlaps(0x0, 0x1000));
+        assert!(!protections.overlaps(0x3000, 0x8000));
+
+        let pages = protections.pages();
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[&0x2000], Permissions::READ_ONLY);
+        assert_eq!(pages[&0x8000], Permissions::NONE);
+    }
+
+    #[test]
+    fn permits() {
+        let access = |write, execute| NestedPageFaultInfo {
+            gpa: 0,
+            write,
+            execute,
+        };
+        let read = access(false, false);
+        let write = access(true, false);
+        let execute = access(false, true);
+        assert!(Permissions::READ_ONLY.permits(&read));
+        assert!(!Permissions::READ_ONLY.permits(&write));
+        assert!(!Permissions::READ_ONLY.permits(&execute));
+        assert!(Permissions::READ_EXECUTE.permits(&execute));
+        assert!(!Permissions::EXECUTE_ONLY.permits(&read));
+        assert!(Permissions::ALL.permits(&write));
+    }
+}
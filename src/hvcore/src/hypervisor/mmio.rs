@@ -0,0 +1,151 @@
+//! This module implements emulation of memory-mapped devices. The embedder of
+//! this crate registers a guest physical range with an [`MmioDevice`], and the
+//! guest accesses to the range are passed to the device instead of memory.
+//! This lets the hypervisor provide small devices to the guest, such as a
+//! debug console or a watchdog.
+//!
+//! The range is protected with `memory_protection` so that no access is
+//! permitted. The instruction causing the violation is decoded with
+//! `instruction_decoder`, and the access is completed with the value the
+//! device returns or takes, then the guest resumes after the instruction. Only
+//! the forms of `MOV` and `MOVZX` `instruction_decoder` can emulate are
+//! supported. The guest receives #GP for any other instruction, including
+//! execution of the range.
+//!
+//! The range should not be memory the guest uses, such as a range beyond the
+//! end of RAM, as reads never reach the memory and writes never change it.
+//!
+//! ```ignore
+//! struct DebugConsole;
+//! impl MmioDevice for DebugConsole {
+//!     fn read(&self, _vcpu: &mut dyn Vcpu, _offset: u64, _size: u8) -> u64 {
+//!         0
+//!     }
+//!     fn write(&self, _vcpu: &mut dyn Vcpu, _offset: u64, _size: u8, value: u64) {
+//!         log::info!("{}", value as u8 as char);
+//!     }
+//! }
+//! register_mmio_device(0x80_0000_0000 - 0x1000, 0x1000, DebugConsole)?;
+//! ```
+
+use alloc::{collections::BTreeSet, sync::Arc};
+use spin::Mutex;
+
+use crate::hypervisor::{
+    host::{NestedPageFaultInfo, Vcpu},
+    instruction_decoder::{self, InstructionError, MemoryAccess},
+    memory_protection::{self, Permissions, ProtectionError, ViolationAction},
+};
+
+/// Represents a memory-mapped device.
+///
+/// Devices run in the host context with interrupts disabled. They must not
+/// call any platform API and should return as soon as possible.
+pub trait MmioDevice: Send + Sync {
+    /// Returns the value of `size` bytes the guest reads at `offset` from the
+    /// start of the range. Only the low `size` bytes are used.
+    fn read(&self, vcpu: &mut dyn Vcpu, offset: u64, size: u8) -> u64;
+
+    /// Handles the write of the low `size` bytes of `value` at `offset` from
+    /// the start of the range.
+    fn write(&self, vcpu: &mut dyn Vcpu, offset: u64, size: u8, value: u64);
+}
+
+/// The errors registration of devices may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmioError {
+    #[error("the range cannot be protected: {0}")]
+    Protection(ProtectionError),
+
+    #[error("no device is registered at {start:#x?}")]
+    NotRegistered { start: u64 },
+}
+
+/// Registers `device` for `len` bytes of guest physical memory at `start`.
+/// Changes take effect on each processor on the next VM-exit on that processor.
+///
+/// # Errors
+///
+/// Returns `Protection` if the range cannot be protected. See
+/// `memory_protection::protect_gpa_range`.
+pub fn register_mmio_device(
+    start: u64,
+    len: u64,
+    device: impl MmioDevice + 'static,
+) -> Result<(), MmioError> {
+    let device: Arc<dyn MmioDevice> = Arc::new(device);
+    let mut devices = DEVICES.lock();
+    memory_protection::protect_gpa_range(start, len, Permissions::NONE, move |vcpu, info| {
+        handle_access(vcpu, device.as_ref(), info.gpa - start, info)
+    })
+    .map_err(MmioError::Protection)?;
+    let _ = devices.insert(start);
+
+    log::debug!("Registered the device for {len:#x?} bytes at {start:#x?}");
+    Ok(())
+}
+
+/// Unregisters the device registered with `register_mmio_device` at `start`.
+/// The range is ordinary memory afterwards.
+///
+/// # Errors
+///
+/// Returns `NotRegistered` if no device is registered at `start`.
+pub fn unregister_mmio_device(start: u64) -> Result<(), MmioError> {
+    let mut devices = DEVICES.lock();
+    if !devices.remove(&start) {
+        return Err(MmioError::NotRegistered { start });
+    }
+    memory_protection::unprotect_gpa_range(start).map_err(MmioError::Protection)?;
+
+    log::debug!("Unregistered the device at {start:#x?}");
+    Ok(())
+}
+
+/// Handles the guest access to `offset` of the range of `device`.
+fn handle_access(
+    vcpu: &mut dyn Vcpu,
+    device: &dyn MmioDevice,
+    offset: u64,
+    info: &NestedPageFaultInfo,
+) -> ViolationAction {
+    let result = emulate(vcpu, device, offset, info);
+    memory_protection::complete(vcpu, result);
+    ViolationAction::Resume
+}
+
+/// Emulates the instruction at the guest RIP with `device` and advances RIP
+/// past it.
+fn emulate(
+    vcpu: &mut dyn Vcpu,
+    device: &dyn MmioDevice,
+    offset: u64,
+    info: &NestedPageFaultInfo,
+) -> Result<(), InstructionError> {
+    if info.execute {
+        return Err(InstructionError::Unsupported);
+    }
+
+    let instruction = instruction_decoder::fetch(vcpu)?;
+    match instruction.access(vcpu.regs()) {
+        Some(MemoryAccess::Read { size }) if !info.write => {
+            let value = device.read(vcpu, offset, size);
+            instruction.complete_read(vcpu.regs(), value);
+        }
+        Some(MemoryAccess::Write { size, value }) if info.write => {
+            device.write(vcpu, offset, size, value);
+        }
+        _ => return Err(InstructionError::Unsupported),
+    }
+    instruction_decoder::advance(vcpu, &instruction);
+    Ok(())
+}
+
+/// Returns whether the devices are locked by any processor. See
+/// `host::fail_open`.
+pub(crate) fn is_locked() -> bool {
+    DEVICES.is_locked()
+}
+
+/// The start addresses of the ranges devices are registered for.
+static DEVICES: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
@@ -2,70 +2,583 @@
 
 #[cfg(not(test))]
 pub mod allocator;
+#[cfg(feature = "amd")]
 mod amd;
 mod apic_id;
+pub mod apic_virt;
+pub mod breakpoint_marker;
+pub mod cache_control;
+pub mod capabilities;
+mod cet;
+pub mod cpuid_policy;
+pub mod cr3_tracking;
+pub mod cr_intercepts;
+pub mod deferred_work;
+pub mod dirty_tracking;
+pub mod ept_hook;
+pub mod event;
+pub mod event_channel;
+pub mod exit_handlers;
+pub mod exit_stats;
+mod exit_trace;
+pub mod foreign_hypervisor;
 pub mod gdt_tss;
+pub mod guest_memory;
+pub mod guest_os;
+mod hidden_memory;
 mod host;
+mod host_window;
+pub mod hw_breakpoint;
+pub mod hypercall;
+mod hyperv;
+pub mod instruction_decoder;
+pub mod integrity;
+#[cfg(feature = "intel")]
 mod intel;
 pub mod interrupt_handlers;
+pub mod interrupt_virtualization;
+pub mod io_intercepts;
+pub mod ipi;
+mod kvm_clock;
+mod log_buffer;
+mod logger;
+mod long_mode;
+mod machine_check;
+pub mod memory_protection;
+pub mod mmio;
+pub mod msr_intercepts;
+mod mtrr;
 pub mod paging_structures;
 pub mod panic;
+mod percpu;
 pub mod platform_ops;
+pub mod power;
+pub mod preemption_timer;
 mod registers;
 mod segment;
-mod serial_logger;
+pub mod self_test;
+pub mod serial_logger;
+pub mod single_step;
+pub mod smm;
+pub mod snapshot;
 mod support;
 mod switch_stack;
+pub mod syscall_protection;
+#[cfg(test)]
+mod test_support;
+pub mod tlb;
+pub mod tsc;
+pub mod virtualization_exception;
+pub mod watchdog;
+pub mod windows_kernel;
 mod x86_instructions;
+mod xsave;
 
-use alloc::vec::Vec;
-use spin::Once;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use alloc::{boxed::Box, collections::BTreeSet, vec::Vec};
+use spin::{Mutex, Once};
 use x86::cpuid::cpuid;
 
-use crate::{hypervisor::registers::Registers, GdtTss, PagingStructures};
+use crate::{GdtTss, PagingStructures};
+
+use self::{
+    apic_virt::ApicVirtualization,
+    cache_control::CacheInvalidationPolicy,
+    capabilities::HvCapabilities,
+    cpuid_policy::CpuidPolicy,
+    cr3_tracking::Cr3Tracking,
+    cr_intercepts::CrIntercepts,
+    exit_handlers::{ExitHandler, ExitHandlers, ExitReason},
+    foreign_hypervisor::{ForeignHypervisor, ForeignHypervisorPolicy},
+    interrupt_handlers::InterruptDescriptorTable,
+    interrupt_virtualization::InterruptVirtualization,
+    io_intercepts::IoIntercepts,
+    msr_intercepts::MsrIntercepts,
+    power::SleepDetection,
+    preemption_timer::PreemptionTimer,
+    registers::ExtendedRegisters,
+    serial_logger::SerialConfig,
+    tsc::TscConfig,
+    watchdog::Watchdog,
+};
+
+pub use self::{
+    host::{
+        CrWriteInfo, DrAccessInfo, GpaMapping, GuestSegment, InstructionInfo, IoInstructionInfo,
+        NestedPageFaultInfo, SegmentRegister, Vcpu, VmExitReason,
+    },
+    registers::{Registers, Xmm},
+};
+
+/// The reasons the system cannot be virtualized.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VirtError {
+    #[error("the processor is neither Intel nor AMD, or its support is not compiled in")]
+    UnsupportedVendor,
+
+    #[error("the processor does not support VMX")]
+    VmxUnsupported,
+
+    #[error("VMX is disabled and locked in IA32_FEATURE_CONTROL by the BIOS")]
+    VmxDisabledByBios,
+
+    #[error("the processor does not support SVM")]
+    SvmUnsupported,
+
+    #[error("SVM is disabled in VM_CR by the BIOS")]
+    SvmDisabledByBios,
+
+    #[error("the processor does not support features the hypervisor requires")]
+    MissingFeatures,
+
+    #[error("{0} is running, which `SharedHostData::foreign_hypervisor` refuses")]
+    ForeignHypervisor(ForeignHypervisor),
+
+    #[error("{0} is running without exposing the virtualization extension. Disable it, or VBS, HVCI and Credential Guard on Windows")]
+    ForeignHypervisorWithoutExtension(ForeignHypervisor),
+
+    #[error("{0} processors may be enumerated, more than the host window supports")]
+    TooManyProcessors(usize),
+
+    #[error("5-level paging is enabled, which the host window does not support")]
+    FiveLevelPaging,
+}
 
-use self::interrupt_handlers::InterruptDescriptorTable;
+/// The errors the hypervisor may return while setting up.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HvError {
+    #[error("{0}")]
+    Unsupported(VirtError),
+
+    #[error("the hypervisor heap is exhausted")]
+    OutOfMemory,
+
+    #[error("{0} failed")]
+    InstructionFailed(&'static str),
+
+    #[error("the processor with APIC ID {0} is not enumerated")]
+    UnknownProcessor(u32),
+
+    #[error("the host GDT or TSS for the processor {0} is invalid")]
+    InvalidHostGdt(usize),
+
+    #[error("the system has never been virtualized")]
+    NotInitialized,
+
+    #[error("no processor slot is left for the processor with APIC ID {0}")]
+    NoProcessorSlot(u32),
+
+    #[error("the heap is hidden from the guest")]
+    HostMemoryHidden,
+
+    #[error("the hypervisor is shut down")]
+    ShutDown,
+
+    #[error("no PML4 entry in the upper half is unused for the host window")]
+    NoHostWindowEntry,
+
+    #[error("{0} is invalid")]
+    InvalidConfig(&'static str),
+}
+
+impl From<VirtError> for HvError {
+    fn from(value: VirtError) -> Self {
+        Self::Unsupported(value)
+    }
+}
 
 /// Hyperjacks the current system by virtualizing all logical processors on this
 /// system.
-pub fn virtualize_system(shared_host: SharedHostData) {
-    serial_logger::init(log::LevelFilter::Info);
+///
+/// # Errors
+///
+/// Returns `Unsupported` describing why if the processor cannot be virtualized.
+/// All processors are assumed to support the same features as the current one.
+/// Returns `InvalidConfig` naming the field of `shared_host` that is invalid,
+/// or if the system has been virtualized before. `SharedHostData` is fixed
+/// after the first call: use `revirtualize_system` to virtualize the system
+/// or processors again with it.
+/// Returns other errors if the hypervisor fails to set up on a processor. In
+/// either case, no processor is left virtualized by this call, as processors
+/// virtualized before the failing one are devirtualized.
+pub fn virtualize_system(shared_host: SharedHostData) -> Result<(), HvError> {
+    if SHUT_DOWN.load(Ordering::Relaxed) {
+        return Err(HvError::ShutDown);
+    }
+    if SHARED_HOST_DATA.get().is_some() {
+        return Err(HvError::InvalidConfig("a second `SharedHostData`"));
+    }
+    if !shared_host.tsc.is_valid() {
+        return Err(HvError::InvalidConfig("`TscConfig::scale`"));
+    }
+    logger::init(
+        shared_host.log_level.unwrap_or(log::LevelFilter::Info),
+        shared_host.serial_log.as_ref(),
+    );
+    let foreign = match foreign_hypervisor::check(shared_host.foreign_hypervisor) {
+        Ok(foreign) => foreign,
+        Err(e) => {
+            log::error!("Cannot virtualize the system: {e}");
+            return Err(e.into());
+        }
+    };
+    match HvCapabilities::detect() {
+        Ok(capabilities) => log::info!("{capabilities}"),
+        Err(e) => log::warn!("Could not detect the capabilities: {e}"),
+    }
     log::info!("Virtualizing the all processors");
 
-    apic_id::init();
-    let _ = SHARED_HOST_DATA.call_once(|| shared_host);
+    #[cfg(not(test))]
+    allocator::grow(shared_host.heap_size)?;
+    apic_id::init(shared_host.hotplug_slots);
+    let mut shared_host = shared_host;
+    update_host_pt(&mut shared_host)?;
+    let _ = SHARED_HOST_DATA.call_once(|| {
+        if shared_host.stealth {
+            shared_host.cpuid_policy = shared_host.cpuid_policy.hide_hypervisor();
+            shared_host.tsc.hide_exit_overhead = true;
+        }
+        if shared_host.syscall_protection {
+            shared_host.msr_intercepts = syscall_protection::install(shared_host.msr_intercepts);
+        }
+        if shared_host.apic_virt.is_enabled() {
+            shared_host.msr_intercepts = apic_virt::install(shared_host.msr_intercepts);
+        }
+        if let Some(foreign) = foreign {
+            shared_host.cpuid_policy =
+                foreign_hypervisor::install(foreign, shared_host.cpuid_policy);
+        }
+        if shared_host.hyperv_enlightenments && !shared_host.stealth && foreign.is_none() {
+            hyperv::install(&mut shared_host);
+        }
+        if shared_host.kvm_clock && !shared_host.stealth && foreign.is_none() {
+            kvm_clock::install(&mut shared_host);
+        }
+        #[cfg(feature = "intel")]
+        if shared_host.nested_virtualization
+            && !shared_host.stealth
+            && foreign.is_none()
+            && x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel"
+        {
+            intel::install_nested_virtualization(&mut shared_host);
+        }
+        #[cfg(feature = "amd")]
+        if shared_host.nested_virtualization
+            && !shared_host.stealth
+            && foreign.is_none()
+            && x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "AuthenticAMD"
+        {
+            amd::install_nested_virtualization(&mut shared_host);
+        }
+        // On AMD, APs the OS starts with INIT-SIPI-SIPI stay virtualized only
+        // if SIPIs are emulated, including those sent in x2APIC mode.
+        #[cfg(feature = "amd")]
+        if cfg!(feature = "uefi")
+            && x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "AuthenticAMD"
+        {
+            shared_host.msr_intercepts = amd::install_sipi_emulation(shared_host.msr_intercepts);
+        }
+        // On AMD, the preemption timer is emulated with the APIC timer, the
+        // registers of which the guest accesses through intercepted MSRs.
+        #[cfg(feature = "amd")]
+        if shared_host.preemption_timer.apic_timer_vector().is_some()
+            && x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "AuthenticAMD"
+        {
+            shared_host.msr_intercepts = amd::install_timer_emulation(shared_host.msr_intercepts);
+        }
+        shared_host.msr_intercepts =
+            xsave::install(shared_host.msr_intercepts, &shared_host.cpuid_policy);
+        shared_host.msr_intercepts = long_mode::install(shared_host.msr_intercepts);
+        if shared_host.sleep_detection.is_enabled() {
+            shared_host.io_intercepts =
+                power::install(shared_host.io_intercepts, &shared_host.sleep_detection);
+        }
+        shared_host
+    });
+    percpu::init(SHARED_HOST_DATA.get().unwrap());
+    host_window::init(SHARED_HOST_DATA.get().unwrap())?;
+    virtualize_processors()
+}
 
-    // Virtualize each logical processor.
+/// Updates `SharedHostData::pt`, if specified: maps the heap if it is built
+/// with `PagingStructures::build_minimal`, and makes the guard pages of the IST
+/// stacks in `SharedHostData::gdts` non-present. Then, checks the translations
+/// with `PagingStructures::self_check` in debug builds.
+fn update_host_pt(shared_host: &mut SharedHostData) -> Result<(), HvError> {
+    let Some(pt) = &mut shared_host.pt else {
+        return Ok(());
+    };
+    #[cfg(not(test))]
+    if pt.is_minimal() {
+        allocator::disable_donation();
+        for heap in allocator::heap_ranges() {
+            pt.map_range(heap.start as u64..heap.end as u64)?;
+        }
+    }
+    let guard_pages: Vec<u64> = shared_host
+        .gdts
+        .iter()
+        .flatten()
+        .flat_map(|gdt_tss| gdt_tss.guard_pages())
+        .collect();
+    for guard_page in &guard_pages {
+        // Safety: `pt` is owned by the hypervisor and not in use yet.
+        unsafe { paging_structures::set_page_present(pt.pml4_pa(), *guard_page, false)? };
+    }
+
+    // Verify the translations against the reference walker in debug builds,
+    // as the host would crash or silently access wrong memory otherwise.
+    if cfg!(debug_assertions) {
+        const SAMPLES: usize = 4096;
+        if let Err(e) = pt.self_check(&guard_pages, SAMPLES) {
+            panic!("The host paging structures are broken: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// Virtualizes the system again with the `SharedHostData` given to the first
+/// `virtualize_system`, for example, on resume from sleep that devirtualized
+/// the processors. See `power`. Only processors that are not yet virtualized
+/// are virtualized.
+///
+/// # Errors
+///
+/// Returns `NotInitialized` if `virtualize_system` has never been called, or
+/// the errors of `virtualize_system`.
+pub fn revirtualize_system() -> Result<(), HvError> {
+    if SHUT_DOWN.load(Ordering::Relaxed) {
+        return Err(HvError::ShutDown);
+    }
+    if SHARED_HOST_DATA.get().is_none() {
+        return Err(HvError::NotInitialized);
+    }
+    let policy = SHARED_HOST_DATA.get().unwrap().foreign_hypervisor;
+    if let Err(e) = foreign_hypervisor::check(policy) {
+        log::error!("Cannot virtualize the system: {e}");
+        return Err(e.into());
+    }
+    log::info!("Virtualizing the all processors again");
+    virtualize_processors()
+}
+
+/// Virtualizes each logical processor that is not yet with `SHARED_HOST_DATA`.
+fn virtualize_processors() -> Result<(), HvError> {
+    // Virtualize each logical processor. The first error, if any, is kept to
+    // be returned, and the processors virtualized by this call are tracked to
+    // roll them back on error. `run_on_all_processors` takes a function
+    // pointer. Pass them through the statics.
+    static FAILURE: Mutex<Option<HvError>> = Mutex::new(None);
+    static VIRTUALIZED: Mutex<BTreeSet<apic_id::ApicId>> = Mutex::new(BTreeSet::new());
+    VIRTUALIZED.lock().clear();
     platform_ops::get().run_on_all_processors(|| {
-        // Take a snapshot of current register values. This will be the initial
-        // state of the guest _including RIP_. This means that the guest starts execution
-        // right after this function call. Think of it as the setjmp() C standard
-        // function.
-        let registers = Registers::capture_current();
-
-        // In the first run, our hypervisor is not installed and the branch is
-        // taken. After starting the guest, the second run, the hypervisor is already
-        // installed and we will bail out.
-        if !is_our_hypervisor_present() {
-            log::info!("Virtualizing the current processor");
-
-            // We are about to execute host code with newly allocated stack.
-            // This is required because the guest will start executing with the
-            // current stack. If we do not change the stack for the host, as soon
-            // as the guest starts, it will smash host's stack.
-            switch_stack::jump_with_new_stack(host::main, &registers);
-        }
-        log::info!("Virtualized the current processor");
+        // Do not virtualize the rest once any processor failed.
+        if FAILURE.lock().is_some() {
+            return;
+        }
+        if let Err(error) = virtualize_current_processor(&VIRTUALIZED) {
+            let _ = FAILURE.lock().get_or_insert(error);
+        }
     });
 
+    // Roll back if any processor failed, instead of leaving the system
+    // partially virtualized.
+    if let Some(error) = FAILURE.lock().take() {
+        log::error!("Devirtualizing the processors virtualized so far: {error}");
+        platform_ops::get().run_on_all_processors(|| {
+            if VIRTUALIZED.lock().remove(&apic_id::get()) {
+                let _ = devirtualize_current_processor();
+            }
+        });
+        return Err(error);
+    }
+    if SHARED_HOST_DATA.get().unwrap().apic_virt.is_enabled() {
+        apic_virt::protect_xapic_page();
+    }
+    if SHARED_HOST_DATA.get().unwrap().hide_host_memory {
+        hidden_memory::request();
+    }
     log::info!("Virtualized the all processors");
+    Ok(())
+}
+
+/// Virtualizes the current logical processor if it is not yet, for example, one
+/// the OS brought online after `virtualize_system`. Must be called on that
+/// processor from the guest, after `virtualize_system` has succeeded. Unlike
+/// `virtualize_system`, this does not use `PlatformOps::run_on_all_processors`,
+/// so it can be called from the notification of the processor being brought
+/// online.
+///
+/// Processors that were not enumerated by `virtualize_system` take the slots
+/// reserved with `SharedHostData::hotplug_slots`, and their per-processor data
+/// structures are allocated on this call. If `SharedHostData::gdts` is
+/// specified, it must have the GDTs for those slots too.
+///
+/// # Errors
+///
+/// Returns `NotInitialized` if `virtualize_system` has never been called,
+/// `HostMemoryHidden` with `SharedHostData::hide_host_memory`, as the heap
+/// cannot be used anymore, `NoProcessorSlot` if no slot is left, or the
+/// errors of `virtualize_system`.
+pub fn virtualize_processor() -> Result<(), HvError> {
+    static VIRTUALIZED: Mutex<BTreeSet<apic_id::ApicId>> = Mutex::new(BTreeSet::new());
+
+    if SHUT_DOWN.load(Ordering::Relaxed) {
+        return Err(HvError::ShutDown);
+    }
+    let Some(shared_host) = SHARED_HOST_DATA.get() else {
+        return Err(HvError::NotInitialized);
+    };
+    if is_our_hypervisor_present() {
+        return Ok(());
+    }
+    if shared_host.hide_host_memory {
+        return Err(HvError::HostMemoryHidden);
+    }
+    host::check_support()?;
+
+    let apic_id = apic_id::get();
+    let id = apic_id::register_current().ok_or(HvError::NoProcessorSlot(apic_id))?;
+    percpu::allocate(id, apic_id, shared_host);
+    virtualize_current_processor(&VIRTUALIZED)
+}
+
+/// Virtualizes the current processor if it is not yet, tracking it in
+/// `virtualized` while it is virtualized by this call.
+fn virtualize_current_processor(
+    virtualized: &Mutex<BTreeSet<apic_id::ApicId>>,
+) -> Result<(), HvError> {
+    // Take a snapshot of current register values. This will be the initial
+    // state of the guest _including RIP_. This means that the guest starts execution
+    // right after this function call. Think of it as the setjmp() C standard
+    // function.
+    let registers = Registers::capture_current();
+
+    // If the host failed to set up, it resumes us here without the
+    // hypervisor, the second run. Free the host stack and bail out.
+    if let Some(error) = host::take_setup_error() {
+        switch_stack::free_stack();
+        let _ = virtualized.lock().remove(&apic_id::get());
+        return Err(error);
+    }
+
+    // In the first run, our hypervisor is not installed and the branch is
+    // taken. After starting the guest, the second run, the hypervisor is already
+    // installed and we will bail out. The extended registers are captured only
+    // in the first run, since the host takes them over, and capturing them
+    // again in the second run would allocate new ones.
+    if !is_our_hypervisor_present() {
+        let extended = SHARED_HOST_DATA
+            .get()
+            .unwrap()
+            .save_extended_registers
+            .then(ExtendedRegisters::capture_current);
+
+        log::info!("Virtualizing the current processor");
+
+        // We are about to execute host code with newly allocated stack.
+        // This is required because the guest will start executing with the
+        // current stack. If we do not change the stack for the host, as soon
+        // as the guest starts, it will smash host's stack. This returns only
+        // if the stack cannot be allocated.
+        let _ = virtualized.lock().insert(apic_id::get());
+        let error = switch_stack::jump_with_new_stack(host::main, &registers, extended.as_ref());
+        log::error!("Could not virtualize the current processor: {error}");
+        let _ = virtualized.lock().remove(&apic_id::get());
+        return Err(error);
+    }
+    log::info!("Virtualized the current processor");
+    Ok(())
+}
+
+/// Devirtualizes all logical processors on this system, undoing
+/// `virtualize_system`.
+///
+/// Per-processor data structures and host stacks are freed. Data structures
+/// shared across processors, such as the nested paging structures, are retained
+/// and reused if the system is virtualized again with `revirtualize_system`.
+/// Use `shut_down` instead before freeing the memory given to `allocator`.
+pub fn devirtualize_system() {
+    log::info!("Devirtualizing all processors");
+    platform_ops::get().run_on_all_processors(|| {
+        let _ = devirtualize_current_processor();
+    });
+    log::info!("Devirtualized all processors");
+}
+
+/// Devirtualizes all logical processors for good, before the memory given to
+/// `allocator` is freed.
+///
+/// The data structures shared across processors, such as `SharedHostData`,
+/// the nested paging structures and those of the hook manager, are retained in
+/// statics that cannot be reset. Once the memory is freed, they point to freed
+/// memory. Hence, the system cannot be virtualized again after this, and
+/// `virtualize_system`, `revirtualize_system` and `virtualize_processor`
+/// return `ShutDown`. Logging is disabled too, as the logger buffers messages
+/// in the heap. No other API of this crate may be used afterwards.
+pub fn shut_down() {
+    devirtualize_system();
+    SHUT_DOWN.store(true, Ordering::Relaxed);
+    log::set_max_level(log::LevelFilter::Off);
+}
+
+/// Whether `shut_down` is called.
+static SHUT_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Devirtualizes the logical processor with `apic_id`, leaving the others
+/// virtualized. Returns `false` if no such processor is virtualized.
+///
+/// The processor can be virtualized again with `revirtualize_system`, which
+/// only virtualizes processors that are not yet.
+pub fn devirtualize_processor(apic_id: u32) -> bool {
+    // `run_on_all_processors` takes a function pointer. Pass the parameter and
+    // result through the statics, serializing callers with the lock.
+    static TARGET_APIC_ID: AtomicU32 = AtomicU32::new(0);
+    static DEVIRTUALIZED: AtomicBool = AtomicBool::new(false);
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    let _guard = LOCK.lock();
+    TARGET_APIC_ID.store(apic_id, Ordering::Relaxed);
+    DEVIRTUALIZED.store(false, Ordering::Relaxed);
+    platform_ops::get().run_on_all_processors(|| {
+        if apic_id::get() == TARGET_APIC_ID.load(Ordering::Relaxed)
+            && devirtualize_current_processor()
+        {
+            DEVIRTUALIZED.store(true, Ordering::Relaxed);
+        }
+    });
+    DEVIRTUALIZED.load(Ordering::Relaxed)
+}
+
+/// Devirtualizes the current processor if it is virtualized by us. Returns
+/// whether it was devirtualized, which it is not while the guest is in VMX or
+/// SVM operation with `SharedHostData::nested_virtualization`. Unlike
+/// `devirtualize_processor`, this does not use
+/// `PlatformOps::run_on_all_processors`, so it can be called from the
+/// notification of the processor going offline.
+pub fn devirtualize_current_processor() -> bool {
+    if !is_our_hypervisor_present() {
+        return false;
+    }
+
+    // The host resumes us right after the hypercall, but without the hypervisor.
+    // Then, no code runs on the host stack. Free it.
+    if let Err(status) = hypercall::issue(hypercall::Hypercall::Devirtualize, 0, 0, 0) {
+        log::warn!("Could not devirtualize the current processor: {status:?}");
+        return false;
+    }
+    switch_stack::free_stack();
+    log::info!("Devirtualized the current processor");
+    true
 }
 
 /// A collection of data that the host depends on for its entire lifespan.
 #[derive(Debug, Default)]
 pub struct SharedHostData {
     /// The paging structures for the host. If `None`, the current paging
-    /// structure is used for both the host and the guest.
+    /// structure is used for both the host and the guest. See
+    /// `PagingStructures::build_minimal` to map only what the host uses.
     pub pt: Option<PagingStructures>,
 
     /// The IDT for the host. If `None`, the current IDT is used for both the
@@ -74,7 +587,169 @@ pub struct SharedHostData {
 
     /// The GDT and TSS for the host for each logical processor. If `None`,
     /// the current GDTs and TSSes are used for both the host and the guest.
+    /// With `idt`, the TSSes must have the stacks for NMIs, #DF and #MC. See
+    /// `GdtTssBuilder::host_exception_stacks`.
     pub gdts: Option<Vec<GdtTss>>,
+
+    /// The custom VM-exit handlers called before the built-in handlers.
+    pub exit_handlers: ExitHandlers,
+
+    /// The overrides of `CPUID` results presented to the guest.
+    pub cpuid_policy: CpuidPolicy,
+
+    /// The MSRs to intercept and their callbacks. Accesses to the other MSRs
+    /// are not intercepted where possible.
+    pub msr_intercepts: MsrIntercepts,
+
+    /// The I/O ports to intercept and their handlers. Accesses to the other
+    /// ports are not intercepted.
+    pub io_intercepts: IoIntercepts,
+
+    /// The bits of CR0 and CR4 to guard and the policies on writes changing
+    /// them. Writes to the other bits are not intercepted where possible.
+    pub cr_intercepts: CrIntercepts,
+
+    /// The observer of CR3 loads by the guest. If enabled, loads of CR3 cause
+    /// VM-exit except for the address spaces the observer ignores.
+    pub cr3_tracking: Cr3Tracking,
+
+    /// The observer of writes to the local APIC by the guest. See `apic_virt`.
+    /// If enabled, the handlers for the x2APIC MSRs in `msr_intercepts` are
+    /// replaced.
+    pub apic_virt: ApicVirtualization,
+
+    /// How to deliver external interrupts to the guest. If enabled, they are
+    /// delivered through the virtual APIC where supported. See
+    /// `interrupt_virtualization`.
+    pub interrupt_virtualization: InterruptVirtualization,
+
+    /// The configuration of the guest TSC.
+    pub tsc: TscConfig,
+
+    /// The period and the handler of the periodic VM-exit. See
+    /// `preemption_timer`. Ignored on AMD processors unless a vector for the
+    /// timer of the local APIC is set.
+    pub preemption_timer: PreemptionTimer,
+
+    /// Whether to switch the x87, SSE, AVX and AVX-512 registers between the
+    /// guest and the host on every VM-exit and VM-entry with `XSAVE`. Without
+    /// this, only XMM0-5 are switched, and host code using other vector
+    /// registers corrupts the guest values. Required if the host is compiled
+    /// to use those registers beyond XMM0-5 and the guest state must be kept
+    /// bit-exact.
+    pub save_extended_registers: bool,
+
+    /// Whether to make our hypervisor harder to detect from the guest. If
+    /// `true`, `cpuid_policy` is modified with `CpuidPolicy::hide_hypervisor`,
+    /// and `tsc.hide_exit_overhead` is set.
+    pub stealth: bool,
+
+    /// Whether to lock IA32_LSTAR and IA32_STAR and write-protect the system
+    /// call handler. See `syscall_protection`. If `true`, the handlers for
+    /// those MSRs in `msr_intercepts` are replaced.
+    pub syscall_protection: bool,
+
+    /// What to do when another hypervisor is already running, such as Hyper-V
+    /// with VBS on Windows. See `foreign_hypervisor`.
+    pub foreign_hypervisor: ForeignHypervisorPolicy,
+
+    /// Whether to expose the Hyper-V CPUID leaves, the reference TSC page and
+    /// a minimal set of synthetic MSRs, so that Windows uses the enlightened
+    /// timers instead of the legacy ones. See `hyperv`. If `true`, the
+    /// handlers for those MSRs in `msr_intercepts` are replaced. Ignored if
+    /// `stealth` is set or nesting under another hypervisor.
+    pub hyperv_enlightenments: bool,
+
+    /// Whether to expose the KVM CPUID leaves and the kvmclock MSRs, so that
+    /// Linux uses kvmclock as a stable clocksource. See `kvm_clock`. Ignored if
+    /// `stealth` is set or nesting under another hypervisor.
+    pub kvm_clock: bool,
+
+    /// Whether to report VMX or SVM to the guest and emulate their instructions,
+    /// so that a hypervisor such as Hyper-V or KVM can run in the guest. See
+    /// `intel::nested` and `amd::nested` for the limitations. Processors cannot
+    /// be devirtualized while the guest is in VMX or SVM operation. Ignored if
+    /// `stealth` is set or nesting under another hypervisor.
+    pub nested_virtualization: bool,
+
+    /// Whether to intercept SMIs on AMD processors to count them. See `smm`.
+    /// Ignored without `idt`, and on Intel processors, where SMIs are counted
+    /// regardless where possible.
+    pub smi_intercept: bool,
+
+    /// How to handle `INVD` and `WBINVD` executed by the guest. See
+    /// `cache_control`.
+    pub cache_invalidation: CacheInvalidationPolicy,
+
+    /// Whether to hide the memory of the hypervisor from the guest once all
+    /// processors are virtualized. The heap given to `allocator::init` and
+    /// `allocator::extend` is mapped to a read-only dummy page for the guest,
+    /// so that the guest cannot read or patch the data structures of the host,
+    /// such as stacks and VMCS. See `hidden_memory`. The guest cannot use the
+    /// heap afterwards: `HookManager` and `protect_gpa_range` return
+    /// `HostMemoryHidden` when called from the guest, and so does
+    /// `virtualize_processor`. They remain usable from the host, such as from
+    /// VM-exit handlers and through hypercalls. Processors can still be
+    /// devirtualized, as they see the heap again once devirtualized.
+    pub hide_host_memory: bool,
+
+    /// The serial port to write logs to in addition to the in-memory buffers,
+    /// which the guest can read with `Hypercall::ReadLog`. If `None`, logs are
+    /// not written to any serial port. Writing to the serial port is slow, and
+    /// may hang without the port.
+    pub serial_log: Option<SerialConfig>,
+
+    /// The maximum level of logs to write until changed with
+    /// `Hypercall::SetLogLevel`. If `None`, `Info`.
+    pub log_level: Option<log::LevelFilter>,
+
+    /// The number of the latest VM-exits to record for each processor, which
+    /// the panic handler dumps. Zero to record only the latest one.
+    pub exit_trace_len: usize,
+
+    /// Whether to devirtualize the current processor and resume the guest
+    /// without the hypervisor when the host panics, instead of halting the
+    /// processor. The guest resumes at the instruction that caused the
+    /// VM-exit, and the rest of the processors stay virtualized. Any lock the
+    /// host held on the processor is never released. Hence, the guest is
+    /// resumed only if no lock shared across processors stays held, and the
+    /// processor is halted otherwise.
+    pub fail_open: bool,
+
+    /// The PM1a control register to detect the guest entering sleep states
+    /// with. See `power`.
+    pub sleep_detection: SleepDetection,
+
+    /// The timeout of the watchdog detecting a processor stuck in a VM-exit
+    /// handler. See `watchdog`.
+    pub watchdog: Watchdog,
+
+    /// The number of processors, in addition to those present at
+    /// `virtualize_system`, that can be virtualized with `virtualize_processor`
+    /// when brought online. Their per-processor data structures are allocated
+    /// then, but the data structures sized by the number of processors, such
+    /// as the host window, are sized for them upfront.
+    pub hotplug_slots: usize,
+
+    /// The minimum size of the heap in bytes. If the heap given to
+    /// `allocator::init` and `allocator::extend` is smaller, `virtualize_system`
+    /// extends it with `PlatformOps::allocate_heap`, and fails with
+    /// `OutOfMemory` if it cannot. Large guests need a larger heap, for
+    /// example, for dirty tracking. 0 keeps the heap as is.
+    pub heap_size: usize,
+
+    /// The I/O port of the `isa-debug-exit` device of QEMU, which the guest
+    /// writes the result of the end-to-end tests to with `Hypercall::ExitTest`
+    /// to exit QEMU. Set only for the test runs; if `None`, the hypercall is
+    /// not supported.
+    pub test_exit_port: Option<u16>,
+}
+
+impl SharedHostData {
+    /// Registers `handler` to be called on VM-exit due to `reason`.
+    pub fn register_exit_handler(&mut self, reason: ExitReason, handler: Box<dyn ExitHandler>) {
+        self.exit_handlers.register(reason, handler);
+    }
 }
 
 static SHARED_HOST_DATA: Once<SharedHostData> = Once::new();
@@ -85,9 +760,16 @@ const OUR_HV_VENDOR_NAME_EBX: u32 = u32::from_ne_bytes(*b"Bare");
 const OUR_HV_VENDOR_NAME_ECX: u32 = u32::from_ne_bytes(*b"viso");
 const OUR_HV_VENDOR_NAME_EDX: u32 = u32::from_ne_bytes(*b"r!  ");
 
+/// The sub-leaf of `HV_CPUID_VENDOR_AND_MAX_FUNCTIONS` that returns our
+/// hypervisor name even in the stealth mode.
+const HV_CPUID_DETECTION_SUB_LEAF: u32 = u32::from_le_bytes(*b"Bare");
+
 /// Tests whether the current processor is already virtualized by our hypervisor.
 fn is_our_hypervisor_present() -> bool {
-    let regs = cpuid!(HV_CPUID_VENDOR_AND_MAX_FUNCTIONS);
+    let regs = cpuid!(
+        HV_CPUID_VENDOR_AND_MAX_FUNCTIONS,
+        HV_CPUID_DETECTION_SUB_LEAF
+    );
     (regs.ebx == OUR_HV_VENDOR_NAME_EBX)
         && (regs.ecx == OUR_HV_VENDOR_NAME_ECX)
         && (regs.edx == OUR_HV_VENDOR_NAME_EDX)
@@ -0,0 +1,197 @@
+//! This module implements configuration of MSR interception. The embedder of
+//! this crate selects MSRs to intercept and attaches callbacks to them, and
+//! the vendor specific code translates the selection into the MSR bitmaps
+//! (Intel) or the MSR permissions map (AMD).
+//!
+//! MSRs outside the ranges covered by the bitmaps are always intercepted by
+//! the processor. Accesses to them without a callback are passed through.
+
+use alloc::{boxed::Box, collections::BTreeMap};
+
+use crate::hypervisor::host::Vcpu;
+
+/// Represents a callback for `RDMSR`. Returns the value the guest reads, or
+/// `None` to read the actual guest value of the MSR.
+pub type MsrReadHandler = dyn Fn(&mut dyn Vcpu, u32) -> Option<u64> + Send + Sync;
+
+/// Represents a callback for `WRMSR`, called with the value the guest writes.
+/// Returns the value to actually write, or `None` to discard the write.
+pub type MsrWriteHandler = dyn Fn(&mut dyn Vcpu, u32, u64) -> Option<u64> + Send + Sync;
+
+/// The set of MSRs to intercept and their callbacks.
+///
+/// ```ignore
+/// let intercepts = MsrIntercepts::new()
+///     .on_read(x86::msr::IA32_LSTAR, |_, _| None)
+///     .on_write(x86::msr::IA32_LSTAR, |_, _, value| Some(value));
+/// ```
+#[derive(Default)]
+pub struct MsrIntercepts {
+    read: BTreeMap<u32, Box<MsrReadHandler>>,
+    write: BTreeMap<u32, Box<MsrWriteHandler>>,
+}
+
+impl MsrIntercepts {
+    /// Returns an empty set, which intercepts no MSR in the bitmap ranges.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Intercepts `RDMSR` of `msr` and calls `handler` on it. Replaces the
+    /// handler already set for `msr`, if any.
+    #[must_use]
+    pub fn on_read(
+        mut self,
+        msr: u32,
+        handler: impl Fn(&mut dyn Vcpu, u32) -> Option<u64> + Send + Sync + 'static,
+    ) -> Self {
+        let _ = self.read.insert(msr, Box::new(handler));
+        self
+    }
+
+    /// Intercepts `WRMSR` of `msr` and calls `handler` on it. Replaces the
+    /// handler already set for `msr`, if any.
+    #[must_use]
+    pub fn on_write(
+        mut self,
+        msr: u32,
+        handler: impl Fn(&mut dyn Vcpu, u32, u64) -> Option<u64> + Send + Sync + 'static,
+    ) -> Self {
+        let _ = self.write.insert(msr, Box::new(handler));
+        self
+    }
+
+    /// Returns the callback for `RDMSR` of `msr`, if any.
+    pub(crate) fn read_handler(&self, msr: u32) -> Option<&MsrReadHandler> {
+        self.read.get(&msr).map(AsRef::as_ref)
+    }
+
+    /// Returns the callback for `WRMSR` of `msr`, if any.
+    pub(crate) fn write_handler(&self, msr: u32) -> Option<&MsrWriteHandler> {
+        self.write.get(&msr).map(AsRef::as_ref)
+    }
+
+    /// Removes and returns the callback for `RDMSR` of `msr`, if any, to be
+    /// wrapped by a built-in handler.
+    #[cfg(feature = "amd")]
+    pub(crate) fn take_read_handler(&mut self, msr: u32) -> Option<Box<MsrReadHandler>> {
+        self.read.remove(&msr)
+    }
+
+    /// Removes and returns the callback for `WRMSR` of `msr`, if any, to be
+    /// wrapped by a built-in handler.
+    #[cfg(feature = "amd")]
+    pub(crate) fn take_write_handler(&mut self, msr: u32) -> Option<Box<MsrWriteHandler>> {
+        self.write.remove(&msr)
+    }
+
+    /// Returns the MSRs with read handlers.
+    pub(crate) fn intercepted_reads(&self) -> impl Iterator<Item = u32> + '_ {
+        self.read.keys().copied()
+    }
+
+    /// Returns whether no MSR is intercepted.
+    #[cfg(feature = "amd")]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.read.is_empty() && self.write.is_empty()
+    }
+
+    /// Sets bits in `bitmaps` for the intercepted MSRs in the format of the
+    /// Intel MSR bitmaps.
+    // See: 25.6.9 MSR-Bitmap Address
+    #[cfg(any(feature = "intel", test))]
+    pub(crate) fn build_vmx_bitmaps(&self, bitmaps: &mut [u8; 0x1000]) {
+        const READ_LOW: usize = 0x0;
+        const READ_HIGH: usize = 0x400;
+        const WRITE_LOW: usize = 0x800;
+        const WRITE_HIGH: usize = 0xc00;
+
+        let offset_of = |msr: u32, low: usize, high: usize| match msr {
+            0..=0x1fff => Some((low, msr)),
+            0xc000_0000..=0xc000_1fff => Some((high, msr - 0xc000_0000)),
+            _ => None,
+        };
+        for &msr in self.read.keys() {
+            if let Some((base, index)) = offset_of(msr, READ_LOW, READ_HIGH) {
+                bitmaps[base + index as usize / 8] |= 1 << (index % 8);
+            }
+        }
+        for &msr in self.write.keys() {
+            if let Some((base, index)) = offset_of(msr, WRITE_LOW, WRITE_HIGH) {
+                bitmaps[base + index as usize / 8] |= 1 << (index % 8);
+            }
+        }
+    }
+
+    /// Sets bits in `msrpm` for the intercepted MSRs in the format of the AMD
+    /// MSR permissions map.
+    // See: 15.11 MSR Intercepts
+    #[cfg(any(feature = "amd", test))]
+    pub(crate) fn build_svm_msrpm(&self, msrpm: &mut [u8; 0x2000]) {
+        // Each MSR takes two bits: the even bit for read and the odd bit for
+        // write.
+        let bit_of = |msr: u32| {
+            let (vector, index) = match msr {
+                0..=0x1fff => (0x0, msr),
+                0xc000_0000..=0xc000_1fff => (0x800, msr - 0xc000_0000),
+                0xc001_0000..=0xc001_1fff => (0x1000, msr - 0xc001_0000),
+                _ => return None,
+            };
+            Some(vector * 8 + index as usize * 2)
+        };
+        for &msr in self.read.keys() {
+            if let Some(bit) = bit_of(msr) {
+                msrpm[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        for &msr in self.write.keys() {
+            if let Some(bit) = bit_of(msr).map(|bit| bit + 1) {
+                msrpm[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+    }
+}
+
+impl core::fmt::Debug for MsrIntercepts {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MsrIntercepts")
+            .field("read", &self.read.keys())
+            .field("write", &self.write.keys())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vmx_bitmaps() {
+        let intercepts = MsrIntercepts::new()
+            .on_read(0x10, |_, _| None)
+            .on_write(x86::msr::IA32_EFER, |_, _, value| Some(value))
+            .on_read(0xc001_0000, |_, _| None);
+
+        let mut bitmaps = [0u8; 0x1000];
+        intercepts.build_vmx_bitmaps(&mut bitmaps);
+        assert_eq!(bitmaps[0x2], 1 << 0);
+        assert_eq!(bitmaps[0xc00 + 0x80 / 8], 1 << 0);
+        assert_eq!(bitmaps.iter().filter(|&&b| b != 0).count(), 2);
+    }
+
+    #[test]
+    fn svm_msrpm() {
+        let intercepts = MsrIntercepts::new()
+            .on_read(0x10, |_, _| None)
+            .on_write(x86::msr::IA32_EFER, |_, _, value| Some(value))
+            .on_read(0xc001_0015, |_, _| None)
+            .on_write(0xc001_0015, |_, _, _| None);
+
+        let mut msrpm = [0u8; 0x2000];
+        intercepts.build_svm_msrpm(&mut msrpm);
+        assert_eq!(msrpm[0x4], 1 << 0);
+        assert_eq!(msrpm[0x800 + 0x80 / 4], 1 << 1);
+        assert_eq!(msrpm[0x1000 + 0x15 / 4], (1 << 2) | (1 << 3));
+        assert_eq!(msrpm.iter().filter(|&&b| b != 0).count(), 3);
+    }
+}
@@ -38,8 +38,9 @@ impl Mtrr {
     pub(crate) fn find(&self, range: Range<u64>) -> Option<MemoryType> {
         // Look up the fixed range MTRRs if the range start within 1MB (which is managed
         // by the fixed range MTRRs), since the fixed range MTRRs are priority over
-        // the variable range MTRRs.
-        if range.start < 0x10_0000 {
+        // the variable range MTRRs. Without them enabled, the variable range MTRRs
+        // manage the lowest 1MB too.
+        if range.start < 0x10_0000 && !self.fixed.is_empty() {
             // If the range crosses the 1MB boundary, report error. For simplicity,
             // we do not attempt to resolve the memory type of the range that spans both
             // fixed and variable range MTRRs. The caller should query a memory type for
@@ -67,11 +68,11 @@ impl Mtrr {
     fn find_from_variable(&self, range: Range<u64>) -> Option<MemoryType> {
         let mut return_memory_type = None::<MemoryType>;
         for mtrr in &self.variable {
-            if mtrr.range.contains(&range.start) {
+            if mtrr.range.start < range.end && range.start < mtrr.range.end {
                 // If the entire range is not managed by this single entry, bail out.
                 // This means the given range is managed by multiple conflicting MTRR
                 // settings. The caller needs to call this function with smaller range.
-                if !mtrr.range.contains(&(range.end - 1)) {
+                if !mtrr.range.contains(&range.start) || !mtrr.range.contains(&(range.end - 1)) {
                     return None;
                 }
 
@@ -117,7 +118,7 @@ impl Mtrr {
             FixedMtrrRangeInfo::new(0xF8000, 0x1000),
         ];
 
-        assert!(raw_fixed_mtrrs.len() == FIXED_MTRR_RANGES.len());
+        assert!(raw_fixed_mtrrs.is_empty() || raw_fixed_mtrrs.len() == FIXED_MTRR_RANGES.len());
 
         let mut combined_ranges = Vec::<MemoryTypeRange>::new();
         for (i, fixed_raw) in raw_fixed_mtrrs.iter().enumerate() {
@@ -252,25 +253,29 @@ impl RawMtrrs {
             x86::msr::IA32_MTRR_PHYSMASK9,
         ];
 
-        // For simplicity, panic when the system does not support MTRRs or enable
-        // fixed range MTRRs.
+        // "all MTRRs are disabled when clear, and the UC memory type is applied
+        //  to all of physical memory."
+        // "If the fixed-range MTRRs are disabled, the variable-range MTRRs can
+        //  still be used and can map the range ordinarily covered by the
+        //  fixed-range MTRRs."
+        // See: 12.11.2.1 IA32_MTRR_DEF_TYPE MSR
         let default_type = rdmsr(x86::msr::IA32_MTRR_DEF_TYPE);
-
-        assert!(
-            (default_type & IA32_MTRR_DEF_TYPE_MTRR_ENABLE_FLAG) != 0,
-            "MTRRs not enabled"
-        );
-        assert!(
-            (default_type & IA32_MTRR_DEF_TYPE_FIXED_RANGE_MTRR_ENABLE_FLAG) != 0,
-            "Fixed range MTRRs not enabled"
-        );
+        if (default_type & IA32_MTRR_DEF_TYPE_MTRR_ENABLE_FLAG) == 0 {
+            return Self {
+                default_memory_type: MemoryType::Uncachable,
+                fixed: Vec::new(),
+                variable: Vec::new(),
+            };
+        }
         let default_memory_type =
             <MemoryType as FromPrimitive>::from_u64(default_type & 0b111).unwrap();
 
-        // Read all fixed range MTRRs.
+        // Read all fixed range MTRRs if enabled.
         let mut fixed = Vec::<RawFixedMtrr>::new();
-        for msr in FIXED_MTRRS {
-            fixed.push(RawFixedMtrr { value: rdmsr(msr) });
+        if (default_type & IA32_MTRR_DEF_TYPE_FIXED_RANGE_MTRR_ENABLE_FLAG) != 0 {
+            for msr in FIXED_MTRRS {
+                fixed.push(RawFixedMtrr { value: rdmsr(msr) });
+            }
         }
 
         // Get how many variable range MTRRs is supported on this system and read
@@ -306,3 +311,36 @@ struct RawVariableMtrr {
     base: u64,
     mask: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variable_ranges() {
+        let mtrr = Mtrr {
+            default_memory_type: MemoryType::WriteBack,
+            fixed: Vec::new(),
+            variable: alloc::vec![MemoryTypeRange {
+                memory_type: MemoryType::Uncachable,
+                range: 0xe000_0000..0x1_0000_0000,
+            }],
+        };
+        assert_eq!(
+            mtrr.find(0xe000_0000..0xe020_0000),
+            Some(MemoryType::Uncachable)
+        );
+        assert_eq!(
+            mtrr.find(0x8000_0000..0xc000_0000),
+            Some(MemoryType::WriteBack)
+        );
+
+        // The range partially covered by the MTRR has multiple memory types.
+        assert_eq!(mtrr.find(0xc000_0000..0x1_0000_0000), None);
+        assert_eq!(mtrr.find(0xf000_0000..0x1_4000_0000), None);
+
+        // The variable range MTRRs manage the lowest 1MB without the fixed range
+        // MTRRs.
+        assert_eq!(mtrr.find(0..0x20_0000), Some(MemoryType::WriteBack));
+    }
+}
@@ -1,13 +1,41 @@
-use core::ptr::addr_of;
+use core::{ops::Range, ptr::addr_of};
 
-use alloc::boxed::Box;
-use x86::bits64::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+#[cfg(feature = "amd")]
+use alloc::alloc::handle_alloc_error;
+use alloc::{
+    boxed::Box,
+    collections::{btree_map, BTreeMap},
+    vec::Vec,
+};
+use x86::{
+    bits64::paging::{BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE},
+    cpuid::cpuid,
+};
 
-use super::{platform_ops, support::zeroed_box};
+use super::{
+    guest_memory::{self, PagingContext, Translation, TranslationError},
+    platform_ops,
+    support::{try_zeroed_box, zeroed_box},
+    x86_instructions::rdtsc,
+    HvError,
+};
 
-#[derive(Debug, derive_deref::Deref, derive_deref::DerefMut)]
+/// The paging structures that identity map the first 512GB. 1GB pages are
+/// used where the processor supports them. The PDs are allocated on demand to
+/// split 1GB pages into 2MB pages.
+#[derive(Debug)]
 pub struct PagingStructures {
     ptr: Box<PagingStructuresRaw>,
+
+    /// The PDs for the 1GB regions mapped with 2MB pages, keyed by the PDPT
+    /// index.
+    pds: BTreeMap<usize, Box<Pd>>,
+
+    /// Whether built with `build_minimal`.
+    minimal: bool,
+
+    /// The ranges mapped with `map_range`, rounded to the pages.
+    mapped: Vec<Range<u64>>,
 }
 
 impl Default for PagingStructures {
@@ -16,12 +44,493 @@ impl Default for PagingStructures {
     }
 }
 
+impl core::ops::Deref for PagingStructures {
+    type Target = Box<PagingStructuresRaw>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ptr
+    }
+}
+
+impl core::ops::DerefMut for PagingStructures {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.ptr
+    }
+}
+
 impl PagingStructures {
     pub fn new() -> Self {
         Self {
             ptr: zeroed_box::<PagingStructuresRaw>(),
+            pds: BTreeMap::new(),
+            minimal: false,
+            mapped: Vec::new(),
+        }
+    }
+
+    /// Returns the empty paging structures like `new`, or `OutOfMemory` if the
+    /// heap is exhausted.
+    #[cfg(feature = "amd")]
+    pub(crate) fn try_new() -> Result<Self, HvError> {
+        Ok(Self {
+            ptr: try_zeroed_box::<PagingStructuresRaw>()?,
+            pds: BTreeMap::new(),
+            minimal: false,
+            mapped: Vec::new(),
+        })
+    }
+
+    /// Builds the identity map for the host. The first 2MB is mapped with 4KB
+    /// pages to make the zero page non-present.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OutOfMemory` if the heap is exhausted while splitting 1GB pages.
+    pub fn build_identity(&mut self) -> Result<(), HvError> {
+        self.build_identity_internal(false)
+    }
+
+    /// Builds the minimal address space for the host, which maps only `image`,
+    /// the address range of the hypervisor image, and the heap, instead of all
+    /// physical memory. Bugs in the host then cannot corrupt the rest of the
+    /// memory, and the guest cannot leak it through the host address space
+    /// with speculative execution, as in Meltdown.
+    ///
+    /// The heap, including the host stacks, is mapped by `virtualize_system`,
+    /// which also stops extending the heap with `PlatformOps::donate_heap`, as
+    /// the host could not access the donated memory. Reserve enough heap with
+    /// `SharedHostData::heap_size` instead. MMIO, such as the local APIC
+    /// registers, is accessed through the host window, and is not mapped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OutOfMemory` if the heap is exhausted.
+    pub fn build_minimal(&mut self, image: Range<u64>) -> Result<(), HvError> {
+        self.minimal = true;
+        self.map_range(image)
+    }
+
+    /// Returns whether built with `build_minimal`.
+    #[cfg(not(test))]
+    pub(crate) fn is_minimal(&self) -> bool {
+        self.minimal
+    }
+
+    /// Maps the pages in `range` to their physical addresses with 4KB pages.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OutOfMemory` if the heap is exhausted.
+    pub(crate) fn map_range(&mut self, range: Range<u64>) -> Result<(), HvError> {
+        let ops = platform_ops::get();
+        let pml4_pa = self.pml4_pa();
+        let start = range.start & !(BASE_PAGE_SIZE as u64 - 1);
+        for va in (start..range.end).step_by(BASE_PAGE_SIZE) {
+            // Safety: the paging structures are owned by `self`.
+            unsafe { map_page(pml4_pa, va, ops.pa(va as _))? };
+        }
+        let end = range.end.next_multiple_of(BASE_PAGE_SIZE as u64);
+        if start < end {
+            self.mapped.push(start..end);
+        }
+        Ok(())
+    }
+
+    /// Checks that the paging structures translate addresses as built, with
+    /// `guest_memory::translate` as the reference walker of the paging
+    /// structures in memory. The addresses checked are the boundaries of the
+    /// pages and the mapped ranges, the pages in `non_present` and their
+    /// neighbours, and `samples` pseudo-random addresses.
+    ///
+    /// Each mapped address must translate to its physical address as writable,
+    /// executable and supervisor-only, and the others must not be present,
+    /// including the pages in `non_present`, which are made non-present with
+    /// `set_page_present` after built.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first address that translates otherwise.
+    pub(crate) fn self_check(
+        &self,
+        non_present: &[u64],
+        samples: usize,
+    ) -> Result<(), MappingMismatch> {
+        const CR0_PE_PG: u64 = (1 << 0) | (1 << 31);
+        const CR4_PAE: u64 = 1 << 5;
+        const EFER_LMA_NXE: u64 = (1 << 10) | (1 << 11);
+        const LIMIT: u64 = HUGE_PAGE_SIZE as u64 * 512;
+
+        let ops = platform_ops::get();
+        let context = PagingContext {
+            cr0: CR0_PE_PG,
+            cr3: self.pml4_pa(),
+            cr4: CR4_PAE,
+            efer: EFER_LMA_NXE,
+            max_phys_addr_bits: max_phys_addr_bits(),
+        };
+        let expected = |va: u64| {
+            let page = va & !(BASE_PAGE_SIZE as u64 - 1);
+            if non_present.contains(&page) {
+                None
+            } else if self.minimal {
+                self.mapped
+                    .iter()
+                    .any(|range| range.contains(&va))
+                    .then(|| ops.pa(va as _))
+            } else {
+                (page != 0 && va < LIMIT).then_some(va)
+            }
+        };
+        let check = |va: u64| {
+            let expected = expected(va);
+            let actual = guest_memory::translate(&context, va, |pa| unsafe {
+                ops.va(pa).cast::<u64>().read_volatile()
+            });
+            let matched = match (expected, actual) {
+                (None, Err(TranslationError::NotPresent { .. })) => true,
+                (Some(pa), Ok(translation)) => {
+                    translation.gpa == pa
+                        && translation.writable
+                        && !translation.user
+                        && !translation.no_execute
+                }
+                _ => false,
+            };
+            if matched {
+                Ok(())
+            } else {
+                Err(MappingMismatch {
+                    va,
+                    expected,
+                    actual,
+                })
+            }
+        };
+
+        let page_size = BASE_PAGE_SIZE as u64;
+        let boundaries = [
+            0,
+            page_size,
+            LARGE_PAGE_SIZE as u64 - page_size,
+            LARGE_PAGE_SIZE as u64,
+            HUGE_PAGE_SIZE as u64 - page_size,
+            HUGE_PAGE_SIZE as u64,
+            LIMIT - page_size,
+            LIMIT,
+        ];
+        let ranges = self
+            .mapped
+            .iter()
+            .flat_map(|range| [range.start, range.end - 1, range.end]);
+        let non_present = non_present.iter().flat_map(|page| {
+            [
+                *page,
+                page + page_size - 1,
+                page.wrapping_sub(page_size),
+                page + page_size,
+            ]
+        });
+        boundaries
+            .into_iter()
+            .chain(ranges)
+            .chain(non_present)
+            .try_for_each(check)?;
+
+        let mut random = SplitMix64::new(rdtsc());
+        for _ in 0..samples {
+            let va = if self.minimal && !self.mapped.is_empty() && random.next().is_multiple_of(2) {
+                let range = &self.mapped[(random.next() % self.mapped.len() as u64) as usize];
+                range.start + random.next() % (range.end - range.start)
+            } else {
+                random.next() % LIMIT
+            };
+            check(va)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn build_identity_internal(&mut self, npt: bool) -> Result<(), HvError> {
+        let ops = platform_ops::get();
+        let user = npt;
+
+        // See: Table 1-17. Information Returned by CPUID Instruction
+        let page_1gb = cpuid!(0x8000_0001).edx & (1 << 26) != 0;
+
+        let pml4 = &mut self.ptr.pml4;
+        pml4.0.entries[0].set_present(true);
+        pml4.0.entries[0].set_writable(true);
+        pml4.0.entries[0].set_user(user);
+        pml4.0.entries[0].set_pfn(ops.pa(addr_of!(self.ptr.pdpt) as _) >> BASE_PAGE_SHIFT);
+
+        for (i, pdpte) in self.ptr.pdpt.0.entries.iter_mut().enumerate() {
+            pdpte.set_present(true);
+            pdpte.set_writable(true);
+            pdpte.set_user(user);
+            pdpte.set_large(true);
+            pdpte.set_pfn((i * HUGE_PAGE_SIZE) as u64 >> BASE_PAGE_SHIFT);
+        }
+
+        // The first 1GB is always mapped with 2MB pages for the host. Others are
+        // split only without 1GB page support.
+        for i in 0..self.ptr.pdpt.0.entries.len() {
+            if !page_1gb || (i == 0 && !npt) {
+                let _ = self.try_pd(i)?;
+            }
+        }
+
+        // The first 2MB is mapped with 4KB pages if it is not for NPT. This is
+        // to make the zero page non-present and cause #PF in case of null
+        // pointer access. Helps debugging.
+        if !npt {
+            let pt_pa = ops.pa(addr_of!(self.ptr.pt) as _);
+            for (i, pte) in self.ptr.pt.0.entries.iter_mut().enumerate() {
+                pte.set_present(true);
+                pte.set_writable(true);
+                pte.set_user(user);
+                pte.set_pfn(i as u64);
+            }
+            // Make the null page invalid to detect null pointer access.
+            self.ptr.pt.0.entries[0].set_present(false);
+
+            let pde = &mut self.pds.get_mut(&0).unwrap().0.entries[0];
+            let mut new_pde = *pde;
+            new_pde.set_large(false);
+            new_pde.set_pfn(pt_pa >> BASE_PAGE_SHIFT);
+            *pde = new_pde;
+        }
+        Ok(())
+    }
+
+    /// Returns the PD for the 1GB region at `pdpt_index`, splitting the 1GB page
+    /// mapping the region into 2MB pages if needed.
+    #[cfg(feature = "amd")]
+    pub(crate) fn pd(&mut self, pdpt_index: usize) -> &mut Pd {
+        self.try_pd(pdpt_index)
+            .unwrap_or_else(|_| handle_alloc_error(core::alloc::Layout::new::<Pd>()))
+    }
+
+    /// Returns the PD for the 1GB region at `pdpt_index` if the region is split
+    /// into 2MB pages.
+    #[cfg(any(feature = "amd", test))]
+    pub(crate) fn split_pd(&self, pdpt_index: usize) -> Option<&Pd> {
+        self.pds.get(&pdpt_index).map(Box::as_ref)
+    }
+
+    /// Returns the physical address of the PML4.
+    pub(crate) fn pml4_pa(&self) -> u64 {
+        platform_ops::get().pa(addr_of!(self.ptr.pml4) as _)
+    }
+
+    /// Returns the PD like `pd`, or `OutOfMemory` if the heap is exhausted.
+    fn try_pd(&mut self, pdpt_index: usize) -> Result<&mut Pd, HvError> {
+        let pdpte = &mut self.ptr.pdpt.0.entries[pdpt_index];
+        match self.pds.entry(pdpt_index) {
+            btree_map::Entry::Occupied(entry) => Ok(entry.into_mut()),
+            btree_map::Entry::Vacant(entry) => {
+                let mut pd = try_zeroed_box::<Pd>()?;
+                split_1gb(pdpte, &mut pd);
+                Ok(entry.insert(pd))
+            }
+        }
+    }
+}
+
+/// An address the paging structures do not translate as built.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug)]
+#[error("{va:#x?} translates to {actual:#x?} instead of {expected:#x?}")]
+pub(crate) struct MappingMismatch {
+    va: u64,
+    expected: Option<u64>,
+    actual: Result<Translation, TranslationError>,
+}
+
+/// Returns MAXPHYADDR, up to which physical addresses in the entries can be.
+#[cfg(not(test))]
+fn max_phys_addr_bits() -> u8 {
+    // See: Table 1-17. Information Returned by CPUID Instruction
+    cpuid!(0x8000_0008).eax as u8
+}
+
+// The physical addresses in tests are the virtual addresses of the test
+// program, which may exceed MAXPHYADDR.
+#[cfg(test)]
+fn max_phys_addr_bits() -> u8 {
+    52
+}
+
+/// The pseudo-random number generator for sampling addresses to check
+/// (SplitMix64).
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Sets whether the 4KB page at `va` is present in the paging structures in
+/// use at `pml4_pa`, splitting the large pages mapping it into 4KB pages if
+/// needed. Once the 4KB pages split from a 2MB page map it as before again,
+/// they are merged back into the 2MB page. The paging structures allocated for
+/// splitting are never freed, even when merged, as other processors may still
+/// be walking them.
+///
+/// # Errors
+///
+/// Returns `OutOfMemory` if the heap is exhausted while splitting pages.
+///
+/// # Safety
+///
+/// The paging structures must be owned by the hypervisor, as they are updated
+/// in place while other processors may be walking them.
+pub(crate) unsafe fn set_page_present(pml4_pa: u64, va: u64, present: bool) -> Result<(), HvError> {
+    let ops = platform_ops::get();
+    let table_of = |entry: &Entry| {
+        assert!(entry.present());
+        unsafe { &mut *ops.va(entry.pfn() << BASE_PAGE_SHIFT).cast::<Table>() }
+    };
+
+    let pml4 = unsafe { &mut *ops.va(pml4_pa).cast::<Table>() };
+    let pdpt = table_of(&pml4.entries[(va >> 39) as usize & 0x1ff]);
+    let pdpte = &mut pdpt.entries[(va >> 30) as usize & 0x1ff];
+    if pdpte.large() {
+        let mut pd = try_zeroed_box::<Pd>()?;
+        split_1gb(pdpte, &mut pd);
+        let _ = Box::leak(pd);
+    }
+    let pd = table_of(pdpte);
+    let pde = &mut pd.entries[(va >> 21) as usize & 0x1ff];
+    if pde.large() {
+        let mut pt = try_zeroed_box::<Pt>()?;
+        split_2mb(pde, &mut pt);
+        let _ = Box::leak(pt);
+    }
+    let pt = table_of(pde);
+    pt.entries[(va >> BASE_PAGE_SHIFT) as usize & 0x1ff].set_present(present);
+    if present {
+        merge_2mb(pde, pt);
+    }
+    Ok(())
+}
+
+/// Maps the 4KB page at `va` to `pa` in the paging structures at `pml4_pa`,
+/// allocating the paging structures if needed, which are never freed.
+///
+/// # Safety
+///
+/// The paging structures must be owned by the hypervisor, as they are updated
+/// in place.
+unsafe fn map_page(pml4_pa: u64, va: u64, pa: u64) -> Result<(), HvError> {
+    let ops = platform_ops::get();
+    let mut table = unsafe { &mut *ops.va(pml4_pa).cast::<Table>() };
+    for shift in [39, 30, 21] {
+        let entry = &mut table.entries[(va >> shift) as usize & 0x1ff];
+        if !entry.present() {
+            let next = Box::leak(try_zeroed_box::<Table>()?);
+            let mut new_entry = Entry(0);
+            new_entry.set_present(true);
+            new_entry.set_writable(true);
+            new_entry.set_pfn(ops.pa(next as *mut _ as _) >> BASE_PAGE_SHIFT);
+            *entry = new_entry;
         }
+        assert!(!entry.large());
+        table = unsafe { &mut *ops.va(entry.pfn() << BASE_PAGE_SHIFT).cast::<Table>() };
     }
+
+    let mut pte = Entry(0);
+    pte.set_present(true);
+    pte.set_writable(true);
+    pte.set_pfn(pa >> BASE_PAGE_SHIFT);
+    table.entries[(va >> BASE_PAGE_SHIFT) as usize & 0x1ff] = pte;
+    Ok(())
+}
+
+/// Updates `pdpte` to point to `pd` to split the page from 1GB to 2MBs.
+fn split_1gb(pdpte: &mut Entry, pd: &mut Pd) {
+    assert!(pdpte.present());
+    assert!(pdpte.large());
+
+    let pages_per_2mb = (LARGE_PAGE_SIZE / BASE_PAGE_SIZE) as u64;
+    for (i, pde) in pd.0.entries.iter_mut().enumerate() {
+        pde.set_present(true);
+        pde.set_writable(pdpte.writable());
+        pde.set_user(pdpte.user());
+        pde.set_large(true);
+        pde.set_no_execute(pdpte.no_execute());
+        pde.set_pfn(pdpte.pfn() + i as u64 * pages_per_2mb);
+    }
+
+    // Update the PDPTE at once, as other processors may be walking the paging
+    // structures.
+    let pd_pa = platform_ops::get().pa(pd as *mut _ as _);
+    let mut new_pdpte = *pdpte;
+    new_pdpte.set_large(false);
+    new_pdpte.set_pfn(pd_pa >> BASE_PAGE_SHIFT);
+    *pdpte = new_pdpte;
+}
+
+/// Updates `pde` to point to `pt` to split the page from 2MB to 4KBs.
+fn split_2mb(pde: &mut Entry, pt: &mut Pt) {
+    assert!(pde.present());
+    assert!(pde.large());
+
+    for (i, pte) in pt.0.entries.iter_mut().enumerate() {
+        pte.set_present(true);
+        pte.set_writable(pde.writable());
+        pte.set_user(pde.user());
+        pte.set_no_execute(pde.no_execute());
+        pte.set_pfn(pde.pfn() + i as u64);
+    }
+
+    // Update the PDE at once, as other processors may be walking the paging
+    // structures.
+    let pt_pa = platform_ops::get().pa(pt as *mut _ as _);
+    let mut new_pde = *pde;
+    new_pde.set_large(false);
+    new_pde.set_pfn(pt_pa >> BASE_PAGE_SHIFT);
+    *pde = new_pde;
+}
+
+/// Updates `pde` to map the 2MB page if the PTEs in `pt` it points to map the
+/// 2MB page with the same flags, undoing `split_2mb`.
+fn merge_2mb(pde: &mut Entry, pt: &Table) {
+    // The accessed and dirty flags may differ, as the processor sets them.
+    const PFN: u64 = 0x000f_ffff_ffff_f000;
+    const ACCESSED_DIRTY: u64 = (1 << 5) | (1 << 6);
+
+    let flags = |entry: &Entry| entry.0 & !PFN & !ACCESSED_DIRTY;
+    let first = pt.entries[0];
+    let pages_per_2mb = (LARGE_PAGE_SIZE / BASE_PAGE_SIZE) as u64;
+    // Bit 7 of PTEs is the PAT bit, which is at bit 12 of 2MB PDEs instead.
+    let mergeable = first.present()
+        && !first.large()
+        && first.pfn().is_multiple_of(pages_per_2mb)
+        && pt
+            .entries
+            .iter()
+            .enumerate()
+            .all(|(i, pte)| flags(pte) == flags(&first) && pte.pfn() == first.pfn() + i as u64);
+    if !mergeable {
+        return;
+    }
+
+    // Keep the permissions the PDE restricted as well. Update the PDE at once,
+    // as other processors may be walking the paging structures.
+    let mut new_pde = Entry(flags(&first));
+    new_pde.set_large(true);
+    new_pde.set_writable(pde.writable() && first.writable());
+    new_pde.set_user(pde.user() && first.user());
+    new_pde.set_no_execute(pde.no_execute() || first.no_execute());
+    new_pde.set_pfn(first.pfn());
+    *pde = new_pde;
 }
 
 #[derive(Debug)]
@@ -29,15 +538,7 @@ impl PagingStructures {
 pub struct PagingStructuresRaw {
     pub(crate) pml4: Pml4,
     pub(crate) pdpt: Pdpt,
-    pub(crate) pd: [Pd; 512],
     pub(crate) pt: Pt,
-    pub(crate) pt_apic: Pt,
-}
-
-impl PagingStructuresRaw {
-    pub fn build_identity(&mut self) {
-        build_identity_internal(self, false);
-    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -65,52 +566,370 @@ bitfield::bitfield! {
     pub present, set_present: 0;
     pub writable, set_writable: 1;
     pub user, set_user: 2;
+    pub write_through, set_write_through: 3;
+    pub cache_disable, set_cache_disable: 4;
     pub large, set_large: 7;
     pub pfn, set_pfn: 51, 12;
+    pub no_execute, set_no_execute: 63;
 }
 
-pub(crate) fn build_identity_internal(ps: &mut PagingStructuresRaw, npt: bool) {
-    let ops = platform_ops::get();
-    let user = npt;
-
-    let pml4 = &mut ps.pml4;
-    pml4.0.entries[0].set_present(true);
-    pml4.0.entries[0].set_writable(true);
-    pml4.0.entries[0].set_user(user);
-    pml4.0.entries[0].set_pfn(ops.pa(addr_of!(ps.pdpt) as _) >> BASE_PAGE_SHIFT);
-
-    let mut pa = 0;
-    for (i, pdpte) in ps.pdpt.0.entries.iter_mut().enumerate() {
-        pdpte.set_present(true);
-        pdpte.set_writable(true);
-        pdpte.set_user(user);
-        pdpte.set_pfn(ops.pa(addr_of!(ps.pd[i]) as _) >> BASE_PAGE_SHIFT);
-        for pde in &mut ps.pd[i].0.entries {
-            // The first 2MB is mapped with 4KB pages if it is not for NPT. This
-            // is to make the zero page non-present and cause #PF in case of null
-            // pointer access. Helps debugging. All other pages are 2MB mapped.
-            if pa == 0 && !npt {
-                pde.set_present(true);
-                pde.set_writable(true);
-                pde.set_user(user);
-                pde.set_pfn(ops.pa(addr_of!(ps.pt) as _) >> BASE_PAGE_SHIFT);
-                for pte in &mut ps.pt.0.entries {
-                    pte.set_present(true);
-                    pte.set_writable(true);
-                    pte.set_user(user);
-                    pte.set_pfn(pa >> BASE_PAGE_SHIFT);
-                    pa += BASE_PAGE_SIZE as u64;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hypervisor::{guest_memory::PageSize, support::Page, test_support};
+
+    const CONTEXT: PagingContext = PagingContext {
+        cr0: (1 << 0) | (1 << 31),
+        cr3: 0,
+        cr4: 1 << 5,
+        efer: (1 << 10) | (1 << 11),
+        max_phys_addr_bits: 52,
+    };
+
+    /// Returns the translation of `va` with the paging structures at
+    /// `pml4_pa` by the reference walker.
+    fn translate(pml4_pa: u64, va: u64) -> Result<Translation, TranslationError> {
+        let context = PagingContext {
+            cr3: pml4_pa,
+            ..CONTEXT
+        };
+        guest_memory::translate(&context, va, |pa| unsafe {
+            platform_ops::get().va(pa).cast::<u64>().read()
+        })
+    }
+
+    /// Returns the physical address `va` translates to with the paging
+    /// structures at `pml4_pa`, or `None` if not mapped.
+    fn walk(pml4_pa: u64, va: u64) -> Option<u64> {
+        translate(pml4_pa, va)
+            .ok()
+            .map(|translation| translation.gpa)
+    }
+
+    #[test]
+    fn identity_map_excludes_null_page() {
+        test_support::init_platform_ops();
+        let mut pt = PagingStructures::new();
+        pt.build_identity().unwrap();
+
+        let pml4_pa = pt.pml4_pa();
+        for va in [
+            0x1000,
+            0x1f_f123,
+            0x20_0000,
+            0x4000_1234,
+            0x1_4000_0000,
+            0x7f_ffff_f000,
+        ] {
+            assert_eq!(walk(pml4_pa, va), Some(va), "{va:#x}");
+        }
+        assert_eq!(walk(pml4_pa, 0), None);
+        assert_eq!(walk(pml4_pa, 0x80_0000_0000), None);
+    }
+
+    #[test]
+    fn set_page_present_splits_large_pages() {
+        test_support::init_platform_ops();
+        let mut pt = PagingStructures::new();
+        pt.build_identity().unwrap();
+
+        let pml4_pa = pt.pml4_pa();
+        let va = 0xc000_5000;
+        unsafe { set_page_present(pml4_pa, va, false) }.unwrap();
+        assert_eq!(walk(pml4_pa, va), None);
+        assert_eq!(walk(pml4_pa, va - 0x1000), Some(va - 0x1000));
+        assert_eq!(walk(pml4_pa, va + 0x1000), Some(va + 0x1000));
+        assert_eq!(walk(pml4_pa, 0xc020_0000), Some(0xc020_0000));
+
+        unsafe { set_page_present(pml4_pa, va, true) }.unwrap();
+        assert_eq!(walk(pml4_pa, va + 0x123), Some(va + 0x123));
+    }
+
+    #[test]
+    fn minimal_map_includes_image_only() {
+        test_support::init_platform_ops();
+        let image = zeroed_box::<[Page; 3]>();
+        let start = image.as_ptr() as u64;
+        let end = start + core::mem::size_of_val(&*image) as u64;
+
+        let mut pt = PagingStructures::new();
+        pt.build_minimal(start + 0x10..end).unwrap();
+
+        let pml4_pa = pt.pml4_pa();
+        for va in (start..end).step_by(BASE_PAGE_SIZE) {
+            assert_eq!(walk(pml4_pa, va + 0x8), Some(va + 0x8));
+        }
+        assert_eq!(walk(pml4_pa, end), None);
+        assert_eq!(walk(pml4_pa, 0x1000), None);
+    }
+
+    #[test]
+    fn self_check_passes_as_built() {
+        test_support::init_platform_ops();
+        let guard_page = zeroed_box::<Page>();
+        let guard_page = guard_page.0.as_ptr() as u64;
+
+        let mut pt = PagingStructures::new();
+        pt.build_identity().unwrap();
+        pt.self_check(&[], 1024).unwrap();
+
+        let image = zeroed_box::<[Page; 4]>();
+        let start = image.as_ptr() as u64;
+        let mut minimal = PagingStructures::new();
+        minimal.build_minimal(start..start + 0x4000).unwrap();
+        minimal.map_range(guard_page..guard_page + 1).unwrap();
+        minimal.self_check(&[], 1024).unwrap();
+
+        // Pages made non-present are not expected to be mapped.
+        unsafe { set_page_present(minimal.pml4_pa(), guard_page, false) }.unwrap();
+        assert!(minimal.self_check(&[], 0).is_err());
+        minimal.self_check(&[guard_page], 1024).unwrap();
+    }
+
+    #[test]
+    fn self_check_detects_wrong_translations() {
+        test_support::init_platform_ops();
+        let mut pt = PagingStructures::new();
+        pt.build_identity().unwrap();
+
+        // Map the page 1 to the page 2.
+        pt.ptr.pt.0.entries[1].set_pfn(2);
+        let error = pt.self_check(&[], 0).unwrap_err();
+        assert_eq!(error.va, 0x1000);
+        assert_eq!(error.expected, Some(0x1000));
+        assert_eq!(error.actual.unwrap().gpa, 0x2000);
+        pt.ptr.pt.0.entries[1].set_pfn(1);
+
+        // Make the page 3 read-only.
+        pt.ptr.pt.0.entries[3].set_writable(false);
+        let mut non_present = [0x2000];
+        unsafe { set_page_present(pt.pml4_pa(), 0x2000, false) }.unwrap();
+        let error = pt.self_check(&non_present, 0).unwrap_err();
+        assert_eq!(error.va, 0x3000);
+        assert!(!error.actual.unwrap().writable);
+        pt.ptr.pt.0.entries[3].set_writable(true);
+        pt.self_check(&non_present, 0).unwrap();
+
+        // The page expected to be non-present is present.
+        non_present[0] = 0x4000;
+        let error = pt.self_check(&non_present, 0).unwrap_err();
+        assert_eq!((error.va, error.expected), (0x4000, None));
+    }
+
+    /// Makes random pages non-present and present again, splitting the large
+    /// pages mapping them, and checks all translations remain as expected.
+    #[test]
+    fn random_splits_round_trip() {
+        const SEEDS: u64 = 16;
+        const STEPS: usize = 48;
+        // Pages are picked near these, so that the same large pages are split
+        // repeatedly, and pages are made present again.
+        const REGIONS: [u64; 4] = [0x20_0000, 0x4000_0000, 0x7f_c000_0000, 0x12_3440_0000];
+
+        test_support::init_platform_ops();
+        for seed in 0..SEEDS {
+            let mut random = SplitMix64::new(seed);
+            let mut pt = PagingStructures::new();
+            pt.build_identity().unwrap();
+
+            let mut non_present = Vec::new();
+            for _ in 0..STEPS {
+                let region = REGIONS[(random.next() % REGIONS.len() as u64) as usize];
+                let page = region + ((random.next() % 0x40_0000) & !0xfff);
+                let present = non_present.contains(&page);
+                unsafe { set_page_present(pt.pml4_pa(), page, present) }.unwrap();
+                if present {
+                    non_present.retain(|p| *p != page);
+                } else {
+                    non_present.push(page);
                 }
-                // Make the null page invalid to detect null pointer access.
-                ps.pt.0.entries[0].set_present(false);
+
+                if let Err(e) = pt.self_check(&non_present, 64) {
+                    panic!("seed {seed}: {e}");
+                }
+            }
+
+            // All pages made present again translate as before the splits.
+            for page in core::mem::take(&mut non_present) {
+                unsafe { set_page_present(pt.pml4_pa(), page, true) }.unwrap();
+            }
+            pt.self_check(&[], 256).unwrap();
+        }
+    }
+
+    /// Sets each combination of permissions on a large page, splits it, and
+    /// checks the pages split from it have the same permissions.
+    #[test]
+    fn splits_preserve_permissions() {
+        test_support::init_platform_ops();
+        for bits in 0..8 {
+            let (writable, user, no_execute) = (bits & 1 != 0, bits & 2 != 0, bits & 4 != 0);
+            let mut pt = PagingStructures::new();
+            pt.build_identity().unwrap();
+
+            // Make the upper levels permissive, so that the leaf entry decides.
+            pt.ptr.pml4.0.entries[0].set_user(true);
+            let pdpte = &mut pt.ptr.pdpt.0.entries[3];
+            let leaf = if pdpte.large() {
+                pdpte
             } else {
-                pde.set_present(true);
-                pde.set_writable(true);
-                pde.set_user(user);
-                pde.set_large(true);
-                pde.set_pfn(pa >> BASE_PAGE_SHIFT);
-                pa += LARGE_PAGE_SIZE as u64;
+                pdpte.set_user(true);
+                &mut pt.pds.get_mut(&3).unwrap().0.entries[5]
+            };
+            leaf.set_writable(writable);
+            leaf.set_user(user);
+            leaf.set_no_execute(no_execute);
+            let page_size = if leaf.large() && pt.ptr.pdpt.0.entries[3].large() {
+                PageSize::Size1Gb
+            } else {
+                PageSize::Size2Mb
+            };
+
+            let va = 0xc0a0_7000;
+            let before = translate(pt.pml4_pa(), va + 0x1000).unwrap();
+            assert_eq!(before.page_size, page_size);
+            assert_eq!(
+                (before.writable, before.user, before.no_execute),
+                (writable, user, no_execute)
+            );
+
+            unsafe { set_page_present(pt.pml4_pa(), va, false) }.unwrap();
+            for neighbour in [va - 0x1000, va + 0x1000, va + 0x1_0000] {
+                let after = translate(pt.pml4_pa(), neighbour).unwrap();
+                assert_eq!(after.page_size, PageSize::Size4Kb);
+                assert_eq!(after.gpa, neighbour);
+                assert_eq!(
+                    (after.writable, after.user, after.no_execute),
+                    (writable, user, no_execute)
+                );
             }
+            assert!(matches!(
+                translate(pt.pml4_pa(), va),
+                Err(TranslationError::NotPresent { level: 1, .. })
+            ));
+        }
+    }
+
+    /// Makes pages non-present and present again, and checks the 2MB page split
+    /// for them is merged back only once all of its pages map it as before.
+    #[test]
+    fn splits_merge_back_round_trip() {
+        test_support::init_platform_ops();
+        let mut pt = PagingStructures::new();
+        pt.build_identity().unwrap();
+        let pml4_pa = pt.pml4_pa();
+
+        let va = 0x4020_3000;
+        let before = translate(pml4_pa, va).unwrap();
+        unsafe { set_page_present(pml4_pa, va, false) }.unwrap();
+        unsafe { set_page_present(pml4_pa, va + 0x1000, false) }.unwrap();
+        unsafe { set_page_present(pml4_pa, va, true) }.unwrap();
+        let split = translate(pml4_pa, va).unwrap();
+        assert_eq!(split.page_size, PageSize::Size4Kb);
+
+        unsafe { set_page_present(pml4_pa, va + 0x1000, true) }.unwrap();
+        for va in [va, va + 0x1000, 0x4020_0000, 0x403f_f000] {
+            let merged = translate(pml4_pa, va).unwrap();
+            assert_eq!(merged.page_size, PageSize::Size2Mb);
+            assert_eq!(merged.gpa, va);
+            assert_eq!(
+                (merged.writable, merged.user, merged.no_execute),
+                (before.writable, before.user, before.no_execute)
+            );
+        }
+        pt.self_check(&[], 256).unwrap();
+
+        // A page with other flags than the rest keeps the 2MB page split.
+        let va = 0x60_5000;
+        unsafe { set_page_present(pml4_pa, va, false) }.unwrap();
+        let pde = pt.split_pd(0).unwrap().0.entries[3];
+        let table = unsafe {
+            &mut *platform_ops::get()
+                .va(pde.pfn() << BASE_PAGE_SHIFT)
+                .cast::<Table>()
+        };
+        table.entries[7].set_writable(false);
+        unsafe { set_page_present(pml4_pa, va, true) }.unwrap();
+        let read_only = translate(pml4_pa, 0x60_7000).unwrap();
+        assert_eq!(read_only.page_size, PageSize::Size4Kb);
+        assert!(!read_only.writable);
+
+        table.entries[7].set_writable(true);
+        unsafe { set_page_present(pml4_pa, va, true) }.unwrap();
+        assert_eq!(
+            translate(pml4_pa, 0x60_7000).unwrap().page_size,
+            PageSize::Size2Mb
+        );
+        pt.self_check(&[], 256).unwrap();
+    }
+
+    /// Sets each combination of permissions on a 2MB page, splits it and merges
+    /// it back, and checks the merged page has the same permissions.
+    #[test]
+    fn merges_preserve_permissions() {
+        test_support::init_platform_ops();
+        for bits in 0..8 {
+            let (writable, user, no_execute) = (bits & 1 != 0, bits & 2 != 0, bits & 4 != 0);
+            let mut pt = PagingStructures::new();
+            pt.build_identity().unwrap();
+
+            // Make the upper levels permissive, so that the leaf entry decides.
+            pt.ptr.pml4.0.entries[0].set_user(true);
+            pt.ptr.pdpt.0.entries[0].set_user(true);
+            let pde = &mut pt.pds.get_mut(&0).unwrap().0.entries[5];
+            pde.set_writable(writable);
+            pde.set_user(user);
+            pde.set_no_execute(no_execute);
+
+            let va = 0xa0_7000;
+            unsafe { set_page_present(pt.pml4_pa(), va, false) }.unwrap();
+            unsafe { set_page_present(pt.pml4_pa(), va, true) }.unwrap();
+            let merged = translate(pt.pml4_pa(), va).unwrap();
+            assert_eq!(merged.page_size, PageSize::Size2Mb);
+            assert_eq!(merged.gpa, va);
+            assert_eq!(
+                (merged.writable, merged.user, merged.no_execute),
+                (writable, user, no_execute)
+            );
+        }
+    }
+
+    /// Checks the identity map for NPT translates each GPA to itself, including
+    /// the null page, with user access permitted, as nested page table walks
+    /// are user accesses. Splits and merges must keep it so.
+    #[test]
+    fn npt_identity_map_permits_user_access() {
+        const LIMIT: u64 = HUGE_PAGE_SIZE as u64 * 512;
+
+        test_support::init_platform_ops();
+        let mut npt = PagingStructures::new();
+        npt.build_identity_internal(true).unwrap();
+        let ncr3 = npt.pml4_pa();
+
+        let mut random = SplitMix64::new(0);
+        let samples: Vec<u64> = (0..256).map(|_| random.next() % LIMIT).collect();
+        for gpa in [0, 0xfff, 0x1000, 0x20_0000, 0x4000_0000, LIMIT - 1]
+            .into_iter()
+            .chain(samples)
+        {
+            let translation = translate(ncr3, gpa).unwrap();
+            assert_eq!(translation.gpa, gpa, "{gpa:#x}");
+            assert!(translation.user && translation.writable && !translation.no_execute);
+            assert_ne!(translation.page_size, PageSize::Size4Kb);
         }
+        assert_eq!(walk(ncr3, LIMIT), None);
+
+        let gpa = 0x1_2345_6000;
+        unsafe { set_page_present(ncr3, gpa, false) }.unwrap();
+        assert_eq!(walk(ncr3, gpa), None);
+        let neighbour = translate(ncr3, gpa + 0x1000).unwrap();
+        assert_eq!(neighbour.page_size, PageSize::Size4Kb);
+        assert!(neighbour.user && neighbour.writable);
+
+        unsafe { set_page_present(ncr3, gpa, true) }.unwrap();
+        let merged = translate(ncr3, gpa).unwrap();
+        assert_eq!(merged.page_size, PageSize::Size2Mb);
+        assert_eq!(merged.gpa, gpa);
+        assert!(merged.user && merged.writable && !merged.no_execute);
     }
 }
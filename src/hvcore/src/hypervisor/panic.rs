@@ -1,5 +1,31 @@
+//! This module implements the panic handler of the hypervisor. It dumps what
+//! helps debugging through the logger before halting the processor: the panic
+//! message, the registers, the latest VM-exits, and a backtrace. With
+//! `SharedHostData::fail_open`, the guest on the current processor is resumed
+//! without the hypervisor instead of halting, unless any lock shared across
+//! processors stays held.
+//!
+//! The backtrace walks the chain of frame pointers, so it is only complete
+//! when the hypervisor is built with `-C force-frame-pointers=yes`.
+
+use crate::hypervisor::{
+    exit_trace, host,
+    registers::Registers,
+    serial_logger,
+    x86_instructions::{cr0, cr2, cr3, cr4},
+};
+
 pub fn panic_impl(info: &core::panic::PanicInfo<'_>) -> ! {
+    // The panic may have occurred while logging. Let us log.
+    serial_logger::release_if_owned();
+
     log::error!("{info}");
+    let registers = Registers::capture_current();
+    dump_registers(&registers);
+    exit_trace::dump_current();
+    dump_backtrace(registers.rbp);
+    exit_trace::dump();
+    host::fail_open();
     loop {
         unsafe {
             x86::irq::disable();
@@ -7,3 +33,75 @@ pub fn panic_impl(info: &core::panic::PanicInfo<'_>) -> ! {
         };
     }
 }
+
+fn dump_registers(registers: &Registers) {
+    log::error!(
+        "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+        registers.rax,
+        registers.rbx,
+        registers.rcx,
+        registers.rdx
+    );
+    log::error!(
+        "RSI={:016x} RDI={:016x} RSP={:016x} RBP={:016x}",
+        registers.rsi,
+        registers.rdi,
+        registers.rsp,
+        registers.rbp
+    );
+    log::error!(
+        "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+        registers.r8,
+        registers.r9,
+        registers.r10,
+        registers.r11
+    );
+    log::error!(
+        "R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+        registers.r12,
+        registers.r13,
+        registers.r14,
+        registers.r15
+    );
+    log::error!("RFLAGS={:016x}", registers.rflags);
+    log::error!(
+        "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}",
+        cr0().bits(),
+        cr2(),
+        cr3(),
+        cr4().bits()
+    );
+}
+
+/// Logs the return addresses by walking the frame pointers from `rbp`.
+fn dump_backtrace(rbp: u64) {
+    // Each frame starts with the caller's RBP followed by the return address.
+    // Stop at anything that does not look like a frame on the same stack, to
+    // avoid faulting while dumping.
+    const MAX_FRAMES: usize = 32;
+    const MAX_FRAME_SIZE: u64 = 0x10_0000;
+
+    log::error!("Backtrace:");
+    let mut rbp = rbp;
+    for i in 0..MAX_FRAMES {
+        if rbp == 0 || !rbp.is_multiple_of(8) || !is_canonical(rbp) {
+            break;
+        }
+        let frame = rbp as *const u64;
+        let (next_rbp, return_address) = unsafe { (*frame, *frame.add(1)) };
+        if return_address == 0 {
+            break;
+        }
+        log::error!("  #{i:02} {return_address:#018x}");
+        if next_rbp <= rbp || next_rbp - rbp > MAX_FRAME_SIZE {
+            break;
+        }
+        rbp = next_rbp;
+    }
+}
+
+/// Returns whether `address` is canonical under 4-level paging.
+fn is_canonical(address: u64) -> bool {
+    let upper = address >> 47;
+    upper == 0 || upper == 0x1_ffff
+}
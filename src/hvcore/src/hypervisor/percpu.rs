@@ -0,0 +1,229 @@
+//! This module implements the per-processor data blocks of the host.
+//!
+//! Each processor has its own block. With the host IDT, the host runs with the
+//! GS base identifying the block, which lets it find its per-processor data
+//! without looking up the processor ID from the APIC ID, which is also safe in
+//! the NMI handler. Without the host IDT, the host runs with the GS base of the
+//! guest, which the interrupt handlers of the guest expect, such as those of
+//! Windows, which expect the GS base to point to the KPCR, and looks up the
+//! block with the APIC ID.
+//!
+//! The GS base of the host is loaded by the processor on VM-exit, and never
+//! written in the guest context, where the interrupt handlers of the guest
+//! may use it. The blocks are allocated for all processors enumerated by
+//! `apic_id::init` before any processor is virtualized, and for processors
+//! brought online later when `virtualize_processor` is called on them. Blocks
+//! are never freed.
+
+use core::sync::atomic::{AtomicBool, AtomicU64};
+
+use alloc::boxed::Box;
+use spin::{Mutex, Once};
+
+use crate::hypervisor::{
+    apic_id::{self, ApicId, ProcessorId, APIC_ID_MAP},
+    cr3_tracking::Cr3Cache,
+    deferred_work::WorkQueue,
+    exit_stats::ExitStats,
+    exit_trace::ExitTrace,
+    host::FailOpenContext,
+    interrupt_virtualization::{HostInterrupts, PostedInterrupts},
+    log_buffer::LogBuffer,
+    logger::LOG_BUFFER_SIZE,
+    serial_logger::SERIAL_PENDING_SIZE,
+    smm::SmiCounter,
+    tlb::PendingFlushes,
+    watchdog::Heartbeat,
+    x86_instructions::rdmsr,
+    SharedHostData,
+};
+
+/// The data block of a processor.
+#[derive(Debug)]
+#[repr(C)]
+pub(crate) struct PerCpu {
+    /// The processor ID.
+    pub(crate) id: ProcessorId,
+
+    /// The APIC ID.
+    pub(crate) apic_id: ApicId,
+
+    /// Whether an NMI occurred in the host and is not delivered to the guest
+    /// yet.
+    pub(crate) host_nmi: AtomicBool,
+
+    /// Whether the host is handling an exception.
+    pub(crate) in_exception: AtomicBool,
+
+    /// The heartbeat checked by the watchdog of the other processors.
+    pub(crate) heartbeat: Heartbeat,
+
+    /// The statistics of VM-exits.
+    pub(crate) exit_stats: ExitStats,
+
+    /// The latest VM-exits.
+    pub(crate) exit_trace: ExitTrace,
+
+    /// The flushes of the guest translations requested for the processor. See
+    /// `tlb`.
+    pub(crate) tlb_flushes: PendingFlushes,
+
+    /// The work queued with `deferred_work::defer` for the processor.
+    pub(crate) deferred_work: WorkQueue,
+
+    /// The generation of the hooks the processor applied. See
+    /// `ept_hook::generation`.
+    pub(crate) hook_generation: AtomicU64,
+
+    /// The address spaces the CR3 observer ignores. Only used on the processor
+    /// owning the block.
+    pub(crate) cr3_cache: Mutex<Cr3Cache>,
+
+    /// The guest to resume without the hypervisor on panic, while the host
+    /// runs it with `SharedHostData::fail_open`.
+    pub(crate) fail_open: Mutex<Option<FailOpenContext>>,
+
+    /// The SMIs observed on the processor. See `smm`.
+    pub(crate) smi: SmiCounter,
+
+    /// The logs written on this processor.
+    pub(crate) log: LogBuffer,
+
+    /// The logs waiting for the serial port, which another processor was
+    /// writing to.
+    pub(crate) serial_pending: LogBuffer,
+
+    /// The interrupts posted to the processor with
+    /// `SharedHostData::interrupt_virtualization`. See `ipi::send_ipi`.
+    pub(crate) posted_interrupts: PostedInterrupts,
+
+    /// The external interrupts the host took for the guest with AVIC. See
+    /// `amd::avic`.
+    pub(crate) host_interrupts: HostInterrupts,
+}
+
+// Safety: the mutable fields are atomic or locked. `fail_open` is only used on
+// the processor owning the block.
+unsafe impl Send for PerCpu {}
+unsafe impl Sync for PerCpu {}
+
+/// Allocates the blocks for all enumerated processors as configured in
+/// `shared_host`, and the slots for those virtualized later with
+/// `virtualize_processor`. Must be called from the guest context after
+/// `apic_id::init`, and before any processor is virtualized. The blocks
+/// allocated for the first call are kept for subsequent calls.
+pub(crate) fn init(shared_host: &SharedHostData) {
+    let _ = BLOCKS.call_once(|| {
+        let slots: Box<[Once<Box<PerCpu>>]> =
+            (0..apic_id::capacity()).map(|_| Once::new()).collect();
+        for (&apic_id, &id) in APIC_ID_MAP.read().iter() {
+            let _ = slots[id].call_once(|| new_block(id, apic_id, shared_host));
+        }
+        slots
+    });
+}
+
+/// Allocates the block of the processor `id` with `apic_id` if not yet. Must be
+/// called from the guest context after `init`.
+pub(crate) fn allocate(id: ProcessorId, apic_id: ApicId, shared_host: &SharedHostData) {
+    let slots = BLOCKS.get().expect("per-processor blocks are allocated");
+    let _ = slots[id].call_once(|| new_block(id, apic_id, shared_host));
+}
+
+/// Returns the new block of the processor `id` with `apic_id`.
+fn new_block(id: ProcessorId, apic_id: ApicId, shared_host: &SharedHostData) -> Box<PerCpu> {
+    Box::new(PerCpu {
+        id,
+        apic_id,
+        host_nmi: AtomicBool::new(false),
+        in_exception: AtomicBool::new(false),
+        heartbeat: Heartbeat::default(),
+        exit_stats: ExitStats::new(),
+        exit_trace: ExitTrace::new(shared_host.exit_trace_len),
+        hook_generation: AtomicU64::new(0),
+        tlb_flushes: PendingFlushes::default(),
+        deferred_work: WorkQueue::default(),
+        cr3_cache: Mutex::new(Cr3Cache::new()),
+        fail_open: Mutex::new(None),
+        smi: SmiCounter::default(),
+        log: LogBuffer::new(LOG_BUFFER_SIZE),
+        serial_pending: LogBuffer::new(SERIAL_PENDING_SIZE),
+        posted_interrupts: PostedInterrupts::default(),
+        host_interrupts: HostInterrupts::default(),
+    })
+}
+
+/// Returns the GS base for the host on the processor `id` as configured in
+/// `shared_host`: the address of the slot of the block with the host IDT, or
+/// `guest_gs_base` otherwise. Must be called on the processor `id` while
+/// setting up the host, which lets the processor load the GS base on VM-exit.
+pub(crate) fn host_gs_base(
+    id: ProcessorId,
+    guest_gs_base: u64,
+    shared_host: &SharedHostData,
+) -> u64 {
+    let slots = BLOCKS.get().expect("per-processor blocks are allocated");
+    let block = slots[id].get().expect("the block is allocated");
+    assert_eq!(block.apic_id, apic_id::get());
+    if shared_host.idt.is_some() {
+        core::ptr::from_ref(&slots[id]) as u64
+    } else {
+        guest_gs_base
+    }
+}
+
+/// Returns the block of the current processor. Must be called from the host.
+/// Before the first VM-exit, and without the host IDT, the block is looked up
+/// with the APIC ID.
+pub(crate) fn current() -> &'static PerCpu {
+    from_gs_base()
+        .or_else(find_current)
+        .expect("the block of the current processor is allocated")
+}
+
+/// Returns the block of the current processor if the GS base is that of the
+/// host returned by `host_gs_base`. Only compares the GS base with the
+/// addresses of the slots, as any other value may be the GS base of the guest.
+fn from_gs_base() -> Option<&'static PerCpu> {
+    let slots = BLOCKS.get()?;
+    let offset = rdmsr(x86::msr::IA32_GS_BASE).wrapping_sub(slots.as_ptr() as u64);
+    let slot_size = core::mem::size_of::<Once<Box<PerCpu>>>() as u64;
+    if !offset.is_multiple_of(slot_size) {
+        return None;
+    }
+    let index = usize::try_from(offset / slot_size).ok()?;
+    slots.get(index)?.get().map(AsRef::as_ref)
+}
+
+/// Returns the block of the current processor looked up with the APIC ID. Unlike
+/// `current`, this can be called from both the host and the guest context.
+/// `None` if the block is not allocated yet.
+pub(crate) fn find_current() -> Option<&'static PerCpu> {
+    get(apic_id::processor_id_from(apic_id::get())?)
+}
+
+/// Returns the block of the processor `id`, or `None` if not allocated yet.
+pub(crate) fn get(id: ProcessorId) -> Option<&'static PerCpu> {
+    BLOCKS.get()?.get(id)?.get().map(AsRef::as_ref)
+}
+
+/// Returns the blocks of all processors allocated so far, in the order of the
+/// processor ID.
+pub(crate) fn all() -> impl Iterator<Item = &'static PerCpu> + Clone {
+    try_all().expect("per-processor blocks are allocated")
+}
+
+/// Returns the blocks of all processors like `all`, or `None` if `init` is not
+/// called yet.
+pub(crate) fn try_all() -> Option<impl Iterator<Item = &'static PerCpu> + Clone> {
+    let slots = BLOCKS.get()?;
+    Some(
+        slots
+            .iter()
+            .filter_map(|slot| slot.get().map(AsRef::as_ref)),
+    )
+}
+
+/// The slots of the blocks, indexed by the processor ID. Allocated blocks are
+/// never freed.
+static BLOCKS: Once<Box<[Once<Box<PerCpu>>]>> = Once::new();
@@ -1,13 +1,50 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use alloc::boxed::Box;
 
 /// A set of platform specific API to be called during the host setup phase.
+///
+/// `pa` and `va` are also called from the host after the setup, for example,
+/// to install EPT hooks, and must keep working after [`retire`] is called.
 pub trait PlatformOps {
     /// Runs `callback` on all logical processors one by one.
     // This function cannot be called in a nested manner.
     fn run_on_all_processors(&self, callback: fn());
 
+    /// Runs `callback` on the logical processor `index`, where processors are
+    /// indexed from zero in the order `run_on_all_processors` runs callbacks
+    /// on them. Panics if `index` is not a valid index.
+    // This function cannot be called in a nested manner.
+    fn run_on_processor(&self, index: usize, callback: fn());
+
+    /// Returns the index of the current logical processor, as taken by
+    /// `run_on_processor`.
+    fn current_processor_index(&self) -> usize;
+
     // Returns a physical address of a linear address specified by `va`.
     fn pa(&self, va: *const core::ffi::c_void) -> u64;
+
+    // Returns a linear address of a physical address specified by `pa`. The
+    // physical address must be mapped into the current address space.
+    fn va(&self, pa: u64) -> *mut core::ffi::c_void;
+
+    /// Returns `size` bytes of 4096 byte-aligned memory that is never paged out,
+    /// or null. Called from `virtualize_system` to extend the heap to
+    /// `SharedHostData::heap_size`. The memory must stay valid while the
+    /// hypervisor runs. Returns null by default.
+    fn allocate_heap(&self, _size: usize) -> *mut u8 {
+        core::ptr::null_mut()
+    }
+
+    /// Returns 4096 byte-aligned memory and its size in bytes to extend the
+    /// heap when it runs out, or `None`. Unlike the other functions, this is
+    /// called after the host is set up, thus, must only hand out memory
+    /// allocated beforehand without calling platform API or allocating memory.
+    /// The memory is not hidden with `SharedHostData::hide_host_memory`.
+    /// Returns `None` by default.
+    fn donate_heap(&self) -> Option<(*mut u8, usize)> {
+        None
+    }
 }
 
 /// Initializes the platform specific API as provided by `ops`.
@@ -16,9 +53,57 @@ pub fn init(ops: Box<dyn PlatformOps>) {
     unsafe { PLATFORM_OPS = Some(Box::leak(ops)) };
 }
 
+/// Marks the platform specific API unavailable except `pa` and `va`, for
+/// example, when UEFI boot services are exited while the hypervisor keeps
+/// running. The other functions panic after this, except `allocate_heap` and
+/// `donate_heap`, which return no memory.
+///
+/// Safe to call while the host runs on other processors.
+pub fn retire() {
+    RETIRED.store(true, Ordering::Release);
+}
+
 /// Returns the platform specific API.
 pub fn get() -> &'static dyn PlatformOps {
-    *unsafe { PLATFORM_OPS.as_ref() }.unwrap()
+    if RETIRED.load(Ordering::Acquire) {
+        return &RetiredOps;
+    }
+    unsafe { PLATFORM_OPS }.unwrap()
+}
+
+/// Returns the platform specific API if initialized.
+// The global allocator, the only user, is not used in tests.
+#[cfg(not(test))]
+pub(crate) fn try_get() -> Option<&'static dyn PlatformOps> {
+    unsafe { PLATFORM_OPS }?;
+    Some(get())
+}
+
+/// The platform specific API after `retire`. Forwards address translation to
+/// the registered API, which is never replaced once registered.
+struct RetiredOps;
+
+impl PlatformOps for RetiredOps {
+    fn run_on_all_processors(&self, _callback: fn()) {
+        panic!("the platform API is retired");
+    }
+
+    fn run_on_processor(&self, _index: usize, _callback: fn()) {
+        panic!("the platform API is retired");
+    }
+
+    fn current_processor_index(&self) -> usize {
+        panic!("the platform API is retired");
+    }
+
+    fn pa(&self, va: *const core::ffi::c_void) -> u64 {
+        unsafe { PLATFORM_OPS }.unwrap().pa(va)
+    }
+
+    fn va(&self, pa: u64) -> *mut core::ffi::c_void {
+        unsafe { PLATFORM_OPS }.unwrap().va(pa)
+    }
 }
 
 static mut PLATFORM_OPS: Option<&dyn PlatformOps> = None;
+static RETIRED: AtomicBool = AtomicBool::new(false);
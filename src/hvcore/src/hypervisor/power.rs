@@ -0,0 +1,98 @@
+//! This module implements detection of the guest entering ACPI sleep states,
+//! the S1-S5 states including S3 (suspend to RAM) and S4 (hibernation). The
+//! hypervisor does not survive the power loss of sleep, as processors are reset
+//! on wake, and the system resumes without the hypervisor.
+//!
+//! The embedder that gets notified of power transitions, such as with the
+//! `\Callback\PowerState` callback on Windows and PM notifiers on Linux, calls
+//! `devirtualize_system` before sleep and `revirtualize_system` on resume.
+//!
+//! Enabled with `SharedHostData::sleep_detection`, the processor writing
+//! SLP_EN to the PM1a control register additionally devirtualizes itself right
+//! before the write, which the guest executes again without the hypervisor.
+//! This covers sleep the embedder is not notified of, and can only be undone
+//! with `revirtualize_system`. The other processors stay virtualized until
+//! they lose power. Writes to the PM1b control register are not intercepted.
+//!
+//! The handler of the port in `SharedHostData::io_intercepts`, if any, is kept
+//! and called on the other writes.
+//!
+//! ```ignore
+//! // The PM1a_CNT_BLK field of the FADT.
+//! shared_host.sleep_detection = SleepDetection::new(fadt.pm1a_control_block);
+//! ```
+
+use crate::hypervisor::{
+    host::IoInstructionInfo,
+    io_intercepts::{IoHandler, IoIntercepts},
+};
+
+/// The configuration of the sleep detection. Disabled by default.
+#[derive(Debug, Default)]
+pub struct SleepDetection {
+    pm1a_control: Option<u16>,
+}
+
+impl SleepDetection {
+    /// Enables the sleep detection with the I/O port of the PM1a control
+    /// register, as found in the PM1a_CNT_BLK field of the FADT.
+    pub fn new(pm1a_control: u16) -> Self {
+        Self {
+            pm1a_control: Some(pm1a_control),
+        }
+    }
+
+    /// Returns whether the sleep detection is enabled.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.pm1a_control.is_some()
+    }
+
+    /// Returns whether `info` and `value` of RAX are a write to the PM1a
+    /// control register entering a sleep state, and logs the state if so.
+    pub(crate) fn is_entering_sleep(&self, info: &IoInstructionInfo, value: u64) -> bool {
+        if self.pm1a_control != Some(info.port) || info.is_in || info.string || info.size < 2 {
+            return false;
+        }
+        let Some(sleep_type) = sleep_type_of(value as u16) else {
+            return false;
+        };
+        log::info!("Entering the sleep state with SLP_TYP {sleep_type}");
+        true
+    }
+}
+
+/// Returns `intercepts` with the PM1a control register intercepted for
+/// `detection`. Called from the guest before any processor is virtualized.
+pub(crate) fn install(intercepts: IoIntercepts, detection: &SleepDetection) -> IoIntercepts {
+    match detection.pm1a_control {
+        Some(port) if intercepts.handler(port).is_none() => intercepts.on_port(port, PassThrough),
+        _ => intercepts,
+    }
+}
+
+/// The handler passing through all accesses, to intercept the port.
+struct PassThrough;
+
+impl IoHandler for PassThrough {}
+
+/// Returns SLP_TYP of `pm1_control` if SLP_EN is set.
+/// See: 4.8.3.2.1 PM1 Control Registers (ACPI)
+fn sleep_type_of(pm1_control: u16) -> Option<u8> {
+    const SLP_EN: u16 = 1 << 13;
+    const SLP_TYP_SHIFT: u16 = 10;
+
+    (pm1_control & SLP_EN != 0).then_some(((pm1_control >> SLP_TYP_SHIFT) & 0b111) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sleep_types_are_decoded() {
+        assert_eq!(sleep_type_of(0x0001), None);
+        assert_eq!(sleep_type_of(0x1401), None);
+        assert_eq!(sleep_type_of(0x3401), Some(5));
+        assert_eq!(sleep_type_of(0x2000), Some(0));
+    }
+}
@@ -0,0 +1,154 @@
+//! This module implements the periodic VM-exit with the VMX-preemption timer.
+//! The embedder of this crate sets a period in TSC ticks and a handler, and
+//! each processor calls the handler at least once per period, regardless of
+//! whether the guest causes any other VM-exit. This is the building block of
+//! watchdogs, periodic harvesting of statistics and time slicing.
+//!
+//! Enabled with `SharedHostData::preemption_timer`, the timer is armed on every
+//! VM-entry with the time left until the next deadline of the processor. The
+//! deadline moves one period past the time the handler is called, so a
+//! processor that is late does not call the handler several times in a row.
+//!
+//! SVM has no equivalent of the timer. On AMD processors, it is emulated with
+//! the timer of the local APIC, which the host takes over from the guest, if a
+//! vector for it is set with `PreemptionTimer::with_apic_timer_vector`. See
+//! `amd::apic_timer`.
+//!
+//! ```ignore
+//! shared_host.preemption_timer = PreemptionTimer::new(tsc_per_second, |vcpu| {
+//!     log::info!("Processor {} is alive", vcpu.id());
+//! })
+//! .with_apic_timer_vector(0xee);
+//! ```
+
+use alloc::boxed::Box;
+
+use crate::hypervisor::host::Vcpu;
+
+/// Represents a handler called on expiry of the timer. Handlers run in the
+/// host context with interrupts disabled. They must not call any platform API
+/// and should return as soon as possible.
+pub type TimerHandler = dyn Fn(&mut dyn Vcpu) + Send + Sync;
+
+/// The configuration of the periodic VM-exit. Disabled by default.
+#[derive(Default)]
+pub struct PreemptionTimer {
+    period: u64,
+    handler: Option<Box<TimerHandler>>,
+    apic_timer_vector: Option<u8>,
+}
+
+impl PreemptionTimer {
+    /// Enables the periodic VM-exit every `period` TSC ticks with `handler`.
+    /// `period` of zero is treated as one.
+    pub fn new(period: u64, handler: impl Fn(&mut dyn Vcpu) + Send + Sync + 'static) -> Self {
+        Self {
+            period: period.max(1),
+            handler: Some(Box::new(handler)),
+            apic_timer_vector: None,
+        }
+    }
+
+    /// Emulates the timer with the timer of the local APIC on AMD processors,
+    /// which interrupts the host with `vector`. It must be 32 or greater and
+    /// not used by the devices and the guest, as an interrupt with it is taken
+    /// as the expiry and not delivered to the guest. An interrupt with it
+    /// pending while the processor is being devirtualized may still reach the
+    /// guest once. Without this, the timer is not available on AMD processors.
+    /// Intel processors ignore the vector.
+    #[must_use]
+    pub fn with_apic_timer_vector(mut self, vector: u8) -> Self {
+        self.apic_timer_vector = Some(vector);
+        self
+    }
+
+    /// Returns whether the periodic VM-exit is enabled.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.handler.is_some()
+    }
+
+    /// Returns the period in TSC ticks.
+    pub(crate) fn period(&self) -> u64 {
+        self.period
+    }
+
+    /// Returns the vector of the timer of the local APIC emulating the timer,
+    /// if enabled and set.
+    #[cfg(feature = "amd")]
+    pub(crate) fn apic_timer_vector(&self) -> Option<u8> {
+        self.apic_timer_vector.filter(|_| self.is_enabled())
+    }
+
+    /// Calls the handler on `vcpu`.
+    pub(crate) fn expire(&self, vcpu: &mut dyn Vcpu) {
+        if let Some(handler) = &self.handler {
+            handler(vcpu);
+        }
+    }
+}
+
+impl core::fmt::Debug for PreemptionTimer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PreemptionTimer")
+            .field("enabled", &self.is_enabled())
+            .field("period", &self.period)
+            .field("apic_timer_vector", &self.apic_timer_vector)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The deadline of the timer on a processor.
+#[derive(Debug)]
+pub(crate) struct TimerDeadline {
+    deadline: u64,
+    period: u64,
+}
+
+impl TimerDeadline {
+    /// Starts the first period at `now`.
+    pub(crate) fn new(now: u64, period: u64) -> Self {
+        Self {
+            deadline: now.saturating_add(period),
+            period,
+        }
+    }
+
+    /// Returns the TSC at which the current period ends.
+    #[cfg(feature = "amd")]
+    pub(crate) fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    /// Returns the value to arm the timer with at `now`, where the timer counts
+    /// down by one every `1 << rate` TSC ticks. The value is rounded up so that
+    /// the timer never expires before the deadline.
+    /// See: 26.5.1 VMX-Preemption Timer
+    #[cfg(any(feature = "intel", test))]
+    pub(crate) fn timer_value(&self, now: u64, rate: u8) -> u32 {
+        let left = self.deadline.saturating_sub(now);
+        let value = left.div_ceil(1 << rate);
+        u32::try_from(value).unwrap_or(u32::MAX)
+    }
+
+    /// Starts the next period at `now`, on expiry of the timer. As the timer
+    /// is armed with the value rounded up, it expires only after the deadline.
+    pub(crate) fn restart(&mut self, now: u64) {
+        self.deadline = now.saturating_add(self.period);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadline_is_rounded_up_and_restarts() {
+        let mut deadline = TimerDeadline::new(1000, 100);
+        assert_eq!(deadline.timer_value(1000, 5), 4);
+        assert_eq!(deadline.timer_value(1099, 5), 1);
+        assert_eq!(deadline.timer_value(1200, 5), 0);
+
+        deadline.restart(1250);
+        assert_eq!(deadline.timer_value(1250, 0), 100);
+    }
+}
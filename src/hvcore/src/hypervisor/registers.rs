@@ -1,34 +1,46 @@
-use core::arch::global_asm;
+use core::{alloc::Layout, arch::global_asm};
 
+use alloc::alloc::handle_alloc_error;
+use x86::controlregs::{Cr4, Xcr0};
+
+use crate::hypervisor::x86_instructions::cr4;
+
+/// The guest general purpose registers and some more that are saved and
+/// restored on each VM-exit and VM-entry.
 #[derive(Clone, Copy, Debug, Default)]
 #[repr(C)]
-pub(crate) struct Registers {
-    pub(crate) rax: u64,
-    pub(crate) rbx: u64,
-    pub(crate) rcx: u64,
-    pub(crate) rdx: u64,
-    pub(crate) rdi: u64,
-    pub(crate) rsi: u64,
-    pub(crate) rbp: u64,
-    pub(crate) r8: u64,
-    pub(crate) r9: u64,
-    pub(crate) r10: u64,
-    pub(crate) r11: u64,
-    pub(crate) r12: u64,
-    pub(crate) r13: u64,
-    pub(crate) r14: u64,
-    pub(crate) r15: u64,
-    pub(crate) rflags: u64,
-    pub(crate) rsp: u64,
-    pub(crate) rip: u64,
-    pub(crate) xmm0: Xmm,
-    pub(crate) xmm1: Xmm,
-    pub(crate) xmm2: Xmm,
-    pub(crate) xmm3: Xmm,
-    pub(crate) xmm4: Xmm,
-    pub(crate) xmm5: Xmm,
-}
-const _: () = assert!(core::mem::size_of::<Registers>() == 0xf0);
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub rip: u64,
+    pub xmm0: Xmm,
+    pub xmm1: Xmm,
+    pub xmm2: Xmm,
+    pub xmm3: Xmm,
+    pub xmm4: Xmm,
+    pub xmm5: Xmm,
+    /// The shadow stack pointer, or zero if supervisor shadow stacks are
+    /// disabled. Unlike the others, this is not updated on each VM-exit, and
+    /// is only valid when captured and when returned by `Guest::deactivate`.
+    /// The guest value is held in the VMCS or VMCB while the guest runs.
+    pub ssp: u64,
+}
+const _: () = assert!(core::mem::size_of::<Registers>() == 0x100);
 
 impl Registers {
     #[inline(always)]
@@ -37,16 +49,211 @@ impl Registers {
         unsafe { capture_registers(&mut registers) };
         registers
     }
+
+    /// Returns the general purpose register numbered `index` as in the
+    /// instruction encoding: 0 for RAX, 1 for RCX, 2 for RDX, 3 for RBX, 4 for
+    /// RSP, 5 for RBP, 6 for RSI, 7 for RDI and 8-15 for R8-R15.
+    pub(crate) fn gpr(&self, index: u8) -> u64 {
+        match index & 0xf {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            4 => self.rsp,
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            _ => self.r15,
+        }
+    }
+
+    /// Sets the general purpose register numbered `index` as in `gpr`.
+    pub(crate) fn set_gpr(&mut self, index: u8, value: u64) {
+        let register = match index & 0xf {
+            0 => &mut self.rax,
+            1 => &mut self.rcx,
+            2 => &mut self.rdx,
+            3 => &mut self.rbx,
+            4 => &mut self.rsp,
+            5 => &mut self.rbp,
+            6 => &mut self.rsi,
+            7 => &mut self.rdi,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            _ => &mut self.r15,
+        };
+        *register = value;
+    }
 }
 
+/// The value of an XMM register.
 #[repr(C, align(16))]
 #[derive(Clone, Copy, Debug, Default)]
-pub(crate) struct Xmm {
-    pub(crate) low: u64,
-    pub(crate) hight: u64,
+pub struct Xmm {
+    pub low: u64,
+    pub hight: u64,
+}
+
+/// The extended register state switched between the guest and the host around
+/// VM-entry and VM-exit in addition to `Registers`: the x87, SSE, AVX and
+/// AVX-512 registers saved with `XSAVE` for the components enabled in XCR0, or
+/// with `FXSAVE` if XCR0 does not enable the SSE state, and the debug
+/// registers.
+///
+/// The guest DR7 is switched by the processor through the VMCS or VMCB, and so
+/// is DR6 on AMD. The guest DR0-DR3 and DR6 are held here while the host runs.
+/// The host DR7 is cleared while the guest DR0-DR3 are loaded, so that the host
+/// breakpoints do not hit with them.
+///
+/// The XSAVE areas are not freed on devirtualization, since the guest values
+/// are loaded from them at the very end of it.
+#[repr(C)]
+pub(crate) struct ExtendedRegisters {
+    /// The XSAVE area of the guest.
+    guest: *mut u8,
+    /// The XSAVE area of the host, saved while the guest runs.
+    host: *mut u8,
+    /// Non-zero to use `XSAVE` and `XRSTOR` instead of `FXSAVE` and `FXRSTOR`.
+    use_xsave: u64,
+    /// DR0-DR3 and DR6 of the guest. DR6 is held in the VMCB instead while the
+    /// guest runs on AMD.
+    guest_dr: [u64; 5],
+    /// DR0-DR3, DR6 and DR7 of the host, saved while the guest runs.
+    host_dr: [u64; 6],
+}
+const _: () = assert!(core::mem::size_of::<ExtendedRegisters>() == 0x70);
+
+// Safety: the XSAVE areas are owned by this struct.
+unsafe impl Send for ExtendedRegisters {}
+
+impl ExtendedRegisters {
+    /// Captures the current extended register state as the initial guest state.
+    #[inline(always)]
+    pub(crate) fn capture_current() -> Self {
+        let mut extended = Self {
+            guest: alloc_xsave_area(),
+            host: alloc_xsave_area(),
+            use_xsave: 0,
+            guest_dr: [0; 5],
+            host_dr: [0; 6],
+        };
+        let _ = extended.prepare();
+        unsafe { capture_extended_registers(&extended) };
+        extended
+    }
+
+    /// Updates the instructions to use for the current XCR0 and returns the
+    /// pointer to pass to `run_vmx_guest`, `run_svm_guest` or
+    /// `restore_registers`. Must be called right before them.
+    pub(crate) fn prepare(&mut self) -> *mut Self {
+        // CR4.OSXSAVE is required to execute `XGETBV` and `XSAVE`. The host sets
+        // it on initialization if `XSAVE` is supported.
+        self.use_xsave = u64::from(
+            cr4().contains(Cr4::CR4_ENABLE_OS_XSAVE)
+                && unsafe { x86::controlregs::xcr0() }.contains(Xcr0::XCR0_SSE_STATE),
+        );
+        self
+    }
+
+    /// Returns the guest value of DR0-DR3 or DR6.
+    pub(crate) fn dr(&self, index: u8) -> u64 {
+        self.guest_dr[Self::dr_slot(index)]
+    }
+
+    /// Sets the guest value of DR0-DR3 or DR6, loaded on the next VM-entry.
+    pub(crate) fn set_dr(&mut self, index: u8, value: u64) {
+        self.guest_dr[Self::dr_slot(index)] = value;
+    }
+
+    fn dr_slot(index: u8) -> usize {
+        match index {
+            0..=3 => usize::from(index),
+            6 => 4,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Drop for ExtendedRegisters {
+    fn drop(&mut self) {
+        unsafe {
+            alloc::alloc::dealloc(self.guest, xsave_area_layout());
+            alloc::alloc::dealloc(self.host, xsave_area_layout());
+        };
+    }
+}
+
+impl core::fmt::Debug for ExtendedRegisters {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ExtendedRegisters")
+            .field("use_xsave", &self.use_xsave)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns whether the processor supports `XSAVE`.
+pub(crate) fn is_xsave_supported() -> bool {
+    // See: Table 1-19. Feature Information Returned in the ECX Register
+    x86::cpuid::cpuid!(1).ecx & (1 << 26) != 0
 }
 
-extern "C" {
+/// Returns the layout of an XSAVE area large enough for all the components the
+/// processor supports.
+fn xsave_area_layout() -> Layout {
+    // The legacy region and the XSAVE header.
+    const MIN_SIZE: usize = 512 + 64;
+
+    // "ECX Bits 31-00: Maximum size (bytes, from the beginning of the
+    //  XSAVE/XRSTOR save area) of the XSAVE/XRSTOR save area required by all
+    //  supported features in the processor, i.e., all the valid bit fields in
+    //  XCR0."
+    // See: Table 1-21. Processor Extended State Enumeration Main Leaf (EAX = 0DH, ECX = 0)
+    let size = if is_xsave_supported() {
+        x86::cpuid::cpuid!(0xd, 0).ecx as usize
+    } else {
+        0
+    };
+
+    // "The XSAVE area must be 64-byte aligned."
+    // See: 13.4 XSAVE AREA
+    Layout::from_size_align(size.max(MIN_SIZE), 64).unwrap()
+}
+
+fn alloc_xsave_area() -> *mut u8 {
+    const XSTATE_BV_OFFSET: usize = 512;
+
+    let layout = xsave_area_layout();
+    let area = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    if area.is_null() {
+        handle_alloc_error(layout);
+    }
+
+    // Mark the x87 and SSE states as saved in the XSAVE header, so that `XRSTOR`
+    // loads them from the legacy region even when they were saved by `FXSAVE`.
+    // Otherwise, switching to `XSAVE` after the guest enables the SSE state in
+    // XCR0 would reset those registers.
+    // See: 13.4.2 XSAVE Header
+    unsafe { area.add(XSTATE_BV_OFFSET).cast::<u64>().write(0b11) };
+    area
+}
+
+extern "win64" {
+    /// Captures the current extended register state into the guest area and
+    /// the debug registers.
+    fn capture_extended_registers(extended: &ExtendedRegisters);
+
     /// Captures current register values.
     fn capture_registers(registers: &mut Registers);
 }
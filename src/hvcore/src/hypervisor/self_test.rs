@@ -0,0 +1,453 @@
+//! This module implements the self-test of the hypervisor, a smoke test run
+//! from the guest after loading the hypervisor, to check that its core features
+//! work on the system. [`run`] is called at CPL 0 in the guest, for example,
+//! from the IOCTL handler of the driver a userland tool talks to, and returns
+//! the outcome of each test:
+//!
+//! - [`SelfTest::CpuidInterception`]: CPUID causes VM-exits.
+//! - [`SelfTest::MsrInterception`]: RDMSR of an MSR with a read handler in
+//!   `SharedHostData::msr_intercepts` causes VM-exits.
+//! - [`SelfTest::EptHook`]: a hook is installed and uninstalled with
+//!   `Hypercall::InstallHook` and `Hypercall::UninstallHook`, and reading the
+//!   hooked page returns the original bytes meanwhile.
+//! - [`SelfTest::EventInjection`]: #BP injected by the host is delivered
+//!   through the IDT of the guest.
+//! - [`SelfTest::TlbFlush`]: a TLB flush requested by the host is performed on
+//!   the next VM-entry.
+//!
+//! The tests talk to the host with `Hypercall::SelfTest`, whose operations are
+//! [`SelfTestOperation`]. They run with interrupts disabled, so that the
+//! VM-exits counted are those on the current processor, and one run of the
+//! tests at a time is allowed across processors.
+//!
+//! The self-test is also the payload of the end-to-end tests, which boot the
+//! UEFI version of the hypervisor under QEMU with nested virtualization (see
+//! `cargo xtask qemu-intel` and `cargo xtask qemu-amd`). The hypervisor runs
+//! the self-test right after virtualizing the system and reports the result
+//! with [`exit_test_run`], which makes QEMU exit with a status telling the
+//! result, instead of the output being parsed.
+//!
+//! # Limitations
+//!
+//! Execution of the shadow page of a hook is not tested, as the hooked page is
+//! data of this module. The hook test requires the host to run with the
+//! address space of the guest, that is, without `SharedHostData::pt`.
+
+use core::{
+    arch::global_asm,
+    cell::UnsafeCell,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive as _;
+use spin::Mutex;
+use x86::{bits64::paging::BASE_PAGE_SIZE, cpuid::cpuid, dtables::DescriptorTablePointer};
+
+use crate::hypervisor::{
+    event::{self, Event},
+    exit_stats::{self, ExitKind, EXIT_KIND_COUNT},
+    host::Vcpu,
+    hypercall::{self, Hypercall, HypercallStatus},
+    interrupt_handlers::InterruptDescriptorTableEntry,
+    support::InterruptGuard,
+    tlb::{self, FlushScope},
+    x86_instructions::{lidt, rdmsr, sidt},
+    SHARED_HOST_DATA,
+};
+
+/// The operations of `Hypercall::SelfTest`, passed in RDX.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum SelfTestOperation {
+    /// Returns the number of VM-exits of the `exit_stats::ExitKind` in R8 on
+    /// the current processor.
+    ReadExitCount = 0,
+
+    /// Returns an MSR with a read handler in `SharedHostData::msr_intercepts`,
+    /// or `NotSupported` if there is none.
+    FindInterceptedMsr = 1,
+
+    /// Returns 1 if the host runs with the address space of the guest, or 0
+    /// otherwise.
+    IsAddressSpaceShared = 2,
+
+    /// Injects #BP into the guest on return from the hypercall.
+    InjectBreakpoint = 3,
+
+    /// Requests flushing all translations of the guest on the current
+    /// processor.
+    RequestTlbFlush = 4,
+
+    /// Returns the flushes requested and not performed yet on the current
+    /// processor: bit 0 for `GuestLinear` and bit 1 for `GuestPhysical`.
+    ReadPendingTlbFlushes = 5,
+}
+
+/// The tests [`run`] performs, in that order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelfTest {
+    CpuidInterception,
+    MsrInterception,
+    EptHook,
+    EventInjection,
+    TlbFlush,
+}
+
+impl SelfTest {
+    /// All tests in the order they run.
+    pub const ALL: [Self; 5] = [
+        Self::CpuidInterception,
+        Self::MsrInterception,
+        Self::EptHook,
+        Self::EventInjection,
+        Self::TlbFlush,
+    ];
+}
+
+/// The outcome of a test.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestOutcome {
+    Passed,
+
+    /// The test failed for the reason.
+    Failed(&'static str),
+
+    /// The test was not applicable to the system for the reason.
+    Skipped(&'static str),
+}
+
+/// The outcomes of the tests, as returned by [`run`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub outcomes: [(SelfTest, TestOutcome); SelfTest::ALL.len()],
+}
+
+impl SelfTestReport {
+    /// Returns whether no test failed.
+    pub fn passed(&self) -> bool {
+        self.outcomes
+            .iter()
+            .all(|(_, outcome)| !matches!(outcome, TestOutcome::Failed(_)))
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (test, outcome) in &self.outcomes {
+            match outcome {
+                TestOutcome::Passed => writeln!(f, "{test:?}: passed")?,
+                TestOutcome::Failed(reason) => writeln!(f, "{test:?}: FAILED: {reason}")?,
+                TestOutcome::Skipped(reason) => writeln!(f, "{test:?}: skipped: {reason}")?,
+            }
+        }
+        let verdict = if self.passed() { "passed" } else { "FAILED" };
+        write!(f, "Self-test {verdict}")
+    }
+}
+
+/// Runs the self-test on the current processor and returns the outcomes. Must
+/// be called at CPL 0 in the guest, while the processor is virtualized.
+pub fn run() -> SelfTestReport {
+    static PAGES: Mutex<SelfTestPages> = Mutex::new(SelfTestPages::new());
+
+    let mut pages = PAGES.lock();
+    let _guard = InterruptGuard::new();
+    let outcomes = SelfTest::ALL.map(|test| {
+        let outcome = match test {
+            SelfTest::CpuidInterception => test_cpuid_interception(),
+            SelfTest::MsrInterception => test_msr_interception(),
+            SelfTest::EptHook => test_ept_hook(&pages.hook_target),
+            SelfTest::EventInjection => test_event_injection(&mut pages.idt),
+            SelfTest::TlbFlush => test_tlb_flush(),
+        };
+        log::info!("Self-test {test:?}: {outcome:?}");
+        (test, outcome)
+    });
+    SelfTestReport { outcomes }
+}
+
+/// The pages the tests use. They are in the image rather than on the heap,
+/// which may be hidden from the guest.
+struct SelfTestPages {
+    /// The page hooked by `SelfTest::EptHook`. It is only accessed by the
+    /// processor while hooked.
+    hook_target: Page<UnsafeCell<[u8; BASE_PAGE_SIZE]>>,
+
+    /// The IDT for `SelfTest::EventInjection`.
+    idt: Page<[u64; BASE_PAGE_SIZE / 8]>,
+}
+
+impl SelfTestPages {
+    const fn new() -> Self {
+        Self {
+            hook_target: Page(UnsafeCell::new([0xcc; BASE_PAGE_SIZE])),
+            idt: Page([0; BASE_PAGE_SIZE / 8]),
+        }
+    }
+}
+
+#[repr(C, align(4096))]
+struct Page<T>(T);
+
+/// Ends the end-to-end test run with the result of `report` with
+/// `Hypercall::ExitTest`: QEMU exits with 1 if the tests passed, or 3
+/// otherwise. Returns the error if the hypercall returns, that is, the run is
+/// not an end-to-end test run.
+pub fn exit_test_run(report: &SelfTestReport) -> HypercallStatus {
+    let code = u64::from(!report.passed());
+    match hypercall::issue(Hypercall::ExitTest, code, 0, 0) {
+        Ok(_) => HypercallStatus::NotSupported,
+        Err(status) => status,
+    }
+}
+
+fn test_cpuid_interception() -> TestOutcome {
+    const ITERATIONS: u64 = 4;
+
+    let before = match read_exit_count(ExitKind::Cpuid) {
+        Ok(count) => count,
+        Err(reason) => return TestOutcome::Failed(reason),
+    };
+    for _ in 0..ITERATIONS {
+        let _ = cpuid!(0);
+    }
+    match read_exit_count(ExitKind::Cpuid) {
+        Ok(after) if after.wrapping_sub(before) >= ITERATIONS => TestOutcome::Passed,
+        Ok(_) => TestOutcome::Failed("CPUID did not cause VM-exits"),
+        Err(reason) => TestOutcome::Failed(reason),
+    }
+}
+
+fn test_msr_interception() -> TestOutcome {
+    let msr = match self_test_hypercall(SelfTestOperation::FindInterceptedMsr, 0) {
+        Ok(msr) => msr as u32,
+        Err(HypercallStatus::NotSupported) => {
+            return TestOutcome::Skipped("no MSR read is intercepted")
+        }
+        Err(_) => return TestOutcome::Failed("the intercepted MSR could not be found"),
+    };
+    let before = match read_exit_count(ExitKind::Rdmsr) {
+        Ok(count) => count,
+        Err(reason) => return TestOutcome::Failed(reason),
+    };
+    let _ = rdmsr(msr);
+    match read_exit_count(ExitKind::Rdmsr) {
+        Ok(after) if after != before => TestOutcome::Passed,
+        Ok(_) => TestOutcome::Failed("RDMSR of the intercepted MSR did not cause a VM-exit"),
+        Err(reason) => TestOutcome::Failed(reason),
+    }
+}
+
+fn test_ept_hook(target: &Page<UnsafeCell<[u8; BASE_PAGE_SIZE]>>) -> TestOutcome {
+    const PATCH: [u8; 4] = [0x0f, 0x0b, 0x0f, 0x0b];
+
+    let address = target.0.get() as u64;
+    if hypercall::issue(
+        Hypercall::InstallHook,
+        address,
+        PATCH.as_ptr() as u64,
+        PATCH.len() as u64,
+    )
+    .is_err()
+    {
+        return TestOutcome::Failed("the hook could not be installed");
+    }
+    // Safety: the page is only accessed while the lock is held. The volatile
+    // read ensures the page is read while hooked.
+    let bytes = unsafe { core::ptr::read_volatile(target.0.get().cast::<[u8; 4]>()) };
+    let uninstalled = hypercall::issue(Hypercall::UninstallHook, address, 0, 0);
+    let uninstalled_again = hypercall::issue(Hypercall::UninstallHook, address, 0, 0);
+
+    if bytes != [0xcc; 4] {
+        TestOutcome::Failed("reading the hooked page returned the patch")
+    } else if uninstalled.is_err() {
+        TestOutcome::Failed("the hook could not be uninstalled")
+    } else if uninstalled_again != Err(HypercallStatus::InvalidParameter) {
+        TestOutcome::Failed("the hook was not uninstalled")
+    } else {
+        TestOutcome::Passed
+    }
+}
+
+/// The number of #BP the handler of `SelfTest::EventInjection` has taken.
+static BREAKPOINT_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Tests #BP injection with an IDT whose #BP handler counts #BP, and the other
+/// entries are those of the guest, so that NMIs are still handled by the guest.
+fn test_event_injection(idt: &mut Page<[u64; BASE_PAGE_SIZE / 8]>) -> TestOutcome {
+    const BP_VECTOR: usize = 3;
+
+    let guest_idtr = sidt();
+    let len = (usize::from(guest_idtr.limit) + 1).min(BASE_PAGE_SIZE) / 8;
+    if len < (BP_VECTOR + 1) * 2 {
+        return TestOutcome::Failed("the IDT does not have an entry for #BP");
+    }
+    // Safety: the IDTR points to the IDT of the current processor, which the
+    // guest keeps valid.
+    let guest_idt = unsafe { core::slice::from_raw_parts(guest_idtr.base, len) };
+    idt.0[..len].copy_from_slice(guest_idt);
+    let entry = InterruptDescriptorTableEntry::new(
+        asm_self_test_breakpoint_handler as *const () as usize,
+        x86::segmentation::cs(),
+        0,
+    );
+    // Safety: the entry is 16-byte long and aligned within the IDT.
+    unsafe {
+        idt.0
+            .as_mut_ptr()
+            .add(BP_VECTOR * 2)
+            .cast::<InterruptDescriptorTableEntry>()
+            .write(entry);
+    };
+
+    let before = BREAKPOINT_COUNT.load(Ordering::SeqCst);
+    let test_idtr = DescriptorTablePointer {
+        limit: guest_idtr.limit,
+        base: idt.0.as_ptr(),
+    };
+    lidt(&test_idtr);
+    let result = self_test_hypercall(SelfTestOperation::InjectBreakpoint, 0);
+    lidt(&guest_idtr);
+    let after = BREAKPOINT_COUNT.load(Ordering::SeqCst);
+
+    match result {
+        Ok(_) if after != before => TestOutcome::Passed,
+        Ok(_) => TestOutcome::Failed("the injected #BP was not delivered"),
+        Err(_) => TestOutcome::Failed("#BP could not be injected"),
+    }
+}
+
+fn test_tlb_flush() -> TestOutcome {
+    if self_test_hypercall(SelfTestOperation::RequestTlbFlush, 0).is_err() {
+        return TestOutcome::Failed("the flush could not be requested");
+    }
+    match self_test_hypercall(SelfTestOperation::ReadPendingTlbFlushes, 0) {
+        Ok(0) => TestOutcome::Passed,
+        Ok(_) => TestOutcome::Failed("the flush was not performed on VM-entry"),
+        Err(_) => TestOutcome::Failed("the pending flushes could not be read"),
+    }
+}
+
+fn read_exit_count(kind: ExitKind) -> Result<u64, &'static str> {
+    self_test_hypercall(SelfTestOperation::ReadExitCount, kind as u64)
+        .map_err(|_| "the VM-exit count could not be read")
+}
+
+fn self_test_hypercall(
+    operation: SelfTestOperation,
+    parameter: u64,
+) -> Result<u64, HypercallStatus> {
+    hypercall::issue(Hypercall::SelfTest, operation as u64, parameter, 0)
+}
+
+/// Handles `Hypercall::SelfTest` with `operation` in RDX and `parameter` in R8,
+/// and returns the status and the output value.
+pub(crate) fn handle_hypercall(
+    vcpu: &mut dyn Vcpu,
+    operation: u64,
+    parameter: u64,
+) -> (HypercallStatus, u64) {
+    let Some(operation) = SelfTestOperation::from_u64(operation) else {
+        return (HypercallStatus::InvalidParameter, 0);
+    };
+    match operation {
+        SelfTestOperation::ReadExitCount => {
+            let kind = parameter as usize;
+            match exit_stats::snapshot(Some(vcpu.id())) {
+                Some(entries) if kind < EXIT_KIND_COUNT => {
+                    (HypercallStatus::Success, entries[kind].count)
+                }
+                _ => (HypercallStatus::InvalidParameter, 0),
+            }
+        }
+        SelfTestOperation::FindInterceptedMsr => {
+            let shared_host = SHARED_HOST_DATA.get().unwrap();
+            match shared_host.msr_intercepts.intercepted_reads().next() {
+                Some(msr) => (HypercallStatus::Success, msr.into()),
+                None => (HypercallStatus::NotSupported, 0),
+            }
+        }
+        SelfTestOperation::IsAddressSpaceShared => {
+            let shared_host = SHARED_HOST_DATA.get().unwrap();
+            (HypercallStatus::Success, shared_host.pt.is_none().into())
+        }
+        SelfTestOperation::InjectBreakpoint => {
+            let bp = Event::Exception {
+                vector: 3,
+                error_code: None,
+            };
+            match event::inject_event(vcpu, bp) {
+                Ok(()) => (HypercallStatus::Success, 0),
+                Err(_) => (HypercallStatus::NotSupported, 0),
+            }
+        }
+        SelfTestOperation::RequestTlbFlush => {
+            tlb::flush_guest(vcpu, FlushScope::GuestLinear);
+            tlb::flush_guest(vcpu, FlushScope::GuestPhysical);
+            (HypercallStatus::Success, 0)
+        }
+        SelfTestOperation::ReadPendingTlbFlushes => {
+            let pending = tlb::pending(vcpu);
+            let bits = u64::from(pending.linear) | u64::from(pending.physical) << 1;
+            (HypercallStatus::Success, bits)
+        }
+    }
+}
+
+// The handler of #BP for `SelfTest::EventInjection`. #BP has no error code.
+global_asm!(
+    ".global asm_self_test_breakpoint_handler",
+    "asm_self_test_breakpoint_handler:",
+    "lock inc qword ptr [rip + {count}]",
+    "iretq",
+    count = sym BREAKPOINT_COUNT,
+);
+extern "win64" {
+    fn asm_self_test_breakpoint_handler();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    use crate::hypervisor::{host::Guest, test_support::MockGuest};
+
+    #[test]
+    fn report_fails_only_on_failures() {
+        let mut report = SelfTestReport {
+            outcomes: SelfTest::ALL.map(|test| (test, TestOutcome::Passed)),
+        };
+        report.outcomes[1].1 = TestOutcome::Skipped("no MSR read is intercepted");
+        assert!(report.passed());
+        assert!(report.to_string().ends_with("Self-test passed"));
+
+        report.outcomes[3].1 = TestOutcome::Failed("the injected #BP was not delivered");
+        assert!(!report.passed());
+        assert!(report
+            .to_string()
+            .contains("EventInjection: FAILED: the injected #BP was not delivered\n"));
+    }
+
+    #[test]
+    fn breakpoint_is_injected() {
+        let mut guest = MockGuest::new(0).unwrap();
+        let (status, _) =
+            handle_hypercall(&mut guest, SelfTestOperation::InjectBreakpoint as u64, 0);
+        assert_eq!(status, HypercallStatus::Success);
+        assert_eq!(
+            guest.pending_event,
+            Some(Event::Exception {
+                vector: 3,
+                error_code: None
+            })
+        );
+
+        // Unknown operations are rejected.
+        assert_eq!(
+            handle_hypercall(&mut guest, 6, 0),
+            (HypercallStatus::InvalidParameter, 0)
+        );
+    }
+}
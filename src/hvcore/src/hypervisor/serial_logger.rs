@@ -1,27 +1,113 @@
 // https://github.com/iankronquist/rustyvisor/tree/83b53ac104d85073858ba83326a28a6e08d1af12/pcuart
 // https://wiki.osdev.org/Serial_Ports
 
-use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, Ordering};
 use spin::{Mutex, Once};
 
-use super::support::InterruptGuard;
+use super::{apic_id, log_buffer::LogBuffer, percpu, support::InterruptGuard};
 
 static LOGGER: Once<SerialLogger> = Once::new();
 
-pub(crate) fn init(level: log::LevelFilter) {
-    let logger = LOGGER.call_once(SerialLogger::new);
-    log::set_logger(logger).unwrap();
-    log::set_max_level(level);
+/// The APIC ID of the processor holding the lock of the port, or `NO_OWNER`.
+static OWNER: AtomicU32 = AtomicU32::new(NO_OWNER);
+const NO_OWNER: u32 = u32::MAX;
+
+/// Initializes the serial port with `config`. Does nothing if it is already
+/// initialized.
+pub(crate) fn init(config: &SerialConfig) {
+    let _ = LOGGER.call_once(|| SerialLogger::new(config));
+}
+
+/// The size of the buffer of each processor for logs waiting for the serial
+/// port in bytes.
+pub(crate) const SERIAL_PENDING_SIZE: usize = 0x1000;
+
+/// Writes `msg` to the serial port if `init` is called.
+///
+/// If another processor is writing to the port, `msg` is left in `pending`, the
+/// buffer of the current processor, instead of waiting for the port. Any
+/// processor writes all pending logs after its own, so that lines from
+/// processors are never interleaved. Without `pending`, this waits for the port.
+pub(crate) fn write(msg: &str, pending: Option<&LogBuffer>) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+
+    // Disable interrupt while holding the lock, to reduce the chance of
+    // reentering this code.
+    let _intr_guard = InterruptGuard::new();
+    let Some(pending) = pending else {
+        logger.write(&mut logger.port.lock(), msg.as_bytes());
+        return;
+    };
+    if let Some(mut uart) = logger.port.try_lock() {
+        logger.write(&mut uart, msg.as_bytes());
+        logger.write_pending(&mut uart);
+        return;
+    }
+
+    // The processor holding the lock may release it before noticing `msg`.
+    // Write it if so.
+    let _ = pending.write(msg.as_bytes());
+    if let Some(mut uart) = logger.port.try_lock() {
+        logger.write_pending(&mut uart);
+    }
+}
+
+/// Releases the lock of the port if the current processor holds it, that is,
+/// an exception occurred while logging. Must be called only from an exception
+/// handler that does not return to the interrupted logging.
+pub(crate) fn release_if_owned() {
+    if let Some(logger) = LOGGER.get() {
+        if OWNER.load(Ordering::Relaxed) == apic_id::get() {
+            OWNER.store(NO_OWNER, Ordering::Relaxed);
+            unsafe { logger.port.force_unlock() };
+        }
+    }
+}
+
+/// The configuration of the serial port to write logs to.
+#[derive(Clone, Copy, Debug)]
+pub struct SerialConfig {
+    /// The UART to use.
+    pub port: SerialPort,
+
+    /// The baud rate. Must divide 115200, the rate with the divisor of 1 on
+    /// the standard UART clock of 1.8432 MHz.
+    pub baud_rate: u32,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            port: SerialPort::Com1,
+            baud_rate: 115_200,
+        }
+    }
 }
 
-#[allow(dead_code)]
-#[derive(Copy, Clone)]
-#[repr(u16)]
-enum SerialPort {
-    Com1 = 0x3f8,
-    Com2 = 0x2f8,
-    Com3 = 0x3e8,
-    Com4 = 0x2e8,
+/// The location of the registers of a 16550 compatible UART.
+#[derive(Clone, Copy, Debug)]
+pub enum SerialPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+
+    /// The I/O port base of the registers.
+    Io(u16),
+
+    /// The memory-mapped registers, such as those of PCIe serial cards and
+    /// AMT serial-over-LAN devices.
+    Mmio {
+        /// The linear address of the registers. Must be mapped as uncacheable
+        /// in both the current address space and the host's.
+        address: u64,
+
+        /// The distance between the registers in bytes, either 1 or 4. The
+        /// registers are accessed with this width.
+        stride: u8,
+    },
 }
 
 struct SerialLogger {
@@ -29,44 +115,100 @@ struct SerialLogger {
 }
 
 impl SerialLogger {
-    fn new() -> Self {
+    fn new(config: &SerialConfig) -> Self {
         Self {
-            port: Mutex::new(Uart::new(SerialPort::Com1, 115200)),
+            port: Mutex::new(Uart::new(config)),
         }
     }
-}
 
-impl log::Log for SerialLogger {
-    fn enabled(&self, metadata: &log::Metadata) -> bool {
-        metadata.level() <= log::Level::Trace
+    /// Writes `bytes` with the lock of the port held as `uart`.
+    fn write(&self, uart: &mut Uart, bytes: &[u8]) {
+        OWNER.store(apic_id::get(), Ordering::Relaxed);
+        uart.write_bytes(bytes);
+        OWNER.store(NO_OWNER, Ordering::Relaxed);
     }
 
-    fn log(&self, record: &log::Record) {
-        if self.enabled(record.metadata()) {
-            let msg = alloc::format!("#{}:{:5}: {}\n", apic_id(), record.level(), record.args());
-            // Disable interrupt while acquiring the mutex, to reduce the chance
-            // of reentering this code.
-            let _intr_guard = InterruptGuard::new();
-            let mut uart = self.port.lock();
-            let _ = uart.write_str(msg.as_str());
+    /// Writes the pending logs of all processors with the lock of the port held
+    /// as `uart`.
+    fn write_pending(&self, uart: &mut Uart) {
+        let Some(blocks) = percpu::try_all() else {
+            return;
+        };
+        let mut chunk = [0u8; 0x100];
+        for block in blocks {
+            loop {
+                let len = block.serial_pending.drain(&mut chunk);
+                if len == 0 {
+                    break;
+                }
+                self.write(uart, &chunk[..len]);
+            }
         }
     }
+}
 
-    fn flush(&self) {}
+/// The registers of a UART, either in the I/O or memory address space.
+enum UartRegisters {
+    Io(u16),
+    Mmio { address: u64, stride: u8 },
+}
+
+impl UartRegisters {
+    fn read(&self, offset: u16) -> u8 {
+        match *self {
+            Self::Io(base) => inb(base + offset),
+            Self::Mmio { address, stride } => {
+                let address = address + u64::from(offset) * u64::from(stride);
+                if stride == 4 {
+                    unsafe { core::ptr::read_volatile(address as *const u32) as u8 }
+                } else {
+                    unsafe { core::ptr::read_volatile(address as *const u8) }
+                }
+            }
+        }
+    }
+
+    fn write(&self, offset: u16, data: u8) {
+        match *self {
+            Self::Io(base) => outb(base + offset, data),
+            Self::Mmio { address, stride } => {
+                let address = address + u64::from(offset) * u64::from(stride);
+                if stride == 4 {
+                    unsafe { core::ptr::write_volatile(address as *mut u32, u32::from(data)) };
+                } else {
+                    unsafe { core::ptr::write_volatile(address as *mut u8, data) };
+                }
+            }
+        }
+    }
 }
 
 struct Uart {
-    port: u16,
+    registers: UartRegisters,
 }
 
+// Safety: the MMIO registers are only accessed under the lock of `SerialLogger`.
+unsafe impl Send for Uart {}
+
 impl Uart {
-    fn new(port: SerialPort, baud_rate: u64) -> Self {
-        let port = port as u16;
-        Self::init(port, baud_rate);
-        Self { port }
+    fn new(config: &SerialConfig) -> Self {
+        let registers = match config.port {
+            SerialPort::Com1 => UartRegisters::Io(0x3f8),
+            SerialPort::Com2 => UartRegisters::Io(0x2f8),
+            SerialPort::Com3 => UartRegisters::Io(0x3e8),
+            SerialPort::Com4 => UartRegisters::Io(0x2e8),
+            SerialPort::Io(base) => UartRegisters::Io(base),
+            SerialPort::Mmio { address, stride } => {
+                assert!(stride == 1 || stride == 4, "unsupported stride {stride}");
+                UartRegisters::Mmio { address, stride }
+            }
+        };
+        let uart = Self { registers };
+        uart.init(config.baud_rate);
+        uart
     }
 
-    pub(crate) fn init(port: u16, baud_rate: u64) {
+    fn init(&self, baud_rate: u32) {
         const UART_OFFSET_DIVISOR_LATCH_LOW: u16 = 0;
         const UART_OFFSET_INTERRUPT_ENABLE: u16 = 1;
         const UART_OFFSET_DIVISOR_LATCH_HIGH: u16 = 1;
@@ -74,34 +216,33 @@ impl Uart {
         const UART_OFFSET_LINE_CONTROL: u16 = 3;
         const UART_OFFSET_MODEM_CONTROL: u16 = 4;
 
-        outb(port + UART_OFFSET_INTERRUPT_ENABLE, 0);
-        outb(port + UART_OFFSET_LINE_CONTROL, 0x80);
+        let registers = &self.registers;
+        registers.write(UART_OFFSET_INTERRUPT_ENABLE, 0);
+        registers.write(UART_OFFSET_LINE_CONTROL, 0x80);
 
-        let divider = 115200 / baud_rate;
+        let divider = (115_200 / baud_rate.max(1)).max(1);
         let dlab_low = divider as u8;
         let dlab_high = (divider >> 8) as u8;
-        outb(port + UART_OFFSET_DIVISOR_LATCH_LOW, dlab_low);
-        outb(port + UART_OFFSET_DIVISOR_LATCH_HIGH, dlab_high);
-        outb(port + UART_OFFSET_LINE_CONTROL, 0x3);
-        outb(port + UART_OFFSET_FIFO_CONTROL, 0xc7);
-        outb(port + UART_OFFSET_MODEM_CONTROL, 0xb);
+        registers.write(UART_OFFSET_DIVISOR_LATCH_LOW, dlab_low);
+        registers.write(UART_OFFSET_DIVISOR_LATCH_HIGH, dlab_high);
+        registers.write(UART_OFFSET_LINE_CONTROL, 0x3);
+        registers.write(UART_OFFSET_FIFO_CONTROL, 0xc7);
+        registers.write(UART_OFFSET_MODEM_CONTROL, 0xb);
 
-        outb(port + UART_OFFSET_INTERRUPT_ENABLE, 0x1);
+        registers.write(UART_OFFSET_INTERRUPT_ENABLE, 0x1);
     }
-}
 
-impl core::fmt::Write for Uart {
-    fn write_str(&mut self, msg: &str) -> Result<(), core::fmt::Error> {
+    fn write_bytes(&mut self, bytes: &[u8]) {
         const UART_OFFSET_LINE_STATUS: u16 = 5;
         const UART_OFFSET_LINE_STATUS_THRE: u8 = 1u8 << 5;
 
-        for data in msg.bytes() {
-            while (inb(self.port + UART_OFFSET_LINE_STATUS) & UART_OFFSET_LINE_STATUS_THRE) == 0 {
+        for &data in bytes {
+            while (self.registers.read(UART_OFFSET_LINE_STATUS) & UART_OFFSET_LINE_STATUS_THRE) == 0
+            {
                 core::hint::spin_loop();
             }
-            outb(self.port, data);
+            self.registers.write(0, data);
         }
-        Ok(())
     }
 }
 
@@ -112,9 +253,3 @@ fn outb(port: u16, data: u8) {
 fn inb(port: u16) -> u8 {
     unsafe { x86::io::inb(port) }
 }
-
-fn apic_id() -> u8 {
-    // See: (AMD) CPUID Fn0000_0001_EBX LocalApicId, LogicalProcessorCount, CLFlush
-    // See: (Intel) Table 3-8. Information Returned by CPUID Instruction
-    (x86::cpuid::cpuid!(0x1).ebx >> 24) as _
-}
@@ -0,0 +1,75 @@
+//! This module implements the vendor agnostic parts of single-stepping the
+//! guest with `Vcpu::single_step`.
+//!
+//! On Intel, single-stepping uses the monitor trap flag (MTF), which causes
+//! VM-exit after the guest executes one instruction without any state visible
+//! to the guest.
+//!
+//! On AMD, which lacks an equivalent of MTF, single-stepping sets the guest
+//! RFLAGS.TF and intercepts #DB. TF and DR6.BS are restored once the step
+//! completes, and #DB is still delivered to the guest if the guest set TF by
+//! itself. Unlike MTF, this is not transparent to the guest: the stepped
+//! instruction observes TF set, for example, with `PUSHF`, and its changes to
+//! TF are lost. If the instruction raises an exception, the step completes
+//! only once the handler returns and the instruction completes.
+//!
+//! ```ignore
+//! // Re-arm the breakpoint after the guest executes the original instruction.
+//! vcpu.single_step(Box::new(move |vcpu| rearm(vcpu, address)))?;
+//! ```
+
+use alloc::boxed::Box;
+
+use crate::hypervisor::host::Vcpu;
+
+/// The callback called on the vCPU once it completes a single-step.
+pub type SingleStepCallback = dyn FnOnce(&mut dyn Vcpu) + Send;
+
+/// The errors single-stepping may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SingleStepError {
+    #[error("the processor does not support single-stepping the guest")]
+    Unsupported,
+
+    #[error("the vCPU is already single-stepping")]
+    Busy,
+}
+
+/// The single-step requested on a vCPU. Each vendor arms its mechanism while
+/// this is pending.
+#[derive(Default)]
+pub(crate) struct SingleStep {
+    callback: Option<Box<SingleStepCallback>>,
+}
+
+impl SingleStep {
+    /// Requests a single-step calling `callback` on completion.
+    pub(crate) fn request(
+        &mut self,
+        callback: Box<SingleStepCallback>,
+    ) -> Result<(), SingleStepError> {
+        if self.callback.is_some() {
+            return Err(SingleStepError::Busy);
+        }
+        self.callback = Some(callback);
+        Ok(())
+    }
+
+    /// Returns whether a single-step is requested.
+    pub(crate) fn is_pending(&self) -> bool {
+        self.callback.is_some()
+    }
+
+    /// Completes the requested single-step and returns its callback.
+    pub(crate) fn complete(&mut self) -> Option<Box<SingleStepCallback>> {
+        self.callback.take()
+    }
+}
+
+impl core::fmt::Debug for SingleStep {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SingleStep")
+            .field("pending", &self.is_pending())
+            .finish()
+    }
+}
@@ -0,0 +1,103 @@
+//! This module implements awareness of system management interrupts (SMIs),
+//! which the firmware uses to run its system management mode (SMM) code
+//! outside the control of both the guest and the host. SMIs that occur often,
+//! or take long in SMM, look like a slow guest or host, so the number of SMIs
+//! each processor observes is counted, and can be read with [`smi_count`] or
+//! `Hypercall::ReadSmiCount`.
+//!
+//! On Intel processors, SMIs use the default treatment: an SMI in either VMX
+//! root or non-root operation enters SMM, and RSM returns to where it occurred
+//! without a VM-exit. The dual-monitor treatment, where an SMM transfer monitor
+//! (STM) runs SMM code as a guest, is never activated, as IA32_SMM_MONITOR_CTL
+//! is not written and the executive-VMCS pointer is unused. SMIs are counted by
+//! the processor in MSR_SMI_COUNT.
+//!
+//! On AMD processors, the host runs with GIF cleared, which holds SMIs pending
+//! until the next VMRUN. A long VM-exit handler therefore delays SMIs, which
+//! some firmware does not tolerate; move heavy work to `deferred_work`. SMIs in
+//! the guest enter SMM directly and are not counted, unless
+//! `SharedHostData::smi_intercept` is set. Then, SMIs cause #VMEXIT, and the
+//! host sets GIF briefly so that the SMI is taken immediately. NMIs pending are
+//! taken then too, by the host IDT, and delivered to the guest.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86::cpuid::cpuid;
+
+use crate::hypervisor::{host::Vcpu, percpu, x86_instructions::rdmsr, SHARED_HOST_DATA};
+
+/// The SMIs observed on a processor.
+#[derive(Debug, Default)]
+pub(crate) struct SmiCounter {
+    /// MSR_SMI_COUNT when the processor was virtualized (Intel).
+    baseline: AtomicU64,
+
+    /// The number of #VMEXIT(SMI) (AMD).
+    intercepted: AtomicU64,
+}
+
+/// Returns the number of SMIs observed on the processor of `vcpu` since it was
+/// virtualized, or `None` if they are not counted on this processor. Must be
+/// called from the host on that processor, such as VM-exit handlers.
+pub fn smi_count(vcpu: &dyn Vcpu) -> Option<u64> {
+    let counter = &percpu::get(vcpu.id())?.smi;
+    if is_smi_count_supported() {
+        let count = read_smi_count().wrapping_sub(counter.baseline.load(Ordering::Relaxed));
+        Some(count & SMI_COUNT_MASK)
+    } else if is_intercepted() {
+        Some(counter.intercepted.load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
+/// Starts counting SMIs on the current processor. Called from the host right
+/// before it runs the guest for the first time.
+pub(crate) fn start_counting() {
+    if is_smi_count_supported() {
+        percpu::current()
+            .smi
+            .baseline
+            .store(read_smi_count(), Ordering::Relaxed);
+    }
+}
+
+/// Records #VMEXIT(SMI) on the current processor (AMD).
+#[cfg(feature = "amd")]
+pub(crate) fn record_intercepted() {
+    let _ = percpu::current()
+        .smi
+        .intercepted
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns whether SMIs are intercepted with `SharedHostData::smi_intercept`
+/// (AMD). It requires the host IDT, which takes NMIs pending while SMIs are
+/// taken.
+pub(crate) fn is_intercepted() -> bool {
+    let shared_host = SHARED_HOST_DATA.get().unwrap();
+    shared_host.smi_intercept && shared_host.idt.is_some()
+}
+
+/// The bits of MSR_SMI_COUNT with the count.
+const SMI_COUNT_MASK: u64 = 0xffff_ffff;
+
+fn read_smi_count() -> u64 {
+    const MSR_SMI_COUNT: u32 = 0x34;
+    rdmsr(MSR_SMI_COUNT) & SMI_COUNT_MASK
+}
+
+/// Returns whether MSR_SMI_COUNT is implemented, that is, on Intel processors
+/// from Nehalem. Reading it is avoided under another hypervisor, which may not
+/// emulate it.
+// See: Table 2-3. MSRs in Processors Based on Intel Core Microarchitecture
+fn is_smi_count_supported() -> bool {
+    const HYPERVISOR_PRESENT_BIT: u32 = 1 << 31;
+    const NEHALEM_MODEL: u32 = 0x1a;
+
+    let is_intel = x86::cpuid::CpuId::new().get_vendor_info().unwrap().as_str() == "GenuineIntel";
+    let regs = cpuid!(1);
+    let family = (regs.eax >> 8) & 0xf;
+    let model = ((regs.eax >> 4) & 0xf) | ((regs.eax >> 12) & 0xf0);
+    is_intel && family == 6 && model >= NEHALEM_MODEL && regs.ecx & HYPERVISOR_PRESENT_BIT == 0
+}
@@ -0,0 +1,154 @@
+//! This module implements snapshots of the guest: the contents of a guest
+//! physical range and the registers of a vCPU, which the guest can be restored
+//! to any number of times. This is the building block of snapshot-based
+//! fuzzing, where the guest runs each input from the same state.
+//!
+//! Memory is captured copy-on-write. The range is protected against writes
+//! with `memory_protection`, and the original contents of each page are saved
+//! on the first write to it. Restoring writes back only the pages written since
+//! the snapshot was taken or last restored. The saved pages are allocated from
+//! the heap of the hypervisor, which limits how much of the range the guest may
+//! write to.
+//!
+//! Only one snapshot exists at a time. The system registers, such as CR3, and
+//! the state of other processors are not captured, so take and restore the
+//! snapshot in the same context, while the other processors are idle.
+//!
+//! The guest uses the snapshot through hypercalls, where `TakeSnapshot` returns
+//! 0 in RDX, and `RestoreSnapshot` resumes the guest from the return of
+//! `TakeSnapshot` with 1 in RDX.
+//!
+//! ```ignore
+//! if hypercall::issue(Hypercall::TakeSnapshot, ram_pa, ram_len, 0)? == 0 {
+//!     log::info!("Snapshot taken");
+//! }
+//! run_input(next_input());
+//! hypercall::issue(Hypercall::RestoreSnapshot, 0, 0, 0)?;
+//! ```
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+};
+use spin::Mutex;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use crate::hypervisor::{
+    guest_memory::UserAccessGuard,
+    host::{NestedPageFaultInfo, Vcpu},
+    host_window,
+    memory_protection::{self, Permissions, ProtectionError, ViolationAction},
+    registers::Registers,
+    support::{zeroed_box, Page},
+};
+
+/// The errors snapshots may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+    #[error("the range cannot be protected: {0}")]
+    Protection(ProtectionError),
+
+    #[error("no snapshot is taken")]
+    NoSnapshot,
+}
+
+/// Takes a snapshot of `len` bytes of guest physical memory at `start`,
+/// discarding the existing snapshot, if any. Restoring the snapshot resumes the
+/// guest with `registers`. Must be called from the host, such as VM-exit
+/// handlers.
+///
+/// # Errors
+///
+/// Returns `Protection` if the range cannot be protected. See
+/// `memory_protection::protect_gpa_range`.
+pub fn take_snapshot(registers: &Registers, start: u64, len: u64) -> Result<(), SnapshotError> {
+    let _ = discard_snapshot();
+
+    memory_protection::protect_gpa_range(start, len, Permissions::READ_EXECUTE, save_page)
+        .map_err(SnapshotError::Protection)?;
+    *SNAPSHOT.lock() = Some(Snapshot {
+        start,
+        registers: *registers,
+        saved: BTreeMap::new(),
+        dirty: BTreeSet::new(),
+    });
+
+    log::debug!("Took a snapshot of {len:#x?} bytes at {start:#x?}");
+    Ok(())
+}
+
+/// Restores the guest memory and the registers of `vcpu` to the snapshot. Must
+/// be called from the host, such as VM-exit handlers.
+///
+/// # Errors
+///
+/// Returns `NoSnapshot` if no snapshot is taken.
+pub fn restore_snapshot(vcpu: &mut dyn Vcpu) -> Result<(), SnapshotError> {
+    let mut snapshot = SNAPSHOT.lock();
+    let snapshot = snapshot.as_mut().ok_or(SnapshotError::NoSnapshot)?;
+
+    let id = vcpu.id();
+    for gpa in core::mem::take(&mut snapshot.dirty) {
+        let page = host_window::map(id, gpa).cast::<Page>();
+        let _guard = UserAccessGuard::new();
+        unsafe { core::ptr::copy_nonoverlapping(snapshot.saved[&gpa].as_ref(), page, 1) };
+    }
+    *vcpu.regs() = snapshot.registers;
+    Ok(())
+}
+
+/// Discards the snapshot and unprotects its range.
+///
+/// # Errors
+///
+/// Returns `NoSnapshot` if no snapshot is taken.
+pub fn discard_snapshot() -> Result<(), SnapshotError> {
+    let snapshot = SNAPSHOT.lock().take().ok_or(SnapshotError::NoSnapshot)?;
+    memory_protection::unprotect_gpa_range(snapshot.start).map_err(SnapshotError::Protection)
+}
+
+/// Saves the original contents of the page the guest is writing to for the
+/// first time since the snapshot was taken, and lets the guest complete the
+/// write.
+fn save_page(vcpu: &mut dyn Vcpu, info: &NestedPageFaultInfo) -> ViolationAction {
+    let gpa = info.gpa & !(BASE_PAGE_SIZE as u64 - 1);
+    let mut snapshot = SNAPSHOT.lock();
+    let Some(snapshot) = snapshot.as_mut() else {
+        return ViolationAction::Allow;
+    };
+
+    if snapshot.dirty.insert(gpa) {
+        let id = vcpu.id();
+        let _ = snapshot.saved.entry(gpa).or_insert_with(|| {
+            let mut page = zeroed_box::<Page>();
+            let original = host_window::map(id, gpa).cast::<Page>();
+            let _guard = UserAccessGuard::new();
+            unsafe { core::ptr::copy_nonoverlapping(original, page.as_mut(), 1) };
+            page
+        });
+    }
+    ViolationAction::Allow
+}
+
+struct Snapshot {
+    /// The GPA of the snapshot range, which is protected.
+    start: u64,
+
+    /// The registers the guest resumes with on restore.
+    registers: Registers,
+
+    /// The original contents of the pages written since the snapshot was
+    /// taken, keyed by the GPAs of the pages.
+    saved: BTreeMap<u64, Box<Page>>,
+
+    /// The pages written since the snapshot was taken or last restored.
+    dirty: BTreeSet<u64>,
+}
+
+/// Returns whether the snapshot is locked by any processor. See
+/// `host::fail_open`.
+pub(crate) fn is_locked() -> bool {
+    SNAPSHOT.is_locked()
+}
+
+static SNAPSHOT: Mutex<Option<Snapshot>> = Mutex::new(None);
@@ -1,8 +1,14 @@
-use core::alloc::Layout;
+use core::{
+    alloc::Layout,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
 
 use alloc::{alloc::handle_alloc_error, boxed::Box};
 use x86::bits64::{paging::BASE_PAGE_SIZE, rflags};
 
+use crate::hypervisor::HvError;
+
 /// Returns zero-initialized Box of `T` without using stack during construction.
 pub(crate) fn zeroed_box<T>() -> Box<T> {
     let layout = Layout::new::<T>();
@@ -13,13 +19,110 @@ pub(crate) fn zeroed_box<T>() -> Box<T> {
     unsafe { Box::from_raw(ptr) }
 }
 
+/// Returns zero-initialized Box of `T` like `zeroed_box`, or `OutOfMemory` if
+/// the heap is exhausted instead of aborting. Used while setting up the
+/// hypervisor, which can still fail gracefully.
+pub(crate) fn try_zeroed_box<T>() -> Result<Box<T>, HvError> {
+    let layout = Layout::new::<T>();
+    let ptr = unsafe { alloc::alloc::alloc_zeroed(layout) }.cast::<T>();
+    if ptr.is_null() {
+        return Err(HvError::OutOfMemory);
+    }
+    Ok(unsafe { Box::from_raw(ptr) })
+}
+
+/// Zero-initialized `T` on page-aligned, physically contiguous pages with the
+/// known physical address, for the structures the processor references by
+/// physical address.
+pub(crate) struct PageBox<T> {
+    ptr: NonNull<T>,
+    pa: u64,
+}
+
+impl<T> PageBox<T> {
+    /// Allocates zeroed pages for `T`, or returns `OutOfMemory` if the heap has
+    /// no such pages.
+    pub(crate) fn try_new() -> Result<Self, HvError> {
+        let (ptr, pa) = alloc_pages(Self::page_count()).ok_or(HvError::OutOfMemory)?;
+        Ok(Self {
+            ptr: ptr.cast(),
+            pa,
+        })
+    }
+
+    /// Returns the physical address of `T`.
+    pub(crate) fn pa(&self) -> u64 {
+        self.pa
+    }
+
+    fn page_count() -> usize {
+        const { assert!(core::mem::align_of::<T>() <= BASE_PAGE_SIZE) };
+        core::mem::size_of::<T>().div_ceil(BASE_PAGE_SIZE).max(1)
+    }
+}
+
+impl<T> Deref for PageBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for PageBox<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for PageBox<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for PageBox<T> {
+    fn drop(&mut self) {
+        unsafe { free_pages(self.ptr.cast(), Self::page_count()) };
+    }
+}
+
+// Safety: `PageBox` owns `T` like `Box`.
+unsafe impl<T: Send> Send for PageBox<T> {}
+unsafe impl<T: Sync> Sync for PageBox<T> {}
+
+#[cfg(not(test))]
+fn alloc_pages(count: usize) -> Option<(NonNull<u8>, u64)> {
+    crate::hypervisor::allocator::alloc_pages(count).map(|pages| (pages.ptr, pages.pa))
+}
+
+#[cfg(not(test))]
+unsafe fn free_pages(ptr: NonNull<u8>, count: usize) {
+    unsafe { crate::hypervisor::allocator::free_pages(ptr, count) };
+}
+
+// The global allocator is not used in tests. The virtual address stands in for
+// the physical address.
+#[cfg(test)]
+fn alloc_pages(count: usize) -> Option<(NonNull<u8>, u64)> {
+    let layout = Layout::from_size_align(count * BASE_PAGE_SIZE, BASE_PAGE_SIZE).unwrap();
+    NonNull::new(unsafe { alloc::alloc::alloc_zeroed(layout) })
+        .map(|ptr| (ptr, ptr.as_ptr() as u64))
+}
+
+#[cfg(test)]
+unsafe fn free_pages(ptr: NonNull<u8>, count: usize) {
+    let layout = Layout::from_size_align(count * BASE_PAGE_SIZE, BASE_PAGE_SIZE).unwrap();
+    unsafe { alloc::alloc::dealloc(ptr.as_ptr(), layout) };
+}
+
 /// The structure representing a single memory page (4KB).
 //
 // This does not _always_ have to be allocated at the page aligned address, but
 // very often it is, so let us specify the alignment.
 #[derive(Debug)]
 #[repr(C, align(4096))]
-pub(crate) struct Page([u8; BASE_PAGE_SIZE]);
+pub(crate) struct Page(pub(crate) [u8; BASE_PAGE_SIZE]);
 
 pub(crate) struct InterruptGuard {
     enabled: bool,
@@ -1,27 +1,114 @@
-use alloc::alloc::handle_alloc_error;
+use alloc::collections::BTreeMap;
 use core::{alloc::Layout, arch::global_asm};
 
-use crate::hypervisor::support::Page;
+use spin::Mutex;
+use x86::bits64::paging::BASE_PAGE_SIZE;
 
-use super::registers::Registers;
+use crate::hypervisor::{
+    apic_id::{self, ApicId},
+    host_window, paging_structures,
+    support::Page,
+    HvError, SHARED_HOST_DATA,
+};
 
-/// Installs the hypervisor on the current processor.
-pub(crate) fn jump_with_new_stack(destination: fn(&Registers) -> !, registers: &Registers) -> ! {
-    // Allocate separate stack space. This is never freed.
-    let layout = Layout::array::<Page>(0x10).unwrap();
+use super::registers::{ExtendedRegisters, Registers};
+
+/// Installs the hypervisor on the current processor. Returns `OutOfMemory`
+/// only if the stack cannot be allocated, and never returns otherwise.
+///
+/// The lowest page of the stack is the guard page. It is made non-present in
+/// `SharedHostData::pt`, if specified, so that overflowing the stack causes
+/// #PF, and then #DF on its own stack, instead of corrupting memory.
+pub(crate) fn jump_with_new_stack(
+    destination: extern "win64" fn(&Registers, Option<&ExtendedRegisters>) -> !,
+    registers: &Registers,
+    extended: Option<&ExtendedRegisters>,
+) -> HvError {
+    // Allocate separate stack space. This is freed only on devirtualization.
+    let layout = stack_layout();
     let stack = unsafe { alloc::alloc::alloc_zeroed(layout) };
     if stack.is_null() {
-        handle_alloc_error(layout);
+        return HvError::OutOfMemory;
+    }
+    if let Err(error) = set_guard_page_present(stack as u64, false) {
+        unsafe { alloc::alloc::dealloc(stack, layout) };
+        return error;
+    }
+    // A stack is left for the processor if the host resumed the guest on panic
+    // with `SharedHostData::fail_open`. Nothing runs on it anymore.
+    if let Some(stale) = STACKS.lock().insert(apic_id::get(), stack as usize) {
+        dealloc_stack(stale);
     }
     let stack_base = stack as u64 + layout.size() as u64 - 0x8;
     log::trace!("Stack range: {:#x?}", (stack as u64..stack_base));
 
-    unsafe { switch_stack(registers, destination as *const () as _, stack_base) };
+    unsafe {
+        switch_stack(
+            registers,
+            extended,
+            destination as *const () as _,
+            stack_base,
+        )
+    };
 }
 
-extern "C" {
+/// Frees the stack allocated for the host on the current processor. Must be
+/// called only after the current processor is devirtualized.
+pub(crate) fn free_stack() {
+    if let Some(stack) = STACKS.lock().remove(&apic_id::get()) {
+        dealloc_stack(stack);
+    }
+}
+
+/// Returns whether `va` is in the guard page of the host stack of the current
+/// processor. Called from the host exception handler, and thus, returns
+/// `false` if the stacks are locked.
+pub(crate) fn is_guard_page(va: u64) -> bool {
+    STACKS.try_lock().is_some_and(|stacks| {
+        stacks.get(&apic_id::get()).is_some_and(|&stack| {
+            (stack as u64..stack as u64 + BASE_PAGE_SIZE as u64).contains(&va)
+        })
+    })
+}
+
+/// Makes the guard page present again, and frees the stack at `stack`.
+fn dealloc_stack(stack: usize) {
+    // The guard page was made non-present once with the same paging
+    // structures, which thus do not need to be split on this call.
+    set_guard_page_present(stack as u64, true).unwrap();
+    unsafe { alloc::alloc::dealloc(stack as *mut u8, stack_layout()) };
+}
+
+/// Sets whether the guard page at the bottom of the stack at `stack` is
+/// present in `SharedHostData::pt`, if specified.
+fn set_guard_page_present(stack: u64, present: bool) -> Result<(), HvError> {
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    if SHARED_HOST_DATA.get().unwrap().pt.is_none() {
+        return Ok(());
+    }
+    let _guard = LOCK.lock();
+    // Safety: `SharedHostData::pt` is owned by the hypervisor, and the host
+    // window shares its paging structures below the PML4.
+    unsafe { paging_structures::set_page_present(host_window::host_cr3(), stack, present) }
+}
+
+/// The guard page and 16 pages of the stack.
+fn stack_layout() -> Layout {
+    Layout::array::<Page>(0x11).unwrap()
+}
+
+/// The addresses of the stacks allocated for the host, keyed by APIC IDs.
+static STACKS: Mutex<BTreeMap<ApicId, usize>> = Mutex::new(BTreeMap::new());
+
+extern "win64" {
     /// Jumps to the landing code with the new stack pointer.
-    fn switch_stack(registers: &Registers, destination: usize, stack_base: u64) -> !;
+    fn switch_stack(
+        registers: &Registers,
+        extended: Option<&ExtendedRegisters>,
+        destination: usize,
+        stack_base: u64,
+    ) -> !;
 }
 global_asm!(
     r#"
@@ -29,7 +116,7 @@ global_asm!(
     .global switch_stack
     switch_stack:
         xchg    bx, bx
-        mov     rsp, r8
-        jmp     rdx
+        mov     rsp, r9
+        jmp     r8
 "#
 );
@@ -0,0 +1,313 @@
+//! This module implements protection of the system call entry of the guest, a
+//! common target of rootkits. Enabled with `SharedHostData::syscall_protection`,
+//! the values of IA32_LSTAR and IA32_STAR are locked, and the page of the
+//! handler IA32_LSTAR points to is write-protected through the EPT (Intel) or
+//! NPT (AMD).
+//!
+//! The values are locked for each processor, as the guest may set up the
+//! system call entry for each, like Linux with the per-processor trampolines.
+//! The values at virtualization of a processor are locked if the guest has set
+//! them up. Otherwise, as with the UEFI version, the first values the guest
+//! writes are locked. Then, writes of other values to the MSRs are discarded,
+//! and writes to the handler page deliver #GP. Either is reported as a
+//! [`TamperEvent`], which the guest reads with `Hypercall::ReadTamperEvents` or
+//! receives through `event_channel`.
+//!
+//! The values of a processor are unlocked on INIT, when the guest leaves long
+//! mode, as with kexec, and on devirtualization, so that the next system call
+//! entry the guest sets up is locked instead. The handler page is unprotected
+//! once it is not locked on any processor.
+//!
+//! The handlers installed for IA32_LSTAR and IA32_STAR replace those in
+//! `SharedHostData::msr_intercepts`, if any.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{collections::VecDeque, vec::Vec};
+use spin::Mutex;
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use crate::hypervisor::{
+    apic_id::{self, PerProcessor, ProcessorId},
+    event::{self, Event},
+    event_channel::{self, EventKind},
+    guest_memory,
+    host::{NestedPageFaultInfo, Vcpu},
+    memory_protection::{self, Permissions, ProtectionError, ViolationAction},
+    msr_intercepts::MsrIntercepts,
+    x86_instructions::rdtsc,
+};
+
+/// The kinds of tampering with the system call entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum TamperKind {
+    /// The guest attempted to write a value other than the locked one to
+    /// IA32_LSTAR or IA32_STAR.
+    MsrWrite = 1,
+
+    /// The guest attempted to write to the page of the system call handler.
+    HandlerWrite = 2,
+}
+
+/// An attempt to tamper with the system call entry, as returned by
+/// `Hypercall::ReadTamperEvents`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct TamperEvent {
+    /// The kind of the attempt.
+    pub kind: TamperKind,
+
+    /// The ID of the processor the attempt was made on.
+    pub processor: u64,
+
+    /// The guest RIP of the instruction attempted.
+    pub rip: u64,
+
+    /// The MSR for `MsrWrite`, or the guest physical address for
+    /// `HandlerWrite`.
+    pub target: u64,
+
+    /// The value attempted to write for `MsrWrite`, or zero.
+    pub value: u64,
+
+    /// The TSC when the attempt was made.
+    pub tsc: u64,
+}
+
+/// The maximum number of events kept until read. The oldest events are lost if
+/// not read before more events are reported.
+pub const MAX_TAMPER_EVENTS: usize = 64;
+
+/// Returns `intercepts` with the handlers protecting IA32_LSTAR and IA32_STAR.
+/// Called from the guest before any processor is virtualized.
+pub(crate) fn install(intercepts: MsrIntercepts) -> MsrIntercepts {
+    // Allocate the entries here rather than on first use in the host.
+    let _ = ENTRIES.get(0);
+
+    intercepts
+        .on_write(x86::msr::IA32_LSTAR, handle_write)
+        .on_write(x86::msr::IA32_STAR, handle_write)
+}
+
+/// Locks the system call entry of the processor `vcpu` runs on, if the guest
+/// has set it up. Called from the host when the processor is virtualized.
+pub(crate) fn lock_current(vcpu: &mut dyn Vcpu) {
+    for msr in [x86::msr::IA32_LSTAR, x86::msr::IA32_STAR] {
+        let value = vcpu.read_msr(msr);
+        if value != 0 {
+            let _ = handle_write(vcpu, msr, value);
+        }
+    }
+}
+
+/// Unlocks the system call entry of the processor `id`, and unprotects the
+/// handler page unless it is locked on another processor. Called from the host
+/// on INIT, when the guest leaves long mode, and on devirtualization.
+pub(crate) fn unlock(id: ProcessorId) {
+    let Some(entry) = ENTRIES.get_allocated(id) else {
+        return;
+    };
+    entry.lstar.store(0, Ordering::Release);
+    entry.star.store(0, Ordering::Release);
+    let page = entry.handler_page.swap(0, Ordering::SeqCst);
+    if page == 0 {
+        return;
+    }
+    log::info!("Unlocked the system call entry");
+
+    let locked_elsewhere = (0..apic_id::capacity())
+        .filter_map(|id| ENTRIES.get_allocated(id))
+        .any(|entry| entry.handler_page.load(Ordering::SeqCst) == page);
+    if locked_elsewhere {
+        return;
+    }
+    match memory_protection::try_unprotect_gpa_range(page) {
+        Some(Ok(()) | Err(ProtectionError::NotProtected { .. })) => {}
+        Some(Err(err)) => log::warn!("Cannot unprotect the system call handler: {err}"),
+        None => {
+            log::warn!("Cannot unprotect the system call handler while protections are updated");
+        }
+    }
+}
+
+/// Moves up to `max` of the unread events out, from the oldest. Must be called
+/// from the host.
+pub(crate) fn take_events(max: usize) -> Vec<TamperEvent> {
+    let mut events = EVENTS.lock();
+    let count = max.min(events.len());
+    events.drain(..count).collect()
+}
+
+/// The result of checking a write against the locked value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WriteCheck {
+    /// No value was locked. `value` is locked now.
+    Locked,
+
+    /// The value written is the locked one.
+    Unchanged,
+
+    /// The value written differs from the locked one.
+    Tampered,
+}
+
+/// Checks the write of `value` against `locked`, locking `value` if nothing is
+/// locked yet. Zero means nothing is locked.
+fn check_write(locked: &AtomicU64, value: u64) -> WriteCheck {
+    match locked.compare_exchange(0, value, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => WriteCheck::Locked,
+        Err(current) if current == value => WriteCheck::Unchanged,
+        Err(_) => WriteCheck::Tampered,
+    }
+}
+
+/// Handles `WRMSR` of IA32_LSTAR or IA32_STAR.
+fn handle_write(vcpu: &mut dyn Vcpu, msr: u32, value: u64) -> Option<u64> {
+    let Some(entry) = ENTRIES.get(vcpu.id()) else {
+        return Some(value);
+    };
+    let locked = if msr == x86::msr::IA32_LSTAR {
+        &entry.lstar
+    } else {
+        &entry.star
+    };
+    match check_write(locked, value) {
+        WriteCheck::Locked => {
+            log::info!("Locked MSR {msr:#x} to {value:#x}");
+            if msr == x86::msr::IA32_LSTAR && value != 0 {
+                // The handler is mapped by the time the guest points IA32_LSTAR
+                // to it. Record the page before protecting it, so that `unlock`
+                // on another processor does not unprotect it meanwhile.
+                match guest_memory::translate_guest(vcpu, value) {
+                    Ok(translation) => {
+                        let page = page_of(translation.gpa);
+                        entry.handler_page.store(page, Ordering::SeqCst);
+                        log_protection(memory_protection::try_protect_gpa_range(
+                            page,
+                            BASE_PAGE_SIZE as u64,
+                            Permissions::READ_EXECUTE,
+                            handle_violation,
+                        ));
+                    }
+                    Err(err) => log::warn!("Cannot protect the system call handler: {err}"),
+                }
+            }
+            Some(value)
+        }
+        WriteCheck::Unchanged => Some(value),
+        WriteCheck::Tampered => {
+            report(vcpu, TamperKind::MsrWrite, u64::from(msr), value);
+            None
+        }
+    }
+}
+
+/// Handles a write to the page of the system call handler.
+fn handle_violation(vcpu: &mut dyn Vcpu, info: &NestedPageFaultInfo) -> ViolationAction {
+    report(vcpu, TamperKind::HandlerWrite, info.gpa, 0);
+
+    // Make the instruction fail rather than skipping it, as the guest would not
+    // notice the write was discarded otherwise.
+    let gp = Event::Exception {
+        vector: 13,
+        error_code: Some(0),
+    };
+    if let Err(err) = event::inject_event(vcpu, gp) {
+        log::error!("Could not inject #GP: {err}");
+    }
+    ViolationAction::Resume
+}
+
+fn report(vcpu: &mut dyn Vcpu, kind: TamperKind, target: u64, value: u64) {
+    let event = TamperEvent {
+        kind,
+        processor: vcpu.id() as u64,
+        rip: vcpu.regs().rip,
+        target,
+        value,
+        tsc: rdtsc(),
+    };
+    log::warn!("Blocked tampering with the system call entry: {event:x?}");
+
+    let mut events = EVENTS.lock();
+    if events.len() == MAX_TAMPER_EVENTS {
+        let _ = events.pop_front();
+    }
+    events.push_back(event);
+    drop(events);
+
+    let _ = event_channel::post(
+        vcpu,
+        EventKind::Tamper,
+        [
+            event.kind as u64,
+            event.processor,
+            event.rip,
+            event.target,
+            event.value,
+            event.tsc,
+        ],
+    );
+}
+
+fn log_protection(result: Option<Result<(), ProtectionError>>) {
+    match result {
+        Some(Ok(())) => {}
+        // Locked on another processor with the same handler.
+        Some(Err(ProtectionError::Overlaps { .. })) => {}
+        Some(Err(err)) => log::warn!("Cannot protect the system call handler: {err}"),
+        None => log::warn!("Cannot protect the system call handler while protections are updated"),
+    }
+}
+
+fn page_of(pa: u64) -> u64 {
+    pa & !(BASE_PAGE_SIZE as u64 - 1)
+}
+
+/// Returns whether the events are locked by any processor. See
+/// `host::fail_open`.
+pub(crate) fn is_locked() -> bool {
+    EVENTS.is_locked()
+}
+
+/// The system call entry locked on a processor.
+#[derive(Debug, Default)]
+struct LockedEntry {
+    /// The locked IA32_LSTAR, or zero if not locked yet.
+    lstar: AtomicU64,
+
+    /// The locked IA32_STAR, or zero if not locked yet.
+    star: AtomicU64,
+
+    /// The guest physical address of the handler page protected for `lstar`,
+    /// or zero if none.
+    handler_page: AtomicU64,
+}
+
+/// The system call entries locked on each processor.
+static ENTRIES: PerProcessor<LockedEntry> = PerProcessor::new();
+
+/// The unread events. Only used in the host.
+static EVENTS: Mutex<VecDeque<TamperEvent>> = Mutex::new(VecDeque::new());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_write_is_locked() {
+        let locked = AtomicU64::new(0);
+        assert_eq!(check_write(&locked, 0x1000), WriteCheck::Locked);
+        assert_eq!(check_write(&locked, 0x1000), WriteCheck::Unchanged);
+        assert_eq!(check_write(&locked, 0x2000), WriteCheck::Tampered);
+        assert_eq!(locked.load(Ordering::Relaxed), 0x1000);
+    }
+
+    #[test]
+    fn zero_does_not_lock() {
+        let locked = AtomicU64::new(0);
+        assert_eq!(check_write(&locked, 0), WriteCheck::Locked);
+        assert_eq!(check_write(&locked, 0x1000), WriteCheck::Locked);
+    }
+}
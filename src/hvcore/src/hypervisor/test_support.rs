@@ -0,0 +1,319 @@
+//! This module implements the mocks of the platform and the processor for unit
+//! tests, which run as a normal program in user mode on the development machine.
+//! They let the logic built on the platform API and the privileged instructions
+//! be tested without the hardware and the platforms the hypervisor runs on.
+//!
+//! - [`MockPlatformOps`] is the platform API of a single processor, where the
+//!   physical address of memory is its virtual address. Register it with
+//!   [`init_platform_ops`]. The host window maps memory the same way, with
+//!   [`map_host_window`] and [`host_pa`].
+//! - [`MockCpu`] is the state of the processor the wrappers of the privileged
+//!   instructions in `x86_instructions` operate on, such as MSRs and control
+//!   registers. Each test thread has its own, accessed with [`with_cpu`].
+//!   `CPUID` is not mocked, as it is executable in user mode.
+//! - [`MockGuest`] is a guest whose state is in memory, for testing VM-exit
+//!   handlers.
+//!
+//! The global allocator is not used in tests, and the heap of the test program
+//! serves allocation instead.
+
+use std::{boxed::Box, cell::RefCell, collections::BTreeMap, sync::Once};
+
+use crate::hypervisor::{
+    dirty_tracking::{DirtyBitmap, DirtyTrackingError},
+    event::Event,
+    exit_trace::RawExitInfo,
+    host::{
+        GpaMapping, Guest, GuestSegment, GuestSystemState, NestedPageFaultInfo, SegmentRegister,
+        Vcpu,
+    },
+    hw_breakpoint::DebugState,
+    memory_protection::Permissions,
+    platform_ops::{self, PlatformOps},
+    registers::{ExtendedRegisters, Registers},
+    single_step::{SingleStepCallback, SingleStepError},
+    virtualization_exception::VeError,
+    HvError, VmExitReason,
+};
+
+/// The platform API of a single processor with the identity mapping between the
+/// physical and virtual addresses.
+#[derive(Debug, Default)]
+pub(crate) struct MockPlatformOps;
+
+impl PlatformOps for MockPlatformOps {
+    fn run_on_all_processors(&self, callback: fn()) {
+        callback();
+    }
+
+    fn run_on_processor(&self, index: usize, callback: fn()) {
+        assert_eq!(index, 0, "only the processor 0 exists");
+        callback();
+    }
+
+    fn current_processor_index(&self) -> usize {
+        0
+    }
+
+    fn pa(&self, va: *const core::ffi::c_void) -> u64 {
+        va as u64
+    }
+
+    fn va(&self, pa: u64) -> *mut core::ffi::c_void {
+        pa as *mut _
+    }
+}
+
+/// Returns the linear address of `pa` through the host window of the mock
+/// processor, which is `pa` itself, as with `MockPlatformOps`.
+pub(crate) fn map_host_window(pa: u64) -> *mut u8 {
+    pa as *mut u8
+}
+
+/// Returns the physical address `va` maps to in the mock host address space,
+/// which is `va` itself, as with `MockPlatformOps`.
+pub(crate) fn host_pa(va: u64) -> Option<u64> {
+    Some(va)
+}
+
+/// Registers `MockPlatformOps` as the platform API, unless already registered
+/// by another test.
+pub(crate) fn init_platform_ops() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| platform_ops::init(Box::new(MockPlatformOps)));
+}
+
+/// The state of the mock processor. Reading an MSR not in `msrs` panics, as
+/// `RDMSR` of an unimplemented MSR causes #GP.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MockCpu {
+    pub(crate) msrs: BTreeMap<u32, u64>,
+    pub(crate) cr0: u64,
+    pub(crate) cr3: u64,
+    pub(crate) cr4: u64,
+    pub(crate) gdtr_base: u64,
+    pub(crate) gdtr_limit: u16,
+    pub(crate) tr: u16,
+
+    /// The time-stamp counter, incremented on each read.
+    pub(crate) tsc: u64,
+
+    /// RFLAGS.AC, set by `STAC` and cleared by `CLAC`.
+    pub(crate) rflags_ac: bool,
+}
+
+impl MockCpu {
+    pub(crate) fn rdmsr(&self, msr: u32) -> u64 {
+        match self.msrs.get(&msr) {
+            Some(value) => *value,
+            None => panic!("#GP: RDMSR of an unimplemented MSR {msr:#x}"),
+        }
+    }
+
+    pub(crate) fn wrmsr(&mut self, msr: u32, value: u64) {
+        let _ = self.msrs.insert(msr, value);
+    }
+}
+
+/// Calls `f` with the mock processor of the current thread.
+pub(crate) fn with_cpu<R>(f: impl FnOnce(&mut MockCpu) -> R) -> R {
+    std::thread_local! {
+        static CPU: RefCell<MockCpu> = RefCell::new(MockCpu::default());
+    }
+    CPU.with(|cpu| f(&mut cpu.borrow_mut()))
+}
+
+/// A guest in 64-bit mode whose state is in memory. The functions to run the
+/// guest and those depending on the virtualization extension panic.
+#[derive(Debug)]
+pub(crate) struct MockGuest {
+    pub(crate) id: usize,
+    pub(crate) regs: Registers,
+    pub(crate) cpl: u8,
+    pub(crate) cr0: u64,
+    pub(crate) cr3: u64,
+    pub(crate) cr4: u64,
+    pub(crate) efer: u64,
+    pub(crate) segments: BTreeMap<u8, GuestSegment>,
+    pub(crate) msrs: BTreeMap<u32, u64>,
+    pub(crate) cr2: u64,
+    pub(crate) pending_event: Option<Event>,
+    pub(crate) queued_interrupts: std::vec::Vec<u8>,
+    pub(crate) dr: [u64; 8],
+    pub(crate) debug_state: DebugState,
+}
+
+impl Vcpu for MockGuest {
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn regs(&mut self) -> &mut Registers {
+        &mut self.regs
+    }
+
+    fn cpl(&self) -> u8 {
+        self.cpl
+    }
+
+    fn cr0(&self) -> u64 {
+        self.cr0
+    }
+
+    fn cr3(&self) -> u64 {
+        self.cr3
+    }
+
+    fn cr4(&self) -> u64 {
+        self.cr4
+    }
+
+    fn efer(&self) -> u64 {
+        self.efer
+    }
+
+    fn segment(&self, register: SegmentRegister) -> GuestSegment {
+        self.segments
+            .get(&(register as u8))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn read_msr(&self, msr: u32) -> u64 {
+        self.msrs.get(&msr).copied().unwrap_or_default()
+    }
+
+    fn write_msr(&mut self, msr: u32, value: u64) {
+        let _ = self.msrs.insert(msr, value);
+    }
+
+    fn set_cr2(&mut self, value: u64) {
+        self.cr2 = value;
+    }
+
+    fn pending_event(&self) -> Option<Event> {
+        self.pending_event
+    }
+
+    fn set_pending_event(&mut self, event: Option<Event>) {
+        self.pending_event = event;
+    }
+
+    fn queue_interrupt(&mut self, vector: u8) {
+        self.queued_interrupts.push(vector);
+    }
+
+    fn single_step(&mut self, _callback: Box<SingleStepCallback>) -> Result<(), SingleStepError> {
+        Err(SingleStepError::Unsupported)
+    }
+
+    fn harvest_dirty_pages(&mut self) -> Result<DirtyBitmap, DirtyTrackingError> {
+        Err(DirtyTrackingError::Unsupported)
+    }
+
+    /// Returns every address as RAM mapped to itself.
+    fn resolve_gpa(&self, _gpa: u64) -> Option<GpaMapping> {
+        Some(GpaMapping {
+            permissions: Permissions::ALL,
+            ram: true,
+        })
+    }
+}
+
+impl Guest for MockGuest {
+    /// Returns the guest at CPL 0 in 64-bit mode.
+    fn new(id: usize) -> Result<Self, HvError> {
+        const CR0_PE: u64 = 1 << 0;
+        const CR0_PG: u64 = 1 << 31;
+        const CR4_PAE: u64 = 1 << 5;
+        const EFER_LME: u64 = 1 << 8;
+        const EFER_LMA: u64 = 1 << 10;
+
+        Ok(Self {
+            id,
+            regs: Registers::default(),
+            cpl: 0,
+            cr0: CR0_PE | CR0_PG,
+            cr3: 0,
+            cr4: CR4_PAE,
+            efer: EFER_LME | EFER_LMA,
+            segments: BTreeMap::new(),
+            msrs: BTreeMap::new(),
+            cr2: 0,
+            pending_event: None,
+            queued_interrupts: std::vec::Vec::new(),
+            dr: [0; 8],
+            debug_state: DebugState::new(),
+        })
+    }
+
+    fn is_shared_data_locked() -> bool {
+        false
+    }
+
+    fn activate(&mut self) -> Result<(), HvError> {
+        Ok(())
+    }
+
+    fn initialize(
+        &mut self,
+        registers: &Registers,
+        _extended: Option<ExtendedRegisters>,
+    ) -> Result<(), HvError> {
+        self.regs = *registers;
+        Ok(())
+    }
+
+    fn run(&mut self) -> VmExitReason {
+        unimplemented!("the mock guest cannot run");
+    }
+
+    fn handle_nested_page_fault(&mut self, info: &NestedPageFaultInfo) {
+        unimplemented!("the mock guest has no nested paging: {info:#x?}");
+    }
+
+    fn exit_info(&self) -> RawExitInfo {
+        RawExitInfo::default()
+    }
+
+    fn write_cr(&mut self, cr: u8, value: u64) {
+        match cr {
+            0 => self.cr0 = value,
+            3 => self.cr3 = value,
+            4 => self.cr4 = value,
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_cr3_targets(&mut self, _targets: &[u64]) {}
+
+    fn debug_state(&mut self) -> &mut DebugState {
+        &mut self.debug_state
+    }
+
+    fn read_dr(&self, dr: u8) -> u64 {
+        self.dr[usize::from(dr)]
+    }
+
+    fn write_dr(&mut self, dr: u8, value: u64) {
+        self.dr[usize::from(dr)] = value;
+    }
+
+    fn intercept_debug(&mut self, _enable: bool) {}
+
+    fn set_virtualization_exception_info(&mut self, _info_pa: Option<u64>) -> Result<(), VeError> {
+        Err(VeError::Unsupported)
+    }
+
+    fn in_nested_guest(&self) -> bool {
+        false
+    }
+
+    fn can_devirtualize(&self) -> bool {
+        true
+    }
+
+    fn deactivate(&mut self) -> GuestSystemState {
+        unimplemented!("the mock guest cannot be deactivated");
+    }
+}
@@ -0,0 +1,137 @@
+//! This module implements management of flushing cached translations of the
+//! guest. Code changing what the guest translations derive from, such as the
+//! nested paging structures, requests a flush with [`flush_guest`] instead of
+//! invalidating by itself.
+//!
+//! Requests are recorded for the processor and coalesced until the next
+//! VM-entry on it, where the architecture specific code performs a single flush
+//! covering all of them: INVEPT on Intel processors, and TLB_CONTROL on AMD
+//! processors, which flushes only entries of the ASID of the guest where
+//! supported. A burst of changes, such as installing many hooks, results in
+//! one flush per processor instead of one per page.
+//!
+//! ```ignore
+//! // In a VM-exit handler, after changing the guest paging structures.
+//! tlb::flush_guest(vcpu, FlushScope::GuestLinear);
+//! ```
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::hypervisor::{host::Vcpu, percpu};
+
+/// The translations to flush.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushScope {
+    /// Translations of guest linear addresses, for example, after the host
+    /// changes the guest paging structures. VM transitions invalidate them on
+    /// Intel processors without VPIDs.
+    GuestLinear,
+
+    /// Translations derived from the EPT or the NPT, for example, after the
+    /// host changes permissions of guest physical pages.
+    GuestPhysical,
+}
+
+impl FlushScope {
+    fn bit(self) -> u8 {
+        match self {
+            Self::GuestLinear => 1 << 0,
+            Self::GuestPhysical => 1 << 1,
+        }
+    }
+}
+
+/// The flushes requested for a processor and not performed yet.
+#[derive(Debug, Default)]
+pub(crate) struct PendingFlushes(AtomicU8);
+
+/// The flushes to perform before the next VM-entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Flushes {
+    pub(crate) linear: bool,
+    pub(crate) physical: bool,
+}
+
+impl Flushes {
+    /// Returns whether any flush is requested.
+    #[cfg(any(feature = "amd", test))]
+    pub(crate) fn any(self) -> bool {
+        self.linear || self.physical
+    }
+}
+
+impl PendingFlushes {
+    fn request(&self, scope: FlushScope) {
+        let _ = self.0.fetch_or(scope.bit(), Ordering::AcqRel);
+    }
+
+    fn take(&self) -> Flushes {
+        Self::decode(self.0.swap(0, Ordering::AcqRel))
+    }
+
+    fn peek(&self) -> Flushes {
+        Self::decode(self.0.load(Ordering::Acquire))
+    }
+
+    fn decode(bits: u8) -> Flushes {
+        Flushes {
+            linear: bits & FlushScope::GuestLinear.bit() != 0,
+            physical: bits & FlushScope::GuestPhysical.bit() != 0,
+        }
+    }
+}
+
+/// Requests flushing the `scope` translations of `vcpu`. Takes effect on the
+/// next VM-entry of `vcpu`, along with any other request made until then.
+pub fn flush_guest(vcpu: &dyn Vcpu, scope: FlushScope) {
+    percpu::get(vcpu.id())
+        .expect("the block is allocated")
+        .tlb_flushes
+        .request(scope);
+}
+
+/// Requests flushing the `scope` translations on all processors. Takes effect
+/// on each processor on the next VM-exit on that processor.
+pub fn flush_all_guests(scope: FlushScope) {
+    for block in percpu::all() {
+        block.tlb_flushes.request(scope);
+    }
+}
+
+/// Returns the flushes requested for `vcpu` and not performed yet, without
+/// clearing them.
+pub(crate) fn pending(vcpu: &dyn Vcpu) -> Flushes {
+    percpu::get(vcpu.id())
+        .expect("the block is allocated")
+        .tlb_flushes
+        .peek()
+}
+
+/// Returns and clears the flushes requested for the current processor. Called
+/// from the host right before VM-entry.
+pub(crate) fn take() -> Flushes {
+    percpu::current().tlb_flushes.take()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_coalesced() {
+        let pending = PendingFlushes::default();
+        assert!(!pending.take().any());
+
+        pending.request(FlushScope::GuestPhysical);
+        pending.request(FlushScope::GuestPhysical);
+        pending.request(FlushScope::GuestLinear);
+        assert_eq!(
+            pending.take(),
+            Flushes {
+                linear: true,
+                physical: true
+            }
+        );
+        assert!(!pending.take().any());
+    }
+}
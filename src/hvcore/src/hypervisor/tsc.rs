@@ -0,0 +1,178 @@
+//! This module implements management of the guest TSC: scaling of its frequency
+//! and compensation for the time spent in the host, so that VM-exits are not
+//! obvious from `RDTSC` and `RDTSCP`.
+//!
+//! For compensation, each processor subtracts the TSC cycles elapsed between
+//! VM-exit and the next VM-entry from its TSC offset. The cycles for the
+//! transitions themselves are not accounted. Also, as the offset diverges across
+//! processors, the guest TSC is no longer synchronized across processors.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::hypervisor::{apic_id::PerProcessor, x86_instructions::rdtsc};
+
+/// The configuration of the guest TSC.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TscConfig {
+    /// Whether to subtract the time spent in the host from the guest TSC.
+    pub hide_exit_overhead: bool,
+
+    /// The ratio of the guest TSC frequency to the host's as a fixed-point
+    /// number with 32 fractional bits, for example, `1 << 31` for half the
+    /// frequency. Must be non-zero and less than 256.0. Ignored with a warning
+    /// if the processor does not support TSC scaling. `None` not to scale.
+    pub scale: Option<u64>,
+}
+
+impl TscConfig {
+    /// Returns whether `scale` is in the range it must be.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.scale
+            .is_none_or(|scale| scale != 0 && scale < MAX_SCALE)
+    }
+}
+
+/// Returns the total TSC cycles of the host hidden from the guest TSC on the
+/// processor `id`, in the host TSC frequency. Zero if
+/// `TscConfig::hide_exit_overhead` is not set.
+pub fn exit_overhead(id: usize) -> u64 {
+    EXIT_OVERHEADS
+        .get_allocated(id)
+        .map_or(0, |overhead| overhead.load(Ordering::Relaxed))
+}
+
+/// Returns the TSC frequency in Hz if the processor enumerates it.
+pub(crate) fn frequency() -> Option<u64> {
+    const CPUID_TSC_FREQUENCY: u32 = 0x15;
+    const CPUID_PROCESSOR_FREQUENCY: u32 = 0x16;
+
+    let max_leaf = x86::cpuid::cpuid!(0x0).eax;
+
+    // "If EBX[31:0] is 0, the TSC/"core crystal clock" ratio is not enumerated."
+    // "If ECX is 0, the nominal core crystal clock frequency is not enumerated."
+    // See: (Intel) Table 3-8. Information Returned by CPUID Instruction
+    if max_leaf >= CPUID_TSC_FREQUENCY {
+        let leaf = x86::cpuid::cpuid!(CPUID_TSC_FREQUENCY);
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return Some(u64::from(leaf.ecx) * u64::from(leaf.ebx) / u64::from(leaf.eax));
+        }
+    }
+
+    // The processor base frequency in MHz approximates the TSC frequency.
+    if max_leaf >= CPUID_PROCESSOR_FREQUENCY {
+        let base_mhz = x86::cpuid::cpuid!(CPUID_PROCESSOR_FREQUENCY).eax & 0xffff;
+        if base_mhz != 0 {
+            return Some(u64::from(base_mhz) * 1_000_000);
+        }
+    }
+    None
+}
+
+/// Returns whether the guest TSC runs at the constant host frequency and is
+/// synchronized across processors with `config`, so that a single conversion
+/// of it into time is valid on every processor.
+pub(crate) fn is_constant_across_processors(config: &TscConfig) -> bool {
+    const CPUID_ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+    // "Bit 08: Invariant TSC available if 1."
+    // See: Table 1-17. Information Returned by CPUID Instruction
+    let invariant = x86::cpuid::cpuid!(0x8000_0000).eax >= CPUID_ADVANCED_POWER_MANAGEMENT
+        && x86::cpuid::cpuid!(CPUID_ADVANCED_POWER_MANAGEMENT).edx & (1 << 8) != 0;
+    invariant && !config.hide_exit_overhead && config.scale.is_none()
+}
+
+/// The value of `TscConfig::scale` that does not change the frequency.
+const SCALE_ONE: u64 = 1 << 32;
+
+/// The smallest value of `TscConfig::scale` out of its range, 256.0.
+const MAX_SCALE: u64 = 256 << 32;
+
+/// The per-processor state of TSC compensation.
+#[derive(Debug)]
+pub(crate) struct TscCompensation {
+    id: usize,
+    enabled: bool,
+    scale: Option<u64>,
+    offset: u64,
+    exit_tsc: Option<u64>,
+}
+
+impl TscCompensation {
+    /// Creates the state for the processor `id`. `scale` is the effective
+    /// value of `TscConfig::scale`, which `virtualize_system` validated.
+    pub(crate) fn new(id: usize, config: &TscConfig, scale: Option<u64>) -> Self {
+        debug_assert!(TscConfig { scale, ..*config }.is_valid());
+        Self {
+            id,
+            enabled: config.hide_exit_overhead,
+            scale,
+            offset: 0,
+            exit_tsc: None,
+        }
+    }
+
+    /// Returns whether the compensation is enabled.
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the effective TSC scale, if the guest TSC is scaled.
+    pub(crate) fn scale(&self) -> Option<u64> {
+        self.scale
+    }
+
+    /// Records the TSC at VM-exit. Must be called right after VM-exit.
+    pub(crate) fn on_exit(&mut self) {
+        if self.enabled {
+            self.exit_tsc = Some(rdtsc());
+        }
+    }
+
+    /// Returns the TSC offset for the next VM-entry, which hides the cycles
+    /// elapsed since `on_exit`. Must be called right before VM-entry.
+    pub(crate) fn on_entry(&mut self) -> u64 {
+        if let Some(exit_tsc) = self.exit_tsc.take() {
+            let elapsed = rdtsc().wrapping_sub(exit_tsc);
+            self.offset = self
+                .offset
+                .wrapping_sub(scaled(elapsed, self.scale.unwrap_or(SCALE_ONE)));
+            if let Some(overhead) = EXIT_OVERHEADS.get(self.id) {
+                let _ = overhead.fetch_add(elapsed, Ordering::Relaxed);
+            }
+        }
+        self.offset
+    }
+}
+
+/// Converts `cycles` of the host TSC into the guest TSC with `scale`.
+fn scaled(cycles: u64, scale: u64) -> u64 {
+    ((u128::from(cycles) * u128::from(scale)) >> 32) as u64
+}
+
+static EXIT_OVERHEADS: PerProcessor<AtomicU64> = PerProcessor::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaling() {
+        assert_eq!(scaled(1000, SCALE_ONE), 1000);
+        assert_eq!(scaled(1000, SCALE_ONE / 2), 500);
+        assert_eq!(scaled(1000, SCALE_ONE * 3), 3000);
+        assert_eq!(scaled(u64::MAX, SCALE_ONE), u64::MAX);
+    }
+
+    #[test]
+    fn scale_range() {
+        let config = |scale| TscConfig {
+            hide_exit_overhead: false,
+            scale,
+        };
+        assert!(config(None).is_valid());
+        assert!(config(Some(1)).is_valid());
+        assert!(config(Some(MAX_SCALE - 1)).is_valid());
+        assert!(!config(Some(0)).is_valid());
+        assert!(!config(Some(MAX_SCALE)).is_valid());
+    }
+}
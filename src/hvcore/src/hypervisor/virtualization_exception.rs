@@ -0,0 +1,153 @@
+//! This module implements conversion of EPT violations into virtualization
+//! exceptions (#VE) delivered to the guest. This lets an agent in the guest
+//! handle violations itself, for example, by switching EPT views with
+//! `ept_hook::switch_view` on hooked pages.
+//!
+//! The agent installs a handler for the vector 20 in the IDT of the guest, and
+//! enables #VE on each processor with `enable_on_current_processor`, passing
+//! the page the processor writes the information of #VE to. Only violations on
+//! pages made convertible with `set_convertible` are converted, and the others
+//! cause VM-exits as usual. The processor delivers #VE only while `busy` of the
+//! information is zero, and sets it to `u32::MAX` on delivery. The handler
+//! clears it to receive the next one.
+//!
+//! This is supported only on Intel processors supporting the "EPT-violation
+//! #VE" VM-execution control.
+//!
+//! ```ignore
+//! set_convertible(hooked_pa, true)?;
+//!
+//! // On each processor, with a page for the processor.
+//! enable_on_current_processor(Box::leak(Box::new(VeInformation::new())))?;
+//! ```
+// See: 26.5.7 Virtualization Exceptions
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::collections::BTreeSet;
+use spin::Mutex;
+#[cfg(feature = "intel")]
+use spin::MutexGuard;
+use x86::bits64::paging::{BASE_PAGE_SIZE, HUGE_PAGE_SIZE};
+
+use crate::hypervisor::{
+    hypercall::{self, Hypercall},
+    platform_ops,
+};
+
+/// The information the processor writes on delivery of #VE.
+// See: Table 26-1. Format of the Virtualization-Exception Information Area
+#[derive(Debug)]
+#[repr(C, align(4096))]
+pub struct VeInformation {
+    /// The basic exit reason for EPT violation, 48.
+    pub exit_reason: u32,
+    /// Zero if the processor may deliver #VE. Set to `u32::MAX` on delivery.
+    pub busy: u32,
+    /// The exit qualification as for VM-exit due to the EPT violation.
+    pub exit_qualification: u64,
+    /// The guest linear address as for VM-exit due to the EPT violation.
+    pub guest_linear_address: u64,
+    /// The guest physical address that caused the violation.
+    pub guest_physical_address: u64,
+    /// The EPT view the processor was in. See `ept_hook`.
+    pub eptp_index: u16,
+}
+
+impl VeInformation {
+    /// Returns the information ready for the first #VE.
+    pub const fn new() -> Self {
+        Self {
+            exit_reason: 0,
+            busy: 0,
+            exit_qualification: 0,
+            guest_linear_address: 0,
+            guest_physical_address: 0,
+            eptp_index: 0,
+        }
+    }
+}
+
+impl Default for VeInformation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The errors virtualization exceptions may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VeError {
+    #[error("the processor does not support virtualization exceptions")]
+    Unsupported,
+
+    #[error("{gpa:#x?} is not page aligned or not in the first 512GB")]
+    InvalidPage { gpa: u64 },
+}
+
+/// Enables #VE on the current processor, with `info` as the page to write the
+/// information of #VE to. Must be called from the guest. `info` must stay
+/// resident at the same physical address while #VE is enabled.
+///
+/// # Errors
+///
+/// Returns `Unsupported` if the processor does not support #VE.
+pub fn enable_on_current_processor(info: &'static mut VeInformation) -> Result<(), VeError> {
+    let info_pa = platform_ops::get().pa(info as *mut _ as *const _);
+    hypercall::issue(Hypercall::EnableVirtualizationExceptions, info_pa, 0, 0)
+        .map(|_| ())
+        .map_err(|_| VeError::Unsupported)
+}
+
+/// Disables #VE on the current processor. Must be called from the guest.
+pub fn disable_on_current_processor() {
+    let _ = hypercall::issue(Hypercall::EnableVirtualizationExceptions, 0, 0, 0);
+}
+
+/// Makes violations on the guest physical page at `gpa` converted into #VE if
+/// `convertible`, or cause VM-exits otherwise. Changes take effect on each
+/// processor on the next VM-exit on that processor.
+///
+/// # Errors
+///
+/// Returns `InvalidPage` if `gpa` is not page aligned or outside the first
+/// 512GB.
+pub fn set_convertible(gpa: u64, convertible: bool) -> Result<(), VeError> {
+    if !gpa.is_multiple_of(BASE_PAGE_SIZE as u64) || gpa >= HUGE_PAGE_SIZE as u64 * 512 {
+        return Err(VeError::InvalidPage { gpa });
+    }
+
+    let mut pages = CONVERTIBLE_PAGES.lock();
+    let changed = if convertible {
+        pages.insert(gpa)
+    } else {
+        pages.remove(&gpa)
+    };
+    if changed {
+        let _ = GENERATION.fetch_add(1, Ordering::AcqRel);
+    }
+    Ok(())
+}
+
+/// Returns the current generation of the convertible pages. It is incremented
+/// every time a page is made convertible or non-convertible.
+#[cfg(feature = "intel")]
+pub(crate) fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Returns the convertible pages if their lock is available. This is used
+/// from the host, where spinning on the lock could deadlock with the guest on
+/// the same processor that already owns it.
+#[cfg(feature = "intel")]
+pub(crate) fn try_convertible_pages() -> Option<MutexGuard<'static, BTreeSet<u64>>> {
+    CONVERTIBLE_PAGES.try_lock()
+}
+
+/// Returns whether the convertible pages are locked by any processor. See
+/// `host::fail_open`.
+pub(crate) fn is_locked() -> bool {
+    CONVERTIBLE_PAGES.is_locked()
+}
+
+static CONVERTIBLE_PAGES: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+static GENERATION: AtomicU64 = AtomicU64::new(0);
@@ -0,0 +1,270 @@
+//! This module implements the watchdog of the host, which detects a processor
+//! stuck in a VM-exit handler, for example, spinning on a lock that is never
+//! released, and dumps what it was doing. Without it, a deadlocked handler
+//! freezes the machine silently.
+//!
+//! Enabled with `SharedHostData::watchdog`, each processor publishes a
+//! heartbeat, the TSC at which it started handling the current VM-exit, and
+//! clears it when it returns to the guest. On their own VM-exits, the other
+//! processors check the heartbeats at most twice per timeout, and report a
+//! processor whose heartbeat is older than the timeout once per stall: its
+//! latest VM-exits are logged, and on Intel processors with the host IDT, an
+//! NMI is sent to it so that the NMI handler of the host captures where it is
+//! stuck. The capture is logged on the next check by any processor, which does
+//! not wait for it. Without the host IDT, the NMI would be handled by the guest.
+//!
+//! Checks only run on VM-exits. Enable `SharedHostData::preemption_timer` too
+//! to check regularly regardless of what the guest does. On AMD processors, the
+//! host runs with GIF cleared, which holds NMIs pending, so the stuck code is
+//! not captured.
+//!
+//! ```ignore
+//! shared_host.watchdog = Watchdog::new(tsc_per_second);
+//! ```
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Once;
+
+use crate::hypervisor::{
+    exit_trace,
+    ipi::{self, IpiKind},
+    percpu::{self, PerCpu},
+    x86_instructions::rdtsc,
+    SHARED_HOST_DATA,
+};
+
+/// The configuration of the watchdog. Disabled by default.
+#[derive(Debug, Default)]
+pub struct Watchdog {
+    timeout: u64,
+}
+
+impl Watchdog {
+    /// Enables the watchdog reporting a processor handling the same VM-exit
+    /// for longer than `timeout` TSC ticks. `timeout` of zero is treated as
+    /// one.
+    pub fn new(timeout: u64) -> Self {
+        Self {
+            timeout: timeout.max(1),
+        }
+    }
+
+    /// Returns whether the watchdog is enabled.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.timeout != 0
+    }
+}
+
+/// The heartbeat of a processor and the state of the watchdog for it.
+#[derive(Debug, Default)]
+pub(crate) struct Heartbeat {
+    /// The TSC at which the processor started handling the current VM-exit, or
+    /// zero while it runs the guest.
+    entered: AtomicU64,
+
+    /// The value of `entered` last reported as stuck.
+    reported: AtomicU64,
+
+    /// The TSC at which the processor last checked the other processors.
+    last_check: AtomicU64,
+
+    /// Whether an NMI is sent to the processor to capture `host_rip` and
+    /// `host_rsp`.
+    dump_requested: AtomicBool,
+
+    /// The TSC at which the NMI was sent, or zero if no capture is awaited.
+    dump_sent: AtomicU64,
+
+    /// Whether `host_rip` and `host_rsp` are captured.
+    dump_ready: AtomicBool,
+    host_rip: AtomicU64,
+    host_rsp: AtomicU64,
+}
+
+impl Heartbeat {
+    /// Records that the processor starts handling a VM-exit at `now`.
+    pub(crate) fn enter(&self, now: u64) {
+        self.entered.store(now.max(1), Ordering::Release);
+    }
+
+    /// Records that the processor returns to the guest.
+    pub(crate) fn leave(&self) {
+        self.entered.store(0, Ordering::Release);
+    }
+
+    /// Returns whether the processor is handling a VM-exit.
+    pub(crate) fn is_entered(&self) -> bool {
+        self.entered.load(Ordering::Acquire) != 0
+    }
+
+    /// Returns and clears whether an NMI for the watchdog is pending on the
+    /// processor. Such an NMI is not for the guest.
+    pub(crate) fn take_dump_request(&self) -> bool {
+        self.dump_requested.swap(false, Ordering::AcqRel)
+    }
+
+    /// Captures the host context interrupted by an NMI for the watchdog.
+    /// Called from the NMI handler of the host.
+    pub(crate) fn capture(&self, rip: u64, rsp: u64) {
+        self.host_rip.store(rip, Ordering::Relaxed);
+        self.host_rsp.store(rsp, Ordering::Relaxed);
+        self.dump_ready.store(true, Ordering::Release);
+    }
+
+    /// Marks that an NMI to capture the host context is sent at `now`.
+    fn request_dump(&self, now: u64) {
+        self.dump_ready.store(false, Ordering::Relaxed);
+        self.dump_requested.store(true, Ordering::Release);
+        self.dump_sent.store(now.max(1), Ordering::Release);
+    }
+
+    /// Returns the captured RIP and RSP of the host if the processor responded
+    /// to the NMI, or `None` inside if it did not in `timeout` at `now`. Returns
+    /// `None` if neither is the case yet or no capture is awaited. Only one
+    /// caller gets each result.
+    fn take_dump(&self, now: u64, timeout: u64) -> Option<Option<(u64, u64)>> {
+        let sent = self.dump_sent.load(Ordering::Acquire);
+        if sent == 0 {
+            return None;
+        }
+        let ready = self.dump_ready.load(Ordering::Acquire);
+        if (!ready && now.saturating_sub(sent) <= timeout)
+            || self
+                .dump_sent
+                .compare_exchange(sent, 0, Ordering::AcqRel, Ordering::Relaxed)
+                .is_err()
+        {
+            return None;
+        }
+        if !ready {
+            let _ = self.take_dump_request();
+            return Some(None);
+        }
+        Some(Some((
+            self.host_rip.load(Ordering::Relaxed),
+            self.host_rsp.load(Ordering::Relaxed),
+        )))
+    }
+
+    /// Returns the TSC at which the processor got stuck if it has handled the
+    /// same VM-exit for longer than `timeout` at `now`, and it is not reported
+    /// yet. Marks it reported, so that only one processor reports it.
+    fn take_stall(&self, now: u64, timeout: u64) -> Option<u64> {
+        let entered = self.entered.load(Ordering::Acquire);
+        if entered == 0 || now.saturating_sub(entered) <= timeout {
+            return None;
+        }
+        let reported = self.reported.load(Ordering::Relaxed);
+        (reported != entered
+            && self
+                .reported
+                .compare_exchange(reported, entered, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok())
+        .then_some(entered)
+    }
+}
+
+/// Checks the heartbeats of the other processors if the current processor did
+/// not recently. Called from the host after handling each VM-exit.
+pub(crate) fn check(watchdog: &Watchdog) {
+    let current = percpu::current();
+    let now = rdtsc();
+    let last_check = current.heartbeat.last_check.load(Ordering::Relaxed);
+    if now.saturating_sub(last_check) < watchdog.timeout / 2 {
+        return;
+    }
+    current.heartbeat.last_check.store(now, Ordering::Relaxed);
+
+    for block in percpu::all().filter(|block| block.id != current.id) {
+        match block.heartbeat.take_dump(now, watchdog.timeout) {
+            Some(Some((rip, rsp))) => log::error!(
+                "The processor {} is at RIP {rip:#x}, RSP {rsp:#x} in host",
+                block.id
+            ),
+            Some(None) => log::error!("The processor {} did not respond to NMI", block.id),
+            None => {}
+        }
+        if let Some(entered) = block.heartbeat.take_stall(now, watchdog.timeout) {
+            report(block, now - entered, now);
+        }
+    }
+}
+
+/// Logs the diagnostics of the processor of `block` stuck for `ticks`, and
+/// sends it an NMI at `now` to capture where it is, which the next check logs.
+fn report(block: &PerCpu, ticks: u64, now: u64) {
+    log::error!(
+        "The processor {} is stuck in a VM-exit handler for {ticks} TSC ticks",
+        block.id
+    );
+    exit_trace::dump_processor(block);
+
+    if let Some(reason) = nmi_dump_unavailable() {
+        log::warn!(
+            "Not capturing where the processor {} is in host, as {reason}",
+            block.id
+        );
+        return;
+    }
+    block.heartbeat.request_dump(now);
+    let _ = ipi::send_ipi(block.id, IpiKind::Nmi);
+}
+
+/// Returns why an NMI cannot capture where a processor is in the host, if it
+/// cannot. Detected once. See the module documentation.
+fn nmi_dump_unavailable() -> Option<&'static str> {
+    static REASON: Once<Option<&'static str>> = Once::new();
+    *REASON.call_once(|| {
+        let is_intel = x86::cpuid::CpuId::new()
+            .get_vendor_info()
+            .is_some_and(|vendor| vendor.as_str() == "GenuineIntel");
+        if !is_intel {
+            Some("the host holds NMIs pending on AMD processors")
+        } else if SHARED_HOST_DATA.get().unwrap().idt.is_none() {
+            Some("the host IDT is not set up")
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stall_is_reported_once() {
+        let heartbeat = Heartbeat::default();
+        assert_eq!(heartbeat.take_stall(1000, 100), None);
+
+        heartbeat.enter(1000);
+        assert_eq!(heartbeat.take_stall(1100, 100), None);
+        assert_eq!(heartbeat.take_stall(1101, 100), Some(1000));
+        assert_eq!(heartbeat.take_stall(1200, 100), None);
+
+        heartbeat.leave();
+        assert_eq!(heartbeat.take_stall(1300, 100), None);
+        heartbeat.enter(1300);
+        assert_eq!(heartbeat.take_stall(1500, 100), Some(1300));
+    }
+
+    #[test]
+    fn dump_is_taken_once_without_waiting() {
+        let heartbeat = Heartbeat::default();
+        assert_eq!(heartbeat.take_dump(1000, 100), None);
+
+        heartbeat.request_dump(1000);
+        assert_eq!(heartbeat.take_dump(1050, 100), None);
+        assert!(heartbeat.take_dump_request());
+        heartbeat.capture(0x1234, 0x5678);
+        assert_eq!(heartbeat.take_dump(1060, 100), Some(Some((0x1234, 0x5678))));
+        assert_eq!(heartbeat.take_dump(1070, 100), None);
+
+        heartbeat.request_dump(2000);
+        assert_eq!(heartbeat.take_dump(2100, 100), None);
+        assert_eq!(heartbeat.take_dump(2101, 100), Some(None));
+        assert!(!heartbeat.take_dump_request());
+        assert_eq!(heartbeat.take_dump(2200, 100), None);
+    }
+}
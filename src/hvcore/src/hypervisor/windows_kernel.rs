@@ -0,0 +1,526 @@
+//! This module implements helpers to introspect the Windows kernel from the
+//! host, so that tools built on this crate do not each parse PE images in
+//! VM-exit handlers:
+//!
+//! - [`KernelImage::locate`] finds ntoskrnl.exe from IA32_LSTAR, and
+//!   [`KernelImage::export`] resolves its exports.
+//! - [`KernelImage::system_call_entry`] and [`KernelImage::service_table`]
+//!   compute the addresses of KiSystemCall64 and the SSDT (KiServiceTable).
+//! - [`hook_routine`] hooks a routine without modifying the memory the guest
+//!   reads, so that PatchGuard does not detect the hook.
+//! - [`EprocessOffsets::for_build`] returns the offsets of commonly used
+//!   EPROCESS fields for known builds reported by `guest_os`.
+//!
+//! All but `hook_routine` read guest memory through `guest_memory` and must be
+//! called from the host, such as VM-exit handlers, while the guest runs with
+//! the kernel mapped.
+
+use core::ops::Range;
+
+use alloc::vec::Vec;
+
+use crate::hypervisor::{
+    breakpoint_marker::{self, MarkerAction, MarkerError},
+    ept_hook::{self, HookError},
+    guest_memory::{self, TranslationError},
+    host::Vcpu,
+};
+
+/// The errors the helpers may return.
+#[derive(thiserror_no_std::Error, Clone, Copy, Debug)]
+pub enum KernelError {
+    #[error("{0}")]
+    Translation(TranslationError),
+
+    #[error("no kernel image contains IA32_LSTAR {lstar:#x?}")]
+    ImageNotFound { lstar: u64 },
+
+    #[error("the export is not found")]
+    ExportNotFound,
+
+    #[error("the section is not found")]
+    SectionNotFound,
+
+    #[error("the code referencing the service descriptor table is not found")]
+    ServiceTableNotFound,
+
+    #[error("the service index {0} is out of range")]
+    InvalidServiceIndex(u32),
+
+    #[error("{0}")]
+    Marker(MarkerError),
+
+    #[error("{0}")]
+    Hook(HookError),
+}
+
+impl From<TranslationError> for KernelError {
+    fn from(value: TranslationError) -> Self {
+        Self::Translation(value)
+    }
+}
+
+/// A loaded kernel image, ntoskrnl.exe.
+#[derive(Clone, Debug)]
+pub struct KernelImage {
+    /// The guest virtual address the image is loaded at.
+    pub base: u64,
+    /// The size of the image in memory.
+    pub size: u32,
+    export_directory: Range<u32>,
+    sections: Vec<Section>,
+}
+
+/// A section of an image, with the address and size relative to the image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Section {
+    name: [u8; 8],
+    rva: u32,
+    size: u32,
+    characteristics: u32,
+}
+
+/// The system call entry IA32_LSTAR points to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SystemCallEntry {
+    /// The address of the entry.
+    pub address: u64,
+    /// Whether the entry is KiSystemCall64Shadow for the kernel virtual
+    /// address shadow (KVA shadow), rather than KiSystemCall64.
+    pub kva_shadow: bool,
+}
+
+/// The system service table, KiServiceTable, that
+/// KeServiceDescriptorTable points to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServiceTable {
+    /// The address of KeServiceDescriptorTable.
+    pub descriptor: u64,
+    /// The address of the table.
+    pub base: u64,
+    /// The number of services.
+    pub count: u32,
+}
+
+/// The size of the page holding the PE headers to parse.
+const HEADER_SIZE: usize = 0x1000;
+
+impl KernelImage {
+    /// Finds the kernel image containing the system call entry in IA32_LSTAR
+    /// by searching the PE header backward from it, up to `SEARCH_SIZE` bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ImageNotFound` if no image is found, for example, before the
+    /// kernel sets up IA32_LSTAR.
+    pub fn locate(vcpu: &dyn Vcpu) -> Result<Self, KernelError> {
+        const SEARCH_SIZE: u64 = 32 * 1024 * 1024;
+
+        let lstar = vcpu.read_msr(x86::msr::IA32_LSTAR);
+        let start = lstar & !(HEADER_SIZE as u64 - 1);
+        let mut page = [0u8; HEADER_SIZE];
+        for base in (start.saturating_sub(SEARCH_SIZE)..=start)
+            .rev()
+            .step_by(HEADER_SIZE)
+        {
+            // Pages of the image may be discarded or paged out. Skip them.
+            let mut magic = [0u8; 2];
+            if guest_memory::read_guest(vcpu, base, &mut magic).is_err() || magic != *b"MZ" {
+                continue;
+            }
+            if guest_memory::read_guest(vcpu, base, &mut page).is_err() {
+                continue;
+            }
+            if let Some(image) = Self::parse(base, &page) {
+                if (base..base + u64::from(image.size)).contains(&lstar) {
+                    return Ok(image);
+                }
+            }
+        }
+        Err(KernelError::ImageNotFound { lstar })
+    }
+
+    /// Parses the PE headers in `page` of the image at `base`.
+    // See: https://learn.microsoft.com/en-us/windows/win32/debug/pe-format
+    fn parse(base: u64, page: &[u8; HEADER_SIZE]) -> Option<Self> {
+        const PE32_PLUS_MAGIC: u16 = 0x20b;
+        const SECTION_HEADER_SIZE: usize = 40;
+
+        let u16_at = |offset: usize| {
+            Some(u16::from_le_bytes(
+                page.get(offset..offset + 2)?.try_into().ok()?,
+            ))
+        };
+        let u32_at = |offset: usize| {
+            Some(u32::from_le_bytes(
+                page.get(offset..offset + 4)?.try_into().ok()?,
+            ))
+        };
+
+        let nt_headers = u32_at(0x3c)? as usize;
+        if page.get(nt_headers..nt_headers + 4)? != b"PE\0\0" {
+            return None;
+        }
+        let file_header = nt_headers + 4;
+        let section_count = usize::from(u16_at(file_header + 2)?);
+        let optional_header = file_header + 20;
+        if u16_at(optional_header)? != PE32_PLUS_MAGIC {
+            return None;
+        }
+        let size = u32_at(optional_header + 56)?;
+        let export_rva = u32_at(optional_header + 112)?;
+        let export_size = u32_at(optional_header + 116)?;
+
+        let section_headers = optional_header + usize::from(u16_at(file_header + 16)?);
+        let sections = (0..section_count)
+            .map(|index| {
+                let header = section_headers + index * SECTION_HEADER_SIZE;
+                Some(Section {
+                    name: page.get(header..header + 8)?.try_into().ok()?,
+                    size: u32_at(header + 8)?,
+                    rva: u32_at(header + 12)?,
+                    characteristics: u32_at(header + 36)?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            base,
+            size,
+            export_directory: export_rva..export_rva.checked_add(export_size)?,
+            sections,
+        })
+    }
+
+    /// Returns the address range of the section `name`, such as ".text".
+    ///
+    /// # Errors
+    ///
+    /// Returns `SectionNotFound` if the image has no such section.
+    pub fn section(&self, name: &str) -> Result<Range<u64>, KernelError> {
+        let section = self
+            .sections
+            .iter()
+            .find(|section| section.name.split(|&byte| byte == 0).next() == Some(name.as_bytes()))
+            .ok_or(KernelError::SectionNotFound)?;
+        let start = self.base + u64::from(section.rva);
+        Ok(start..start + u64::from(section.size))
+    }
+
+    /// Returns the address ranges of the executable sections, except those
+    /// discarded after initialization, such as INIT.
+    pub fn code_sections(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        const IMAGE_SCN_MEM_DISCARDABLE: u32 = 0x0200_0000;
+        const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+
+        self.sections
+            .iter()
+            .filter(|section| {
+                section.characteristics & (IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_DISCARDABLE)
+                    == IMAGE_SCN_MEM_EXECUTE
+            })
+            .map(|section| {
+                let start = self.base + u64::from(section.rva);
+                start..start + u64::from(section.size)
+            })
+    }
+
+    /// Returns the address of the export `name`, such as "PsLookupProcessByProcessId".
+    ///
+    /// # Errors
+    ///
+    /// Returns `ExportNotFound` if the image has no such export, or it is
+    /// forwarded to another image.
+    pub fn export(&self, vcpu: &dyn Vcpu, name: &str) -> Result<u64, KernelError> {
+        if self.export_directory.is_empty() {
+            return Err(KernelError::ExportNotFound);
+        }
+        let directory = self.base + u64::from(self.export_directory.start);
+        let name_count = self.read_u32(vcpu, directory + 0x18)?;
+        let functions = self.base + u64::from(self.read_u32(vcpu, directory + 0x1c)?);
+        let names = self.base + u64::from(self.read_u32(vcpu, directory + 0x20)?);
+        let ordinals = self.base + u64::from(self.read_u32(vcpu, directory + 0x24)?);
+
+        // The names are sorted in the ascending order. Compare the names with
+        // the terminating NUL so that a prefix of another name compares lower.
+        let mut target = Vec::with_capacity(name.len() + 1);
+        target.extend_from_slice(name.as_bytes());
+        target.push(0);
+        let mut candidate = alloc::vec![0u8; target.len()];
+        let (mut low, mut high) = (0, name_count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let name_rva = self.read_u32(vcpu, names + u64::from(middle) * 4)?;
+            guest_memory::read_guest(vcpu, self.base + u64::from(name_rva), &mut candidate)?;
+            match candidate.as_slice().cmp(&target) {
+                core::cmp::Ordering::Less => low = middle + 1,
+                core::cmp::Ordering::Greater => high = middle,
+                core::cmp::Ordering::Equal => {
+                    let mut ordinal = [0u8; 2];
+                    guest_memory::read_guest(vcpu, ordinals + u64::from(middle) * 2, &mut ordinal)?;
+                    let function = functions + u64::from(u16::from_le_bytes(ordinal)) * 4;
+                    let rva = self.read_u32(vcpu, function)?;
+                    if self.export_directory.contains(&rva) {
+                        return Err(KernelError::ExportNotFound);
+                    }
+                    return Ok(self.base + u64::from(rva));
+                }
+            }
+        }
+        Err(KernelError::ExportNotFound)
+    }
+
+    /// Returns the system call entry IA32_LSTAR points to. With the KVA
+    /// shadow, the entry is KiSystemCall64Shadow in the KVASCODE section,
+    /// which switches to the kernel address space and jumps into
+    /// KiSystemCall64.
+    pub fn system_call_entry(&self, vcpu: &dyn Vcpu) -> SystemCallEntry {
+        let address = vcpu.read_msr(x86::msr::IA32_LSTAR);
+        let kva_shadow = self
+            .section("KVASCODE")
+            .is_ok_and(|section| section.contains(&address));
+        SystemCallEntry {
+            address,
+            kva_shadow,
+        }
+    }
+
+    /// Finds KeServiceDescriptorTable, which is not exported, from the code of
+    /// KiSystemServiceRepeat in the .text section, and returns the system
+    /// service table it describes. Reads the whole .text section, so cache the
+    /// result.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ServiceTableNotFound` if the code is not found.
+    pub fn service_table(&self, vcpu: &dyn Vcpu) -> Result<ServiceTable, KernelError> {
+        const CHUNK_SIZE: usize = 0x1000;
+
+        let text = self.section(".text")?;
+        let mut buffer = [0u8; SERVICE_TABLE_PATTERN_LEN + CHUNK_SIZE];
+        for chunk in text.clone().step_by(CHUNK_SIZE) {
+            // Keep the end of the previous chunk to find the code across chunks.
+            buffer.copy_within(CHUNK_SIZE.., 0);
+            let len = (text.end - chunk).min(CHUNK_SIZE as u64) as usize;
+            let read_to = &mut buffer[SERVICE_TABLE_PATTERN_LEN..SERVICE_TABLE_PATTERN_LEN + len];
+            if guest_memory::read_guest(vcpu, chunk, read_to).is_err() {
+                buffer.fill(0);
+                continue;
+            }
+            let buffer_start = chunk - SERVICE_TABLE_PATTERN_LEN as u64;
+            let found = find_service_table_reference(&buffer[..SERVICE_TABLE_PATTERN_LEN + len]);
+            if let Some(rel32) = found {
+                let (offset, displacement) = rel32;
+                let descriptor = (buffer_start + offset as u64).wrapping_add_signed(displacement);
+                let mut entry = [0u8; 24];
+                guest_memory::read_guest(vcpu, descriptor, &mut entry)?;
+                return Ok(ServiceTable {
+                    descriptor,
+                    base: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+                    count: u64::from_le_bytes(entry[16..24].try_into().unwrap()) as u32,
+                });
+            }
+        }
+        Err(KernelError::ServiceTableNotFound)
+    }
+
+    fn read_u32(&self, vcpu: &dyn Vcpu, gva: u64) -> Result<u32, KernelError> {
+        let mut bytes = [0u8; 4];
+        guest_memory::read_guest(vcpu, gva, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+}
+
+impl ServiceTable {
+    /// Returns the address of the service routine of `index`, such as
+    /// NtCreateFile for its system call number.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidServiceIndex` if `index` is out of range.
+    pub fn routine(&self, vcpu: &dyn Vcpu, index: u32) -> Result<u64, KernelError> {
+        if index >= self.count {
+            return Err(KernelError::InvalidServiceIndex(index));
+        }
+        let mut entry = [0u8; 4];
+        guest_memory::read_guest(vcpu, self.base + u64::from(index) * 4, &mut entry)?;
+        Ok(service_routine(self.base, i32::from_le_bytes(entry)))
+    }
+}
+
+/// The length of `lea r10, [rip+X]; lea r11, [rip+Y]` in KiSystemServiceRepeat.
+const SERVICE_TABLE_PATTERN_LEN: usize = 14;
+
+/// Returns the offset of the end of `lea r10, [rip+X]` in `bytes` followed by
+/// `lea r11, [rip+Y]`, and X, which is relative to the offset. X points to
+/// KeServiceDescriptorTable and Y to KeServiceDescriptorTableShadow.
+fn find_service_table_reference(bytes: &[u8]) -> Option<(usize, i64)> {
+    bytes
+        .windows(SERVICE_TABLE_PATTERN_LEN)
+        .position(|window| {
+            window[0..3] == [0x4c, 0x8d, 0x15] && window[7..10] == [0x4c, 0x8d, 0x1d]
+        })
+        .map(|index| {
+            let displacement = i32::from_le_bytes(bytes[index + 3..index + 7].try_into().unwrap());
+            (index + 7, i64::from(displacement))
+        })
+}
+
+/// Returns the address of the routine an entry of KiServiceTable at `base`
+/// encodes: the offset from `base` in bits 31:4 and the number of arguments
+/// passed on the stack in bits 3:0.
+fn service_routine(base: u64, entry: i32) -> u64 {
+    base.wrapping_add_signed(i64::from(entry >> 4))
+}
+
+/// Hooks the routine at `address` with a marker (see `breakpoint_marker`):
+/// `INT3` is placed on an `ept_hook` shadow page, so that the guest executes it
+/// while reads of the routine, including those by PatchGuard, return the
+/// original bytes. Must be called from the guest, with `address` mapped in the
+/// current address space.
+///
+/// The original instruction at `address` is not executed. `handler` must make
+/// the guest resume elsewhere, for example, with [`return_from_routine`].
+///
+/// # Errors
+///
+/// Returns `Marker` if a marker is already registered at `address`, or `Hook`
+/// if the page cannot be hooked.
+pub fn hook_routine(
+    address: u64,
+    handler: impl Fn(&mut dyn Vcpu, u64) -> MarkerAction + Send + Sync + 'static,
+) -> Result<(), KernelError> {
+    breakpoint_marker::add_marker(address, handler).map_err(KernelError::Marker)?;
+    if let Err(e) = ept_hook::hook_manager().install(address as *const u8, &[0xcc]) {
+        let _ = breakpoint_marker::remove_marker(address);
+        return Err(KernelError::Hook(e));
+    }
+    Ok(())
+}
+
+/// Makes the guest return from the routine it has just entered with `value`
+/// in RAX, by emulating `RET`. Returns `MarkerAction::Resume` for a handler of
+/// `hook_routine` to return.
+///
+/// # Errors
+///
+/// Returns `Err` if the return address on the guest stack cannot be read.
+pub fn return_from_routine(vcpu: &mut dyn Vcpu, value: u64) -> Result<MarkerAction, KernelError> {
+    let mut return_address = [0u8; 8];
+    let rsp = vcpu.regs().rsp;
+    guest_memory::read_guest(vcpu, rsp, &mut return_address)?;
+    let regs = vcpu.regs();
+    regs.rip = u64::from_le_bytes(return_address);
+    regs.rsp += 8;
+    regs.rax = value;
+    Ok(MarkerAction::Resume)
+}
+
+/// The offsets of commonly used fields of EPROCESS on x64.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EprocessOffsets {
+    /// KPROCESS.DirectoryTableBase, the CR3 of the process.
+    pub directory_table_base: usize,
+    /// EPROCESS.UniqueProcessId.
+    pub unique_process_id: usize,
+    /// EPROCESS.ActiveProcessLinks.
+    pub active_process_links: usize,
+    /// EPROCESS.ImageFileName.
+    pub image_file_name: usize,
+}
+
+impl EprocessOffsets {
+    /// Returns the offsets for the Windows 10 or later `build`, such as
+    /// `GuestOs::Windows::build`, or `None` if the build is not known.
+    // See: https://www.vergiliusproject.com/kernels/x64
+    pub fn for_build(build: u32) -> Option<Self> {
+        let (unique_process_id, active_process_links, image_file_name) = match build {
+            // Windows 10 2004 to 22H2, Windows Server 2022, and Windows 11 21H2
+            // to 23H2.
+            19041..=19045 | 20348 | 22000 | 22621 | 22631 => (0x440, 0x448, 0x5a8),
+            // Windows 11 24H2 and Windows Server 2025.
+            26100 => (0x1d0, 0x1d8, 0x338),
+            _ => return None,
+        };
+        Some(Self {
+            directory_table_base: 0x28,
+            unique_process_id,
+            active_process_links,
+            image_file_name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pe_headers_are_parsed() {
+        let mut page = [0u8; HEADER_SIZE];
+        page[0..2].copy_from_slice(b"MZ");
+        page[0x3c..0x40].copy_from_slice(&0x100u32.to_le_bytes());
+        page[0x100..0x104].copy_from_slice(b"PE\0\0");
+        page[0x106..0x108].copy_from_slice(&2u16.to_le_bytes());
+        page[0x114..0x116].copy_from_slice(&0xf0u16.to_le_bytes());
+        page[0x118..0x11a].copy_from_slice(&0x20bu16.to_le_bytes());
+        page[0x118 + 56..0x118 + 60].copy_from_slice(&0x80_0000u32.to_le_bytes());
+        page[0x118 + 112..0x118 + 116].copy_from_slice(&0x1000u32.to_le_bytes());
+        page[0x118 + 116..0x118 + 120].copy_from_slice(&0x200u32.to_le_bytes());
+        let sections = [
+            (b".text\0\0\0", 0x2000u32, 0x6000_0020u32),
+            (b"INIT\0\0\0\0", 0x9000, 0x6200_0020),
+        ];
+        for (index, (name, rva, characteristics)) in sections.into_iter().enumerate() {
+            let header = 0x208 + index * 40;
+            page[header..header + 8].copy_from_slice(name);
+            page[header + 8..header + 12].copy_from_slice(&0x1000u32.to_le_bytes());
+            page[header + 12..header + 16].copy_from_slice(&rva.to_le_bytes());
+            page[header + 36..header + 40].copy_from_slice(&characteristics.to_le_bytes());
+        }
+
+        let image = KernelImage::parse(0xffff_f800_0000_0000, &page).unwrap();
+        assert_eq!(image.size, 0x80_0000);
+        assert_eq!(image.export_directory, 0x1000..0x1200);
+        assert_eq!(
+            image.section(".text").unwrap(),
+            0xffff_f800_0000_2000..0xffff_f800_0000_3000
+        );
+        assert_eq!(
+            image.section("INIT").unwrap(),
+            0xffff_f800_0000_9000..0xffff_f800_0000_a000
+        );
+        let code = image.code_sections().collect::<Vec<_>>();
+        assert_eq!(code.len(), 1);
+        assert_eq!(code[0], 0xffff_f800_0000_2000..0xffff_f800_0000_3000);
+        assert!(matches!(
+            image.section(".tex"),
+            Err(KernelError::SectionNotFound)
+        ));
+
+        page[0x118] = 0x0b;
+        page[0x119] = 0x01;
+        assert!(KernelImage::parse(0, &page).is_none());
+    }
+
+    #[test]
+    fn service_table_is_found() {
+        let code = [
+            0x90, 0x4c, 0x8d, 0x15, 0x10, 0x00, 0x00, 0x00, 0x4c, 0x8d, 0x1d, 0x20, 0x00, 0x00,
+            0x00, 0xf7,
+        ];
+        assert_eq!(find_service_table_reference(&code), Some((8, 0x10)));
+        assert_eq!(find_service_table_reference(&code[2..]), None);
+
+        assert_eq!(service_routine(0x1000, 0x0012_3450), 0x1000 + 0x1_2345);
+        assert_eq!(service_routine(0x1000, -0x100), 0x1000 - 0x10);
+    }
+
+    #[test]
+    fn eprocess_offsets_are_known_for_supported_builds() {
+        assert_eq!(
+            EprocessOffsets::for_build(22631).map(|offsets| offsets.image_file_name),
+            Some(0x5a8)
+        );
+        assert_eq!(EprocessOffsets::for_build(17763), None);
+    }
+}
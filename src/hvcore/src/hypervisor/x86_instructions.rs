@@ -1,25 +1,65 @@
 //! The module implements wrapper functions for x86 instructions.
+//!
+//! In tests, the wrappers of the privileged instructions operate on the mock
+//! processor of the current thread instead. See `test_support::MockCpu`.
 
 use core::arch::asm;
 
+#[cfg(feature = "intel")]
+use x86::bits64::rflags::RFlags;
 use x86::{
-    bits64::rflags::RFlags,
-    controlregs::{Cr0, Cr4, Xcr0},
+    controlregs::{Cr0, Cr4},
     dtables::DescriptorTablePointer,
     segmentation::SegmentSelector,
 };
 
+#[cfg(test)]
+use crate::hypervisor::test_support::with_cpu;
+
 /// Reads an MSR.
+#[cfg(not(test))]
 pub(crate) fn rdmsr(msr: u32) -> u64 {
     unsafe { x86::msr::rdmsr(msr) }
 }
 
 /// Writes a value to an MSR.
+#[cfg(not(test))]
 pub(crate) fn wrmsr(msr: u32, value: u64) {
     unsafe { x86::msr::wrmsr(msr, value) };
 }
 
+/// Reads `size` bytes from an I/O port.
+pub(crate) fn in_port(port: u16, size: u8) -> u32 {
+    unsafe {
+        match size {
+            1 => u32::from(x86::io::inb(port)),
+            2 => u32::from(x86::io::inw(port)),
+            4 => x86::io::inl(port),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Writes `size` bytes of a value to an I/O port.
+pub(crate) fn out_port(port: u16, size: u8, value: u32) {
+    unsafe {
+        match size {
+            1 => x86::io::outb(port, value as u8),
+            2 => x86::io::outw(port, value as u16),
+            4 => x86::io::outl(port, value),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Reads the time-stamp counter.
+#[cfg(not(test))]
+pub(crate) fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
 /// Reads the CR0.
+#[cfg(not(test))]
 pub(crate) fn cr0() -> Cr0 {
     let value: usize;
     unsafe { asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags)) };
@@ -27,6 +67,7 @@ pub(crate) fn cr0() -> Cr0 {
 }
 
 /// Writes a value to the CR0.
+#[cfg(not(test))]
 pub(crate) fn cr0_write(val: Cr0) {
     unsafe { x86::controlregs::cr0_write(val) };
 }
@@ -37,16 +78,19 @@ pub(crate) fn cr2() -> u64 {
 }
 
 /// Write a value to CR2.
+#[cfg(feature = "intel")]
 pub(crate) fn write_cr2(val: u64) {
     unsafe { x86::controlregs::cr2_write(val) };
 }
 
 /// Reads the CR3.
+#[cfg(not(test))]
 pub(crate) fn cr3() -> u64 {
     unsafe { x86::controlregs::cr3() }
 }
 
 /// Reads the CR4.
+#[cfg(not(test))]
 pub(crate) fn cr4() -> Cr4 {
     let value: usize;
     unsafe { asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags)) };
@@ -54,10 +98,39 @@ pub(crate) fn cr4() -> Cr4 {
 }
 
 /// Writes a value to the CR4.
+#[cfg(not(test))]
 pub(crate) fn cr4_write(val: Cr4) {
     unsafe { x86::controlregs::cr4_write(val) };
 }
 
+/// Reads DR0-DR3 or DR6.
+pub(crate) fn dr(index: u8) -> u64 {
+    unsafe {
+        match index {
+            0 => x86::debugregs::dr0() as _,
+            1 => x86::debugregs::dr1() as _,
+            2 => x86::debugregs::dr2() as _,
+            3 => x86::debugregs::dr3() as _,
+            6 => x86::debugregs::dr6().bits() as _,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Writes a value to DR0-DR3 or DR6.
+pub(crate) fn dr_write(index: u8, value: u64) {
+    unsafe {
+        match index {
+            0 => x86::debugregs::dr0_write(value as _),
+            1 => x86::debugregs::dr1_write(value as _),
+            2 => x86::debugregs::dr2_write(value as _),
+            3 => x86::debugregs::dr3_write(value as _),
+            6 => x86::debugregs::dr6_write(x86::debugregs::Dr6::from_bits_unchecked(value as _)),
+            _ => unreachable!(),
+        }
+    }
+}
+
 /// Write a value to the IDTR.
 pub(crate) fn lidt(idtr: &DescriptorTablePointer<u64>) {
     unsafe { x86::dtables::lidt(idtr) };
@@ -71,13 +144,21 @@ pub(crate) fn sidt() -> DescriptorTablePointer<u64> {
 }
 
 /// Reads the GDTR.
+#[cfg(not(test))]
 pub(crate) fn sgdt() -> DescriptorTablePointer<u64> {
     let mut gdtr = DescriptorTablePointer::<u64>::default();
     unsafe { x86::dtables::sgdt(&mut gdtr) };
     gdtr
 }
 
+/// Writes a value to the GDTR.
+#[cfg(not(test))]
+pub(crate) fn lgdt(gdtr: &DescriptorTablePointer<u64>) {
+    unsafe { x86::dtables::lgdt(gdtr) };
+}
+
 /// LSL-Load Segment Limit
+#[cfg(feature = "intel")]
 pub(crate) fn lsl(selector: SegmentSelector) -> u32 {
     let flags: u64;
     let mut limit: u64;
@@ -99,6 +180,7 @@ pub(crate) fn lsl(selector: SegmentSelector) -> u32 {
 }
 
 /// LAR-Load Access Rights Byte
+#[cfg(feature = "intel")]
 pub(crate) fn lar(selector: SegmentSelector) -> u32 {
     let flags: u64;
     let mut access_rights: u64;
@@ -119,18 +201,148 @@ pub(crate) fn lar(selector: SegmentSelector) -> u32 {
     }
 }
 
-/// Writes a value to XCR.
-pub(crate) fn xsetbv(xcr: u32, val: Xcr0) {
-    assert!(xcr == 0);
-    unsafe { x86::controlregs::xcr0_write(val) };
+/// Writes a value to XCR. Takes the raw value, as `Xcr0` lacks newer state
+/// components, such as AMX.
+pub(crate) fn xsetbv(xcr: u32, val: u64) {
+    unsafe {
+        asm!(
+            "xsetbv",
+            in("ecx") xcr,
+            in("eax") val as u32,
+            in("edx") (val >> 32) as u32,
+            options(nomem, nostack, preserves_flags)
+        );
+    };
 }
 
 /// Reads the TR.
+#[cfg(not(test))]
 pub(crate) fn tr() -> SegmentSelector {
     unsafe { x86::task::tr() }
 }
 
+/// Writes to the TR.
+#[cfg(not(test))]
+pub(crate) fn ltr(selector: SegmentSelector) {
+    unsafe { x86::task::load_tr(selector) };
+}
+
 /// Reads the LDTR.
+#[cfg(feature = "intel")]
 pub(crate) fn ldtr() -> SegmentSelector {
     unsafe { x86::dtables::ldtr() }
 }
+
+/// Writes to the LDTR.
+pub(crate) fn lldt(selector: SegmentSelector) {
+    unsafe { asm!("lldt {0:x}", in(reg) selector.bits(), options(nostack, nomem)) };
+}
+
+/// Sets RFLAGS.AC, allowing supervisor-mode access to user pages under SMAP.
+/// Causes #UD if SMAP is not supported.
+#[cfg(not(test))]
+pub(crate) fn stac() {
+    unsafe { asm!("stac", options(nostack, nomem)) };
+}
+
+/// Clears RFLAGS.AC. Causes #UD if SMAP is not supported.
+#[cfg(not(test))]
+pub(crate) fn clac() {
+    unsafe { asm!("clac", options(nostack, nomem)) };
+}
+
+/// Writes back all modified lines of the caches to memory and invalidates the
+/// caches.
+pub(crate) fn wbinvd() {
+    unsafe { asm!("wbinvd", options(nostack, preserves_flags)) };
+}
+
+/// Invalidates the caches without writing back modified lines, discarding
+/// writes to memory not yet written back, including those of the host.
+pub(crate) fn invd() {
+    unsafe { asm!("invd", options(nostack, preserves_flags)) };
+}
+
+// The privileged instructions cannot be executed in tests, which run in user
+// mode. The mock processor of the current thread stands in for the processor.
+
+#[cfg(test)]
+pub(crate) fn rdmsr(msr: u32) -> u64 {
+    with_cpu(|cpu| cpu.rdmsr(msr))
+}
+
+#[cfg(test)]
+pub(crate) fn wrmsr(msr: u32, value: u64) {
+    with_cpu(|cpu| cpu.wrmsr(msr, value));
+}
+
+#[cfg(test)]
+pub(crate) fn rdtsc() -> u64 {
+    with_cpu(|cpu| {
+        cpu.tsc += 1;
+        cpu.tsc
+    })
+}
+
+#[cfg(test)]
+pub(crate) fn cr0() -> Cr0 {
+    unsafe { Cr0::from_bits_unchecked(with_cpu(|cpu| cpu.cr0) as _) }
+}
+
+#[cfg(test)]
+pub(crate) fn cr0_write(val: Cr0) {
+    with_cpu(|cpu| cpu.cr0 = val.bits() as _);
+}
+
+#[cfg(test)]
+pub(crate) fn cr3() -> u64 {
+    with_cpu(|cpu| cpu.cr3)
+}
+
+#[cfg(test)]
+pub(crate) fn cr4() -> Cr4 {
+    unsafe { Cr4::from_bits_unchecked(with_cpu(|cpu| cpu.cr4) as _) }
+}
+
+#[cfg(test)]
+pub(crate) fn cr4_write(val: Cr4) {
+    with_cpu(|cpu| cpu.cr4 = val.bits() as _);
+}
+
+#[cfg(test)]
+pub(crate) fn sgdt() -> DescriptorTablePointer<u64> {
+    let (base, limit) = with_cpu(|cpu| (cpu.gdtr_base, cpu.gdtr_limit));
+    DescriptorTablePointer {
+        limit,
+        base: base as *const u64,
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn lgdt(gdtr: &DescriptorTablePointer<u64>) {
+    let (base, limit) = (gdtr.base as u64, gdtr.limit);
+    with_cpu(|cpu| {
+        cpu.gdtr_base = base;
+        cpu.gdtr_limit = limit;
+    });
+}
+
+#[cfg(test)]
+pub(crate) fn tr() -> SegmentSelector {
+    SegmentSelector::from_raw(with_cpu(|cpu| cpu.tr))
+}
+
+#[cfg(test)]
+pub(crate) fn ltr(selector: SegmentSelector) {
+    with_cpu(|cpu| cpu.tr = selector.bits());
+}
+
+#[cfg(test)]
+pub(crate) fn stac() {
+    with_cpu(|cpu| cpu.rflags_ac = true);
+}
+
+#[cfg(test)]
+pub(crate) fn clac() {
+    with_cpu(|cpu| cpu.rflags_ac = false);
+}
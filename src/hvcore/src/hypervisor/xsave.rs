@@ -0,0 +1,143 @@
+//! This module implements validation of the state components the guest enables
+//! in XCR0 with `XSETBV` and in IA32_XSS with `WRMSR`. The values are checked
+//! against what CPUID reports to the guest with `SharedHostData::cpuid_policy`,
+//! rather than what the processor supports, and invalid ones cause #GP as on
+//! bare metal. This keeps the guest from enabling state, such as the CET or
+//! AMX state, that CPUID says is unsupported.
+//!
+//! CPUID leaf 0xD reports only the components supported with the features
+//! reported in the other leaves. For example, hiding AVX-512 with the policy
+//! also hides the opmask and ZMM state.
+
+use x86::cpuid::CpuIdResult;
+
+use crate::hypervisor::{cet, cpuid_policy::CpuidPolicy, msr_intercepts::MsrIntercepts};
+
+/// The MSR of the supervisor state components.
+pub(crate) const IA32_XSS: u32 = 0xda0;
+
+// See: 13.1 XSAVE-Supported Features and State-Component Bitmaps
+const X87: u64 = 1 << 0;
+const SSE: u64 = 1 << 1;
+const AVX: u64 = 1 << 2;
+const MPX: u64 = 0b11 << 3;
+const AVX_512: u64 = 0b111 << 5;
+const PT: u64 = 1 << 8;
+const PKRU: u64 = 1 << 9;
+const CET: u64 = 0b11 << 11;
+const AMX: u64 = 0b11 << 17;
+
+/// Returns `intercepts` with IA32_XSS intercepted if `policy` hides any of the
+/// supervisor state components the processor supports, to validate writes to
+/// it. Called from the guest before any processor is virtualized.
+pub(crate) fn install(intercepts: MsrIntercepts, policy: &CpuidPolicy) -> MsrIntercepts {
+    let native = supported_xss(&CpuidPolicy::empty());
+    if native == supported_xss(policy) || intercepts.write_handler(IA32_XSS).is_some() {
+        return intercepts;
+    }
+    // The value is validated before the handler is called. See `is_valid_xss`.
+    intercepts.on_write(IA32_XSS, |_, _, value| Some(value))
+}
+
+/// Returns the user state components CPUID reports to the guest as supported
+/// in XCR0.
+pub(crate) fn supported_xcr0(policy: &CpuidPolicy) -> u64 {
+    let leaf_d = guest_cpuid(policy, 0xd, 0);
+    let leaf_1 = guest_cpuid(policy, 1, 0);
+    let leaf_7 = guest_cpuid(policy, 7, 0);
+    let mut supported = u64::from(leaf_d.edx) << 32 | u64::from(leaf_d.eax);
+
+    // See: Table 1-17. Information Returned by CPUID Instruction
+    if leaf_1.ecx & (1 << 28) == 0 {
+        supported &= !(AVX | AVX_512);
+    }
+    if leaf_7.ebx & (1 << 14) == 0 {
+        supported &= !MPX;
+    }
+    if leaf_7.ebx & (1 << 16) == 0 {
+        supported &= !AVX_512;
+    }
+    if leaf_7.ecx & (1 << 3) == 0 {
+        supported &= !PKRU;
+    }
+    if leaf_7.edx & (1 << 24) == 0 {
+        supported &= !AMX;
+    }
+    supported
+}
+
+/// Returns the supervisor state components CPUID reports to the guest as
+/// supported in IA32_XSS, or `None` if IA32_XSS is reported as unsupported.
+pub(crate) fn supported_xss(policy: &CpuidPolicy) -> Option<u64> {
+    let leaf_d = guest_cpuid(policy, 0xd, 1);
+    let leaf_7 = guest_cpuid(policy, 7, 0);
+    if leaf_d.eax & (1 << 3) == 0 {
+        return None;
+    }
+    let mut supported = u64::from(leaf_d.edx) << 32 | u64::from(leaf_d.ecx);
+
+    // CET_U and CET_S are for both shadow stacks and indirect branch tracking.
+    if leaf_7.ebx & (1 << 25) == 0 {
+        supported &= !PT;
+    }
+    if leaf_7.ecx & (1 << 7) == 0 && leaf_7.edx & (1 << 20) == 0 {
+        supported &= !CET;
+    }
+    Some(supported)
+}
+
+/// Returns whether `value` can be written to XCR0 with `XSETBV`, given the
+/// `supported` components.
+// See: XSETBV—Set Extended Control Register
+pub(crate) fn is_valid_xcr0(value: u64, supported: u64) -> bool {
+    let all_or_none = |bits: u64| value & bits == 0 || value & bits == bits;
+
+    value & !supported == 0
+        && value & X87 != 0
+        && (value & AVX == 0 || value & SSE != 0)
+        && (value & AVX_512 == 0 || value & AVX != 0)
+        && all_or_none(MPX)
+        && all_or_none(AVX_512)
+        && all_or_none(AMX)
+}
+
+/// Returns whether `value` can be written to IA32_XSS, given the `supported`
+/// components.
+pub(crate) fn is_valid_xss(value: u64, supported: Option<u64>) -> bool {
+    supported.is_some_and(|supported| value & !supported == 0)
+}
+
+/// Returns the result of `CPUID` the guest sees.
+fn guest_cpuid(policy: &CpuidPolicy, leaf: u32, sub_leaf: u32) -> CpuIdResult {
+    let mut result = x86::cpuid::cpuid!(leaf, sub_leaf);
+    policy.apply(leaf, sub_leaf, &mut result);
+    cet::apply_cpuid(leaf, sub_leaf, &mut result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xcr0_values_are_validated() {
+        let supported = X87 | SSE | AVX | AVX_512 | PKRU | AMX;
+        assert!(is_valid_xcr0(X87 | SSE | AVX, supported));
+        assert!(is_valid_xcr0(X87 | SSE | AVX | AVX_512 | AMX, supported));
+        assert!(!is_valid_xcr0(SSE | AVX, supported));
+        assert!(!is_valid_xcr0(X87 | AVX, supported));
+        assert!(!is_valid_xcr0(X87 | SSE | AVX_512, supported));
+        assert!(!is_valid_xcr0(X87 | SSE | AVX | (1 << 5), supported));
+        assert!(!is_valid_xcr0(X87 | SSE | (1 << 17), supported));
+        assert!(!is_valid_xcr0(X87 | SSE | MPX, supported));
+        assert!(!is_valid_xcr0(X87 | SSE | CET, supported));
+    }
+
+    #[test]
+    fn xss_values_are_validated() {
+        assert!(is_valid_xss(0, Some(0)));
+        assert!(is_valid_xss(CET, Some(PT | CET)));
+        assert!(!is_valid_xss(CET, Some(PT)));
+        assert!(!is_valid_xss(0, None));
+    }
+}
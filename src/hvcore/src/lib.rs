@@ -1,15 +1,76 @@
 #![no_std]
 
 extern crate alloc;
+#[cfg(test)]
+extern crate std;
 
 pub mod hypervisor;
 
+#[cfg(not(any(feature = "amd", feature = "intel")))]
+compile_error!("Enable the `amd` or `intel` feature, or both.");
+
 #[cfg(not(test))]
 pub use hypervisor::allocator;
+pub use hypervisor::apic_virt;
+pub use hypervisor::breakpoint_marker;
+pub use hypervisor::cache_control;
+pub use hypervisor::capabilities;
+pub use hypervisor::cpuid_policy;
+pub use hypervisor::cr3_tracking;
+pub use hypervisor::cr_intercepts;
+pub use hypervisor::deferred_work;
+pub use hypervisor::devirtualize_current_processor;
+pub use hypervisor::devirtualize_processor;
+pub use hypervisor::devirtualize_system;
+pub use hypervisor::dirty_tracking;
+pub use hypervisor::ept_hook;
+pub use hypervisor::event;
+pub use hypervisor::event_channel;
+pub use hypervisor::exit_handlers;
+pub use hypervisor::exit_stats;
+pub use hypervisor::foreign_hypervisor;
 pub use hypervisor::gdt_tss::GdtTss;
+pub use hypervisor::gdt_tss::GdtTssBuilder;
+pub use hypervisor::gdt_tss::IstStack;
+pub use hypervisor::guest_memory;
+pub use hypervisor::guest_os;
+pub use hypervisor::hw_breakpoint;
+pub use hypervisor::hypercall;
+pub use hypervisor::instruction_decoder;
+pub use hypervisor::integrity;
 pub use hypervisor::interrupt_handlers::InterruptDescriptorTable;
+pub use hypervisor::interrupt_virtualization;
+pub use hypervisor::io_intercepts;
+pub use hypervisor::ipi;
+pub use hypervisor::memory_protection;
+pub use hypervisor::mmio;
+pub use hypervisor::msr_intercepts;
 pub use hypervisor::paging_structures::PagingStructures;
 pub use hypervisor::panic::panic_impl;
 pub use hypervisor::platform_ops;
+pub use hypervisor::power;
+pub use hypervisor::preemption_timer;
+pub use hypervisor::revirtualize_system;
+pub use hypervisor::self_test;
+pub use hypervisor::serial_logger;
+pub use hypervisor::shut_down;
+pub use hypervisor::single_step;
+pub use hypervisor::smm;
+pub use hypervisor::snapshot;
+pub use hypervisor::syscall_protection;
+pub use hypervisor::tlb;
+pub use hypervisor::tsc;
+pub use hypervisor::virtualization_exception;
+pub use hypervisor::virtualize_processor;
 pub use hypervisor::virtualize_system;
+pub use hypervisor::watchdog;
+pub use hypervisor::windows_kernel;
+pub use hypervisor::GpaMapping;
+pub use hypervisor::GuestSegment;
+pub use hypervisor::HvError;
+pub use hypervisor::Registers;
+pub use hypervisor::SegmentRegister;
 pub use hypervisor::SharedHostData;
+pub use hypervisor::Vcpu;
+pub use hypervisor::VirtError;
+pub use hypervisor::VmExitReason;
@@ -0,0 +1,196 @@
+// SPDX-License-Identifier: MIT
+/*
+ * The entry points of lin_hv.ko and the kernel API wrappers the Rust code
+ * calls. See src/shim.rs for the declarations.
+ */
+
+#include <linux/cpu.h>
+#include <linux/cpuhotplug.h>
+#include <linux/cpumask.h>
+#include <linux/mm.h>
+#include <linux/module.h>
+#include <linux/smp.h>
+#include <linux/suspend.h>
+#include <linux/vmalloc.h>
+
+/* Implemented in Rust. See src/lib.rs. */
+int lin_hv_rs_init(void);
+void lin_hv_rs_exit(void);
+void lin_hv_rs_suspend(void);
+void lin_hv_rs_resume(void);
+int lin_hv_rs_cpu_online(void);
+void lin_hv_rs_cpu_offline(void);
+
+void *lin_hv_vmalloc(size_t size)
+{
+	return vmalloc(size);
+}
+
+void lin_hv_vfree(void *ptr)
+{
+	vfree(ptr);
+}
+
+u64 lin_hv_pa(const void *va)
+{
+	/* Module data, including the Rust code, and vmalloc memory are not in
+	 * the direct map. */
+	if (virt_addr_valid(va))
+		return __pa(va);
+	return page_to_phys(vmalloc_to_page(va)) + offset_in_page(va);
+}
+
+void *lin_hv_va(u64 pa)
+{
+	return __va(pa);
+}
+
+struct lin_hv_call {
+	void (*callback)(void *context);
+	void *context;
+};
+
+static void lin_hv_call_function(void *info)
+{
+	struct lin_hv_call *call = info;
+
+	call->callback(call->context);
+}
+
+void lin_hv_run_on_all_cpus(void (*callback)(void *), void *context)
+{
+	struct lin_hv_call call = { callback, context };
+	int cpu;
+
+	/* Run on one processor at a time, as the callbacks of Barevisor expect,
+	 * unlike on_each_cpu. Processors brought online later are covered by the
+	 * CPU hotplug callbacks. */
+	cpus_read_lock();
+	for_each_online_cpu(cpu)
+		WARN_ON(smp_call_function_single(cpu, lin_hv_call_function,
+						 &call, 1));
+	cpus_read_unlock();
+}
+
+void lin_hv_run_on_cpu(unsigned int index, void (*callback)(void *),
+		       void *context)
+{
+	struct lin_hv_call call = { callback, context };
+	unsigned int cpu;
+
+	cpus_read_lock();
+	cpu = cpumask_nth(index, cpu_online_mask);
+	BUG_ON(cpu >= nr_cpu_ids);
+	WARN_ON(smp_call_function_single(cpu, lin_hv_call_function, &call, 1));
+	cpus_read_unlock();
+}
+
+unsigned int lin_hv_current_cpu_index(void)
+{
+	/* The number of online processors numbered lower than this one. */
+	return bitmap_weight(cpumask_bits(cpu_online_mask),
+			     raw_smp_processor_id());
+}
+
+unsigned int lin_hv_offline_cpu_count(void)
+{
+	return num_possible_cpus() - num_online_cpus();
+}
+
+void lin_hv_print(const char *msg, size_t len)
+{
+	pr_info("%.*s", (int)len, msg);
+}
+
+/*
+ * Devirtualizes the system before suspend and hibernation, and virtualizes it
+ * again after resume, or after failing to enter the sleep state.
+ */
+static int lin_hv_pm_notify(struct notifier_block *nb, unsigned long action,
+			    void *data)
+{
+	switch (action) {
+	case PM_SUSPEND_PREPARE:
+	case PM_HIBERNATION_PREPARE:
+		lin_hv_rs_suspend();
+		break;
+	case PM_POST_SUSPEND:
+	case PM_POST_HIBERNATION:
+		lin_hv_rs_resume();
+		break;
+	}
+	return NOTIFY_DONE;
+}
+
+static struct notifier_block lin_hv_pm_nb = {
+	.notifier_call = lin_hv_pm_notify,
+};
+
+/*
+ * Virtualizes processors brought online after loading, and devirtualizes
+ * processors going offline. Both run on the processor, in the hotplug thread
+ * bound to it.
+ */
+static int lin_hv_cpu_online(unsigned int cpu)
+{
+	unsigned long flags;
+	int ret;
+
+	local_irq_save(flags);
+	ret = lin_hv_rs_cpu_online();
+	local_irq_restore(flags);
+	return ret;
+}
+
+static int lin_hv_cpu_offline(unsigned int cpu)
+{
+	unsigned long flags;
+
+	local_irq_save(flags);
+	lin_hv_rs_cpu_offline();
+	local_irq_restore(flags);
+	return 0;
+}
+
+static enum cpuhp_state lin_hv_cpuhp_state;
+
+static int __init lin_hv_init(void)
+{
+	int ret;
+
+	ret = lin_hv_rs_init();
+	if (ret)
+		return ret;
+
+	ret = register_pm_notifier(&lin_hv_pm_nb);
+	if (ret)
+		goto exit;
+
+	/* The processors online now are already virtualized. */
+	ret = cpuhp_setup_state_nocalls(CPUHP_AP_ONLINE_DYN, "lin_hv:online",
+					lin_hv_cpu_online, lin_hv_cpu_offline);
+	if (ret < 0)
+		goto unregister;
+	lin_hv_cpuhp_state = ret;
+	return 0;
+
+unregister:
+	unregister_pm_notifier(&lin_hv_pm_nb);
+exit:
+	lin_hv_rs_exit();
+	return ret;
+}
+
+static void __exit lin_hv_exit(void)
+{
+	cpuhp_remove_state_nocalls(lin_hv_cpuhp_state);
+	unregister_pm_notifier(&lin_hv_pm_nb);
+	lin_hv_rs_exit();
+}
+
+module_init(lin_hv_init);
+module_exit(lin_hv_exit);
+
+MODULE_DESCRIPTION("Barevisor as a Linux kernel module");
+MODULE_AUTHOR("Satoshi Tanda <tanda.sat@gmail.com>");
+MODULE_LICENSE("Dual MIT/GPL");
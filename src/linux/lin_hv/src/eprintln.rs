@@ -0,0 +1,56 @@
+use core::fmt::Write;
+
+use spin::Mutex;
+
+use crate::shim;
+
+/// Prints a message to the kernel log with a newline.
+#[macro_export]
+macro_rules! eprintln {
+    () => {
+        ($crate::print!("\n"));
+    };
+
+    ($($arg:tt)*) => {
+        ($crate::print!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Prints a message to the kernel log without a newline.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        ($crate::eprintln::print_fmt(format_args!($($arg)*)))
+    };
+}
+
+#[doc(hidden)]
+pub(crate) fn print_fmt(args: core::fmt::Arguments<'_>) {
+    // Format the whole message first, as each write to the kernel log starts a
+    // new record.
+    let mut printer = KERNEL_PRINTER.lock();
+    printer.length = 0;
+    let _ = Write::write_fmt(&mut *printer, args);
+    unsafe { shim::lin_hv_print(printer.buffer.as_ptr().cast(), printer.length) };
+}
+
+static KERNEL_PRINTER: Mutex<KernelOutput> = Mutex::new(KernelOutput {
+    buffer: [0; 256],
+    length: 0,
+});
+
+/// The buffer of a message. Avoid heap allocation so the eprint(ln) macros are
+/// usable before initializing the allocator. Longer messages are truncated.
+struct KernelOutput {
+    buffer: [u8; 256],
+    length: usize,
+}
+
+impl Write for KernelOutput {
+    fn write_str(&mut self, msg: &str) -> core::fmt::Result {
+        let length = core::cmp::min(self.buffer.len() - self.length, msg.len());
+        self.buffer[self.length..self.length + length].copy_from_slice(&msg.as_bytes()[..length]);
+        self.length += length;
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+#![doc = include_str!("../../README.md")]
+#![no_std]
+
+extern crate alloc;
+
+mod eprintln;
+mod ops;
+mod shim;
+
+use core::{
+    ffi::{c_int, c_void},
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use alloc::boxed::Box;
+
+/// The buffer given to the global allocator. Freed on unload.
+static ALLOCATOR_BUFFER: AtomicPtr<c_void> = AtomicPtr::new(core::ptr::null_mut());
+
+/// The buffers allocated to extend the heap with `PlatformOps::allocate_heap`.
+/// Freed on unload.
+pub(crate) static HEAP_EXTENSIONS: [AtomicPtr<c_void>; hv::allocator::MAX_EXTENSIONS] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; hv::allocator::MAX_EXTENSIONS];
+
+/// The error codes returned to the kernel.
+const ENOMEM: c_int = 12;
+const EOPNOTSUPP: c_int = 95;
+
+/// Called from `module_init` of the module.
+#[unsafe(no_mangle)]
+extern "C" fn lin_hv_rs_init() -> c_int {
+    eprintln!("Loading lin_hv.ko");
+
+    // Initialize the global allocator with pre-allocated buffer.
+    let ptr = unsafe { shim::lin_hv_vmalloc(hv::allocator::ALLOCATION_BYTES) };
+    if ptr.is_null() {
+        eprintln!("Memory allocation failed");
+        return -ENOMEM;
+    }
+    hv::allocator::init(ptr.cast::<u8>());
+    ALLOCATOR_BUFFER.store(ptr, Ordering::Relaxed);
+
+    // Register the platform specific API.
+    hv::platform_ops::init(Box::new(ops::LinuxOps));
+
+    // Virtualize the system. No host IDT, GDT, TSS and page tables are given,
+    // meaning that they are all that of the kernel, like the Windows version.
+    let shared_host = hv::SharedHostData {
+        serial_log: Some(hv::serial_logger::SerialConfig::default()),
+        hotplug_slots: unsafe { shim::lin_hv_offline_cpu_count() } as usize,
+        ..Default::default()
+    };
+    if let Err(e) = hv::virtualize_system(shared_host) {
+        eprintln!("virtualize_system failed: {e}");
+        hv::shut_down();
+        free_heap();
+        return match e {
+            hv::HvError::OutOfMemory => -ENOMEM,
+            _ => -EOPNOTSUPP,
+        };
+    }
+
+    eprintln!("Loaded lin_hv.ko");
+    0
+}
+
+/// Called from `module_exit` of the module.
+#[unsafe(no_mangle)]
+extern "C" fn lin_hv_rs_exit() {
+    eprintln!("Unloading lin_hv.ko");
+
+    // Devirtualize the system for good, then free the memory the hypervisor
+    // used. No code uses the global allocator after this.
+    hv::shut_down();
+    free_heap();
+
+    eprintln!("Unloaded lin_hv.ko");
+}
+
+/// Called from the PM notifier of the module before suspend and hibernation.
+#[unsafe(no_mangle)]
+extern "C" fn lin_hv_rs_suspend() {
+    eprintln!("Devirtualizing the system before sleep");
+    hv::devirtualize_system();
+}
+
+/// Called from the PM notifier of the module after resume.
+#[unsafe(no_mangle)]
+extern "C" fn lin_hv_rs_resume() {
+    if let Err(e) = hv::revirtualize_system() {
+        eprintln!("revirtualize_system failed: {e}");
+    }
+}
+
+/// Called from the CPU hotplug callback of the module on the processor brought
+/// online, with interrupts disabled. Failing keeps the processor offline.
+#[unsafe(no_mangle)]
+extern "C" fn lin_hv_rs_cpu_online() -> c_int {
+    match hv::virtualize_processor() {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("virtualize_processor failed: {e}");
+            match e {
+                hv::HvError::OutOfMemory => -ENOMEM,
+                _ => -EOPNOTSUPP,
+            }
+        }
+    }
+}
+
+/// Called from the CPU hotplug callback of the module on the processor going
+/// offline, with interrupts disabled.
+#[unsafe(no_mangle)]
+extern "C" fn lin_hv_rs_cpu_offline() {
+    let _ = hv::devirtualize_current_processor();
+}
+
+/// Frees the memory given to the global allocator.
+fn free_heap() {
+    for ptr in core::iter::once(&ALLOCATOR_BUFFER).chain(&HEAP_EXTENSIONS) {
+        let ptr = ptr.swap(core::ptr::null_mut(), Ordering::Relaxed);
+        if !ptr.is_null() {
+            unsafe { shim::lin_hv_vfree(ptr) };
+        }
+    }
+}
+
+#[panic_handler]
+fn panic_handler(info: &core::panic::PanicInfo<'_>) -> ! {
+    hv::panic_impl(info)
+}
@@ -0,0 +1,55 @@
+//! This module implements Linux kernel module-based implementation of
+//! [`hv::PlatformOps`].
+
+use core::{ffi::c_void, sync::atomic::Ordering};
+
+use hv::platform_ops::PlatformOps;
+
+use crate::{shim, HEAP_EXTENSIONS};
+
+pub(crate) struct LinuxOps;
+
+impl PlatformOps for LinuxOps {
+    fn run_on_all_processors(&self, callback: fn()) {
+        // The callback runs with interrupts disabled in the context of the IPI
+        // function call, or of the caller on its own processor.
+        unsafe { shim::lin_hv_run_on_all_cpus(run_callback, callback as *mut _) };
+    }
+
+    fn run_on_processor(&self, index: usize, callback: fn()) {
+        let index = u32::try_from(index).unwrap();
+        unsafe { shim::lin_hv_run_on_cpu(index, run_callback, callback as *mut _) };
+    }
+
+    fn current_processor_index(&self) -> usize {
+        unsafe { shim::lin_hv_current_cpu_index() as usize }
+    }
+
+    fn pa(&self, va: *const c_void) -> u64 {
+        unsafe { shim::lin_hv_pa(va) }
+    }
+
+    fn va(&self, pa: u64) -> *mut c_void {
+        unsafe { shim::lin_hv_va(pa) }
+    }
+
+    fn allocate_heap(&self, size: usize) -> *mut u8 {
+        // The heap can be extended only as many times as there are slots.
+        let Some(slot) = HEAP_EXTENSIONS
+            .iter()
+            .find(|slot| slot.load(Ordering::Relaxed).is_null())
+        else {
+            return core::ptr::null_mut();
+        };
+
+        // vmalloc returns page aligned memory that is never paged out.
+        let ptr = unsafe { shim::lin_hv_vmalloc(size) };
+        slot.store(ptr, Ordering::Relaxed);
+        ptr.cast()
+    }
+}
+
+extern "C" fn run_callback(context: *mut c_void) {
+    let callback: fn() = unsafe { core::mem::transmute(context) };
+    callback();
+}
@@ -0,0 +1,45 @@
+//! This module declares the kernel API wrappers implemented in C in
+//! `module/lin_hv_main.c`. The kernel API is mostly macros and inline functions
+//! with no stable ABI, so the module exposes them as plain functions instead.
+
+use core::ffi::{c_char, c_uint, c_void};
+
+unsafe extern "C" {
+    /// Returns `size` bytes of page-aligned memory from `vmalloc`, or null.
+    pub(crate) fn lin_hv_vmalloc(size: usize) -> *mut c_void;
+
+    /// Frees the memory returned by `lin_hv_vmalloc`.
+    pub(crate) fn lin_hv_vfree(ptr: *mut c_void);
+
+    /// Returns the physical address of `va`, either in the direct map, or in
+    /// the vmalloc or module area.
+    pub(crate) fn lin_hv_pa(va: *const c_void) -> u64;
+
+    /// Returns the address of `pa` in the direct map.
+    pub(crate) fn lin_hv_va(pa: u64) -> *mut c_void;
+
+    /// Runs `callback` on each online processor one by one, in the order of
+    /// the processor numbers.
+    pub(crate) fn lin_hv_run_on_all_cpus(
+        callback: extern "C" fn(*mut c_void),
+        context: *mut c_void,
+    );
+
+    /// Runs `callback` on the online processor `index`, counted in the same
+    /// order as `lin_hv_run_on_all_cpus`.
+    pub(crate) fn lin_hv_run_on_cpu(
+        index: c_uint,
+        callback: extern "C" fn(*mut c_void),
+        context: *mut c_void,
+    );
+
+    /// Returns the index of the current processor, counted in the same order
+    /// as `lin_hv_run_on_all_cpus`.
+    pub(crate) fn lin_hv_current_cpu_index() -> c_uint;
+
+    /// Returns the number of possible processors that are not online.
+    pub(crate) fn lin_hv_offline_cpu_count() -> c_uint;
+
+    /// Writes `len` bytes of `msg` into the kernel log.
+    pub(crate) fn lin_hv_print(msg: *const c_char, len: usize);
+}
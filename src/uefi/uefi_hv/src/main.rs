@@ -7,12 +7,15 @@ extern crate alloc;
 mod ops;
 mod println;
 
+use core::{ffi::c_void, ops::Range, ptr::NonNull};
+
 use alloc::{boxed::Box, vec::Vec};
 use hv::{GdtTss, PagingStructures};
 use uefi::{
     prelude::*,
     proto::{loaded_image::LoadedImage, pi::mp::MpServices},
-    table::boot::{AllocateType, MemoryType},
+    table::boot::{AllocateType, EventType, MemoryType, Tpl},
+    Event,
 };
 use x86::bits64::task::TaskStateSegment;
 
@@ -60,12 +63,35 @@ fn main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
     // version, the current IDT, GDT, TSS and paging structures are destroyed as
     // the system transition to the runtime-phase. Thus, the host cannot depend
     // on them and needs its own data structures.
-    match create_shared_host_data(&system_table) {
-        Ok(shared_host) => hv::virtualize_system(shared_host),
+    let shared_host = match create_shared_host_data(&system_table) {
+        Ok(shared_host) => shared_host,
         Err(e) => {
             println!("create_shared_host_data failed: {e}");
             return e.status();
         }
+    };
+    if let Err(e) = hv::virtualize_system(shared_host) {
+        println!("virtualize_system failed: {e}");
+        return match e {
+            hv::HvError::OutOfMemory => Status::OUT_OF_RESOURCES,
+            _ => Status::UNSUPPORTED,
+        };
+    }
+
+    // Report the result of the self-test to the end-to-end test runner. This
+    // does not return as QEMU exits.
+    if cfg!(feature = "e2e") {
+        let report = hv::self_test::run();
+        println!("{report}");
+        let status = hv::self_test::exit_test_run(&report);
+        println!("Could not exit QEMU: {status:?}");
+        return Status::ABORTED;
+    }
+
+    // Keep running under the booted OS. See the function comment.
+    if let Err(e) = register_exit_boot_services(&system_table) {
+        println!("register_exit_boot_services failed: {e}");
+        return e.status();
     }
 
     println!("Loaded uefi_hv.efi");
@@ -73,10 +99,14 @@ fn main(image_handle: Handle, system_table: SystemTable<Boot>) -> Status {
 }
 
 /// Creates `hv::SharedHostData`.
-// - GDT and TSS are clones of the current.
+// - GDT and TSS are copies of the current, with the stacks for NMIs, #DF and #MC.
 // - IDT is as implemented in `hv::InterruptDescriptorTable`.
-// - Paging structures are identity mapped and all RWX.
+// - Paging structures are identity mapped and all RWX. With the
+//   `minimal_host_pt` feature, they map only this image and the heap.
 fn create_shared_host_data(system_table: &SystemTable<Boot>) -> uefi::Result<hv::SharedHostData> {
+    // The I/O port of the `isa-debug-exit` device `cargo xtask qemu-*` adds.
+    const QEMU_EXIT_PORT: u16 = 0xf4;
+
     /// Gets the number of usable logical processors on this system.
     fn processor_count(system_table: &SystemTable<Boot>) -> uefi::Result<u32> {
         let bs = system_table.boot_services();
@@ -85,23 +115,30 @@ fn create_shared_host_data(system_table: &SystemTable<Boot>) -> uefi::Result<hv:
         Ok(u32::try_from(mp_services.get_number_of_processors().unwrap().enabled).unwrap())
     }
 
-    // Each logical processor needs to have its own GDT, so clone the current
-    // GDT and TSS for each processor.
-    let gdt_tss = GdtTss::new_from_current();
+    // Each logical processor needs to have its own GDT, so copy the current
+    // GDT and TSS for each processor, with the stacks for the host exceptions.
     let mut host_gdt_tss = Vec::<GdtTss>::new();
     for _ in 0..processor_count(system_table)? {
-        host_gdt_tss.push(gdt_tss.clone());
+        host_gdt_tss.push(GdtTss::builder().host_exception_stacks().build());
     }
 
     let host_idt = hv::InterruptDescriptorTable::new(host_gdt_tss[0].cs);
 
     let mut host_pt = PagingStructures::new();
-    host_pt.build_identity();
+    if cfg!(feature = "minimal_host_pt") {
+        host_pt.build_minimal(image_range(system_table)?)
+    } else {
+        host_pt.build_identity()
+    }
+    .map_err(|_| uefi::Error::from(Status::OUT_OF_RESOURCES))?;
 
     Ok(hv::SharedHostData {
         pt: Some(host_pt),
         idt: Some(host_idt),
         gdts: Some(host_gdt_tss),
+        serial_log: Some(hv::serial_logger::SerialConfig::default()),
+        test_exit_port: cfg!(feature = "e2e").then_some(QEMU_EXIT_PORT),
+        ..Default::default()
     })
 }
 
@@ -121,13 +158,10 @@ fn zap_relocation_table(system_table: &SystemTable<Boot>) -> uefi::Result<()> {
     const NT_RELOCATION_DIRECTORY_RVA: u64 = 0x128;
     const NT_RELOCATION_DIRECTORY_SIZE: u64 = 0x12c;
 
-    let bs = system_table.boot_services();
-    let loaded_image = bs.open_protocol_exclusive::<LoadedImage>(bs.image_handle())?;
-    let (image_base, image_size) = loaded_image.info();
-    let image_base = image_base as u64;
-    let image_range = image_base..image_base + image_size;
+    let image_range = image_range(system_table)?;
     println!("Image base: {image_range:#x?}");
 
+    let image_base = image_range.start;
     unsafe {
         *((image_base + NT_RELOCATION_DIRECTORY_RVA) as *mut u32) = 0;
         *((image_base + NT_RELOCATION_DIRECTORY_SIZE) as *mut u32) = 0;
@@ -135,6 +169,44 @@ fn zap_relocation_table(system_table: &SystemTable<Boot>) -> uefi::Result<()> {
     Ok(())
 }
 
+/// Gets the address range of this image.
+fn image_range(system_table: &SystemTable<Boot>) -> uefi::Result<Range<u64>> {
+    let bs = system_table.boot_services();
+    let loaded_image = bs.open_protocol_exclusive::<LoadedImage>(bs.image_handle())?;
+    let (image_base, image_size) = loaded_image.info();
+    let image_base = image_base as u64;
+    Ok(image_base..image_base + image_size)
+}
+
+/// Registers the callback retiring the use of boot services when the OS loader
+/// calls `ExitBootServices`.
+// All memory of the hypervisor, namely this image, the heap and the host
+// paging structures, is of the runtime services types, thus, is kept reserved
+// by the booted OS. What does not survive is boot services, which `UefiOps` and
+// `println!` use. `SetVirtualAddressMap`, called after `ExitBootServices`, only
+// changes the guest's address space: the host keeps using its own identity
+// mapped paging structures, and `zap_relocation_table` prevents patching of
+// this image, so no pointer needs to be converted.
+fn register_exit_boot_services(system_table: &SystemTable<Boot>) -> uefi::Result<()> {
+    // The event is never closed, as this image is never unloaded.
+    let _ = unsafe {
+        system_table.boot_services().create_event(
+            EventType::SIGNAL_EXIT_BOOT_SERVICES,
+            Tpl::NOTIFY,
+            Some(exit_boot_services),
+            None,
+        )
+    }?;
+    Ok(())
+}
+
+/// Called by the firmware in the guest on `ExitBootServices`.
+// The notification function must not use memory allocation services.
+unsafe extern "efiapi" fn exit_boot_services(_event: Event, _context: Option<NonNull<c_void>>) {
+    println::retire();
+    hv::platform_ops::retire();
+}
+
 #[panic_handler]
 fn panic_handler(info: &core::panic::PanicInfo<'_>) -> ! {
     hv::panic_impl(info)
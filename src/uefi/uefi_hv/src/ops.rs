@@ -1,7 +1,11 @@
 use core::ffi::c_void;
 
 use hv::platform_ops::PlatformOps;
-use uefi::{prelude::*, proto::pi::mp::MpServices};
+use uefi::{
+    prelude::*,
+    proto::pi::mp::MpServices,
+    table::boot::{AllocateType, MemoryType},
+};
 
 pub(crate) struct UefiOps {
     system_table: SystemTable<Boot>,
@@ -32,9 +36,46 @@ impl PlatformOps for UefiOps {
         }
     }
 
+    fn run_on_processor(&self, index: usize, callback: fn()) {
+        let bs = self.system_table.boot_services();
+        let handle = bs.get_handle_for_protocol::<MpServices>().unwrap();
+        let mp_services = bs.open_protocol_exclusive::<MpServices>(handle).unwrap();
+
+        // The API cannot run the procedure on the caller, which is the BSP.
+        if index == mp_services.who_am_i().unwrap() {
+            callback();
+            return;
+        }
+        mp_services
+            .startup_this_ap(index, run_callback, callback as *mut _, None, None)
+            .unwrap();
+    }
+
+    fn current_processor_index(&self) -> usize {
+        let bs = self.system_table.boot_services();
+        let handle = bs.get_handle_for_protocol::<MpServices>().unwrap();
+        let mp_services = bs.open_protocol_exclusive::<MpServices>(handle).unwrap();
+        mp_services.who_am_i().unwrap()
+    }
+
     fn pa(&self, va: *const c_void) -> u64 {
         va as _
     }
+
+    fn va(&self, pa: u64) -> *mut c_void {
+        pa as _
+    }
+
+    fn allocate_heap(&self, size: usize) -> *mut u8 {
+        self.system_table
+            .boot_services()
+            .allocate_pages(
+                AllocateType::AnyPages,
+                MemoryType::RUNTIME_SERVICES_DATA,
+                size.div_ceil(0x1000),
+            )
+            .unwrap_or(0) as *mut u8
+    }
 }
 
 extern "efiapi" fn run_callback(context: *mut c_void) {
@@ -10,11 +10,16 @@ pub(crate) fn init(system_table: &SystemTable<Boot>) {
     SYSTEM_TABLE.store(system_table.as_ptr().cast_mut(), Ordering::Release);
 }
 
+/// Disables the macros as the console is unavailable after `ExitBootServices`.
+pub(crate) fn retire() {
+    SYSTEM_TABLE.store(ptr::null_mut(), Ordering::Release);
+}
+
 static SYSTEM_TABLE: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
 
-fn system_table() -> SystemTable<Boot> {
+fn system_table() -> Option<SystemTable<Boot>> {
     let ptr = SYSTEM_TABLE.load(Ordering::Acquire);
-    unsafe { SystemTable::from_ptr(ptr) }.unwrap()
+    unsafe { SystemTable::from_ptr(ptr) }
 }
 
 /// Debug prints a message to stdout with a newline.
@@ -39,5 +44,7 @@ macro_rules! print {
 
 #[doc(hidden)]
 pub(crate) fn _print(args: core::fmt::Arguments<'_>) {
-    core::fmt::Write::write_fmt(&mut system_table().stdout(), args).unwrap();
+    if let Some(mut system_table) = system_table() {
+        core::fmt::Write::write_fmt(&mut system_table.stdout(), args).unwrap();
+    }
 }
@@ -33,7 +33,12 @@ pub(crate) enum Profile {
     Release,
 }
 
-pub(crate) fn cargo_run(action: Action, package: Package, profile: Profile) -> Result<()> {
+pub(crate) fn cargo_run(
+    action: Action,
+    package: Package,
+    profile: Profile,
+    features: &[&str],
+) -> Result<()> {
     let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
     let mut command = Command::new(cargo);
     let _ = command.arg(match action {
@@ -44,6 +49,9 @@ pub(crate) fn cargo_run(action: Action, package: Package, profile: Profile) -> R
         let _ = command.args(["--target", "x86_64-unknown-uefi"]);
     }
     let _ = command.args(["--package", package.name()]);
+    if !features.is_empty() {
+        let _ = command.args(["--features", &features.join(",")]);
+    }
     let release = profile == Profile::Release;
     if release {
         let _ = command.arg("--release");
@@ -14,6 +14,7 @@ use cargo::{cargo_run, Action, Package, Profile};
 use clap::{Parser, Subcommand};
 use vmtest::{
     bochs::{Bochs, Cpu},
+    qemu::Qemu,
     vmware::Vmware,
 };
 
@@ -43,16 +44,24 @@ enum Commands {
     BochsAmd,
     /// Start a VMware VM
     Vmware,
+    /// Run the end-to-end tests on QEMU with an Intel processor (KVM with
+    /// nested VMX required)
+    QemuIntel,
+    /// Run the end-to-end tests on QEMU with an AMD processor (KVM with
+    /// nested SVM, or TCG)
+    QemuAmd,
 }
 
 fn main() {
     let cli = Cli::parse();
     let result = match &cli.command {
-        Commands::Build => build(cli.release),
+        Commands::Build => build(cli.release, &[]),
         Commands::Clippy => clippy(),
         Commands::BochsIntel => vmtest::run(&Bochs { cpu: Cpu::Intel }, cli.release),
         Commands::BochsAmd => vmtest::run(&Bochs { cpu: Cpu::Amd }, cli.release),
         Commands::Vmware => vmtest::run(&Vmware {}, cli.release),
+        Commands::QemuIntel => vmtest::run(&Qemu { cpu: Cpu::Intel }, cli.release),
+        Commands::QemuAmd => vmtest::run(&Qemu { cpu: Cpu::Amd }, cli.release),
     };
     if let Err(e) = result {
         eprintln!("{e}");
@@ -60,20 +69,27 @@ fn main() {
     }
 }
 
-fn build(release: bool) -> Result<()> {
+/// Builds the hypervisor with `features` of `uefi_hv`, and the checker.
+fn build(release: bool, features: &[&str]) -> Result<()> {
     let profile = if release {
         Profile::Release
     } else {
         Profile::Debug
     };
-    cargo_run(Action::Build, Package::Hypervisor, profile)?;
-    cargo_run(Action::Build, Package::CheckHvVendor, profile)
+    cargo_run(Action::Build, Package::Hypervisor, profile, features)?;
+    cargo_run(Action::Build, Package::CheckHvVendor, profile, &[])
 }
 
 fn clippy() -> Result<()> {
-    cargo_run(Action::Clippy, Package::Hypervisor, Profile::Debug)?;
-    cargo_run(Action::Clippy, Package::CheckHvVendor, Profile::Debug)?;
-    cargo_run(Action::Clippy, Package::Xtask, Profile::Debug)
+    cargo_run(Action::Clippy, Package::Hypervisor, Profile::Debug, &[])?;
+    cargo_run(
+        Action::Clippy,
+        Package::Hypervisor,
+        Profile::Debug,
+        &["e2e"],
+    )?;
+    cargo_run(Action::Clippy, Package::CheckHvVendor, Profile::Debug, &[])?;
+    cargo_run(Action::Clippy, Package::Xtask, Profile::Debug, &[])
 }
 
 fn output_dir(release: bool) -> PathBuf {
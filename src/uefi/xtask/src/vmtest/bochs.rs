@@ -10,7 +10,7 @@ use std::{
 
 use anyhow::Result;
 
-use super::{copy_artifacts_to, TestVm, UnixCommand};
+use super::{copy_artifacts_to, extract_samples, TestVm, UnixCommand};
 
 pub(crate) struct Bochs {
     pub(crate) cpu: Cpu,
@@ -18,6 +18,7 @@ pub(crate) struct Bochs {
 
 impl TestVm for Bochs {
     fn deploy(&self, release: bool) -> Result<()> {
+        extract_samples()?;
         copy_artifacts_to("./tests/samples/bochs_disk.img", release)
     }
 
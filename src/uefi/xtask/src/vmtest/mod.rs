@@ -5,16 +5,20 @@ use anyhow::{ensure, Result};
 use crate::{build, cargo::Package, output_dir, project_root_dir};
 
 pub(crate) mod bochs;
+pub(crate) mod qemu;
 pub(crate) mod vmware;
 
 pub(crate) trait TestVm {
+    /// The features of `uefi_hv` to build the hypervisor with for the VM.
+    fn features(&self) -> &[&str] {
+        &[]
+    }
     fn deploy(&self, release: bool) -> Result<()>;
     fn run(&self) -> Result<()>;
 }
 
 pub(crate) fn run<T: TestVm>(vm: &T, release: bool) -> Result<()> {
-    build(release)?;
-    extract_samples()?;
+    build(release, vm.features())?;
     vm.deploy(release)?;
     vm.run()
 }
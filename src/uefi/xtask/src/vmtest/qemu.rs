@@ -0,0 +1,110 @@
+use std::{
+    env, fs,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+
+use super::{bochs::Cpu, TestVm};
+use crate::{cargo::Package, output_dir, project_root_dir};
+
+/// A QEMU VM running the end-to-end tests. The hypervisor built with the `e2e`
+/// feature runs `hv::self_test` after virtualizing the system, and makes QEMU
+/// exit with the result through the `isa-debug-exit` device, so the exit
+/// status of this command tells the result, for example, in CI.
+pub(crate) struct Qemu {
+    pub(crate) cpu: Cpu,
+}
+
+impl TestVm for Qemu {
+    fn features(&self) -> &[&str] {
+        &["e2e"]
+    }
+
+    fn deploy(&self, release: bool) -> Result<()> {
+        // QEMU exposes the directory as a FAT drive, so no disk image is needed.
+        let esp = esp_dir();
+        fs::create_dir_all(&esp)?;
+        for package in [Package::Hypervisor, Package::CheckHvVendor] {
+            let file_name = package.name().to_owned() + ".efi";
+            let _ = fs::copy(output_dir(release).join(&file_name), esp.join(&file_name))?;
+        }
+
+        // Shut down if loading the hypervisor fails, instead of waiting for the
+        // timeout at the shell.
+        let script = fs::read_to_string(project_root_dir().join("tests/startup.nsh"))?;
+        fs::write(esp.join("startup.nsh"), script + "\nreset -s\n")?;
+        Ok(())
+    }
+
+    fn run(&self) -> Result<()> {
+        const TIMEOUT: Duration = Duration::from_secs(300);
+        // `isa-debug-exit` makes QEMU exit with `(code << 1) | 1`, and the
+        // hypervisor writes 0 if the tests passed.
+        const PASSED_STATUS: i32 = 1;
+
+        // Nested VMX requires KVM on an Intel processor, while TCG emulates SVM
+        // on any processor.
+        let (accel, cpu) = match self.cpu {
+            Cpu::Intel => ("kvm", "host,+vmx"),
+            Cpu::Amd => ("kvm:tcg", "max,+svm"),
+        };
+        let ovmf = env::var("OVMF").map_or_else(
+            |_| project_root_dir().join("tests/bochs/bios/OVMF.fd"),
+            PathBuf::from,
+        );
+        let drive = format!("format=raw,file=fat:rw:{}", esp_dir().display());
+
+        println!("🕒 Starting the QEMU VM");
+        let mut qemu = Command::new("qemu-system-x86_64")
+            .args(["-nographic", "-no-reboot", "-m", "1024", "-smp", "2"])
+            .args(["-machine", &format!("q35,accel={accel}"), "-cpu", cpu])
+            .arg("-bios")
+            .arg(ovmf)
+            .args(["-drive", &drive])
+            .args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let stdout = qemu.stdout.take().unwrap();
+        let _unused = thread::spawn(move || {
+            let now = SystemTime::now();
+            let reader = BufReader::new(stdout);
+            reader.lines().map_while(Result::ok).for_each(|line| {
+                println!(
+                    "{:>4}: {line}\r",
+                    now.elapsed().unwrap_or_default().as_secs()
+                );
+            });
+        });
+
+        let started = SystemTime::now();
+        let status = loop {
+            if let Some(status) = qemu.try_wait()? {
+                break status;
+            }
+            if started.elapsed().unwrap_or_default() > TIMEOUT {
+                qemu.kill()?;
+                bail!("❌ The end-to-end tests timed out after {TIMEOUT:?}");
+            }
+            thread::sleep(Duration::from_millis(100));
+        };
+        match status.code() {
+            Some(PASSED_STATUS) => {
+                println!("✅ The end-to-end tests passed");
+                Ok(())
+            }
+            code => bail!("❌ The end-to-end tests failed: QEMU exited with {code:?}"),
+        }
+    }
+}
+
+/// Returns the directory exposed to the VM as the EFI system partition.
+fn esp_dir() -> PathBuf {
+    project_root_dir().join("target").join("qemu_esp")
+}
@@ -10,12 +10,13 @@ use std::{
 
 use anyhow::{ensure, Result};
 
-use super::{copy_artifacts_to, TestVm, UnixCommand};
+use super::{copy_artifacts_to, extract_samples, TestVm, UnixCommand};
 
 pub(crate) struct Vmware;
 
 impl TestVm for Vmware {
     fn deploy(&self, release: bool) -> Result<()> {
+        extract_samples()?;
         let output = UnixCommand::new("dd")
             .args([
                 "if=/dev/zero",
@@ -0,0 +1,76 @@
+//! This module implements virtualization of processors added to the system
+//! while the hypervisor runs (dynamic partitioning), with the processor change
+//! callback.
+
+use core::{
+    ffi::c_void,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use wdk_sys::{
+    ntddk::{
+        KeDeregisterProcessorChangeCallback, KeQueryActiveProcessorCountEx,
+        KeQueryMaximumProcessorCountEx, KeRegisterProcessorChangeCallback,
+    },
+    ALL_PROCESSOR_GROUPS, NTSTATUS, PKE_PROCESSOR_CHANGE_NOTIFY_CONTEXT, PNTSTATUS, PVOID,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_SUCCESS,
+};
+
+/// The registration of `processor_change_callback`.
+static REGISTRATION: AtomicPtr<c_void> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Returns the number of processors that can be added to the system.
+pub(crate) fn slot_count() -> usize {
+    let groups = u16::try_from(ALL_PROCESSOR_GROUPS).unwrap();
+    let maximum = unsafe { KeQueryMaximumProcessorCountEx(groups) };
+    let active = unsafe { KeQueryActiveProcessorCountEx(groups) };
+    maximum.saturating_sub(active) as usize
+}
+
+/// Registers the processor change callback.
+pub(crate) fn register() -> NTSTATUS {
+    let registration = unsafe {
+        KeRegisterProcessorChangeCallback(
+            Some(processor_change_callback),
+            core::ptr::null_mut(),
+            0,
+        )
+    };
+    if registration.is_null() {
+        return STATUS_INSUFFICIENT_RESOURCES;
+    }
+    REGISTRATION.store(registration, Ordering::Relaxed);
+    STATUS_SUCCESS
+}
+
+/// Unregisters the callback registered with `register`, if any.
+pub(crate) fn unregister() {
+    let registration = REGISTRATION.swap(core::ptr::null_mut(), Ordering::Relaxed);
+    if !registration.is_null() {
+        unsafe { KeDeregisterProcessorChangeCallback(registration) };
+    }
+}
+
+/// Called at PASSIVE_LEVEL when a processor is added to the system.
+// See: KeRegisterProcessorChangeCallback function (wdm.h)
+unsafe extern "C" fn processor_change_callback(
+    _context: PVOID,
+    change_context: PKE_PROCESSOR_CHANGE_NOTIFY_CONTEXT,
+    _operation_status: PNTSTATUS,
+) {
+    const KE_PROCESSOR_ADD_COMPLETE_NOTIFY: u32 = 1;
+
+    // The new processor starts running threads only once the addition is
+    // complete.
+    let change_context = unsafe { &*change_context };
+    if change_context.State as u32 != KE_PROCESSOR_ADD_COMPLETE_NOTIFY {
+        return;
+    }
+
+    crate::eprintln!("Virtualizing the added processor {}", change_context.NtNumber);
+    hv::platform_ops::get().run_on_processor(change_context.NtNumber as usize, || {
+        if let Err(e) = hv::virtualize_processor() {
+            crate::eprintln!("virtualize_processor failed: {e}");
+        }
+    });
+}
@@ -4,21 +4,36 @@
 extern crate alloc;
 
 mod eprintln;
+mod hotplug;
 mod ops;
+mod power;
+
+use core::sync::atomic::{AtomicPtr, Ordering};
 
 use alloc::boxed::Box;
 use wdk_sys::{
-    ntddk::ExAllocatePool2, DRIVER_OBJECT, NTSTATUS, PCUNICODE_STRING, POOL_FLAG_NON_PAGED,
-    STATUS_INSUFFICIENT_RESOURCES, STATUS_SUCCESS,
+    ntddk::{ExAllocatePool2, ExFreePool},
+    DRIVER_OBJECT, NTSTATUS, NT_SUCCESS, PCUNICODE_STRING, PDRIVER_OBJECT, POOL_FLAG_NON_PAGED,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_NOT_SUPPORTED, STATUS_SUCCESS,
 };
 
+/// The buffer given to the global allocator. Freed on unload.
+static ALLOCATOR_BUFFER: AtomicPtr<core::ffi::c_void> = AtomicPtr::new(core::ptr::null_mut());
+
+/// The buffers allocated to extend the heap with `PlatformOps::allocate_heap`.
+/// Freed on unload.
+pub(crate) static HEAP_EXTENSIONS: [AtomicPtr<core::ffi::c_void>; hv::allocator::MAX_EXTENSIONS] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; hv::allocator::MAX_EXTENSIONS];
+
+/// The pool tag of the memory allocated for the hypervisor.
+pub(crate) const POOL_TAG: u32 = u32::from_ne_bytes(*b"Bare");
+
 #[link_section = "INIT"]
 #[export_name = "DriverEntry"]
 extern "C" fn driver_entry(
-    _driver: &mut DRIVER_OBJECT,
+    driver: &mut DRIVER_OBJECT,
     _registry_path: PCUNICODE_STRING,
 ) -> NTSTATUS {
-    const POOL_TAG: u32 = u32::from_ne_bytes(*b"Bare");
     eprintln!("Loading win_hv.sys");
 
     // Initialize the global allocator with pre-allocated buffer.
@@ -34,19 +49,76 @@ extern "C" fn driver_entry(
         return STATUS_INSUFFICIENT_RESOURCES;
     }
     hv::allocator::init(ptr.cast::<u8>());
+    ALLOCATOR_BUFFER.store(ptr, Ordering::Relaxed);
 
     // Register the platform specific API.
     hv::platform_ops::init(Box::new(ops::WindowsOps));
 
-    // Virtualize the system. No `SharedHostData` is given, meaning that host's
-    // IDT, GDT, TSS and page tables are all that of the system process (PID=4).
-    // This makes the host debuggable with Windbg but also breakable from CPL0.
-    hv::virtualize_system(hv::SharedHostData::default());
+    // Virtualize the system. No host IDT, GDT, TSS and page tables are given,
+    // meaning that they are all that of the system process (PID=4). This makes
+    // the host debuggable with Windbg but also breakable from CPL0.
+    let shared_host = hv::SharedHostData {
+        serial_log: Some(hv::serial_logger::SerialConfig::default()),
+        hotplug_slots: hotplug::slot_count(),
+        ..Default::default()
+    };
+    if let Err(e) = hv::virtualize_system(shared_host) {
+        eprintln!("virtualize_system failed: {e}");
+        hv::shut_down();
+        free_heap();
+        return match e {
+            hv::HvError::OutOfMemory => STATUS_INSUFFICIENT_RESOURCES,
+            _ => STATUS_NOT_SUPPORTED,
+        };
+    }
+
+    // Devirtualize the system before sleep and virtualize it again on resume.
+    let status = power::register();
+    if !NT_SUCCESS(status) {
+        eprintln!("power::register failed: {status:#x}");
+        hv::shut_down();
+        free_heap();
+        return status;
+    }
+
+    // Virtualize processors added to the system later.
+    let status = hotplug::register();
+    if !NT_SUCCESS(status) {
+        eprintln!("hotplug::register failed: {status:#x}");
+        power::unregister();
+        hv::shut_down();
+        free_heap();
+        return status;
+    }
+    driver.DriverUnload = Some(driver_unload);
 
     eprintln!("Loaded win_hv.sys");
     STATUS_SUCCESS
 }
 
+extern "C" fn driver_unload(_driver: PDRIVER_OBJECT) {
+    eprintln!("Unloading win_hv.sys");
+
+    // Devirtualize the system for good, then free the memory the hypervisor
+    // used. No code uses the global allocator after this.
+    hotplug::unregister();
+    power::unregister();
+    hv::shut_down();
+    free_heap();
+
+    eprintln!("Unloaded win_hv.sys");
+}
+
+/// Frees the memory given to the global allocator.
+fn free_heap() {
+    for ptr in core::iter::once(&ALLOCATOR_BUFFER).chain(&HEAP_EXTENSIONS) {
+        let ptr = ptr.swap(core::ptr::null_mut(), Ordering::Relaxed);
+        if !ptr.is_null() {
+            unsafe { ExFreePool(ptr) };
+        }
+    }
+}
+
 #[panic_handler]
 fn panic_handler(info: &core::panic::PanicInfo<'_>) -> ! {
     if unsafe { *wdk_sys::KdDebuggerNotPresent } == 0 {
@@ -1,15 +1,22 @@
 //! This module implements Windows kernel driver-based implementation of
 //! [`hv::PlatformOps`].
 
+use core::sync::atomic::Ordering;
+
 use hv::platform_ops::PlatformOps;
 use wdk_sys::{
     ntddk::{
-        KeGetCurrentIrql, KeGetProcessorNumberFromIndex, KeQueryActiveProcessorCountEx,
+        ExAllocatePool2, KeGetCurrentIrql, KeGetCurrentProcessorNumberEx,
+        KeGetProcessorNumberFromIndex, KeQueryActiveProcessorCountEx,
         KeRevertToUserGroupAffinityThread, KeSetSystemGroupAffinityThread, MmGetPhysicalAddress,
+        MmGetVirtualForPhysical,
     },
-    ALL_PROCESSOR_GROUPS, APC_LEVEL, GROUP_AFFINITY, NT_SUCCESS, PAGED_CODE, PROCESSOR_NUMBER,
+    ALL_PROCESSOR_GROUPS, APC_LEVEL, GROUP_AFFINITY, NT_SUCCESS, PAGED_CODE, PHYSICAL_ADDRESS,
+    POOL_FLAG_NON_PAGED, PROCESSOR_NUMBER,
 };
 
+use crate::{HEAP_EXTENSIONS, POOL_TAG};
+
 pub(crate) struct WindowsOps;
 
 impl PlatformOps for WindowsOps {
@@ -21,22 +28,18 @@ impl PlatformOps for WindowsOps {
         PAGED_CODE!();
 
         for index in 0..processor_count() {
-            let mut processor_number = PROCESSOR_NUMBER::default();
-            let status = unsafe { KeGetProcessorNumberFromIndex(index, &mut processor_number) };
-            assert!(NT_SUCCESS(status));
+            run_on_processor_index(index, callback);
+        }
+    }
 
-            let mut old_affinity = GROUP_AFFINITY::default();
-            let mut affinity = GROUP_AFFINITY {
-                Group: processor_number.Group,
-                Mask: 1 << processor_number.Number,
-                Reserved: [0, 0, 0],
-            };
-            unsafe { KeSetSystemGroupAffinityThread(&mut affinity, &mut old_affinity) };
+    fn run_on_processor(&self, index: usize, callback: fn()) {
+        PAGED_CODE!();
 
-            callback();
+        run_on_processor_index(u32::try_from(index).unwrap(), callback);
+    }
 
-            unsafe { KeRevertToUserGroupAffinityThread(&mut old_affinity) };
-        }
+    fn current_processor_index(&self) -> usize {
+        unsafe { KeGetCurrentProcessorNumberEx(core::ptr::null_mut()) as usize }
     }
 
     fn pa(&self, va: *const core::ffi::c_void) -> u64 {
@@ -45,4 +48,47 @@ impl PlatformOps for WindowsOps {
             MmGetPhysicalAddress(va.cast_mut()).QuadPart as u64
         }
     }
+
+    fn va(&self, pa: u64) -> *mut core::ffi::c_void {
+        #[allow(clippy::cast_possible_wrap)]
+        let pa = PHYSICAL_ADDRESS {
+            QuadPart: pa as i64,
+        };
+        unsafe { MmGetVirtualForPhysical(pa) }
+    }
+
+    fn allocate_heap(&self, size: usize) -> *mut u8 {
+        // The heap can be extended only as many times as there are slots.
+        let Some(slot) = HEAP_EXTENSIONS
+            .iter()
+            .find(|slot| slot.load(Ordering::Relaxed).is_null())
+        else {
+            return core::ptr::null_mut();
+        };
+
+        // Allocations of a page or larger are page aligned.
+        let ptr = unsafe { ExAllocatePool2(POOL_FLAG_NON_PAGED, size as _, POOL_TAG) };
+        slot.store(ptr, Ordering::Relaxed);
+        ptr.cast()
+    }
+}
+
+/// Runs `callback` on the processor `index` by switching the affinity of the
+/// current thread to it.
+fn run_on_processor_index(index: u32, callback: fn()) {
+    let mut processor_number = PROCESSOR_NUMBER::default();
+    let status = unsafe { KeGetProcessorNumberFromIndex(index, &mut processor_number) };
+    assert!(NT_SUCCESS(status), "{index} is not a valid processor index");
+
+    let mut old_affinity = GROUP_AFFINITY::default();
+    let mut affinity = GROUP_AFFINITY {
+        Group: processor_number.Group,
+        Mask: 1 << processor_number.Number,
+        Reserved: [0, 0, 0],
+    };
+    unsafe { KeSetSystemGroupAffinityThread(&mut affinity, &mut old_affinity) };
+
+    callback();
+
+    unsafe { KeRevertToUserGroupAffinityThread(&mut old_affinity) };
 }
@@ -0,0 +1,89 @@
+//! This module implements devirtualization before the system sleeps and
+//! virtualization again on resume, with the `\Callback\PowerState` callback
+//! object. The hypervisor does not survive sleep, such as a lid close.
+
+use core::{
+    ffi::c_void,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use alloc::vec::Vec;
+use wdk_sys::{
+    ntddk::{ExCreateCallback, ExRegisterCallback, ExUnregisterCallback, ObfDereferenceObject},
+    NTSTATUS, NT_SUCCESS, OBJECT_ATTRIBUTES, OBJ_CASE_INSENSITIVE, PCALLBACK_OBJECT, PVOID,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_SUCCESS, UNICODE_STRING,
+};
+
+/// The callback object opened by `register`.
+static CALLBACK_OBJECT: AtomicPtr<c_void> = AtomicPtr::new(core::ptr::null_mut());
+
+/// The registration of `power_state_callback`.
+static REGISTRATION: AtomicPtr<c_void> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Registers the callback with the `\Callback\PowerState` callback object.
+pub(crate) fn register() -> NTSTATUS {
+    let mut name: Vec<u16> = "\\Callback\\PowerState".encode_utf16().collect();
+    let length = u16::try_from(name.len() * 2).unwrap();
+    let mut name = UNICODE_STRING {
+        Length: length,
+        MaximumLength: length,
+        Buffer: name.as_mut_ptr(),
+    };
+    let mut attributes = OBJECT_ATTRIBUTES {
+        Length: u32::try_from(core::mem::size_of::<OBJECT_ATTRIBUTES>()).unwrap(),
+        ObjectName: &mut name,
+        Attributes: OBJ_CASE_INSENSITIVE,
+        ..Default::default()
+    };
+
+    let mut callback_object: PCALLBACK_OBJECT = core::ptr::null_mut();
+    let status = unsafe { ExCreateCallback(&mut callback_object, &mut attributes, 0, 1) };
+    if !NT_SUCCESS(status) {
+        return status;
+    }
+
+    let registration = unsafe {
+        ExRegisterCallback(
+            callback_object,
+            Some(power_state_callback),
+            core::ptr::null_mut(),
+        )
+    };
+    if registration.is_null() {
+        unsafe { ObfDereferenceObject(callback_object.cast()) };
+        return STATUS_INSUFFICIENT_RESOURCES;
+    }
+    CALLBACK_OBJECT.store(callback_object.cast(), Ordering::Relaxed);
+    REGISTRATION.store(registration, Ordering::Relaxed);
+    STATUS_SUCCESS
+}
+
+/// Unregisters the callback registered with `register`, if any.
+pub(crate) fn unregister() {
+    let registration = REGISTRATION.swap(core::ptr::null_mut(), Ordering::Relaxed);
+    if !registration.is_null() {
+        unsafe { ExUnregisterCallback(registration) };
+    }
+    let callback_object = CALLBACK_OBJECT.swap(core::ptr::null_mut(), Ordering::Relaxed);
+    if !callback_object.is_null() {
+        unsafe { ObfDereferenceObject(callback_object) };
+    }
+}
+
+/// Called at PASSIVE_LEVEL when the system power state changes.
+// See: Using a System-Defined Callback Object
+unsafe extern "C" fn power_state_callback(_context: PVOID, argument1: PVOID, argument2: PVOID) {
+    const PO_CB_SYSTEM_STATE_LOCK: usize = 3;
+
+    if argument1 as usize != PO_CB_SYSTEM_STATE_LOCK {
+        return;
+    }
+
+    // Zero if the system is about to sleep, and one if it has resumed.
+    if argument2.is_null() {
+        crate::eprintln!("Devirtualizing the system before sleep");
+        hv::devirtualize_system();
+    } else if let Err(e) = hv::revirtualize_system() {
+        crate::eprintln!("revirtualize_system failed: {e}");
+    }
+}